@@ -0,0 +1,10 @@
+#![no_main]
+
+// A whole GGUF file: header, metadata, tensor table, then the tensor index and
+// model summary every loader builds.
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = llmetal::fuzz::parse_from_bytes(data);
+});
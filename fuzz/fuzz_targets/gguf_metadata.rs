@@ -0,0 +1,9 @@
+#![no_main]
+
+// The metadata section on its own (lead bytes: layout flags, entry count).
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = llmetal::fuzz::metadata_from_bytes(data);
+});
@@ -0,0 +1,9 @@
+#![no_main]
+
+// The tensor-info table on its own (lead bytes: layout flags, entry count).
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = llmetal::fuzz::tensor_info_from_bytes(data);
+});
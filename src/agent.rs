@@ -0,0 +1,311 @@
+//! Agent mode: the model calls tools, LLMetal runs them and feeds the
+//! results back, until the model answers.
+//!
+//! Each round generates an assistant turn on a `chat::Conversation` and takes
+//! it apart with `reply::parse`. A turn without tool calls is the answer.
+//! Otherwise every call runs, through a Rust closure or a subprocess, and its
+//! output goes into the history as a `Role::Tool` turn before the next
+//! round. A failed call is fed back as `error: ...` so the model can try
+//! something else. `Agent::max_iterations` caps the number of rounds.
+//!
+//! The model learns about the tools from the system prompt
+//! (`Agent::system_prompt`): a `<tools>` block of OpenAI function schemas
+//! and the instruction to answer with Hermes-style `<tool_call>` blocks,
+//! which `reply::parse` reads in every chat format.
+//!
+//! A subprocess tool is sandboxed as far as a plain process can be:
+//! - only registered commands run;
+//! - the arguments arrive as JSON on stdin and never go through a shell;
+//! - the environment is cleared except for `Sandbox::keep_env`;
+//! - the process runs in `Sandbox::dir` and is killed after
+//!   `Sandbox::timeout`;
+//! - its output is cut at `Sandbox::max_output` bytes.
+//!
+//! Filesystem and network isolation is left to the command itself, e.g. a
+//! container or `sandbox-exec` wrapper.
+
+use std::io::{Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use serde_json::{Value, json};
+
+use crate::chat::{Conversation, Message, Role};
+use crate::events::GenerationEvent;
+use crate::model::{GenerateOptions, Generator};
+use crate::reply::{self, AssistantReply, ToolCall};
+
+/// A tool implemented in Rust: the call's arguments in, its output out.
+pub type ToolFn = Box<dyn FnMut(&Value) -> Result<String>>;
+
+pub enum ToolHandler {
+    Closure(ToolFn),
+    /// A program and its arguments, run under the agent's `Sandbox`.
+    Command(Vec<String>),
+}
+
+pub struct Tool {
+    pub name: String,
+    pub description: String,
+    /// JSON Schema for the arguments object.
+    pub parameters: Value,
+    pub handler: ToolHandler,
+}
+
+/// Limits on subprocess tools.
+#[derive(Clone, Debug)]
+pub struct Sandbox {
+    /// Working directory; the current one when unset.
+    pub dir: Option<PathBuf>,
+    pub timeout: Duration,
+    /// Output past this many bytes is dropped.
+    pub max_output: usize,
+    /// Environment variables the command inherits; all others are cleared.
+    pub keep_env: Vec<String>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self {
+            dir: None,
+            timeout: Duration::from_secs(30),
+            max_output: 16 << 10,
+            keep_env: ["PATH", "HOME", "LANG"].map(String::from).to_vec(),
+        }
+    }
+}
+
+/// One tool call and what came of it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ToolRun {
+    pub call: ToolCall,
+    /// The output, or the error fed back in its place.
+    pub output: String,
+    pub ok: bool,
+}
+
+/// The agent's answer and the calls it took to get there.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AgentAnswer {
+    pub reply: AssistantReply,
+    pub runs: Vec<ToolRun>,
+    /// Assistant turns generated, the answer included.
+    pub iterations: usize,
+}
+
+pub struct Agent {
+    pub tools: Vec<Tool>,
+    pub sandbox: Sandbox,
+    /// Most assistant turns one `run` may generate before giving up.
+    pub max_iterations: usize,
+}
+
+impl Default for Agent {
+    fn default() -> Self {
+        Self { tools: Vec::new(), sandbox: Sandbox::default(), max_iterations: 8 }
+    }
+}
+
+impl Agent {
+    pub fn register(
+        &mut self,
+        name: &str,
+        description: &str,
+        parameters: Value,
+        f: impl FnMut(&Value) -> Result<String> + 'static,
+    ) -> &mut Self {
+        self.add(Tool {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters,
+            handler: ToolHandler::Closure(Box::new(f)),
+        })
+    }
+
+    pub fn register_command(&mut self, name: &str, description: &str, parameters: Value, command: Vec<String>) -> &mut Self {
+        self.add(Tool {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters,
+            handler: ToolHandler::Command(command),
+        })
+    }
+
+    /// Add `tool`, replacing one of the same name.
+    pub fn add(&mut self, tool: Tool) -> &mut Self {
+        self.tools.retain(|t| t.name != tool.name);
+        self.tools.push(tool);
+        self
+    }
+
+    /// Subprocess tools from a JSON file: an array of `{"name",
+    /// "description", "parameters", "command"}`, `command` an array of
+    /// strings or one string split on whitespace.
+    pub fn load_tools(&mut self, path: &Path) -> Result<()> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let tools: Value = serde_json::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+        for t in tools.as_array().with_context(|| format!("{} is not a JSON array of tools", path.display()))? {
+            let name = t["name"].as_str().context("tool without a name")?;
+            let command: Vec<String> = match &t["command"] {
+                Value::String(s) => s.split_whitespace().map(String::from).collect(),
+                Value::Array(parts) => {
+                    parts.iter().map(|p| p.as_str().map(String::from)).collect::<Option<_>>().context("command parts must be strings")?
+                }
+                _ => bail!("tool '{name}' has no command"),
+            };
+            ensure!(!command.is_empty(), "tool '{name}' has an empty command");
+            let parameters = match &t["parameters"] {
+                Value::Null => json!({ "type": "object", "properties": {} }),
+                p => p.clone(),
+            };
+            self.register_command(name, t["description"].as_str().unwrap_or_default(), parameters, command);
+        }
+        Ok(())
+    }
+
+    /// `base` followed by the tool list and how to call a tool.
+    pub fn system_prompt(&self, base: Option<&str>) -> String {
+        let mut out = base.map(|b| format!("{b}\n\n")).unwrap_or_default();
+        out.push_str("You may call one or more functions to help with the user's request. The functions are:\n<tools>\n");
+        for t in &self.tools {
+            let schema = json!({
+                "type": "function",
+                "function": { "name": t.name, "description": t.description, "parameters": t.parameters },
+            });
+            out.push_str(&format!("{schema}\n"));
+        }
+        out.push_str(
+            "</tools>\n\nTo call a function, reply with one block per call:\n\
+             <tool_call>\n{\"name\": <function-name>, \"arguments\": <args-json-object>}\n</tool_call>\n\
+             The results come back in the next message. Once you have what you need, answer without calling a function.",
+        );
+        out
+    }
+
+    /// Send `user` and keep running the tools the model calls until it
+    /// answers. `on_tool` sees each call once it has run. Errs when the
+    /// model is still calling tools after `max_iterations` turns.
+    pub fn run(
+        &mut self,
+        conv: &mut Conversation,
+        model: &mut dyn Generator,
+        user: &str,
+        opts: &GenerateOptions,
+        on_event: &mut dyn FnMut(GenerationEvent),
+        on_tool: &mut dyn FnMut(&ToolRun),
+    ) -> Result<AgentAnswer> {
+        ensure!(self.max_iterations > 0, "max_iterations must be at least 1");
+        conv.history.turns.push(Message::new(Role::User, user));
+        let mut answer = AgentAnswer::default();
+        while answer.iterations < self.max_iterations {
+            let text = conv.respond(model, opts, on_event)?;
+            answer.iterations += 1;
+            let reply = reply::parse(conv.format, &text);
+            if reply.tool_calls.is_empty() {
+                answer.reply = reply;
+                return Ok(answer);
+            }
+            for call in reply.tool_calls {
+                let (output, ok) = match self.call(&call) {
+                    Ok(output) => (output, true),
+                    Err(e) => (format!("error: {e:#}"), false),
+                };
+                conv.history.turns.push(Message::new(Role::Tool, output.as_str()));
+                let run = ToolRun { call, output, ok };
+                on_tool(&run);
+                answer.runs.push(run);
+            }
+        }
+        bail!("no answer after {} turns of tool calls", self.max_iterations)
+    }
+
+    /// Run one call with its tool.
+    pub fn call(&mut self, call: &ToolCall) -> Result<String> {
+        let sandbox = &self.sandbox;
+        let tool = self.tools.iter_mut().find(|t| t.name == call.name).with_context(|| format!("no tool named '{}'", call.name))?;
+        match &mut tool.handler {
+            ToolHandler::Closure(f) => f(&call.arguments),
+            ToolHandler::Command(command) => run_command(command, &call.arguments, sandbox),
+        }
+    }
+}
+
+/// `command` with `arguments` as JSON on stdin, under `sandbox`. Its
+/// stdout is the output; a nonzero exit is an error carrying its stderr.
+/// The command runs in its own process group, so the timeout kills
+/// whatever it started as well, and covers reading its output: a
+/// background child still holding the pipes open counts too.
+fn run_command(command: &[String], arguments: &Value, sandbox: &Sandbox) -> Result<String> {
+    let (program, args) = command.split_first().context("empty tool command")?;
+    let mut cmd = Command::new(program);
+    cmd.args(args).env_clear().stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    cmd.process_group(0);
+    for key in &sandbox.keep_env {
+        if let Some(v) = std::env::var_os(key) {
+            cmd.env(key, v);
+        }
+    }
+    if let Some(dir) = &sandbox.dir {
+        cmd.current_dir(dir);
+    }
+    let mut child = cmd.spawn().with_context(|| format!("starting {program}"))?;
+    let mut stdin = child.stdin.take().context("tool stdin")?;
+    let input = arguments.to_string();
+    // Written from a thread so a command that never reads cannot block us.
+    std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let limit = sandbox.max_output;
+    let read = |pipe: Option<Box<dyn Read + Send>>| {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.by_ref().take(limit as u64).read_to_end(&mut buf);
+                // Keep draining so the command does not stall on a full pipe.
+                let _ = std::io::copy(&mut pipe, &mut std::io::sink());
+            }
+            let _ = tx.send(String::from_utf8_lossy(&buf).into_owned());
+        });
+        rx
+    };
+    let stdout = read(child.stdout.take().map(|p| Box::new(p) as Box<dyn Read + Send>));
+    let stderr = read(child.stderr.take().map(|p| Box::new(p) as Box<dyn Read + Send>));
+    let deadline = Instant::now() + sandbox.timeout;
+    let timed_out = |child: &mut Child| {
+        kill_group(child);
+        let _ = child.wait();
+        anyhow!("{program} timed out after {:?}", sandbox.timeout)
+    };
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() > deadline {
+            return Err(timed_out(&mut child));
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    };
+    let output = |rx: mpsc::Receiver<String>| rx.recv_timeout(deadline.saturating_duration_since(Instant::now()));
+    let (Ok(stdout), Ok(stderr)) = (output(stdout), output(stderr)) else {
+        return Err(timed_out(&mut child));
+    };
+    if !status.success() {
+        bail!("{program} exited with {status}: {}", stderr.trim());
+    }
+    Ok(stdout.trim_end().to_string())
+}
+
+/// SIGKILL to `child`'s process group (`process_group(0)` made its pid the
+/// group id), then to `child` itself in case the group is already gone.
+fn kill_group(child: &mut Child) {
+    unsafe extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+    }
+    const SIGKILL: i32 = 9;
+    // SAFETY: kill(2) takes no pointers; a negative pid names the group.
+    unsafe { kill(-(child.id() as i32), SIGKILL) };
+    let _ = child.kill();
+}
@@ -0,0 +1,76 @@
+//! Async surface for services built on tokio (enable the `tokio` feature).
+//!
+//! Loading and generation are CPU/GPU-bound and run on tokio's blocking pool,
+//! so they never stall an executor thread. Only the cheap file probe before a
+//! load is real async IO.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result, bail};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::events::GenerationEvent;
+use crate::model::{GenerateOptions, LlamaModel};
+
+/// A `LlamaModel` that can be shared between tasks. Generations are serialised:
+/// one model owns one GPU queue and one weight cache.
+#[derive(Clone)]
+pub struct AsyncModel {
+    inner: Arc<Mutex<LlamaModel>>,
+}
+
+/// Events of one running generation, plus the task producing them.
+pub struct GenerationStream {
+    events: mpsc::UnboundedReceiver<GenerationEvent>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl AsyncModel {
+    pub async fn load(path: impl Into<String>) -> Result<Self> {
+        let path = path.into();
+
+        // Reject non-GGUF files before handing a multi-GB mmap to the blocking
+        // pool; sealed ones are checked once decrypted.
+        let mut file = tokio::fs::File::open(&path)
+            .await
+            .with_context(|| format!("open {path}"))?;
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic).await.context("read GGUF magic")?;
+        if &magic != b"GGUF" && magic != crate::envelope::MAGIC[..4] {
+            bail!("{path}: not a GGUF file");
+        }
+
+        let model = tokio::task::spawn_blocking(move || LlamaModel::load(&path))
+            .await
+            .context("load task panicked")??;
+        Ok(Self { inner: Arc::new(Mutex::new(model)) })
+    }
+
+    /// Start a generation; events arrive on the returned stream as they happen.
+    pub fn generate(&self, tokens: Vec<u32>, opts: GenerateOptions, vocab: Arc<[String]>) -> GenerationStream {
+        let (tx, events) = mpsc::unbounded_channel();
+        let inner = self.inner.clone();
+        let task = tokio::task::spawn_blocking(move || {
+            let mut model = inner.lock().map_err(|_| anyhow::anyhow!("model lock poisoned"))?;
+            model.generate(&tokens, &opts, &vocab, &mut |event| {
+                // A dropped receiver just means nobody is listening any more.
+                let _ = tx.send(event);
+            })
+        });
+        GenerationStream { events, task }
+    }
+}
+
+impl GenerationStream {
+    /// Next event, or `None` once generation has finished.
+    pub async fn next(&mut self) -> Option<GenerationEvent> {
+        self.events.recv().await
+    }
+
+    /// Wait for the generation task and surface its error, if any.
+    pub async fn finish(self) -> Result<()> {
+        self.task.await.context("generation task panicked")?
+    }
+}
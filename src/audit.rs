@@ -0,0 +1,156 @@
+//! `llmetal audit`: value statistics for every tensor, after dequantization.
+//!
+//! A broken quantization or conversion bug rarely fails at load time. It
+//! shows up as NaNs deep in a forward pass, or as a tensor whose values are
+//! off by orders of magnitude. Summarising each tensor up front catches both
+//! without touching the GPU.
+
+use anyhow::{Context, Result};
+
+use crate::gguf::GgufFile;
+use crate::tensor::{
+    GGML_F16, GGML_F32, GGML_IQ4_NL, GGML_Q2_K, GGML_Q3_K, GGML_Q8_0, IQ4_NL_BLOCK, Q2_K_BLOCK, Q3_K_BLOCK,
+    Q8_0_BLOCK, TensorStore, index_tensors,
+};
+
+/// BF16: the top half of an f32.
+const GGML_BF16: u32 = 30;
+/// Blocks dequantized at a time, so a large tensor is never expanded in full.
+const CHUNK_BLOCKS: usize = 4096;
+
+/// min/max/mean/std over the finite values; NaN and ±Inf are only counted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TensorStats {
+    pub count: u64,
+    pub nan: u64,
+    pub inf: u64,
+    pub min: f32,
+    pub max: f32,
+    pub mean: f64,
+    pub std: f64,
+}
+
+impl Default for TensorStats {
+    fn default() -> Self {
+        Self { count: 0, nan: 0, inf: 0, min: f32::INFINITY, max: f32::NEG_INFINITY, mean: 0.0, std: 0.0 }
+    }
+}
+
+impl TensorStats {
+    /// Finite values seen so far.
+    pub fn finite(&self) -> u64 {
+        self.count - self.nan - self.inf
+    }
+
+    pub fn is_broken(&self) -> bool {
+        self.nan > 0 || self.inf > 0
+    }
+}
+
+/// Welford's running mean/variance; `m2` becomes `std` in `finish`.
+#[derive(Default)]
+struct Accumulator {
+    stats: TensorStats,
+    m2: f64,
+}
+
+impl Accumulator {
+    fn push(&mut self, v: f32) {
+        let s = &mut self.stats;
+        s.count += 1;
+        if v.is_nan() {
+            s.nan += 1;
+            return;
+        }
+        if v.is_infinite() {
+            s.inf += 1;
+            return;
+        }
+        s.min = s.min.min(v);
+        s.max = s.max.max(v);
+        let n = s.finite() as f64;
+        let delta = v as f64 - s.mean;
+        s.mean += delta / n;
+        self.m2 += delta * (v as f64 - s.mean);
+    }
+
+    fn finish(mut self) -> TensorStats {
+        let n = self.stats.finite();
+        self.stats.std = if n > 0 { (self.m2 / n as f64).sqrt() } else { 0.0 };
+        self.stats
+    }
+}
+
+pub struct TensorAudit {
+    pub name: String,
+    pub kind: u32,
+    pub shape: Vec<u64>,
+    /// `None` when there is no CPU dequantizer for `kind`.
+    pub stats: Option<TensorStats>,
+}
+
+/// Statistics for one tensor's raw GGUF bytes. F32, F16, BF16, Q8_0, Q2_K,
+/// Q3_K and IQ4_NL are supported; other types return `None`.
+pub fn tensor_stats(kind: u32, bytes: &[u8]) -> Option<TensorStats> {
+    let mut acc = Accumulator::default();
+    match kind {
+        GGML_F32 => bytes
+            .chunks_exact(4)
+            .for_each(|c| acc.push(f32::from_le_bytes([c[0], c[1], c[2], c[3]]))),
+        GGML_F16 => {
+            for chunk in bytes.chunks(2 * CHUNK_BLOCKS) {
+                TensorStore::dequant_f16_row(chunk).into_iter().for_each(|v| acc.push(v));
+            }
+        }
+        GGML_BF16 => bytes
+            .chunks_exact(2)
+            .for_each(|c| acc.push(f32::from_bits((u16::from_le_bytes([c[0], c[1]]) as u32) << 16))),
+        GGML_Q8_0 => {
+            for chunk in bytes.chunks(Q8_0_BLOCK * CHUNK_BLOCKS) {
+                TensorStore::dequant_q8_0_row(chunk).into_iter().for_each(|v| acc.push(v));
+            }
+        }
+        GGML_Q2_K => {
+            for chunk in bytes.chunks(Q2_K_BLOCK * CHUNK_BLOCKS) {
+                TensorStore::dequant_q2_k_row(chunk).into_iter().for_each(|v| acc.push(v));
+            }
+        }
+        GGML_Q3_K => {
+            for chunk in bytes.chunks(Q3_K_BLOCK * CHUNK_BLOCKS) {
+                TensorStore::dequant_q3_k_row(chunk).into_iter().for_each(|v| acc.push(v));
+            }
+        }
+        GGML_IQ4_NL => {
+            for chunk in bytes.chunks(IQ4_NL_BLOCK * CHUNK_BLOCKS) {
+                TensorStore::dequant_iq4_nl_row(chunk).into_iter().for_each(|v| acc.push(v));
+            }
+        }
+        _ => return None,
+    }
+    Some(acc.finish())
+}
+
+/// Audit every tensor in a GGUF file, in tensor-table order.
+pub fn audit(path: &str) -> Result<Vec<TensorAudit>> {
+    let mmap = crate::envelope::map(path)?;
+    let gguf = GgufFile::parse(&mmap).with_context(|| format!("parse {path}"))?;
+    gguf.ensure_little_endian_data().with_context(|| format!("audit {path}"))?;
+    let index = index_tensors(&gguf)?;
+
+    gguf.tensors
+        .iter()
+        .map(|t| {
+            let meta = &index[&t.name];
+            let start = meta.file_offset as usize;
+            let bytes = mmap
+                .get(start..start + meta.byte_size as usize)
+                .with_context(|| format!("tensor '{}' out of file bounds", t.name))?;
+            Ok(TensorAudit {
+                name: t.name.clone(),
+                kind: t.kind,
+                shape: t.shape.clone(),
+                stats: tensor_stats(t.kind, bytes),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,124 @@
+//! Threadgroup autotuning for the matmul kernels (`run --autotune`).
+//!
+//! Every matmul kernel gives each output row one simdgroup, so how many
+//! simdgroups share a threadgroup is free to choose, and the best width
+//! depends on the GPU and on the shape: a narrow projection wants small
+//! groups to fill every core, a wide one can afford large ones. While
+//! tuning, the first dispatches of each (kernel, shape) try every candidate
+//! width in turn, `REPS` times each, and the fastest becomes that shape's
+//! pick. One forward pass is usually enough to settle every shape of a
+//! model. Tuning runs the real dispatches, so the output is unchanged.
+//!
+//! Picks are saved per GPU and kernel source in
+//! `<cache dir>/autotune/<family>-<gpu>-<hash>.json` (see
+//! `shader_cache::device_key`):
+//!
+//! ```text
+//! {"threadgroups": {"q8_0_matvec n4096 k4096 b1": 128, ...}}
+//! ```
+//!
+//! and used on every later launch, tuning or not. Batches are bucketed to
+//! the next power of two, so each prompt length does not tune afresh.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+
+/// The width every dispatch used before tuning, and still uses for shapes
+/// without a pick.
+pub const DEFAULT_THREADGROUP: usize = 256;
+
+/// Timed dispatches per candidate; the fastest of them counts.
+pub const REPS: usize = 3;
+
+#[derive(Debug, Default)]
+pub struct Tuner {
+    /// Where picks are saved; `None` keeps them in memory only.
+    path: Option<PathBuf>,
+    best: BTreeMap<String, usize>,
+    /// Timings so far of each shape still being tuned.
+    trials: HashMap<String, Vec<(usize, Duration)>>,
+    /// Try candidates for shapes without a pick, instead of the default.
+    pub enabled: bool,
+}
+
+impl Tuner {
+    /// Picks saved at `path` earlier; none when it is missing or unreadable.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let best = path.as_deref().and_then(|p| read_picks(p).ok()).unwrap_or_default();
+        Self { path, best, ..Self::default() }
+    }
+
+    pub fn picks(&self) -> &BTreeMap<String, usize> {
+        &self.best
+    }
+
+    /// The width for the next dispatch of `key`, and whether it is a trial
+    /// whose time goes to `observe`.
+    pub fn choose(&self, key: &str, max_threads: usize) -> (usize, bool) {
+        if let Some(&tg) = self.best.get(key) {
+            return (tg, false);
+        }
+        if !self.enabled {
+            return (DEFAULT_THREADGROUP.min(max_threads), false);
+        }
+        let done = self.trials.get(key).map_or(0, Vec::len);
+        (candidates(max_threads)[done / REPS], true)
+    }
+
+    /// Record a trial. The last one settles the pick for `key` and saves
+    /// every pick.
+    pub fn observe(&mut self, key: &str, tg: usize, took: Duration, max_threads: usize) -> Result<()> {
+        let trials = self.trials.entry(key.to_string()).or_default();
+        trials.push((tg, took));
+        if trials.len() < candidates(max_threads).len() * REPS {
+            return Ok(());
+        }
+        let trials = self.trials.remove(key).unwrap_or_default();
+        let fastest = trials.iter().min_by_key(|&&(tg, took)| (took, tg)).map_or(DEFAULT_THREADGROUP, |&(tg, _)| tg);
+        self.best.insert(key.to_string(), fastest);
+        match &self.path {
+            Some(path) => write_picks(path, &self.best),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Threadgroup widths worth trying: whole simdgroups, up to what the
+/// pipeline allows.
+pub fn candidates(max_threads: usize) -> Vec<usize> {
+    let widths: Vec<usize> = [64, 128, 256, 512, 1024].into_iter().filter(|&w| w <= max_threads).collect();
+    if widths.is_empty() { vec![max_threads.max(32)] } else { widths }
+}
+
+/// `q8_0_matvec n4096 k4096 b1`.
+pub fn key(kernel: &str, n: usize, k: usize, batch: usize) -> String {
+    format!("{kernel} n{n} k{k} b{}", batch.next_power_of_two())
+}
+
+/// `<cache dir>/autotune/<device key>-<source hash>.json`.
+pub fn picks_path(device_key: &str, source: &str) -> Option<PathBuf> {
+    let hash = crate::shader_cache::source_key(source, "");
+    Some(crate::repack::cache_dir()?.join("autotune").join(format!("{device_key}-{hash:016x}.json")))
+}
+
+pub fn read_picks(path: &Path) -> Result<BTreeMap<String, usize>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    let picks: Value = serde_json::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+    let Some(picks) = picks["threadgroups"].as_object() else { return Ok(BTreeMap::new()) };
+    Ok(picks.iter().filter_map(|(key, tg)| Some((key.clone(), tg.as_u64()? as usize))).collect())
+}
+
+/// Through a temporary file, so a concurrent launch never reads half of it.
+pub fn write_picks(path: &Path, picks: &BTreeMap<String, usize>) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    }
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    std::fs::write(&tmp, serde_json::to_string_pretty(&json!({ "threadgroups": picks }))?)
+        .with_context(|| format!("write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("write {}", path.display()))
+}
@@ -0,0 +1,275 @@
+//! Encoder-only transformers: BERT and nomic-bert embedding/reranker GGUFs.
+//!
+//! Same tensor store and kernels as the decoder, different block. Attention is
+//! bidirectional (every token sees the whole sequence, so there is no KV
+//! cache), normalisation is post-residual LayerNorm with biases, and the
+//! output is a hidden state per token rather than logits. Embeddings pool
+//! those states (`{arch}.pooling_type`); rerankers run the `[CLS]` state
+//! through the `cls` pooler and `cls.output` head.
+//!
+//! BERT adds learned position and token-type embeddings and uses a GELU FFN.
+//! nomic-bert fuses Q/K/V into `attn_qkv`, rotates Q/K with NeoX-style RoPE
+//! instead of position embeddings, and uses a SwiGLU FFN.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, ensure};
+use metal::Buffer;
+
+use crate::cpu::dot;
+use crate::embed::{Pooling, l2_normalize, pool};
+use crate::gpu::Gpu;
+use crate::model::attention;
+use crate::tensor::{GGML_Q8_0, TensorStore};
+use crate::weights::{EncoderWeights, LayerNorm, Linear, Qkv};
+
+/// `general.architecture` values this module runs.
+pub const ENCODER_ARCHS: [&str; 2] = ["bert", "nomic-bert"];
+
+pub fn is_encoder(architecture: &str) -> bool {
+    ENCODER_ARCHS.contains(&architecture)
+}
+
+#[derive(Clone, Debug)]
+pub struct EncoderArch {
+    pub hidden: usize,
+    pub n_layers: usize,
+    pub n_heads: usize,
+    pub ffn_hidden: usize,
+    pub vocab_size: usize,
+    pub layer_norm_eps: f32,
+    /// `Some(base)` when Q/K are rotated instead of adding position embeddings.
+    pub rope_base: Option<f32>,
+    /// From `{arch}.pooling_type`; mean when the GGUF does not say.
+    pub pooling: Pooling,
+}
+
+pub struct BertModel {
+    pub arch: EncoderArch,
+    pub weights: Arc<EncoderWeights>,
+    store: TensorStore,
+    gpu: Gpu,
+    /// Q8_0 weights uploaded for the matmul kernels.
+    weight_cache: HashMap<String, Buffer>,
+    /// Everything else (F32/F16 weights, biases, norms), dequantized once.
+    cpu_cache: HashMap<String, Arc<[f32]>>,
+}
+
+impl BertModel {
+    pub fn load(path: &str) -> Result<Self> {
+        let gpu = Gpu::new()?;
+        let store = TensorStore::open(path, &gpu.device)?;
+        let meta = &store.metadata;
+        let prefix = meta.get("general.architecture").and_then(|v| v.as_str()).unwrap_or("bert").to_string();
+        ensure!(is_encoder(&prefix), "'{prefix}' is not an encoder architecture");
+        let get_u = |key: &str| meta.get(&format!("{prefix}.{key}")).and_then(|v| v.as_u64()).map(|v| v as usize);
+        let get_f = |key: &str| meta.get(&format!("{prefix}.{key}")).and_then(|v| v.as_f64()).map(|v| v as f32);
+
+        let hidden = get_u("embedding_length").context("missing embedding_length")?;
+        let arch = EncoderArch {
+            hidden,
+            n_layers: get_u("block_count").context("missing block_count")?,
+            n_heads: get_u("attention.head_count").context("missing attention.head_count")?,
+            ffn_hidden: get_u("feed_forward_length").unwrap_or(hidden * 4),
+            vocab_size: store.index.get("token_embd.weight").map(|m| m.rows()).context("missing token_embd.weight")?,
+            layer_norm_eps: get_f("attention.layer_norm_epsilon").unwrap_or(1e-12),
+            rope_base: (prefix == "nomic-bert").then(|| get_f("rope.freq_base").unwrap_or(1000.0)),
+            // llama.cpp's enum: 1 mean, 2 cls, 3 last, 4 rank (which pools [CLS]).
+            pooling: match get_u("pooling_type") {
+                Some(2 | 4) => Pooling::Cls,
+                Some(3) => Pooling::Last,
+                _ => Pooling::Mean,
+            },
+        };
+        let weights = Arc::new(EncoderWeights::from_index(&store.index, &arch)?);
+        Ok(Self { arch, weights, store, gpu, weight_cache: HashMap::new(), cpu_cache: HashMap::new() })
+    }
+
+    /// Sentence embedding of a `[CLS] ... [SEP]` sequence, pooled (the GGUF's
+    /// pooling unless overridden) and L2-normalised.
+    pub fn embed_tokens(&mut self, tokens: &[u32], pooling: Option<Pooling>) -> Result<Vec<f32>> {
+        let hidden = self.forward(tokens, &vec![0; tokens.len()])?;
+        let mut v = pool(&hidden, self.arch.hidden, pooling.unwrap_or(self.arch.pooling));
+        l2_normalize(&mut v);
+        Ok(v)
+    }
+
+    /// Relevance logit of a `[CLS] query [SEP] document [SEP]` pair. Tokens
+    /// after the first `sep` are segment 1.
+    pub fn rerank_logit(&mut self, tokens: &[u32], sep: u32) -> Result<f32> {
+        ensure!(self.weights.cls_output.is_some(), "not a reranker: no cls.output.weight");
+        let first_sep = tokens.iter().position(|&t| t == sep).unwrap_or(tokens.len());
+        let types: Vec<u32> = (0..tokens.len()).map(|i| (i > first_sep) as u32).collect();
+        Ok(self.head_logits(tokens, &types)?[0])
+    }
+
+    /// One logit per class of a `[CLS] text [SEP]` sequence.
+    pub fn class_logits(&mut self, tokens: &[u32]) -> Result<Vec<f32>> {
+        ensure!(self.weights.cls_output.is_some(), "not a classifier: no cls.output.weight");
+        self.head_logits(tokens, &vec![0; tokens.len()])
+    }
+
+    /// The classifier's label names; see `classify::labels`.
+    pub fn class_labels(&self) -> Vec<String> {
+        let n = self.weights.cls_output.as_ref().map_or(0, |l| l.weight.rows);
+        crate::classify::labels(&self.store.metadata, n)
+    }
+
+    /// The `[CLS]` state through the `cls` pooler (if any) and `cls.output`.
+    fn head_logits(&mut self, tokens: &[u32], types: &[u32]) -> Result<Vec<f32>> {
+        let weights = self.weights.clone();
+        let head = weights.cls_output.as_ref().context("no cls.output.weight")?;
+        let hidden = self.forward(tokens, types)?;
+        let mut x = hidden[..self.arch.hidden].to_vec();
+        if let Some(cls) = &weights.cls {
+            x = self.linear(cls, &x, 1)?;
+            x.iter_mut().for_each(|v| *v = v.tanh());
+        }
+        self.linear(head, &x, 1)
+    }
+
+    /// Final hidden states, packed `[tokens, hidden]`.
+    fn forward(&mut self, tokens: &[u32], types: &[u32]) -> Result<Vec<f32>> {
+        ensure!(!tokens.is_empty(), "cannot encode an empty token sequence");
+        let (h, n) = (self.arch.hidden, tokens.len());
+        let weights = self.weights.clone();
+
+        let mut x = Vec::with_capacity(n * h);
+        for (i, (&t, &ty)) in tokens.iter().zip(types).enumerate() {
+            let mut row = self.store.dequant_row(&weights.token_embd.name, t as usize)?;
+            if let Some(tt) = &weights.token_types {
+                add(&mut row, &self.store.dequant_row(&tt.name, ty as usize)?);
+            }
+            if let Some(pe) = &weights.position_embd {
+                ensure!(i < pe.rows, "sequence of {n} tokens is longer than the model's {} positions", pe.rows);
+                add(&mut row, &self.store.dequant_row(&pe.name, i)?);
+            }
+            x.extend(row);
+        }
+        x = self.layer_norm(&weights.token_embd_norm, &x)?;
+
+        let n_heads = self.arch.n_heads;
+        let head_dim = h / n_heads;
+        for w in &weights.layers {
+            let (mut q, mut k, v) = match &w.qkv {
+                Qkv::Split { q, k, v } => (self.linear(q, &x, n)?, self.linear(k, &x, n)?, self.linear(v, &x, n)?),
+                Qkv::Fused(qkv) => {
+                    let all = self.linear(qkv, &x, n)?;
+                    let part = |p: usize| all.chunks_exact(3 * h).flat_map(|r| r[p * h..][..h].to_vec()).collect::<Vec<_>>();
+                    (part(0), part(1), part(2))
+                }
+            };
+            if let Some(base) = self.arch.rope_base {
+                for i in 0..n {
+                    rope_neox(&mut q[i * h..][..h], n_heads, head_dim, i, base);
+                    rope_neox(&mut k[i * h..][..h], n_heads, head_dim, i, base);
+                }
+            }
+            // Bidirectional: every query attends to every position.
+            let k_rows: Vec<Arc<[f32]>> = k.chunks_exact(h).map(Arc::from).collect();
+            let v_rows: Vec<Arc<[f32]>> = v.chunks_exact(h).map(Arc::from).collect();
+            let attn: Vec<f32> = q
+                .chunks_exact(h)
+                .flat_map(|qi| attention(qi, &k_rows, &v_rows, n_heads, n_heads, head_dim))
+                .collect();
+
+            let mut res = self.linear(&w.attn_output, &attn, n)?;
+            add(&mut res, &x);
+            x = self.layer_norm(&w.attn_output_norm, &res)?;
+
+            let up = self.linear(&w.ffn_up, &x, n)?;
+            let mid: Vec<f32> = match &w.ffn_gate {
+                Some(gate) => self.linear(gate, &x, n)?.iter().zip(&up).map(|(g, u)| silu(*g) * u).collect(),
+                None => up.iter().map(|&u| gelu(u)).collect(),
+            };
+            let mut res = self.linear(&w.ffn_down, &mid, n)?;
+            add(&mut res, &x);
+            x = self.layer_norm(&w.layer_output_norm, &res)?;
+        }
+        Ok(x)
+    }
+
+    /// `x @ W^T + b` over `n` packed rows. Q8_0 weights go through the GPU
+    /// kernels; F32/F16 weights (common in small embedding models) stay on the CPU.
+    fn linear(&mut self, l: &Linear, x: &[f32], n: usize) -> Result<Vec<f32>> {
+        let (rows, cols) = (l.weight.rows, l.weight.cols);
+        let mut out = if l.weight.kind == GGML_Q8_0 {
+            if !self.weight_cache.contains_key(&l.weight.name) {
+                let buf = self.gpu.buf_from_bytes(self.store.get(&l.weight.name)?);
+                self.weight_cache.insert(l.weight.name.clone(), buf);
+            }
+            let w = &self.weight_cache[&l.weight.name];
+            let x_buf = self.gpu.buf_from_f32(x);
+            let out = match n {
+                1 => self.gpu.q8_0_matvec(w, 0, &x_buf, rows, cols),
+                _ => self.gpu.q8_0_matmul(w, 0, &x_buf, rows, cols, n),
+            };
+            self.gpu.read_f32(&out, rows * n).to_vec()
+        } else {
+            let w = self.cpu_weights(&l.weight.name)?;
+            x.chunks_exact(cols).flat_map(|xi| w.chunks_exact(cols).map(|r| dot(r, xi)).collect::<Vec<_>>()).collect()
+        };
+        if let Some(b) = &l.bias {
+            let b = self.cpu_weights(&b.name)?;
+            out.chunks_exact_mut(rows).for_each(|r| add(r, &b));
+        }
+        Ok(out)
+    }
+
+    fn layer_norm(&mut self, norm: &LayerNorm, x: &[f32]) -> Result<Vec<f32>> {
+        let (w, b) = (self.cpu_weights(&norm.weight.name)?, self.cpu_weights(&norm.bias.name)?);
+        Ok(x.chunks_exact(w.len()).flat_map(|r| layer_norm(r, &w, &b, self.arch.layer_norm_eps)).collect())
+    }
+
+    fn cpu_weights(&mut self, name: &str) -> Result<Arc<[f32]>> {
+        if let Some(w) = self.cpu_cache.get(name) {
+            return Ok(w.clone());
+        }
+        let w: Arc<[f32]> = self.store.dequant(name)?.into();
+        self.cpu_cache.insert(name.to_string(), w.clone());
+        Ok(w)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// CPU math
+// ---------------------------------------------------------------------------
+
+fn add(x: &mut [f32], y: &[f32]) {
+    x.iter_mut().zip(y).for_each(|(a, b)| *a += b);
+}
+
+pub(crate) fn layer_norm(x: &[f32], w: &[f32], b: &[f32], eps: f32) -> Vec<f32> {
+    let n = x.len() as f32;
+    let mean = x.iter().sum::<f32>() / n;
+    let var = x.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / n;
+    let inv = 1.0 / (var + eps).sqrt();
+    x.iter().zip(w).zip(b).map(|((xi, wi), bi)| (xi - mean) * inv * wi + bi).collect()
+}
+
+/// tanh approximation, as in ggml.
+pub(crate) fn gelu(x: f32) -> f32 {
+    const SQRT_2_OVER_PI: f32 = 0.797_884_6;
+    0.5 * x * (1.0 + (SQRT_2_OVER_PI * (x + 0.044_715 * x * x * x)).tanh())
+}
+
+fn silu(x: f32) -> f32 {
+    x / (1.0 + (-x).exp())
+}
+
+/// NeoX RoPE: dim `i` pairs with `i + head_dim/2` rather than `i + 1`.
+pub(crate) fn rope_neox(x: &mut [f32], n_heads: usize, head_dim: usize, pos: usize, base: f32) {
+    let half = head_dim / 2;
+    for h in 0..n_heads {
+        let off = h * head_dim;
+        for i in 0..half {
+            let theta = pos as f32 / base.powf(2.0 * i as f32 / head_dim as f32);
+            let (s, c) = theta.sin_cos();
+            let (x0, x1) = (x[off + i], x[off + i + half]);
+            x[off + i] = x0 * c - x1 * s;
+            x[off + i + half] = x0 * s + x1 * c;
+        }
+    }
+}
+
@@ -0,0 +1,90 @@
+//! Prefill GEMMs on the CPU through Apple's Accelerate (`cblas_sgemm`).
+//!
+//! On M-series chips Accelerate runs single-precision GEMM on the AMX
+//! units, which beats a Metal dispatch once a prompt's batch is large
+//! enough to amortise reading the activations back. Only F32 and F16
+//! weights take this path; F16 ones are widened to f32 on first use and
+//! kept, so each costs twice its file size in memory. Built without the
+//! `accelerate` feature (or off macOS) `sgemm` is a plain loop, kept for
+//! checking results, and `LlamaModel::use_accelerate` refuses.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::tensor::{GGML_F16, GGML_F32, TensorStore};
+
+/// Whether `sgemm` calls Accelerate in this build.
+pub const ACCELERATE: bool = cfg!(all(feature = "accelerate", target_os = "macos"));
+
+/// Which matmuls go to the CPU, and their weights as f32.
+pub struct CpuGemm {
+    /// Batches smaller than this (decode steps, short prompts) stay on the GPU.
+    pub min_batch: usize,
+    weights: HashMap<String, Vec<f32>>,
+}
+
+impl CpuGemm {
+    pub fn new(min_batch: usize) -> Self {
+        Self { min_batch, weights: HashMap::new() }
+    }
+
+    /// `name` as row-major f32, widened on first use; `None` for any type
+    /// but F32 and F16.
+    pub fn weights(&mut self, store: &TensorStore, name: &str) -> Result<Option<&[f32]>> {
+        if !self.weights.contains_key(name) {
+            if !matches!(store.meta(name)?.kind, GGML_F32 | GGML_F16) {
+                return Ok(None);
+            }
+            self.weights.insert(name.to_string(), store.dequant(name)?);
+        }
+        Ok(self.weights.get(name).map(Vec::as_slice))
+    }
+
+    /// Bytes held by widened weights.
+    pub fn bytes(&self) -> usize {
+        self.weights.values().map(|w| w.len() * 4).sum()
+    }
+}
+
+/// `x` (`[batch, k]`) times `w` (`[n, k]`, one output per row) transposed:
+/// the `[batch, n]` result a matmul kernel would write.
+pub fn sgemm(x: &[f32], w: &[f32], batch: usize, n: usize, k: usize) -> Vec<f32> {
+    assert!(x.len() >= batch * k && w.len() >= n * k, "sgemm: inputs shorter than [{batch}, {k}] x [{n}, {k}]");
+    let mut out = vec![0.0f32; batch * n];
+    #[cfg(all(feature = "accelerate", target_os = "macos"))]
+    unsafe {
+        ffi::cblas_sgemm(
+            ffi::ROW_MAJOR, ffi::NO_TRANS, ffi::TRANS,
+            batch as i32, n as i32, k as i32,
+            1.0, x.as_ptr(), k as i32, w.as_ptr(), k as i32,
+            0.0, out.as_mut_ptr(), n as i32,
+        );
+    }
+    #[cfg(not(all(feature = "accelerate", target_os = "macos")))]
+    for (row, xr) in out.chunks_exact_mut(n).zip(x.chunks_exact(k)) {
+        for (o, wr) in row.iter_mut().zip(w.chunks_exact(k)) {
+            *o = crate::cpu::dot(xr, wr);
+        }
+    }
+    out
+}
+
+#[cfg(all(feature = "accelerate", target_os = "macos"))]
+mod ffi {
+    /// `CBLAS_ORDER` and `CBLAS_TRANSPOSE`.
+    pub const ROW_MAJOR: i32 = 101;
+    pub const NO_TRANS: i32 = 111;
+    pub const TRANS: i32 = 112;
+
+    #[link(name = "Accelerate", kind = "framework")]
+    unsafe extern "C" {
+        #[allow(clippy::too_many_arguments)]
+        pub fn cblas_sgemm(
+            order: i32, trans_a: i32, trans_b: i32,
+            m: i32, n: i32, k: i32,
+            alpha: f32, a: *const f32, lda: i32, b: *const f32, ldb: i32,
+            beta: f32, c: *mut f32, ldc: i32,
+        );
+    }
+}
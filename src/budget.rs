@@ -0,0 +1,63 @@
+//! Context budgeting: the context window split into the prompt and a
+//! reserve for the reply, checked before anything is prefilled.
+//!
+//! A prompt that leaves less than `max_tokens` free would run the reply past
+//! the window the model was trained on, so `run` drops the oldest prompt
+//! tokens and `chat` shifts out (or summarizes) old turns until the reserve
+//! fits. What cannot be made to fit is an error up front, never a reply cut
+//! off halfway.
+
+use anyhow::{Result, ensure};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContextBudget {
+    /// The whole window, prompt plus reply.
+    pub ctx: usize,
+    /// Tokens held back for the reply: the request's `max_tokens`.
+    pub reserve: usize,
+}
+
+impl ContextBudget {
+    pub fn new(ctx: usize, reserve: usize) -> Self {
+        Self { ctx, reserve }
+    }
+
+    /// The longest prompt that still leaves the whole reserve free.
+    pub fn prompt_limit(&self) -> usize {
+        self.ctx.saturating_sub(self.reserve)
+    }
+
+    pub fn fits(&self, prompt_len: usize) -> bool {
+        prompt_len <= self.prompt_limit()
+    }
+
+    /// Drop the oldest tokens after the first `keep` (BOS, a system prompt)
+    /// until `tokens` fits. Returns how many were dropped.
+    pub fn truncate(&self, tokens: &mut Vec<u32>, keep: usize) -> Result<usize> {
+        let limit = self.prompt_limit();
+        if tokens.len() <= limit {
+            return Ok(0);
+        }
+        ensure!(
+            keep < limit,
+            "the first {keep} prompt tokens alone leave no room for {} reply tokens in a context of {}",
+            self.reserve,
+            self.ctx
+        );
+        let excess = tokens.len() - limit;
+        tokens.drain(keep..keep + excess);
+        Ok(excess)
+    }
+
+    /// Fail before prefill, with the numbers, when `prompt_len` does not fit.
+    pub fn check(&self, prompt_len: usize) -> Result<()> {
+        ensure!(
+            self.fits(prompt_len),
+            "the prompt is {prompt_len} tokens, but a context of {} with {} reserved for the reply leaves {}",
+            self.ctx,
+            self.reserve,
+            self.prompt_limit()
+        );
+        Ok(())
+    }
+}
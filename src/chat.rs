@@ -0,0 +1,559 @@
+//! Built-in chat templates for the common model families.
+//!
+//! GGUFs usually embed a Jinja template in `tokenizer.chat_template`. LLMetal
+//! does not evaluate Jinja; it recognises which family an embedded template
+//! belongs to and renders that family's format itself. `--chat-format`
+//! overrides the choice, and is the only option for a GGUF with no template.
+//!
+//! BOS is not part of any rendered string: the tokenizer adds it.
+
+use std::path::Path;
+
+use anyhow::{Context, Result, bail, ensure};
+use serde_json::{Value, json};
+
+use crate::budget::ContextBudget;
+use crate::events::GenerationEvent;
+use crate::model::{GenerateOptions, Generator, KvSession, LlamaModel};
+use crate::sampler::SamplerConfig;
+use crate::tokenizer::{PromptTokenizer, SpecialTokens};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+    /// A tool's output, fed back to the model after it called the tool.
+    Tool,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::Tool => "tool",
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "system" => Ok(Role::System),
+            "user" => Ok(Role::User),
+            "assistant" => Ok(Role::Assistant),
+            "tool" => Ok(Role::Tool),
+            _ => bail!("unknown role '{s}'"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub role: Role,
+    pub content: String,
+}
+
+impl Message {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self { role, content: content.into() }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChatFormat {
+    /// `<|im_start|>role\n...<|im_end|>`: Qwen, Yi, OpenHermes and most fine-tunes.
+    ChatMl,
+    /// `<|start_header_id|>role<|end_header_id|>\n\n...<|eot_id|>`.
+    Llama3,
+    /// `[INST] ... [/INST]`, system text folded into the first user turn.
+    Mistral,
+    /// `<start_of_turn>user|model\n...<end_of_turn>`, no system role.
+    Gemma,
+    /// Phi-3: `<|user|>\n...<|end|>`.
+    Phi,
+}
+
+impl ChatFormat {
+    pub const ALL: [ChatFormat; 5] = [Self::ChatMl, Self::Llama3, Self::Mistral, Self::Gemma, Self::Phi];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::ChatMl => "chatml",
+            Self::Llama3 => "llama3",
+            Self::Mistral => "mistral",
+            Self::Gemma => "gemma",
+            Self::Phi => "phi",
+        }
+    }
+
+    /// The family an embedded Jinja template belongs to, by its marker tokens.
+    pub fn detect(template: &str) -> Option<Self> {
+        [
+            ("<|im_start|>", Self::ChatMl),
+            ("<|start_header_id|>", Self::Llama3),
+            ("<start_of_turn>", Self::Gemma),
+            ("<|assistant|>", Self::Phi),
+            ("[INST]", Self::Mistral),
+        ]
+        .into_iter()
+        .find_map(|(marker, format)| template.contains(marker).then_some(format))
+    }
+
+    /// `messages` as one prompt string. With `add_generation_prompt`, ends with
+    /// the opening of an assistant turn so the model writes the reply.
+    pub fn render(self, messages: &[Message], add_generation_prompt: bool) -> String {
+        self.render_parts(messages, add_generation_prompt).into_iter().map(|(text, _)| text).collect()
+    }
+
+    /// `render`, cut into template markup (`None`) and message content tagged
+    /// with its role, so content can be tokenized under a different
+    /// special-token policy than the markup around it.
+    pub fn render_parts(self, messages: &[Message], add_generation_prompt: bool) -> Vec<(String, Option<Role>)> {
+        // Mistral and Gemma have no system role: fold it into the first user turn.
+        let fold_system = matches!(self, Self::Mistral | Self::Gemma);
+        let mut system = messages
+            .iter()
+            .find(|m| fold_system && m.role == Role::System)
+            .map(|m| m.content.as_str())
+            .filter(|s| !s.is_empty());
+        let mut out = Vec::new();
+        for m in messages.iter().filter(|m| !(fold_system && m.role == Role::System)) {
+            out.push((self.open(m.role.as_str()), None));
+            if let Some(sys) = system.filter(|_| m.role == Role::User) {
+                system = None;
+                out.push((sys.to_string(), Some(Role::System)));
+                out.push(("\n\n".to_string(), None));
+            }
+            out.push((m.content.clone(), Some(m.role)));
+            out.push((self.close(m.role.as_str()).to_string(), None));
+        }
+        if add_generation_prompt {
+            out.push((self.assistant_prefix().to_string(), None));
+        }
+        out.retain(|(text, _)| !text.is_empty());
+        out
+    }
+
+    /// Tokens that close an assistant turn in this format, most specific first.
+    pub fn end_of_turn(self) -> &'static [&'static str] {
+        match self {
+            Self::ChatMl => &["<|im_end|>", "<|endoftext|>"],
+            Self::Llama3 => &["<|eot_id|>", "<|end_of_text|>"],
+            Self::Mistral => &["</s>"],
+            Self::Gemma => &["<end_of_turn>", "<eos>"],
+            Self::Phi => &["<|end|>", "<|endoftext|>"],
+        }
+    }
+
+    /// Markup before a turn's content. Tool output goes where each family's
+    /// tool-use template puts it: Qwen's `<tool_response>` inside a user
+    /// turn, Llama 3's `ipython` role, Mistral's `[TOOL_RESULTS]`, and a
+    /// plain user turn for Gemma and Phi, which have no tool role.
+    fn open(self, role: &str) -> String {
+        match (self, role) {
+            (Self::ChatMl, "tool") => return "<|im_start|>user\n<tool_response>\n".to_string(),
+            (Self::Llama3, "tool") => return "<|start_header_id|>ipython<|end_header_id|>\n\n".to_string(),
+            (Self::Mistral, "tool") => return "[TOOL_RESULTS] ".to_string(),
+            (Self::Gemma | Self::Phi, "tool") => return self.open("user"),
+            _ => {}
+        }
+        match self {
+            Self::ChatMl => format!("<|im_start|>{role}\n"),
+            Self::Llama3 => format!("<|start_header_id|>{role}<|end_header_id|>\n\n"),
+            Self::Phi => format!("<|{role}|>\n"),
+            Self::Mistral if role == "user" => "[INST] ".to_string(),
+            Self::Mistral => String::new(),
+            Self::Gemma => {
+                let role = if role == "assistant" { "model" } else { role };
+                format!("<start_of_turn>{role}\n")
+            }
+        }
+    }
+
+    /// Markup after a turn's content.
+    fn close(self, role: &str) -> &'static str {
+        match (self, role) {
+            (Self::ChatMl, "tool") => return "\n</tool_response><|im_end|>\n",
+            (Self::Mistral, "tool") => return " [/TOOL_RESULTS]",
+            _ => {}
+        }
+        match self {
+            Self::ChatMl => "<|im_end|>\n",
+            Self::Llama3 => "<|eot_id|>",
+            Self::Phi => "<|end|>\n",
+            Self::Mistral if role == "user" => " [/INST]",
+            Self::Mistral => "</s>",
+            Self::Gemma => "<end_of_turn>\n",
+        }
+    }
+
+    fn assistant_prefix(self) -> &'static str {
+        match self {
+            Self::ChatMl => "<|im_start|>assistant\n",
+            Self::Llama3 => "<|start_header_id|>assistant<|end_header_id|>\n\n",
+            Self::Phi => "<|assistant|>\n",
+            Self::Mistral => "",
+            Self::Gemma => "<start_of_turn>model\n",
+        }
+    }
+}
+
+impl std::str::FromStr for ChatFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match Self::ALL.into_iter().find(|f| f.name() == s) {
+            Some(f) => Ok(f),
+            None => {
+                let names: Vec<&str> = Self::ALL.iter().map(|f| f.name()).collect();
+                bail!("unknown chat format '{s}' (expected {})", names.join(", "))
+            }
+        }
+    }
+}
+
+/// Stop tokens for a chat: the format's end-of-turn tokens that exist in
+/// `vocab`, plus whatever EOS/EOT ids the GGUF declares.
+pub fn stop_tokens(format: ChatFormat, vocab: &[String], declared: &[u32]) -> Vec<u32> {
+    let mut ids: Vec<u32> = format
+        .end_of_turn()
+        .iter()
+        .filter_map(|t| vocab.iter().position(|v| v == t).map(|i| i as u32))
+        .collect();
+    for &id in declared {
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    ids
+}
+
+/// `--chat-format` resolution: an explicit name wins; `auto` uses the family
+/// of the GGUF's embedded template and fails if there is none to recognise.
+pub fn resolve(flag: &str, embedded: Option<&str>) -> Result<ChatFormat> {
+    if flag != "auto" {
+        return flag.parse();
+    }
+    match embedded {
+        Some(t) => match ChatFormat::detect(t) {
+            Some(f) => Ok(f),
+            None => bail!("unrecognised tokenizer.chat_template; pick one with --chat-format"),
+        },
+        None => bail!("GGUF has no tokenizer.chat_template; pick one with --chat-format"),
+    }
+}
+
+/// Introduces `ChatHistory::summary` in the system prompt.
+const SUMMARY_HEADING: &str = "Summary of the conversation so far:";
+
+/// The instruction that turns shifted-out turns into a summary.
+const SUMMARIZE_PROMPT: &str = "Summarize the conversation below in a few sentences. \
+Keep the names, facts, numbers and decisions a later reply may need.";
+
+/// Longest summary a `Conversation` generates.
+const SUMMARY_TOKENS: usize = 160;
+
+/// What condenses old turns into `ChatHistory::summary`.
+pub enum Summarizer {
+    /// The conversation's own model, asked to summarize.
+    Model,
+    /// Any other function, given the earlier summary (if any) and the turns
+    /// to fold in; the string it returns replaces both.
+    Callback(SummarizeFn),
+}
+
+/// `Summarizer::Callback`'s function.
+pub type SummarizeFn = Box<dyn FnMut(Option<&str>, &[Message]) -> Result<String>>;
+
+/// A conversation's messages. The system prompt is held apart from the turns
+/// so it always renders first and no context shift can drop it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChatHistory {
+    pub system: Option<String>,
+    /// What the turns folded away so far said, when the conversation has a
+    /// `Summarizer`; it renders as part of the system prompt.
+    pub summary: Option<String>,
+    pub turns: Vec<Message>,
+}
+
+impl ChatHistory {
+    /// System prompt (if any) followed by every turn, ready for `render`.
+    pub fn messages(&self) -> Vec<Message> {
+        let system = match (&self.system, &self.summary) {
+            (Some(system), Some(summary)) => Some(format!("{system}\n\n{SUMMARY_HEADING} {summary}")),
+            (None, Some(summary)) => Some(format!("{SUMMARY_HEADING} {summary}")),
+            (system, None) => system.clone(),
+        };
+        let system = system.map(|s| Message::new(Role::System, s));
+        system.into_iter().chain(self.turns.iter().cloned()).collect()
+    }
+
+    /// Context shift: drop the oldest exchanges until `fits` accepts the
+    /// rendered prompt. The system prompt and the latest turn always stay.
+    /// Returns how many turns were dropped.
+    pub fn shift_to_fit(&mut self, format: ChatFormat, fits: impl Fn(&str) -> bool) -> usize {
+        let mut dropped = 0;
+        while self.turns.len() > 1 && !fits(&format.render(&self.messages(), true)) {
+            self.turns.remove(0);
+            dropped += 1;
+            // Keep the history starting on a user turn.
+            while self.turns.len() > 1 && self.turns[0].role != Role::User {
+                self.turns.remove(0);
+                dropped += 1;
+            }
+        }
+        dropped
+    }
+    /// Drop the last exchange: the trailing assistant reply, if any, and the
+    /// user turn before it. False when there is nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        if self.turns.last().is_some_and(|m| m.role == Role::Assistant) {
+            self.turns.pop();
+        }
+        self.turns.pop().is_some()
+    }
+
+    /// Session file: `{"system": ..., "summary": ..., "turns": [{"role",
+    /// "content"}, ...]}`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let turns: Vec<Value> = self.turns.iter().map(|m| json!({ "role": m.role.as_str(), "content": m.content })).collect();
+        let session = json!({ "system": self.system, "summary": self.summary, "turns": turns });
+        std::fs::write(path, serde_json::to_string_pretty(&session)?).with_context(|| format!("write {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let session: Value = serde_json::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+        let turns = session["turns"]
+            .as_array()
+            .context("session has no \"turns\" array")?
+            .iter()
+            .map(|t| {
+                let role = t["role"].as_str().context("turn without a role")?.parse()?;
+                let content = t["content"].as_str().context("turn without content")?;
+                Ok(Message::new(role, content))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            system: session["system"].as_str().map(str::to_string),
+            summary: session["summary"].as_str().map(str::to_string),
+            turns,
+        })
+    }
+}
+
+/// Multi-turn chat over one model: history, template, context window and the
+/// KV cache kept between turns. Each `send` renders the whole history, but
+/// only tokens past the prefix already in the cache are prefilled.
+pub struct Conversation {
+    pub history: ChatHistory,
+    pub format: ChatFormat,
+    /// Token budget for prompt plus reply; older turns are shifted out past it.
+    pub ctx: usize,
+    /// Fold old turns into `ChatHistory::summary` instead of dropping them
+    /// outright when they no longer fit.
+    pub summarizer: Option<Summarizer>,
+    /// With a summarizer, also fold once the rendered history passes this
+    /// many tokens, down to half of it, so a long chat stays short well
+    /// before the context fills.
+    pub summarize_after: Option<usize>,
+    tokenizer: PromptTokenizer,
+    session: KvSession,
+    /// Added to every request's `stop_tokens` so replies end at the turn marker.
+    pub stop_tokens: Vec<u32>,
+    /// How special-token text in user turns is encoded. `Literal` by default:
+    /// a user who types `<|im_end|>` gets those characters, not the marker.
+    pub user_special: SpecialTokens,
+    /// Leave special tokens out of `Token` event text and the stored reply
+    /// (the default); clear it to see them while debugging a template.
+    pub skip_special_tokens: bool,
+    last: TurnStats,
+}
+
+/// What the last reply cost beyond generation itself.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TurnStats {
+    /// Prompt tokens taken from the KV cache instead of prefilled.
+    pub reused_tokens: usize,
+    /// Old turns shifted out to fit `ctx`.
+    pub dropped_turns: usize,
+    /// Old turns folded into the summary instead.
+    pub summarized_turns: usize,
+}
+
+impl Conversation {
+    pub fn new(model: &LlamaModel, format: ChatFormat, tokenizer: PromptTokenizer) -> Self {
+        Self::with_limits(format, tokenizer, model.arch.ctx_train, &model.declared_stop_tokens())
+    }
+
+    /// `new` for a model that is not in this process (a daemon's): its
+    /// training context and declared stop tokens are passed in instead.
+    pub fn with_limits(format: ChatFormat, tokenizer: PromptTokenizer, ctx: usize, declared_stop_tokens: &[u32]) -> Self {
+        Self {
+            history: ChatHistory::default(),
+            format,
+            ctx,
+            summarizer: None,
+            summarize_after: None,
+            stop_tokens: stop_tokens(format, tokenizer.vocab(), declared_stop_tokens),
+            user_special: SpecialTokens::Literal,
+            skip_special_tokens: true,
+            tokenizer,
+            session: KvSession::default(),
+            last: TurnStats::default(),
+        }
+    }
+
+    /// Add a user turn and generate the reply, which joins the history.
+    pub fn send(
+        &mut self,
+        model: &mut dyn Generator,
+        user: &str,
+        opts: &GenerateOptions,
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<String> {
+        self.history.turns.push(Message::new(Role::User, user));
+        self.reply(model, opts, on_event)
+    }
+
+    /// Replace the last reply with a new one (pass a different seed in `opts`).
+    pub fn regenerate(
+        &mut self,
+        model: &mut dyn Generator,
+        opts: &GenerateOptions,
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<String> {
+        if self.history.turns.last().is_some_and(|m| m.role == Role::Assistant) {
+            self.history.turns.pop();
+        }
+        ensure!(!self.history.turns.is_empty(), "nothing to regenerate");
+        self.reply(model, opts, on_event)
+    }
+
+    /// Generate the next assistant turn for the history as it stands, e.g.
+    /// once tool results have been added after the last reply.
+    pub fn respond(
+        &mut self,
+        model: &mut dyn Generator,
+        opts: &GenerateOptions,
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<String> {
+        ensure!(self.history.turns.last().is_some_and(|m| m.role != Role::Assistant), "nothing to respond to");
+        self.reply(model, opts, on_event)
+    }
+
+    pub fn last_turn(&self) -> TurnStats {
+        self.last
+    }
+
+    fn reply(
+        &mut self,
+        model: &mut dyn Generator,
+        opts: &GenerateOptions,
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<String> {
+        let budget = ContextBudget::new(self.ctx, opts.max_new);
+        self.last.summarized_turns = 0;
+        if self.summarizer.is_some() {
+            let tokenizer = &self.tokenizer;
+            let len = |p: &str| tokenizer.tokenize_bos(p).len();
+            let target = match self.summarize_after {
+                Some(after) if len(&self.format.render(&self.history.messages(), true)) > after => {
+                    (after / 2).min(budget.prompt_limit())
+                }
+                _ => budget.prompt_limit(),
+            };
+            let n = self.history.clone().shift_to_fit(self.format, |p| len(p) <= target);
+            let summary = if n > 0 { self.summarize_turns(model, n, opts)? } else { None };
+            if let Some(summary) = summary {
+                self.history.turns.drain(..n);
+                self.history.summary = Some(summary);
+                self.last.summarized_turns = n;
+            }
+        }
+        let tokenizer = &self.tokenizer;
+        self.last.dropped_turns = self.history.shift_to_fit(self.format, |p| budget.fits(tokenizer.tokenize_bos(p).len()));
+        let tokens = self.encode(&self.history.messages());
+        // The latest turn alone can still be too long; say so before prefill.
+        budget.check(tokens.len())?;
+        let mut opts = opts.clone();
+        for &id in &self.stop_tokens {
+            if !opts.stop_tokens.contains(&id) {
+                opts.stop_tokens.push(id);
+            }
+        }
+        let mut text = String::new();
+        let (tokenizer, skip) = (&self.tokenizer, self.skip_special_tokens);
+        self.last.reused_tokens = model.generate_cached(&mut self.session, &tokens, &opts, tokenizer.vocab(), &mut |event| {
+            match event {
+                GenerationEvent::Token { id, logprob, .. } => {
+                    let t = tokenizer.decode(&[id], skip);
+                    text.push_str(&t);
+                    on_event(GenerationEvent::Token { id, text: t, logprob });
+                }
+                event => on_event(event),
+            }
+        })?;
+        let text = text.trim().to_string();
+        self.history.turns.push(Message::new(Role::Assistant, text.as_str()));
+        Ok(text)
+    }
+
+    /// `messages` rendered and tokenized, user turns with `user_special`.
+    /// Tool output is as untrusted as user text and is encoded the same way.
+    fn encode(&self, messages: &[Message]) -> Vec<u32> {
+        let rendered = self.format.render_parts(messages, true);
+        let parts: Vec<(&str, SpecialTokens)> = rendered
+            .iter()
+            .map(|(text, role)| {
+                let special = match role {
+                    Some(Role::User | Role::Tool) => self.user_special,
+                    _ => SpecialTokens::Parse,
+                };
+                (text.as_str(), special)
+            })
+            .collect();
+        self.tokenizer.encode_bos(&parts)
+    }
+
+    /// The earlier summary and the oldest `n` turns, condensed by the
+    /// summarizer. `None` when a model summary would not fit the context
+    /// itself, and the turns are simply dropped.
+    fn summarize_turns(&mut self, model: &mut dyn Generator, n: usize, opts: &GenerateOptions) -> Result<Option<String>> {
+        if let Some(Summarizer::Callback(summarize)) = &mut self.summarizer {
+            let summary = summarize(self.history.summary.as_deref(), &self.history.turns[..n])?;
+            return Ok(Some(summary.trim().to_string()));
+        }
+        let mut transcript = self.history.summary.iter().map(|s| format!("{SUMMARY_HEADING} {s}\n\n")).collect::<String>();
+        for m in &self.history.turns[..n] {
+            transcript.push_str(&format!("{}: {}\n", m.role.as_str(), m.content));
+        }
+        let tokens = self.encode(&[Message::new(Role::System, SUMMARIZE_PROMPT), Message::new(Role::User, transcript)]);
+        if !ContextBudget::new(self.ctx, SUMMARY_TOKENS).fits(tokens.len()) {
+            return Ok(None);
+        }
+        let opts = GenerateOptions {
+            max_new: SUMMARY_TOKENS,
+            sampling: SamplerConfig { seed: opts.sampling.seed, ..SamplerConfig::default() },
+            stop_tokens: self.stop_tokens.clone(),
+            cancel: opts.cancel.clone(),
+            ..GenerateOptions::default()
+        };
+        // A session of its own, so the conversation's cached prefix survives.
+        let mut session = KvSession::default();
+        let mut summary = String::new();
+        let tokenizer = &self.tokenizer;
+        model.generate_cached(&mut session, &tokens, &opts, tokenizer.vocab(), &mut |event| {
+            if let GenerationEvent::Token { id, .. } = event {
+                summary.push_str(&tokenizer.decode(&[id], true));
+            }
+        })?;
+        let summary = summary.trim();
+        Ok((!summary.is_empty()).then(|| summary.to_string()))
+    }
+}
@@ -0,0 +1,88 @@
+//! Constrained choice: decoding that can only spell out one of a fixed list
+//! of strings (`run --choice yes --choice no`), for classifying or routing
+//! with a plain LLM prompt.
+//!
+//! Each step masks every token that does not continue some candidate and
+//! takes the most likely of the rest, so the output is always exactly one
+//! candidate. The log-probabilities come from the full vocabulary, before
+//! masking, so a low total means the model wanted to say something else.
+//! If one candidate is a prefix of another (`yes` and `yes, but`), the
+//! shorter one wins when an end token (`</s>` or a stop token) is more
+//! likely than the best continuation.
+
+use anyhow::{Result, bail, ensure};
+
+/// `</s>`, which always ends a shorter candidate.
+const EOS: u32 = 2;
+
+/// The candidates as token sequences.
+#[derive(Clone, Debug)]
+pub struct Choices {
+    seqs: Vec<Vec<u32>>,
+}
+
+/// What was chosen.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Choice {
+    /// Position in the candidate list.
+    pub index: usize,
+    /// Summed log-probability of the candidate's tokens.
+    pub logprob: f32,
+}
+
+/// The next move of a constrained decode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Step {
+    Token { id: u32, logprob: f32 },
+    Done(usize),
+}
+
+impl Choices {
+    pub fn new(seqs: Vec<Vec<u32>>) -> Result<Self> {
+        ensure!(!seqs.is_empty(), "no candidates to choose from");
+        for (i, seq) in seqs.iter().enumerate() {
+            ensure!(!seq.is_empty(), "candidate {i} has no tokens");
+            if let Some(j) = seqs[..i].iter().position(|s| s == seq) {
+                bail!("candidates {j} and {i} tokenize the same");
+            }
+        }
+        Ok(Self { seqs })
+    }
+
+    pub fn len(&self) -> usize {
+        self.seqs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seqs.is_empty()
+    }
+
+    /// Tokens that extend `prefix` towards some candidate, ascending.
+    pub fn next_tokens(&self, prefix: &[u32]) -> Vec<u32> {
+        let mut next: Vec<u32> =
+            self.seqs.iter().filter(|s| s.len() > prefix.len() && s.starts_with(prefix)).map(|s| s[prefix.len()]).collect();
+        next.sort_unstable();
+        next.dedup();
+        next
+    }
+
+    /// The candidate `prefix` spells out exactly.
+    pub fn complete(&self, prefix: &[u32]) -> Option<usize> {
+        self.seqs.iter().position(|s| s == prefix)
+    }
+
+    /// The move after `prefix`, given the next position's log-probabilities.
+    pub fn step(&self, prefix: &[u32], logprobs: &[f32], end_tokens: &[u32]) -> Step {
+        let lp = |id: u32| logprobs.get(id as usize).copied().unwrap_or(f32::NEG_INFINITY);
+        let best = self.next_tokens(prefix).into_iter().max_by(|&a, &b| lp(a).total_cmp(&lp(b)).then(b.cmp(&a)));
+        match (self.complete(prefix), best) {
+            (Some(i), None) => Step::Done(i),
+            (Some(i), Some(id)) => {
+                let end = std::iter::once(EOS).chain(end_tokens.iter().copied()).map(lp).fold(f32::NEG_INFINITY, f32::max);
+                if end > lp(id) { Step::Done(i) } else { Step::Token { id, logprob: lp(id) } }
+            }
+            (None, Some(id)) => Step::Token { id, logprob: lp(id) },
+            (None, None) => unreachable!("every prefix the steps build leads to a candidate"),
+        }
+    }
+}
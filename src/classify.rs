@@ -0,0 +1,85 @@
+//! Sequence classification (`llmetal classify`): moderation, routing and
+//! sentiment models exported with a score head.
+//!
+//! The head is `cls.output.weight` (`[n_classes, hidden]`, optional
+//! `cls.output.bias`), the same tensors a classifier reranker has. Decoders
+//! apply it to the last token's normed hidden state; encoders to the
+//! `[CLS]` state after the `cls` pooler. Label names come from
+//! `{arch}.classifier.output_labels` when the converter wrote them.
+//!
+//! Logits become probabilities with a softmax over the classes, or one
+//! sigmoid per class for multi-label models, where several can apply at
+//! once. A single-logit head is always a sigmoid.
+
+use std::collections::BTreeMap;
+
+use crate::cpu::dot;
+use crate::gguf::MetaValue;
+use crate::rerank::sigmoid;
+use crate::sampler::softmax;
+
+/// A decoder's `cls.output` head, dequantized.
+#[derive(Clone, Debug)]
+pub struct ClassifierHead {
+    /// `[n_classes, hidden]`, row-major.
+    pub weight: Vec<f32>,
+    pub bias: Option<Vec<f32>>,
+    pub labels: Vec<String>,
+}
+
+impl ClassifierHead {
+    /// One logit per class for a pooled hidden state.
+    pub fn logits(&self, x: &[f32]) -> Vec<f32> {
+        self.weight
+            .chunks_exact(x.len())
+            .enumerate()
+            .map(|(i, row)| dot(row, x) + self.bias.as_ref().map_or(0.0, |b| b[i]))
+            .collect()
+    }
+}
+
+/// One class's score.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prediction {
+    pub label: String,
+    pub probability: f32,
+    pub logit: f32,
+}
+
+/// `{arch}.classifier.output_labels`, or `LABEL_0`.. as transformers names
+/// them when a config has none.
+pub fn labels(metadata: &BTreeMap<String, MetaValue>, n_classes: usize) -> Vec<String> {
+    let arch = metadata.get("general.architecture").and_then(MetaValue::as_str).unwrap_or("llama");
+    let named: Vec<String> = metadata
+        .get(&format!("{arch}.classifier.output_labels"))
+        .and_then(MetaValue::as_array)
+        .map(|a| a.iter().filter_map(|v| v.as_str().map(str::to_string)).collect())
+        .unwrap_or_default();
+    if named.len() == n_classes {
+        named
+    } else {
+        (0..n_classes).map(|i| format!("LABEL_{i}")).collect()
+    }
+}
+
+/// Every class with its probability, most likely first; ties keep class
+/// order.
+pub fn predictions(logits: &[f32], labels: &[String], multi_label: bool) -> Vec<Prediction> {
+    let probs = if multi_label || logits.len() == 1 {
+        logits.iter().map(|&l| sigmoid(l)).collect()
+    } else {
+        softmax(logits)
+    };
+    let mut out: Vec<Prediction> = logits
+        .iter()
+        .zip(probs)
+        .enumerate()
+        .map(|(i, (&logit, probability))| Prediction {
+            label: labels.get(i).cloned().unwrap_or_else(|| format!("LABEL_{i}")),
+            probability,
+            logit,
+        })
+        .collect();
+    out.sort_by(|a, b| b.probability.total_cmp(&a.probability));
+    out
+}
@@ -0,0 +1,138 @@
+//! Commit messages from a staged diff (`llmetal commit-msg`).
+//!
+//! `summarize` reads a unified diff (`git diff --staged`). It counts added
+//! and removed lines per file and takes an excerpt that fits a character
+//! budget, so one huge file cannot crowd out the rest: every file's header
+//! is kept and each gets an even share of the budget for its hunks.
+//! `prompt` asks for a Conventional Commits message with the file list and
+//! excerpt. `clean` checks what the model wrote: it must open with
+//! `type(scope)!: subject` of a known type, in lower case, and the subject
+//! gets a trimmed trailing period. A wrapped body is kept. Fences and
+//! preambles such as "Commit message:" are taken off first.
+
+use std::fmt::Write as _;
+
+/// The Conventional Commits types a header may use.
+pub const TYPES: [&str; 11] = ["feat", "fix", "docs", "style", "refactor", "perf", "test", "build", "ci", "chore", "revert"];
+
+/// Longest subject line `prompt` asks for.
+pub const SUBJECT_MAX: usize = 72;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileChange {
+    pub path: String,
+    pub added: usize,
+    pub removed: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiffSummary {
+    pub files: Vec<FileChange>,
+    /// The diff cut to the budget, with a note where lines were left out.
+    pub excerpt: String,
+}
+
+/// Per-file counts and an excerpt of at most about `budget` characters.
+pub fn summarize(diff: &str, budget: usize) -> DiffSummary {
+    // (header lines, hunk lines) per file.
+    let mut sections: Vec<(Vec<&str>, Vec<&str>)> = Vec::new();
+    let mut files: Vec<FileChange> = Vec::new();
+    for line in diff.lines() {
+        if let Some(rest) = line.strip_prefix("diff --git ") {
+            let path = rest.rsplit_once(" b/").map_or(rest, |(_, b)| b);
+            files.push(FileChange { path: path.to_string(), ..FileChange::default() });
+            sections.push((vec![line], Vec::new()));
+            continue;
+        }
+        let (Some(file), Some((header, hunks))) = (files.last_mut(), sections.last_mut()) else { continue };
+        if hunks.is_empty() && !line.starts_with("@@") {
+            header.push(line);
+            continue;
+        }
+        if line.starts_with('+') && !line.starts_with("+++") {
+            file.added += 1;
+        } else if line.starts_with('-') && !line.starts_with("---") {
+            file.removed += 1;
+        }
+        hunks.push(line);
+    }
+    let headers: usize = sections.iter().flat_map(|(h, _)| h).map(|l| l.len() + 1).sum();
+    let share = budget.saturating_sub(headers) / sections.len().max(1);
+    let mut excerpt = String::new();
+    for (header, hunks) in &sections {
+        for line in header {
+            let _ = writeln!(excerpt, "{line}");
+        }
+        let mut used = 0;
+        for (i, line) in hunks.iter().enumerate() {
+            if used + line.len() + 1 > share {
+                let _ = writeln!(excerpt, "[... {} more lines]", hunks.len() - i);
+                break;
+            }
+            used += line.len() + 1;
+            let _ = writeln!(excerpt, "{line}");
+        }
+    }
+    DiffSummary { files, excerpt }
+}
+
+/// The instruction and the staged changes; `hint` is the author's own note
+/// on what the change is for.
+pub fn prompt(summary: &DiffSummary, hint: Option<&str>) -> String {
+    let mut out = format!(
+        "Write a git commit message in the Conventional Commits format for the staged changes below.\n\
+         - First line: <type>(<optional scope>): <summary>, at most {SUBJECT_MAX} characters, imperative mood, no period.\n\
+         - <type> is one of: {}.\n\
+         - Then a blank line and a short body saying what changed and why, wrapped at 72 columns. Leave the body out for a trivial change.\n\
+         - Reply with the commit message only.\n",
+        TYPES.join(", ")
+    );
+    if let Some(hint) = hint {
+        let _ = writeln!(out, "\nThe author says: {hint}");
+    }
+    out.push_str("\nFiles changed:\n");
+    for f in &summary.files {
+        let _ = writeln!(out, "  {} (+{} -{})", f.path, f.added, f.removed);
+    }
+    let _ = write!(out, "\nDiff:\n```diff\n{}```", summary.excerpt);
+    out
+}
+
+/// Whether `line` is a Conventional Commits header with a known type.
+pub fn is_header(line: &str) -> bool {
+    let Some((head, subject)) = line.split_once(": ") else { return false };
+    let head = head.strip_suffix('!').unwrap_or(head);
+    let kind = match head.split_once('(') {
+        Some((kind, scope)) => match scope.strip_suffix(')') {
+            Some(scope) if !scope.is_empty() && !scope.contains([' ', '(', ')']) => kind,
+            _ => return false,
+        },
+        None => head,
+    };
+    TYPES.contains(&kind) && !subject.trim().is_empty()
+}
+
+/// `line` trimmed, with everything before its first colon in lower case:
+/// some models write `Feat: ...`.
+fn lowercase_type(line: &str) -> String {
+    let line = line.trim();
+    match line.split_once(':') {
+        Some((head, rest)) => format!("{}:{rest}", head.to_lowercase()),
+        None => line.to_string(),
+    }
+}
+
+/// The message in `reply`, tidied, or `None` when it has no valid header.
+pub fn clean(reply: &str) -> Option<String> {
+    let lines: Vec<&str> = reply.lines().filter(|l| !l.trim_start().starts_with("```")).collect();
+    let start = lines.iter().position(|l| is_header(&lowercase_type(l)))?;
+    let header = lowercase_type(lines[start]);
+    let mut out = header.trim_end_matches('.').to_string();
+    let body: Vec<&str> = lines[start + 1..].iter().map(|l| l.trim_end()).collect();
+    let body = body.join("\n");
+    let body = body.trim();
+    if !body.is_empty() {
+        let _ = write!(out, "\n\n{body}");
+    }
+    Some(out)
+}
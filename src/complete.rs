@@ -0,0 +1,332 @@
+//! Code completion at a cursor for editor plugins (`llmetal complete`):
+//! a fill-in-the-middle prompt from the file around it, and ranked
+//! candidates for what goes there.
+//!
+//! Code models trained for FIM mark the code before the cursor, the code
+//! after it and the hole with three tokens. `FimTokens::find` recognises
+//! the spellings of Qwen2.5-Coder and CodeGemma (`<|fim_prefix|>`),
+//! StarCoder (`<fim_prefix>`), DeepSeek-Coder (`<｜fim▁begin｜>`) and
+//! CodeLlama (`▁<PRE>`). The prompt is prefix-suffix-middle order. A path
+//! comment opens the prefix, in the language's comment syntax. Distant
+//! lines are dropped to fit the token budget, three quarters of it for the
+//! prefix, and the file's text is always encoded literally.
+//!
+//! `candidates` samples one greedy and several tempered fills from one KV
+//! session, so the prompt is prefilled once. It cuts each fill where it
+//! runs into the code after the cursor and ranks them by mean token
+//! log-probability. A `Completer` keeps the model and these settings
+//! together for callers that fill many holes (`llmetal lsp`), and a hole
+//! may also be a selection to rewrite.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result, bail, ensure};
+
+use crate::events::GenerationEvent;
+use crate::model::{GenerateOptions, Generator, KvSession};
+use crate::sampler::SamplerConfig;
+use crate::tokenizer::{PromptTokenizer, SpecialTokens};
+
+/// Prefix, suffix and middle markers, in that order, per model family.
+const FIM_STYLES: [[&str; 3]; 4] = [
+    ["<|fim_prefix|>", "<|fim_suffix|>", "<|fim_middle|>"],
+    ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+    ["<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>"],
+    ["▁<PRE>", "▁<SUF>", "▁<MID>"],
+];
+
+/// Tokens that end a fill besides the model's own EOS: padding, end of
+/// text and the separators of repository-level training.
+const FIM_STOPS: [&str; 9] = [
+    "<|fim_pad|>",
+    "<|endoftext|>",
+    "<|file_sep|>",
+    "<|repo_name|>",
+    "<file_sep>",
+    "<|file_separator|>",
+    "▁<EOT>",
+    "<EOT>",
+    "<｜end▁of▁sentence｜>",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FimTokens {
+    pub prefix: u32,
+    pub suffix: u32,
+    pub middle: u32,
+    pub stops: Vec<u32>,
+}
+
+impl FimTokens {
+    /// The first family whose three markers are all in `vocab`.
+    pub fn find(vocab: &[String]) -> Option<Self> {
+        let id = |t: &str| vocab.iter().position(|v| v == t).map(|i| i as u32);
+        let [prefix, suffix, middle] = FIM_STYLES.iter().find_map(|s| Some([id(s[0])?, id(s[1])?, id(s[2])?]))?;
+        let stops = FIM_STOPS.iter().filter_map(|t| id(t)).collect();
+        Some(Self { prefix, suffix, middle, stops })
+    }
+}
+
+/// A 1-based `line:column` position; the column counts characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::str::FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (line, column) = s.split_once(':').context("expected LINE:COLUMN")?;
+        let cursor = Self { line: line.parse().context("cursor line")?, column: column.parse().context("cursor column")? };
+        ensure!(cursor.line > 0 && cursor.column > 0, "lines and columns count from 1");
+        Ok(cursor)
+    }
+}
+
+impl Cursor {
+    /// Byte offset of the cursor in `text`. One past the last character of
+    /// a line is its end.
+    pub fn offset(&self, text: &str) -> Result<usize> {
+        let mut start = 0;
+        for (n, line) in text.split_inclusive('\n').enumerate() {
+            if n + 1 == self.line {
+                let body = line.strip_suffix('\n').unwrap_or(line);
+                let body = body.strip_suffix('\r').unwrap_or(body);
+                let chars = body.chars().count();
+                ensure!(self.column <= chars + 1, "line {} has {chars} characters, no column {}", self.line, self.column);
+                let col = body.char_indices().nth(self.column - 1).map_or(body.len(), |(i, _)| i);
+                return Ok(start + col);
+            }
+            start += line.len();
+        }
+        // The empty line after a final newline.
+        if self.line == text.split_inclusive('\n').count() + 1 && self.column == 1 {
+            return Ok(text.len());
+        }
+        bail!("the file has no line {}", self.line)
+    }
+}
+
+/// The language a file extension usually means.
+pub fn language_for_path(path: &Path) -> Option<&'static str> {
+    let lang = match path.extension()?.to_str()? {
+        "rs" => "rust",
+        "py" => "python",
+        "js" | "mjs" | "cjs" => "javascript",
+        "ts" | "tsx" => "typescript",
+        "go" => "go",
+        "c" | "h" => "c",
+        "cc" | "cpp" | "cxx" | "hpp" => "cpp",
+        "m" | "mm" => "objective-c",
+        "swift" => "swift",
+        "java" => "java",
+        "kt" => "kotlin",
+        "rb" => "ruby",
+        "sh" | "bash" | "zsh" => "shell",
+        "lua" => "lua",
+        "sql" => "sql",
+        "hs" => "haskell",
+        "toml" => "toml",
+        "yaml" | "yml" => "yaml",
+        _ => return None,
+    };
+    Some(lang)
+}
+
+/// The line-comment marker of `language`; `//` when unknown.
+pub fn line_comment(language: &str) -> &'static str {
+    match language {
+        "python" | "ruby" | "shell" | "bash" | "toml" | "yaml" | "perl" | "r" => "#",
+        "lua" | "sql" | "haskell" => "--",
+        _ => "//",
+    }
+}
+
+/// The FIM prompt for a cursor at byte `offset` of `text`, in at most
+/// `budget` tokens.
+pub fn fim_prompt(
+    tokenizer: &PromptTokenizer,
+    fim: &FimTokens,
+    text: &str,
+    offset: usize,
+    header: &str,
+    budget: usize,
+    bos: bool,
+) -> Vec<u32> {
+    let (before, after) = text.split_at(offset);
+    let literal = |s: &str| tokenizer.encode(&[(s, SpecialTokens::Literal)]);
+    let fixed = 3 + bos as usize;
+    let prefix_budget = budget.saturating_sub(fixed) * 3 / 4;
+    // Lines are sized one by one but each side is encoded once, so a
+    // tokenizer's leading space goes on it once. The cursor's line stays.
+    let mut used = literal(header).len() + literal(before.rsplit('\n').next().unwrap_or_default()).len();
+    let mut start = before.rfind('\n').map_or(0, |i| i + 1);
+    while let Some(i) = before[..start.saturating_sub(1)].rfind('\n').map(|i| i + 1).or((start > 0).then_some(0)) {
+        used += literal(&before[i..start]).len();
+        if used > prefix_budget {
+            break;
+        }
+        start = i;
+    }
+    let mut prefix = literal(&format!("{header}{}", &before[start..]));
+    prefix.drain(..prefix.len().saturating_sub(prefix_budget.max(1)));
+    let suffix_budget = budget.saturating_sub(fixed + prefix.len());
+    let (mut end, mut used) = (0, 0);
+    for line in after.split_inclusive('\n') {
+        used += literal(line).len();
+        if used > suffix_budget {
+            break;
+        }
+        end += line.len();
+    }
+    let mut suffix = literal(&after[..end]);
+    suffix.truncate(suffix_budget);
+    let mut out = Vec::with_capacity(budget);
+    if bos {
+        out.push(1);
+    }
+    out.push(fim.prefix);
+    out.extend(prefix);
+    out.push(fim.suffix);
+    out.extend(suffix);
+    out.push(fim.middle);
+    out
+}
+
+#[derive(Clone, Debug)]
+pub struct CompleteOptions {
+    /// Candidates to sample: the greedy one, then tempered ones.
+    pub n: usize,
+    pub max_new: usize,
+    /// Stop each candidate at the end of the cursor's line.
+    pub single_line: bool,
+    pub temperature: f32,
+    pub seed: u64,
+}
+
+impl Default for CompleteOptions {
+    fn default() -> Self {
+        Self { n: 3, max_new: 64, single_line: false, temperature: 0.6, seed: 0 }
+    }
+}
+
+/// A fill for the cursor and its mean token log-probability.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Completion {
+    pub text: String,
+    pub score: f32,
+    pub tokens: usize,
+}
+
+/// Ranked, distinct, non-empty fills for `prompt`; `after` is the text
+/// following the cursor.
+pub fn candidates(
+    model: &mut dyn Generator,
+    tokenizer: &PromptTokenizer,
+    prompt: &[u32],
+    stop_tokens: &[u32],
+    after: &str,
+    opts: &CompleteOptions,
+) -> Result<Vec<Completion>> {
+    let mut session = KvSession::default();
+    let mut out: Vec<Completion> = Vec::new();
+    for i in 0..opts.n.max(1) {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let gen_opts = GenerateOptions {
+            max_new: opts.max_new,
+            sampling: SamplerConfig {
+                temperature: if i == 0 { 0.0 } else { opts.temperature },
+                seed: Some(opts.seed.wrapping_add(i as u64)),
+                ..SamplerConfig::default()
+            },
+            stop_tokens: stop_tokens.to_vec(),
+            cancel: Some(cancel.clone()),
+            ..GenerateOptions::default()
+        };
+        let (mut text, mut logprob, mut tokens) = (String::new(), 0.0, 0);
+        model.generate_cached(&mut session, prompt, &gen_opts, tokenizer.vocab(), &mut |event| {
+            if let GenerationEvent::Token { id, logprob: lp, .. } = event {
+                text.push_str(&tokenizer.decode(&[id], true));
+                logprob += lp;
+                tokens += 1;
+                if opts.single_line && text.contains('\n') {
+                    cancel.store(true, Ordering::SeqCst);
+                }
+            }
+        })?;
+        let text = trim_completion(&text, after, opts.single_line);
+        if text.trim().is_empty() || out.iter().any(|c| c.text == text) {
+            continue;
+        }
+        out.push(Completion { text, score: logprob / tokens.max(1) as f32, tokens });
+    }
+    out.sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(out)
+}
+
+/// A hole to fill: `text[start..end]`, empty for a cursor.
+#[derive(Clone, Copy, Debug)]
+pub struct Fill<'a> {
+    pub path: &'a str,
+    /// By default from the path's extension.
+    pub language: Option<&'a str>,
+    pub text: &'a str,
+    pub start: usize,
+    pub end: usize,
+    pub single_line: bool,
+}
+
+/// A FIM model and how to prompt it.
+pub struct Completer {
+    pub model: Box<dyn Generator>,
+    pub tokenizer: PromptTokenizer,
+    pub fim: FimTokens,
+    /// The model's own stop tokens and `fim.stops`.
+    pub stop_tokens: Vec<u32>,
+    pub bos: bool,
+    /// Prompt plus completion, in tokens.
+    pub ctx: usize,
+    pub opts: CompleteOptions,
+}
+
+impl Completer {
+    /// The prompt for `fill`: the text around the hole, under a path comment.
+    pub fn prompt(&self, fill: &Fill) -> Vec<u32> {
+        let language = fill.language.or_else(|| language_for_path(Path::new(fill.path))).unwrap_or("text");
+        let header = format!("{} Path: {}\n", line_comment(language), fill.path);
+        let text = format!("{}{}", &fill.text[..fill.start], &fill.text[fill.end..]);
+        let budget = self.ctx.saturating_sub(self.opts.max_new);
+        fim_prompt(&self.tokenizer, &self.fim, &text, fill.start, &header, budget, self.bos)
+    }
+
+    /// Ranked candidates for what goes in the hole.
+    pub fn fill(&mut self, fill: &Fill) -> Result<Vec<Completion>> {
+        let prompt = self.prompt(fill);
+        let opts = CompleteOptions { single_line: fill.single_line, ..self.opts.clone() };
+        candidates(self.model.as_mut(), &self.tokenizer, &prompt, &self.stop_tokens, &fill.text[fill.end..], &opts)
+    }
+}
+
+/// `text` cut at the end of its first line for `single_line`, else where it
+/// reaches the first non-blank line of `after`, which is already there.
+pub fn trim_completion(text: &str, after: &str, single_line: bool) -> String {
+    if single_line {
+        return text.split('\n').next().unwrap_or_default().trim_end().to_string();
+    }
+    let mut text = text;
+    if let Some(next) = after.lines().map(str::trim).find(|l| !l.is_empty()) {
+        let mut start = 0;
+        for line in text.split_inclusive('\n') {
+            if start > 0 && line.trim() == next {
+                text = &text[..start];
+                break;
+            }
+            start += line.len();
+        }
+    }
+    text.trim_end().to_string()
+}
@@ -0,0 +1,392 @@
+//! What the host CPU can do, and the CPU kernels picked by it.
+//!
+//! The GPU does the matmuls; the CPU still owns attention over the KV cache
+//! (one dot product per cached position, then a weighted sum of V rows).
+//! Those two loops have a scalar, a NEON and an AVX2+FMA version. The best
+//! one the CPU supports is picked on first use; `set_kernel` (the CLI's
+//! `--force-kernel`) pins another, so a suspected SIMD bug can be checked
+//! against the scalar path.
+//!
+//! Blocks offloaded to the CPU multiply their weights there too. For Q8_0
+//! weights, `quantize_q8` can turn each activation row into int8 blocks on
+//! the fly, so `dot_q8_0` multiplies int8 by int8 (`sdot` where the CPU has
+//! it) instead of widening every weight to f32 first.
+
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use anyhow::{Result, bail};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuKernel {
+    Scalar,
+    Neon,
+    /// AVX2 with FMA.
+    Avx2,
+}
+
+impl std::str::FromStr for CpuKernel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "scalar" => Ok(Self::Scalar),
+            "neon" => Ok(Self::Neon),
+            "avx2" => Ok(Self::Avx2),
+            _ => bail!("unknown CPU kernel '{s}' (expected scalar, neon or avx2)"),
+        }
+    }
+}
+
+impl CpuKernel {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Scalar => "scalar",
+            Self::Neon => "neon",
+            Self::Avx2 => "avx2",
+        }
+    }
+
+    pub fn is_supported(self, features: &CpuFeatures) -> bool {
+        match self {
+            Self::Scalar => true,
+            Self::Neon => features.neon,
+            Self::Avx2 => features.avx2 && features.fma,
+        }
+    }
+}
+
+/// The SIMD extensions this build can reach, detected at runtime.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuFeatures {
+    pub neon: bool,
+    /// Armv8.4 `sdot`/`udot`.
+    pub dotprod: bool,
+    /// Half-precision arithmetic.
+    pub fp16: bool,
+    pub avx2: bool,
+    pub fma: bool,
+    pub avx512f: bool,
+    /// Apple's matrix coprocessor. It has no public instructions, only
+    /// Accelerate's BLAS, so this means "Apple silicon".
+    pub amx: bool,
+}
+
+impl CpuFeatures {
+    pub fn detect() -> Self {
+        #[allow(unused_mut)]
+        let mut f = Self::default();
+        #[cfg(target_arch = "aarch64")]
+        {
+            f.neon = std::arch::is_aarch64_feature_detected!("neon");
+            f.dotprod = std::arch::is_aarch64_feature_detected!("dotprod");
+            f.fp16 = std::arch::is_aarch64_feature_detected!("fp16");
+            f.amx = cfg!(target_os = "macos");
+        }
+        #[cfg(target_arch = "x86_64")]
+        {
+            f.avx2 = std::arch::is_x86_feature_detected!("avx2");
+            f.fma = std::arch::is_x86_feature_detected!("fma");
+            f.avx512f = std::arch::is_x86_feature_detected!("avx512f");
+        }
+        f
+    }
+
+    /// The feature names present, for `sysinfo`.
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (self.neon, "neon"),
+            (self.dotprod, "dotprod"),
+            (self.fp16, "fp16"),
+            (self.avx2, "avx2"),
+            (self.fma, "fma"),
+            (self.avx512f, "avx512f"),
+            (self.amx, "amx"),
+        ]
+        .into_iter()
+        .filter_map(|(on, name)| on.then_some(name))
+        .collect()
+    }
+
+    pub fn best_kernel(&self) -> CpuKernel {
+        [CpuKernel::Avx2, CpuKernel::Neon].into_iter().find(|k| k.is_supported(self)).unwrap_or(CpuKernel::Scalar)
+    }
+}
+
+const UNSET: u8 = u8::MAX;
+static KERNEL: AtomicU8 = AtomicU8::new(UNSET);
+
+/// The kernel `dot` and `axpy` use: the forced one, else the best detected.
+pub fn kernel() -> CpuKernel {
+    match KERNEL.load(Ordering::Relaxed) {
+        0 => CpuKernel::Scalar,
+        1 => CpuKernel::Neon,
+        2 => CpuKernel::Avx2,
+        _ => {
+            let best = CpuFeatures::detect().best_kernel();
+            KERNEL.store(best as u8, Ordering::Relaxed);
+            best
+        }
+    }
+}
+
+/// Use `kernel` from now on, in every thread. Fails if this CPU lacks it.
+pub fn set_kernel(kernel: CpuKernel) -> Result<()> {
+    if !kernel.is_supported(&CpuFeatures::detect()) {
+        bail!("this CPU cannot run the {} kernel", kernel.name());
+    }
+    KERNEL.store(kernel as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    dot_with(kernel(), a, b)
+}
+
+/// `y += a * x`.
+pub fn axpy(y: &mut [f32], a: f32, x: &[f32]) {
+    axpy_with(kernel(), y, a, x)
+}
+
+/// `dot` on a given kernel, which must be supported (scalar otherwise).
+pub fn dot_with(kernel: CpuKernel, a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len().min(b.len());
+    let (a, b) = (&a[..n], &b[..n]);
+    match kernel {
+        #[cfg(target_arch = "aarch64")]
+        CpuKernel::Neon => unsafe { neon::dot(a, b) },
+        #[cfg(target_arch = "x86_64")]
+        CpuKernel::Avx2 if CpuKernel::Avx2.is_supported(&CpuFeatures::detect()) => unsafe { avx2::dot(a, b) },
+        _ => a.iter().zip(b).map(|(x, y)| x * y).sum(),
+    }
+}
+
+/// `axpy` on a given kernel, which must be supported (scalar otherwise).
+pub fn axpy_with(kernel: CpuKernel, y: &mut [f32], a: f32, x: &[f32]) {
+    let n = y.len().min(x.len());
+    let (y, x) = (&mut y[..n], &x[..n]);
+    match kernel {
+        #[cfg(target_arch = "aarch64")]
+        CpuKernel::Neon => unsafe { neon::axpy(y, a, x) },
+        #[cfg(target_arch = "x86_64")]
+        CpuKernel::Avx2 if CpuKernel::Avx2.is_supported(&CpuFeatures::detect()) => unsafe { avx2::axpy(y, a, x) },
+        _ => y.iter_mut().zip(x).for_each(|(yi, xi)| *yi += a * xi),
+    }
+}
+
+/// `a · b` over int8 values in `-127..=127`, of the same length.
+pub fn dot_i8(a: &[i8], b: &[i8]) -> i32 {
+    dot_i8_with(kernel(), a, b)
+}
+
+/// `dot_i8` on a given kernel, which must be supported (scalar otherwise).
+/// NEON uses `sdot` on CPUs with dotprod, and widening multiplies elsewhere.
+pub fn dot_i8_with(kernel: CpuKernel, a: &[i8], b: &[i8]) -> i32 {
+    let n = a.len().min(b.len());
+    let (a, b) = (&a[..n], &b[..n]);
+    match kernel {
+        #[cfg(target_arch = "aarch64")]
+        CpuKernel::Neon if std::arch::is_aarch64_feature_detected!("dotprod") => unsafe { neon::dot_i8_sdot(a, b) },
+        #[cfg(target_arch = "aarch64")]
+        CpuKernel::Neon => unsafe { neon::dot_i8(a, b) },
+        #[cfg(target_arch = "x86_64")]
+        CpuKernel::Avx2 if CpuKernel::Avx2.is_supported(&CpuFeatures::detect()) => unsafe { avx2::dot_i8(a, b) },
+        _ => a.iter().zip(b).map(|(&x, &y)| x as i32 * y as i32).sum(),
+    }
+}
+
+/// One activation row quantized like Q8_0: a scale per 32 values and the
+/// values as int8, the last block zero-padded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Q8Activations {
+    pub scales: Vec<f32>,
+    pub quants: Vec<i8>,
+}
+
+/// `x` in Q8_0 blocks, each scaled by its largest magnitude so it spans
+/// `-127..=127`. Done once per row and matmul, then shared by every weight
+/// row it is dotted with.
+pub fn quantize_q8(x: &[f32]) -> Q8Activations {
+    let blocks = x.len().div_ceil(32);
+    let (mut scales, mut quants) = (Vec::with_capacity(blocks), vec![0i8; blocks * 32]);
+    for (block, q) in x.chunks(32).zip(quants.chunks_exact_mut(32)) {
+        let d = block.iter().fold(0.0f32, |m, v| m.max(v.abs())) / 127.0;
+        let inv = if d > 0.0 { 1.0 / d } else { 0.0 };
+        q.iter_mut().zip(block).for_each(|(q, v)| *q = (v * inv).round() as i8);
+        scales.push(d);
+    }
+    Q8Activations { scales, quants }
+}
+
+/// A Q8_0 weight row (`tensor::Q8_0_BLOCK` bytes per 32 values) dotted
+/// with an activation row from `quantize_q8` of the same width.
+pub fn dot_q8_0(row: &[u8], x: &Q8Activations) -> f32 {
+    let kernel = kernel();
+    row.chunks_exact(crate::tensor::Q8_0_BLOCK)
+        .zip(x.scales.iter().zip(x.quants.chunks_exact(32)))
+        .map(|(block, (&dx, qx))| {
+            let dw = half::f16::from_bits(u16::from_le_bytes([block[0], block[1]])).to_f32();
+            // SAFETY: i8 and u8 have the same size and alignment.
+            let qw = unsafe { std::slice::from_raw_parts(block[2..].as_ptr() as *const i8, 32) };
+            dw * dx * dot_i8_with(kernel, qw, qx) as f32
+        })
+        .sum()
+}
+
+/// The host's CPU model, if the OS says.
+pub fn brand() -> Option<String> {
+    #[cfg(target_os = "macos")]
+    {
+        sysctl("machdep.cpu.brand_string")
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        let info = std::fs::read_to_string("/proc/cpuinfo").ok()?;
+        let line = info.lines().find(|l| l.starts_with("model name"))?;
+        Some(line.split_once(':')?.1.trim().to_string())
+    }
+}
+
+/// Physical memory in bytes, if the OS says.
+pub fn physical_memory() -> Option<u64> {
+    #[cfg(target_os = "macos")]
+    {
+        sysctl("hw.memsize")?.parse().ok()
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        let info = std::fs::read_to_string("/proc/meminfo").ok()?;
+        let kb = info.lines().find_map(|l| l.strip_prefix("MemTotal:"))?.trim().trim_end_matches("kB").trim();
+        Some(kb.parse::<u64>().ok()? * 1024)
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn sysctl(name: &str) -> Option<String> {
+    let out = std::process::Command::new("/usr/sbin/sysctl").args(["-n", name]).output().ok()?;
+    out.status.success().then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+
+    /// `a` and `b` are the same length.
+    pub unsafe fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let chunks = a.len() / 4;
+        unsafe {
+            let mut acc = vdupq_n_f32(0.0);
+            for i in 0..chunks {
+                acc = vfmaq_f32(acc, vld1q_f32(a.as_ptr().add(4 * i)), vld1q_f32(b.as_ptr().add(4 * i)));
+            }
+            let tail: f32 = a[4 * chunks..].iter().zip(&b[4 * chunks..]).map(|(x, y)| x * y).sum();
+            vaddvq_f32(acc) + tail
+        }
+    }
+
+    /// `y` and `x` are the same length.
+    pub unsafe fn axpy(y: &mut [f32], a: f32, x: &[f32]) {
+        let chunks = y.len() / 4;
+        unsafe {
+            let va = vdupq_n_f32(a);
+            for i in 0..chunks {
+                let p = y.as_mut_ptr().add(4 * i);
+                vst1q_f32(p, vfmaq_f32(vld1q_f32(p), va, vld1q_f32(x.as_ptr().add(4 * i))));
+            }
+        }
+        y[4 * chunks..].iter_mut().zip(&x[4 * chunks..]).for_each(|(yi, xi)| *yi += a * xi);
+    }
+
+    /// `a` and `b` are the same length.
+    pub unsafe fn dot_i8(a: &[i8], b: &[i8]) -> i32 {
+        let chunks = a.len() / 16;
+        unsafe {
+            let mut acc = vdupq_n_s32(0);
+            for i in 0..chunks {
+                let (va, vb) = (vld1q_s8(a.as_ptr().add(16 * i)), vld1q_s8(b.as_ptr().add(16 * i)));
+                acc = vpadalq_s16(acc, vmull_s8(vget_low_s8(va), vget_low_s8(vb)));
+                acc = vpadalq_s16(acc, vmull_high_s8(va, vb));
+            }
+            let tail: i32 = a[16 * chunks..].iter().zip(&b[16 * chunks..]).map(|(&x, &y)| x as i32 * y as i32).sum();
+            vaddvq_s32(acc) + tail
+        }
+    }
+
+    /// `a` and `b` are the same length; the CPU has dotprod. The `sdot`
+    /// intrinsic is not stable yet, so the instruction is written out.
+    #[target_feature(enable = "dotprod")]
+    pub unsafe fn dot_i8_sdot(a: &[i8], b: &[i8]) -> i32 {
+        let chunks = a.len() / 16;
+        unsafe {
+            let mut acc = vdupq_n_s32(0);
+            for i in 0..chunks {
+                let (va, vb) = (vld1q_s8(a.as_ptr().add(16 * i)), vld1q_s8(b.as_ptr().add(16 * i)));
+                std::arch::asm!(
+                    "sdot {acc:v}.4s, {a:v}.16b, {b:v}.16b",
+                    acc = inout(vreg) acc,
+                    a = in(vreg) va,
+                    b = in(vreg) vb,
+                    options(pure, nomem, nostack),
+                );
+            }
+            let tail: i32 = a[16 * chunks..].iter().zip(&b[16 * chunks..]).map(|(&x, &y)| x as i32 * y as i32).sum();
+            vaddvq_s32(acc) + tail
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod avx2 {
+    use std::arch::x86_64::*;
+
+    /// `a` and `b` are the same length; the CPU has AVX2 and FMA.
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot(a: &[f32], b: &[f32]) -> f32 {
+        let chunks = a.len() / 8;
+        let mut acc = _mm256_setzero_ps();
+        for i in 0..chunks {
+            let (va, vb) = unsafe { (_mm256_loadu_ps(a.as_ptr().add(8 * i)), _mm256_loadu_ps(b.as_ptr().add(8 * i))) };
+            acc = _mm256_fmadd_ps(va, vb, acc);
+        }
+        let mut lanes = [0.0f32; 8];
+        unsafe { _mm256_storeu_ps(lanes.as_mut_ptr(), acc) };
+        let tail: f32 = a[8 * chunks..].iter().zip(&b[8 * chunks..]).map(|(x, y)| x * y).sum();
+        lanes.iter().sum::<f32>() + tail
+    }
+
+    /// `y` and `x` are the same length; the CPU has AVX2 and FMA.
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn axpy(y: &mut [f32], a: f32, x: &[f32]) {
+        let chunks = y.len() / 8;
+        let va = _mm256_set1_ps(a);
+        for i in 0..chunks {
+            unsafe {
+                let p = y.as_mut_ptr().add(8 * i);
+                _mm256_storeu_ps(p, _mm256_fmadd_ps(va, _mm256_loadu_ps(x.as_ptr().add(8 * i)), _mm256_loadu_ps(p)));
+            }
+        }
+        y[8 * chunks..].iter_mut().zip(&x[8 * chunks..]).for_each(|(yi, xi)| *yi += a * xi);
+    }
+
+    /// `a` and `b` are the same length, values in `-127..=127`; the CPU
+    /// has AVX2. `maddubs` multiplies unsigned by signed bytes, so `a`'s
+    /// signs move onto `b` first; two products of 127 still fit its i16s.
+    #[target_feature(enable = "avx2,fma")]
+    pub unsafe fn dot_i8(a: &[i8], b: &[i8]) -> i32 {
+        let chunks = a.len() / 32;
+        let (mut acc, ones) = (_mm256_setzero_si256(), _mm256_set1_epi16(1));
+        for i in 0..chunks {
+            let (va, vb) = unsafe {
+                (
+                    _mm256_loadu_si256(a.as_ptr().add(32 * i) as *const __m256i),
+                    _mm256_loadu_si256(b.as_ptr().add(32 * i) as *const __m256i),
+                )
+            };
+            let pairs = _mm256_maddubs_epi16(_mm256_sign_epi8(va, va), _mm256_sign_epi8(vb, va));
+            acc = _mm256_add_epi32(acc, _mm256_madd_epi16(pairs, ones));
+        }
+        let mut lanes = [0i32; 8];
+        unsafe { _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, acc) };
+        let tail: i32 = a[32 * chunks..].iter().zip(&b[32 * chunks..]).map(|(&x, &y)| x as i32 * y as i32).sum();
+        lanes.iter().sum::<i32>() + tail
+    }
+}
@@ -0,0 +1,834 @@
+//! `llmetal daemon`: one model kept resident behind a Unix domain socket, so
+//! repeated CLI runs skip the multi-GB load.
+//!
+//! Every message, either way, is a frame: a little-endian u32 byte length
+//! and then that much JSON. A connection carries any number of requests,
+//! answered in order:
+//!
+//! - `{"op": "info"}` → `{"model", "n_layers", "hidden", "vocab_size",
+//!   "ctx_train", "stop_tokens", "kv_cache", "sessions", "section_cache"}`;
+//!   `kv_cache` is `{"positions", "bytes", "slack_bytes"}` (see `KvStats`)
+//!   for requests without a session id, `sessions` the number of named
+//!   ones, `section_cache` `{"sections", "bytes", "hits", "misses"}`
+//! - `{"op": "generate", "prompt": TEXT, ...}` → `{"event": "progress",
+//!   "done", "total", "ms", "eta_ms"}` per prefill chunk of a long prompt,
+//!   `{"event": "prompt"}`, one `{"event": "token", "id", "text"}` per token
+//!   (with `"reasoning": true` inside `<think>...</think>`), then `{"event":
+//!   "done", "finish_reason", "usage", "timings"}`; `usage` is
+//!   `Usage::to_json`. `"tokens": [ids]` may replace `prompt` (the client
+//!   tokenized and templated it). `"sections": [TEXT or [ids], ...]` go in
+//!   front of it, verbatim and in order, with their K/V taken from the
+//!   section cache (see `section_cache`); the BOS then starts the first
+//!   section rather than the prompt. Optional fields: `session`,
+//!   `max_tokens`, `chat_format`, `bos`, `special`, `stop_tokens`, `stop`
+//!   (strings that are each one token) and the sampler's `temperature`,
+//!   `top_k`, `top_p`, `seed`, `repeat_penalty`, `dry`, `xtc` and
+//!   `loop_guard` (`{"action", "value", "max_period", "min_repeats",
+//!   "min_tokens"}`, finishing with `"loop"` when its action is `stop`). Each
+//!   request brings its own; the daemon's `Limits` clamp them.
+//!   `"response_format": {"type": "json_object"}` constrains the reply to one
+//!   JSON object (see `json_grammar`); its `done` frame adds `json_valid`,
+//!   false only when `max_tokens` cut the object short.
+//!   `{"type": "regex", "pattern": P}` constrains it to one whole match of
+//!   `P` (see `regex_grammar`), and `done` adds `regex_valid` likewise.
+//!   With `chat_format`, `done` also carries `message`, the reply parsed
+//!   into content, reasoning and tool calls (`reply::AssistantReply::to_json`).
+//!   For reasoning models, `max_thinking` (tokens) or `reasoning_effort`
+//!   (`low`, `medium`, `high`) caps the thinking, and `"reasoning": "hide"`
+//!   leaves its token frames out.
+//! - `{"op": "tokenize", "text": TEXT}` → `{"tokens": [{"id", "start",
+//!   "end", "special"}]}`: the ids a `generate` with this `prompt` (and the
+//!   same `bos`) would prefill, with byte ranges as `PromptTokenizer::spans`.
+//! - `{"op": "detokenize", "tokens": [ids]}` → `{"text", "tokens"}`, the
+//!   spans into `text`; `special: false` leaves special tokens' text out.
+//! - `{"op": "compact"}` → `{"freed_bytes", "kv_cache"}`: release the KV
+//!   capacity left by truncated positions now rather than at hang-up.
+//! - `{"op": "sessions"}` → `{"sessions": [{"id", "idle_ms", "positions",
+//!   "bytes", "slack_bytes"}]}`; `{"op": "drop_session", "session": ID}` →
+//!   `{"dropped"}`.
+//! - `{"op": "shutdown"}` → `{"ok": true}`, and the daemon exits.
+//!
+//! The daemon keeps the K/V rows of its last generation, so a request that
+//! extends the previous one (the next chat turn) only prefills what is new.
+//! A `generate` with `"session": ID` uses, and leaves behind, that
+//! conversation's own rows instead, so several clients can each resume
+//! theirs; named sessions expire after `Limits::session_ttl` and are dropped
+//! least recently used first past `Limits::session_bytes`. When a client
+//! hangs up every cache is compacted.
+//!
+//! A failed request is answered with `{"event": "error", "message"}`; the
+//! connection stays usable. Connections are served one at a time.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail, ensure};
+use serde_json::{Value, json};
+
+use crate::chat;
+use crate::events::{FinishReason, GenerationEvent, Timings, Usage};
+use crate::gguf_loader::GgufModelInfo;
+use crate::model::{GenerateOptions, Generator, KvSession, KvStats, LlamaModel, WarmupStats};
+use crate::reasoning::{Segment, ThinkStream, ThinkTokens};
+use crate::regex_grammar::Regex;
+use crate::reply;
+use crate::section_cache::{self, SectionCache, SectionStats};
+use crate::sampler::{DryConfig, LoopAction, LoopGuard, SamplerConfig, XtcConfig};
+use crate::tokenizer::{PromptTokenizer, TokenSpan};
+
+/// Larger frames are refused rather than allocated.
+const MAX_FRAME: usize = 64 << 20;
+
+pub fn write_frame(w: &mut dyn Write, msg: &Value) -> Result<()> {
+    let body = serde_json::to_vec(msg)?;
+    ensure!(body.len() <= MAX_FRAME, "frame of {} bytes exceeds {MAX_FRAME}", body.len());
+    w.write_all(&(body.len() as u32).to_le_bytes())?;
+    w.write_all(&body)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// The next frame, or `None` when the peer closed the connection between frames.
+pub fn read_frame(r: &mut dyn Read) -> Result<Option<Value>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = u32::from_le_bytes(len) as usize;
+    ensure!(len <= MAX_FRAME, "frame of {len} bytes exceeds {MAX_FRAME}");
+    let mut body = vec![0; len];
+    r.read_exact(&mut body).context("truncated frame")?;
+    Ok(Some(serde_json::from_slice(&body).context("frame is not JSON")?))
+}
+
+/// Where the daemon for `model_path` listens unless told otherwise:
+/// `$TMPDIR/llmetal-<FNV-1a of the canonical path>.sock`.
+pub fn default_socket_path(model_path: &str) -> PathBuf {
+    let canonical = std::fs::canonicalize(model_path).unwrap_or_else(|_| PathBuf::from(model_path));
+    let key = crate::repack::model_key(canonical.as_os_str().as_encoded_bytes(), 0, &[]);
+    std::env::temp_dir().join(format!("llmetal-{key:016x}.sock"))
+}
+
+/// What a single request may ask for at most. Out-of-range values are
+/// clamped, not refused, so a client written for another server still works.
+/// The session limits bound what named sessions keep between requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub max_tokens: usize,
+    pub max_temperature: f32,
+    /// Upper bound for `repeat_penalty` and the DRY multiplier.
+    pub max_penalty: f32,
+    /// A named session unused for this long is dropped.
+    pub session_ttl: Duration,
+    /// K/V bytes all named sessions together may hold; the least recently
+    /// used go first.
+    pub session_bytes: usize,
+    /// K/V bytes the section cache may hold, likewise.
+    pub section_bytes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_tokens: 4096,
+            max_temperature: 2.0,
+            max_penalty: 4.0,
+            session_ttl: Duration::from_secs(30 * 60),
+            session_bytes: 2 << 30,
+            section_bytes: section_cache::DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+impl Limits {
+    pub fn clamp(&self, opts: &mut GenerateOptions) {
+        let s = &mut opts.sampling;
+        opts.max_new = opts.max_new.min(self.max_tokens);
+        s.temperature = s.temperature.clamp(0.0, self.max_temperature);
+        s.top_p = s.top_p.clamp(0.0, 1.0);
+        s.repetition_penalty = s.repetition_penalty.clamp(1.0, self.max_penalty);
+        if let Some(d) = &mut s.dry {
+            d.multiplier = d.multiplier.clamp(0.0, self.max_penalty);
+        }
+        if let Some(x) = &mut s.xtc {
+            x.probability = x.probability.clamp(0.0, 1.0);
+        }
+        if let Some(LoopGuard { action: LoopAction::Diversify(t), .. }) = &mut s.loop_guard {
+            *t = t.clamp(0.0, self.max_temperature);
+        }
+    }
+}
+
+pub struct Daemon {
+    model_path: String,
+    model: LlamaModel,
+    info: GgufModelInfo,
+    tokenizer: PromptTokenizer,
+    /// Requests without a `session` id share this one.
+    session: KvSession,
+    sessions: HashMap<String, Retained>,
+    /// K/V of the `sections` requests put in front of their prompts.
+    sections: SectionCache,
+    limits: Limits,
+}
+
+struct Retained {
+    session: KvSession,
+    last_used: Instant,
+}
+
+impl Daemon {
+    /// Load the model and upload every weight up front, on `threads` threads.
+    pub fn load(model_path: &str, threads: usize) -> Result<Self> {
+        let mut model = LlamaModel::load(model_path)?;
+        model.load_all_tensors(threads)?;
+        let info = GgufModelInfo::load(model_path)?;
+        let tokenizer = PromptTokenizer::for_model(&info)?;
+        Ok(Self {
+            model_path: model_path.to_string(),
+            model,
+            info,
+            tokenizer,
+            session: KvSession::default(),
+            sessions: HashMap::new(),
+            sections: SectionCache::default(),
+            limits: Limits::default(),
+        })
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self.sections = SectionCache::new(limits.section_bytes);
+        self
+    }
+
+    /// `LlamaModel::warmup`, before the first client connects.
+    pub fn warmup(&mut self) -> Result<WarmupStats> {
+        self.model.warmup()
+    }
+
+    /// Accept connections on `socket` until a `shutdown` request. A stale
+    /// socket file is replaced; one a live daemon answers on is an error.
+    pub fn serve(mut self, socket: &Path) -> Result<()> {
+        if socket.exists() {
+            ensure!(UnixStream::connect(socket).is_err(), "a daemon is already listening on {}", socket.display());
+            std::fs::remove_file(socket).with_context(|| format!("remove stale {}", socket.display()))?;
+        }
+        let listener = UnixListener::bind(socket).with_context(|| format!("bind {}", socket.display()))?;
+        eprintln!("llmetal daemon: {} on {}", self.model_path, socket.display());
+        let result = (|| -> Result<()> {
+            for stream in listener.incoming() {
+                let mut stream = stream?;
+                match self.serve_connection(&mut stream) {
+                    Ok(true) => {
+                        let freed = self.compact();
+                        if freed > 0 {
+                            eprintln!("compacted KV cache: freed {freed} bytes");
+                        }
+                    }
+                    Ok(false) => return Ok(()),
+                    // One broken client must not take the daemon down.
+                    Err(e) => eprintln!("connection error: {e:#}"),
+                }
+            }
+            Ok(())
+        })();
+        let _ = std::fs::remove_file(socket);
+        result
+    }
+
+    /// Answer requests until the client hangs up (`true`) or asks the daemon
+    /// to shut down (`false`).
+    fn serve_connection(&mut self, stream: &mut UnixStream) -> Result<bool> {
+        while let Some(req) = read_frame(stream)? {
+            match self.handle(&req, stream) {
+                Ok(true) => {}
+                Ok(false) => return Ok(false),
+                Err(e) => write_frame(stream, &json!({ "event": "error", "message": format!("{e:#}") }))?,
+            }
+        }
+        Ok(true)
+    }
+
+    /// Answer one request, writing its reply frames to `out`. Returns
+    /// `false` for `shutdown`.
+    pub fn handle(&mut self, req: &Value, out: &mut dyn Write) -> Result<bool> {
+        self.evict();
+        match req["op"].as_str() {
+            Some("info") => write_frame(out, &json!({
+                "model": self.model_path,
+                "n_layers": self.model.arch.n_layers,
+                "hidden": self.model.arch.hidden,
+                "vocab_size": self.model.arch.vocab_size,
+                "ctx_train": self.model.arch.ctx_train,
+                "stop_tokens": self.model.declared_stop_tokens(),
+                "kv_cache": kv_stats_to_json(&self.session.stats()),
+                "sessions": self.sessions.len(),
+                "section_cache": section_stats_to_json(&self.sections.stats()),
+            }))?,
+            Some("generate") => self.generate(req, out)?,
+            Some("tokenize") => {
+                let text = req["text"].as_str().context("tokenize needs a \"text\" string")?;
+                let ids = if req["bos"].as_bool().unwrap_or(true) {
+                    self.tokenizer.tokenize_bos(text)
+                } else {
+                    self.tokenizer.tokenize(text)
+                };
+                write_frame(out, &json!({ "tokens": spans_to_json(&self.tokenizer.spans(&ids, false)) }))?;
+            }
+            Some("detokenize") => {
+                let ids = token_ids(&req["tokens"])?;
+                let skip_special = !req["special"].as_bool().unwrap_or(true);
+                write_frame(out, &json!({
+                    "text": self.tokenizer.decode(&ids, skip_special),
+                    "tokens": spans_to_json(&self.tokenizer.spans(&ids, skip_special)),
+                }))?;
+            }
+            Some("compact") => {
+                let freed = self.compact();
+                write_frame(out, &json!({ "freed_bytes": freed, "kv_cache": kv_stats_to_json(&self.session.stats()) }))?;
+            }
+            Some("sessions") => {
+                let now = Instant::now();
+                let list: Vec<Value> = self
+                    .sessions
+                    .iter()
+                    .map(|(id, r)| {
+                        let mut v = kv_stats_to_json(&r.session.stats());
+                        v["id"] = id.as_str().into();
+                        v["idle_ms"] = json!((now - r.last_used).as_millis());
+                        v
+                    })
+                    .collect();
+                write_frame(out, &json!({ "sessions": list }))?;
+            }
+            Some("drop_session") => {
+                let id = req["session"].as_str().context("drop_session needs a \"session\" id")?;
+                write_frame(out, &json!({ "dropped": self.sessions.remove(id).is_some() }))?;
+            }
+            Some("shutdown") => {
+                write_frame(out, &json!({ "ok": true }))?;
+                return Ok(false);
+            }
+            Some(op) => bail!("unknown op {op:?}"),
+            None => bail!("request has no \"op\""),
+        }
+        Ok(true)
+    }
+
+    fn generate(&mut self, req: &Value, out: &mut dyn Write) -> Result<()> {
+        let mut opts = options_from_json(req)?;
+        let bos = req["bos"].as_bool().unwrap_or(true);
+        let mut sections = Vec::new();
+        for (i, section) in req["sections"].as_array().into_iter().flatten().enumerate() {
+            sections.push(match section.as_str() {
+                Some(text) if bos && i == 0 => self.tokenizer.tokenize_bos(text),
+                Some(text) => self.tokenizer.tokenize(text),
+                None => token_ids(section).context("\"sections\" must be strings or token id arrays")?,
+            });
+        }
+        let mut reply_format = None;
+        let tokens: Vec<u32> = match (&req["tokens"], req["prompt"].as_str()) {
+            (ids @ Value::Array(_), _) => token_ids(ids)?,
+            (_, Some(prompt)) => {
+                let mut declared = self.model.declared_stop_tokens();
+                let prompt = match req["chat_format"].as_str() {
+                    Some(flag) => {
+                        let format = chat::resolve(flag, self.info.chat_template.as_deref())?;
+                        declared = chat::stop_tokens(format, &self.info.vocab, &declared);
+                        reply_format = Some(format);
+                        format.render(&[chat::Message::new(chat::Role::User, prompt)], true)
+                    }
+                    None => prompt.to_string(),
+                };
+                if req["stop_tokens"].is_null() {
+                    opts.stop_tokens = declared;
+                }
+                if bos && sections.is_empty() {
+                    self.tokenizer.tokenize_bos(&prompt)
+                } else {
+                    self.tokenizer.tokenize(&prompt)
+                }
+            }
+            _ => bail!("generate needs a \"prompt\" string or \"tokens\""),
+        };
+        let tokens = [sections.concat(), tokens].concat();
+        for stop in req["stop"].as_array().into_iter().flatten() {
+            let text = stop.as_str().context("\"stop\" must be strings")?;
+            match self.tokenizer.tokenize(text)[..] {
+                [id] => opts.stop_tokens.push(id),
+                _ => bail!("stop string {text:?} is not a single token"),
+            }
+        }
+        self.limits.clamp(&mut opts);
+        let skip_special = !req["special"].as_bool().unwrap_or(false);
+        let think_tags = ThinkTokens::find(&self.info.vocab);
+        ensure!(
+            opts.sampling.max_thinking.is_none() || think_tags.is_some(),
+            "max_thinking needs {} and {} tokens in the vocab",
+            crate::reasoning::OPEN,
+            crate::reasoning::CLOSE
+        );
+        let mut think = think_tags.map(|tags| ThinkStream::new(tags, &tokens));
+        let hide_reasoning = match req["reasoning"].as_str() {
+            None | Some("show") => false,
+            Some("hide") => true,
+            Some(other) => bail!("unknown reasoning mode {other:?} (expected show, hide)"),
+        };
+
+        // A failed write means the client hung up, which cancels the
+        // generation; the error is reported once it has stopped.
+        let hung_up = Arc::new(AtomicBool::new(false));
+        opts.cancel = Some(hung_up.clone());
+        let (tokenizer, mut written) = (&self.tokenizer, Ok(()));
+        let (json_object, regex) = (opts.sampling.json_object, opts.sampling.regex.clone());
+        let mut generated = Vec::new();
+        let mut send = |msg: Value| {
+            if written.is_ok() {
+                written = write_frame(out, &msg);
+                hung_up.store(written.is_err(), Ordering::Relaxed);
+            }
+        };
+        let id = req["session"].as_str();
+        let mut session = match id {
+            Some(id) => self.sessions.remove(id).map(|r| r.session).unwrap_or_default(),
+            None => std::mem::take(&mut self.session),
+        };
+        // Stitching only fills the front of `session`; `generate_cached`
+        // then finds those tokens already there and prefills the rest.
+        let stitched = if sections.is_empty() {
+            Ok(0)
+        } else {
+            self.model.stitch_sections(&mut self.sections, &mut session, &sections)
+        };
+        let result = stitched.and_then(|_| {
+            self.model.generate_cached(&mut session, &tokens, &opts, &self.info.vocab, &mut |event| match event {
+                GenerationEvent::PrefillProgress { done, total, ms, eta_ms } => {
+                    send(json!({ "event": "progress", "done": done, "total": total, "ms": ms, "eta_ms": eta_ms }))
+                }
+                GenerationEvent::PromptProcessed { n_tokens, ms } => {
+                    send(json!({ "event": "prompt", "n_tokens": n_tokens, "ms": ms }))
+                }
+                GenerationEvent::Token { id, logprob, .. } => {
+                    generated.push(id);
+                    let mut frame = json!({ "event": "token", "id": id, "text": tokenizer.decode(&[id], skip_special), "logprob": logprob });
+                    if think.as_mut().is_some_and(|t| t.route(id) != Segment::Content) {
+                        if hide_reasoning {
+                            return;
+                        }
+                        frame["reasoning"] = true.into();
+                    }
+                    send(frame)
+                }
+                GenerationEvent::Done { reason, timings, usage } => {
+                    let mut done = json!({
+                        "event": "done",
+                        "finish_reason": reason.as_str(),
+                        "usage": usage.to_json(),
+                        "timings": timings_to_json(&timings),
+                    });
+                    if json_object {
+                        let text = tokenizer.decode(&generated, skip_special);
+                        done["json_valid"] = serde_json::from_str::<Value>(&text).is_ok_and(|v| v.is_object()).into();
+                    }
+                    if let Some(regex) = &regex {
+                        done["regex_valid"] = regex.is_match(&tokenizer.decode(&generated, skip_special)).into();
+                    }
+                    if let Some(format) = reply_format {
+                        done["message"] = reply::parse(format, &tokenizer.decode(&generated, skip_special)).to_json();
+                    }
+                    send(done)
+                }
+            })
+        });
+        match id {
+            Some(id) => {
+                self.sessions.insert(id.to_string(), Retained { session, last_used: Instant::now() });
+                self.evict();
+            }
+            None => self.session = session,
+        }
+        let reused = result?;
+        if reused > 0 {
+            eprintln!("reused {reused} cached prompt tokens");
+        }
+        written
+    }
+}
+
+impl Daemon {
+    /// Drop named sessions idle past the TTL, then the least recently used
+    /// until the rest fit in `session_bytes`. The one just used goes last,
+    /// so only when it alone is over the cap.
+    fn evict(&mut self) {
+        let Limits { session_ttl, session_bytes, .. } = self.limits;
+        let now = Instant::now();
+        self.sessions.retain(|_, r| now - r.last_used < session_ttl);
+        let mut total: usize = self.sessions.values().map(|r| r.session.stats().bytes).sum();
+        while total > session_bytes {
+            let Some(oldest) = self.sessions.iter().min_by_key(|(_, r)| r.last_used).map(|(id, _)| id.clone()) else {
+                break;
+            };
+            total -= self.sessions.remove(&oldest).map_or(0, |r| r.session.stats().bytes);
+            eprintln!("dropped session {oldest:?}: named sessions are over {session_bytes} bytes");
+        }
+    }
+
+    /// `KvSession::shrink_to_fit` on every session; returns the bytes freed.
+    fn compact(&mut self) -> usize {
+        self.session.shrink_to_fit() + self.sessions.values_mut().map(|r| r.session.shrink_to_fit()).sum::<usize>()
+    }
+}
+
+/// The request fields `options_from_json` reads back. Guidance and
+/// speculative drafts are not part of the protocol.
+pub fn options_to_json(opts: &GenerateOptions) -> Value {
+    let s = &opts.sampling;
+    json!({
+        "max_tokens": opts.max_new,
+        "stop_tokens": opts.stop_tokens,
+        "seed": s.seed,
+        "temperature": s.temperature,
+        "top_k": s.top_k,
+        "top_p": s.top_p,
+        "repeat_penalty": s.repetition_penalty,
+        "dry": s.dry.as_ref().map(|d| json!({
+            "multiplier": d.multiplier,
+            "base": d.base,
+            "allowed_length": d.allowed_length,
+            "sequence_breakers": d.sequence_breakers,
+        })),
+        "xtc": s.xtc.map(|x| json!({ "probability": x.probability, "threshold": x.threshold })),
+        "loop_guard": s.loop_guard.map(|g| json!({
+            "action": g.action.name(),
+            "value": match g.action {
+                LoopAction::Stop => None,
+                LoopAction::Penalize(v) | LoopAction::Diversify(v) => Some(v),
+            },
+            "max_period": g.max_period,
+            "min_repeats": g.min_repeats,
+            "min_tokens": g.min_tokens,
+        })),
+        "max_thinking": s.max_thinking,
+        "response_format": match (&s.regex, s.json_object) {
+            (Some(regex), _) => json!({ "type": "regex", "pattern": regex.pattern() }),
+            (None, true) => json!({ "type": "json_object" }),
+            (None, false) => json!({ "type": "text" }),
+        },
+    })
+}
+
+/// `GenerateOptions` from a `generate` request; absent fields keep their defaults.
+pub fn options_from_json(req: &Value) -> Result<GenerateOptions> {
+    let f32_or = |v: &Value, default: f32| v.as_f64().map_or(default, |x| x as f32);
+    let mut sampling = SamplerConfig {
+        seed: req["seed"].as_u64(),
+        ..SamplerConfig::default()
+    };
+    sampling.temperature = f32_or(&req["temperature"], sampling.temperature);
+    sampling.top_k = req["top_k"].as_u64().map_or(sampling.top_k, |k| k as usize);
+    sampling.top_p = f32_or(&req["top_p"], sampling.top_p);
+    sampling.repetition_penalty = f32_or(&req["repeat_penalty"], sampling.repetition_penalty);
+    // Indexing a `Value` (not its map) yields null for a missing key, so
+    // partial objects fall back field by field.
+    if let d @ Value::Object(_) = &req["dry"] {
+        let defaults = DryConfig::default();
+        sampling.dry = Some(DryConfig {
+            multiplier: f32_or(&d["multiplier"], defaults.multiplier),
+            base: f32_or(&d["base"], defaults.base),
+            allowed_length: d["allowed_length"].as_u64().map_or(defaults.allowed_length, |n| n as usize),
+            sequence_breakers: match d["sequence_breakers"].as_array() {
+                Some(b) => b.iter().filter_map(|s| s.as_str().map(String::from)).collect(),
+                None => defaults.sequence_breakers,
+            },
+        });
+    }
+    if let x @ Value::Object(_) = &req["xtc"] {
+        sampling.xtc = Some(XtcConfig { probability: f32_or(&x["probability"], 0.0), threshold: f32_or(&x["threshold"], 0.1) });
+    }
+    if let g @ Value::Object(_) = &req["loop_guard"] {
+        let defaults = LoopGuard::default();
+        let n_or = |v: &Value, default: usize| v.as_u64().map_or(default, |n| n as usize);
+        let action = match (g["action"].as_str(), g["value"].as_f64()) {
+            (None, _) => defaults.action,
+            (Some(name), None) => name.parse()?,
+            (Some(name), Some(v)) => format!("{name}={v}").parse()?,
+        };
+        sampling.loop_guard = Some(LoopGuard {
+            max_period: n_or(&g["max_period"], defaults.max_period),
+            min_repeats: n_or(&g["min_repeats"], defaults.min_repeats),
+            min_tokens: n_or(&g["min_tokens"], defaults.min_tokens),
+            action,
+        });
+    }
+    sampling.max_thinking = match (req["max_thinking"].as_u64(), req["reasoning_effort"].as_str()) {
+        (Some(n), _) => Some(n as usize),
+        (None, Some(effort)) => Some(crate::reasoning::effort_budget(effort)?),
+        (None, None) => None,
+    };
+    match req["response_format"]["type"].as_str() {
+        None | Some("text") => {}
+        Some("json_object") => sampling.json_object = true,
+        Some("regex") => {
+            let pattern = req["response_format"]["pattern"].as_str().context("response_format regex needs a \"pattern\"")?;
+            sampling.regex = Some(Arc::new(Regex::new(pattern).with_context(|| format!("regex {pattern:?}"))?));
+        }
+        Some(other) => bail!("unsupported response_format {other:?}"),
+    }
+    let stop_tokens = match req["stop_tokens"].as_array() {
+        Some(ids) => ids.iter().filter_map(|id| id.as_u64().map(|id| id as u32)).collect(),
+        None => Vec::new(),
+    };
+    Ok(GenerateOptions {
+        max_new: req["max_tokens"].as_u64().map_or(64, |n| n as usize),
+        sampling,
+        stop_tokens,
+        ..GenerateOptions::default()
+    })
+}
+
+fn token_ids(ids: &Value) -> Result<Vec<u32>> {
+    ids.as_array()
+        .context("\"tokens\" must be an array")?
+        .iter()
+        .map(|id| id.as_u64().and_then(|id| u32::try_from(id).ok()).context("\"tokens\" must be token ids"))
+        .collect()
+}
+
+fn section_stats_to_json(s: &SectionStats) -> Value {
+    json!({ "sections": s.sections, "bytes": s.bytes, "hits": s.hits, "misses": s.misses })
+}
+
+fn spans_to_json(spans: &[TokenSpan]) -> Value {
+    spans.iter().map(|t| json!({ "id": t.id, "start": t.start, "end": t.end, "special": t.special })).collect()
+}
+
+fn spans_from_json(v: &Value) -> Vec<TokenSpan> {
+    let count = |v: &Value| v.as_u64().unwrap_or(0) as usize;
+    v.as_array().into_iter().flatten().map(|t| TokenSpan {
+        id: count(&t["id"]) as u32,
+        start: count(&t["start"]),
+        end: count(&t["end"]),
+        special: t["special"].as_bool().unwrap_or(false),
+    }).collect()
+}
+
+fn kv_stats_to_json(s: &KvStats) -> Value {
+    json!({ "positions": s.positions, "bytes": s.bytes, "slack_bytes": s.slack_bytes })
+}
+
+fn timings_to_json(t: &Timings) -> Value {
+    json!({
+        "load_ms": t.load_ms,
+        "prefill_tokens": t.prefill_tokens,
+        "prefill_ms": t.prefill_ms,
+        "decode_tokens": t.decode_tokens,
+        "decode_ms": t.decode_ms,
+        "sample_ms": t.sample_ms,
+        "detokenize_ms": t.detokenize_ms,
+        "decode_tokens_per_second": t.decode_tps(),
+        "drafted_tokens": t.drafted_tokens,
+        "accepted_tokens": t.accepted_tokens,
+        "draft_len": t.draft_len,
+    })
+}
+
+fn timings_from_json(v: &Value) -> Timings {
+    let ms = |k: &str| v[k].as_u64().unwrap_or(0) as u128;
+    let count = |k: &str| v[k].as_u64().unwrap_or(0) as usize;
+    Timings {
+        load_ms: ms("load_ms"),
+        prefill_tokens: count("prefill_tokens"),
+        prefill_ms: ms("prefill_ms"),
+        decode_tokens: count("decode_tokens"),
+        decode_ms: ms("decode_ms"),
+        sample_ms: ms("sample_ms"),
+        detokenize_ms: ms("detokenize_ms"),
+        drafted_tokens: count("drafted_tokens"),
+        accepted_tokens: count("accepted_tokens"),
+        draft_len: count("draft_len"),
+    }
+}
+
+pub struct DaemonInfo {
+    pub model: String,
+    pub ctx_train: usize,
+    pub stop_tokens: Vec<u32>,
+    pub kv_cache: KvStats,
+}
+
+/// A connection to a running daemon.
+pub struct DaemonClient {
+    socket: PathBuf,
+    stream: UnixStream,
+    session: Option<String>,
+}
+
+impl DaemonClient {
+    pub fn connect(socket: &Path) -> Result<Self> {
+        let stream = UnixStream::connect(socket).with_context(|| format!("connect {}", socket.display()))?;
+        Ok(Self { socket: socket.to_path_buf(), stream, session: None })
+    }
+
+    /// Generate in the daemon's session `id` rather than the anonymous one.
+    pub fn with_session(mut self, id: &str) -> Self {
+        self.session = Some(id.to_string());
+        self
+    }
+
+    /// The daemon serving `model_path` at its default socket, if one is up.
+    pub fn for_model(model_path: &str) -> Option<Self> {
+        Self::connect(&default_socket_path(model_path)).ok()
+    }
+
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    /// The `info` reply, for a client that tokenizes on its own.
+    pub fn info(&mut self) -> Result<DaemonInfo> {
+        let info = self.request(&json!({ "op": "info" }))?;
+        let count = |v: &Value| v.as_u64().unwrap_or(0) as usize;
+        let stop_tokens = info["stop_tokens"].as_array().map_or(Vec::new(), |ids| {
+            ids.iter().filter_map(|id| id.as_u64().map(|id| id as u32)).collect()
+        });
+        Ok(DaemonInfo {
+            model: info["model"].as_str().unwrap_or_default().to_string(),
+            ctx_train: info["ctx_train"].as_u64().context("daemon info has no ctx_train")? as usize,
+            stop_tokens,
+            kv_cache: KvStats {
+                positions: count(&info["kv_cache"]["positions"]),
+                bytes: count(&info["kv_cache"]["bytes"]),
+                slack_bytes: count(&info["kv_cache"]["slack_bytes"]),
+            },
+        })
+    }
+
+    /// The daemon's tokenization of `text` as a `generate` prompt, BOS included.
+    pub fn tokenize(&mut self, text: &str) -> Result<Vec<TokenSpan>> {
+        let reply = self.request(&json!({ "op": "tokenize", "text": text }))?;
+        Ok(spans_from_json(&reply["tokens"]))
+    }
+
+    /// The text of `ids`, special tokens included, and each one's span in it.
+    pub fn detokenize(&mut self, ids: &[u32]) -> Result<(String, Vec<TokenSpan>)> {
+        let reply = self.request(&json!({ "op": "detokenize", "tokens": ids }))?;
+        Ok((reply["text"].as_str().unwrap_or_default().to_string(), spans_from_json(&reply["tokens"])))
+    }
+
+    /// Compact the daemon's KV cache; returns the bytes freed.
+    pub fn compact(&mut self) -> Result<usize> {
+        let reply = self.request(&json!({ "op": "compact" }))?;
+        Ok(reply["freed_bytes"].as_u64().unwrap_or(0) as usize)
+    }
+
+    /// Send a request with a single reply frame (anything but `generate`).
+    pub fn request(&mut self, req: &Value) -> Result<Value> {
+        write_frame(&mut self.stream, req)?;
+        let reply = read_frame(&mut self.stream)?.context("daemon closed the connection")?;
+        check(reply)
+    }
+
+    /// Send a `generate` request, passing every event frame to `on_event`
+    /// until the final `done` frame, which is returned.
+    pub fn generate(&mut self, req: &Value, on_event: &mut dyn FnMut(&Value)) -> Result<Value> {
+        write_frame(&mut self.stream, req)?;
+        loop {
+            let frame = check(read_frame(&mut self.stream)?.context("daemon closed the connection mid-reply")?)?;
+            if frame["event"] == "done" {
+                return Ok(frame);
+            }
+            on_event(&frame);
+        }
+    }
+}
+
+/// Generation through the daemon. Prompts go as token ids, so templating
+/// and special-token handling stay with the caller; `session` is unused, as
+/// the daemon keeps its own.
+impl Generator for DaemonClient {
+    fn generate_cached(
+        &mut self,
+        _session: &mut KvSession,
+        tokens: &[u32],
+        opts: &GenerateOptions,
+        _vocab: &[String],
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<usize> {
+        ensure!(
+            opts.cfg.is_none() && opts.draft.is_none(),
+            "the daemon protocol carries neither classifier-free guidance nor speculative decoding"
+        );
+        ensure!(opts.sampling.processors.is_empty(), "logits processors run in-process; load the model locally");
+        let mut req = options_to_json(opts);
+        req["op"] = "generate".into();
+        req["tokens"] = tokens.into();
+        if let Some(id) = &self.session {
+            req["session"] = id.as_str().into();
+        }
+        write_frame(&mut self.stream, &req)?;
+        let mut completion_tokens = 0;
+        let done = loop {
+            if opts.cancel.as_ref().is_some_and(|c| c.load(Ordering::Relaxed)) {
+                // Hanging up is how a client cancels; the daemon stops at its
+                // next token. The fresh connection waits its turn behind it.
+                self.stream = UnixStream::connect(&self.socket)?;
+                let usage = Usage { prompt_tokens: tokens.len(), completion_tokens, cached_tokens: 0 };
+                on_event(GenerationEvent::Done { reason: FinishReason::Cancelled, timings: Timings::default(), usage });
+                return Ok(0);
+            }
+            let frame = check(read_frame(&mut self.stream)?.context("daemon closed the connection mid-reply")?)?;
+            match frame["event"].as_str() {
+                Some("progress") => on_event(GenerationEvent::PrefillProgress {
+                    done: frame["done"].as_u64().unwrap_or(0) as usize,
+                    total: frame["total"].as_u64().unwrap_or(0) as usize,
+                    ms: frame["ms"].as_u64().unwrap_or(0) as u128,
+                    eta_ms: frame["eta_ms"].as_u64().unwrap_or(0) as u128,
+                }),
+                Some("prompt") => on_event(GenerationEvent::PromptProcessed {
+                    n_tokens: frame["n_tokens"].as_u64().unwrap_or(0) as usize,
+                    ms: frame["ms"].as_u64().unwrap_or(0) as u128,
+                }),
+                Some("token") => {
+                    completion_tokens += 1;
+                    on_event(GenerationEvent::Token {
+                        id: frame["id"].as_u64().unwrap_or(0) as u32,
+                        text: frame["text"].as_str().unwrap_or_default().to_string(),
+                        logprob: frame["logprob"].as_f64().unwrap_or(0.0) as f32,
+                    })
+                }
+                Some("done") => break frame,
+                _ => {}
+            }
+        };
+        let reason = match done["finish_reason"].as_str() {
+            Some("eos") => FinishReason::Eos,
+            Some("stop") => FinishReason::Stop,
+            Some("cancelled") => FinishReason::Cancelled,
+            Some("loop") => FinishReason::Loop,
+            _ => FinishReason::MaxTokens,
+        };
+        let count = |v: &Value| v.as_u64().unwrap_or(0) as usize;
+        let usage = Usage {
+            prompt_tokens: count(&done["usage"]["prompt_tokens"]),
+            completion_tokens: count(&done["usage"]["completion_tokens"]),
+            cached_tokens: count(&done["usage"]["prompt_tokens_details"]["cached_tokens"]),
+        };
+        on_event(GenerationEvent::Done { reason, timings: timings_from_json(&done["timings"]), usage });
+        Ok(usage.cached_tokens)
+    }
+}
+
+fn check(frame: Value) -> Result<Value> {
+    if frame["event"] == "error" {
+        bail!("daemon: {}", frame["message"].as_str().unwrap_or("unknown error"));
+    }
+    Ok(frame)
+}
@@ -0,0 +1,238 @@
+//! Intermediate activations on disk, for bisecting numerical divergence.
+//!
+//! Tensors are named after llama.cpp's graph (`attn_norm-3`, `kqv_out-3`,
+//! `ffn_inp-3`, `ffn_norm-3`, `ffn_out-3`, `l_out-3`, `result_norm`,
+//! `result_output`) so a dump from its eval callback lines up name for name.
+//! Each tensor is one `<name>.f32` file: little-endian f32, one row per token,
+//! row-major. `manifest.tsv` lists `name rows cols` in graph order, which is
+//! also the order `compare` walks to find the first layer that disagrees.
+//!
+//! `read_logits` and `compare_logits` hold the final logits against another
+//! implementation's for the same model and tokens, position by position,
+//! for certifying parity with llama.cpp. `first_divergent` then walks one
+//! token's row through a reference dump to the first tensor that disagrees.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail, ensure};
+
+use crate::quality::kl_divergence;
+use crate::sampler::argmax;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DumpedTensor {
+    pub name: String,
+    pub cols: usize,
+    pub data: Vec<f32>,
+}
+
+impl DumpedTensor {
+    pub fn rows(&self) -> usize {
+        self.data.len() / self.cols.max(1)
+    }
+}
+
+/// Activations in the order they were first produced.
+#[derive(Default)]
+pub struct ActivationDump {
+    tensors: Vec<DumpedTensor>,
+    by_name: HashMap<String, usize>,
+}
+
+impl ActivationDump {
+    /// Append rows (`data.len() / cols` tokens) to the tensor `name`.
+    pub fn record(&mut self, name: &str, data: &[f32], cols: usize) {
+        match self.by_name.get(name) {
+            Some(&i) => self.tensors[i].data.extend_from_slice(data),
+            None => {
+                self.by_name.insert(name.to_string(), self.tensors.len());
+                self.tensors.push(DumpedTensor { name: name.to_string(), cols, data: data.to_vec() });
+            }
+        }
+    }
+
+    pub fn tensors(&self) -> &[DumpedTensor] {
+        &self.tensors
+    }
+
+    pub fn get(&self, name: &str) -> Option<&DumpedTensor> {
+        self.by_name.get(name).map(|&i| &self.tensors[i])
+    }
+
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        let mut manifest = String::new();
+        for t in &self.tensors {
+            let bytes: Vec<u8> = t.data.iter().flat_map(|v| v.to_le_bytes()).collect();
+            let path = dir.join(format!("{}.f32", t.name));
+            std::fs::write(&path, bytes).with_context(|| format!("write {}", path.display()))?;
+            writeln!(manifest, "{}\t{}\t{}", t.name, t.rows(), t.cols)?;
+        }
+        std::fs::write(dir.join("manifest.tsv"), manifest)?;
+        Ok(())
+    }
+
+    pub fn read(dir: &Path) -> Result<Self> {
+        let manifest = std::fs::read_to_string(dir.join("manifest.tsv"))
+            .with_context(|| format!("read {}/manifest.tsv", dir.display()))?;
+        let mut dump = Self::default();
+        for line in manifest.lines().filter(|l| !l.trim().is_empty()) {
+            let [name, rows, cols] = line.split('\t').collect::<Vec<_>>()[..] else {
+                bail!("bad manifest line: {line:?}");
+            };
+            let (rows, cols): (usize, usize) = (rows.parse()?, cols.parse()?);
+            let bytes = std::fs::read(dir.join(format!("{name}.f32")))
+                .with_context(|| format!("read {name}.f32"))?;
+            ensure!(bytes.len() == rows * cols * 4, "{name}.f32 is {} bytes, manifest says {rows}x{cols}", bytes.len());
+            let data: Vec<f32> = bytes
+                .chunks_exact(4)
+                .map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect();
+            dump.record(name, &data, cols);
+        }
+        Ok(dump)
+    }
+}
+
+/// How far one tensor of `b` is from the same tensor of `a`.
+#[derive(Clone, Debug)]
+pub struct TensorDiff {
+    pub name: String,
+    pub max_abs: f32,
+    /// `max_abs` over the largest magnitude in `a`.
+    pub rel: f32,
+}
+
+/// Diff every tensor present in both dumps, in `a`'s graph order. Tensors
+/// whose shapes differ are reported with an infinite difference.
+pub fn compare(a: &ActivationDump, b: &ActivationDump) -> Vec<TensorDiff> {
+    a.tensors
+        .iter()
+        .filter_map(|ta| {
+            let tb = &b.tensors[*b.by_name.get(&ta.name)?];
+            let (max_abs, rel) = if (ta.cols, ta.data.len()) != (tb.cols, tb.data.len()) {
+                (f32::INFINITY, f32::INFINITY)
+            } else {
+                let max_abs = ta.data.iter().zip(&tb.data).map(|(x, y)| diff(*x, *y)).fold(0.0, f32::max);
+                let scale = ta.data.iter().map(|x| x.abs()).fold(0.0, f32::max);
+                (max_abs, if scale > 0.0 { max_abs / scale } else { max_abs })
+            };
+            Some(TensorDiff { name: ta.name.clone(), max_abs, rel })
+        })
+        .collect()
+}
+
+/// Logit rows of width `n_vocab` from another implementation:
+/// - a dump directory, whose `result_output` tensor is the logits;
+/// - a `.txt` file of one row per line, or of `index: value` lines for a
+///   single row (what llama.cpp's model-conversion `logits` tool writes);
+/// - anything else as raw little-endian f32, rows back to back, e.g. the
+///   `.bin` next to that `.txt`, or `llama_get_logits` saved for every
+///   position.
+pub fn read_logits(path: &Path, n_vocab: usize) -> Result<Vec<Vec<f32>>> {
+    ensure!(n_vocab > 0, "empty vocabulary");
+    let data = if path.is_dir() {
+        let dump = ActivationDump::read(path)?;
+        let t = dump.get("result_output").with_context(|| format!("{} has no result_output tensor", path.display()))?;
+        ensure!(t.cols == n_vocab, "result_output is {} wide, the model's vocabulary is {n_vocab}", t.cols);
+        t.data.clone()
+    } else if path.extension().is_some_and(|e| e == "txt") {
+        let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let mut data = Vec::new();
+        for (i, line) in text.lines().enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+            let values = line.split_once(':').map_or(line, |(_, v)| v);
+            for v in values.split_whitespace() {
+                data.push(v.parse::<f32>().with_context(|| format!("{}:{}: bad number {v:?}", path.display(), i + 1))?);
+            }
+        }
+        data
+    } else {
+        let bytes = std::fs::read(path).with_context(|| format!("read {}", path.display()))?;
+        ensure!(bytes.len() % 4 == 0, "{} is not a whole number of f32s", path.display());
+        bytes.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect()
+    };
+    ensure!(
+        !data.is_empty() && data.len() % n_vocab == 0,
+        "{} holds {} values, not whole rows of {n_vocab}",
+        path.display(),
+        data.len()
+    );
+    Ok(data.chunks(n_vocab).map(<[f32]>::to_vec).collect())
+}
+
+/// How far one position's logits are from the reference's.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PositionDiff {
+    pub pos: usize,
+    pub max_abs: f32,
+    /// `max_abs` over the largest reference logit magnitude.
+    pub rel: f32,
+    pub top_ours: u32,
+    pub top_reference: u32,
+    /// KL(reference ‖ ours) in nats.
+    pub kl: f64,
+}
+
+/// Diff `ours` against `reference` position by position. A reference with
+/// fewer rows covers the last positions: llama.cpp only keeps the logits
+/// of the final token unless asked for all of them.
+pub fn compare_logits(ours: &[Vec<f32>], reference: &[Vec<f32>]) -> Result<Vec<PositionDiff>> {
+    ensure!(
+        reference.len() <= ours.len(),
+        "the reference has {} positions, more than the {} evaluated here; are the prompts tokenized the same?",
+        reference.len(),
+        ours.len()
+    );
+    let offset = ours.len() - reference.len();
+    reference
+        .iter()
+        .enumerate()
+        .map(|(i, r)| {
+            let (pos, o) = (offset + i, &ours[offset + i]);
+            ensure!(o.len() == r.len(), "position {pos}: {} logits here, {} in the reference", o.len(), r.len());
+            let max_abs = r.iter().zip(o).map(|(x, y)| diff(*x, *y)).fold(0.0, f32::max);
+            let scale = r.iter().map(|x| x.abs()).fold(0.0, f32::max);
+            Ok(PositionDiff {
+                pos,
+                max_abs,
+                rel: if scale > 0.0 { max_abs / scale } else { max_abs },
+                top_ours: argmax(o),
+                top_reference: argmax(r),
+                kl: kl_divergence(r, o),
+            })
+        })
+        .collect()
+}
+
+/// The first tensor, in `reference`'s graph order, whose row `pos` is
+/// further than `tol` (relative) from the same row of `ours`. Tensors with
+/// one row per token only; a tensor missing from `ours` is skipped.
+pub fn first_divergent(reference: &ActivationDump, ours: &ActivationDump, pos: usize, tol: f32) -> Option<TensorDiff> {
+    reference.tensors.iter().find_map(|tr| {
+        let to = ours.get(&tr.name)?;
+        if pos >= tr.rows() || tr.rows() != to.rows() {
+            return None;
+        }
+        let r = &tr.data[pos * tr.cols..(pos + 1) * tr.cols];
+        let max_abs = if tr.cols != to.cols {
+            f32::INFINITY
+        } else {
+            let o = &to.data[pos * to.cols..(pos + 1) * to.cols];
+            r.iter().zip(o).map(|(x, y)| diff(*x, *y)).fold(0.0, f32::max)
+        };
+        let scale = r.iter().map(|x| x.abs()).fold(0.0, f32::max);
+        let rel = if scale > 0.0 { max_abs / scale } else { max_abs };
+        (rel > tol).then(|| TensorDiff { name: tr.name.clone(), max_abs, rel })
+    })
+}
+
+/// NaN on one side only is as far apart as it gets; NaN on both is agreement.
+fn diff(x: f32, y: f32) -> f32 {
+    match (x.is_nan(), y.is_nan()) {
+        (true, true) => 0.0,
+        (false, false) => (x - y).abs(),
+        _ => f32::INFINITY,
+    }
+}
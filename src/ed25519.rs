@@ -0,0 +1,41 @@
+//! SHA-512 and Ed25519 signatures (RFC 8032), for signed model manifests:
+//! the `sha2` and `ed25519-dalek` crates behind the byte-array interface
+//! `manifest` uses.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use sha2::Digest;
+
+/// Streaming SHA-512.
+#[derive(Clone, Default)]
+pub struct Sha512(sha2::Sha512);
+
+impl Sha512 {
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> [u8; 64] {
+        self.0.finalize().into()
+    }
+}
+
+pub fn sha512(data: &[u8]) -> [u8; 64] {
+    sha2::Sha512::digest(data).into()
+}
+
+/// The public key for a 32-byte secret seed.
+pub fn public_key(seed: &[u8; 32]) -> [u8; 32] {
+    SigningKey::from_bytes(seed).verifying_key().to_bytes()
+}
+
+/// The 64-byte signature `R || S` of `msg`.
+pub fn sign(seed: &[u8; 32], msg: &[u8]) -> [u8; 64] {
+    SigningKey::from_bytes(seed).sign(msg).to_bytes()
+}
+
+/// Whether `sig` is `public_key`'s signature of `msg`. The check is
+/// `verify_strict`: non-canonical `S` values and small-order keys are
+/// rejected, so a valid signature cannot be re-encoded.
+pub fn verify(public_key: &[u8; 32], msg: &[u8], sig: &[u8; 64]) -> bool {
+    VerifyingKey::from_bytes(public_key).is_ok_and(|key| key.verify_strict(msg, &Signature::from_bytes(sig)).is_ok())
+}
@@ -0,0 +1,168 @@
+//! Bulk embeddings: JSONL documents in, one vector per document out.
+//!
+//! A document's vector is its final normed hidden states pooled over tokens
+//! (mean by default, or the first/last token for models trained that way) and
+//! L2-normalised. Each document runs as one batched forward pass. Documents
+//! are read and tokenized in micro-batches across threads while the GPU
+//! works through the previous batch's sequences.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+
+use crate::tokenizer::PromptTokenizer;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pooling {
+    Mean,
+    /// First token: BERT's `[CLS]`.
+    Cls,
+    Last,
+}
+
+impl Pooling {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Mean => "mean",
+            Self::Cls => "cls",
+            Self::Last => "last",
+        }
+    }
+}
+
+impl std::str::FromStr for Pooling {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mean" => Ok(Self::Mean),
+            "cls" => Ok(Self::Cls),
+            "last" => Ok(Self::Last),
+            _ => bail!("unknown pooling '{s}' (expected mean, cls or last)"),
+        }
+    }
+}
+
+/// Pool packed `[tokens, dim]` hidden states into one `dim` vector.
+pub fn pool(hidden: &[f32], dim: usize, pooling: Pooling) -> Vec<f32> {
+    let rows = hidden.chunks_exact(dim);
+    match pooling {
+        Pooling::Cls => hidden.get(..dim).map(<[f32]>::to_vec).unwrap_or_else(|| vec![0.0; dim]),
+        Pooling::Last => rows.last().map(<[f32]>::to_vec).unwrap_or_else(|| vec![0.0; dim]),
+        Pooling::Mean => {
+            let n = rows.len().max(1) as f32;
+            let mut out = vec![0.0; dim];
+            for row in rows {
+                out.iter_mut().zip(row).for_each(|(o, v)| *o += v);
+            }
+            out.iter_mut().for_each(|o| *o /= n);
+            out
+        }
+    }
+}
+
+pub fn l2_normalize(v: &mut [f32]) {
+    let norm = v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        v.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+/// One input line: `{"id": ..., "text": "..."}`, or a bare JSON string.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Document {
+    pub id: Option<String>,
+    pub text: String,
+}
+
+pub fn read_jsonl(path: &Path) -> Result<Vec<Document>> {
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let mut docs = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        docs.push(parse_document(&line).with_context(|| format!("{}:{}", path.display(), i + 1))?);
+    }
+    Ok(docs)
+}
+
+pub fn parse_document(line: &str) -> Result<Document> {
+    let value: serde_json::Value = serde_json::from_str(line).context("invalid JSON")?;
+    match value {
+        serde_json::Value::String(text) => Ok(Document { id: None, text }),
+        serde_json::Value::Object(obj) => {
+            let text = obj.get("text").and_then(|t| t.as_str()).context("missing string field \"text\"")?;
+            let id = obj.get("id").map(|id| match id {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            });
+            Ok(Document { id, text: text.to_string() })
+        }
+        _ => bail!("expected an object with \"text\" or a string"),
+    }
+}
+
+/// Tokenize `texts` on up to `threads` threads, preserving order.
+/// Each sequence is BOS-prefixed and cut to `max_tokens`.
+pub fn tokenize_parallel(tokenizer: &PromptTokenizer, texts: &[&str], threads: usize, max_tokens: usize) -> Vec<Vec<u32>> {
+    let per_thread = texts.len().div_ceil(threads.max(1)).max(1);
+    std::thread::scope(|s| {
+        let handles: Vec<_> = texts
+            .chunks(per_thread)
+            .map(|chunk| {
+                s.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|t| {
+                            let mut ids = tokenizer.tokenize_bos(t);
+                            ids.truncate(max_tokens);
+                            ids
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    })
+}
+
+/// Write `rows` as a little-endian f32 `.npy` array of shape `(rows, dim)`.
+pub fn write_npy(path: &Path, rows: &[Vec<f32>]) -> Result<()> {
+    let dim = rows.first().map_or(0, Vec::len);
+    if rows.iter().any(|r| r.len() != dim) {
+        bail!("embeddings have mixed dimensions");
+    }
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {dim}), }}", rows.len());
+    // Magic (6) + version (2) + header length (2) + header, padded with
+    // spaces to a multiple of 64 and ending in a newline.
+    let total = (10 + header.len() + 1).next_multiple_of(64);
+    header.push_str(&" ".repeat(total - 10 - header.len() - 1));
+    header.push('\n');
+
+    let mut w = BufWriter::new(File::create(path).with_context(|| format!("create {}", path.display()))?);
+    w.write_all(b"\x93NUMPY\x01\x00")?;
+    w.write_all(&(header.len() as u16).to_le_bytes())?;
+    w.write_all(header.as_bytes())?;
+    for row in rows {
+        for v in row {
+            w.write_all(&v.to_le_bytes())?;
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+/// One `{"id": ..., "embedding": [...]}` object per line, in input order.
+pub fn write_jsonl(path: &Path, docs: &[Document], rows: &[Vec<f32>]) -> Result<()> {
+    let mut w = BufWriter::new(File::create(path).with_context(|| format!("create {}", path.display()))?);
+    for (i, (doc, row)) in docs.iter().zip(rows).enumerate() {
+        let id = doc.id.clone().unwrap_or_else(|| i.to_string());
+        writeln!(w, "{}", serde_json::json!({ "id": id, "embedding": row }))?;
+    }
+    w.flush()?;
+    Ok(())
+}
@@ -0,0 +1,160 @@
+//! Sealed model files: a GGUF encrypted and authenticated with AES-256-GCM,
+//! for fine-tunes that ship inside an app and must not be readable off disk.
+//!
+//! Layout: the 8-byte `MAGIC`, a 12-byte nonce, the ciphertext and the
+//! 16-byte tag. The magic is the associated data, so a header edit fails
+//! the tag check like any other. Every loader goes through `map`, which
+//! decrypts a sealed file into anonymous memory (page-aligned, so it still
+//! wraps into a no-copy Metal buffer) and maps a plain GGUF as before.
+//!
+//! The key is 32 bytes, hex-encoded, from `LLMETAL_MODEL_KEY` or, on macOS,
+//! the login keychain item with service `llmetal` and the sealed file's
+//! name as the account. The cipher is the `aes-gcm` crate, which uses the
+//! CPU's AES and carry-less multiply instructions, and is constant-time.
+//! GCM's 32-bit block counter caps one message at `MAX_PAYLOAD` (just under
+//! 64 GiB); a longer model is refused rather than reusing keystream.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce, Tag};
+use anyhow::{Context, Result, anyhow, ensure};
+use memmap2::{Mmap, MmapMut};
+
+pub const MAGIC: &[u8; 8] = b"LLMSEAL1";
+const NONCE: usize = 12;
+const TAG: usize = 16;
+pub const KEY_ENV: &str = "LLMETAL_MODEL_KEY";
+/// The longest payload one nonce covers: 2^32 - 2 blocks of 16 bytes.
+pub const MAX_PAYLOAD: u64 = ((1 << 32) - 2) * 16;
+
+/// Whether `bytes` starts like a sealed file.
+pub fn is_sealed(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+/// The model file at `path`, decrypted if it is sealed, and checked
+/// against its manifest if `manifest::require` registered one.
+pub fn map(path: &str) -> Result<Mmap> {
+    let mmap = map_unchecked(path)?;
+    crate::manifest::check_mapping(path, &mmap)?;
+    Ok(mmap)
+}
+
+/// `map` without the manifest check.
+pub fn map_unchecked(path: &str) -> Result<Mmap> {
+    let file = File::open(path).with_context(|| format!("open {path}"))?;
+    let mmap = unsafe { Mmap::map(&file) }.context("mmap")?;
+    if !is_sealed(&mmap) {
+        return Ok(mmap);
+    }
+    let key = load_key(Path::new(path))?;
+    let mut plain = MmapMut::map_anon(mmap.len().saturating_sub(MAGIC.len() + NONCE + TAG).max(1))
+        .context("allocate decrypted model")?;
+    open_into(&mmap, &key, &mut plain).with_context(|| format!("unseal {path}"))?;
+    Ok(plain.make_read_only()?)
+}
+
+/// `plain` encrypted under `key`. The nonce must never repeat for a key;
+/// `seal_file` draws it from the OS.
+pub fn seal(plain: &[u8], key: &[u8; 32], nonce: &[u8; NONCE]) -> Result<Vec<u8>> {
+    check_len(plain.len() as u64)?;
+    let mut out = Vec::with_capacity(MAGIC.len() + NONCE + plain.len() + TAG);
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(nonce);
+    out.extend_from_slice(plain);
+    let tag = encrypt_in_place(key, nonce, MAGIC, &mut out[MAGIC.len() + NONCE..])?;
+    out.extend_from_slice(&tag);
+    Ok(out)
+}
+
+/// AES-256-GCM: encrypt `data` in place and return the tag over `aad` and
+/// the ciphertext.
+pub fn encrypt_in_place(key: &[u8; 32], nonce: &[u8; NONCE], aad: &[u8], data: &mut [u8]) -> Result<[u8; TAG]> {
+    check_len(data.len() as u64)?;
+    let tag = Aes256Gcm::new(key.into())
+        .encrypt_in_place_detached(Nonce::from_slice(nonce), aad, data)
+        .map_err(|_| anyhow!("AES-GCM encryption failed"))?;
+    Ok(tag.into())
+}
+
+fn check_len(len: u64) -> Result<()> {
+    ensure!(len <= MAX_PAYLOAD, "{len} bytes is more than one AES-GCM message can hold ({MAX_PAYLOAD})");
+    Ok(())
+}
+
+/// Decrypt a sealed file into `plain`, which must be at least the payload's
+/// length. Nothing is returned unless the tag matches.
+pub fn open_into(sealed: &[u8], key: &[u8; 32], plain: &mut [u8]) -> Result<()> {
+    ensure!(is_sealed(sealed) && sealed.len() >= MAGIC.len() + NONCE + TAG, "not a sealed model file");
+    let nonce = Nonce::from_slice(&sealed[MAGIC.len()..MAGIC.len() + NONCE]);
+    let (body, tag) = sealed[MAGIC.len() + NONCE..].split_at(sealed.len() - MAGIC.len() - NONCE - TAG);
+    check_len(body.len() as u64)?;
+    let plain = &mut plain[..body.len()];
+    plain.copy_from_slice(body);
+    // The tag is checked (in constant time) before anything is decrypted.
+    Aes256Gcm::new(key.into())
+        .decrypt_in_place_detached(nonce, MAGIC, plain, Tag::from_slice(tag))
+        .map_err(|_| anyhow!("authentication failed: wrong key, or the file was modified"))
+}
+
+/// `open_into` a fresh buffer.
+pub fn open(sealed: &[u8], key: &[u8; 32]) -> Result<Vec<u8>> {
+    let mut plain = vec![0; sealed.len().saturating_sub(MAGIC.len() + NONCE + TAG)];
+    open_into(sealed, key, &mut plain)?;
+    Ok(plain)
+}
+
+/// Seal the GGUF at `input` as `output`, with a random nonce.
+pub fn seal_file(input: &Path, output: &Path, key: &[u8; 32]) -> Result<()> {
+    let len = std::fs::metadata(input).with_context(|| format!("stat {}", input.display()))?.len();
+    check_len(len).with_context(|| format!("{} is too large to seal", input.display()))?;
+    let plain = std::fs::read(input).with_context(|| format!("read {}", input.display()))?;
+    ensure!(plain.starts_with(b"GGUF"), "{} is not a GGUF file", input.display());
+    let mut nonce = [0u8; NONCE];
+    File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut nonce)).context("read /dev/urandom")?;
+    std::fs::write(output, seal(&plain, key, &nonce)?).with_context(|| format!("write {}", output.display()))
+}
+
+/// The key for the sealed file at `path`: `LLMETAL_MODEL_KEY`, else the
+/// keychain.
+pub fn load_key(path: &Path) -> Result<[u8; 32]> {
+    if let Ok(hex) = std::env::var(KEY_ENV) {
+        return parse_key(&hex).with_context(|| format!("${KEY_ENV}"));
+    }
+    let account = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let hex = keychain_password(&account).with_context(|| {
+        format!("{} is sealed: set ${KEY_ENV} or add keychain item service \"llmetal\", account {account:?}", path.display())
+    })?;
+    parse_key(&hex).context("keychain item")
+}
+
+/// 64 hex digits.
+pub fn parse_key(hex: &str) -> Result<[u8; 32]> {
+    let hex = hex.trim();
+    // Digits only, so the slicing below stays on char boundaries.
+    ensure!(hex.bytes().all(|b| b.is_ascii_hexdigit()), "a model key is 64 hex digits");
+    ensure!(hex.len() == 64, "a model key is 64 hex digits, got {}", hex.len());
+    let mut key = [0u8; 32];
+    for (i, b) in key.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).context("a model key is 64 hex digits")?;
+    }
+    Ok(key)
+}
+
+#[cfg(target_os = "macos")]
+fn keychain_password(account: &str) -> Result<String> {
+    let out = std::process::Command::new("/usr/bin/security")
+        .args(["find-generic-password", "-s", "llmetal", "-a", account, "-w"])
+        .output()
+        .context("run security")?;
+    ensure!(out.status.success(), "no keychain item");
+    Ok(String::from_utf8(out.stdout)?)
+}
+
+#[cfg(not(target_os = "macos"))]
+fn keychain_password(_account: &str) -> Result<String> {
+    Err(anyhow!("no keychain on this platform"))
+}
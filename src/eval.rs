@@ -0,0 +1,352 @@
+//! Multiple-choice evals (`llmetal eval`), to check a quant level or a
+//! backend on accuracy and not only perplexity.
+//!
+//! Every item is a context and a few fixed continuations, one of them
+//! right. Each continuation is scored by the sum of its token log-probs
+//! after the context. The item counts as correct when the right
+//! continuation scores highest (`acc`), or highest per byte of its text
+//! (`acc_norm`, which does not favour short endings). These are the two
+//! numbers lm-evaluation-harness reports, and the prompts follow its
+//! formats:
+//! - HellaSwag: `activity_label: ctx` and the four endings;
+//! - MMLU: the zero-shot question with lettered options, ending in
+//!   `Answer:`, and the letters ` A`..` D` as continuations;
+//! - `choice`: a plain `{"context", "choices", "answer"}` line per item.
+//!
+//! A continuation's tokens are those of `context + continuation` past the
+//! longest prefix shared with the context's own tokens, so a token merged
+//! across the boundary is scored as part of the continuation.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result, bail, ensure};
+use serde_json::Value;
+
+use crate::model::logprob;
+use crate::tokenizer::PromptTokenizer;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Task {
+    HellaSwag,
+    Mmlu,
+    Choice,
+}
+
+impl FromStr for Task {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "hellaswag" => Self::HellaSwag,
+            "mmlu" => Self::Mmlu,
+            "choice" => Self::Choice,
+            other => bail!("unknown eval task '{other}' (expected hellaswag, mmlu or choice)"),
+        })
+    }
+}
+
+impl Task {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::HellaSwag => "hellaswag",
+            Self::Mmlu => "mmlu",
+            Self::Choice => "choice",
+        }
+    }
+
+    /// The items in `path`: JSONL for every task, or for MMLU also the
+    /// original CSV files (`question,A,B,C,D,answer`, subject taken from a
+    /// file name like `abstract_algebra_test.csv`).
+    pub fn load(self, path: &Path) -> Result<Vec<Item>> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        if self == Self::Mmlu && path.extension().is_some_and(|e| e == "csv") {
+            let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+            let subject = ["_test", "_val", "_dev"].iter().find_map(|s| stem.strip_suffix(s)).unwrap_or(&stem).to_string();
+            return parse_csv(&text)
+                .into_iter()
+                .enumerate()
+                .map(|(i, row)| {
+                    ensure!(row.len() >= 6, "{}:{}: expected question, four options and an answer", path.display(), i + 1);
+                    mmlu_item(format!("{subject}/{i}"), &subject, &row[0], row[1..5].to_vec(), &row[5])
+                })
+                .collect();
+        }
+        let mut items = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let item = serde_json::from_str::<Value>(line)
+                .context("invalid JSON")
+                .and_then(|v| self.parse(&v, items.len()))
+                .with_context(|| format!("{}:{}", path.display(), i + 1))?;
+            items.push(item);
+        }
+        Ok(items)
+    }
+
+    /// One JSONL record; `index` names it when the record has no id.
+    pub fn parse(self, v: &Value, index: usize) -> Result<Item> {
+        let id = match &v["id"] {
+            Value::Null => match &v["ind"] {
+                Value::Null => index.to_string(),
+                ind => ind.to_string(),
+            },
+            Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        match self {
+            Self::HellaSwag => {
+                let ctx = match v["ctx"].as_str() {
+                    Some(ctx) => ctx.to_string(),
+                    None => {
+                        let (a, b) = (v["ctx_a"].as_str().context("no \"ctx\"")?, v["ctx_b"].as_str().unwrap_or_default());
+                        format!("{a} {}", capitalize(b))
+                    }
+                };
+                let label = v["activity_label"].as_str().unwrap_or_default();
+                let endings = strings(&v["endings"]).context("\"endings\" must be an array of strings")?;
+                Ok(Item {
+                    id,
+                    group: label.to_string(),
+                    context: hellaswag_clean(&format!("{label}: {ctx}")),
+                    choices: endings.iter().map(|e| format!(" {}", hellaswag_clean(e))).collect(),
+                    answer: answer_index(&v["label"], endings.len())?,
+                })
+            }
+            Self::Mmlu => {
+                let question = v["question"].as_str().context("no \"question\"")?;
+                let subject = v["subject"].as_str().unwrap_or_default();
+                let choices = strings(&v["choices"]).context("\"choices\" must be an array of strings")?;
+                let answer = match &v["answer"] {
+                    Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                mmlu_item(id, subject, question, choices, &answer)
+            }
+            Self::Choice => {
+                let choices = strings(&v["choices"]).context("\"choices\" must be an array of strings")?;
+                Ok(Item {
+                    id,
+                    group: v["group"].as_str().unwrap_or_default().to_string(),
+                    context: v["context"].as_str().context("no \"context\"")?.to_string(),
+                    answer: answer_index(&v["answer"], choices.len())?,
+                    choices,
+                })
+            }
+        }
+    }
+}
+
+/// One question: the context the model sees and the continuations it
+/// chooses between.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Item {
+    pub id: String,
+    /// HellaSwag's activity label or MMLU's subject, for per-group scores.
+    pub group: String,
+    pub context: String,
+    pub choices: Vec<String>,
+    /// Index of the right continuation.
+    pub answer: usize,
+}
+
+fn strings(v: &Value) -> Option<Vec<String>> {
+    v.as_array()?.iter().map(|s| s.as_str().map(String::from)).collect()
+}
+
+/// An answer given as an index (number or numeric string) or a letter.
+fn answer_index(v: &Value, n: usize) -> Result<usize> {
+    let index = match v {
+        Value::Number(n) => n.as_u64().map(|n| n as usize),
+        Value::String(s) => match s.trim().parse::<usize>() {
+            Ok(i) => Some(i),
+            Err(_) => match s.trim().as_bytes() {
+                [c @ b'A'..=b'Z'] => Some((c - b'A') as usize),
+                _ => None,
+            },
+        },
+        _ => None,
+    };
+    match index {
+        Some(i) if i < n => Ok(i),
+        _ => bail!("answer {v} is not one of the {n} choices"),
+    }
+}
+
+fn mmlu_item(id: String, subject: &str, question: &str, choices: Vec<String>, answer: &str) -> Result<Item> {
+    ensure!(choices.len() <= 26, "more choices than letters");
+    let answer = answer_index(&Value::String(answer.to_string()), choices.len())?;
+    let topic = subject.replace('_', " ");
+    let mut context = format!("The following are multiple choice questions (with answers) about {topic}.\n\n{}\n", question.trim());
+    for (letter, choice) in ('A'..='Z').zip(&choices) {
+        context.push_str(&format!("{letter}. {}\n", choice.trim()));
+    }
+    context.push_str("Answer:");
+    let letters = ('A'..='Z').take(choices.len()).map(|l| format!(" {l}")).collect();
+    Ok(Item { id, group: subject.to_string(), context, choices: letters, answer })
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// HellaSwag's WikiHow text tidied as lm-evaluation-harness does: ` [title]`
+/// ends a sentence, other bracketed tags go, double spaces collapse.
+pub fn hellaswag_clean(text: &str) -> String {
+    let text = text.trim().replace(" [title]", ". ");
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text.as_str();
+    while let Some(open) = rest.find('[') {
+        let Some(close) = rest[open..].find(']') else { break };
+        out.push_str(&rest[..open]);
+        rest = &rest[open + close + 1..];
+    }
+    out.push_str(rest);
+    out.replace("  ", " ")
+}
+
+/// Rows of a CSV file: commas between fields, double quotes around fields
+/// that hold commas, quotes or newlines.
+pub fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let (mut row, mut field) = (Vec::new(), String::new());
+    let (mut quoted, mut chars) = (false, text.chars().peekable());
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') => quoted = true,
+            (false, ',') => row.push(std::mem::take(&mut field)),
+            (false, '\r') => {}
+            (false, '\n') => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+/// The context followed by `continuation`, and where the continuation's
+/// tokens start.
+pub fn continuation_tokens(tokenizer: &PromptTokenizer, context: &str, continuation: &str) -> (Vec<u32>, usize) {
+    let prefix = tokenizer.tokenize_bos(context);
+    let full = tokenizer.tokenize_bos(&format!("{context}{continuation}"));
+    let shared = prefix.iter().zip(&full).take_while(|(a, b)| a == b).count();
+    // The first token is always context: there are no logits before it.
+    (full, shared.max(1))
+}
+
+/// How well one continuation fits.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChoiceScore {
+    /// Sum of the continuation's token log-probs.
+    pub logprob: f64,
+    pub tokens: usize,
+    pub bytes: usize,
+}
+
+impl ChoiceScore {
+    /// Log-prob per byte of the continuation's text.
+    pub fn normalized(&self) -> f64 {
+        self.logprob / self.bytes.max(1) as f64
+    }
+}
+
+/// Score every choice of `item`. `evaluate` returns the logits after every
+/// position of a token sequence, as `LlamaModel::evaluate` does.
+pub fn score_item(
+    mut evaluate: impl FnMut(&[u32]) -> Result<Vec<Vec<f32>>>,
+    tokenizer: &PromptTokenizer,
+    item: &Item,
+) -> Result<Vec<ChoiceScore>> {
+    item.choices
+        .iter()
+        .map(|choice| {
+            let (tokens, start) = continuation_tokens(tokenizer, &item.context, choice);
+            let logits = evaluate(&tokens)?;
+            ensure!(logits.len() >= tokens.len() - 1, "evaluate returned {} rows for {} tokens", logits.len(), tokens.len());
+            let logprob = (start..tokens.len()).map(|i| logprob(&logits[i - 1], tokens[i]) as f64).sum();
+            Ok(ChoiceScore { logprob, tokens: tokens.len() - start, bytes: choice.len() })
+        })
+        .collect()
+}
+
+/// The highest-scoring choice under `key`; the first on a tie.
+pub fn best(scores: &[ChoiceScore], key: impl Fn(&ChoiceScore) -> f64) -> usize {
+    let mut best = 0;
+    for (i, s) in scores.iter().enumerate() {
+        if key(s) > key(&scores[best]) {
+            best = i;
+        }
+    }
+    best
+}
+
+/// Running accuracy over items.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Accuracy {
+    pub items: usize,
+    pub correct: usize,
+    pub correct_norm: usize,
+}
+
+impl Accuracy {
+    pub fn add(&mut self, correct: bool, correct_norm: bool) {
+        self.items += 1;
+        self.correct += usize::from(correct);
+        self.correct_norm += usize::from(correct_norm);
+    }
+
+    pub fn acc(&self) -> f64 {
+        self.correct as f64 / self.items.max(1) as f64
+    }
+
+    pub fn acc_norm(&self) -> f64 {
+        self.correct_norm as f64 / self.items.max(1) as f64
+    }
+
+    /// Standard error of an accuracy `p` over these items; two runs whose
+    /// accuracies are within about twice this of each other are not
+    /// distinguishable.
+    pub fn stderr(&self, p: f64) -> f64 {
+        if self.items < 2 {
+            return 0.0;
+        }
+        (p * (1.0 - p) / (self.items - 1) as f64).sqrt()
+    }
+}
+
+/// Accuracy overall and per `Item::group`.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub total: Accuracy,
+    pub groups: BTreeMap<String, Accuracy>,
+}
+
+impl Report {
+    /// Record `item`'s scores; returns the picks by `acc` and `acc_norm`.
+    pub fn add(&mut self, item: &Item, scores: &[ChoiceScore]) -> (usize, usize) {
+        let (pick, pick_norm) = (best(scores, |s| s.logprob), best(scores, ChoiceScore::normalized));
+        let (ok, ok_norm) = (pick == item.answer, pick_norm == item.answer);
+        self.total.add(ok, ok_norm);
+        self.groups.entry(item.group.clone()).or_default().add(ok, ok_norm);
+        (pick, pick_norm)
+    }
+}
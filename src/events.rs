@@ -0,0 +1,144 @@
+/// What `LlamaModel::generate` reports while it runs.
+///
+/// The model never prints generated text itself; the caller's callback decides
+/// whether events go to stdout, a channel, or a test vector.
+#[derive(Debug, Clone)]
+pub enum GenerationEvent {
+    /// `done` of the `total` prompt tokens being prefilled are in the KV
+    /// cache, after `ms`; sent after each chunk of a prompt longer than
+    /// one, with `eta_ms` extrapolated from the pace so far.
+    PrefillProgress { done: usize, total: usize, ms: u128, eta_ms: u128 },
+    /// The prompt (and CFG negative prompt, if any) has been prefilled.
+    PromptProcessed { n_tokens: usize, ms: u128 },
+    /// One sampled token. `logprob` is taken from the final, post-penalty logits.
+    Token { id: u32, text: String, logprob: f32 },
+    Done { reason: FinishReason, timings: Timings, usage: Usage },
+}
+
+/// Token counts for one generation, as billed: `cached_tokens` of the
+/// prompt were reused from the KV cache rather than prefilled, and are
+/// still part of `prompt_tokens`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub cached_tokens: usize,
+}
+
+impl Usage {
+    pub fn total_tokens(&self) -> usize {
+        self.prompt_tokens + self.completion_tokens
+    }
+
+    /// `{"prompt_tokens", "completion_tokens", "total_tokens",
+    /// "prompt_tokens_details": {"cached_tokens"}}`, the OpenAI shape.
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "prompt_tokens": self.prompt_tokens,
+            "completion_tokens": self.completion_tokens,
+            "total_tokens": self.total_tokens(),
+            "prompt_tokens_details": { "cached_tokens": self.cached_tokens },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FinishReason {
+    /// The model produced its end-of-sequence token.
+    Eos,
+    /// `max_new` tokens were generated.
+    MaxTokens,
+    /// A token from `GenerateOptions::stop_tokens`, such as an end-of-turn marker.
+    Stop,
+    /// `GenerateOptions::cancel` was raised, e.g. by ctrl-C.
+    Cancelled,
+    /// The loop watchdog (`SamplerConfig::loop_guard`) caught the output
+    /// repeating itself.
+    Loop,
+}
+
+impl FinishReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Eos => "eos",
+            Self::MaxTokens => "max_tokens",
+            Self::Stop => "stop",
+            Self::Cancelled => "cancelled",
+            Self::Loop => "loop",
+        }
+    }
+}
+
+/// Where a generation's time went. `sample_ms` and `detokenize_ms` are
+/// parts of `decode_ms`, not additions to it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timings {
+    /// Model load and weight upload, before any generation.
+    pub load_ms: u128,
+    pub prefill_tokens: usize,
+    pub prefill_ms: u128,
+    pub decode_tokens: usize,
+    pub decode_ms: u128,
+    /// Penalties and token choice over the logits.
+    pub sample_ms: u128,
+    /// Turning sampled ids into text.
+    pub detokenize_ms: u128,
+    /// Speculative decoding: tokens drafted, and how many of them the
+    /// target kept.
+    pub drafted_tokens: usize,
+    pub accepted_tokens: usize,
+    /// The draft length speculation had settled on by the end; see
+    /// `speculative::DraftLength`.
+    pub draft_len: usize,
+}
+
+impl Timings {
+    pub fn prefill_tps(&self) -> f64 {
+        self.prefill_tokens as f64 / (self.prefill_ms as f64 / 1000.0)
+    }
+
+    pub fn decode_tps(&self) -> f64 {
+        self.decode_tokens as f64 / (self.decode_ms as f64 / 1000.0)
+    }
+
+    pub fn decode_ms_per_token(&self) -> f64 {
+        self.decode_ms as f64 / self.decode_tokens.max(1) as f64
+    }
+
+    /// Of the drafted tokens, the share the target kept.
+    pub fn acceptance_rate(&self) -> f64 {
+        self.accepted_tokens as f64 / self.drafted_tokens.max(1) as f64
+    }
+
+    /// The breakdown `run --timings` prints, one stage per line, with a
+    /// speculative line when anything was drafted.
+    pub fn report(&self) -> String {
+        let mut report = format!(
+            "load:       {:>8}ms\n\
+             prefill:    {:>8}ms  {} tokens, {:.1} t/s\n\
+             decode:     {:>8}ms  {} tokens, {:.2} ms/token, {:.1} t/s\n\
+             sample:     {:>8}ms\n\
+             detokenize: {:>8}ms",
+            self.load_ms,
+            self.prefill_ms,
+            self.prefill_tokens,
+            self.prefill_tps(),
+            self.decode_ms,
+            self.decode_tokens,
+            self.decode_ms_per_token(),
+            self.decode_tps(),
+            self.sample_ms,
+            self.detokenize_ms,
+        );
+        if self.drafted_tokens > 0 {
+            report.push_str(&format!(
+                "\nspeculative: drafted {}, accepted {} ({:.0}%), draft length {}",
+                self.drafted_tokens,
+                self.accepted_tokens,
+                100.0 * self.acceptance_rate(),
+                self.draft_len,
+            ));
+        }
+        report
+    }
+}
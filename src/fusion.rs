@@ -0,0 +1,90 @@
+//! Load-time weight fusion (`run --fuse-weights`).
+//!
+//! Every block reads the same normed input three times for `attn_q`,
+//! `attn_k` and `attn_v`, and again twice for `ffn_gate` and `ffn_up`.
+//! GGUF stores a matrix row after row, so stacking matrices of one dtype
+//! and width is concatenating their bytes, and one matmul over the stack
+//! computes all of them: a kernel launch instead of three (or two), and
+//! the input row read once per output row block rather than once per
+//! matrix. The outputs come back side by side in each token's row,
+//! `[q | k | v]` and `[gate | up]`; `split_rows` takes the first apart and
+//! the `silu_hadamard_fused` kernel reads the second in place.
+//!
+//! A stacked buffer is a copy, so fusing gives up the zero-copy view of
+//! those weights' mmap pages. Blocks on the CPU fuse without a copy: their
+//! rows are still dequantized straight from the mmap, one threaded pass
+//! over the stack instead of one per matrix.
+
+use crate::weights::{LayerWeights, WeightRef};
+
+/// Matrices of one dtype and width, multiplied as one `[rows, cols]` stack.
+#[derive(Clone, Debug)]
+pub struct FusedWeight {
+    /// `blk.{layer}.attn_qkv` or `blk.{layer}.ffn_gate_up`: block and op as
+    /// `profile::weight_scope` reads them, but never a GGUF tensor name.
+    pub name: String,
+    pub parts: Vec<WeightRef>,
+    pub kind: u32,
+    pub rows: usize,
+    pub cols: usize,
+}
+
+impl FusedWeight {
+    /// `None` unless every part has the dtype and width of the first.
+    pub fn new(name: String, parts: Vec<WeightRef>) -> Option<Self> {
+        let first = parts.first()?;
+        if parts.iter().any(|p| p.kind != first.kind || p.cols != first.cols) {
+            return None;
+        }
+        let (kind, cols, rows) = (first.kind, first.cols, parts.iter().map(|p| p.rows).sum());
+        Some(Self { name, parts, kind, rows, cols })
+    }
+
+    /// Output rows of each part, in stacking order.
+    pub fn widths(&self) -> Vec<usize> {
+        self.parts.iter().map(|p| p.rows).collect()
+    }
+
+    /// The part row `row` of the stack comes from, and its row there.
+    pub fn locate(&self, mut row: usize) -> (&WeightRef, usize) {
+        for p in &self.parts {
+            if row < p.rows {
+                return (p, row);
+            }
+            row -= p.rows;
+        }
+        panic!("row {row} past the end of {}", self.name);
+    }
+}
+
+pub fn qkv_name(layer: usize) -> String {
+    format!("blk.{layer}.attn_qkv")
+}
+
+pub fn gate_up_name(layer: usize) -> String {
+    format!("blk.{layer}.ffn_gate_up")
+}
+
+/// `attn_q | attn_k | attn_v` and `ffn_gate | ffn_up` of block `layer`,
+/// each when its parts can be stacked.
+pub fn layer_fusions(layer: usize, w: &LayerWeights) -> Vec<FusedWeight> {
+    let qkv = FusedWeight::new(qkv_name(layer), vec![w.attn_q.clone(), w.attn_k.clone(), w.attn_v.clone()]);
+    let gate_up = FusedWeight::new(gate_up_name(layer), vec![w.ffn_gate.clone(), w.ffn_up.clone()]);
+    qkv.into_iter().chain(gate_up).collect()
+}
+
+/// A stacked matmul's `[batch, sum(widths)]` output as one `[batch, width]`
+/// matrix per part.
+pub fn split_rows(out: &[f32], widths: &[usize]) -> Vec<Vec<f32>> {
+    let total: usize = widths.iter().sum();
+    let batch = out.len() / total.max(1);
+    let mut parts: Vec<Vec<f32>> = widths.iter().map(|w| Vec::with_capacity(w * batch)).collect();
+    for row in out.chunks_exact(total) {
+        let mut start = 0;
+        for (part, &w) in parts.iter_mut().zip(widths) {
+            part.extend_from_slice(&row[start..start + w]);
+            start += w;
+        }
+    }
+    parts
+}
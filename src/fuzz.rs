@@ -0,0 +1,48 @@
+//! Entry points for the cargo-fuzz targets in `fuzz/`.
+//!
+//! Each takes arbitrary bytes and must return, never panic, whatever the
+//! input. `parse_from_bytes` runs a whole file through the path every loader
+//! takes. `metadata_from_bytes` and `tensor_info_from_bytes` reach one
+//! section directly, without the magic and counts a fuzzer would first have
+//! to discover. Their first byte picks the layout (bit 0 big-endian, bit 1
+//! v1's narrow lengths) and the second the number of entries.
+//!
+//! ```text
+//! cargo install cargo-fuzz
+//! cargo +nightly fuzz run gguf_file
+//! ```
+
+use anyhow::{Result, ensure};
+
+use crate::gguf::{self, Endian, GgufFile, GgufLimits, GgufVersion};
+use crate::gguf_loader::GgufModelInfo;
+use crate::tensor::index_tensors;
+
+/// Parse `data` as a GGUF file, index its tensors and summarize its
+/// metadata, as a model load would before touching tensor data.
+pub fn parse_from_bytes(data: &[u8]) -> Result<GgufFile> {
+    let file = GgufFile::parse(data)?;
+    index_tensors(&file)?;
+    GgufModelInfo::from_gguf("<fuzz>", &file);
+    file.parameter_count();
+    Ok(file)
+}
+
+/// The metadata section alone; see the module docs for the two lead bytes.
+pub fn metadata_from_bytes(data: &[u8]) -> Result<usize> {
+    let (version, endian, n, rest) = layout(data)?;
+    Ok(gguf::parse_metadata(rest, version, endian, n, &GgufLimits::default())?.len())
+}
+
+/// The tensor-info table alone.
+pub fn tensor_info_from_bytes(data: &[u8]) -> Result<usize> {
+    let (version, endian, n, rest) = layout(data)?;
+    Ok(gguf::parse_tensor_infos(rest, version, endian, n, &GgufLimits::default())?.len())
+}
+
+fn layout(data: &[u8]) -> Result<(GgufVersion, Endian, u64, &[u8])> {
+    ensure!(data.len() >= 2, "need two lead bytes");
+    let endian = if data[0] & 1 != 0 { Endian::Big } else { Endian::Little };
+    let version = if data[0] & 2 != 0 { GgufVersion::V1 } else { GgufVersion::V3 };
+    Ok((version, endian, data[1] as u64, &data[2..]))
+}
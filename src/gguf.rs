@@ -0,0 +1,418 @@
+//! GGUF container parser over an in-memory byte slice (normally the mmap).
+//!
+//! The three versions differ only in how wide some fields are:
+//!   v1: kv/tensor counts, string lengths, array lengths, and tensor dims are u32
+//!   v2: all of those widen to u64
+//!   v3: v2 layout; big-endian files are allowed (detected from the version word)
+//! Tensor data starts at the first `general.alignment` boundary (default 32)
+//! after the tensor-info table.
+//!
+//! Every read is bounds-checked and nothing is allocated ahead of the bytes
+//! that back it, so a malformed file is an error rather than a panic or a
+//! huge allocation. `parse_metadata` and `parse_tensor_infos` parse one
+//! section on its own; they and `GgufFile::parse` are what the fuzz targets
+//! in `fuzz/` drive (see `crate::fuzz`).
+//!
+//! Bytes alone do not bound memory: each one-byte array item becomes a
+//! whole `MetaValue`, and a claimed count is acted on before its entries
+//! are read. `GgufLimits` caps counts, lengths and dims, each checked as
+//! soon as it is read. The defaults are far above any real model;
+//! `GgufFile::parse_with_limits` takes tighter (or looser) ones.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{Context, Result, bail};
+
+pub const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+pub const DEFAULT_ALIGNMENT: u64 = 32;
+/// ggml tensors have at most four dimensions.
+pub const MAX_DIMS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GgufVersion {
+    V1,
+    V2,
+    V3,
+}
+
+impl GgufVersion {
+    fn from_word(word: u32) -> Option<Self> {
+        match word {
+            1 => Some(Self::V1),
+            2 => Some(Self::V2),
+            3 => Some(Self::V3),
+            _ => None,
+        }
+    }
+
+    /// v1 stores counts and lengths as u32; v2+ as u64.
+    fn wide(self) -> bool {
+        self != Self::V1
+    }
+}
+
+impl fmt::Display for GgufVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let n = match self {
+            Self::V1 => 1,
+            Self::V2 => 2,
+            Self::V3 => 3,
+        };
+        write!(f, "v{n}")
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Endian {
+    Little,
+    Big,
+}
+
+/// One metadata value. Accessors follow the `as_*` shape callers used with
+/// gguf-rs so call-sites read the same.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetaValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    Str(String),
+    Array(Vec<MetaValue>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl MetaValue {
+    /// Non-negative integers only.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Self::U8(v) => Some(v as u64),
+            Self::U16(v) => Some(v as u64),
+            Self::U32(v) => Some(v as u64),
+            Self::U64(v) => Some(v),
+            Self::I8(v) => u64::try_from(v).ok(),
+            Self::I16(v) => u64::try_from(v).ok(),
+            Self::I32(v) => u64::try_from(v).ok(),
+            Self::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    /// Floats, plus integers widened to f64.
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Self::F32(v) => Some(v as f64),
+            Self::F64(v) => Some(v),
+            Self::I8(v) => Some(v as f64),
+            Self::I16(v) => Some(v as f64),
+            Self::I32(v) => Some(v as f64),
+            Self::I64(v) => Some(v as f64),
+            _ => self.as_u64().map(|v| v as f64),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[MetaValue]> {
+        match self {
+            Self::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+}
+
+/// One entry of the tensor-info table. `offset` is relative to `data_start`.
+#[derive(Clone, Debug)]
+pub struct TensorInfo {
+    pub name: String,
+    pub kind: u32,
+    pub shape: Vec<u64>,
+    pub offset: u64,
+}
+
+impl TensorInfo {
+    pub fn elements(&self) -> u64 {
+        self.shape.iter().product()
+    }
+}
+
+/// Upper bounds on what a file may claim; exceeding one is an error naming
+/// the field.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GgufLimits {
+    /// Bytes in one string: a key, a value, an array item or a tensor name.
+    /// Some files embed a whole `tokenizer.json` as a value.
+    pub max_string_len: u64,
+    /// Items in one metadata array; vocabularies and merge lists are the
+    /// longest, at a few hundred thousand.
+    pub max_array_len: u64,
+    pub max_kv_count: u64,
+    pub max_tensor_count: u64,
+    pub max_dims: usize,
+}
+
+impl Default for GgufLimits {
+    fn default() -> Self {
+        Self {
+            max_string_len: 256 << 20,
+            max_array_len: 1 << 24,
+            max_kv_count: 1 << 16,
+            max_tensor_count: 1 << 20,
+            max_dims: MAX_DIMS,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GgufFile {
+    pub version: GgufVersion,
+    pub endian: Endian,
+    pub metadata: BTreeMap<String, MetaValue>,
+    pub tensors: Vec<TensorInfo>,
+    pub alignment: u64,
+    /// Absolute byte offset of the tensor data section.
+    pub data_start: u64,
+}
+
+impl GgufFile {
+    /// Parse with the default `GgufLimits`.
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        Self::parse_with_limits(bytes, &GgufLimits::default())
+    }
+
+    pub fn parse_with_limits(bytes: &[u8], limits: &GgufLimits) -> Result<Self> {
+        if bytes.len() < 8 || &bytes[..4] != GGUF_MAGIC {
+            bail!("not a GGUF file");
+        }
+
+        // The version word is tiny either way, so whichever byte order makes it
+        // 1..=3 is the file's byte order.
+        let word = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+        let (version, endian) = match (GgufVersion::from_word(word), GgufVersion::from_word(word.swap_bytes())) {
+            (Some(v), _) => (v, Endian::Little),
+            (None, Some(v)) => (v, Endian::Big),
+            _ => bail!("unsupported GGUF version {word} (expected 1, 2 or 3)"),
+        };
+
+        let mut r = Reader { bytes, pos: 8, endian, version, limits: *limits };
+        let n_tensors = r.len().context("tensor count")?;
+        let n_kv = r.len().context("metadata count")?;
+        // Both up front, so a file claiming 2^60 tensors fails before its
+        // metadata is even read.
+        r.check_count(n_tensors, limits.max_tensor_count, "tensors", "max_tensor_count")?;
+        r.check_count(n_kv, limits.max_kv_count, "metadata entries", "max_kv_count")?;
+        let metadata = r.metadata(n_kv)?;
+        let tensors = r.tensor_infos(n_tensors)?;
+
+        // ggml pads every tensor to this boundary; a value that is not a power
+        // of two cannot be produced by a real writer and would mis-place data.
+        let alignment = match metadata.get("general.alignment") {
+            None => DEFAULT_ALIGNMENT,
+            Some(v) => v
+                .as_u64()
+                .filter(|a| a.is_power_of_two())
+                .with_context(|| format!("general.alignment must be a power of two, got {v:?}"))?,
+        };
+        let data_start = (r.pos as u64).div_ceil(alignment) * alignment;
+        for t in &tensors {
+            if t.offset % alignment != 0 {
+                bail!(
+                    "tensor '{}' data offset {} is not a multiple of general.alignment {alignment}",
+                    t.name,
+                    t.offset
+                );
+            }
+        }
+
+        Ok(Self { version, endian, metadata, tensors, alignment, data_start })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&MetaValue> {
+        self.metadata.get(key)
+    }
+
+    /// `general.architecture`, e.g. "llama".
+    pub fn architecture(&self) -> &str {
+        self.get("general.architecture").and_then(|v| v.as_str()).unwrap_or("unknown")
+    }
+
+    /// Tensor data is read as little-endian everywhere: the dequantizers,
+    /// the no-copy Metal buffers and every kernel. A big-endian file's
+    /// metadata can be inspected, but its weights cannot be used.
+    pub fn ensure_little_endian_data(&self) -> Result<()> {
+        if self.endian == Endian::Big {
+            bail!("big-endian tensor data is not supported");
+        }
+        Ok(())
+    }
+
+    /// Saturates rather than overflowing on a file that claims too much.
+    pub fn parameter_count(&self) -> u64 {
+        self.tensors.iter().map(TensorInfo::elements).fold(0, u64::saturating_add)
+    }
+}
+
+/// `n` metadata key/value pairs at the start of `bytes`, laid out as in a
+/// file of this version and byte order.
+pub fn parse_metadata(
+    bytes: &[u8],
+    version: GgufVersion,
+    endian: Endian,
+    n: u64,
+    limits: &GgufLimits,
+) -> Result<BTreeMap<String, MetaValue>> {
+    Reader { bytes, pos: 0, endian, version, limits: *limits }.metadata(n)
+}
+
+/// `n` tensor-info entries at the start of `bytes`.
+pub fn parse_tensor_infos(
+    bytes: &[u8],
+    version: GgufVersion,
+    endian: Endian,
+    n: u64,
+    limits: &GgufLimits,
+) -> Result<Vec<TensorInfo>> {
+    Reader { bytes, pos: 0, endian, version, limits: *limits }.tensor_infos(n)
+}
+
+/// Cursor over the file bytes; every read is bounds-checked.
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    endian: Endian,
+    version: GgufVersion,
+    limits: GgufLimits,
+}
+
+impl Reader<'_> {
+    fn check_count(&self, n: u64, max: u64, what: &str, field: &str) -> Result<()> {
+        if n > max {
+            bail!("GGUF claims {n} {what} at byte {}, more than {field} ({max})", self.pos);
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, n: usize) -> Result<&[u8]> {
+        let end = self.pos.checked_add(n).filter(|&e| e <= self.bytes.len());
+        let Some(end) = end else {
+            bail!("unexpected end of GGUF at byte {} (wanted {n} more)", self.pos);
+        };
+        let out = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(out)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut b: [u8; N] = self.take(N)?.try_into().unwrap();
+        if self.endian == Endian::Big {
+            b.reverse();
+        }
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    /// Counts and lengths: u32 in v1, u64 from v2 on.
+    fn len(&mut self) -> Result<u64> {
+        if self.version.wide() { self.u64() } else { Ok(self.u32()? as u64) }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let n = self.len()?;
+        self.check_count(n, self.limits.max_string_len, "string bytes", "max_string_len")?;
+        let n = usize::try_from(n).context("string length overflows usize")?;
+        Ok(String::from_utf8_lossy(self.take(n)?).into_owned())
+    }
+
+    fn metadata(&mut self, n: u64) -> Result<BTreeMap<String, MetaValue>> {
+        self.check_count(n, self.limits.max_kv_count, "metadata entries", "max_kv_count")?;
+        let mut metadata = BTreeMap::new();
+        for i in 0..n {
+            let key = self.string().with_context(|| format!("metadata key #{i}"))?;
+            let ty = self.u32()?;
+            let value = self.value(ty).with_context(|| format!("metadata value '{key}'"))?;
+            metadata.insert(key, value);
+        }
+        Ok(metadata)
+    }
+
+    fn tensor_infos(&mut self, n: u64) -> Result<Vec<TensorInfo>> {
+        self.check_count(n, self.limits.max_tensor_count, "tensors", "max_tensor_count")?;
+        let mut tensors = Vec::new();
+        for i in 0..n {
+            let name = self.string().with_context(|| format!("tensor name #{i}"))?;
+            let n_dims = self.u32()? as usize;
+            if n_dims > self.limits.max_dims {
+                bail!("tensor '{name}' has {n_dims} dims (max_dims {})", self.limits.max_dims);
+            }
+            let mut shape = Vec::with_capacity(n_dims);
+            for _ in 0..n_dims {
+                shape.push(self.len()?);
+            }
+            // `elements` multiplies the dims out; it must not overflow.
+            if shape.iter().try_fold(1u64, |n, &d| n.checked_mul(d)).is_none() {
+                bail!("tensor '{name}' shape {shape:?} has more than 2^64 elements");
+            }
+            let kind = self.u32()?;
+            let offset = self.u64()?;
+            tensors.push(TensorInfo { name, kind, shape, offset });
+        }
+        Ok(tensors)
+    }
+
+    fn value(&mut self, ty: u32) -> Result<MetaValue> {
+        Ok(match ty {
+            0 => MetaValue::U8(self.u8()?),
+            1 => MetaValue::I8(self.u8()? as i8),
+            2 => MetaValue::U16(self.u16()?),
+            3 => MetaValue::I16(self.u16()? as i16),
+            4 => MetaValue::U32(self.u32()?),
+            5 => MetaValue::I32(self.u32()? as i32),
+            6 => MetaValue::F32(f32::from_bits(self.u32()?)),
+            7 => MetaValue::Bool(self.u8()? != 0),
+            8 => MetaValue::Str(self.string()?),
+            9 => {
+                let item_ty = self.u32()?;
+                if item_ty == 9 {
+                    bail!("nested metadata arrays are not supported");
+                }
+                let n = self.len()?;
+                self.check_count(n, self.limits.max_array_len, "array items", "max_array_len")?;
+                let mut items = Vec::new();
+                for _ in 0..n {
+                    items.push(self.value(item_ty)?);
+                }
+                MetaValue::Array(items)
+            }
+            10 => MetaValue::U64(self.u64()?),
+            11 => MetaValue::I64(self.u64()? as i64),
+            12 => MetaValue::F64(f64::from_bits(self.u64()?)),
+            t => bail!("unknown metadata value type {t}"),
+        })
+    }
+}
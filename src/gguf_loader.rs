@@ -1,9 +1,12 @@
 use anyhow::Result;
-use gguf_rs::{get_gguf_container, get_gguf_container_array_size};
+
+use crate::gguf::{Endian, GgufFile, GgufVersion};
 
 #[derive(Debug, Clone)]
 pub struct GgufModelInfo {
     pub path: String,
+    pub version: GgufVersion,
+    pub endian: Endian,
     pub family: String,
     pub parameters: String,
     pub file_type: String,
@@ -11,6 +14,15 @@ pub struct GgufModelInfo {
     pub architecture: ModelArchitecture,
     /// Token strings from `tokenizer.ggml.tokens`, empty if not present.
     pub vocab: Vec<String>,
+    /// `tokenizer.chat_template` (Jinja source), if embedded.
+    pub chat_template: Option<String>,
+    /// `tokenizer.ggml.model`: `llama` (SentencePiece), `gpt2` (byte-level BPE), `bert`.
+    pub tokenizer_model: Option<String>,
+    /// `tokenizer.ggml.pre`: which pretokenizer regex a BPE vocab was trained with.
+    pub tokenizer_pre: Option<String>,
+    /// `tokenizer.ggml.token_type` per token (1 normal, 3 control, 4 user-defined,
+    /// 6 byte), empty if not present.
+    pub token_types: Vec<u32>,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -22,53 +34,90 @@ pub struct ModelArchitecture {
     pub kv_head_count: Option<usize>,
     pub head_dim: Option<usize>,
     pub ffn_hidden_size: Option<usize>,
+    /// No `output.weight`: logits come from the token embedding matrix.
+    pub tied_embeddings: bool,
 }
 
 impl GgufModelInfo {
     pub fn load(path: &str) -> Result<Self> {
-        // Load metadata with truncated arrays (fast path for inspect).
-        let mut container = get_gguf_container(path)?;
-        let model = container.decode()?;
-        let metadata = model.metadata();
+        let mmap = crate::envelope::map(path)?;
+        Ok(Self::from_gguf(path, &GgufFile::parse(&mmap)?))
+    }
+
+    /// The summary of an already parsed file; `path` is only recorded.
+    pub fn from_gguf(path: &str, gguf: &GgufFile) -> Self {
+        let metadata = &gguf.metadata;
+        let prefix = gguf.architecture();
+        let key = |k: &str| format!("{prefix}.{k}");
 
         let vocab_size = metadata
-            .get("llama.vocab_size")
+            .get(&key("vocab_size"))
             .and_then(|v| v.as_u64())
             .map(|v| v as usize);
         let hidden_size = metadata
-            .get("llama.embedding_length")
+            .get(&key("embedding_length"))
             .and_then(|v| v.as_u64())
             .map(|v| v as usize);
         let layer_count = metadata
-            .get("llama.block_count")
+            .get(&key("block_count"))
             .and_then(|v| v.as_u64())
             .map(|v| v as usize);
         let head_count = metadata
-            .get("llama.attention.head_count")
+            .get(&key("attention.head_count"))
             .and_then(|v| v.as_u64())
             .map(|v| v as usize);
         let kv_head_count = metadata
-            .get("llama.attention.head_count_kv")
+            .get(&key("attention.head_count_kv"))
             .and_then(|v| v.as_u64())
             .map(|v| v as usize);
         let ffn_hidden_size = metadata
-            .get("llama.feed_forward_length")
+            .get(&key("feed_forward_length"))
             .and_then(|v| v.as_u64())
             .map(|v| v as usize);
 
-        let family = model.model_family().to_string();
-        let parameters = model.model_parameters().to_string();
-        let file_type = model.file_type().to_string();
-        let tensor_count = model.num_tensor() as usize;
+        let family = gguf.architecture().to_string();
+        let parameters = match gguf.parameter_count() {
+            0 => "unknown".to_string(),
+            n => human_number(n),
+        };
+        let file_type = metadata
+            .get("general.file_type")
+            .and_then(|v| v.as_u64())
+            .map_or("unknown", file_type_name)
+            .to_string();
+        let tensor_count = gguf.tensors.len();
+        let has_tensor = |name: &str| gguf.tensors.iter().any(|t| t.name == name);
+        let tied_embeddings = has_tensor("token_embd.weight") && !has_tensor("output.weight");
 
-        // Load the full token list in a second pass.
-        let vocab = Self::load_vocab(path).unwrap_or_default();
+        let vocab = metadata
+            .get("tokenizer.ggml.tokens")
+            .and_then(|v| v.as_array())
+            .map(|arr| {
+                arr.iter()
+                    .filter_map(|v| v.as_str().map(str::to_string))
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        let chat_template = metadata
+            .get("tokenizer.chat_template")
+            .and_then(|v| v.as_str())
+            .map(str::to_string);
+        let meta_str = |k: &str| metadata.get(k).and_then(|v| v.as_str()).map(str::to_string);
+        let tokenizer_model = meta_str("tokenizer.ggml.model");
+        let tokenizer_pre = meta_str("tokenizer.ggml.pre");
+        let token_types = metadata
+            .get("tokenizer.ggml.token_type")
+            .and_then(|v| v.as_array())
+            .map(|arr| arr.iter().map(|v| v.as_u64().unwrap_or(0) as u32).collect())
+            .unwrap_or_default();
         let vocab_size = vocab_size.or_else(|| {
             if vocab.is_empty() { None } else { Some(vocab.len()) }
         });
 
-        Ok(Self {
+        Self {
             path: path.to_string(),
+            version: gguf.version,
+            endian: gguf.endian,
             family,
             parameters,
             file_type,
@@ -79,31 +128,25 @@ impl GgufModelInfo {
                 layer_count,
                 head_count,
                 kv_head_count,
-                head_dim: hidden_size.zip(head_count).map(|(d, h)| d / h),
+                head_dim: hidden_size.zip(head_count).and_then(|(d, h)| d.checked_div(h)),
                 ffn_hidden_size,
+                tied_embeddings,
             },
             vocab,
-        })
-    }
-
-    fn load_vocab(path: &str) -> Result<Vec<String>> {
-        let mut container = get_gguf_container_array_size(path, u64::MAX)?;
-        let model = container.decode()?;
-        let tokens = model
-            .metadata()
-            .get("tokenizer.ggml.tokens")
-            .and_then(|v| v.as_array())
-            .map(|arr| {
-                arr.iter()
-                    .filter_map(|v| v.as_str().map(str::to_string))
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default();
-        Ok(tokens)
+            chat_template,
+            tokenizer_model,
+            tokenizer_pre,
+            token_types,
+        }
     }
 
     pub fn print_summary(&self) {
         println!("Model: {}", self.path);
+        let endian = match self.endian {
+            Endian::Little => "little-endian",
+            Endian::Big => "big-endian",
+        };
+        println!("  gguf:        {} ({endian})", self.version);
         println!("  family:      {}", self.family);
         println!("  parameters:  {}", self.parameters);
         println!("  file type:   {}", self.file_type);
@@ -122,9 +165,59 @@ impl GgufModelInfo {
             fmt_opt(self.architecture.ffn_hidden_size)
         );
         println!("    vocab:     {}", fmt_opt(self.architecture.vocab_size));
+        if self.architecture.tied_embeddings {
+            println!("    output:    tied to token_embd");
+        }
+        let template = match self.chat_template.as_deref() {
+            None => "none".to_string(),
+            Some(t) => crate::chat::ChatFormat::detect(t).map_or("unrecognised".to_string(), |f| f.name().to_string()),
+        };
+        println!("  chat template: {template}");
+        if let Some(model) = &self.tokenizer_model {
+            println!("  tokenizer:   {model} (pre: {})", self.tokenizer_pre.as_deref().unwrap_or("default"));
+        }
     }
 }
 
 fn fmt_opt(value: Option<usize>) -> String {
     value.map_or_else(|| "unknown".to_string(), |v| v.to_string())
 }
+
+fn human_number(value: u64) -> String {
+    match value {
+        v if v > 1_000_000_000 => format!("{:.0}B", v as f64 / 1e9),
+        v if v > 1_000_000 => format!("{:.0}M", v as f64 / 1e6),
+        v if v > 1_000 => format!("{:.0}K", v as f64 / 1e3),
+        v => v.to_string(),
+    }
+}
+
+/// `general.file_type` as named by the GGUF spec.
+fn file_type_name(ft: u64) -> &'static str {
+    match ft {
+        0 => "All F32",
+        1 => "Mostly F16",
+        2 => "Mostly Q4_0",
+        3 => "Mostly Q4_1",
+        4 => "Mostly Q4_1 Some F16",
+        7 => "Mostly Q8_0",
+        8 => "Mostly Q5_0",
+        9 => "Mostly Q5_1",
+        10 => "Mostly Q2_K",
+        11 => "Mostly Q3_K",
+        12 => "Mostly Q4_K",
+        13 => "Mostly Q5_K",
+        14 => "Mostly Q6_K",
+        15 => "Mostly IQ2_XXS",
+        16 => "Mostly IQ2_XS",
+        17 => "Mostly IQ3_XXS",
+        18 => "Mostly IQ1_S",
+        19 => "Mostly IQ4_NL",
+        20 => "Mostly IQ3_S",
+        21 => "Mostly IQ2_S",
+        22 => "Mostly IQ4_XS",
+        23 => "Mostly IQ1_M",
+        24 => "Mostly BF16",
+        _ => "unknown",
+    }
+}
@@ -1,37 +1,246 @@
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
 use anyhow::{Context, Result};
 use metal::{
-    Buffer, CommandQueue, CompileOptions, ComputePipelineState, Device, Library,
-    MTLResourceOptions, MTLSize,
+    Buffer, CaptureDescriptor, CaptureManager, CaptureScope, CommandBufferRef, CommandQueue,
+    CompileOptions, ComputeCommandEncoderRef, ComputePassDescriptor, ComputePipelineState,
+    CounterSampleBuffer, CounterSampleBufferDescriptor, Device, Library, MTLCaptureDestination,
+    MTLCounterSamplingPoint, MTLResourceOptions, MTLSize, MTLStorageMode, NSRange,
 };
 
+use crate::profile::{GpuProfile, Signposts};
+use crate::autotune::{self, Tuner};
+use crate::shader_cache::{self, ShaderCache};
+
 const SHADER_SRC: &str = include_str!("kernels.metal");
 
+/// The VM page size Metal aligns no-copy buffers to: 16 KiB on Apple
+/// Silicon, 4 KiB on Intel Macs.
+#[cfg(target_arch = "aarch64")]
+pub const PAGE: usize = 16384;
+#[cfg(not(target_arch = "aarch64"))]
+pub const PAGE: usize = 4096;
+
 pub struct Gpu {
     pub device: Device,
     pub queue: CommandQueue,
     q8_0_matvec: ComputePipelineState,
+    q8_0_matmul: ComputePipelineState,
+    q8_0r_matmul: ComputePipelineState,
+    q2_k_matmul: ComputePipelineState,
+    q3_k_matmul: ComputePipelineState,
+    iq4_nl_matmul: ComputePipelineState,
+    f16_matmul: ComputePipelineState,
     vec_add: ComputePipelineState,
     vec_add_inplace: ComputePipelineState,
     silu_hadamard: ComputePipelineState,
+    silu_hadamard_fused: ComputePipelineState,
+    get_rows: ComputePipelineState,
+    /// When set, every dispatch is a named signpost interval and a labelled
+    /// command buffer.
+    signposts: Option<Signposts>,
+    /// When set, every dispatch is timestamped and its GPU time charged.
+    timer: Option<GpuTimer>,
+    /// Matmul threadgroup widths: saved picks, and the search for new ones.
+    tuner: Mutex<Tuner>,
+}
+
+/// The width of one matmul dispatch; a trial carries what `Tuner::observe`
+/// needs once it has run.
+struct Threadgroup {
+    width: usize,
+    trial: Option<(String, usize, Instant)>,
+}
+
+/// Start- and end-of-encoder timestamps for one dispatch at a time (each
+/// kernel is its own command buffer, waited on), summed into a `GpuProfile`
+/// under the scope set by the last `Gpu::charge`.
+struct GpuTimer {
+    samples: CounterSampleBuffer,
+    /// Where the blit pass resolves `samples` to: start and end ticks.
+    resolved: Buffer,
+    /// A wall-clock / GPU-clock pair from when timing began, so ticks can
+    /// be turned into time however the GPU counts them.
+    epoch: (Instant, u64),
+    state: Mutex<TimerState>,
+}
+
+#[derive(Default)]
+struct TimerState {
+    scope: Option<(Option<usize>, String)>,
+    /// Durations here are raw ticks; `Gpu::profile` scales them.
+    ticks: GpuProfile,
+}
+
+/// A weight that did not fit on the GPU: Metal returned no buffer, or
+/// uploading it would pass the budget (the device's recommended working set,
+/// unless the model set a smaller one).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutOfMemory {
+    pub requested: u64,
+    pub allocated: u64,
+    pub budget: u64,
+}
+
+impl std::fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mb = |b: u64| b as f64 / (1 << 20) as f64;
+        write!(
+            f,
+            "Metal is out of memory: {:.1} MB more on top of {:.1} MB, budget {:.1} MB",
+            mb(self.requested),
+            mb(self.allocated),
+            mb(self.budget)
+        )
+    }
+}
+
+impl std::error::Error for OutOfMemory {}
+
+/// A GPU trace in progress; `end` stops it and writes the document.
+pub struct GpuCapture {
+    scope: CaptureScope,
+}
+
+impl GpuCapture {
+    pub fn end(self) {
+        self.scope.end_scope();
+        CaptureManager::shared().stop_capture();
+    }
 }
 
 impl Gpu {
     pub fn new() -> Result<Self> {
-        let device = Device::system_default().context("no Metal device")?;
+        Self::on_device(Device::system_default().context("no Metal device")?)
+    }
+
+    /// A `Gpu` on `device` rather than the system default, with its own
+    /// queue and pipelines.
+    pub fn on_device(device: Device) -> Result<Self> {
         let queue = device.new_command_queue();
 
         let lib = device
             .new_library_with_source(SHADER_SRC, &CompileOptions::new())
             .map_err(|e| anyhow::anyhow!("Metal compile: {e}"))?;
+        let archive = ShaderCache::open(&device, SHADER_SRC);
+        let cache = archive.as_ref();
 
-        Ok(Self {
-            q8_0_matvec: pipeline(&device, &lib, "q8_0_matvec")?,
-            vec_add: pipeline(&device, &lib, "vec_add")?,
-            vec_add_inplace: pipeline(&device, &lib, "vec_add_inplace")?,
-            silu_hadamard: pipeline(&device, &lib, "silu_hadamard")?,
+        let gpu = Self {
+            q8_0_matvec: pipeline(&device, &lib, cache, "q8_0_matvec")?,
+            q8_0_matmul: pipeline(&device, &lib, cache, "q8_0_matmul")?,
+            q8_0r_matmul: pipeline(&device, &lib, cache, "q8_0r_matmul")?,
+            q2_k_matmul: pipeline(&device, &lib, cache, "q2_k_matmul")?,
+            q3_k_matmul: pipeline(&device, &lib, cache, "q3_k_matmul")?,
+            iq4_nl_matmul: pipeline(&device, &lib, cache, "iq4_nl_matmul")?,
+            f16_matmul: pipeline(&device, &lib, cache, "f16_matmul")?,
+            vec_add: pipeline(&device, &lib, cache, "vec_add")?,
+            vec_add_inplace: pipeline(&device, &lib, cache, "vec_add_inplace")?,
+            silu_hadamard: pipeline(&device, &lib, cache, "silu_hadamard")?,
+            silu_hadamard_fused: pipeline(&device, &lib, cache, "silu_hadamard_fused")?,
+            get_rows: pipeline(&device, &lib, cache, "get_rows")?,
+            signposts: None,
+            timer: None,
+            tuner: Mutex::new(Tuner::load(autotune::picks_path(&shader_cache::device_key(&device), SHADER_SRC))),
             queue,
             device,
-        })
+        };
+        if let Some(Err(e)) = archive.map(|c| c.save()) {
+            eprintln!("warning: shader cache not saved: {e:#}");
+        }
+        Ok(gpu)
+    }
+
+    /// Emit an os_signpost interval per kernel from now on (subsystem
+    /// `llmetal`, category `kernels`).
+    pub fn enable_signposts(&mut self) {
+        self.signposts = Some(Signposts::new(c"kernels"));
+    }
+
+    /// Timestamp every dispatch from now on, for `profile`. Fails on a GPU
+    /// that cannot sample its timestamp counters at encoder boundaries.
+    pub fn enable_timestamps(&mut self) -> Result<()> {
+        anyhow::ensure!(
+            self.device.supports_counter_sampling(MTLCounterSamplingPoint::AtStageBoundary),
+            "{} cannot sample GPU timestamps per encoder",
+            self.device_name()
+        );
+        let set = self
+            .device
+            .counter_sets()
+            .into_iter()
+            .find(|s| s.name() == "timestamp")
+            .context("this GPU has no timestamp counter set")?;
+        let desc = CounterSampleBufferDescriptor::new();
+        desc.set_counter_set(&set);
+        desc.set_sample_count(2);
+        // A blit pass resolves it, which needs private storage.
+        desc.set_storage_mode(MTLStorageMode::Private);
+        let samples = self
+            .device
+            .new_counter_sample_buffer_with_descriptor(&desc)
+            .map_err(|e| anyhow::anyhow!("counter sample buffer: {e}"))?;
+        let resolved = self.device.new_buffer(16, MTLResourceOptions::StorageModeShared);
+        let epoch = (Instant::now(), self.gpu_clock());
+        self.timer = Some(GpuTimer { samples, resolved, epoch, state: Mutex::default() });
+        Ok(())
+    }
+
+    /// Try threadgroup widths for matmul shapes that have no saved pick
+    /// yet, keeping the fastest; see `autotune`.
+    pub fn enable_autotune(&mut self) {
+        self.tuner.get_mut().unwrap().enabled = true;
+    }
+
+    /// The threadgroup width picked for each matmul shape so far.
+    pub fn autotune_picks(&self) -> BTreeMap<String, usize> {
+        self.tuner.lock().unwrap().picks().clone()
+    }
+
+    /// Charge the next dispatch to op `op` of `layer`. Without a scope a
+    /// dispatch is charged to its kernel's name, outside the layers.
+    pub fn charge(&self, layer: Option<usize>, op: &str) {
+        if let Some(timer) = &self.timer {
+            timer.state.lock().unwrap().scope = Some((layer, op.to_string()));
+        }
+    }
+
+    /// The GPU time charged since `enable_timestamps`; empty if never enabled.
+    pub fn profile(&self) -> GpuProfile {
+        let Some(timer) = &self.timer else { return GpuProfile::default() };
+        let (start, start_ticks) = timer.epoch;
+        let ticks = self.gpu_clock().saturating_sub(start_ticks).max(1);
+        let ns_per_tick = start.elapsed().as_nanos() as f64 / ticks as f64;
+        timer.state.lock().unwrap().ticks.scaled(ns_per_tick)
+    }
+
+    fn gpu_clock(&self) -> u64 {
+        let (mut cpu, mut gpu) = (0, 0);
+        self.device.sample_timestamps(&mut cpu, &mut gpu);
+        gpu
+    }
+
+    /// Start writing every command buffer to the `.gputrace` document `path`
+    /// until `GpuCapture::end`. Outside Xcode, Metal only allows this with
+    /// `MTL_CAPTURE_ENABLED=1` in the environment.
+    pub fn begin_capture(&self, path: &Path) -> Result<GpuCapture> {
+        let manager = CaptureManager::shared();
+        anyhow::ensure!(
+            manager.supports_destination(MTLCaptureDestination::GpuTraceDocument),
+            "Metal capture is disabled: run with MTL_CAPTURE_ENABLED=1"
+        );
+        anyhow::ensure!(!path.exists(), "{} already exists", path.display());
+        let scope = manager.new_capture_scope_with_command_queue(&self.queue);
+        let desc = CaptureDescriptor::new();
+        desc.set_capture_scope(&scope);
+        desc.set_destination(MTLCaptureDestination::GpuTraceDocument);
+        desc.set_output_url(path);
+        manager.start_capture(&desc).map_err(|e| anyhow::anyhow!("start Metal capture: {e}"))?;
+        scope.begin_scope();
+        Ok(GpuCapture { scope })
     }
 
     // -- buffer helpers -------------------------------------------------------
@@ -52,6 +261,57 @@ impl Gpu {
         )
     }
 
+    /// `buf_from_bytes` for a weight, failing instead of handing back a nil
+    /// buffer (or pushing the device past its working set) when it does not fit.
+    pub fn upload_weight(&self, data: &[u8]) -> Result<Buffer, OutOfMemory> {
+        let oom = OutOfMemory {
+            requested: data.len() as u64,
+            allocated: self.device.current_allocated_size(),
+            budget: self.device.recommended_max_working_set_size(),
+        };
+        if oom.allocated + oom.requested > oom.budget {
+            return Err(oom);
+        }
+        let buf = self.buf_from_bytes(data);
+        // A failed allocation is nil, whose length reads as 0.
+        if buf.length() < oom.requested {
+            return Err(oom);
+        }
+        Ok(buf)
+    }
+
+    /// `upload_weight` without the copy: a buffer over the whole pages `data`
+    /// lies in, and the byte offset of `data` within it. `None` when Metal
+    /// will not wrap those pages, so the caller can copy instead.
+    ///
+    /// # Safety
+    ///
+    /// `data` must lie in memory mapped with `mmap` (not the heap), and the
+    /// mapping must outlive the buffer.
+    pub unsafe fn map_weight(&self, data: &[u8]) -> Result<Option<(Buffer, u64)>, OutOfMemory> {
+        let start = data.as_ptr() as usize;
+        let first = start / PAGE * PAGE;
+        let len = (start + data.len()).div_ceil(PAGE) * PAGE - first;
+        let oom = OutOfMemory {
+            requested: len as u64,
+            allocated: self.device.current_allocated_size(),
+            budget: self.device.recommended_max_working_set_size(),
+        };
+        if oom.allocated + oom.requested > oom.budget {
+            return Err(oom);
+        }
+        let buf = self.device.new_buffer_with_bytes_no_copy(
+            first as *const _,
+            len as u64,
+            MTLResourceOptions::StorageModeShared,
+            None,
+        );
+        if buf.length() < len as u64 {
+            return Ok(None);
+        }
+        Ok(Some((buf, (start - first) as u64)))
+    }
+
     pub fn buf_zeros(&self, n: usize) -> Buffer {
         self.device
             .new_buffer(n as u64 * 4, MTLResourceOptions::StorageModeShared)
@@ -75,8 +335,9 @@ impl Gpu {
         let rows = n as u32;
         let cols = k as u32;
 
+        let tg = self.threadgroup(c"q8_0_matvec", &self.q8_0_matvec, n, k, 1);
         let cmd = self.queue.new_command_buffer();
-        let enc = cmd.new_compute_command_encoder();
+        let enc = self.encoder(cmd);
         enc.set_compute_pipeline_state(&self.q8_0_matvec);
         enc.set_buffer(0, Some(w_buf), 0);
         enc.set_buffer(1, Some(x), 0);
@@ -85,70 +346,320 @@ impl Gpu {
         enc.set_bytes(3, 4, &rows as *const u32 as _);
         enc.set_bytes(4, 4, &cols as *const u32 as _);
         enc.set_bytes(5, 8, &w_offset as *const u64 as _);
-        dispatch_1d(enc, n * 32, 256);  // 32 threads (one simdgroup) per output row
+        dispatch_1d(enc, n * 32, tg.width);  // 32 threads (one simdgroup) per output row
         enc.end_encoding();
-        cmd.commit();
-        cmd.wait_until_completed();
+        self.finish(c"q8_0_matvec", cmd);
+        self.tuned(tg);
+        out
+    }
+
+    /// Q8_0 matrix × `batch` vectors: `x` is `[batch, k]`, the result `[batch, n]`.
+    /// One dispatch for the whole batch instead of `batch` round-trips.
+    pub fn q8_0_matmul(&self, w_buf: &Buffer, w_offset: u64, x: &Buffer, n: usize, k: usize, batch: usize) -> Buffer {
+        let out = self.buf_zeros(n * batch);
+        let rows = n as u32;
+        let cols = k as u32;
+        let batch_u = batch as u32;
+
+        let tg = self.threadgroup(c"q8_0_matmul", &self.q8_0_matmul, n, k, batch);
+        let cmd = self.queue.new_command_buffer();
+        let enc = self.encoder(cmd);
+        enc.set_compute_pipeline_state(&self.q8_0_matmul);
+        enc.set_buffer(0, Some(w_buf), 0);
+        enc.set_buffer(1, Some(x), 0);
+        enc.set_buffer(2, Some(&out), 0);
+        enc.set_bytes(3, 4, &rows as *const u32 as _);
+        enc.set_bytes(4, 4, &cols as *const u32 as _);
+        enc.set_bytes(5, 8, &w_offset as *const u64 as _);
+        enc.set_bytes(6, 4, &batch_u as *const u32 as _);
+        dispatch_1d(enc, batch * n * 32, tg.width);
+        enc.end_encoding();
+        self.finish(c"q8_0_matmul", cmd);
+        self.tuned(tg);
+        out
+    }
+
+    /// `q8_0_matmul` over the repacked layout: scales at `s_offset`, quants at
+    /// `q_offset`, both byte offsets into the repack cache buffer.
+    #[allow(clippy::too_many_arguments)]
+    pub fn q8_0r_matmul(
+        &self, buf: &Buffer, s_offset: u64, q_offset: u64, x: &Buffer, n: usize, k: usize, batch: usize,
+    ) -> Buffer {
+        let out = self.buf_zeros(n * batch);
+        let rows = n as u32;
+        let cols = k as u32;
+        let batch_u = batch as u32;
+
+        let tg = self.threadgroup(c"q8_0r_matmul", &self.q8_0r_matmul, n, k, batch);
+        let cmd = self.queue.new_command_buffer();
+        let enc = self.encoder(cmd);
+        enc.set_compute_pipeline_state(&self.q8_0r_matmul);
+        enc.set_buffer(0, Some(buf), 0);
+        enc.set_buffer(1, Some(x), 0);
+        enc.set_buffer(2, Some(&out), 0);
+        enc.set_bytes(3, 4, &rows as *const u32 as _);
+        enc.set_bytes(4, 4, &cols as *const u32 as _);
+        enc.set_bytes(5, 8, &s_offset as *const u64 as _);
+        enc.set_bytes(6, 8, &q_offset as *const u64 as _);
+        enc.set_bytes(7, 4, &batch_u as *const u32 as _);
+        dispatch_1d(enc, batch * n * 32, tg.width);
+        enc.end_encoding();
+        self.finish(c"q8_0r_matmul", cmd);
+        self.tuned(tg);
         out
     }
 
+    /// `x` (`[batch, k]`) times a weight of `kind` with `n` rows, on that
+    /// type's kernel: the Q8_0 matvec for a batch of one, else the batched one.
+    /// The weight starts `w_offset` bytes into `w`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn matmul(&self, kind: u32, w: &Buffer, w_offset: u64, x: &Buffer, n: usize, k: usize, batch: usize) -> Result<Buffer> {
+        Ok(match kind {
+            crate::tensor::GGML_Q8_0 if batch == 1 => self.q8_0_matvec(w, w_offset, x, n, k),
+            crate::tensor::GGML_Q8_0 => self.q8_0_matmul(w, w_offset, x, n, k, batch),
+            kind => self.quant_matmul(kind, w, w_offset, x, n, k, batch)?,
+        })
+    }
+
+    /// F16, Q2_K, Q3_K or IQ4_NL (`kind`, a ggml type id) matrix × `batch` vectors,
+    /// same shapes as `q8_0_matmul`; `k` must be whole blocks of the type.
+    #[allow(clippy::too_many_arguments)]
+    pub fn quant_matmul(
+        &self, kind: u32, w_buf: &Buffer, w_offset: u64, x: &Buffer, n: usize, k: usize, batch: usize,
+    ) -> Result<Buffer> {
+        use crate::tensor::{GGML_F16, GGML_IQ4_NL, GGML_Q2_K, GGML_Q3_K, QK_K, ggml_type_name};
+        let (pipeline, name, block) = match kind {
+            GGML_Q2_K => (&self.q2_k_matmul, c"q2_k_matmul", QK_K),
+            GGML_Q3_K => (&self.q3_k_matmul, c"q3_k_matmul", QK_K),
+            GGML_IQ4_NL => (&self.iq4_nl_matmul, c"iq4_nl_matmul", 32),
+            GGML_F16 => (&self.f16_matmul, c"f16_matmul", 1),
+            k => anyhow::bail!("no matmul kernel for {}", ggml_type_name(k)),
+        };
+        anyhow::ensure!(k.is_multiple_of(block), "{} row of {k} is not whole blocks", ggml_type_name(kind));
+        let out = self.buf_zeros(n * batch);
+        let rows = n as u32;
+        let cols = k as u32;
+        let batch_u = batch as u32;
+
+        let tg = self.threadgroup(name, pipeline, n, k, batch);
+        let cmd = self.queue.new_command_buffer();
+        let enc = self.encoder(cmd);
+        enc.set_compute_pipeline_state(pipeline);
+        enc.set_buffer(0, Some(w_buf), 0);
+        enc.set_buffer(1, Some(x), 0);
+        enc.set_buffer(2, Some(&out), 0);
+        enc.set_bytes(3, 4, &rows as *const u32 as _);
+        enc.set_bytes(4, 4, &cols as *const u32 as _);
+        enc.set_bytes(5, 8, &w_offset as *const u64 as _);
+        enc.set_bytes(6, 4, &batch_u as *const u32 as _);
+        dispatch_1d(enc, batch * n * 32, tg.width);
+        enc.end_encoding();
+        self.finish(name, cmd);
+        self.tuned(tg);
+        Ok(out)
+    }
+
     /// out[i] = a[i] + b[i]
     pub fn add(&self, a: &Buffer, b: &Buffer, n: usize) -> Buffer {
         let out = self.buf_zeros(n);
         let cmd = self.queue.new_command_buffer();
-        let enc = cmd.new_compute_command_encoder();
+        let enc = self.encoder(cmd);
         enc.set_compute_pipeline_state(&self.vec_add);
         enc.set_buffer(0, Some(a), 0);
         enc.set_buffer(1, Some(b), 0);
         enc.set_buffer(2, Some(&out), 0);
         dispatch_1d(enc, n, 256);
         enc.end_encoding();
-        cmd.commit();
-        cmd.wait_until_completed();
+        self.finish(c"vec_add", cmd);
         out
     }
 
     /// a[i] += b[i]  (in-place)
     pub fn add_inplace(&self, a: &Buffer, b: &Buffer, n: usize) {
         let cmd = self.queue.new_command_buffer();
-        let enc = cmd.new_compute_command_encoder();
+        let enc = self.encoder(cmd);
         enc.set_compute_pipeline_state(&self.vec_add_inplace);
         enc.set_buffer(0, Some(a), 0);
         enc.set_buffer(1, Some(b), 0);
         dispatch_1d(enc, n, 256);
         enc.end_encoding();
-        cmd.commit();
-        cmd.wait_until_completed();
+        self.finish(c"vec_add_inplace", cmd);
     }
 
     /// out[i] = silu(gate[i]) * up[i]
     pub fn silu_hadamard(&self, gate: &Buffer, up: &Buffer, n: usize) -> Buffer {
         let out = self.buf_zeros(n);
         let cmd = self.queue.new_command_buffer();
-        let enc = cmd.new_compute_command_encoder();
+        let enc = self.encoder(cmd);
         enc.set_compute_pipeline_state(&self.silu_hadamard);
         enc.set_buffer(0, Some(gate), 0);
         enc.set_buffer(1, Some(up), 0);
         enc.set_buffer(2, Some(&out), 0);
         dispatch_1d(enc, n, 256);
         enc.end_encoding();
-        cmd.commit();
-        cmd.wait_until_completed();
+        self.finish(c"silu_hadamard", cmd);
         out
     }
 
+    /// `silu_hadamard` over a fused gate/up matmul: each of the `batch` rows
+    /// of `gate_up` is `[gate | up]`, `ffn` wide each.
+    pub fn silu_hadamard_fused(&self, gate_up: &Buffer, ffn: usize, batch: usize) -> Buffer {
+        let out = self.buf_zeros(ffn * batch);
+        let (ffn_u, n_u) = (ffn as u32, (ffn * batch) as u32);
+        let cmd = self.queue.new_command_buffer();
+        let enc = self.encoder(cmd);
+        enc.set_compute_pipeline_state(&self.silu_hadamard_fused);
+        enc.set_buffer(0, Some(gate_up), 0);
+        enc.set_buffer(1, Some(&out), 0);
+        enc.set_bytes(2, 4, &ffn_u as *const u32 as _);
+        enc.set_bytes(3, 4, &n_u as *const u32 as _);
+        dispatch_1d(enc, ffn * batch, 256);
+        enc.end_encoding();
+        self.finish(c"silu_hadamard_fused", cmd);
+        out
+    }
+
+    /// Rows `ids` of a stored `[rows, cols]` F32, F16 or Q8_0 table (an
+    /// embedding matrix), dequantized into `[ids, cols]` f32. Every id must
+    /// be below the table's row count.
+    pub fn get_rows(&self, kind: u32, w: &Buffer, w_offset: u64, ids: &[u32], cols: usize) -> Result<Buffer> {
+        use crate::tensor::{GGML_F16, GGML_F32, GGML_Q8_0, ggml_type_name};
+        anyhow::ensure!(
+            matches!(kind, GGML_F32 | GGML_F16 | GGML_Q8_0),
+            "no row lookup kernel for {}",
+            ggml_type_name(kind)
+        );
+        anyhow::ensure!(kind != GGML_Q8_0 || cols.is_multiple_of(32), "Q8_0 row of {cols} is not whole blocks");
+        let n = ids.len() * cols;
+        let out = self.buf_zeros(n);
+        let id_bytes: Vec<u8> = ids.iter().flat_map(|id| id.to_le_bytes()).collect();
+        let ids = self.buf_from_bytes(&id_bytes);
+        let (cols_u, n_u) = (cols as u32, n as u32);
+        let cmd = self.queue.new_command_buffer();
+        let enc = self.encoder(cmd);
+        enc.set_compute_pipeline_state(&self.get_rows);
+        enc.set_buffer(0, Some(w), 0);
+        enc.set_buffer(1, Some(&ids), 0);
+        enc.set_buffer(2, Some(&out), 0);
+        enc.set_bytes(3, 4, &cols_u as *const u32 as _);
+        enc.set_bytes(4, 8, &w_offset as *const u64 as _);
+        enc.set_bytes(5, 4, &kind as *const u32 as _);
+        enc.set_bytes(6, 4, &n_u as *const u32 as _);
+        dispatch_1d(enc, n, 256);
+        enc.end_encoding();
+        self.finish(c"get_rows", cmd);
+        Ok(out)
+    }
+
     pub fn device_name(&self) -> String {
         self.device.name().to_string()
     }
+
+    /// A compute encoder on `cmd`, timestamped at both ends when timing.
+    fn encoder<'a>(&self, cmd: &'a CommandBufferRef) -> &'a ComputeCommandEncoderRef {
+        let Some(timer) = &self.timer else { return cmd.new_compute_command_encoder() };
+        let pass = ComputePassDescriptor::new();
+        let attachment = pass.sample_buffer_attachments().object_at(0).expect("sample buffer attachment 0");
+        attachment.set_sample_buffer(&timer.samples);
+        attachment.set_start_of_encoder_sample_index(0);
+        attachment.set_end_of_encoder_sample_index(1);
+        cmd.compute_command_encoder_with_descriptor(pass)
+    }
+
+    /// The width for the next dispatch of `kernel` on `pipeline` with this
+    /// shape: the saved pick, a candidate while tuning, or the default.
+    fn threadgroup(&self, kernel: &CStr, pipeline: &ComputePipelineState, n: usize, k: usize, batch: usize) -> Threadgroup {
+        let max = pipeline.max_total_threads_per_threadgroup() as usize;
+        let key = autotune::key(&kernel.to_string_lossy(), n, k, batch);
+        let (width, trial) = self.tuner.lock().unwrap().choose(&key, max);
+        // A profile times the kernels, not the search for their widths.
+        if trial && self.timer.is_some() {
+            return Threadgroup { width: autotune::DEFAULT_THREADGROUP.min(max), trial: None };
+        }
+        Threadgroup { width, trial: trial.then(|| (key, max, Instant::now())) }
+    }
+
+    /// Report a finished trial dispatch to the tuner.
+    fn tuned(&self, tg: Threadgroup) {
+        let Some((key, max, t0)) = tg.trial else { return };
+        if let Err(e) = self.tuner.lock().unwrap().observe(&key, tg.width, t0.elapsed(), max) {
+            eprintln!("warning: autotune picks not saved: {e:#}");
+        }
+    }
+
+    /// Run `cmd` to completion, inside a signpost interval named after the
+    /// kernel when profiling, and charge its GPU time when timing.
+    fn finish(&self, kernel: &'static CStr, cmd: &CommandBufferRef) {
+        if let Some(timer) = &self.timer {
+            let blit = cmd.new_blit_command_encoder();
+            blit.resolve_counters(&timer.samples, NSRange::new(0, 2), &timer.resolved, 0);
+            blit.end_encoding();
+        }
+        let _interval = self.signposts.as_ref().map(|s| {
+            cmd.set_label(kernel.to_str().unwrap_or_default());
+            s.interval(kernel)
+        });
+        cmd.commit();
+        cmd.wait_until_completed();
+        if let Some(timer) = &self.timer {
+            let t = unsafe { std::slice::from_raw_parts(timer.resolved.contents() as *const u64, 2) };
+            let mut state = timer.state.lock().unwrap();
+            let (layer, op) = state.scope.take().unwrap_or_else(|| (None, kernel.to_string_lossy().into_owned()));
+            state.ticks.record(layer, &op, Duration::from_nanos(t[1].saturating_sub(t[0])));
+        }
+    }
+}
+
+/// One Metal device as `sysinfo` reports it.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub name: String,
+    /// The one `Gpu::new` picks.
+    pub is_default: bool,
+    pub unified_memory: bool,
+    /// How much memory Metal suggests using before paging hurts.
+    pub working_set_bytes: u64,
+    pub max_buffer_bytes: u64,
 }
 
-fn pipeline(device: &Device, lib: &Library, name: &str) -> Result<ComputePipelineState> {
+/// Every Metal device on the host; empty off macOS.
+pub fn devices() -> Vec<DeviceInfo> {
+    let default = Device::system_default().map(|d| d.registry_id());
+    Device::all()
+        .iter()
+        .map(|d| DeviceInfo {
+            name: d.name().to_string(),
+            is_default: Some(d.registry_id()) == default,
+            unified_memory: d.has_unified_memory(),
+            working_set_bytes: d.recommended_max_working_set_size(),
+            max_buffer_bytes: d.max_buffer_length(),
+        })
+        .collect()
+}
+
+/// A `Gpu` for each index into `Device::all()` (the order `sysinfo` lists
+/// them in). Naming a device twice gives it two queues.
+pub fn open_devices(indices: &[usize]) -> Result<Vec<Gpu>> {
+    let all = Device::all();
+    indices
+        .iter()
+        .map(|&i| {
+            let device = all.get(i).with_context(|| format!("no Metal device {i} ({} found)", all.len()))?;
+            Gpu::on_device(device.clone())
+        })
+        .collect()
+}
+
+/// `name`'s pipeline, through the binary archive when there is one.
+fn pipeline(device: &Device, lib: &Library, cache: Option<&ShaderCache>, name: &str) -> Result<ComputePipelineState> {
     let func = lib
         .get_function(name, None)
         .map_err(|e| anyhow::anyhow!("get_function({name}): {e}"))?;
-    device
-        .new_compute_pipeline_state_with_function(&func)
-        .map_err(|e| anyhow::anyhow!("pipeline({name}): {e}"))
+    match cache {
+        Some(cache) => cache.pipeline(device, &func),
+        None => device.new_compute_pipeline_state_with_function(&func),
+    }
+    .map_err(|e| anyhow::anyhow!("pipeline({name}): {e}"))
 }
 
 fn dispatch_1d(enc: &metal::ComputeCommandEncoderRef, n: usize, tg_size: usize) {
@@ -0,0 +1,135 @@
+//! Importance matrices for quantization.
+//!
+//! For every matmul weight, the mean of x² over the calibration tokens for
+//! each input column x. A quantizer that minimises the error weighted by
+//! these values spends its precision on the columns that actually carry
+//! signal. The file is llama.cpp's `imatrix.dat` layout, so
+//! `llama-quantize --imatrix` reads it directly:
+//!
+//! ```text
+//! i32 n_entries
+//! n_entries × { i32 name_len, name, i32 ncall, i32 nval, f32 × nval }
+//! i32 chunks, i32 dataset_len, dataset
+//! ```
+//!
+//! The stored values are mean(x²) × ncall, which is what llama.cpp writes.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail, ensure};
+
+/// Squared activations for one weight.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImatrixEntry {
+    /// Σ x² per input column.
+    pub sums: Vec<f32>,
+    /// Input rows (tokens) summed.
+    pub count: usize,
+    /// Calibration chunks that reached this weight.
+    pub ncall: usize,
+}
+
+impl ImatrixEntry {
+    /// mean(x²) per column.
+    pub fn importance(&self) -> Vec<f32> {
+        self.sums.iter().map(|s| s / self.count.max(1) as f32).collect()
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Imatrix {
+    pub entries: BTreeMap<String, ImatrixEntry>,
+    /// Calibration chunks processed.
+    pub chunks: usize,
+    /// Where the calibration text came from, kept in the file.
+    pub dataset: String,
+}
+
+impl Imatrix {
+    /// Accumulate the input rows `x` (`x.len() / cols` tokens) of weight `name`.
+    pub fn record(&mut self, name: &str, x: &[f32], cols: usize) {
+        let entry = self.entries.entry(name.to_string()).or_default();
+        if entry.sums.is_empty() {
+            entry.sums = vec![0.0; cols];
+        }
+        for row in x.chunks_exact(cols) {
+            entry.sums.iter_mut().zip(row).for_each(|(s, v)| *s += v * v);
+            entry.count += 1;
+        }
+    }
+
+    /// Close one calibration chunk: every weight recorded so far was reached
+    /// once more.
+    pub fn end_chunk(&mut self) {
+        self.chunks += 1;
+        self.entries.values_mut().for_each(|e| e.ncall += 1);
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut out = Vec::new();
+        let int = |out: &mut Vec<u8>, v: usize| -> Result<()> {
+            out.extend(i32::try_from(v).context("imatrix field exceeds i32")?.to_le_bytes());
+            Ok(())
+        };
+        int(&mut out, self.entries.len())?;
+        for (name, e) in &self.entries {
+            int(&mut out, name.len())?;
+            out.extend(name.as_bytes());
+            int(&mut out, e.ncall)?;
+            int(&mut out, e.sums.len())?;
+            out.extend(e.importance().iter().flat_map(|v| (v * e.ncall as f32).to_le_bytes()));
+        }
+        int(&mut out, self.chunks)?;
+        int(&mut out, self.dataset.len())?;
+        out.extend(self.dataset.as_bytes());
+        std::fs::write(path, out).with_context(|| format!("write {}", path.display()))
+    }
+
+    /// Read a file `write` produced (or llama.cpp's legacy `imatrix.dat`).
+    /// Per-entry token counts are not stored; `count` comes back as `ncall`,
+    /// which keeps `importance` equal to what was written.
+    pub fn read(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("read {}", path.display()))?;
+        let mut r = Reader { bytes: &bytes, at: 0 };
+        let mut m = Self::default();
+        for _ in 0..r.int()? {
+            let name = String::from_utf8(r.string()?.to_vec()).context("imatrix tensor name")?;
+            let ncall = r.int()?;
+            let nval = r.int()?;
+            let sums = r.take(nval * 4)?.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect();
+            m.entries.insert(name, ImatrixEntry { sums, count: ncall, ncall });
+        }
+        // The trailer is missing from old files.
+        if let Ok(chunks) = r.int() {
+            m.chunks = chunks;
+            m.dataset = String::from_utf8_lossy(r.string()?).into_owned();
+        }
+        Ok(m)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let Some(chunk) = self.bytes.get(self.at..self.at + n) else { bail!("truncated imatrix at byte {}", self.at) };
+        self.at += n;
+        Ok(chunk)
+    }
+
+    fn int(&mut self) -> Result<usize> {
+        let v = i32::from_le_bytes(self.take(4)?.try_into()?);
+        ensure!(v >= 0, "negative length in imatrix at byte {}", self.at - 4);
+        Ok(v as usize)
+    }
+
+    /// A length-prefixed byte string.
+    fn string(&mut self) -> Result<&'a [u8]> {
+        let len = self.int()?;
+        self.take(len)
+    }
+}
@@ -10,7 +10,7 @@ pub struct TransparentRunner {
 
 impl TransparentRunner {
     pub fn new(model: GgufModelInfo, gpu: Gpu) -> Self {
-        let tokenizer = PromptTokenizer::new(model.vocab.clone());
+        let tokenizer = PromptTokenizer::for_model(&model).unwrap_or_else(|_| PromptTokenizer::new(model.vocab.clone()));
         Self { model, gpu, tokenizer }
     }
 
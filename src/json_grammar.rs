@@ -0,0 +1,244 @@
+//! The JSON grammar behind `response_format: {"type": "json_object"}`.
+//!
+//! `JsonState` is a byte-at-a-time recognizer for one JSON object (leading
+//! and trailing whitespace allowed). `JsonConstraint` runs every vocab
+//! token's bytes through a copy of it before each pick and masks the tokens
+//! that would break the document, so whatever the sampler chooses is still a
+//! prefix of valid JSON, and end-of-sequence is only possible once the object
+//! is closed.
+
+use crate::tokenizer::detokenize;
+
+/// Deepest nesting the recognizer tracks; deeper documents are refused.
+pub const MAX_DEPTH: u8 = 64;
+
+/// Where in the document the next byte lands. `Copy`, so trying a token is
+/// a copy and a few comparisons per byte.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JsonState {
+    mode: Mode,
+    /// Open containers, innermost in the low bit: 1 for an object, 0 for an array.
+    stack: u64,
+    depth: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    /// Before the top-level `{`.
+    Start,
+    /// After `:` or an array's `,`.
+    Value,
+    /// After `[`: a value or `]`.
+    ValueOrClose,
+    /// After `{`: a key or `}`.
+    KeyOrClose,
+    /// After an object's `,`.
+    Key,
+    Str { key: bool, esc: Esc },
+    Colon,
+    /// After a value inside a container: `,` or its closer.
+    After,
+    Number(Num),
+    /// The rest of `true`, `false` or `null`.
+    Literal(&'static [u8]),
+    Done,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Esc {
+    None,
+    Backslash,
+    /// Hex digits still due after `\u`.
+    Hex(u8),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Num {
+    Minus,
+    Zero,
+    Int,
+    Dot,
+    Frac,
+    Exp,
+    ExpSign,
+    ExpInt,
+}
+
+impl Default for JsonState {
+    fn default() -> Self {
+        Self { mode: Mode::Start, stack: 0, depth: 0 }
+    }
+}
+
+impl JsonState {
+    /// The top-level object is closed; only whitespace may follow.
+    pub fn is_complete(&self) -> bool {
+        self.mode == Mode::Done
+    }
+
+    /// Consume `bytes`, or return `false` (leaving `self` part-way) at the
+    /// first one that cannot continue the document.
+    pub fn feed_all(&mut self, bytes: &[u8]) -> bool {
+        bytes.iter().all(|&b| self.feed(b))
+    }
+
+    pub fn feed(&mut self, b: u8) -> bool {
+        let ws = matches!(b, b' ' | b'\t' | b'\n' | b'\r');
+        self.mode = match self.mode {
+            Mode::Start | Mode::Value | Mode::ValueOrClose | Mode::KeyOrClose | Mode::Key | Mode::Colon | Mode::After | Mode::Done
+                if ws =>
+            {
+                self.mode
+            }
+            Mode::Start if b == b'{' => return self.open(true),
+            Mode::ValueOrClose if b == b']' => return self.close(),
+            Mode::Value | Mode::ValueOrClose => return self.value(b),
+            Mode::KeyOrClose if b == b'}' => return self.close(),
+            Mode::KeyOrClose | Mode::Key if b == b'"' => Mode::Str { key: true, esc: Esc::None },
+            Mode::Str { key, esc } => match (esc, b) {
+                (Esc::None, b'"') if key => Mode::Colon,
+                (Esc::None, b'"') => return self.end_value(),
+                (Esc::None, b'\\') => Mode::Str { key, esc: Esc::Backslash },
+                (Esc::None, 0..=0x1F) => return false,
+                (Esc::None, _) => self.mode,
+                (Esc::Backslash, b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't') => Mode::Str { key, esc: Esc::None },
+                (Esc::Backslash, b'u') => Mode::Str { key, esc: Esc::Hex(4) },
+                (Esc::Hex(n), b) if b.is_ascii_hexdigit() => {
+                    Mode::Str { key, esc: if n == 1 { Esc::None } else { Esc::Hex(n - 1) } }
+                }
+                _ => return false,
+            },
+            Mode::Colon if b == b':' => Mode::Value,
+            Mode::After if b == b',' => if self.in_object() { Mode::Key } else { Mode::Value },
+            Mode::After if b == self.closer() => return self.close(),
+            Mode::Number(n) => match (n, b) {
+                (Num::Minus, b'0') => Mode::Number(Num::Zero),
+                (Num::Minus | Num::Int, b'0'..=b'9') => Mode::Number(Num::Int),
+                (Num::Zero | Num::Int, b'.') => Mode::Number(Num::Dot),
+                (Num::Dot | Num::Frac, b'0'..=b'9') => Mode::Number(Num::Frac),
+                (Num::Zero | Num::Int | Num::Frac, b'e' | b'E') => Mode::Number(Num::Exp),
+                (Num::Exp, b'+' | b'-') => Mode::Number(Num::ExpSign),
+                (Num::Exp | Num::ExpSign | Num::ExpInt, b'0'..=b'9') => Mode::Number(Num::ExpInt),
+                // A number ends at the first byte that is not part of it;
+                // that byte then counts as whatever follows the value.
+                (Num::Zero | Num::Int | Num::Frac | Num::ExpInt, _) => return self.end_value() && self.feed(b),
+                _ => return false,
+            },
+            Mode::Literal(rest) if rest[0] == b => match rest {
+                [_] => return self.end_value(),
+                _ => Mode::Literal(&rest[1..]),
+            },
+            _ => return false,
+        };
+        true
+    }
+
+    fn in_object(&self) -> bool {
+        self.stack & 1 == 1
+    }
+
+    fn closer(&self) -> u8 {
+        if self.in_object() { b'}' } else { b']' }
+    }
+
+    fn value(&mut self, b: u8) -> bool {
+        self.mode = match b {
+            b'{' => return self.open(true),
+            b'[' => return self.open(false),
+            b'"' => Mode::Str { key: false, esc: Esc::None },
+            b'-' => Mode::Number(Num::Minus),
+            b'0' => Mode::Number(Num::Zero),
+            b'1'..=b'9' => Mode::Number(Num::Int),
+            b't' => Mode::Literal(b"rue"),
+            b'f' => Mode::Literal(b"alse"),
+            b'n' => Mode::Literal(b"ull"),
+            _ => return false,
+        };
+        true
+    }
+
+    fn open(&mut self, object: bool) -> bool {
+        if self.depth == MAX_DEPTH {
+            return false;
+        }
+        self.stack = self.stack << 1 | object as u64;
+        self.depth += 1;
+        self.mode = if object { Mode::KeyOrClose } else { Mode::ValueOrClose };
+        true
+    }
+
+    fn close(&mut self) -> bool {
+        self.stack >>= 1;
+        self.depth -= 1;
+        self.end_value()
+    }
+
+    fn end_value(&mut self) -> bool {
+        self.mode = if self.depth == 0 { Mode::Done } else { Mode::After };
+        true
+    }
+}
+
+/// `JsonState` over a vocabulary: masks logits so sampling stays inside the
+/// grammar.
+pub struct JsonConstraint {
+    state: JsonState,
+    /// Each token's text as the grammar sees it.
+    tokens: Vec<Vec<u8>>,
+    /// Tokens that end generation: allowed only once the object is closed.
+    end: Vec<u32>,
+}
+
+impl JsonConstraint {
+    pub fn new(vocab: &[String]) -> Self {
+        let tokens = (0..vocab.len() as u32).map(|id| token_bytes(&detokenize(id, vocab))).collect();
+        Self { state: JsonState::default(), tokens, end: vec![2] }
+    }
+
+    /// Also treat `ids` (the request's stop tokens) as ending generation.
+    pub fn with_end_tokens(mut self, ids: &[u32]) -> Self {
+        self.end.extend_from_slice(ids);
+        self
+    }
+
+    pub fn state(&self) -> &JsonState {
+        &self.state
+    }
+
+    /// Set the logit of every token that cannot come next to -inf.
+    pub fn mask(&self, logits: &mut [f32]) {
+        for (id, l) in logits.iter_mut().enumerate() {
+            let allowed = if self.end.contains(&(id as u32)) {
+                self.state.is_complete()
+            } else {
+                let bytes = self.tokens.get(id).map_or(&[][..], Vec::as_slice);
+                let mut state = self.state;
+                !bytes.is_empty() && state.feed_all(bytes)
+            };
+            if !allowed {
+                *l = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    /// Advance past the chosen token.
+    pub fn accept(&mut self, id: u32) {
+        if let Some(bytes) = self.tokens.get(id as usize) {
+            self.state.feed_all(bytes);
+        }
+    }
+}
+
+/// `detokenize`'s text with the rest of GPT-2's byte alphabet below space
+/// (`Ċ` is a newline, `ĉ` a tab) mapped back to those bytes, so a token that
+/// decodes to a raw control character cannot land inside a string.
+pub(crate) fn token_bytes(text: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        match c as u32 {
+            cp @ 0x100..=0x11F => out.push((cp - 0x100) as u8),
+            _ => out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    out
+}
@@ -49,6 +49,283 @@ kernel void q8_0_matvec(
     if (lane == 0) out[row] = total;
 }
 
+// ---------------------------------------------------------------------------
+// Q8_0 matrix × batch of vectors (speculative verification, batched prefill)
+//   x  : [batch, cols] float32, one input row per token
+//   out: [batch, rows] float32
+//
+//   Launch with batch*rows*32 threads. Same simdgroup-per-output layout as
+//   q8_0_matvec; simdgroup s computes row (s % rows) of token (s / rows).
+//   The whole simdgroup shares one (row, token), so the early return never
+//   splits a simd_sum.
+// ---------------------------------------------------------------------------
+kernel void q8_0_matmul(
+    device const uint8_t* W [[buffer(0)]],
+    device const float*   x [[buffer(1)]],
+    device float*       out [[buffer(2)]],
+    constant uint& rows     [[buffer(3)]],
+    constant uint& cols     [[buffer(4)]],
+    constant ulong& W_off   [[buffer(5)]],
+    constant uint& batch    [[buffer(6)]],
+    uint tid  [[thread_position_in_grid]],
+    uint lane [[thread_index_in_simdgroup]]
+) {
+    const uint sg  = tid / 32;
+    const uint row = sg % rows;
+    const uint t   = sg / rows;
+    if (t >= batch) return;
+
+    const uint blocks_per_row = cols / 32;
+    const uint block_stride   = 34;
+    device const float* xt = x + (ulong)t * cols;
+
+    float acc = 0.0f;
+    ulong base = W_off + (ulong)row * (ulong)blocks_per_row * block_stride;
+    for (uint b = lane; b < blocks_per_row; b += 32) {
+        ulong bo = base + (ulong)b * block_stride;
+        uint16_t scale_bits = (uint16_t)W[bo] | ((uint16_t)W[bo + 1] << 8);
+        float scale = (float)as_type<half>(scale_bits);
+        uint xi = b * 32;
+        for (uint k = 0; k < 32; k++) {
+            acc += scale * (float)(int8_t)W[bo + 2 + k] * xt[xi + k];
+        }
+    }
+
+    float total = simd_sum(acc);
+    if (lane == 0) out[(ulong)t * rows + row] = total;
+}
+
+// ---------------------------------------------------------------------------
+// Repacked Q8_0 matrix × batch of vectors (layout from src/repack.rs)
+//   buf : repack cache; S_off → f16 scale plane [rows, cols/32],
+//         Q_off → int8 quant plane [rows, cols], each row contiguous
+//   x   : [batch, cols] float32, out: [batch, rows] float32
+//
+//   Same launch shape as q8_0_matmul. With scales out of the way every block
+//   of quants is eight aligned char4 loads against eight float4s of x.
+// ---------------------------------------------------------------------------
+kernel void q8_0r_matmul(
+    device const uint8_t* buf [[buffer(0)]],
+    device const float*   x   [[buffer(1)]],
+    device float*         out [[buffer(2)]],
+    constant uint& rows       [[buffer(3)]],
+    constant uint& cols       [[buffer(4)]],
+    constant ulong& S_off     [[buffer(5)]],
+    constant ulong& Q_off     [[buffer(6)]],
+    constant uint& batch      [[buffer(7)]],
+    uint tid  [[thread_position_in_grid]],
+    uint lane [[thread_index_in_simdgroup]]
+) {
+    const uint sg  = tid / 32;
+    const uint row = sg % rows;
+    const uint t   = sg / rows;
+    if (t >= batch) return;
+
+    const uint blocks_per_row = cols / 32;
+    device const half*   S  = (device const half*)(buf + S_off) + (ulong)row * blocks_per_row;
+    device const char4*  Q  = (device const char4*)(buf + Q_off + (ulong)row * cols);
+    device const float4* xt = (device const float4*)(x + (ulong)t * cols);
+
+    float acc = 0.0f;
+    for (uint b = lane; b < blocks_per_row; b += 32) {
+        float4 part = 0.0f;
+        for (uint j = 0; j < 8; j++) {
+            part += float4(Q[b * 8 + j]) * xt[b * 8 + j];
+        }
+        acc += (float)S[b] * (part.x + part.y + part.z + part.w);
+    }
+
+    float total = simd_sum(acc);
+    if (lane == 0) out[(ulong)t * rows + row] = total;
+}
+
+// ---------------------------------------------------------------------------
+// Q2_K / Q3_K matrix × batch of vectors (batch = 1 is the matvec)
+//   W  : [rows, cols] in 256-element super-blocks, layouts as ggml's
+//        block_q2_K (84 B) and block_q3_K (110 B); see src/tensor.rs
+//   x  : [batch, cols] float32, out: [batch, rows] float32
+//
+//   Same launch shape as q8_0_matmul. A 4096-wide row has only 16
+//   super-blocks, so lanes stride over 16-element sub-blocks instead: each
+//   has one scale, and its quants are 16 consecutive bytes at one bit shift.
+// ---------------------------------------------------------------------------
+static inline float load_half(device const uint8_t* p) {
+    return (float)as_type<half>((uint16_t)p[0] | ((uint16_t)p[1] << 8));
+}
+
+kernel void q2_k_matmul(
+    device const uint8_t* W [[buffer(0)]],
+    device const float*   x [[buffer(1)]],
+    device float*       out [[buffer(2)]],
+    constant uint& rows     [[buffer(3)]],
+    constant uint& cols     [[buffer(4)]],
+    constant ulong& W_off   [[buffer(5)]],
+    constant uint& batch    [[buffer(6)]],
+    uint tid  [[thread_position_in_grid]],
+    uint lane [[thread_index_in_simdgroup]]
+) {
+    const uint sg  = tid / 32;
+    const uint row = sg % rows;
+    const uint t   = sg / rows;
+    if (t >= batch) return;
+
+    const uint block_stride = 84;
+    const uint subs_per_row = cols / 16;
+    device const float* xt = x + (ulong)t * cols;
+    ulong base = W_off + (ulong)row * (ulong)(cols / 256) * block_stride;
+
+    float acc = 0.0f;
+    for (uint c = lane; c < subs_per_row; c += 32) {
+        ulong bo = base + (ulong)(c / 16) * block_stride;
+        uint s = c % 16;
+        uint8_t sc = W[bo + s];
+        float dl = load_half(W + bo + 80) * (float)(sc & 0xF);
+        float ml = load_half(W + bo + 82) * (float)(sc >> 4);
+        device const uint8_t* q = W + bo + 16 + 32 * (s / 8) + 16 * (s % 2);
+        uint shift = 2 * ((s % 8) / 2);
+        device const float* xs = xt + c * 16;
+        float sum = 0.0f, xsum = 0.0f;
+        for (uint l = 0; l < 16; l++) {
+            sum  += (float)((q[l] >> shift) & 3) * xs[l];
+            xsum += xs[l];
+        }
+        acc += dl * sum - ml * xsum;
+    }
+
+    float total = simd_sum(acc);
+    if (lane == 0) out[(ulong)t * rows + row] = total;
+}
+
+kernel void q3_k_matmul(
+    device const uint8_t* W [[buffer(0)]],
+    device const float*   x [[buffer(1)]],
+    device float*       out [[buffer(2)]],
+    constant uint& rows     [[buffer(3)]],
+    constant uint& cols     [[buffer(4)]],
+    constant ulong& W_off   [[buffer(5)]],
+    constant uint& batch    [[buffer(6)]],
+    uint tid  [[thread_position_in_grid]],
+    uint lane [[thread_index_in_simdgroup]]
+) {
+    const uint sg  = tid / 32;
+    const uint row = sg % rows;
+    const uint t   = sg / rows;
+    if (t >= batch) return;
+
+    const uint block_stride = 110;
+    const uint subs_per_row = cols / 16;
+    device const float* xt = x + (ulong)t * cols;
+    ulong base = W_off + (ulong)row * (ulong)(cols / 256) * block_stride;
+
+    float acc = 0.0f;
+    for (uint c = lane; c < subs_per_row; c += 32) {
+        ulong bo = base + (ulong)(c / 16) * block_stride;
+        uint s = c % 16;
+        // 6-bit scale: low nibble from bytes 96..104, top bits from 104..108.
+        uint low  = (W[bo + 96 + s % 8] >> (4 * (s / 8))) & 0xF;
+        uint high = (W[bo + 104 + s % 4] >> (2 * (s / 4))) & 3;
+        float dl = load_half(W + bo + 108) * (float)((int)(low | (high << 4)) - 32);
+        uint l0 = 16 * (s % 2);
+        device const uint8_t* q  = W + bo + 32 + 32 * (s / 8) + l0;
+        device const uint8_t* hm = W + bo + l0;
+        uint shift = 2 * ((s % 8) / 2);
+        uint8_t bit = (uint8_t)(1 << (4 * (s / 8) + (s % 8) / 2));
+        device const float* xs = xt + c * 16;
+        float sum = 0.0f;
+        for (uint l = 0; l < 16; l++) {
+            int v = (int)((q[l] >> shift) & 3) - ((hm[l] & bit) ? 0 : 4);
+            sum += (float)v * xs[l];
+        }
+        acc += dl * sum;
+    }
+
+    float total = simd_sum(acc);
+    if (lane == 0) out[(ulong)t * rows + row] = total;
+}
+
+// ---------------------------------------------------------------------------
+// IQ4_NL matrix × batch of vectors (batch = 1 is the matvec)
+//   W  : [rows, cols] in 18-byte blocks of 32: [f16 d] [16 bytes of nibbles]
+//        byte j holds elements j (low nibble) and j+16 (high nibble); each
+//        nibble indexes the kvalues codebook below
+//
+//   Same launch shape and lane-per-block stride as q8_0_matmul.
+// ---------------------------------------------------------------------------
+constant float iq4nl_values[16] = {
+    -127, -104, -83, -65, -49, -35, -22, -10, 1, 13, 25, 38, 53, 69, 89, 113,
+};
+
+kernel void iq4_nl_matmul(
+    device const uint8_t* W [[buffer(0)]],
+    device const float*   x [[buffer(1)]],
+    device float*       out [[buffer(2)]],
+    constant uint& rows     [[buffer(3)]],
+    constant uint& cols     [[buffer(4)]],
+    constant ulong& W_off   [[buffer(5)]],
+    constant uint& batch    [[buffer(6)]],
+    uint tid  [[thread_position_in_grid]],
+    uint lane [[thread_index_in_simdgroup]]
+) {
+    const uint sg  = tid / 32;
+    const uint row = sg % rows;
+    const uint t   = sg / rows;
+    if (t >= batch) return;
+
+    const uint blocks_per_row = cols / 32;
+    const uint block_stride   = 18;
+    device const float* xt = x + (ulong)t * cols;
+
+    float acc = 0.0f;
+    ulong base = W_off + (ulong)row * (ulong)blocks_per_row * block_stride;
+    for (uint b = lane; b < blocks_per_row; b += 32) {
+        ulong bo = base + (ulong)b * block_stride;
+        device const uint8_t* qs = W + bo + 2;
+        device const float* xs = xt + b * 32;
+        float sum = 0.0f;
+        for (uint j = 0; j < 16; j++) {
+            sum += iq4nl_values[qs[j] & 0xF] * xs[j] + iq4nl_values[qs[j] >> 4] * xs[j + 16];
+        }
+        acc += load_half(W + bo) * sum;
+    }
+
+    float total = simd_sum(acc);
+    if (lane == 0) out[(ulong)t * rows + row] = total;
+}
+
+// ---------------------------------------------------------------------------
+// F16 matrix × batch of vectors (batch = 1 is the matvec)
+//   W  : [rows, cols] row-major f16, for unquantized models (imatrix runs)
+//
+//   Same launch shape as q8_0_matmul; each lane strides across the columns.
+// ---------------------------------------------------------------------------
+kernel void f16_matmul(
+    device const uint8_t* W [[buffer(0)]],
+    device const float*   x [[buffer(1)]],
+    device float*       out [[buffer(2)]],
+    constant uint& rows     [[buffer(3)]],
+    constant uint& cols     [[buffer(4)]],
+    constant ulong& W_off   [[buffer(5)]],
+    constant uint& batch    [[buffer(6)]],
+    uint tid  [[thread_position_in_grid]],
+    uint lane [[thread_index_in_simdgroup]]
+) {
+    const uint sg  = tid / 32;
+    const uint row = sg % rows;
+    const uint t   = sg / rows;
+    if (t >= batch) return;
+
+    device const uint8_t* w = W + W_off + (ulong)row * cols * 2;
+    device const float* xt = x + (ulong)t * cols;
+
+    float acc = 0.0f;
+    for (uint c = lane; c < cols; c += 32) {
+        acc += load_half(w + 2 * c) * xt[c];
+    }
+
+    float total = simd_sum(acc);
+    if (lane == 0) out[(ulong)t * rows + row] = total;
+}
+
 // ---------------------------------------------------------------------------
 // Element-wise add (residual stream)
 // ---------------------------------------------------------------------------
@@ -84,3 +361,49 @@ kernel void silu_hadamard(
     float g = gate[i];
     out[i] = (g / (1.0f + exp(-g))) * up[i];
 }
+
+// ---------------------------------------------------------------------------
+// SiLU(gate) ⊙ up from a fused gate/up matmul: row t is [gate | up]
+// ---------------------------------------------------------------------------
+kernel void silu_hadamard_fused(
+    device const float* gate_up [[buffer(0)]],
+    device float*       out     [[buffer(1)]],
+    constant uint&      ffn     [[buffer(2)]],
+    constant uint&      n       [[buffer(3)]],
+    uint i [[thread_position_in_grid]]
+) {
+    if (i >= n) return;
+    uint t = i / ffn, j = i % ffn;
+    float g = gate_up[t * 2 * ffn + j];
+    out[i] = (g / (1.0f + exp(-g))) * gate_up[t * 2 * ffn + ffn + j];
+}
+
+// ---------------------------------------------------------------------------
+// Embedding lookup: rows ids[t] of a stored [rows, cols] table, as float
+//   kind: ggml type id, 0 (F32), 1 (F16) or 8 (Q8_0)
+//   out : [n_ids, cols] float32
+//
+//   One thread per output value; the table itself is never dequantized.
+// ---------------------------------------------------------------------------
+kernel void get_rows(
+    device const uint8_t* W   [[buffer(0)]],
+    device const uint*    ids [[buffer(1)]],
+    device float*         out [[buffer(2)]],
+    constant uint& cols       [[buffer(3)]],
+    constant ulong& W_off     [[buffer(4)]],
+    constant uint& kind       [[buffer(5)]],
+    constant uint& n          [[buffer(6)]],
+    uint i [[thread_position_in_grid]]
+) {
+    if (i >= n) return;
+    const uint t = i / cols, c = i % cols;
+    const ulong row = ids[t];
+    if (kind == 8) {
+        ulong bo = W_off + (row * (cols / 32) + c / 32) * 34;
+        out[i] = load_half(W + bo) * (float)(int8_t)W[bo + 2 + c % 32];
+    } else if (kind == 1) {
+        out[i] = load_half(W + W_off + (row * cols + c) * 2);
+    } else {
+        out[i] = *(device const float*)(W + W_off + (row * cols + c) * 4);
+    }
+}
@@ -0,0 +1,69 @@
+//! LLMetal as a library: the same modules the CLI drives, exposed so other
+//! programs can load a GGUF and run generation without scraping stdout.
+
+pub mod agent;
+#[cfg(feature = "tokio")]
+pub mod async_api;
+pub mod audit;
+pub mod autotune;
+pub mod bert;
+pub mod blas;
+pub mod budget;
+pub mod chat;
+pub mod choice;
+pub mod classify;
+pub mod commit_msg;
+pub mod complete;
+pub mod cpu;
+pub mod daemon;
+pub mod dump;
+pub mod ed25519;
+pub mod embed;
+pub mod envelope;
+pub mod eval;
+pub mod events;
+pub mod fusion;
+pub mod fuzz;
+pub mod gguf;
+pub mod gguf_loader;
+pub mod gpu;
+pub mod imatrix;
+pub mod inference;
+pub mod json_grammar;
+pub mod lsp;
+pub mod manifest;
+pub mod mcp;
+pub mod model;
+pub mod pipeline;
+pub mod profile;
+pub mod quality;
+pub mod rag;
+pub mod reasoning;
+pub mod recurrent;
+pub mod regex_grammar;
+pub mod repack;
+pub mod repair;
+pub mod reply;
+pub mod rerank;
+pub mod rwkv;
+pub mod rpc;
+pub mod sampler;
+pub mod search;
+pub mod section_cache;
+pub mod shader_cache;
+pub mod shard;
+pub mod sink;
+pub mod speculative;
+pub mod ssm;
+pub mod t5;
+pub mod tensor;
+pub mod tokenizer;
+pub mod unicode;
+pub mod vision;
+pub mod watermark;
+pub mod weights;
+pub mod whisper;
+
+#[cfg(test)]
+mod test_support;
+mod tests;
@@ -0,0 +1,253 @@
+//! An experimental Language Server Protocol server (`llmetal lsp`), so an
+//! editor can get completions from a local model through the protocol it
+//! already speaks.
+//!
+//! Messages are JSON-RPC 2.0 on stdin and stdout, each framed by a
+//! `Content-Length` header. The server keeps open documents in full
+//! (`textDocumentSync` 1: every change resends the whole text) and answers:
+//! - `textDocument/inlineCompletion` (LSP 3.18) with multi-line ghost text;
+//! - `textDocument/completion` with single-line items, for editors without
+//!   inline completion;
+//! - `textDocument/codeAction` on a selection with "Rewrite with llmetal".
+//!   The model only runs once the action is chosen (`codeAction/resolve`):
+//!   it fills the selection as a hole and the edit replaces it.
+//!
+//! Every answer comes from the `fill` callback `serve` is given, normally
+//! `complete::Completer::fill`. Requests are handled one at a time, so
+//! `$/cancelRequest` is ignored. LSP positions count UTF-16 code units,
+//! which `offset_at` and `position_at` convert.
+
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use anyhow::{Context, Result, bail};
+use serde_json::{Value, json};
+
+use crate::complete::{Completion, Fill};
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+
+/// The code action's title and kind.
+pub const REWRITE_TITLE: &str = "Rewrite with llmetal";
+const REWRITE_KIND: &str = "refactor.rewrite";
+
+/// Read one framed message; `None` at end of input.
+pub fn read_message(input: &mut impl BufRead) -> Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.eq_ignore_ascii_case("content-length")
+        {
+            length = Some(value.trim().parse::<usize>().context("bad Content-Length")?);
+        }
+    }
+    let mut body = vec![0; length.context("message without a Content-Length")?];
+    input.read_exact(&mut body)?;
+    Ok(Some(serde_json::from_slice(&body).context("message is not JSON")?))
+}
+
+pub fn write_message(output: &mut impl Write, msg: &Value) -> Result<()> {
+    let body = msg.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Byte offset of the 0-based `line` and UTF-16 `character` in `text`,
+/// clamped to the line's end (and the line to the last).
+pub fn offset_at(text: &str, line: usize, character: usize) -> usize {
+    let mut start = 0;
+    for _ in 0..line {
+        match text[start..].find('\n') {
+            Some(i) => start += i + 1,
+            None => return text.len(),
+        }
+    }
+    let body = &text[start..start + text[start..].find('\n').unwrap_or(text.len() - start)];
+    let mut units = 0;
+    for (i, c) in body.char_indices() {
+        if units >= character {
+            return start + i;
+        }
+        units += c.len_utf16();
+    }
+    start + body.len()
+}
+
+/// The LSP position of byte `offset` in `text`.
+pub fn position_at(text: &str, offset: usize) -> Value {
+    let before = &text[..offset];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    let character: usize = before[line_start..].chars().map(char::len_utf16).sum();
+    json!({ "line": line, "character": character })
+}
+
+struct Document {
+    text: String,
+    language: String,
+}
+
+/// Serve one editor session until `exit` or the end of `input`.
+pub fn serve(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    fill: &mut dyn FnMut(&Fill) -> Result<Vec<Completion>>,
+) -> Result<()> {
+    let mut docs: HashMap<String, Document> = HashMap::new();
+    while let Some(msg) = read_message(input)? {
+        let method = msg["method"].as_str().unwrap_or_default();
+        let params = &msg["params"];
+        let id = msg.get("id").cloned();
+        match method {
+            "exit" => return Ok(()),
+            "textDocument/didOpen" => {
+                let doc = &params["textDocument"];
+                if let (Some(uri), Some(text)) = (doc["uri"].as_str(), doc["text"].as_str()) {
+                    let language = doc["languageId"].as_str().unwrap_or_default().to_string();
+                    docs.insert(uri.to_string(), Document { text: text.to_string(), language });
+                }
+            }
+            "textDocument/didChange" => {
+                let uri = params["textDocument"]["uri"].as_str().unwrap_or_default();
+                let text = params["contentChanges"].as_array().and_then(|c| c.last()).and_then(|c| c["text"].as_str());
+                if let (Some(doc), Some(text)) = (docs.get_mut(uri), text) {
+                    doc.text = text.to_string();
+                }
+            }
+            "textDocument/didClose" => {
+                docs.remove(params["textDocument"]["uri"].as_str().unwrap_or_default());
+            }
+            _ => {}
+        }
+        // Notifications (no id) get no answer.
+        let Some(id) = id else { continue };
+        let result = match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    "textDocumentSync": { "openClose": true, "change": 1 },
+                    "completionProvider": {},
+                    "inlineCompletionProvider": {},
+                    "codeActionProvider": { "codeActionKinds": [REWRITE_KIND], "resolveProvider": true },
+                },
+                "serverInfo": { "name": "llmetal", "version": env!("CARGO_PKG_VERSION") },
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/inlineCompletion" => at_cursor(&docs, params, false, fill).map(|(range, found)| {
+                let items: Vec<Value> = found.iter().map(|c| json!({ "insertText": c.text, "range": range })).collect();
+                json!({ "items": items })
+            }),
+            "textDocument/completion" => at_cursor(&docs, params, true, fill).map(|(range, found)| {
+                let items: Vec<Value> = found
+                    .iter()
+                    .enumerate()
+                    .map(|(rank, c)| {
+                        json!({
+                            "label": c.text.trim(),
+                            "kind": 1,
+                            "detail": "llmetal",
+                            "sortText": format!("{rank:04}"),
+                            "textEdit": { "range": range, "newText": c.text },
+                        })
+                    })
+                    .collect();
+                json!({ "isIncomplete": true, "items": items })
+            }),
+            "textDocument/codeAction" => Ok(code_actions(params)),
+            "codeAction/resolve" => resolve_rewrite(&docs, params, fill),
+            _ => Err(RpcError(METHOD_NOT_FOUND, format!("{method} is not supported"))),
+        };
+        let reply = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(RpcError(code, message)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }),
+        };
+        write_message(output, &reply)?;
+    }
+    Ok(())
+}
+
+/// A JSON-RPC error code and message.
+struct RpcError(i64, String);
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self(INTERNAL_ERROR, format!("{e:#}"))
+    }
+}
+
+fn document<'a, 'u>(docs: &'a HashMap<String, Document>, uri: &'u Value) -> Result<(&'u str, &'a Document), RpcError> {
+    let uri = uri.as_str().ok_or_else(|| RpcError(INVALID_PARAMS, "no document uri".into()))?;
+    let doc = docs.get(uri).ok_or_else(|| RpcError(INVALID_PARAMS, format!("{uri} is not open")))?;
+    Ok((uri, doc))
+}
+
+fn offset_of(text: &str, position: &Value) -> Result<usize> {
+    let (Some(line), Some(character)) = (position["line"].as_u64(), position["character"].as_u64()) else {
+        bail!("bad position {position}");
+    };
+    Ok(offset_at(text, line as usize, character as usize))
+}
+
+/// The path of a `file://` URI, or the URI itself.
+fn uri_path(uri: &str) -> &str {
+    uri.strip_prefix("file://").unwrap_or(uri)
+}
+
+/// Candidates at `params.position` and the empty range they insert at.
+fn at_cursor(
+    docs: &HashMap<String, Document>,
+    params: &Value,
+    single_line: bool,
+    fill: &mut dyn FnMut(&Fill) -> Result<Vec<Completion>>,
+) -> Result<(Value, Vec<Completion>), RpcError> {
+    let (uri, doc) = document(docs, &params["textDocument"]["uri"])?;
+    let offset = offset_of(&doc.text, &params["position"])?;
+    let language = Some(doc.language.as_str()).filter(|l| !l.is_empty());
+    let found = fill(&Fill { path: uri_path(uri), language, text: &doc.text, start: offset, end: offset, single_line })?;
+    let at = position_at(&doc.text, offset);
+    Ok((json!({ "start": at, "end": at }), found))
+}
+
+/// The rewrite action for a non-empty selection, its edit left to resolve.
+fn code_actions(params: &Value) -> Value {
+    let range = &params["range"];
+    if range["start"] == range["end"] {
+        return json!([]);
+    }
+    json!([{
+        "title": REWRITE_TITLE,
+        "kind": REWRITE_KIND,
+        "data": { "uri": params["textDocument"]["uri"], "range": range },
+    }])
+}
+
+/// `codeAction/resolve`: the selection filled as a hole, as a workspace
+/// edit. An action the model had nothing for comes back without an edit.
+fn resolve_rewrite(
+    docs: &HashMap<String, Document>,
+    action: &Value,
+    fill: &mut dyn FnMut(&Fill) -> Result<Vec<Completion>>,
+) -> Result<Value, RpcError> {
+    let data = &action["data"];
+    let (uri, doc) = document(docs, &data["uri"])?;
+    let range = &data["range"];
+    let (start, end) = (offset_of(&doc.text, &range["start"])?, offset_of(&doc.text, &range["end"])?);
+    let language = Some(doc.language.as_str()).filter(|l| !l.is_empty());
+    let found = fill(&Fill { path: uri_path(uri), language, text: &doc.text, start, end, single_line: false })?;
+    let mut action = action.clone();
+    if let Some(best) = found.first() {
+        let range = json!({ "start": position_at(&doc.text, start), "end": position_at(&doc.text, end) });
+        action["edit"] = json!({ "changes": { uri: [{ "range": range, "newText": best.text }] } });
+    }
+    Ok(action)
+}
@@ -1,66 +1,2474 @@
-mod gguf_loader;
-mod gpu;
-mod inference;
-mod model;
-mod tensor;
-mod tests;
-mod tokenizer;
+use std::io::{IsTerminal, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use anyhow::{Context, Result, bail};
-use gguf_loader::GgufModelInfo;
-use inference::TransparentRunner;
-use model::LlamaModel;
+use llmetal::agent::{Agent, ToolRun};
+use llmetal::events::{FinishReason, GenerationEvent};
+use llmetal::mcp::{McpClient, ServerConfig};
+use llmetal::gguf_loader::GgufModelInfo;
+use llmetal::inference::TransparentRunner;
+use llmetal::model::{self, GenerateOptions, LlamaModel};
+use llmetal::reasoning::{ReasoningMode, Segment, ThinkStream, ThinkTokens};
+use llmetal::sampler::{DryConfig, LoopGuard, SamplerConfig, XtcConfig};
+use llmetal::sink::{CommandSink, OutputSink};
+use llmetal::speculative::{DraftSource, EarlyExitConfig, LookupConfig, MedusaConfig};
+use llmetal::bert::{self, BertModel};
+use llmetal::rwkv::{self, RwkvModel};
+use llmetal::ssm::{self, SsmModel, SsmState};
+use llmetal::t5::{self, T5Model};
+use llmetal::whisper::{self, WhisperModel};
+use llmetal::{audit, budget, chat, choice, classify, commit_msg, complete, cpu, daemon, dump, embed, envelope, eval, gpu, lsp, manifest, quality, rag, reasoning, regex_grammar, repair, reply, rerank, rpc, search, tensor, tokenizer, watermark};
 
 fn main() -> Result<()> {
-    let command = Command::from_env()?;
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    // `--force-kernel NAME` goes with any command.
+    if let Some(i) = args.iter().position(|a| a == "--force-kernel") {
+        let name = args.get(i + 1).context("--force-kernel needs a value")?;
+        cpu::set_kernel(name.parse()?)?;
+        args.drain(i..i + 2);
+    }
+    let command = Command::parse(args.into_iter())?;
+    install_interrupt_handler();
 
     match command {
         Command::Inspect { model_path } => {
             let model = GgufModelInfo::load(&model_path)
                 .with_context(|| format!("failed to inspect GGUF file: {model_path}"))?;
             model.print_summary();
+            if let Some(truncation) = repair::check(&model_path)? {
+                eprintln!("warning: {}", truncation.with_source());
+            }
         }
+        Command::Repair { model_path, url } => repair_model(&model_path, url)?,
         Command::Trace { model_path, prompt } => {
             let model = GgufModelInfo::load(&model_path)
                 .with_context(|| format!("failed to inspect GGUF file: {model_path}"))?;
             let runner = TransparentRunner::new(model, gpu::Gpu::new()?);
             runner.describe_prompt_pass(&prompt);
         }
-        Command::Run { model_path, prompt, max_new } => {
-            eprintln!("Loading model tensors (mmap)...");
-            let model = LlamaModel::load(&model_path)?;
+        Command::Audit { model_path } => print_audit(&model_path)?,
+        Command::Sysinfo => print_sysinfo(),
+        Command::Dump { model_path, out_dir, prompt } => dump_activations(&model_path, &out_dir, &prompt)?,
+        Command::DumpDiff { a, b, tol } => dump_diff(&a, &b, tol)?,
+        Command::LogitDiff(args) => logit_diff(args)?,
+        Command::Embed(args) => embed_documents(args)?,
+        Command::Rerank(args) => rerank_documents(args)?,
+        Command::Classify(args) => classify_documents(args)?,
+        Command::DetectWatermark(args) => detect_watermark(args)?,
+        Command::Search(args) => search_index(args)?,
+        Command::Rag(args) => rag_answer(args)?,
+        Command::Chat(args) => chat(args)?,
+        Command::Complete(args) => complete_code(args)?,
+        Command::CommitMsg(args) => commit_message(args)?,
+        Command::Lsp(args) => {
+            let mut completer = load_completer(&args.model_path, args.ctx, args.opts)?;
+            eprintln!("llmetal lsp: serving on stdio");
+            let (stdin, stdout) = (std::io::stdin(), std::io::stdout());
+            lsp::serve(&mut stdin.lock(), &mut stdout.lock(), &mut |fill| completer.fill(fill))?;
+        }
+        Command::Compare(args) => compare_models(args)?,
+        Command::Eval(args) => run_eval(args)?,
+        Command::Imatrix(args) => collect_imatrix(args)?,
+        Command::Batch(args) => batch_generate(args)?,
+        Command::Daemon { model_path, socket, limits, warmup } => {
+            let socket = socket.map_or_else(|| daemon::default_socket_path(&model_path), Into::into);
+            let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+            let mut daemon = daemon::Daemon::load(&model_path, threads)?.with_limits(limits);
+            if warmup {
+                report_warmup(&daemon.warmup()?);
+            }
+            daemon.serve(&socket)?;
+        }
+        Command::Worker { listen } => {
+            let listener = std::net::TcpListener::bind(&listen).with_context(|| format!("listen on {listen}"))?;
+            rpc::serve(listener)?;
+        }
+        Command::Seal { input, output } => {
+            let output = std::path::Path::new(&output);
+            let key = envelope::load_key(output)?;
+            envelope::seal_file(std::path::Path::new(&input), output, &key)?;
+            eprintln!("sealed {input} as {}", output.display());
+        }
+        Command::Sign { model_path, key, manifest_path } => sign_model(&model_path, &key, manifest_path)?,
+        Command::Run(args) => run(*args)?,
+    }
+
+    Ok(())
+}
+
+fn run(mut args: RunArgs) -> Result<()> {
+    if !args.rpc.is_empty() && (args.beams.is_some() || args.cfg_negative.is_some()) {
+        bail!("--rpc workers keep one sequence's K/V rows, so they cannot run --beams or --cfg-negative-prompt");
+    }
+    let regex = match &args.regex {
+        Some(_) if args.beams.is_some() || !args.choices.is_empty() => bail!("--regex constrains sampling; drop --beams and --choice"),
+        Some(pattern) => Some(Arc::new(regex_grammar::Regex::new(pattern).with_context(|| format!("--regex {pattern:?}"))?)),
+        None => None,
+    };
+    if args.watermark.is_some() && (args.beams.is_some() || !args.choices.is_empty()) {
+        bail!("--watermark biases sampling; drop --beams and --choice");
+    }
+    let loop_guard = match &args.loop_action {
+        Some(_) if args.beams.is_some() || !args.choices.is_empty() => bail!("--loop-guard watches sampled output; drop --beams and --choice"),
+        Some(action) => Some(LoopGuard { action: action.parse().context("--loop-guard")?, ..args.loop_guard }),
+        None => None,
+    };
+    let reasoning_mode: ReasoningMode = args.reasoning.as_deref().map(str::parse).transpose().context("--reasoning")?.unwrap_or_default();
+    let max_thinking = match (args.max_thinking, args.reasoning_effort.as_deref()) {
+        (Some(n), _) => Some(n),
+        (None, Some(effort)) => Some(reasoning::effort_budget(effort).context("--reasoning-effort")?),
+        (None, None) => None,
+    };
+    if max_thinking.is_some() && (args.beams.is_some() || !args.choices.is_empty()) {
+        bail!("--max-thinking caps sampled output; drop --beams and --choice");
+    }
+    if args.yarn && args.rope_scale.is_none() {
+        bail!("--yarn needs --rope-scale");
+    }
+    if let Some(key) = &args.verify_signature {
+        verify_signature(&args.model_path, key, args.manifest.as_deref())?;
+    }
+    eprintln!("Loading vocabulary...");
+    let gguf = GgufModelInfo::load(&args.model_path)?;
+    let is_ssm = ssm::is_ssm(&gguf.family);
+    let is_rwkv = rwkv::is_rwkv(&gguf.family);
+    let is_t5 = t5::is_t5(&gguf.family);
+    if (is_ssm || is_rwkv || is_t5)
+        && (args.beams.is_some()
+            || args.cfg_negative.is_some()
+            || args.draft.is_some()
+            || !args.choices.is_empty()
+            || !args.rpc.is_empty()
+            || args.devices.is_some()
+            || args.pipeline.is_some())
+    {
+        bail!("{} models run their own decoding loop: drop --beams, --cfg-negative-prompt, drafts, --choice and multi-device flags", gguf.family);
+    }
+    if args.ssm_state.is_some() && !is_ssm {
+        bail!("--ssm-state is for state-space models (mamba, jamba), not {}", gguf.family);
+    }
+    // T5 vocabularies have no BOS (id 1 is `</s>`); the encoder adds `</s>`.
+    args.no_bos |= is_t5;
+    // The daemon's model is loaded as-is and generates one sequence at a
+    // time; anything else needs this process's own copy.
+    let local_only = args.local
+        || args.beams.is_some()
+        || args.cfg_negative.is_some()
+        || args.draft.is_some()
+        || args.rope_scale.is_some()
+        || args.rope_base.is_some()
+        || args.metal_capture.is_some()
+        || args.profile
+        || args.repack_cache
+        || args.fuse_weights
+        || args.int8_activations
+        || args.autotune
+        || args.prefill_chunk.is_some()
+        || args.accelerate.is_some()
+        || args.gpu_layers.is_some()
+        || args.devices.is_some()
+        || args.pipeline.is_some()
+        || !args.rpc.is_empty()
+        || !args.choices.is_empty()
+        || args.watermark.is_some()
+        || args.verify_signature.is_some()
+        || is_ssm
+        || is_rwkv
+        || is_t5;
+    let mut remote = if local_only { None } else { daemon::DaemonClient::for_model(&args.model_path) };
+    let (mut recurrent, mut rwkv_model, mut t5_model) = (None, None, None);
+    let (mut local, mut stop_tokens, ctx_train) = match &mut remote {
+        Some(client) => {
+            eprintln!("Using the daemon on {}", client.socket().display());
+            let info = client.info()?;
+            (None, info.stop_tokens, info.ctx_train)
+        }
+        None if is_ssm => {
+            eprintln!("Loading model tensors (mmap)...");
+            let mut model = SsmModel::load(&args.model_path)?;
+            eprintln!("Architecture: {} ({} layers, state {}x{})", gguf.family, model.arch.n_layers, model.arch.d_inner, model.arch.d_state);
+            if let Some(path) = &args.ssm_state
+                && std::path::Path::new(path).exists()
+            {
+                model.session = SsmState::load(path.as_ref())?;
+                eprintln!("Restored the state of {} tokens from {path}", model.session.tokens().len());
+            }
+            let stop_tokens = model.declared_stop_tokens();
+            let ctx_train = model.arch.ctx_train;
+            recurrent = Some(model);
+            (None, stop_tokens, ctx_train)
+        }
+        None if is_rwkv => {
+            eprintln!("Loading model tensors (mmap)...");
+            let model = RwkvModel::load(&args.model_path)?;
+            eprintln!("Architecture: {} ({} layers, {} heads of {})", gguf.family, model.arch.n_layers, model.arch.n_heads(), model.arch.head_size);
+            let stop_tokens = model.declared_stop_tokens();
+            let ctx_train = model.arch.ctx_train;
+            rwkv_model = Some(model);
+            (None, stop_tokens, ctx_train)
+        }
+        None if is_t5 => {
+            eprintln!("Loading model tensors (mmap)...");
+            let model = T5Model::load(&args.model_path)?;
+            eprintln!("Architecture: {} ({} encoder + {} decoder layers)", gguf.family, model.arch.n_enc_layers, model.arch.n_dec_layers);
+            let stop_tokens = model.declared_stop_tokens();
+            let ctx_train = model.arch.ctx_train;
+            t5_model = Some(model);
+            (None, stop_tokens, ctx_train)
+        }
+        None => {
+            let model = load_run_model(&args)?;
+            let stop_tokens = model.declared_stop_tokens();
+            let ctx_train = model.arch.ctx_train;
+            (Some(model), stop_tokens, ctx_train)
+        }
+    };
+
+    let tokenizer = tokenizer::PromptTokenizer::for_model(&gguf)?;
+    let vocab = gguf.vocab;
+    // `--prompt-file -`, or no prompt words with stdin redirected, reads stdin.
+    let from_stdin = args.prompt_file.as_deref() == Some("-")
+        || (args.prompt_file.is_none() && args.prompt.is_none() && !std::io::stdin().is_terminal());
+    let prompt = match (&args.prompt_file, &args.prompt) {
+        _ if from_stdin => Some(std::io::read_to_string(std::io::stdin()).context("read prompt from stdin")?),
+        (Some(path), _) => Some(std::fs::read_to_string(path).with_context(|| format!("read {path}"))?),
+        (None, words) => words.clone(),
+    };
+    let prompt = match prompt {
+        // Editors and `echo` end input with a newline nobody meant as prompt text.
+        Some(text) if args.prompt_file.is_some() || from_stdin => {
+            if args.raw { text } else { text.trim_end_matches(['\n', '\r']).to_string() }
+        }
+        Some(text) => text,
+        None => "Hello".to_string(),
+    };
+    let prompt = match &args.audio {
+        Some(wav) => {
+            let transcript = transcribe(wav, args.audio_model.as_deref(), args.audio_language.as_deref())?;
+            match args.prompt.is_some() || args.prompt_file.is_some() || from_stdin {
+                true => format!("{prompt}\n\n{transcript}"),
+                false => transcript,
+            }
+        }
+        None => prompt,
+    };
+    if args.raw && args.chat_format.is_some() {
+        bail!("--raw sends the prompt untemplated; drop --chat-format");
+    }
+    let format = args.chat_format.as_deref().map(|flag| chat::resolve(flag, gguf.chat_template.as_deref())).transpose()?;
+    let prompt = match format {
+        Some(format) => {
+            stop_tokens = chat::stop_tokens(format, &vocab, &stop_tokens);
+            eprintln!("Chat format: {}", format.name());
+            format.render(&[chat::Message::new(chat::Role::User, &prompt)], true)
+        }
+        None => prompt,
+    };
+
+    eprintln!("Tokenizing prompt...");
+    let mut token_ids = if args.no_bos { tokenizer.tokenize(&prompt) } else { tokenizer.tokenize_bos(&prompt) };
+    eprintln!("  {} tokens", token_ids.len());
+    if args.choices.is_empty() {
+        // Keep room for the whole reply; BOS stays, the oldest text goes.
+        let budget = budget::ContextBudget::new(args.ctx.unwrap_or(ctx_train), args.max_new);
+        let dropped = budget.truncate(&mut token_ids, usize::from(!args.no_bos))?;
+        if dropped > 0 {
+            eprintln!("  dropped the {dropped} oldest prompt tokens to leave room for --max {}", args.max_new);
+        }
+    }
+    let cfg = args.cfg_negative.as_ref().map(|negative| model::Guidance {
+        negative: tokenizer.tokenize_bos(negative),
+        scale: args.cfg_scale,
+    });
+
+    if !args.choices.is_empty() {
+        if args.beams.is_some() || cfg.is_some() || args.draft.is_some() {
+            bail!("--choice decodes greedily; drop --beams, --cfg-negative-prompt and drafts");
+        }
+        let model = local.as_mut().context("--choice needs the model loaded locally")?;
+        let choices = choice::Choices::new(args.choices.iter().map(|c| tokenizer.tokenize(c)).collect())?;
+        let picked = model.choose(&token_ids, &choices, &stop_tokens)?;
+        let text = &args.choices[picked.index];
+        if args.json_output {
+            println!("{}", serde_json::json!({ "choice": text, "index": picked.index, "logprob": picked.logprob }));
+        } else {
+            println!("{text}");
+            eprintln!("choice {} of {}, logprob {:.3}", picked.index + 1, choices.len(), picked.logprob);
+        }
+        return Ok(());
+    }
+
+    let think_tags = think_tokens(reasoning_mode, max_thinking, &vocab)?;
+    let mut think = think_tags.filter(|_| reasoning_mode != ReasoningMode::Show).map(|t| ThinkStream::new(t, &token_ids));
+
+    eprintln!("\n--- generation ---");
+    let (mut text, mut thought, mut done) = (String::new(), String::new(), None);
+    let mut sink: Option<Box<dyn OutputSink>> = match &args.tts_command {
+        Some(command) => Some(Box::new(CommandSink::spawn(command)?)),
+        None => None,
+    };
+    let mut emit = |event| match event {
+        GenerationEvent::Token { id, logprob, .. } => {
+            let piece = tokenizer.decode(&[id], !args.special);
+            match think.as_mut().map(|t| t.route(id)) {
+                Some(Segment::Tag) => {}
+                Some(Segment::Reasoning) if reasoning_mode == ReasoningMode::Hide => {}
+                Some(Segment::Reasoning) if args.json_output => thought.push_str(&piece),
+                Some(Segment::Reasoning) => eprint!("{piece}"),
+                _ if args.json_output => {
+                    speak(&mut sink, &piece);
+                    text.push_str(&piece);
+                }
+                _ => {
+                    speak(&mut sink, &piece);
+                    print_event(GenerationEvent::Token { id, text: piece, logprob });
+                }
+            }
+        }
+        GenerationEvent::Done { reason, timings, usage } => {
+            if args.json_output {
+                done = Some((reason, timings, usage));
+            } else {
+                print_event(GenerationEvent::Done { reason, timings, usage });
+            }
+            if args.timings {
+                eprintln!("\n--- timings ---\n{}", timings.report());
+            }
+        }
+        event => print_event(event),
+    };
+    if let Some(beams) = &args.beams {
+        if cfg.is_some() {
+            bail!("--beams cannot be combined with --cfg-negative-prompt");
+        }
+        if args.metal_capture.is_some() {
+            bail!("--metal-capture traces a decode step, which --beams does not have");
+        }
+        let model = local.as_mut().context("beam search needs the model loaded locally")?;
+        model.beam_search(&token_ids, args.max_new, beams, &vocab, &mut emit)?;
+    } else {
+        let mut sampling = SamplerConfig { regex, loop_guard, max_thinking, ..args.sampling };
+        if let Some(w) = args.watermark {
+            sampling.processors.push(Arc::new(w));
+        }
+        let mut opts = GenerateOptions {
+            max_new: args.max_new,
+            cfg,
+            sampling,
+            draft: args.draft,
+            adaptive_draft: !args.fixed_draft,
+            stop_tokens,
+            metal_capture: args.metal_capture.map(Into::into),
+            cancel: None,
+        };
+        let generator: &mut dyn model::Generator = match (&mut remote, &mut local, &mut recurrent, &mut rwkv_model, &mut t5_model) {
+            (Some(client), ..) => client,
+            (None, Some(model), ..) => model,
+            (None, None, Some(model), ..) => model,
+            (None, None, None, Some(model), _) => model,
+            (None, None, None, None, Some(model)) => model,
+            (None, None, None, None, None) => unreachable!("run loads the model when no daemon answers"),
+        };
+        let mut session = model::KvSession::default();
+        interruptible(&mut opts, |opts| {
+            generator.generate_cached(&mut session, &token_ids, opts, &vocab, &mut emit).map(drop)
+        })?;
+    }
+    if let Some(mut sink) = sink {
+        sink.finish()?;
+    }
+    if let (Some(model), Some(path)) = (&recurrent, &args.ssm_state) {
+        model.session.save(path.as_ref())?;
+        eprintln!("Saved the state of {} tokens to {path}", model.session.tokens().len());
+    }
+    if args.profile
+        && let Some(model) = &local
+    {
+        eprintln!("\n--- GPU profile ---\n{}", model.gpu_profile().report());
+    }
+    if args.autotune
+        && let Some(model) = &local
+    {
+        eprintln!("\n--- autotune ---");
+        for (shape, width) in model.autotune_picks() {
+            eprintln!("{shape}: {width} threads per threadgroup");
+        }
+    }
+    if let Some((reason, t, usage)) = done {
+        let mut result = serde_json::json!({
+            "text": text,
+            "finish_reason": reason.as_str(),
+            "prompt_tokens": usage.prompt_tokens,
+            "completion_tokens": usage.completion_tokens,
+            "usage": usage.to_json(),
+            "timings": {
+                "load_ms": t.load_ms,
+                "prefill_ms": t.prefill_ms,
+                "decode_ms": t.decode_ms,
+                "prefill_tokens_per_second": t.prefill_tps(),
+                "decode_tokens_per_second": t.decode_tps(),
+            },
+        });
+        if t.drafted_tokens > 0 {
+            result["timings"]["speculative"] = serde_json::json!({
+                "drafted_tokens": t.drafted_tokens,
+                "accepted_tokens": t.accepted_tokens,
+                "acceptance_rate": t.acceptance_rate(),
+                "draft_len": t.draft_len,
+            });
+        }
+        if reasoning_mode == ReasoningMode::Separate {
+            result["reasoning"] = thought.into();
+        }
+        if let Some(format) = format {
+            result["message"] = reply::parse(format, &text).to_json();
+        }
+        println!("{result}");
+    }
+    Ok(())
+}
+
+/// The model as `run`'s flags configure it, every weight uploaded.
+fn load_run_model(args: &RunArgs) -> Result<LlamaModel> {
+    eprintln!("Loading model tensors (mmap)...");
+    let mut model = LlamaModel::load(&args.model_path)?;
+    if let Some(factor) = args.rope_scale {
+        model.arch.rope_scaling = if args.yarn {
+            model::RopeScaling::Yarn(model::YarnParams {
+                factor,
+                original_ctx: model.rope_original_ctx(),
+                beta_fast: 32.0,
+                beta_slow: 1.0,
+            })
+        } else {
+            model::RopeScaling::Linear { factor }
+        };
+    }
+    if let Some(base) = args.rope_base {
+        model.arch.rope_base = base;
+    }
+    eprintln!(
+        "Architecture: {} layers, {} hidden, {} heads, {} kv-heads{}",
+        model.arch.n_layers,
+        model.arch.hidden,
+        model.arch.n_heads,
+        model.arch.n_kv_heads,
+        if model.weights.tied_head() { ", output head tied to the embeddings" } else { "" }
+    );
+    let scaling = model.arch.scaling.active();
+    if !scaling.is_empty() {
+        eprintln!("Score scaling: {}", scaling.join(", "));
+    }
+    if args.metal_capture.is_some() {
+        model.enable_signposts();
+    }
+    if let Some(path) = &args.medusa {
+        eprintln!("Loaded {} Medusa heads from {path}", model.load_medusa(path)?);
+    }
+    if let Some(n) = args.gpu_layers {
+        model.set_gpu_layers(n);
+    }
+    model.set_int8_activations(args.int8_activations);
+    if let Some(devices) = &args.devices {
+        let n = model.use_devices(devices)?;
+        let per_device: Vec<_> =
+            model.device_weight_bytes().iter().map(|&b| format!("{:.1} MB", b as f64 / 1e6)).collect();
+        eprintln!("Split {n} weights across devices {devices:?}: {}", per_device.join(", "));
+    }
+    if args.pipeline.is_some() || !args.rpc.is_empty() {
+        // Workers take the blocks after this Mac's own devices.
+        let default = gpu::devices().iter().position(|d| d.is_default).unwrap_or(0);
+        let devices = args.pipeline.clone().unwrap_or_else(|| vec![default]);
+        model.use_pipeline(&devices, &args.rpc, args.micro_batch)?;
+        for stage in model.pipeline_stages() {
+            eprintln!(
+                "Pipeline stage on {}: layers {}..{} ({:.1} MB)",
+                stage.place(),
+                stage.layers.start,
+                stage.layers.end,
+                stage.bytes() as f64 / 1e6
+            );
+        }
+    }
+    if let Some(min_batch) = args.accelerate {
+        model.use_accelerate(min_batch)?;
+        eprintln!("F32/F16 matmuls of {min_batch}+ rows run on Accelerate");
+    }
+    if args.repack_cache {
+        let (path, built) = model.use_repack_cache()?;
+        eprintln!("Repack cache {}: {}", if built { "built" } else { "loaded" }, path.display());
+    }
+    if args.fuse_weights {
+        eprintln!("Fused {} Q/K/V and gate/up stacks", model.fuse_weights()?);
+    }
+    let load = model.load_all_tensors(args.load_threads)?;
+    eprintln!(
+        "Uploaded {} weights ({} zero-copy, {:.1} MB) in {}ms on {} threads",
+        load.tensors,
+        load.mapped,
+        load.bytes as f64 / 1e6,
+        load.ms,
+        load.threads
+    );
+    if model.gpu_layers() <= model.arch.n_layers {
+        eprintln!("{} of {} layers on the GPU; the rest run on the CPU", model.gpu_layers(), model.arch.n_layers);
+    }
+    if args.autotune {
+        model.enable_autotune();
+    }
+    if let Some(n) = args.prefill_chunk {
+        model.set_prefill_chunk(n);
+    }
+    if args.warmup {
+        report_warmup(&model.warmup()?);
+    }
+    if args.profile {
+        model.enable_gpu_profile()?;
+    }
+    Ok(model)
+}
+
+fn report_warmup(stats: &model::WarmupStats) {
+    eprintln!("Warmed up {:.1} MB of weights in {}ms", stats.bytes as f64 / 1e6, stats.ms);
+}
+
+/// Write a signed manifest for `model_path`. A missing key file gets a
+/// fresh key, readable only by its owner.
+fn sign_model(model_path: &str, key_path: &str, manifest_path: Option<String>) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let seed = match std::fs::read_to_string(key_path) {
+        Ok(_) => manifest::read_key(key_path)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut seed = [0u8; 32];
+            std::io::Read::read_exact(&mut std::fs::File::open("/dev/urandom")?, &mut seed)?;
+            let mut file = std::fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(key_path)?;
+            writeln!(file, "{}", manifest::hex(&seed))?;
+            eprintln!("new signing key in {key_path}");
+            seed
+        }
+        Err(e) => return Err(e).with_context(|| format!("read {key_path}")),
+    };
+    let signed = manifest::sign(model_path, &seed)?;
+    let path = manifest_path.map_or_else(|| manifest::default_path(model_path), Into::into);
+    std::fs::write(&path, serde_json::to_string_pretty(&signed)?).with_context(|| format!("write {}", path.display()))?;
+    eprintln!("signed {} tensors into {}", signed["tensors"].as_object().map_or(0, |t| t.len()), path.display());
+    println!("{}", signed["public_key"].as_str().unwrap_or_default());
+    Ok(())
+}
+
+/// `--verify-signature KEY [--manifest PATH]`, before anything is loaded.
+fn verify_signature(model_path: &str, key: &str, manifest_path: Option<&str>) -> Result<()> {
+    let path = manifest_path.map_or_else(|| manifest::default_path(model_path), Into::into);
+    manifest::require(model_path, &path, &manifest::read_key(key)?)?;
+    eprintln!("Signature verified against {}; the weights are checked as they are mapped", path.display());
+    Ok(())
+}
+
+/// Hand `piece` to the `--tts-command` sink. A failing command stops the
+/// speech with a warning, not the generation.
+fn speak(sink: &mut Option<Box<dyn OutputSink>>, piece: &str) {
+    if let Some(s) = sink
+        && let Err(e) = s.text(piece)
+    {
+        eprintln!("\nspeech output stopped: {e:#}");
+        *sink = None;
+    }
+}
+
+/// `--audio FILE.wav`: the speech in it as text, through a Whisper GGUF.
+fn transcribe(wav: &str, model_path: Option<&str>, language: Option<&str>) -> Result<String> {
+    let model_path = model_path.context("--audio needs --audio-model WHISPER.gguf")?;
+    let info = GgufModelInfo::load(model_path)?;
+    if !whisper::is_whisper(&info.family) {
+        bail!("--audio-model must be a Whisper GGUF, not {}", info.family);
+    }
+    let tokenizer = tokenizer::PromptTokenizer::for_model(&info)?;
+    let samples = whisper::read_audio(wav.as_ref())?;
+    eprintln!("Transcribing {wav} ({:.1} s)...", samples.len() as f32 / whisper::SAMPLE_RATE as f32);
+    let mut model = WhisperModel::load(model_path)?;
+    let transcript = model.transcribe(&samples, language, tokenizer.vocab())?;
+    let text = tokenizer.decode(&transcript.tokens, true).trim().to_string();
+    match &transcript.language {
+        Some(lang) => eprintln!("  {} tokens ({lang})", transcript.tokens.len()),
+        None => eprintln!("  {} tokens", transcript.tokens.len()),
+    }
+    Ok(text)
+}
+
+/// The CPU's SIMD features and the attention kernel picked for them, each
+/// Metal device, and physical memory.
+fn print_sysinfo() {
+    const GB: f64 = (1u64 << 30) as f64;
+    let features = cpu::CpuFeatures::detect();
+    let threads = std::thread::available_parallelism().map_or(0, |n| n.get());
+    let brand = cpu::brand().unwrap_or_else(|| "unknown CPU".into());
+    println!("CPU:     {brand} ({}, {threads} threads)", std::env::consts::ARCH);
+    let names = features.names();
+    println!("  features: {}", if names.is_empty() { "none detected".to_string() } else { names.join(" ") });
+    println!("  AMX:      {}", if features.amx { "yes, through Accelerate" } else { "no" });
+    println!("  kernel:   {} (best here: {})", cpu::kernel().name(), features.best_kernel().name());
+    let devices = gpu::devices();
+    if devices.is_empty() {
+        println!("Metal:   no device");
+    }
+    for (i, d) in devices.iter().enumerate() {
+        println!("Metal {i}: {}{}", d.name, if d.is_default { " (default)" } else { "" });
+        println!("  unified memory:      {}", if d.unified_memory { "yes" } else { "no" });
+        println!("  working set:         {:.1} GB", d.working_set_bytes as f64 / GB);
+        println!("  largest buffer:      {:.1} GB", d.max_buffer_bytes as f64 / GB);
+    }
+    match cpu::physical_memory() {
+        Some(bytes) => println!("Memory:  {:.1} GB", bytes as f64 / GB),
+        None => println!("Memory:  unknown"),
+    }
+}
+
+/// One line per tensor; NaN/Inf rows are flagged and counted at the end.
+fn print_audit(model_path: &str) -> Result<()> {
+    let tensors = audit::audit(model_path)
+        .with_context(|| format!("failed to audit GGUF file: {model_path}"))?;
+    println!(
+        "{:<40} {:>7} {:>12} {:>12} {:>12} {:>12} {:>8} {:>8}",
+        "tensor", "type", "min", "max", "mean", "std", "nan", "inf"
+    );
+    let mut broken = 0;
+    let mut skipped = 0;
+    for t in &tensors {
+        let kind = tensor::ggml_type_name(t.kind);
+        let Some(s) = &t.stats else {
+            skipped += 1;
+            println!("{:<40} {kind:>7}   (no CPU dequantizer)", t.name);
+            continue;
+        };
+        broken += s.is_broken() as usize;
+        println!(
+            "{:<40} {kind:>7} {:>12.5} {:>12.5} {:>12.5} {:>12.5} {:>8} {:>8}{}",
+            t.name, s.min, s.max, s.mean, s.std, s.nan, s.inf,
+            if s.is_broken() { "  <-- NaN/Inf" } else { "" }
+        );
+    }
+    println!("\n{} tensors, {broken} with NaN/Inf, {skipped} not audited", tensors.len());
+    if broken > 0 {
+        bail!("{broken} tensors contain NaN or Inf");
+    }
+    Ok(())
+}
+
+/// Evaluate `prompt` once and write every intermediate activation to `out_dir`.
+fn dump_activations(model_path: &str, out_dir: &str, prompt: &str) -> Result<()> {
+    let mut model = LlamaModel::load(model_path)?;
+    let tokens = tokenizer::PromptTokenizer::for_model(&GgufModelInfo::load(model_path)?)?.tokenize_bos(prompt);
+    eprintln!("{} prompt tokens", tokens.len());
+    model.enable_activation_dump();
+    model.evaluate(&tokens)?;
+    let dump = model.take_activation_dump().context("activation dump was not recorded")?;
+    dump.write(std::path::Path::new(out_dir))?;
+    for t in dump.tensors() {
+        println!("{:<20} {}x{}", t.name, t.rows(), t.cols);
+    }
+    eprintln!("wrote {} tensors to {out_dir}", dump.tensors().len());
+    Ok(())
+}
+
+/// Compare two dumps in graph order and point at the first tensor past `tol`.
+fn dump_diff(a: &str, b: &str, tol: f32) -> Result<()> {
+    let da = dump::ActivationDump::read(std::path::Path::new(a))?;
+    let db = dump::ActivationDump::read(std::path::Path::new(b))?;
+    let diffs = dump::compare(&da, &db);
+    let mut first_bad = None;
+    for d in &diffs {
+        let bad = d.rel > tol;
+        println!("{:<20} max_abs {:>12.6e}  rel {:>12.6e}{}", d.name, d.max_abs, d.rel, if bad { "  <--" } else { "" });
+        if bad && first_bad.is_none() {
+            first_bad = Some(d.name.clone());
+        }
+    }
+    match first_bad {
+        Some(name) => bail!("dumps diverge first at {name} (rel > {tol})"),
+        None => {
+            eprintln!("{} tensors agree within rel {tol}", diffs.len());
+            Ok(())
+        }
+    }
+}
+
+/// Evaluate the prompt and hold every position's logits against llama.cpp's
+/// for the same model and tokens. When the reference is a full activation
+/// dump, the first position past `--tol` is traced back to the first
+/// tensor that disagrees at that token.
+fn logit_diff(args: LogitDiffArgs) -> Result<()> {
+    let tokenizer = tokenizer::PromptTokenizer::for_model(&GgufModelInfo::load(&args.model_path)?)?;
+    let tokens = args.tokens.clone().unwrap_or_else(|| tokenizer.tokenize_bos(&args.prompt));
+    anyhow::ensure!(!tokens.is_empty(), "no tokens to evaluate");
+    eprintln!("{} tokens: {tokens:?}", tokens.len());
+    let mut model = LlamaModel::load(&args.model_path)?;
+    model.enable_activation_dump();
+    let ours = model.evaluate(&tokens)?;
+    let activations = model.take_activation_dump().context("activation dump was not recorded")?;
+    let reference_path = std::path::Path::new(&args.reference);
+    let reference = dump::read_logits(reference_path, ours[0].len())?;
+    let diffs = dump::compare_logits(&ours, &reference)?;
+    let mut first_bad = None;
+    for d in &diffs {
+        let bad = d.max_abs > args.tol;
+        let text = tokenizer.decode(&tokens[d.pos..=d.pos], false);
+        println!(
+            "{:>5} {:<14} max_abs {:>12.6e}  rel {:>12.6e}  kl {:>10.3e}  top-1 {}{}",
+            d.pos,
+            format!("{text:?}"),
+            d.max_abs,
+            d.rel,
+            d.kl,
+            if d.top_ours == d.top_reference { "same".to_string() } else { format!("{} vs {}", d.top_ours, d.top_reference) },
+            if bad { "  <--" } else { "" }
+        );
+        if bad && first_bad.is_none() {
+            first_bad = Some(d);
+        }
+    }
+    let worst = diffs.iter().map(|d| d.max_abs).fold(0.0, f32::max);
+    let same = diffs.iter().filter(|d| d.top_ours == d.top_reference).count();
+    println!("{} positions, max_abs {worst:.6e}, top-1 agreement {same}/{}", diffs.len(), diffs.len());
+    let Some(first) = first_bad else {
+        eprintln!("logits match the reference within {}", args.tol);
+        return Ok(());
+    };
+    if reference_path.is_dir() {
+        let reference = dump::ActivationDump::read(reference_path)?;
+        match dump::first_divergent(&reference, &activations, first.pos, args.layer_tol) {
+            Some(t) => println!("position {}: first divergent tensor {} (max_abs {:.6e}, rel {:.6e})", first.pos, t.name, t.max_abs, t.rel),
+            None => println!("position {}: no shared tensor differs past rel {}", first.pos, args.layer_tol),
+        }
+    }
+    bail!("logits diverge first at position {} (token {}): max_abs {:.6e} > {}", first.pos, tokens[first.pos], first.max_abs, args.tol)
+}
+
+const CHAT_HELP: &str = "/system [TEXT]  show or set the system prompt
+/summary        show the summary of older turns
+/save PATH      write the conversation to a session file
+/load PATH      replace the conversation with a session file
+/reset          clear every turn and the summary (the system prompt stays)
+/regen          drop the last reply and generate it again with a new seed
+/undo           drop the last exchange
+/help           this list";
+
+/// Interactive chat on stdin over a `chat::Conversation`: the system prompt
+/// stays at the front however many old turns are shifted out, is saved with
+/// the session, and the KV cache carries over between turns.
+fn chat(args: ChatArgs) -> Result<()> {
+    if let Some(key) = &args.verify_signature {
+        verify_signature(&args.model_path, key, args.manifest.as_deref())?;
+    }
+    let gguf = GgufModelInfo::load(&args.model_path)?;
+    let format = chat::resolve(&args.chat_format, gguf.chat_template.as_deref())?;
+    let think_tags = think_tokens(args.reasoning, args.max_thinking, &gguf.vocab)?;
+    let tokenizer = tokenizer::PromptTokenizer::for_model(&gguf)?;
+    let local_only = args.local || args.verify_signature.is_some();
+    let mut remote = if local_only { None } else { daemon::DaemonClient::for_model(&args.model_path) }
+        .map(|client| match &args.session {
+            Some(id) => client.with_session(id),
+            None => client,
+        });
+    let mut local = None;
+    let mut conv = match &mut remote {
+        Some(client) => {
+            let info = client.info()?;
+            eprintln!("Using the daemon on {}", client.socket().display());
+            chat::Conversation::with_limits(format, tokenizer, info.ctx_train, &info.stop_tokens)
+        }
+        None => {
+            let mut model = LlamaModel::load(&args.model_path)?;
+            model.load_all_tensors(std::thread::available_parallelism().map_or(4, |n| n.get()))?;
+            if let Some(n) = args.prefill_chunk {
+                model.set_prefill_chunk(n);
+            }
+            if args.warmup {
+                report_warmup(&model.warmup()?);
+            }
+            let conv = chat::Conversation::new(&model, format, tokenizer);
+            local = Some(model);
+            conv
+        }
+    };
+    let model: &mut dyn model::Generator = match (&mut remote, &mut local) {
+        (Some(client), _) => client,
+        (None, Some(model)) => model,
+        (None, None) => unreachable!("chat loads the model when no daemon answers"),
+    };
+    let mut agent = None;
+    if args.tools.is_some() || !args.mcp.is_empty() || args.mcp_config.is_some() {
+        let mut a = Agent { max_iterations: args.max_tool_rounds, ..Agent::default() };
+        if let Some(path) = &args.tools {
+            a.load_tools(std::path::Path::new(path))?;
+        }
+        if let Some(secs) = args.tool_timeout {
+            a.sandbox.timeout = std::time::Duration::from_secs(secs);
+        }
+        a.sandbox.dir = args.tool_dir.as_ref().map(std::path::PathBuf::from);
+        let mut servers = match &args.mcp_config {
+            Some(path) => ServerConfig::load(std::path::Path::new(path))?,
+            None => Vec::new(),
+        };
+        for line in &args.mcp {
+            servers.push(ServerConfig::from_command_line(line)?);
+        }
+        for server in &servers {
+            let mut client = McpClient::spawn(server)?;
+            if let Some(secs) = args.tool_timeout {
+                client.timeout = std::time::Duration::from_secs(secs);
+            }
+            let name = client.name.clone();
+            let n = client.register_tools(&mut a).with_context(|| format!("listing the tools of MCP server '{name}'"))?;
+            eprintln!("MCP server {name}: {n} tools");
+        }
+        agent = Some(a);
+    }
+    conv.history.system = match &agent {
+        Some(agent) => Some(agent.system_prompt(args.system.as_deref())),
+        None => args.system,
+    };
+    if args.parse_special {
+        conv.user_special = tokenizer::SpecialTokens::Parse;
+    }
+    conv.skip_special_tokens = !args.special;
+    if let Some(ctx) = args.ctx {
+        conv.ctx = ctx;
+    }
+    if args.summarize || args.summarize_after.is_some() {
+        conv.summarizer = Some(chat::Summarizer::Model);
+        conv.summarize_after = args.summarize_after;
+    }
+    let mut opts = GenerateOptions {
+        max_new: args.max_new,
+        sampling: SamplerConfig { seed: args.seed, xtc: args.xtc, max_thinking: args.max_thinking, ..SamplerConfig::default() },
+        ..GenerateOptions::default()
+    };
+    eprintln!("Chat format: {}. /help lists commands.", format.name());
+    let report = |conv: &chat::Conversation| {
+        let last = conv.last_turn();
+        if last.summarized_turns > 0 {
+            eprintln!("(summarized the {} oldest messages)", last.summarized_turns);
+        }
+        if last.dropped_turns > 0 {
+            eprintln!("(context shift: dropped the {} oldest messages)", last.dropped_turns);
+        }
+        if last.reused_tokens > 0 {
+            eprintln!("(reused {} cached prompt tokens)", last.reused_tokens);
+        }
+    };
+
+    let mut lines = std::io::stdin().lines();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+        let Some(line) = lines.next().transpose()? else { break };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with('/') {
+            let mut print = reasoning_printer(args.reasoning, think_tags);
+            match &mut agent {
+                Some(agent) => {
+                    let answer = interruptible(&mut opts, |opts| agent.run(&mut conv, model, line, opts, &mut print, &mut report_tool));
+                    if let Err(e) = answer {
+                        eprintln!("error: {e:#}");
+                    }
+                }
+                None => {
+                    interruptible(&mut opts, |opts| conv.send(model, line, opts, &mut print))?;
+                }
+            }
+            report(&conv);
+            continue;
+        }
+
+        let history = &mut conv.history;
+        let (command, rest) = line.split_once(' ').map_or((line, ""), |(c, r)| (c, r.trim()));
+        match (command, rest) {
+            ("/system", "") => println!("{}", history.system.as_deref().unwrap_or("(no system prompt)")),
+            ("/system", text) => history.system = Some(text.to_string()),
+            ("/save", "") | ("/load", "") => eprintln!("{command} needs a path"),
+            ("/save", path) => match history.save(std::path::Path::new(path)) {
+                Ok(()) => eprintln!("saved {} turns to {path}", history.turns.len()),
+                Err(e) => eprintln!("error: {e:#}"),
+            },
+            ("/load", path) => match chat::ChatHistory::load(std::path::Path::new(path)) {
+                Ok(loaded) => {
+                    *history = loaded;
+                    eprintln!("loaded {} turns from {path}", history.turns.len());
+                }
+                Err(e) => eprintln!("error: {e:#}"),
+            },
+            ("/summary", _) => println!("{}", history.summary.as_deref().unwrap_or("(no summary)")),
+            ("/reset", _) => {
+                history.turns.clear();
+                history.summary = None;
+                eprintln!("conversation cleared");
+            }
+            ("/undo", _) => {
+                if !history.undo() {
+                    eprintln!("nothing to undo");
+                }
+            }
+            ("/regen", _) => {
+                if history.turns.iter().all(|m| m.role == chat::Role::Assistant) {
+                    eprintln!("nothing to regenerate");
+                    continue;
+                }
+                // Unseeded samplers reseed from the clock on their own. Greedy
+                // decoding ignores the seed; only XTC makes the reply differ.
+                if let Some(seed) = &mut opts.sampling.seed {
+                    *seed = seed.wrapping_add(1);
+                }
+                let mut print = reasoning_printer(args.reasoning, think_tags);
+                interruptible(&mut opts, |opts| conv.regenerate(model, opts, &mut print))?;
+                report(&conv);
+            }
+            ("/help", _) => eprintln!("{CHAT_HELP}"),
+            _ => eprintln!("unknown command {command}; /help lists them"),
+        }
+    }
+    Ok(())
+}
+
+/// One line on stderr per tool an agent-mode chat ran.
+fn report_tool(run: &ToolRun) {
+    let status = if run.ok { "ok" } else { "failed" };
+    eprintln!("(tool {}({}) {status}: {} bytes)", run.call.name, run.call.arguments, run.output.len());
+}
+
+/// A FIM code model loaded for `complete` and `lsp`.
+fn load_completer(model_path: &str, ctx: usize, opts: complete::CompleteOptions) -> Result<complete::Completer> {
+    let gguf = GgufModelInfo::load(model_path)?;
+    let fim = complete::FimTokens::find(&gguf.vocab)
+        .context("no fill-in-the-middle tokens in the vocab; this needs a FIM-trained code model")?;
+    let tokenizer = tokenizer::PromptTokenizer::for_model(&gguf)?;
+    let mut model = LlamaModel::load(model_path)?;
+    model.load_all_tensors(std::thread::available_parallelism().map_or(4, |n| n.get()))?;
+    let mut stop_tokens = model.declared_stop_tokens();
+    stop_tokens.extend(&fim.stops);
+    Ok(complete::Completer {
+        model: Box::new(model),
+        tokenizer,
+        fim,
+        stop_tokens,
+        // Byte-level BPE code models (Qwen, StarCoder, DeepSeek) take no BOS.
+        bos: gguf.tokenizer_model.as_deref() != Some("gpt2"),
+        ctx,
+        opts,
+    })
+}
+
+/// Fill-in-the-middle completions at a cursor, printed as one JSON line:
+/// `{"file", "cursor", "completions": [{"text", "score", "tokens"}]}`,
+/// best first.
+fn complete_code(args: CompleteArgs) -> Result<()> {
+    let text = if args.stdin {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(&args.file).with_context(|| format!("read {}", args.file))?
+    };
+    let offset = args.cursor.offset(&text).context("--cursor")?;
+    let mut completer = load_completer(&args.model_path, args.ctx, args.opts)?;
+    let fill = complete::Fill {
+        path: &args.file,
+        language: args.language.as_deref(),
+        text: &text,
+        start: offset,
+        end: offset,
+        single_line: completer.opts.single_line,
+    };
+    let t0 = std::time::Instant::now();
+    let n_prompt = completer.prompt(&fill).len();
+    let completions = completer.fill(&fill)?;
+    eprintln!("{n_prompt} prompt tokens, {} completions in {}ms", completions.len(), t0.elapsed().as_millis());
+    let completions: Vec<_> = completions
+        .iter()
+        .map(|c| serde_json::json!({ "text": c.text, "score": c.score, "tokens": c.tokens }))
+        .collect();
+    let cursor = serde_json::json!({ "line": args.cursor.line, "column": args.cursor.column });
+    println!("{}", serde_json::json!({ "file": args.file, "cursor": cursor, "completions": completions }));
+    Ok(())
+}
+
+/// Complete every prompt of a JSONL file with continuous batching and write
+/// one result line per prompt, in the order they finish.
+fn batch_generate(args: BatchArgs) -> Result<()> {
+    let docs = embed::read_jsonl(std::path::Path::new(&args.input))?;
+    anyhow::ensure!(!docs.is_empty(), "{} has no prompts", args.input);
+    let mut model = LlamaModel::load(&args.model_path)?;
+    model.load_all_tensors(std::thread::available_parallelism().map_or(4, |n| n.get()))?;
+    let gguf = GgufModelInfo::load(&args.model_path)?;
+    let tokenizer = tokenizer::PromptTokenizer::for_model(&gguf)?;
+    let mut stop_tokens = model.declared_stop_tokens();
+    let format = match &args.chat_format {
+        Some(flag) => Some(chat::resolve(flag, gguf.chat_template.as_deref())?),
+        None => None,
+    };
+    if let Some(format) = format {
+        stop_tokens = chat::stop_tokens(format, &gguf.vocab, &stop_tokens);
+    }
+    let prompts: Vec<Vec<u32>> = docs
+        .iter()
+        .map(|d| match format {
+            Some(f) => tokenizer.tokenize_bos(&f.render(&[chat::Message::new(chat::Role::User, &d.text)], true)),
+            None => tokenizer.tokenize_bos(&d.text),
+        })
+        .collect();
+    let mut opts = GenerateOptions {
+        max_new: args.max_new,
+        sampling: SamplerConfig { seed: args.seed, ..SamplerConfig::default() },
+        stop_tokens,
+        ..GenerateOptions::default()
+    };
+
+    let file = std::fs::File::create(&args.output).with_context(|| format!("create {}", args.output))?;
+    let mut out = std::io::BufWriter::new(file);
+    let mut texts = vec![String::new(); docs.len()];
+    let (mut written, mut write_result, mut total) = (0, Ok(()), 0);
+    let t0 = std::time::Instant::now();
+    interruptible(&mut opts, |opts| {
+        model.generate_batch(&prompts, opts, args.batch, &gguf.vocab, &mut |i, event| match event {
+            GenerationEvent::Token { id, .. } => texts[i].push_str(&tokenizer.decode(&[id], true)),
+            GenerationEvent::Done { reason, timings, usage } => {
+                let mut row = serde_json::json!({
+                    "index": i,
+                    "text": texts[i].trim(),
+                    "finish_reason": reason.as_str(),
+                    "usage": usage.to_json(),
+                    "timings": {
+                        "prefill_ms": timings.prefill_ms,
+                        "decode_ms": timings.decode_ms,
+                        "decode_tokens_per_second": timings.decode_tps(),
+                    },
+                });
+                if let Some(id) = &docs[i].id {
+                    row["id"] = id.clone().into();
+                }
+                if write_result.is_ok() {
+                    write_result = writeln!(out, "{row}").and_then(|()| out.flush());
+                }
+                written += 1;
+                total += usage.completion_tokens;
+                eprintln!("[{written}/{}] prompt {i}: {} tokens, {}", docs.len(), usage.completion_tokens, reason.as_str());
+            }
+            GenerationEvent::PrefillProgress { .. } | GenerationEvent::PromptProcessed { .. } => {}
+        })
+    })?;
+    write_result.with_context(|| format!("write {}", args.output))?;
+    let secs = t0.elapsed().as_secs_f64();
+    eprintln!("{written} completions, {total} tokens in {secs:.1}s ({:.1} t/s)", total as f64 / secs);
+    Ok(())
+}
+
+/// Run both models over the same prompts and report how far model B's
+/// next-token distributions are from model A's. The models are loaded one
+/// after the other, so only one is resident; A's logits for every position
+/// are kept meanwhile (vocab × 4 bytes per token).
+fn compare_models(args: CompareArgs) -> Result<()> {
+    let prompts = embed::read_jsonl(std::path::Path::new(&args.prompts))?;
+    anyhow::ensure!(!prompts.is_empty(), "{} has no prompts", args.prompts);
+    let (info_a, info_b) = (GgufModelInfo::load(&args.model_a)?, GgufModelInfo::load(&args.model_b)?);
+    anyhow::ensure!(info_a.vocab == info_b.vocab, "the models have different vocabularies; compare needs the same tokens");
+    let tokenizer = tokenizer::PromptTokenizer::for_model(&info_a)?;
+    let tokens: Vec<Vec<u32>> = prompts
+        .iter()
+        .map(|p| tokenizer.tokenize_bos(&p.text).into_iter().take(args.max_tokens.max(1)).collect())
+        .collect();
+
+    eprintln!("Reference: {}", args.model_a);
+    let reference = {
+        let mut model = LlamaModel::load(&args.model_a)?;
+        tokens.iter().map(|t| model.evaluate(t)).collect::<Result<Vec<_>>>()?
+    };
+    eprintln!("Candidate: {}", args.model_b);
+    let mut model = LlamaModel::load(&args.model_b)?;
+    let mut total = quality::LogitAgreement::default();
+    for (i, (t, ref_logits)) in tokens.iter().zip(&reference).enumerate() {
+        let mut prompt = quality::LogitAgreement::default();
+        for (r, c) in ref_logits.iter().zip(model.evaluate(t)?) {
+            prompt.add(r, &c);
+        }
+        let label = prompts[i].id.clone().unwrap_or_else(|| i.to_string());
+        println!(
+            "{label}: {} tokens, mean KL {:.6}, max KL {:.6}, top-1 {:.1}%",
+            prompt.positions,
+            prompt.mean_kl(),
+            prompt.kl_max,
+            100.0 * prompt.top1_agreement()
+        );
+        total.merge(&prompt);
+    }
+    println!(
+        "total: {} tokens, mean KL {:.6}, max KL {:.6}, top-1 agreement {:.2}%",
+        total.positions,
+        total.mean_kl(),
+        total.kl_max,
+        100.0 * total.top1_agreement()
+    );
+    Ok(())
+}
+
+/// Score a multiple-choice task and print `acc` and `acc_norm` with their
+/// standard errors. `--output` keeps every item's choice log-probs, so two
+/// quant levels or backends can be compared item by item.
+fn run_eval(args: EvalArgs) -> Result<()> {
+    let mut items = args.task.load(std::path::Path::new(&args.data))?;
+    items.truncate(args.limit.unwrap_or(usize::MAX));
+    anyhow::ensure!(!items.is_empty(), "{} has no items", args.data);
+    let tokenizer = tokenizer::PromptTokenizer::for_model(&GgufModelInfo::load(&args.model_path)?)?;
+    let mut model = LlamaModel::load(&args.model_path)?;
+    let mut out = match &args.output {
+        Some(path) => Some(std::io::BufWriter::new(std::fs::File::create(path).with_context(|| format!("create {path}"))?)),
+        None => None,
+    };
+    let mut report = eval::Report::default();
+    let t0 = std::time::Instant::now();
+    for (i, item) in items.iter().enumerate() {
+        let scores = eval::score_item(|tokens: &[u32]| model.evaluate(tokens), &tokenizer, item)
+            .with_context(|| format!("item {}", item.id))?;
+        let (pick, pick_norm) = report.add(item, &scores);
+        if let Some(out) = &mut out {
+            let row = serde_json::json!({
+                "id": item.id,
+                "answer": item.answer,
+                "pick": pick,
+                "pick_norm": pick_norm,
+                "logprobs": scores.iter().map(|s| s.logprob).collect::<Vec<_>>(),
+                "bytes": scores.iter().map(|s| s.bytes).collect::<Vec<_>>(),
+            });
+            writeln!(out, "{row}")?;
+        }
+        if (i + 1) % 50 == 0 {
+            let t = &report.total;
+            eprintln!("[{}/{}] acc {:.4}, acc_norm {:.4}, {:.1}s", i + 1, items.len(), t.acc(), t.acc_norm(), t0.elapsed().as_secs_f64());
+        }
+    }
+    if let Some(mut out) = out {
+        out.flush()?;
+    }
+    let line = |name: &str, a: &eval::Accuracy| {
+        println!(
+            "{name}: {} items, acc {:.4} ± {:.4}, acc_norm {:.4} ± {:.4}",
+            a.items,
+            a.acc(),
+            a.stderr(a.acc()),
+            a.acc_norm(),
+            a.stderr(a.acc_norm())
+        );
+    };
+    if args.by_group {
+        for (group, a) in &report.groups {
+            line(&format!("  {}", if group.is_empty() { "(none)" } else { group }), a);
+        }
+    }
+    line(args.task.name(), &report.total);
+    Ok(())
+}
+
+/// Run calibration text through the model in `--chunk`-token pieces (each a
+/// fresh context, as llama.cpp's imatrix tool does) and write the
+/// accumulated importance matrix for `llama-quantize --imatrix`.
+fn collect_imatrix(args: ImatrixArgs) -> Result<()> {
+    let text = std::fs::read_to_string(&args.input)
+        .with_context(|| format!("read {}", args.input))?;
+    let tokenizer = tokenizer::PromptTokenizer::for_model(&GgufModelInfo::load(&args.model_path)?)?;
+    let tokens = tokenizer.tokenize(&text);
+    let chunks: Vec<&[u32]> = tokens.chunks(args.chunk).filter(|c| c.len() == args.chunk).collect();
+    anyhow::ensure!(
+        !chunks.is_empty(),
+        "{} is {} tokens, less than one chunk of {}",
+        args.input,
+        tokens.len(),
+        args.chunk
+    );
+    let mut model = LlamaModel::load(&args.model_path)?;
+    model.enable_imatrix();
+    for (i, chunk) in chunks.iter().enumerate() {
+        // Every chunk starts a sequence, as it would in use.
+        let input: Vec<u32> = std::iter::once(1).chain(chunk[1..].iter().copied()).collect();
+        let t = std::time::Instant::now();
+        model.evaluate(&input)?;
+        model.imatrix_mut().context("imatrix was not enabled")?.end_chunk();
+        eprintln!("chunk {}/{}: {}ms", i + 1, chunks.len(), t.elapsed().as_millis());
+    }
+    let mut imatrix = model.take_imatrix().context("imatrix was not recorded")?;
+    imatrix.dataset = args.input.clone();
+    imatrix.write(std::path::Path::new(&args.output))?;
+    eprintln!(
+        "wrote {} tensors over {} chunks of {} tokens to {}",
+        imatrix.entries.len(),
+        imatrix.chunks,
+        args.chunk,
+        args.output
+    );
+    Ok(())
+}
+
+/// `embed` and `rerank` take decoder and encoder (BERT-family) GGUFs alike.
+#[allow(clippy::large_enum_variant)] // one per process
+enum EmbeddingModel {
+    Decoder(LlamaModel),
+    Encoder(BertModel),
+}
+
+impl EmbeddingModel {
+    fn load(path: &str) -> Result<(Self, tokenizer::PromptTokenizer)> {
+        let info = GgufModelInfo::load(path)?;
+        if bert::is_encoder(&info.family) {
+            Ok((Self::Encoder(BertModel::load(path)?), tokenizer::PromptTokenizer::wordpiece(info.vocab)?))
+        } else {
+            Ok((Self::Decoder(LlamaModel::load(path)?), tokenizer::PromptTokenizer::for_model(&info)?))
+        }
+    }
+
+    /// One sequence's vector; `None` pools as the GGUF says, or mean for
+    /// decoders.
+    fn embed(&mut self, tokens: &[u32], pooling: Option<embed::Pooling>) -> Result<Vec<f32>> {
+        match self {
+            Self::Decoder(m) => m.embed_tokens(tokens, pooling.unwrap_or(embed::Pooling::Mean)),
+            Self::Encoder(m) => m.embed_tokens(tokens, pooling),
+        }
+    }
+}
+
+/// Score every document of a JSONL file against one query and print them
+/// best first, one JSON object per line.
+fn rerank_documents(args: RerankArgs) -> Result<()> {
+    let docs = embed::read_jsonl(std::path::Path::new(&args.input))?;
+    let (mut model, tokenizer) = EmbeddingModel::load(&args.model_path)?;
+    let head = match &model {
+        EmbeddingModel::Decoder(m) => Some(m.rerank_head(&GgufModelInfo::load(&args.model_path)?.vocab)?),
+        EmbeddingModel::Encoder(_) => None,
+    };
+    let kind = match &head {
+        Some(rerank::RerankHead::YesNo { .. }) => "yes/no",
+        Some(rerank::RerankHead::Classifier { .. }) => "classifier",
+        None => "encoder classifier",
+    };
+    eprintln!("{} documents, {kind} head", docs.len());
+
+    let mut scores = Vec::with_capacity(docs.len());
+    for (index, doc) in docs.iter().enumerate() {
+        let logit = match (&mut model, &head) {
+            (EmbeddingModel::Decoder(m), Some(head)) => {
+                let tokens = rerank::pair_tokens(&tokenizer, head, &args.instruction, &args.query, &doc.text);
+                m.rerank_logit(&tokens, head)?
+            }
+            (EmbeddingModel::Encoder(m), _) => {
+                // [CLS] query [SEP] document [SEP]
+                let sep = tokenizer.sep().context("encoder vocab has no [SEP]")?;
+                let mut tokens = tokenizer.tokenize_bos(&args.query);
+                tokens.extend(tokenizer.tokenize(&doc.text));
+                tokens.push(sep);
+                m.rerank_logit(&tokens, sep)?
+            }
+            (EmbeddingModel::Decoder(_), None) => unreachable!(),
+        };
+        scores.push(rerank::RerankScore { index, logit, relevance: rerank::sigmoid(logit) });
+    }
+    let ranked = rerank::rank(scores);
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    for s in ranked.iter().take(args.top.unwrap_or(usize::MAX)) {
+        let mut row = serde_json::json!({ "index": s.index, "relevance": s.relevance, "logit": s.logit });
+        if let Some(id) = &docs[s.index].id {
+            row["id"] = id.clone().into();
+        }
+        writeln!(out, "{row}")?;
+    }
+    Ok(())
+}
+
+/// Classify `--text` or every document of a JSONL file and print one JSON
+/// object per input: the top label and each class's probability.
+fn classify_documents(args: ClassifyArgs) -> Result<()> {
+    let docs = match (&args.text, &args.input) {
+        (Some(text), _) => vec![embed::Document { id: None, text: text.clone() }],
+        (None, Some(path)) => embed::read_jsonl(std::path::Path::new(path))?,
+        (None, None) => unreachable!("ClassifyArgs::parse requires --text or --input-file"),
+    };
+    let (mut model, tokenizer) = EmbeddingModel::load(&args.model_path)?;
+    let head = match &model {
+        EmbeddingModel::Decoder(m) => Some(m.classifier_head()?),
+        EmbeddingModel::Encoder(_) => None,
+    };
+    let labels = match (&model, &head) {
+        (_, Some(head)) => head.labels.clone(),
+        (EmbeddingModel::Encoder(m), None) => m.class_labels(),
+        (EmbeddingModel::Decoder(_), None) => unreachable!(),
+    };
+    anyhow::ensure!(!labels.is_empty(), "not a classifier: no cls.output.weight");
+    eprintln!("{} inputs, {} classes: {}", docs.len(), labels.len(), labels.join(", "));
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    for (index, doc) in docs.iter().enumerate() {
+        let mut tokens = tokenizer.tokenize_bos(&doc.text);
+        tokens.truncate(args.max_tokens);
+        let logits = match (&mut model, &head) {
+            (EmbeddingModel::Decoder(m), Some(head)) => m.class_logits(&tokens, head)?,
+            (EmbeddingModel::Encoder(m), _) => m.class_logits(&tokens)?,
+            (EmbeddingModel::Decoder(_), None) => unreachable!(),
+        };
+        let predictions = classify::predictions(&logits, &labels, args.multi_label);
+        let scores: Vec<_> = predictions
+            .iter()
+            .take(args.top.unwrap_or(usize::MAX))
+            .map(|p| serde_json::json!({ "label": p.label, "probability": p.probability, "logit": p.logit }))
+            .collect();
+        let mut row = serde_json::json!({ "index": index, "label": predictions[0].label, "scores": scores });
+        if let Some(id) = &doc.id {
+            row["id"] = id.clone().into();
+        }
+        writeln!(out, "{row}")?;
+    }
+    Ok(())
+}
+
+/// Resume an interrupted download of `model_path` from `--url` or the model
+/// cache manifest; `--url` is recorded there for next time.
+fn repair_model(model_path: &str, url: Option<String>) -> Result<()> {
+    if let Some(url) = &url {
+        let size = repair::lookup(model_path).filter(|s| &s.url == url).and_then(|s| s.size);
+        repair::record(model_path, repair::Source { url: url.clone(), size })?;
+    }
+    // A header cut short does not parse at all; resuming still fixes it.
+    let before = match repair::check(model_path) {
+        Ok(None) => {
+            eprintln!("{model_path} is complete");
+            return Ok(());
+        }
+        Ok(Some(truncation)) => {
+            eprintln!("{truncation}");
+            Some(truncation)
+        }
+        Err(e) => {
+            eprintln!("{e:#}");
+            None
+        }
+    };
+    let source = url.map(|url| repair::Source { url, size: None }).or_else(|| repair::lookup(model_path));
+    let source = source.with_context(|| {
+        let manifest = repair::manifest_path().map_or_else(|| "models.json".into(), |p| p.display().to_string());
+        format!("no source for {model_path} in {manifest}; pass --url URL")
+    })?;
+    eprintln!("Resuming from {}", source.url);
+    repair::resume(model_path, &source.url)?;
+    match repair::check(model_path)? {
+        Some(truncation) => bail!("still incomplete after resuming: {truncation}"),
+        None => {
+            let len = std::fs::metadata(model_path)?.len();
+            let fetched = before.map_or(String::new(), |t| format!(", {} bytes fetched", len - t.file_len));
+            eprintln!("{model_path} is complete: {len} bytes{fetched}");
+            Ok(())
+        }
+    }
+}
+
+/// Score texts for `run --watermark`'s green-list watermark. Only the
+/// tokenizer is loaded.
+fn detect_watermark(args: DetectWatermarkArgs) -> Result<()> {
+    let docs = match (&args.text, &args.input) {
+        (Some(text), _) => vec![embed::Document { id: None, text: text.clone() }],
+        (None, Some(path)) => embed::read_jsonl(std::path::Path::new(path))?,
+        (None, None) => unreachable!("DetectWatermarkArgs::parse requires --text or --input-file"),
+    };
+    let gguf = GgufModelInfo::load(&args.model_path)?;
+    let tokenizer = tokenizer::PromptTokenizer::for_model(&gguf)?;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    for (index, doc) in docs.iter().enumerate() {
+        let found = args.watermark.detect(&tokenizer.tokenize(&doc.text));
+        let mut row = serde_json::json!({
+            "index": index,
+            "scored": found.scored,
+            "green": found.green,
+            "z": found.z,
+            "watermarked": found.z >= args.threshold,
+        });
+        if let Some(id) = &doc.id {
+            row["id"] = id.clone().into();
+        }
+        writeln!(out, "{row}")?;
+    }
+    Ok(())
+}
+
+/// Embed every document of a JSONL file. While the GPU embeds one micro-batch,
+/// a second thread tokenizes the next.
+fn embed_documents(args: EmbedArgs) -> Result<()> {
+    let out_path = std::path::Path::new(&args.output);
+    let format = match out_path.extension().and_then(|e| e.to_str()) {
+        Some(ext @ ("npy" | "jsonl" | "idx")) => ext,
+        Some("parquet") => bail!("parquet output is not supported yet; write .npy, .jsonl or .idx"),
+        _ => bail!("--output must end in .npy, .jsonl or .idx"),
+    };
+    let docs = embed::read_jsonl(std::path::Path::new(&args.input))?;
+    eprintln!("{} documents", docs.len());
+
+    let (mut model, tokenizer) = EmbeddingModel::load(&args.model_path)?;
+    let texts: Vec<&str> = docs.iter().map(|d| d.text.as_str()).collect();
+    let batches: Vec<&[&str]> = texts.chunks(args.batch.max(1)).collect();
+    let tokenize = |batch: &[&str]| embed::tokenize_parallel(&tokenizer, batch, args.threads, args.max_tokens);
+
+    let t0 = std::time::Instant::now();
+    let mut rows = Vec::with_capacity(docs.len());
+    let mut next = batches.first().map(|b| tokenize(b));
+    for i in 0..batches.len() {
+        let current = next.take().unwrap_or_default();
+        next = std::thread::scope(|s| -> Result<_> {
+            let pending = batches.get(i + 1).map(|b| s.spawn(|| tokenize(b)));
+            for tokens in &current {
+                rows.push(model.embed(tokens, args.pooling)?);
+            }
+            Ok(pending.map(|h| h.join().unwrap()))
+        })?;
+        eprintln!("embedded {}/{}", rows.len(), docs.len());
+    }
+    eprintln!("{} embeddings in {}ms", rows.len(), t0.elapsed().as_millis());
+
+    match format {
+        "npy" => embed::write_npy(out_path, &rows),
+        "jsonl" => embed::write_jsonl(out_path, &docs, &rows),
+        _ => {
+            let mut index = search::VectorIndex::new(docs, &rows)?;
+            index.pooling = args.pooling;
+            index.max_tokens = Some(args.max_tokens);
+            index.write(out_path)
+        }
+    }
+}
+
+/// Embed the query like the index's documents were and print the nearest
+/// ones, best first, one JSON object per line.
+fn search_index(args: SearchArgs) -> Result<()> {
+    let t0 = std::time::Instant::now();
+    let mut index = search::VectorIndex::load(std::path::Path::new(&args.index))?;
+    if let Some(m) = args.hnsw {
+        index.build_hnsw(m, args.ef);
+    }
+    eprintln!("{} documents of dimension {} in {}ms", index.len(), index.dim, t0.elapsed().as_millis());
+
+    let (mut model, tokenizer) = EmbeddingModel::load(&args.model_path)?;
+    let mut tokens = tokenizer.tokenize_bos(&args.query);
+    tokens.truncate(index.max_tokens.unwrap_or(512));
+    let query = model.embed(&tokens, index.pooling)?;
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    for hit in index.search(&query, args.top)? {
+        let doc = &index.docs[hit.index];
+        let mut row = serde_json::json!({ "index": hit.index, "score": hit.score });
+        if let Some(id) = &doc.id {
+            row["id"] = id.clone().into();
+        }
+        if !doc.text.is_empty() {
+            row["text"] = doc.text.clone().into();
+        }
+        writeln!(out, "{row}")?;
+    }
+    Ok(())
+}
+
+/// Chunk and embed the documents (or load a prebuilt index), retrieve the
+/// chunks nearest the question, and answer it from a prompt holding them.
+/// The embedding model is dropped before the generating one loads.
+fn rag_answer(args: RagArgs) -> Result<()> {
+    let t0 = std::time::Instant::now();
+    let embed_path = args.embed_model.as_deref().unwrap_or(&args.model_path);
+    let (mut embedder, embed_tokenizer) = EmbeddingModel::load(embed_path)?;
+    let index = match (&args.index, &args.input) {
+        (Some(path), _) => search::VectorIndex::load(std::path::Path::new(path))?,
+        (None, Some(path)) => {
+            let docs = embed::read_jsonl(std::path::Path::new(path))?;
+            let chunks = rag::chunk_documents(&docs, args.chunk, args.overlap);
+            eprintln!("{} documents in {} chunks", docs.len(), chunks.len());
+            let texts: Vec<&str> = chunks.iter().map(|c| c.text.as_str()).collect();
+            let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+            let tokens = embed::tokenize_parallel(&embed_tokenizer, &texts, threads, args.max_tokens);
+            let rows = tokens.iter().map(|t| embedder.embed(t, None)).collect::<Result<Vec<_>>>()?;
+            let mut index = search::VectorIndex::new(chunks, &rows)?;
+            index.max_tokens = Some(args.max_tokens);
+            if let Some(path) = &args.save_index {
+                index.write(std::path::Path::new(path))?;
+                eprintln!("wrote the index to {path}");
+            }
+            index
+        }
+        (None, None) => unreachable!("RagArgs::parse requires --index or --input-file"),
+    };
+    let mut query = embed_tokenizer.tokenize_bos(&args.query);
+    query.truncate(index.max_tokens.unwrap_or(args.max_tokens));
+    let hits = index.search(&embedder.embed(&query, index.pooling)?, args.top)?;
+    drop(embedder);
+    for hit in &hits {
+        let id = index.docs[hit.index].id.clone().unwrap_or_else(|| hit.index.to_string());
+        eprintln!("retrieved {id} ({:.3})", hit.score);
+    }
+    eprintln!("retrieval: {}ms", t0.elapsed().as_millis());
+
+    let template = match &args.template {
+        Some(path) => std::fs::read_to_string(path).with_context(|| format!("read {path}"))?,
+        None => rag::DEFAULT_TEMPLATE.to_string(),
+    };
+    let prompt = rag::render_prompt(&template, &args.query, &index.docs, &hits);
+    let gguf = GgufModelInfo::load(&args.model_path)?;
+    let tokenizer = tokenizer::PromptTokenizer::for_model(&gguf)?;
+    let mut model = LlamaModel::load(&args.model_path)?;
+    model.load_all_tensors(std::thread::available_parallelism().map_or(4, |n| n.get()))?;
+    let mut stop_tokens = model.declared_stop_tokens();
+    let prompt = match args.chat_format.as_deref() {
+        Some("none") => prompt,
+        flag => {
+            let format = chat::resolve(flag.unwrap_or("auto"), gguf.chat_template.as_deref())?;
+            stop_tokens = chat::stop_tokens(format, &gguf.vocab, &stop_tokens);
+            format.render(&[chat::Message::new(chat::Role::User, &prompt)], true)
+        }
+    };
+    let mut opts = GenerateOptions {
+        max_new: args.max_new,
+        sampling: SamplerConfig { seed: args.seed, ..SamplerConfig::default() },
+        stop_tokens,
+        ..GenerateOptions::default()
+    };
+    let tokens = tokenizer.tokenize_bos(&prompt);
+    interruptible(&mut opts, |opts| model.generate(&tokens, opts, &gguf.vocab, &mut print_event))
+}
+
+/// A Conventional Commits message for the diff piped to stdin, or for
+/// `git diff --staged` when stdin is a terminal. The first reply is greedy;
+/// one without a valid header is sampled again, up to `--attempts` times.
+fn commit_message(args: CommitMsgArgs) -> Result<()> {
+    let diff = if std::io::stdin().is_terminal() {
+        let out = std::process::Command::new("git")
+            .args(["diff", "--staged", "--no-color", "--no-ext-diff"])
+            .output()
+            .context("running git diff --staged")?;
+        anyhow::ensure!(out.status.success(), "git diff --staged failed: {}", String::from_utf8_lossy(&out.stderr).trim());
+        String::from_utf8_lossy(&out.stdout).into_owned()
+    } else {
+        std::io::read_to_string(std::io::stdin())?
+    };
+    anyhow::ensure!(!diff.trim().is_empty(), "nothing staged: the diff is empty");
+    let summary = commit_msg::summarize(&diff, args.max_diff);
+    eprintln!("{} files changed", summary.files.len());
+
+    let gguf = GgufModelInfo::load(&args.model_path)?;
+    let tokenizer = tokenizer::PromptTokenizer::for_model(&gguf)?;
+    let format = chat::resolve(args.chat_format.as_deref().unwrap_or("auto"), gguf.chat_template.as_deref())?;
+    let mut model = LlamaModel::load(&args.model_path)?;
+    model.load_all_tensors(std::thread::available_parallelism().map_or(4, |n| n.get()))?;
+    let prompt = commit_msg::prompt(&summary, args.hint.as_deref());
+    let tokens = tokenizer.tokenize_bos(&format.render(&[chat::Message::new(chat::Role::User, &prompt)], true));
+    let stop_tokens = chat::stop_tokens(format, &gguf.vocab, &model.declared_stop_tokens());
+    let mut session = model::KvSession::default();
+    let mut first = None;
+    for attempt in 0..args.attempts.max(1) {
+        let seed = args.seed.unwrap_or(0).wrapping_add(attempt as u64);
+        let temperature = if attempt == 0 { 0.0 } else { 0.7 };
+        let mut opts = GenerateOptions {
+            max_new: args.max_new,
+            sampling: SamplerConfig { seed: Some(seed), temperature, ..SamplerConfig::default() },
+            stop_tokens: stop_tokens.clone(),
+            ..GenerateOptions::default()
+        };
+        let mut ids = Vec::new();
+        interruptible(&mut opts, |opts| {
+            model.generate_cached(&mut session, &tokens, opts, &gguf.vocab, &mut |event| {
+                if let GenerationEvent::Token { id, .. } = event {
+                    ids.push(id);
+                }
+            })
+        })?;
+        let reply = tokenizer.decode(&ids, true);
+        if let Some(message) = commit_msg::clean(&reply) {
+            println!("{message}");
+            return Ok(());
+        }
+        first.get_or_insert(reply);
+    }
+    eprintln!("warning: no reply was a Conventional Commits message; printing the first as written");
+    println!("{}", first.unwrap_or_default().trim());
+    Ok(())
+}
+
+/// Raised by the first ctrl-C during generation; see `install_interrupt_handler`.
+static CANCEL: std::sync::OnceLock<Arc<AtomicBool>> = std::sync::OnceLock::new();
+/// Whether a generation is running that ctrl-C should stop rather than exit.
+static GENERATING: AtomicBool = AtomicBool::new(false);
+
+/// ctrl-C during generation stops it at the next token: the reply so far,
+/// its timings and any chat session survive. A second ctrl-C before it has
+/// stopped, or one while not generating, exits at once.
+fn install_interrupt_handler() {
+    unsafe extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+        fn _exit(status: i32) -> !;
+    }
+    const SIGINT: i32 = 2;
+    extern "C" fn on_sigint(_: i32) {
+        // Atomics only: this runs inside the signal handler.
+        match CANCEL.get() {
+            Some(cancel) if GENERATING.load(Ordering::SeqCst) && !cancel.swap(true, Ordering::SeqCst) => {}
+            _ => unsafe { _exit(130) },
+        }
+    }
+    CANCEL.get_or_init(|| Arc::new(AtomicBool::new(false)));
+    unsafe { signal(SIGINT, on_sigint) };
+}
+
+/// Run one generation that ctrl-C can stop; `opts.cancel` gets the flag.
+fn interruptible<T>(opts: &mut GenerateOptions, generate: impl FnOnce(&GenerateOptions) -> T) -> T {
+    let cancel = CANCEL.get_or_init(|| Arc::new(AtomicBool::new(false)));
+    cancel.store(false, Ordering::SeqCst);
+    opts.cancel = Some(cancel.clone());
+    GENERATING.store(true, Ordering::SeqCst);
+    let out = generate(opts);
+    GENERATING.store(false, Ordering::SeqCst);
+    out
+}
+
+/// The thinking tags, when `--reasoning` or `--max-thinking` needs them.
+fn think_tokens(mode: ReasoningMode, max_thinking: Option<usize>, vocab: &[String]) -> Result<Option<ThinkTokens>> {
+    if mode == ReasoningMode::Show && max_thinking.is_none() {
+        return Ok(None);
+    }
+    match ThinkTokens::find(vocab) {
+        Some(tags) => Ok(Some(tags)),
+        None => bail!("--reasoning and --max-thinking need {} and {} tokens in the vocab", reasoning::OPEN, reasoning::CLOSE),
+    }
+}
+
+/// `print_event` for one reply, with a reasoning model's thinking printed
+/// as `mode` says: on stderr for `Separate`, nowhere for `Hide`.
+fn reasoning_printer(mode: ReasoningMode, tags: Option<ThinkTokens>) -> impl FnMut(GenerationEvent) {
+    let mut think = tags.filter(|_| mode != ReasoningMode::Show).map(|t| ThinkStream::new(t, &[]));
+    move |event| match (event, think.as_mut()) {
+        (GenerationEvent::Token { id, text, logprob }, Some(think)) => match think.route(id) {
+            Segment::Tag => {}
+            Segment::Reasoning if mode == ReasoningMode::Hide => {}
+            Segment::Reasoning => eprint!("{text}"),
+            Segment::Content => print_event(GenerationEvent::Token { id, text, logprob }),
+        },
+        (event, _) => print_event(event),
+    }
+}
+
+/// Generated text goes to stdout as it arrives; prefill/decode stats to stderr.
+fn print_event(event: GenerationEvent) {
+    match event {
+        GenerationEvent::PrefillProgress { done, total, eta_ms, .. } => {
+            if std::io::stderr().is_terminal() {
+                eprint!("\r{}", progress_bar(done, total, eta_ms));
+            }
+        }
+        GenerationEvent::PromptProcessed { n_tokens, ms } => {
+            if std::io::stderr().is_terminal() {
+                // Clear a progress bar left on this line.
+                eprint!("\r\x1b[K");
+            }
             eprintln!(
-                "Architecture: {} layers, {} hidden, {} heads, {} kv-heads",
-                model.arch.n_layers, model.arch.hidden, model.arch.n_heads, model.arch.n_kv_heads
+                "prefill: {n_tokens} tokens in {ms}ms  ({:.1} t/s)",
+                n_tokens as f64 / (ms as f64 / 1000.0)
             );
-
-            eprintln!("Loading vocabulary...");
-            let gguf = GgufModelInfo::load(&model_path)?;
-            let vocab = gguf.vocab;
-            let tokenizer = tokenizer::PromptTokenizer::new(vocab.clone());
-
-            eprintln!("Tokenizing prompt...");
-            let token_ids = tokenizer.tokenize_bos(&prompt);
-            eprintln!("  {} tokens", token_ids.len());
-
-            eprintln!("\n--- generation ---");
-            let mut model = model;
-            model.generate(&token_ids, max_new, &vocab)?;
+        }
+        GenerationEvent::Token { text, .. } => {
+            print!("{text}");
+            let _ = std::io::stdout().flush();
+        }
+        GenerationEvent::Done { reason: FinishReason::Cancelled, timings, .. } => {
+            println!();
+            eprintln!("interrupted\n{}", timings.report());
+        }
+        GenerationEvent::Done { timings, .. } => {
+            println!();
+            if timings.decode_tokens > 0 {
+                eprintln!(
+                    "decode:  {} tokens in {}ms  ({:.1} t/s)",
+                    timings.decode_tokens,
+                    timings.decode_ms,
+                    timings.decode_tps()
+                );
+            }
         }
     }
+}
 
-    Ok(())
+/// `prefill [=========>          ] 1024/2048 tokens, 3.2s left`.
+fn progress_bar(done: usize, total: usize, eta_ms: u128) -> String {
+    const WIDTH: usize = 30;
+    let filled = (done * WIDTH / total.max(1)).min(WIDTH);
+    let bar = match filled {
+        WIDTH => "=".repeat(WIDTH),
+        n => format!("{}>{}", "=".repeat(n), " ".repeat(WIDTH - n - 1)),
+    };
+    format!("prefill [{bar}] {done}/{total} tokens, {:.1}s left", eta_ms as f64 / 1000.0)
 }
 
 enum Command {
     Inspect { model_path: String },
     Trace { model_path: String, prompt: String },
-    Run { model_path: String, prompt: String, max_new: usize },
+    Audit { model_path: String },
+    Repair { model_path: String, url: Option<String> },
+    Sysinfo,
+    Embed(EmbedArgs),
+    Rerank(RerankArgs),
+    Classify(ClassifyArgs),
+    DetectWatermark(DetectWatermarkArgs),
+    Search(SearchArgs),
+    Rag(RagArgs),
+    Chat(ChatArgs),
+    Complete(CompleteArgs),
+    Lsp(LspArgs),
+    CommitMsg(CommitMsgArgs),
+    Compare(CompareArgs),
+    Eval(EvalArgs),
+    Imatrix(ImatrixArgs),
+    Batch(BatchArgs),
+    Daemon { model_path: String, socket: Option<String>, limits: daemon::Limits, warmup: bool },
+    Worker { listen: String },
+    Dump { model_path: String, out_dir: String, prompt: String },
+    DumpDiff { a: String, b: String, tol: f32 },
+    LogitDiff(LogitDiffArgs),
+    Seal { input: String, output: String },
+    Sign { model_path: String, key: String, manifest_path: Option<String> },
+    Run(Box<RunArgs>),
+}
+
+struct RunArgs {
+    model_path: String,
+    /// The words on the command line, if any.
+    prompt: Option<String>,
+    max_new: usize,
+    cfg_negative: Option<String>,
+    cfg_scale: f32,
+    beams: Option<model::BeamConfig>,
+    rope_scale: Option<f32>,
+    rope_base: Option<f32>,
+    yarn: bool,
+    sampling: SamplerConfig,
+    draft: Option<DraftSource>,
+    /// `--fixed-draft`: always draft the full `n_draft`.
+    fixed_draft: bool,
+    load_threads: usize,
+    repack_cache: bool,
+    /// `--fuse-weights`: one matmul for Q/K/V and one for gate/up per block.
+    fuse_weights: bool,
+    /// `--int8-activations`: CPU blocks dot Q8_0 weights in integers.
+    int8_activations: bool,
+    /// `--warmup`: fault in every page and run a throwaway pass after load.
+    warmup: bool,
+    /// `--autotune`: search threadgroup widths for untuned matmul shapes.
+    autotune: bool,
+    /// `--prefill-chunk N`: prompt tokens per prefill pass.
+    prefill_chunk: Option<usize>,
+    /// `--accelerate N`: F32/F16 matmuls of N or more rows on Accelerate.
+    accelerate: Option<usize>,
+    /// `--gpu-layers N`: blocks past the first N multiply on the CPU.
+    gpu_layers: Option<usize>,
+    /// `--devices 0,1`: matmul weights split across these Metal devices.
+    devices: Option<Vec<usize>>,
+    /// `--pipeline 0,1`: runs of blocks on these Metal devices, in order.
+    pipeline: Option<Vec<usize>>,
+    /// `--rpc HOST:PORT,...`: `llmetal worker`s running the last blocks.
+    rpc: Vec<String>,
+    /// `--micro-batch N`: prompt rows handed between pipeline stages at once.
+    micro_batch: usize,
+    /// `--chat-format NAME|auto`: wrap the prompt as one user turn.
+    chat_format: Option<String>,
+    /// `--special`: print special tokens as their text instead of hiding them.
+    special: bool,
+    /// `--metal-capture FILE.gputrace`: trace one decode step and signpost
+    /// every kernel.
+    metal_capture: Option<String>,
+    /// `--ssm-state FILE`: Mamba and Jamba only. Continue from the
+    /// recurrent state saved in FILE, if there is one, and save the new one there.
+    ssm_state: Option<String>,
+    /// `--timings`: print the per-stage breakdown after generation.
+    timings: bool,
+    /// `--profile`: timestamp every kernel and print GPU time by layer and op.
+    profile: bool,
+    /// `--prompt-file PATH`: read the prompt from a file instead of the words.
+    prompt_file: Option<String>,
+    /// `--raw`: the prompt exactly as given, never wrapped in a chat template.
+    raw: bool,
+    /// `--audio FILE.wav`: transcribe it with `--audio-model` and use the
+    /// transcript as the prompt, or after the prompt when there is one.
+    audio: Option<String>,
+    /// `--audio-model WHISPER.gguf`.
+    audio_model: Option<String>,
+    /// `--audio-language CODE`: skip language detection.
+    audio_language: Option<String>,
+    /// `--tts-command CMD`: stream the reply to CMD's stdin a sentence per
+    /// line, for a text-to-speech engine.
+    tts_command: Option<String>,
+    /// `--no-bos`: do not prepend the BOS token.
+    no_bos: bool,
+    /// `--json-output`: one JSON object on stdout once done, instead of the
+    /// streamed text.
+    json_output: bool,
+    /// `--local`: load the model here even when a daemon serves it.
+    local: bool,
+    /// `--verify-signature KEY`: the signer's public key (hex, or a file
+    /// holding it); the manifest must verify before the model loads.
+    verify_signature: Option<String>,
+    /// `--manifest PATH`: instead of `<model>.manifest.json`.
+    manifest: Option<String>,
+    /// `--medusa FILE`: a companion GGUF of Medusa heads to draft from.
+    medusa: Option<String>,
+    /// `--choice TEXT`, repeated: answer with exactly one of these.
+    choices: Vec<String>,
+    /// `--regex PATTERN`: the reply is one whole match of the pattern.
+    regex: Option<String>,
+    /// `--watermark KEY`, with `--watermark-gamma` / `--watermark-delta`.
+    watermark: Option<watermark::Watermark>,
+    /// `--ctx N`: the context budget, prompt plus reply; the model's
+    /// trained context by default.
+    ctx: Option<usize>,
+    /// `--loop-guard ACTION`: what to do when the output starts looping.
+    loop_action: Option<String>,
+    /// `--reasoning show|hide|separate`: where a reasoning model's thinking goes.
+    reasoning: Option<String>,
+    /// `--max-thinking N`, or `--reasoning-effort`'s budget.
+    max_thinking: Option<usize>,
+    reasoning_effort: Option<String>,
+    /// `--loop-period`, `--loop-repeats` and `--loop-min-tokens`.
+    loop_guard: LoopGuard,
+}
+
+struct EmbedArgs {
+    model_path: String,
+    input: String,
+    output: String,
+    batch: usize,
+    threads: usize,
+    max_tokens: usize,
+    /// `None`: the encoder GGUF's own pooling, or mean for decoders.
+    pooling: Option<embed::Pooling>,
+}
+
+impl EmbedArgs {
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut input = None;
+        let mut output = None;
+        let mut out = Self {
+            model_path,
+            input: String::new(),
+            output: String::new(),
+            batch: 32,
+            threads: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            max_tokens: 512,
+            pooling: None,
+        };
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--input-file" => input = Some(value()?),
+                "--output" => output = Some(value()?),
+                "--batch" => out.batch = value()?.parse().context("--batch")?,
+                "--threads" => out.threads = value()?.parse().context("--threads")?,
+                "--max-tokens" => out.max_tokens = value()?.parse().context("--max-tokens")?,
+                "--pooling" => out.pooling = Some(value()?.parse()?),
+                _ => bail!("unknown embed flag: {flag}"),
+            }
+        }
+        out.input = input.context("embed needs --input-file")?;
+        out.output = output.context("embed needs --output")?;
+        Ok(out)
+    }
+}
+
+struct ChatArgs {
+    model_path: String,
+    system: Option<String>,
+    chat_format: String,
+    max_new: usize,
+    /// Prompt budget in tokens; the model's trained context by default.
+    ctx: Option<usize>,
+    /// Summarize turns that no longer fit rather than dropping them.
+    summarize: bool,
+    /// `--summarize-after N`: also summarize once the history passes N tokens.
+    summarize_after: Option<usize>,
+    seed: Option<u64>,
+    xtc: Option<XtcConfig>,
+    /// Encode special-token text the user types as the tokens themselves.
+    parse_special: bool,
+    /// Print special tokens in replies instead of hiding them.
+    special: bool,
+    /// Load the model here even when a daemon serves it.
+    local: bool,
+    /// `--warmup`, as for `run`; only for a model loaded here.
+    warmup: bool,
+    /// `--prefill-chunk N`, as for `run`; only for a model loaded here.
+    prefill_chunk: Option<usize>,
+    /// `--reasoning`, `--max-thinking` and `--reasoning-effort`, as for `run`.
+    reasoning: ReasoningMode,
+    max_thinking: Option<usize>,
+    /// The daemon session to chat in, so the conversation's KV cache
+    /// survives other clients' requests.
+    session: Option<String>,
+    /// The signer's public key; see `RunArgs::verify_signature`.
+    verify_signature: Option<String>,
+    manifest: Option<String>,
+    /// `--tools FILE`: agent mode, with the subprocess tools `FILE` lists
+    /// (`agent::Agent::load_tools`).
+    tools: Option<String>,
+    /// `--mcp CMD` (repeatable) and `--mcp-config FILE`: MCP servers whose
+    /// tools join agent mode.
+    mcp: Vec<String>,
+    mcp_config: Option<String>,
+    /// `--max-tool-rounds N`: assistant turns per message before giving up.
+    max_tool_rounds: usize,
+    /// `--tool-timeout SECS` and `--tool-dir DIR`: the tools' `agent::Sandbox`.
+    tool_timeout: Option<u64>,
+    tool_dir: Option<String>,
+}
+
+impl ChatArgs {
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut out = Self {
+            model_path,
+            system: None,
+            chat_format: "auto".into(),
+            max_new: 256,
+            ctx: None,
+            summarize: false,
+            summarize_after: None,
+            seed: None,
+            xtc: None,
+            parse_special: false,
+            special: false,
+            local: false,
+            warmup: false,
+            prefill_chunk: None,
+            reasoning: ReasoningMode::Show,
+            max_thinking: None,
+            session: None,
+            verify_signature: None,
+            manifest: None,
+            tools: None,
+            mcp: Vec::new(),
+            mcp_config: None,
+            max_tool_rounds: 8,
+            tool_timeout: None,
+            tool_dir: None,
+        };
+        let mut xtc = XtcConfig { probability: 0.0, threshold: 0.1 };
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--system" => out.system = Some(value()?),
+                "--chat-format" => out.chat_format = value()?,
+                "--max" => out.max_new = value()?.parse().context("--max")?,
+                "--ctx" => out.ctx = Some(value()?.parse().context("--ctx")?),
+                "--summarize" => out.summarize = true,
+                "--summarize-after" => out.summarize_after = Some(value()?.parse().context("--summarize-after")?),
+                "--seed" => out.seed = Some(value()?.parse().context("--seed")?),
+                "--xtc-probability" => xtc.probability = value()?.parse().context("--xtc-probability")?,
+                "--xtc-threshold" => xtc.threshold = value()?.parse().context("--xtc-threshold")?,
+                "--parse-special" => out.parse_special = true,
+                "--special" => out.special = true,
+                "--local" => out.local = true,
+                "--warmup" => out.warmup = true,
+                "--prefill-chunk" => out.prefill_chunk = Some(value()?.parse().context("--prefill-chunk")?),
+                "--reasoning" => out.reasoning = value()?.parse().context("--reasoning")?,
+                "--max-thinking" => out.max_thinking = Some(value()?.parse().context("--max-thinking")?),
+                "--reasoning-effort" => out.max_thinking = Some(reasoning::effort_budget(&value()?).context("--reasoning-effort")?),
+                "--session" => out.session = Some(value()?),
+                "--verify-signature" => out.verify_signature = Some(value()?),
+                "--manifest" => out.manifest = Some(value()?),
+                "--tools" => out.tools = Some(value()?),
+                "--mcp" => out.mcp.push(value()?),
+                "--mcp-config" => out.mcp_config = Some(value()?),
+                "--max-tool-rounds" => out.max_tool_rounds = value()?.parse().context("--max-tool-rounds")?,
+                "--tool-timeout" => out.tool_timeout = Some(value()?.parse().context("--tool-timeout")?),
+                "--tool-dir" => out.tool_dir = Some(value()?),
+                _ => bail!("unknown chat flag: {flag}"),
+            }
+        }
+        out.xtc = (xtc.probability > 0.0).then_some(xtc);
+        Ok(out)
+    }
+}
+
+struct CompleteArgs {
+    model_path: String,
+    /// The file being edited; it names the file in the prompt.
+    file: String,
+    /// `--stdin`: read the text from stdin, e.g. an unsaved editor buffer.
+    stdin: bool,
+    cursor: complete::Cursor,
+    /// `--language`; by default from the file's extension.
+    language: Option<String>,
+    /// Prompt budget in tokens.
+    ctx: usize,
+    opts: complete::CompleteOptions,
+}
+
+impl CompleteArgs {
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let (mut file, mut cursor) = (None, None);
+        let mut out = Self {
+            model_path,
+            file: String::new(),
+            stdin: false,
+            cursor: complete::Cursor { line: 1, column: 1 },
+            language: None,
+            ctx: 2048,
+            opts: complete::CompleteOptions::default(),
+        };
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--file" => file = Some(value()?),
+                "--stdin" => out.stdin = true,
+                "--cursor" => cursor = Some(value()?.parse().context("--cursor")?),
+                "--language" => out.language = Some(value()?),
+                "--ctx" => out.ctx = value()?.parse().context("--ctx")?,
+                "-n" | "--candidates" => out.opts.n = value()?.parse().context("--candidates")?,
+                "--max" => out.opts.max_new = value()?.parse().context("--max")?,
+                "--single-line" => out.opts.single_line = true,
+                "--temp" => out.opts.temperature = value()?.parse().context("--temp")?,
+                "--seed" => out.opts.seed = value()?.parse().context("--seed")?,
+                _ => bail!("unknown complete flag: {flag}"),
+            }
+        }
+        out.file = file.context("complete needs --file PATH")?;
+        out.cursor = cursor.context("complete needs --cursor LINE:COLUMN")?;
+        Ok(out)
+    }
+}
+
+/// `llmetal lsp`: the model and completion settings, as for `complete`.
+struct LspArgs {
+    model_path: String,
+    ctx: usize,
+    opts: complete::CompleteOptions,
+}
+
+impl LspArgs {
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut out = Self { model_path, ctx: 2048, opts: complete::CompleteOptions::default() };
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--ctx" => out.ctx = value()?.parse().context("--ctx")?,
+                "-n" | "--candidates" => out.opts.n = value()?.parse().context("--candidates")?,
+                "--max" => out.opts.max_new = value()?.parse().context("--max")?,
+                "--temp" => out.opts.temperature = value()?.parse().context("--temp")?,
+                "--seed" => out.opts.seed = value()?.parse().context("--seed")?,
+                _ => bail!("unknown lsp flag: {flag}"),
+            }
+        }
+        Ok(out)
+    }
+}
+
+struct CommitMsgArgs {
+    model_path: String,
+    /// `--hint TEXT`: what the change is for, in the author's words.
+    hint: Option<String>,
+    /// Characters of diff the prompt may quote.
+    max_diff: usize,
+    chat_format: Option<String>,
+    max_new: usize,
+    seed: Option<u64>,
+    /// Replies to sample before settling for one that is not a
+    /// Conventional Commits message.
+    attempts: usize,
+}
+
+impl CommitMsgArgs {
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut out =
+            Self { model_path, hint: None, max_diff: 12_000, chat_format: None, max_new: 160, seed: None, attempts: 3 };
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--hint" => out.hint = Some(value()?),
+                "--max-diff" => out.max_diff = value()?.parse().context("--max-diff")?,
+                "--chat-format" => out.chat_format = Some(value()?),
+                "--max" => out.max_new = value()?.parse().context("--max")?,
+                "--seed" => out.seed = Some(value()?.parse().context("--seed")?),
+                "--attempts" => out.attempts = value()?.parse().context("--attempts")?,
+                _ => bail!("unknown commit-msg flag: {flag}"),
+            }
+        }
+        Ok(out)
+    }
+}
+
+struct LogitDiffArgs {
+    model_path: String,
+    /// llama.cpp's logits: a dump directory, a `.txt`, or raw f32.
+    reference: String,
+    prompt: String,
+    /// Token ids to evaluate instead of tokenizing `prompt`, so both sides
+    /// are certain to see the same sequence.
+    tokens: Option<Vec<u32>>,
+    /// Largest absolute logit difference that still counts as parity.
+    tol: f32,
+    /// Relative difference at which an intermediate tensor counts as the
+    /// first to diverge, as in `dump-diff`.
+    layer_tol: f32,
+}
+
+impl LogitDiffArgs {
+    fn parse(model_path: String, reference: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut out = Self { model_path, reference, prompt: "Hello".into(), tokens: None, tol: 1e-2, layer_tol: 1e-3 };
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--prompt" => out.prompt = value()?,
+                "--tokens" => {
+                    let ids = value()?;
+                    let ids = ids.split([',', ' ']).filter(|s| !s.is_empty()).map(str::parse).collect::<Result<Vec<u32>, _>>();
+                    out.tokens = Some(ids.context("--tokens takes comma-separated token ids")?);
+                }
+                "--tol" => out.tol = value()?.parse().context("--tol")?,
+                "--layer-tol" => out.layer_tol = value()?.parse().context("--layer-tol")?,
+                _ => bail!("unknown logit-diff flag: {flag}"),
+            }
+        }
+        Ok(out)
+    }
+}
+
+struct CompareArgs {
+    /// The reference, usually the F16 or Q8_0 file.
+    model_a: String,
+    model_b: String,
+    prompts: String,
+    max_tokens: usize,
+}
+
+impl CompareArgs {
+    fn parse(model_a: String, model_b: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut prompts = None;
+        let mut max_tokens = 512;
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--prompts" => prompts = Some(value()?),
+                "--max-tokens" => max_tokens = value()?.parse().context("--max-tokens")?,
+                _ => bail!("unknown compare flag: {flag}"),
+            }
+        }
+        let prompts = prompts.context("compare needs --prompts FILE")?;
+        Ok(Self { model_a, model_b, prompts, max_tokens })
+    }
+}
+
+struct EvalArgs {
+    model_path: String,
+    task: eval::Task,
+    data: String,
+    /// Score only the first N items.
+    limit: Option<usize>,
+    /// Per-item scores as JSONL.
+    output: Option<String>,
+    by_group: bool,
+}
+
+impl EvalArgs {
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let (mut task, mut data) = (None, None);
+        let (mut limit, mut output, mut by_group) = (None, None, false);
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--task" => task = Some(value()?.parse()?),
+                "--data" => data = Some(value()?),
+                "--limit" => limit = Some(value()?.parse().context("--limit")?),
+                "--output" => output = Some(value()?),
+                "--by-group" => by_group = true,
+                _ => bail!("unknown eval flag: {flag}"),
+            }
+        }
+        let task = task.context("eval needs --task hellaswag|mmlu|choice")?;
+        let data = data.context("eval needs --data FILE")?;
+        Ok(Self { model_path, task, data, limit, output, by_group })
+    }
+}
+
+struct BatchArgs {
+    model_path: String,
+    input: String,
+    output: String,
+    /// Sequences in flight at once.
+    batch: usize,
+    max_new: usize,
+    chat_format: Option<String>,
+    seed: Option<u64>,
+}
+
+impl BatchArgs {
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let (mut input, mut output) = (None, None);
+        let mut out = Self {
+            model_path,
+            input: String::new(),
+            output: String::new(),
+            batch: 8,
+            max_new: 128,
+            chat_format: None,
+            seed: None,
+        };
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--input" | "--input-file" => input = Some(value()?),
+                "--output" => output = Some(value()?),
+                "--batch" => out.batch = value()?.parse().context("--batch")?,
+                "--max" => out.max_new = value()?.parse().context("--max")?,
+                "--chat-format" => out.chat_format = Some(value()?),
+                "--seed" => out.seed = Some(value()?.parse().context("--seed")?),
+                _ => bail!("unknown batch flag: {flag}"),
+            }
+        }
+        out.input = input.context("batch needs --input prompts.jsonl")?;
+        out.output = output.context("batch needs --output results.jsonl")?;
+        Ok(out)
+    }
+}
+
+struct ImatrixArgs {
+    model_path: String,
+    /// Plain calibration text.
+    input: String,
+    output: String,
+    chunk: usize,
+}
+
+impl ImatrixArgs {
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let (mut input, mut output) = (None, None);
+        let mut chunk = 512;
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--input-file" => input = Some(value()?),
+                "--output" => output = Some(value()?),
+                "--chunk" => chunk = value()?.parse().context("--chunk")?,
+                _ => bail!("unknown imatrix flag: {flag}"),
+            }
+        }
+        anyhow::ensure!(chunk > 1, "--chunk must be at least 2 tokens");
+        let input = input.context("imatrix needs --input-file FILE")?;
+        let output = output.unwrap_or_else(|| "imatrix.dat".to_string());
+        Ok(Self { model_path, input, output, chunk })
+    }
+}
+
+struct RerankArgs {
+    model_path: String,
+    query: String,
+    input: String,
+    instruction: String,
+    top: Option<usize>,
+}
+
+impl RerankArgs {
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let (mut query, mut input, mut top) = (None, None, None);
+        let mut instruction = rerank::DEFAULT_INSTRUCTION.to_string();
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--query" => query = Some(value()?),
+                "--input-file" => input = Some(value()?),
+                "--instruction" => instruction = value()?,
+                "--top" => top = Some(value()?.parse().context("--top")?),
+                _ => bail!("unknown rerank flag: {flag}"),
+            }
+        }
+        Ok(Self {
+            model_path,
+            query: query.context("rerank needs --query")?,
+            input: input.context("rerank needs --input-file")?,
+            instruction,
+            top,
+        })
+    }
+}
+
+struct ClassifyArgs {
+    model_path: String,
+    text: Option<String>,
+    input: Option<String>,
+    max_tokens: usize,
+    top: Option<usize>,
+    /// `--multi-label`: an independent sigmoid per class instead of a softmax.
+    multi_label: bool,
+}
+
+impl ClassifyArgs {
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut out = Self { model_path, text: None, input: None, max_tokens: 512, top: None, multi_label: false };
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--text" => out.text = Some(value()?),
+                "--input-file" => out.input = Some(value()?),
+                "--max-tokens" => out.max_tokens = value()?.parse().context("--max-tokens")?,
+                "--top" => out.top = Some(value()?.parse().context("--top")?),
+                "--multi-label" => out.multi_label = true,
+                _ => bail!("unknown classify flag: {flag}"),
+            }
+        }
+        match (&out.text, &out.input) {
+            (None, None) => bail!("classify needs --text TEXT or --input-file docs.jsonl"),
+            (Some(_), Some(_)) => bail!("classify takes --text or --input-file, not both"),
+            _ => Ok(out),
+        }
+    }
+}
+
+struct DetectWatermarkArgs {
+    model_path: String,
+    text: Option<String>,
+    input: Option<String>,
+    watermark: watermark::Watermark,
+    /// z-score from which a text counts as watermarked.
+    threshold: f32,
+}
+
+impl DetectWatermarkArgs {
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let (mut text, mut input, mut key) = (None, None, None);
+        let mut watermark = watermark::Watermark::new(0);
+        let mut threshold = watermark::DEFAULT_THRESHOLD;
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--text" => text = Some(value()?),
+                "--input-file" => input = Some(value()?),
+                "--key" => key = Some(value()?.parse().context("--key")?),
+                "--gamma" => watermark.gamma = watermark::Watermark::parse_gamma(&value()?).context("--gamma")?,
+                "--threshold" => threshold = value()?.parse().context("--threshold")?,
+                _ => bail!("unknown detect-watermark flag: {flag}"),
+            }
+        }
+        watermark.key = key.context("detect-watermark needs the --key the text was generated with")?;
+        match (&text, &input) {
+            (None, None) => bail!("detect-watermark needs --text TEXT or --input-file docs.jsonl"),
+            (Some(_), Some(_)) => bail!("detect-watermark takes --text or --input-file, not both"),
+            _ => Ok(Self { model_path, text, input, watermark, threshold }),
+        }
+    }
+}
+
+struct SearchArgs {
+    model_path: String,
+    index: String,
+    query: String,
+    top: usize,
+    /// `--hnsw M`: walk an HNSW graph with M links per node instead of
+    /// scanning every vector.
+    hnsw: Option<usize>,
+    ef: usize,
+}
+
+impl SearchArgs {
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let (mut index, mut query, mut hnsw) = (None, None, None);
+        let (mut top, mut ef) = (10, 64);
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--index" => index = Some(value()?),
+                "--query" => query = Some(value()?),
+                "--top" => top = value()?.parse().context("--top")?,
+                "--hnsw" => hnsw = Some(value()?.parse().context("--hnsw")?),
+                "--ef" => ef = value()?.parse().context("--ef")?,
+                _ => bail!("unknown search flag: {flag}"),
+            }
+        }
+        Ok(Self {
+            model_path,
+            index: index.context("search needs --index")?,
+            query: query.context("search needs --query")?,
+            top,
+            hnsw,
+            ef,
+        })
+    }
+}
+
+struct RagArgs {
+    model_path: String,
+    /// `--embed-model PATH`: embeds chunks and the question; the generating
+    /// model by default.
+    embed_model: Option<String>,
+    input: Option<String>,
+    index: Option<String>,
+    save_index: Option<String>,
+    query: String,
+    top: usize,
+    /// Words per chunk and words shared by neighbouring chunks.
+    chunk: usize,
+    overlap: usize,
+    max_tokens: usize,
+    template: Option<String>,
+    /// `auto` by default; `none` sends the rendered prompt untemplated.
+    chat_format: Option<String>,
+    max_new: usize,
+    seed: Option<u64>,
+}
+
+impl RagArgs {
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut query = None;
+        let mut out = Self {
+            model_path,
+            embed_model: None,
+            input: None,
+            index: None,
+            save_index: None,
+            query: String::new(),
+            top: 4,
+            chunk: 200,
+            overlap: 40,
+            max_tokens: 512,
+            template: None,
+            chat_format: None,
+            max_new: 256,
+            seed: None,
+        };
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+            match flag.as_str() {
+                "--embed-model" => out.embed_model = Some(value()?),
+                "--input-file" => out.input = Some(value()?),
+                "--index" => out.index = Some(value()?),
+                "--save-index" => out.save_index = Some(value()?),
+                "--query" => query = Some(value()?),
+                "--top" => out.top = value()?.parse().context("--top")?,
+                "--chunk" => out.chunk = value()?.parse().context("--chunk")?,
+                "--overlap" => out.overlap = value()?.parse().context("--overlap")?,
+                "--max-tokens" => out.max_tokens = value()?.parse().context("--max-tokens")?,
+                "--template" => out.template = Some(value()?),
+                "--chat-format" => out.chat_format = Some(value()?),
+                "--max" => out.max_new = value()?.parse().context("--max")?,
+                "--seed" => out.seed = Some(value()?.parse().context("--seed")?),
+                _ => bail!("unknown rag flag: {flag}"),
+            }
+        }
+        out.query = query.context("rag needs --query")?;
+        match (&out.input, &out.index) {
+            (None, None) => bail!("rag needs --input-file docs.jsonl or --index docs.idx"),
+            (Some(_), Some(_)) => bail!("rag takes --input-file or --index, not both"),
+            (None, Some(_)) if out.save_index.is_some() => bail!("--save-index needs --input-file"),
+            _ => {}
+        }
+        Ok(out)
+    }
 }
 
 impl Command {
-    fn from_env() -> Result<Self> {
-        let mut args = std::env::args().skip(1);
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self> {
         let Some(command) = args.next() else {
             print_usage();
             bail!("missing command");
@@ -87,28 +2495,216 @@ impl Command {
                 };
                 Ok(Self::Trace { model_path, prompt })
             }
-            "run" => {
+            "audit" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::Audit { model_path })
+            }
+            "repair" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                let url = match (args.next().as_deref(), args.next()) {
+                    (None, _) => None,
+                    (Some("--url"), Some(url)) => Some(url),
+                    (Some(flag), _) => bail!("usage: repair <model.gguf> [--url URL] (got {flag})"),
+                };
+                Ok(Self::Repair { model_path, url })
+            }
+            "sysinfo" => Ok(Self::Sysinfo),
+            "dump" => {
+                let (Some(model_path), Some(out_dir)) = (args.next(), args.next()) else {
+                    print_usage();
+                    bail!("usage: dump <model.gguf> <out_dir> [prompt]");
+                };
+                let prompt = args.collect::<Vec<_>>().join(" ");
+                let prompt = if prompt.is_empty() { "Hello".to_string() } else { prompt };
+                Ok(Self::Dump { model_path, out_dir, prompt })
+            }
+            "dump-diff" => {
+                let (Some(a), Some(b)) = (args.next(), args.next()) else {
+                    print_usage();
+                    bail!("usage: dump-diff <dir_a> <dir_b> [--tol F]");
+                };
+                let tol = match (args.next().as_deref(), args.next()) {
+                    (Some("--tol"), Some(v)) => v.parse().context("--tol")?,
+                    _ => 1e-3,
+                };
+                Ok(Self::DumpDiff { a, b, tol })
+            }
+            "logit-diff" => {
+                let (Some(model_path), Some(reference)) = (args.next(), args.next()) else {
+                    print_usage();
+                    bail!("usage: logit-diff <model.gguf> <reference> [--prompt TEXT | --tokens IDS]");
+                };
+                Ok(Self::LogitDiff(LogitDiffArgs::parse(model_path, reference, args)?))
+            }
+            "embed" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::Embed(EmbedArgs::parse(model_path, args)?))
+            }
+            "rerank" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::Rerank(RerankArgs::parse(model_path, args)?))
+            }
+            "rag" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::Rag(RagArgs::parse(model_path, args)?))
+            }
+            "classify" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::Classify(ClassifyArgs::parse(model_path, args)?))
+            }
+            "detect-watermark" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::DetectWatermark(DetectWatermarkArgs::parse(model_path, args)?))
+            }
+            "search" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::Search(SearchArgs::parse(model_path, args)?))
+            }
+            "chat" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::Chat(ChatArgs::parse(model_path, args)?))
+            }
+            "complete" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::Complete(CompleteArgs::parse(model_path, args)?))
+            }
+            "lsp" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::Lsp(LspArgs::parse(model_path, args)?))
+            }
+            "commit-msg" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::CommitMsg(CommitMsgArgs::parse(model_path, args)?))
+            }
+            "compare" => {
+                let (Some(model_a), Some(model_b)) = (args.next(), args.next()) else {
+                    print_usage();
+                    bail!("usage: compare <model-a.gguf> <model-b.gguf> --prompts FILE");
+                };
+                Ok(Self::Compare(CompareArgs::parse(model_a, model_b, args)?))
+            }
+            "eval" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::Eval(EvalArgs::parse(model_path, args)?))
+            }
+            "daemon" => {
                 let Some(model_path) = args.next() else {
                     print_usage();
                     bail!("missing GGUF path");
                 };
-                let mut max_new = 64;
-                let mut prompt_words = Vec::new();
-                loop {
-                    match args.next().as_deref() {
-                        Some("--max") => {
-                            max_new = args.next().and_then(|s| s.parse().ok()).unwrap_or(64);
+                let (mut socket, mut limits, mut warmup) = (None, daemon::Limits::default(), false);
+                while let Some(flag) = args.next() {
+                    let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+                    match flag.as_str() {
+                        "--socket" => socket = Some(value()?),
+                        "--max-tokens" => limits.max_tokens = value()?.parse().context("--max-tokens")?,
+                        "--max-temperature" => limits.max_temperature = value()?.parse().context("--max-temperature")?,
+                        "--max-penalty" => limits.max_penalty = value()?.parse().context("--max-penalty")?,
+                        "--session-ttl" => {
+                            limits.session_ttl = std::time::Duration::from_secs(value()?.parse().context("--session-ttl")?)
                         }
-                        Some(w) => prompt_words.push(w.to_string()),
-                        None => break,
+                        "--session-memory" => limits.session_bytes = value()?.parse::<usize>().context("--session-memory")? << 20,
+                        "--section-memory" => limits.section_bytes = value()?.parse::<usize>().context("--section-memory")? << 20,
+                        "--warmup" => warmup = true,
+                        _ => bail!("unknown daemon flag: {flag}"),
                     }
                 }
-                let prompt = if prompt_words.is_empty() {
-                    "Hello".to_string()
-                } else {
-                    prompt_words.join(" ")
+                Ok(Self::Daemon { model_path, socket, limits, warmup })
+            }
+            "worker" => {
+                let mut listen = rpc::DEFAULT_LISTEN.to_string();
+                while let Some(flag) = args.next() {
+                    let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+                    match flag.as_str() {
+                        "--listen" => listen = value()?,
+                        _ => bail!("unknown worker flag: {flag}"),
+                    }
+                }
+                Ok(Self::Worker { listen })
+            }
+            "batch" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::Batch(BatchArgs::parse(model_path, args)?))
+            }
+            "imatrix" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                Ok(Self::Imatrix(ImatrixArgs::parse(model_path, args)?))
+            }
+            "seal" => {
+                let (Some(input), Some(output)) = (args.next(), args.next()) else {
+                    print_usage();
+                    bail!("usage: seal <model.gguf> <sealed.gguf>");
+                };
+                Ok(Self::Seal { input, output })
+            }
+            "sign" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
+                };
+                let (mut key, mut manifest_path) = (None, None);
+                while let Some(flag) = args.next() {
+                    let mut value = || args.next().with_context(|| format!("{flag} needs a value"));
+                    match flag.as_str() {
+                        "--key" => key = Some(value()?),
+                        "--manifest" => manifest_path = Some(value()?),
+                        _ => bail!("unknown sign flag: {flag}"),
+                    }
+                }
+                let key = key.context("sign needs --key FILE")?;
+                Ok(Self::Sign { model_path, key, manifest_path })
+            }
+            "run" => {
+                let Some(model_path) = args.next() else {
+                    print_usage();
+                    bail!("missing GGUF path");
                 };
-                Ok(Self::Run { model_path, prompt, max_new })
+                Ok(Self::Run(Box::new(RunArgs::parse(model_path, args)?)))
             }
             _ => {
                 print_usage();
@@ -118,9 +2714,254 @@ impl Command {
     }
 }
 
+impl RunArgs {
+    /// Flags may appear anywhere; every other word is part of the prompt.
+    /// The watermark flags must parse: a bad key is an error, not key 0.
+    fn parse(model_path: String, mut args: impl Iterator<Item = String>) -> Result<Self> {
+        let mut run = Self {
+            model_path,
+            prompt: None,
+            max_new: 64,
+            cfg_negative: None,
+            cfg_scale: 1.0,
+            beams: None,
+            rope_scale: None,
+            rope_base: None,
+            yarn: false,
+            sampling: SamplerConfig::default(),
+            draft: None,
+            fixed_draft: false,
+            load_threads: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            repack_cache: false,
+            fuse_weights: false,
+            int8_activations: false,
+            warmup: false,
+            autotune: false,
+            prefill_chunk: None,
+            accelerate: None,
+            gpu_layers: None,
+            devices: None,
+            pipeline: None,
+            rpc: Vec::new(),
+            micro_batch: llmetal::pipeline::DEFAULT_MICRO_BATCH,
+            chat_format: None,
+            special: false,
+            metal_capture: None,
+            ssm_state: None,
+            audio: None,
+            audio_model: None,
+            audio_language: None,
+            tts_command: None,
+            timings: false,
+            profile: false,
+            prompt_file: None,
+            raw: false,
+            no_bos: false,
+            json_output: false,
+            local: false,
+            verify_signature: None,
+            manifest: None,
+            medusa: None,
+            choices: Vec::new(),
+            regex: None,
+            watermark: None,
+            ctx: None,
+            loop_action: None,
+            reasoning: None,
+            max_thinking: None,
+            reasoning_effort: None,
+            loop_guard: LoopGuard::default(),
+        };
+        let mut beam_width = 1;
+        let mut length_penalty = 1.0;
+        let mut dry = DryConfig { multiplier: 0.0, ..DryConfig::default() };
+        let mut xtc = XtcConfig { probability: 0.0, threshold: 0.1 };
+        let mut lookup = LookupConfig { n_draft: 0, ..LookupConfig::default() };
+        let mut early_exit = EarlyExitConfig { layers: 0, n_draft: 4 };
+        let mut medusa = MedusaConfig { n_draft: usize::MAX };
+        let mut watermark = watermark::Watermark::new(0);
+        let mut watermarked = false;
+        let mut prompt_words = Vec::new();
+
+        fn num<T: std::str::FromStr>(v: Option<String>, default: T) -> T {
+            v.and_then(|s| s.parse().ok()).unwrap_or(default)
+        }
+        fn required(v: Option<String>, flag: &str) -> Result<String> {
+            v.with_context(|| format!("{flag} needs a value"))
+        }
+
+        loop {
+            match args.next().as_deref() {
+                Some("--max") => run.max_new = num(args.next(), 64),
+                Some("--cfg-negative-prompt") => run.cfg_negative = args.next(),
+                Some("--cfg-scale") => run.cfg_scale = num(args.next(), 1.0),
+                Some("--beams") => beam_width = num(args.next(), 1),
+                Some("--length-penalty") => length_penalty = num(args.next(), 1.0),
+                Some("--rope-scale") => run.rope_scale = args.next().and_then(|s| s.parse().ok()),
+                Some("--rope-freq-base") => run.rope_base = args.next().and_then(|s| s.parse().ok()),
+                Some("--yarn") => run.yarn = true,
+                Some("--temp") => run.sampling.temperature = num(args.next(), 0.0),
+                Some("--top-k") => run.sampling.top_k = num(args.next(), 0),
+                Some("--top-p") => run.sampling.top_p = num(args.next(), 1.0),
+                Some("--repeat-penalty") => run.sampling.repetition_penalty = num(args.next(), 1.3),
+                Some("--dry-multiplier") => dry.multiplier = num(args.next(), 0.0),
+                Some("--dry-base") => dry.base = num(args.next(), 1.75),
+                Some("--dry-allowed-length") => dry.allowed_length = num(args.next(), 2),
+                Some("--xtc-probability") => xtc.probability = num(args.next(), 0.0),
+                Some("--xtc-threshold") => xtc.threshold = num(args.next(), 0.1),
+                Some("--lookup-draft") => lookup.n_draft = num(args.next(), 0),
+                Some("--lookup-ngram") => lookup.ngram_max = num(args.next(), 3),
+                Some("--early-exit") => early_exit.layers = num(args.next(), 0),
+                Some("--early-exit-draft") => early_exit.n_draft = num(args.next(), 4),
+                Some("--medusa") => run.medusa = args.next(),
+                Some("--medusa-draft") => medusa.n_draft = num(args.next(), usize::MAX),
+                Some("--fixed-draft") => run.fixed_draft = true,
+                Some("--choice") => run.choices.extend(args.next()),
+                Some("--regex") => run.regex = args.next(),
+                Some("--watermark") => (watermark.key, watermarked) = (required(args.next(), "--watermark")?.parse().context("--watermark")?, true),
+                Some("--watermark-gamma") => {
+                    watermark.gamma = watermark::Watermark::parse_gamma(&required(args.next(), "--watermark-gamma")?).context("--watermark-gamma")?
+                }
+                Some("--watermark-delta") => watermark.delta = required(args.next(), "--watermark-delta")?.parse().context("--watermark-delta")?,
+                Some("--ctx") => run.ctx = args.next().and_then(|s| s.parse().ok()),
+                Some("--loop-guard") => run.loop_action = args.next(),
+                Some("--loop-period") => run.loop_guard.max_period = num(args.next(), 32),
+                Some("--loop-repeats") => run.loop_guard.min_repeats = num(args.next(), 3),
+                Some("--loop-min-tokens") => run.loop_guard.min_tokens = num(args.next(), 32),
+                Some("--reasoning") => run.reasoning = args.next(),
+                Some("--max-thinking") => run.max_thinking = Some(num(args.next(), 0)),
+                Some("--reasoning-effort") => run.reasoning_effort = args.next(),
+                Some("--load-threads") => run.load_threads = num(args.next(), run.load_threads),
+                Some("--repack-cache") => run.repack_cache = true,
+                Some("--fuse-weights") => run.fuse_weights = true,
+                Some("--int8-activations") => run.int8_activations = true,
+                Some("--warmup") => run.warmup = true,
+                Some("--autotune") => run.autotune = true,
+                Some("--prefill-chunk") => run.prefill_chunk = Some(num(args.next(), model::PREFILL_CHUNK)),
+                Some("--accelerate") => run.accelerate = Some(num(args.next(), 32)),
+                Some("--gpu-layers") => run.gpu_layers = Some(num(args.next(), usize::MAX)),
+                Some("--devices") => run.devices = args.next().map(|s| device_list(&s)),
+                Some("--pipeline") => run.pipeline = args.next().map(|s| device_list(&s)),
+                Some("--rpc") => run.rpc = args.next().map_or_else(Vec::new, |s| s.split(',').map(str::to_string).collect()),
+                Some("--micro-batch") => run.micro_batch = num(args.next(), run.micro_batch),
+                Some("--chat-format") => run.chat_format = args.next(),
+                Some("--special") => run.special = true,
+                Some("--metal-capture") => run.metal_capture = args.next(),
+                Some("--ssm-state") => run.ssm_state = args.next(),
+                Some("--audio") => run.audio = args.next(),
+                Some("--audio-model") => run.audio_model = args.next(),
+                Some("--audio-language") => run.audio_language = args.next(),
+                Some("--tts-command") => run.tts_command = args.next(),
+                Some("--timings") => run.timings = true,
+                Some("--profile") => run.profile = true,
+                Some("--prompt-file") => run.prompt_file = args.next(),
+                Some("--raw") => run.raw = true,
+                Some("--no-bos") => run.no_bos = true,
+                Some("--json-output") => run.json_output = true,
+                Some("--local") => run.local = true,
+                Some("--verify-signature") => run.verify_signature = args.next(),
+                Some("--manifest") => run.manifest = args.next(),
+                Some("--seed") => run.sampling.seed = args.next().and_then(|s| s.parse().ok()),
+                Some(w) => prompt_words.push(w.to_string()),
+                None => break,
+            }
+        }
+
+        run.prompt = (!prompt_words.is_empty()).then(|| prompt_words.join(" "));
+        run.beams = (beam_width > 1)
+            .then_some(model::BeamConfig { width: beam_width, length_penalty });
+        run.sampling.dry = (dry.multiplier > 0.0).then_some(dry);
+        run.sampling.xtc = (xtc.probability > 0.0).then_some(xtc);
+        run.watermark = watermarked.then_some(watermark);
+        run.draft = if run.medusa.is_some() {
+            Some(DraftSource::Medusa(medusa))
+        } else if early_exit.layers > 0 {
+            Some(DraftSource::EarlyExit(early_exit))
+        } else {
+            (lookup.n_draft > 0).then_some(DraftSource::Lookup(lookup))
+        };
+        Ok(run)
+    }
+}
+
+/// `0,1` as device indices.
+fn device_list(s: &str) -> Vec<usize> {
+    s.split(',').filter_map(|d| d.trim().parse().ok()).collect()
+}
+
 fn print_usage() {
     eprintln!("Usage:");
     eprintln!("  llmetal inspect <model.gguf>");
     eprintln!("  llmetal trace   <model.gguf> [prompt]");
-    eprintln!("  llmetal run     <model.gguf> [--max N] [prompt text]");
+    eprintln!("  llmetal audit   <model.gguf>");
+    eprintln!("  llmetal repair  <model.gguf> [--url URL]");
+    eprintln!("  llmetal sysinfo");
+    eprintln!("  llmetal dump    <model.gguf> <out_dir> [prompt]");
+    eprintln!("  llmetal embed   <model.gguf> --input-file docs.jsonl --output out.npy|out.jsonl|out.idx");
+    eprintln!("                  [--batch N] [--threads N] [--max-tokens N] [--pooling mean|cls|last]");
+    eprintln!("  llmetal rerank  <model.gguf> --query TEXT --input-file docs.jsonl");
+    eprintln!("                  [--instruction TEXT] [--top N]");
+    eprintln!("  llmetal classify <model.gguf> --text TEXT|--input-file docs.jsonl [--top N] [--multi-label]");
+    eprintln!("                  [--max-tokens N]");
+    eprintln!("  llmetal detect-watermark <model.gguf> --key KEY --text TEXT|--input-file docs.jsonl");
+    eprintln!("                  [--gamma F] [--threshold Z]");
+    eprintln!("  llmetal search  <model.gguf> --index docs.idx --query TEXT [--top N] [--hnsw M [--ef N]]");
+    eprintln!("  llmetal rag     <model.gguf> --query TEXT --input-file docs.jsonl [--save-index docs.idx]|--index docs.idx");
+    eprintln!("                  [--embed-model PATH] [--top N] [--chunk WORDS] [--overlap WORDS] [--max-tokens N]");
+    eprintln!("                  [--template FILE] [--chat-format auto|none|NAME] [--max N] [--seed N]");
+    eprintln!("  llmetal chat    <model.gguf> [--system TEXT] [--chat-format auto|NAME]");
+    eprintln!("                  [--max N] [--ctx N] [--summarize] [--summarize-after N] [--seed N]");
+    eprintln!("                  [--xtc-probability F] [--xtc-threshold F]");
+    eprintln!("                  [--parse-special] [--special] [--local] [--warmup] [--prefill-chunk N]");
+    eprintln!("                  [--reasoning show|hide|separate] [--max-thinking N] [--reasoning-effort low|medium|high]");
+    eprintln!("                  [--session ID]");
+    eprintln!("                  [--verify-signature KEY [--manifest PATH]]");
+    eprintln!("                  [--tools FILE] [--mcp CMD]... [--mcp-config FILE]");
+    eprintln!("                  [--max-tool-rounds N] [--tool-timeout SECS] [--tool-dir DIR]");
+    eprintln!("  llmetal complete <model.gguf> --file PATH --cursor LINE:COL [--stdin] [--language NAME]");
+    eprintln!("                  [--candidates N] [--max N] [--single-line] [--temp F] [--seed N] [--ctx N]");
+    eprintln!("  llmetal lsp     <model.gguf> [--candidates N] [--max N] [--temp F] [--seed N] [--ctx N]");
+    eprintln!("  llmetal commit-msg <model.gguf> [--hint TEXT] [--max-diff CHARS] [--attempts N]");
+    eprintln!("                  [--chat-format auto|NAME] [--max N] [--seed N]   (diff on stdin, else `git diff --staged`)");
+    eprintln!("  llmetal compare <model-a.gguf> <model-b.gguf> --prompts prompts.jsonl [--max-tokens N]");
+    eprintln!("  llmetal eval    <model.gguf> --task hellaswag|mmlu|choice --data FILE [--limit N]");
+    eprintln!("                  [--output scores.jsonl] [--by-group]");
+    eprintln!("  llmetal daemon  <model.gguf> [--socket PATH]");
+    eprintln!("                  [--max-tokens N] [--max-temperature F] [--max-penalty F]");
+    eprintln!("                  [--session-ttl SECS] [--session-memory MB] [--section-memory MB] [--warmup]");
+    eprintln!("  llmetal worker  [--listen ADDR:PORT]   (default 127.0.0.1:50052; no authentication)");
+    eprintln!("  llmetal batch   <model.gguf> --input prompts.jsonl --output results.jsonl");
+    eprintln!("                  [--batch N] [--max N] [--chat-format auto|NAME] [--seed N]");
+    eprintln!("  llmetal imatrix <model.gguf> --input-file calibration.txt [--output imatrix.dat] [--chunk N]");
+    eprintln!("  llmetal dump-diff <dir_a> <dir_b> [--tol F]");
+    eprintln!("  llmetal logit-diff <model.gguf> <llama.cpp logits|dump dir> [--prompt TEXT | --tokens 1,2,3]");
+    eprintln!("                  [--tol F] [--layer-tol F]");
+    eprintln!("  llmetal seal    <model.gguf> <sealed.gguf>   (key: $LLMETAL_MODEL_KEY or keychain)");
+    eprintln!("  llmetal sign    <model.gguf> --key secret.key [--manifest PATH]");
+    eprintln!("  llmetal run     <model.gguf> [--max N] [--ctx N] [prompt text]");
+    eprintln!("                  [--cfg-negative-prompt TEXT] [--cfg-scale F]");
+    eprintln!("                  [--beams N] [--length-penalty F]");
+    eprintln!("                  [--rope-scale F [--yarn]] [--rope-freq-base F]");
+    eprintln!("                  [--temp F] [--top-k N] [--top-p F] [--repeat-penalty F]");
+    eprintln!("                  [--dry-multiplier F] [--dry-base F] [--dry-allowed-length N]");
+    eprintln!("                  [--xtc-probability F] [--xtc-threshold F] [--seed N]");
+    eprintln!("                  [--lookup-draft N] [--lookup-ngram N]");
+    eprintln!("                  [--early-exit K] [--early-exit-draft N]");
+    eprintln!("                  [--medusa HEADS.gguf] [--medusa-draft N] [--fixed-draft]");
+    eprintln!("                  [--choice TEXT]... [--regex PATTERN]");
+    eprintln!("                  [--loop-guard stop|penalize[=F]|diversify[=F]] [--loop-period N]");
+    eprintln!("                  [--loop-repeats N] [--loop-min-tokens N]");
+    eprintln!("                  [--reasoning show|hide|separate] [--max-thinking N] [--reasoning-effort low|medium|high]");
+    eprintln!("                  [--watermark KEY [--watermark-gamma F] [--watermark-delta F]]");
+    eprintln!("                  [--load-threads N] [--repack-cache] [--fuse-weights] [--warmup]");
+    eprintln!("                  [--accelerate MIN_BATCH] [--int8-activations]");
+    eprintln!("                  [--gpu-layers N] [--autotune] [--prefill-chunk N]");
+    eprintln!("                  [--devices 0,1] [--pipeline 0,1] [--rpc HOST:PORT,...] [--micro-batch N]");
+    eprintln!("                  [--metal-capture FILE.gputrace] [--timings] [--profile] [--ssm-state FILE]");
+    eprintln!("                  [--chat-format auto|chatml|llama3|mistral|gemma|phi] [--special]");
+    eprintln!("                  [--prompt-file PATH|-] [--raw] [--no-bos] [--json-output] [--local]");
+    eprintln!("                  [--audio FILE.wav --audio-model WHISPER.gguf [--audio-language CODE]]");
+    eprintln!("                  [--tts-command CMD]");
+    eprintln!("                  [--verify-signature KEY [--manifest PATH]]");
+    eprintln!("Any command: --force-kernel scalar|neon|avx2 pins the CPU attention kernel.");
 }
@@ -0,0 +1,180 @@
+//! Signed weight manifests: a SHA-512 per tensor plus one for the header
+//! (metadata and tensor table), signed with Ed25519. `llmetal sign` writes
+//! one next to the model; `--verify-signature KEY` checks it before the
+//! weights are loaded, so a swapped tensor or edited tokenizer is refused.
+//!
+//! `require` checks the signature and registers the manifest; from then on
+//! every `envelope::map` of that model hashes the mapping it returns, the
+//! one the loader then reads, so the file cannot be swapped between the
+//! check and the load. A file rewritten in place after that still shows
+//! through the mapped pages: keep signed models read-only.
+//!
+//! The manifest is JSON:
+//!
+//! ```text
+//! {"format": "llmetal-manifest-1", "header": HEX, "tensors": {NAME: HEX, ...},
+//!  "public_key": HEX, "signature": HEX}
+//! ```
+//!
+//! The signature covers the compact serialization of everything but
+//! `public_key` and `signature`, keys sorted. A sealed model (`envelope`) is
+//! hashed as loaded, after decryption.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result, bail, ensure};
+use serde_json::{Value, json};
+
+use crate::ed25519::{self, Sha512};
+use crate::gguf::GgufFile;
+use crate::tensor::index_tensors;
+
+const FORMAT: &str = "llmetal-manifest-1";
+
+/// Manifests registered by `require`, by canonical model path.
+static REQUIRED: Mutex<BTreeMap<PathBuf, Value>> = Mutex::new(BTreeMap::new());
+
+/// `<model>.manifest.json`.
+pub fn default_path(model_path: &str) -> PathBuf {
+    PathBuf::from(format!("{model_path}.manifest.json"))
+}
+
+/// The header digest and one digest per tensor, hashed on every core.
+pub fn hash_model(model_path: &str) -> Result<(String, BTreeMap<String, String>)> {
+    hash_bytes(model_path, &crate::envelope::map_unchecked(model_path)?)
+}
+
+/// `hash_model` over the already mapped (and decrypted) `mmap`.
+fn hash_bytes(model_path: &str, mmap: &[u8]) -> Result<(String, BTreeMap<String, String>)> {
+    let gguf = GgufFile::parse(mmap).with_context(|| format!("parse {model_path}"))?;
+    let index = index_tensors(&gguf)?;
+    let mut tensors: Vec<(&String, &[u8])> = Vec::with_capacity(index.len());
+    for (name, meta) in &index {
+        let start = meta.file_offset as usize;
+        let bytes = mmap
+            .get(start..start + meta.byte_size as usize)
+            .with_context(|| format!("tensor '{name}' out of file bounds"))?;
+        tensors.push((name, bytes));
+    }
+    // Largest first, dealt round-robin, keeps the threads about even.
+    tensors.sort_by_key(|(_, bytes)| std::cmp::Reverse(bytes.len()));
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let digests = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let tensors = &tensors;
+                s.spawn(move || {
+                    tensors
+                        .iter()
+                        .skip(t)
+                        .step_by(threads)
+                        .map(|(name, bytes)| (name.to_string(), hex(&ed25519::sha512(bytes))))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles.into_iter().flat_map(|h| h.join().expect("hash thread panicked")).collect()
+    });
+    // A file without tensors may end before its aligned data start.
+    let mut header = Sha512::default();
+    header.update(&mmap[..(gguf.data_start as usize).min(mmap.len())]);
+    Ok((hex(&header.finish()), digests))
+}
+
+/// Hash `model_path` and sign the result with `seed`.
+pub fn sign(model_path: &str, seed: &[u8; 32]) -> Result<Value> {
+    let (header, tensors) = hash_model(model_path)?;
+    let mut manifest = json!({ "format": FORMAT, "header": header, "tensors": tensors });
+    let signature = ed25519::sign(seed, &signed_bytes(&manifest)?);
+    manifest["public_key"] = hex(&ed25519::public_key(seed)).into();
+    manifest["signature"] = hex(&signature).into();
+    Ok(manifest)
+}
+
+/// Check the manifest at `manifest_path` against `public_key` and then the
+/// model against the manifest. Every failure is an error that names what
+/// did not match.
+pub fn verify(model_path: &str, manifest_path: &Path, public_key: &[u8; 32]) -> Result<()> {
+    let manifest = read_signed(manifest_path, public_key)?;
+    let (header, tensors) = hash_model(model_path)?;
+    compare(model_path, &manifest, &header, &tensors)
+}
+
+/// Check the manifest's signature now and, for the rest of the process,
+/// every mapping of `model_path` against it as it is loaded.
+pub fn require(model_path: &str, manifest_path: &Path, public_key: &[u8; 32]) -> Result<()> {
+    let manifest = read_signed(manifest_path, public_key)?;
+    let path = std::fs::canonicalize(model_path).with_context(|| format!("open {model_path}"))?;
+    REQUIRED.lock().unwrap_or_else(|e| e.into_inner()).insert(path, manifest);
+    Ok(())
+}
+
+/// If `require` registered `model_path`, check `mmap`, a mapping of it,
+/// against the manifest.
+pub(crate) fn check_mapping(model_path: &str, mmap: &[u8]) -> Result<()> {
+    let Ok(path) = std::fs::canonicalize(model_path) else { return Ok(()) };
+    let Some(manifest) = REQUIRED.lock().unwrap_or_else(|e| e.into_inner()).get(&path).cloned() else {
+        return Ok(());
+    };
+    let (header, tensors) = hash_bytes(model_path, mmap)?;
+    compare(model_path, &manifest, &header, &tensors)
+}
+
+/// The manifest at `manifest_path`, once its signature verifies.
+fn read_signed(manifest_path: &Path, public_key: &[u8; 32]) -> Result<Value> {
+    let text = std::fs::read_to_string(manifest_path)
+        .with_context(|| format!("read manifest {}", manifest_path.display()))?;
+    let manifest: Value = serde_json::from_str(&text).context("manifest is not JSON")?;
+    ensure!(manifest["format"] == FORMAT, "{} is not an {FORMAT} file", manifest_path.display());
+    let signature: [u8; 64] = unhex(manifest["signature"].as_str().context("manifest has no signature")?)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("signature is not 64 bytes"))?;
+    if !ed25519::verify(public_key, &signed_bytes(&manifest)?, &signature) {
+        bail!("{}: signature does not verify with key {}", manifest_path.display(), hex(public_key));
+    }
+    Ok(manifest)
+}
+
+fn compare(model_path: &str, manifest: &Value, header: &str, tensors: &BTreeMap<String, String>) -> Result<()> {
+    ensure!(manifest["header"] == header, "{model_path}: metadata or tensor table differs from the manifest");
+    let signed = manifest["tensors"].as_object().context("manifest has no tensors")?;
+    for (name, digest) in tensors {
+        match signed.get(name).and_then(Value::as_str) {
+            Some(d) if d == digest => {}
+            Some(_) => bail!("{model_path}: tensor '{name}' differs from the manifest"),
+            None => bail!("{model_path}: tensor '{name}' is not in the manifest"),
+        }
+    }
+    if let Some(name) = signed.keys().find(|name| !tensors.contains_key(*name)) {
+        bail!("{model_path}: tensor '{name}' from the manifest is missing");
+    }
+    Ok(())
+}
+
+/// A 32-byte key given as hex, or the path of a file holding that hex.
+pub fn read_key(arg: &str) -> Result<[u8; 32]> {
+    let text = match std::fs::read_to_string(arg) {
+        Ok(text) => text,
+        Err(_) => arg.to_string(),
+    };
+    unhex(text.trim())?.try_into().map_err(|_| anyhow::anyhow!("a key is 64 hex digits"))
+}
+
+/// What the signature covers: the manifest without its key and signature.
+fn signed_bytes(manifest: &Value) -> Result<Vec<u8>> {
+    let mut body = manifest.as_object().context("manifest is not an object")?.clone();
+    body.remove("public_key");
+    body.remove("signature");
+    Ok(serde_json::to_vec(&body)?)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn unhex(s: &str) -> Result<Vec<u8>> {
+    ensure!(s.len().is_multiple_of(2) && s.is_ascii(), "not hex: {s:?}");
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).context("not hex")).collect()
+}
@@ -0,0 +1,254 @@
+//! Model Context Protocol client over stdio, so agent mode can use the
+//! tools MCP servers provide (`chat --mcp CMD`, `chat --mcp-config FILE`).
+//!
+//! A server is a subprocess that speaks JSON-RPC 2.0, one message per line
+//! on its stdin and stdout. `McpClient::spawn` runs the `initialize`
+//! handshake, `list_tools` pages through `tools/list`, and `call_tool` sends
+//! `tools/call` and joins the text content of the result. A result flagged
+//! `isError` becomes an `Err`, which the agent feeds back to the model.
+//! `register_tools` adds every tool to an `agent::Agent` as a closure that
+//! forwards the call.
+//!
+//! Notifications from the server (logging, progress) are ignored. It may
+//! send a `ping`, which gets the empty result the spec asks for. Any other
+//! request it sends is declined as unsupported. Sampling and roots are not
+//! offered in the client capabilities.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result, bail};
+use serde_json::{Value, json};
+
+use crate::agent::Agent;
+
+pub const PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// How to start one server, as in the `mcpServers` map of an MCP config file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ServerConfig {
+    pub name: String,
+    pub command: String,
+    pub args: Vec<String>,
+    /// Added to the inherited environment.
+    pub env: BTreeMap<String, String>,
+}
+
+impl ServerConfig {
+    /// A whitespace-separated command line, named after its program.
+    pub fn from_command_line(line: &str) -> Result<Self> {
+        let mut parts = line.split_whitespace().map(String::from);
+        let command = parts.next().context("empty MCP server command")?;
+        let name = Path::new(&command).file_name().map_or(command.clone(), |n| n.to_string_lossy().into_owned());
+        Ok(Self { name, command, args: parts.collect(), env: BTreeMap::new() })
+    }
+
+    /// `{"mcpServers": {"NAME": {"command", "args", "env"}}}`, the layout
+    /// other MCP clients read. Servers with a `url` instead of a command
+    /// need an HTTP transport and are refused.
+    pub fn load(path: &Path) -> Result<Vec<Self>> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let config: Value = serde_json::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+        let servers = config["mcpServers"].as_object().with_context(|| format!("{} has no \"mcpServers\" object", path.display()))?;
+        servers
+            .iter()
+            .map(|(name, s)| {
+                let Some(command) = s["command"].as_str() else {
+                    bail!("MCP server '{name}' has no command (only stdio servers are supported)");
+                };
+                let strings = |v: &Value| v.as_str().map(String::from).with_context(|| format!("MCP server '{name}': expected a string"));
+                let args = s["args"].as_array().map_or(Ok(Vec::new()), |a| a.iter().map(strings).collect())?;
+                let env = match s["env"].as_object() {
+                    Some(env) => env.iter().map(|(k, v)| Ok((k.clone(), strings(v)?))).collect::<Result<_>>()?,
+                    None => BTreeMap::new(),
+                };
+                Ok(Self { name: name.clone(), command: command.to_string(), args, env })
+            })
+            .collect()
+    }
+}
+
+/// A tool as `tools/list` describes it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct McpTool {
+    pub name: String,
+    pub description: String,
+    pub input_schema: Value,
+}
+
+/// A running server and the JSON-RPC session with it.
+pub struct McpClient {
+    pub name: String,
+    /// Longest wait for any one response.
+    pub timeout: Duration,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    /// Lines from the server's stdout, read on a thread of their own.
+    lines: Receiver<String>,
+    next_id: u64,
+}
+
+impl McpClient {
+    /// Start the server and run the `initialize` handshake.
+    pub fn spawn(config: &ServerConfig) -> Result<Self> {
+        let mut child = Command::new(&config.command)
+            .args(&config.args)
+            .envs(&config.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("starting MCP server '{}' ({})", config.name, config.command))?;
+        let stdout = child.stdout.take().context("MCP server stdout")?;
+        let (tx, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(|l| l.ok()) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        let stdin = child.stdin.take();
+        let mut client = Self { name: config.name.clone(), timeout: Duration::from_secs(60), child, stdin, lines, next_id: 1 };
+        let init = client
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": { "name": "llmetal", "version": env!("CARGO_PKG_VERSION") },
+                }),
+            )
+            .with_context(|| format!("initializing MCP server '{}'", config.name))?;
+        if let Some(name) = init["serverInfo"]["name"].as_str() {
+            client.name = format!("{} ({name})", config.name);
+        }
+        client.send(&json!({ "jsonrpc": "2.0", "method": "notifications/initialized" }))?;
+        Ok(client)
+    }
+
+    /// Every tool the server offers, across pages.
+    pub fn list_tools(&mut self) -> Result<Vec<McpTool>> {
+        let mut tools = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let params = match &cursor {
+                Some(c) => json!({ "cursor": c }),
+                None => json!({}),
+            };
+            let page = self.request("tools/list", params)?;
+            for t in page["tools"].as_array().context("tools/list result has no \"tools\" array")? {
+                tools.push(McpTool {
+                    name: t["name"].as_str().context("MCP tool without a name")?.to_string(),
+                    description: t["description"].as_str().unwrap_or_default().to_string(),
+                    input_schema: match &t["inputSchema"] {
+                        Value::Null => json!({ "type": "object", "properties": {} }),
+                        s => s.clone(),
+                    },
+                });
+            }
+            cursor = page["nextCursor"].as_str().map(String::from);
+            if cursor.is_none() {
+                return Ok(tools);
+            }
+        }
+    }
+
+    /// `tools/call`: the text parts of the result, one per line. Parts of
+    /// another type (images, audio, resources) show as `[type]`.
+    pub fn call_tool(&mut self, name: &str, arguments: &Value) -> Result<String> {
+        let result = self.request("tools/call", json!({ "name": name, "arguments": arguments }))?;
+        let parts: Vec<String> = result["content"]
+            .as_array()
+            .map(|c| {
+                c.iter()
+                    .map(|part| match part["text"].as_str() {
+                        Some(text) => text.to_string(),
+                        None => format!("[{}]", part["type"].as_str().unwrap_or("content")),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        let text = parts.join("\n");
+        if result["isError"].as_bool() == Some(true) {
+            bail!("{text}");
+        }
+        Ok(text)
+    }
+
+    /// Add the server's tools to `agent`; returns how many there were.
+    pub fn register_tools(self, agent: &mut Agent) -> Result<usize> {
+        let client = Rc::new(RefCell::new(self));
+        let tools = client.borrow_mut().list_tools()?;
+        for tool in &tools {
+            let (client, name) = (client.clone(), tool.name.clone());
+            agent.register(&tool.name, &tool.description, tool.input_schema.clone(), move |args| {
+                client.borrow_mut().call_tool(&name, args)
+            });
+        }
+        Ok(tools.len())
+    }
+
+    /// Send a request and wait for its response, answering pings and
+    /// skipping notifications meanwhile.
+    pub fn request(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.send(&json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params }))?;
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            let line = match self.lines.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => bail!("MCP server '{}' did not answer {method} within {:?}", self.name, self.timeout),
+                Err(RecvTimeoutError::Disconnected) => bail!("MCP server '{}' exited", self.name),
+            };
+            let Ok(msg) = serde_json::from_str::<Value>(&line) else { continue };
+            match (&msg["method"], &msg["id"]) {
+                // A request from the server.
+                (Value::String(m), req_id) if !req_id.is_null() => {
+                    let reply = if m == "ping" {
+                        json!({ "jsonrpc": "2.0", "id": req_id, "result": {} })
+                    } else {
+                        json!({ "jsonrpc": "2.0", "id": req_id, "error": { "code": -32601, "message": format!("{m} is not supported") } })
+                    };
+                    self.send(&reply)?;
+                }
+                (Value::String(_), _) => {}
+                (_, req_id) if req_id.as_u64() == Some(id) => {
+                    if let Some(err) = msg.get("error") {
+                        bail!("MCP {method}: {} (code {})", err["message"].as_str().unwrap_or("error"), err["code"]);
+                    }
+                    return Ok(msg["result"].clone());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn send(&mut self, msg: &Value) -> Result<()> {
+        let stdin = self.stdin.as_mut().context("MCP server input is closed")?;
+        writeln!(stdin, "{msg}").and_then(|_| stdin.flush()).with_context(|| format!("MCP server '{}' stopped reading", self.name))
+    }
+}
+
+impl Drop for McpClient {
+    /// The stdio shutdown the spec describes: close the server's input,
+    /// give it a moment to exit, then kill it.
+    fn drop(&mut self) {
+        drop(self.stdin.take());
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Instant::now() < deadline {
+            if !matches!(self.child.try_wait(), Ok(None)) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
@@ -1,17 +1,76 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, mpsc};
 
-use anyhow::Result;
+use anyhow::{Context, Result, ensure};
 use metal::Buffer;
 
-use crate::gpu::Gpu;
-use crate::tensor::{TensorStore, GGML_F16, GGML_Q8_0, Q8_0_BLOCK};
+use crate::blas::{self, CpuGemm};
+use crate::choice::{Choice, Choices, Step};
+use crate::classify::ClassifierHead;
+use crate::cpu::{Q8Activations, axpy, dot, dot_q8_0, quantize_q8};
+use crate::dump::ActivationDump;
+use crate::embed::{Pooling, l2_normalize, pool};
+use crate::events::{FinishReason, GenerationEvent, Timings, Usage};
+use crate::fusion::{self, FusedWeight};
+use crate::gguf::MetaValue;
+use crate::gpu::{Gpu, OutOfMemory};
+use crate::imatrix::Imatrix;
+use crate::profile::{GpuProfile, weight_scope};
+use crate::repack::{self, RepackCache};
+use crate::pipeline::{Pipeline, Stage};
+use crate::rerank::RerankHead;
+use crate::section_cache::SectionCache;
+use crate::shard::TensorParallel;
+use crate::sampler::{Sampler, SamplerConfig, argmax};
+use crate::speculative::{DraftLength, DraftSource, MedusaHead, medusa_heads, medusa_residual, ngram_draft};
+use crate::tensor::{ggml_type_name, TensorStore, WeightBuf, GGML_F16, GGML_F32, GGML_IQ4_NL, GGML_Q2_K, GGML_Q3_K, GGML_Q8_0};
+use crate::tokenizer::detokenize;
+use crate::weights::ModelWeights;
 
 pub struct LlamaModel {
     pub arch: Arch,
+    pub weights: Arc<ModelWeights>,
     store: TensorStore,
     gpu: Gpu,
     /// Lazily-uploaded weight buffers: upload once, reuse every forward pass.
-    weight_cache: HashMap<String, Buffer>,
+    weight_cache: HashMap<String, WeightBuf>,
+    /// Weights in the repacked layout; takes precedence over `weight_cache`.
+    repacked: Option<RepackCache>,
+    /// When set, every forward pass records its intermediate activations here.
+    dump: Option<ActivationDump>,
+    /// When set, every matmul adds its input's squares here (lm_head excepted).
+    imatrix: Option<Imatrix>,
+    /// Time spent in `load`, `load_all_tensors` and `warmup`, reported in `Timings`.
+    load_ms: u128,
+    /// Heads from `load_medusa`; their weights live in `weight_cache`.
+    medusa: Option<Vec<MedusaHead>>,
+    /// While Medusa heads are loaded: the normed hidden states of the last
+    /// full forward pass, packed `[tokens, hidden]`.
+    last_hidden: Vec<f32>,
+    /// When set, large-batch F32/F16 matmuls run through Accelerate.
+    cpu_gemm: Option<CpuGemm>,
+    /// Blocks `0..gpu_layers` multiply on the GPU, the rest on the CPU; the
+    /// output head counts as block `n_layers`. Lowered when Metal runs out
+    /// of memory.
+    gpu_layers: usize,
+    /// Weight bytes the GPU may hold, below the device's own limit.
+    weight_budget: Option<u64>,
+    /// Matmul weights split across devices by `use_devices`; these never
+    /// enter `weight_cache`.
+    shards: Option<TensorParallel>,
+    /// Prompt tokens per forward pass during prefill; see `set_prefill_chunk`.
+    prefill_chunk: usize,
+    /// Blocks assigned to other devices by `use_pipeline`; their weights
+    /// never enter `weight_cache` either.
+    pipeline: Option<Pipeline>,
+    /// CPU matmuls over Q8_0 weights quantize their input to int8 first;
+    /// see `set_int8_activations`.
+    int8_activations: bool,
+    /// Stacked matmuls from `fuse_weights`, by `FusedWeight::name`. The
+    /// stack's buffer, when its block is on the GPU, is in `weight_cache`
+    /// under the same name, in place of its parts'.
+    fused: HashMap<String, FusedWeight>,
 }
 
 #[derive(Clone, Debug)]
@@ -23,48 +82,417 @@ pub struct Arch {
     pub head_dim: usize,
     pub ffn_hidden: usize,
     pub vocab_size: usize,
+    /// `{arch}.context_length`: the context the model was trained on.
+    pub ctx_train: usize,
     pub rope_base: f32,
+    pub rope_scaling: RopeScaling,
+    pub scaling: ScoreScaling,
 }
 
+/// How RoPE positions are stretched to reach past the trained context.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RopeScaling {
+    None,
+    /// Linear position interpolation: every position is divided by `factor`,
+    /// so a model trained on 4k sees 8k tokens as 4k "slots" at factor 2.
+    Linear { factor: f32 },
+    /// YaRN: high-frequency dims keep their trained rotation, low-frequency
+    /// dims are interpolated like `Linear`, with a ramp in between, and
+    /// attention is sharpened by `mscale` to compensate for the longer range.
+    Yarn(YarnParams),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct YarnParams {
+    pub factor: f32,
+    /// Context length before scaling; sets where the ramp starts and ends.
+    pub original_ctx: usize,
+    /// Dims rotating more than `beta_fast` times over `original_ctx` are extrapolated.
+    pub beta_fast: f32,
+    /// Dims rotating fewer than `beta_slow` times are fully interpolated.
+    pub beta_slow: f32,
+}
+
+impl YarnParams {
+    /// Pair-index range `[low, high]` of the interpolation ramp (ggml_rope_yarn_corr_dims).
+    fn corr_dims(&self, head_dim: usize, base: f32) -> (f32, f32) {
+        let corr_dim = |n_rot: f32| {
+            head_dim as f32 * (self.original_ctx as f32 / (n_rot * 2.0 * std::f32::consts::PI)).ln()
+                / (2.0 * base.ln())
+        };
+        let low = corr_dim(self.beta_fast).floor().max(0.0);
+        let high = corr_dim(self.beta_slow).ceil().min(head_dim as f32 - 1.0);
+        (low, high)
+    }
+}
+
+/// Architecture-specific scaling of attention scores and output logits,
+/// read from metadata: Gemma-2 softcaps both, Granite and Gemma-2 27B scale
+/// queries by something other than `1/sqrt(head_dim)`, and ALiBi (MPT,
+/// BLOOM; see `ensure_supported`) replaces RoPE with a linear distance
+/// penalty. `None` is the plain Llama behaviour.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ScoreScaling {
+    /// `{arch}.attention.scale`: multiplies `q·k` instead of `1/sqrt(head_dim)`.
+    pub query_scale: Option<f32>,
+    /// `{arch}.attn_logit_softcapping`: scores become `c * tanh(s / c)`
+    /// before the softmax.
+    pub attn_softcap: Option<f32>,
+    /// `{arch}.final_logit_softcapping`: the same on the output logits.
+    pub final_softcap: Option<f32>,
+    /// `{arch}.attention.max_alibi_bias`: no RoPE; head `h` instead adds
+    /// `alibi_slope(h) * (t - pos)` to the score of key `t`.
+    pub alibi_max_bias: Option<f32>,
+}
+
+impl ScoreScaling {
+    /// The keys under `prefix` (`general.architecture`) in `meta`. A zero
+    /// value is how some converters write "off".
+    pub fn from_metadata(meta: &BTreeMap<String, MetaValue>, prefix: &str) -> Self {
+        let get = |key: &str| {
+            meta.get(&format!("{prefix}.{key}")).and_then(|v| v.as_f64()).map(|v| v as f32).filter(|&v| v > 0.0)
+        };
+        Self {
+            query_scale: get("attention.scale"),
+            attn_softcap: get("attn_logit_softcapping"),
+            final_softcap: get("final_logit_softcapping"),
+            alibi_max_bias: get("attention.max_alibi_bias"),
+        }
+    }
+
+    /// ALiBi slope of head `h` of `n_heads`: a geometric sequence from
+    /// `2^(-max_bias / n)` over the largest power of two `n <= n_heads`,
+    /// interleaved with a half-step one for the heads past it (as in
+    /// ggml's `soft_max_ext`). `None` without ALiBi.
+    pub fn alibi_slope(&self, h: usize, n_heads: usize) -> Option<f32> {
+        let max_bias = self.alibi_max_bias?;
+        let n = 1usize << n_heads.max(1).ilog2();
+        let (m0, m1) = ((-max_bias / n as f32).exp2(), (-max_bias / 2.0 / n as f32).exp2());
+        Some(if h < n { m0.powi(h as i32 + 1) } else { m1.powi(2 * (h - n) as i32 + 1) })
+    }
+
+    /// The factor on `q·k` for heads of `head_dim`.
+    pub fn query_scale(&self, head_dim: usize) -> f32 {
+        self.query_scale.unwrap_or(1.0 / (head_dim as f32).sqrt())
+    }
+
+    /// What differs from plain Llama, e.g. `["attn softcap 50", "final softcap 30"]`.
+    pub fn active(&self) -> Vec<String> {
+        [
+            ("query scale", self.query_scale),
+            ("attn softcap", self.attn_softcap),
+            ("final softcap", self.final_softcap),
+            ("alibi max bias", self.alibi_max_bias),
+        ]
+            .into_iter()
+            .filter_map(|(name, v)| v.map(|v| format!("{name} {v}")))
+            .collect()
+    }
+}
+
+/// `cap * tanh(x / cap)` in place: smooth, and never past `±cap`.
+pub fn softcap(xs: &mut [f32], cap: f32) {
+    xs.iter_mut().for_each(|x| *x = cap * (*x / cap).tanh());
+}
+
+/// Classifier-free guidance: a second context that sees the negative prompt
+/// instead of the real one, then every generated token. Weights are shared;
+/// only the KV cache is separate.
+#[derive(Clone)]
+pub struct Guidance {
+    pub negative: Vec<u32>,
+    pub scale: f32,
+}
+
+/// Everything `generate` needs besides the prompt.
+#[derive(Clone)]
+pub struct GenerateOptions {
+    pub max_new: usize,
+    pub cfg: Option<Guidance>,
+    pub sampling: SamplerConfig,
+    /// Speculative decoding draft source; `None` decodes one token per pass.
+    pub draft: Option<DraftSource>,
+    /// Adjust the draft length to how much of each draft is accepted, up
+    /// to the source's `n_draft`; `false` always drafts `n_draft`.
+    pub adaptive_draft: bool,
+    /// Token ids that end generation besides `</s>` (id 2): end-of-turn
+    /// markers such as `<|im_end|>`, see `chat::stop_tokens`.
+    pub stop_tokens: Vec<u32>,
+    /// Write a Metal GPU trace of the first decode step to this `.gputrace`.
+    pub metal_capture: Option<std::path::PathBuf>,
+    /// Checked before every decode step; once set, generation ends with
+    /// `FinishReason::Cancelled` and the session keeps what was generated.
+    pub cancel: Option<Arc<AtomicBool>>,
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        Self {
+            max_new: 64,
+            cfg: None,
+            sampling: SamplerConfig::default(),
+            draft: None,
+            adaptive_draft: true,
+            stop_tokens: Vec::new(),
+            metal_capture: None,
+            cancel: None,
+        }
+    }
+}
+
+/// Default prompt tokens per prefill pass: large enough that each matmul
+/// stays one long dispatch, small enough that the activations and the
+/// attention scores of a pass stay a few hundred MB on long prompts.
+pub const PREFILL_CHUNK: usize = 512;
+
+/// Batches at least this long look their embeddings up on the GPU; a few
+/// rows are quicker to dequantize on the CPU than to dispatch for.
+pub const GPU_EMBED_MIN: usize = 16;
+
+/// What `load_all_tensors` did.
+pub struct LoadStats {
+    pub tensors: usize,
+    /// Of `tensors`, those viewing the mmap in place rather than copied.
+    pub mapped: usize,
+    pub bytes: u64,
+    pub ms: u128,
+    pub threads: usize,
+}
+
+/// What `warmup` did.
+pub struct WarmupStats {
+    /// Tensor bytes whose pages were touched.
+    pub bytes: u64,
+    pub ms: u128,
+}
+
+/// Beam search settings. Scores are `logprob / len^length_penalty`.
+pub struct BeamConfig {
+    pub width: usize,
+    pub length_penalty: f32,
+}
+
+/// Per-layer K/V rows, one row per position.
+///
+/// Rows are `Arc`-shared so cloning a cache (forking a beam) copies pointers,
+/// not floats: beams share their common prefix and only their own new rows
+/// are distinct allocations.
+#[derive(Clone, Default)]
 struct KvCache {
-    k: Vec<Vec<Vec<f32>>>,
-    v: Vec<Vec<Vec<f32>>>,
+    k: Vec<Vec<Arc<[f32]>>>,
+    v: Vec<Vec<Arc<[f32]>>>,
 }
 
 impl KvCache {
     fn new(n_layers: usize) -> Self {
         Self { k: vec![Vec::new(); n_layers], v: vec![Vec::new(); n_layers] }
     }
-    fn push(&mut self, layer: usize, k: Vec<f32>, v: Vec<f32>) {
-        self.k[layer].push(k);
-        self.v[layer].push(v);
+    /// Drop every position from `len` on (rejected draft tokens).
+    fn truncate(&mut self, len: usize) {
+        self.k.iter_mut().for_each(|rows| rows.truncate(len));
+        self.v.iter_mut().for_each(|rows| rows.truncate(len));
+    }
+    fn stats(&self) -> KvStats {
+        let tables = self.k.iter().chain(&self.v);
+        KvStats {
+            positions: self.k.first().map_or(0, Vec::len),
+            bytes: tables.clone().flatten().map(|row| row.len() * size_of::<f32>()).sum(),
+            slack_bytes: tables.map(|rows| (rows.capacity() - rows.len()) * size_of::<Arc<[f32]>>()).sum(),
+        }
+    }
+    fn shrink_to_fit(&mut self) {
+        self.k.iter_mut().chain(&mut self.v).for_each(Vec::shrink_to_fit);
+    }
+}
+
+/// The size of a KV cache. Every position's row is its own allocation and
+/// goes back to the allocator as soon as it is truncated away; what stays
+/// behind is the per-layer row tables' capacity, `slack_bytes`, until
+/// `KvSession::shrink_to_fit`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KvStats {
+    pub positions: usize,
+    /// K and V rows of every layer.
+    pub bytes: usize,
+    pub slack_bytes: usize,
+}
+
+/// `len` consecutive rows of a batch that belong to one sequence, starting
+/// at position `pos` of its cache.
+struct Span<'a> {
+    pos: usize,
+    len: usize,
+    kv: &'a mut KvCache,
+}
+
+/// K/V rows kept between `LlamaModel::generate_cached` calls, together with
+/// the tokens they were computed for. `Default` is an empty session that
+/// takes its shape from the first model it is used with.
+#[derive(Clone, Default)]
+pub struct KvSession {
+    kv: KvCache,
+    tokens: Vec<u32>,
+}
+
+impl KvSession {
+    pub fn new(model: &LlamaModel) -> Self {
+        Self { kv: KvCache::new(model.arch.n_layers), tokens: Vec::new() }
+    }
+
+    /// Tokens whose K/V rows are cached, in position order.
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    pub fn clear(&mut self) {
+        self.kv.truncate(0);
+        self.tokens.clear();
+    }
+
+    pub fn stats(&self) -> KvStats {
+        self.kv.stats()
+    }
+
+    /// Release the capacity left by positions that were truncated away (a
+    /// shorter request after a long one). Returns the bytes freed.
+    pub fn shrink_to_fit(&mut self) -> usize {
+        let slack = |s: &Self| s.kv.stats().slack_bytes + (s.tokens.capacity() - s.tokens.len()) * size_of::<u32>();
+        let before = slack(self);
+        self.kv.shrink_to_fit();
+        self.tokens.shrink_to_fit();
+        before - slack(self)
+    }
+
+    /// Put `section`'s rows after this session's, as if its tokens came
+    /// next: K rows are rotated forward to their new positions, but still
+    /// attended only within `section` (see `section_cache`). ALiBi keys
+    /// carry no position, so they are kept as they are.
+    pub fn append(&mut self, section: &KvSession, arch: &Arch) {
+        if self.kv.k.is_empty() {
+            self.kv = KvCache::new(section.kv.k.len());
+        }
+        let shift = self.kv.stats().positions;
+        for (rows, theirs) in self.kv.k.iter_mut().zip(&section.kv.k) {
+            rows.extend(theirs.iter().map(|k| {
+                if shift == 0 || arch.scaling.alibi_max_bias.is_some() {
+                    return k.clone();
+                }
+                let mut k = k.to_vec();
+                shift_rope(&mut k, arch.n_kv_heads, arch.head_dim, shift, arch.rope_base, arch.rope_scaling);
+                k.into()
+            }));
+        }
+        for (rows, theirs) in self.kv.v.iter_mut().zip(&section.kv.v) {
+            rows.extend(theirs.iter().cloned());
+        }
+        self.tokens.extend(&section.tokens);
+    }
+
+    fn common_prefix(&self, tokens: &[u32]) -> usize {
+        self.tokens.iter().zip(tokens).take_while(|(a, b)| a == b).count()
+    }
+}
+
+/// What `chat::Conversation` and `run` generate with: a `LlamaModel` in this
+/// process, or a `daemon::DaemonClient` whose daemon holds one (and keeps
+/// the K/V rows on its side, leaving `session` untouched).
+pub trait Generator {
+    /// `LlamaModel::generate_cached`.
+    fn generate_cached(
+        &mut self,
+        session: &mut KvSession,
+        tokens: &[u32],
+        opts: &GenerateOptions,
+        vocab: &[String],
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<usize>;
+}
+
+impl Generator for LlamaModel {
+    fn generate_cached(
+        &mut self,
+        session: &mut KvSession,
+        tokens: &[u32],
+        opts: &GenerateOptions,
+        vocab: &[String],
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<usize> {
+        LlamaModel::generate_cached(self, session, tokens, opts, vocab, on_event)
+    }
+}
+
+/// One beam-search hypothesis: its tokens and their log-probs, the
+/// running score, and its own KV cache and next-token logits.
+struct Beam {
+    tokens: Vec<u32>,
+    logprobs: Vec<f32>,
+    logprob: f32,
+    kv: KvCache,
+    logits: Vec<f32>,
+}
+
+/// Live state of the CFG negative context during generation.
+struct NegativeContext {
+    kv: KvCache,
+    pos: usize,
+    logits: Vec<f32>,
+    scale: f32,
+}
+
+/// Refuse architectures whose blocks `LlamaModel` cannot run. MPT and BLOOM
+/// get their ALiBi bias (`ScoreScaling`), but also need LayerNorm with bias
+/// and an ungated GELU FFN, which are not implemented; loading them as
+/// Llama blocks would produce garbage rather than an error.
+pub fn ensure_supported(arch: &str) -> Result<()> {
+    if matches!(arch, "mpt" | "bloom") {
+        anyhow::bail!("{arch} models are not supported: their LayerNorm and ungated GELU FFN blocks are not implemented");
     }
+    Ok(())
 }
 
 impl LlamaModel {
     pub fn load(path: &str) -> Result<Self> {
+        let t0 = std::time::Instant::now();
         let gpu = Gpu::new()?;
         // Pass gpu.device so TensorStore can (optionally) create the mmap buffer.
         let store = TensorStore::open(path, &gpu.device)?;
 
-        let mut container = gguf_rs::get_gguf_container_array_size(path, 0)?;
-        let model = container.decode()?;
-        let meta = model.metadata();
+        let meta = &store.metadata;
+        // Hyperparameters live under the architecture name: llama.*, qwen2.*, ...
+        let prefix = meta.get("general.architecture").and_then(|v| v.as_str()).unwrap_or("llama");
+        ensure_supported(prefix)?;
 
         let get_u = |key: &str, default: usize| {
-            meta.get(key).and_then(|v| v.as_u64()).map(|v| v as usize).unwrap_or(default)
+            meta.get(&format!("{prefix}.{key}")).and_then(|v| v.as_u64()).map(|v| v as usize).unwrap_or(default)
         };
         let get_f = |key: &str, default: f32| {
-            meta.get(key).and_then(|v| v.as_f64()).map(|v| v as f32).unwrap_or(default)
+            meta.get(&format!("{prefix}.{key}")).and_then(|v| v.as_f64()).map(|v| v as f32).unwrap_or(default)
         };
 
-        let hidden     = get_u("llama.embedding_length", 4096);
-        let n_layers   = get_u("llama.block_count", 32);
-        let n_heads    = get_u("llama.attention.head_count", 32);
-        let n_kv_heads = get_u("llama.attention.head_count_kv", n_heads);
-        let ffn_hidden = get_u("llama.feed_forward_length", hidden * 4);
-        let vocab_size = get_u("llama.vocab_size", 32000);
-        let rope_base  = get_f("llama.rope.freq_base", 10000.0);
+        let hidden     = get_u("embedding_length", 4096);
+        let n_layers   = get_u("block_count", 32);
+        let n_heads    = get_u("attention.head_count", 32);
+        let n_kv_heads = get_u("attention.head_count_kv", n_heads);
+        let ffn_hidden = get_u("feed_forward_length", hidden * 4);
+        // The embedding table is authoritative; many GGUFs omit {arch}.vocab_size.
+        let vocab_size = store.index.get("token_embd.weight")
+            .and_then(|m| m.shape.get(1).copied())
+            .map(|v| v as usize)
+            .unwrap_or_else(|| get_u("vocab_size", 32000));
+        let ctx_train  = get_u("context_length", 4096);
+        let rope_base  = get_f("rope.freq_base", 10000.0);
+        let scaling_type = meta.get(&format!("{prefix}.rope.scaling.type")).and_then(|v| v.as_str());
+        let rope_scaling = match scaling_type {
+            Some("linear") => RopeScaling::Linear { factor: get_f("rope.scaling.factor", 1.0) },
+            Some("yarn") => RopeScaling::Yarn(YarnParams {
+                factor: get_f("rope.scaling.factor", 1.0),
+                original_ctx: get_u("rope.scaling.original_context_length", ctx_train),
+                beta_fast: get_f("rope.scaling.yarn_beta_fast", 32.0),
+                beta_slow: get_f("rope.scaling.yarn_beta_slow", 1.0),
+            }),
+            _ => RopeScaling::None,
+        };
+        let scaling = ScoreScaling::from_metadata(meta, prefix);
 
         let head_dim = store.index.get("blk.0.attn_q.weight")
             .and_then(|m| m.shape.get(1).copied())
@@ -72,186 +500,1551 @@ impl LlamaModel {
             .filter(|&d| d > 0)
             .unwrap_or(hidden / n_heads);
 
+        let arch = Arch {
+            hidden, n_layers, n_heads, n_kv_heads, head_dim, ffn_hidden, vocab_size,
+            ctx_train, rope_base, rope_scaling, scaling,
+        };
+        let weights = Arc::new(ModelWeights::from_index(&store.index, &arch)?);
+
         Ok(Self {
-            arch: Arch { hidden, n_layers, n_heads, n_kv_heads, head_dim, ffn_hidden, vocab_size, rope_base },
+            arch,
+            weights,
             store,
             gpu,
             weight_cache: HashMap::new(),
+            repacked: None,
+            dump: None,
+            imatrix: None,
+            load_ms: t0.elapsed().as_millis(),
+            medusa: None,
+            last_hidden: Vec::new(),
+            cpu_gemm: None,
+            gpu_layers: usize::MAX,
+            weight_budget: None,
+            shards: None,
+            prefill_chunk: PREFILL_CHUNK,
+            pipeline: None,
+            int8_activations: false,
+            fused: HashMap::new(),
         })
     }
 
-    pub fn generate(&mut self, tokens: &[u32], max_new: usize, vocab: &[String]) -> Result<()> {
+    /// End-of-sequence and end-of-turn ids the GGUF declares
+    /// (`tokenizer.ggml.eos_token_id`, `tokenizer.ggml.eot_token_id`).
+    pub fn declared_stop_tokens(&self) -> Vec<u32> {
+        ["tokenizer.ggml.eos_token_id", "tokenizer.ggml.eot_token_id"]
+            .iter()
+            .filter_map(|k| self.store.metadata.get(*k).and_then(|v| v.as_u64()))
+            .map(|id| id as u32)
+            .collect()
+    }
+
+    /// Load Medusa heads from a companion GGUF (see
+    /// `speculative::medusa_heads` for its layout) so that
+    /// `DraftSource::Medusa` can draft from them. Returns the head count.
+    pub fn load_medusa(&mut self, path: &str) -> Result<usize> {
+        let store = TensorStore::open(path, &self.gpu.device)?;
+        let heads = medusa_heads(&store, self.arch.hidden, self.arch.vocab_size)
+            .with_context(|| format!("load Medusa heads from {path}"))?;
+        for head in &heads {
+            for name in std::iter::once(&head.fc).chain(head.output.as_ref().map(|(n, _)| n)) {
+                let buf = store.weight_buffer(&self.gpu, store.get(name)?)?;
+                self.weight_cache.insert(name.clone(), buf);
+            }
+        }
+        let n = heads.len();
+        self.medusa = Some(heads);
+        Ok(n)
+    }
+
+    /// Mark every kernel dispatch with an os_signpost interval, for
+    /// Instruments.
+    pub fn enable_signposts(&mut self) {
+        self.gpu.enable_signposts();
+    }
+
+    /// Run F32 and F16 matmuls over `min_batch` or more rows (prefill, not
+    /// decode) through Accelerate instead of Metal. Needs a macOS build with
+    /// the `accelerate` feature.
+    pub fn use_accelerate(&mut self, min_batch: usize) -> Result<()> {
+        anyhow::ensure!(blas::ACCELERATE, "built without Accelerate: rebuild on macOS with --features accelerate");
+        self.cpu_gemm = Some(CpuGemm::new(min_batch.max(1)));
+        Ok(())
+    }
+
+    /// Prefill prompts `n` tokens per forward pass. A pass holds activations
+    /// for all its tokens and attention scores against the whole cache, so
+    /// this bounds prefill memory however long the prompt; 1 is token by token.
+    pub fn set_prefill_chunk(&mut self, n: usize) {
+        self.prefill_chunk = n.max(1);
+    }
+
+    /// Blocks on the CPU multiply Q8_0 weights by int8 activations: each
+    /// input row is quantized per 32 values (`cpu::quantize_q8`) and dotted
+    /// in integers, `sdot` on Arm. Much faster than widening every weight
+    /// row to f32, at the cost of rounding the activations to 8 bits as
+    /// llama.cpp's CPU backend does. Other dtypes and the GPU are unchanged.
+    pub fn set_int8_activations(&mut self, on: bool) {
+        self.int8_activations = on;
+    }
+
+    /// Keep only blocks `0..n` (and the output head when `n > n_layers`) on
+    /// the GPU; the rest multiply on the CPU, dequantizing rows from the mmap
+    /// as they go. Lowering it frees the weights already uploaded above `n`.
+    pub fn set_gpu_layers(&mut self, n: usize) {
+        self.gpu_layers = n;
+        let n_layers = self.arch.n_layers;
+        self.weight_cache.retain(|name, _| weight_scope(name).0.unwrap_or(n_layers) < n);
+    }
+
+    /// Blocks on the GPU, `n_layers + 1` when the output head is too.
+    pub fn gpu_layers(&self) -> usize {
+        self.gpu_layers.min(self.arch.n_layers + 1)
+    }
+
+    /// Split every matmul weight across the Metal devices at `devices`
+    /// (indices into `sysinfo`'s list) and run those matmuls on all of them
+    /// at once; see `shard`. Weights already uploaded here are freed.
+    /// Returns how many were split.
+    pub fn use_devices(&mut self, devices: &[usize]) -> Result<usize> {
+        ensure!(self.pipeline.is_none(), "a pipelined model cannot also be split across devices");
+        let mut tp = TensorParallel::new(devices)?;
+        let weights = self.weights.clone();
+        let mut names: Vec<&str> = weights.matmul_weights().iter().map(|w| w.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        for name in &names {
+            tp.shard(&self.store, name)?;
+            self.weight_cache.remove(*name);
+        }
+        self.shards = Some(tp);
+        Ok(names.len())
+    }
+
+    /// Weight bytes on each `use_devices` device, in its order.
+    pub fn device_weight_bytes(&self) -> Vec<u64> {
+        self.shards.as_ref().map_or_else(Vec::new, |tp| (0..tp.devices()).map(|d| tp.bytes_on(d)).collect())
+    }
+
+    /// Run contiguous runs of blocks on the Metal devices at `devices`,
+    /// then on the `llmetal worker`s at `workers` (`HOST:PORT`), in order,
+    /// handing prompts from one to the next in micro-batches of
+    /// `micro_batch` rows; see `pipeline`. The output head stays here.
+    ///
+    /// A worker keeps one sequence's K/V rows, so with workers only plain
+    /// and speculative decoding work: no beams, guidance or batches.
+    pub fn use_pipeline(&mut self, devices: &[usize], workers: &[String], micro_batch: usize) -> Result<()> {
+        ensure!(self.shards.is_none(), "a model split across devices cannot also be pipelined");
+        let mut pipeline = Pipeline::new(devices, workers, self.arch.n_layers, micro_batch)?;
+        pipeline.load(&self.store, &self.arch, &self.weights)?;
+        self.weight_cache.retain(|name, _| weight_scope(name).0.is_none());
+        self.pipeline = Some(pipeline);
+        Ok(())
+    }
+
+    /// The stages of `use_pipeline`, empty without one.
+    pub fn pipeline_stages(&self) -> &[Stage] {
+        self.pipeline.as_ref().map_or(&[], Pipeline::stages)
+    }
+
+    /// Treat the GPU as full once `bytes` of weights are uploaded, as if
+    /// Metal had refused the next one.
+    pub fn limit_gpu_memory(&mut self, bytes: u64) {
+        self.weight_budget = Some(bytes);
+    }
+
+    /// Tune the threadgroup width of each matmul shape on its first
+    /// dispatches; see `autotune`.
+    pub fn enable_autotune(&mut self) {
+        self.gpu.enable_autotune();
+    }
+
+    /// Threadgroup widths picked per matmul shape, tuned now or saved.
+    pub fn autotune_picks(&self) -> std::collections::BTreeMap<String, usize> {
+        self.gpu.autotune_picks()
+    }
+
+    /// Time every kernel dispatch on the GPU from now on; `gpu_profile`
+    /// has the totals by layer and op.
+    pub fn enable_gpu_profile(&mut self) -> Result<()> {
+        self.gpu.enable_timestamps()
+    }
+
+    pub fn gpu_profile(&self) -> GpuProfile {
+        self.gpu.profile()
+    }
+
+    /// Start accumulating an importance matrix over every forward pass. Call
+    /// `Imatrix::end_chunk` on `imatrix_mut` between calibration chunks.
+    pub fn enable_imatrix(&mut self) {
+        self.imatrix = Some(Imatrix::default());
+    }
+
+    pub fn imatrix_mut(&mut self) -> Option<&mut Imatrix> {
+        self.imatrix.as_mut()
+    }
+
+    pub fn take_imatrix(&mut self) -> Option<Imatrix> {
+        self.imatrix.take()
+    }
+
+    /// Start recording intermediate activations on every forward pass.
+    pub fn enable_activation_dump(&mut self) {
+        self.dump = Some(ActivationDump::default());
+    }
+
+    /// Stop recording and hand back what was recorded.
+    pub fn take_activation_dump(&mut self) -> Option<ActivationDump> {
+        self.dump.take()
+    }
+
+    /// Sentence embedding for `tokens`: the final normed hidden states of one
+    /// batched pass on a fresh cache, pooled and L2-normalised.
+    pub fn embed_tokens(&mut self, tokens: &[u32], pooling: Pooling) -> Result<Vec<f32>> {
+        ensure!(!tokens.is_empty(), "cannot embed an empty token sequence");
         let mut kv = KvCache::new(self.arch.n_layers);
-        let mut last = 0u32;
-        let mut generated: Vec<u32> = Vec::new();
+        let hidden = self.forward_hidden(tokens, 0, &mut kv, self.arch.n_layers)?;
+        let mut v = pool(&hidden, self.arch.hidden, pooling);
+        l2_normalize(&mut v);
+        Ok(v)
+    }
 
-        // Prefill
+    /// The reranking head this GGUF supports: its `cls.output` classifier if
+    /// present, else the "yes"/"no" logits of its LM head.
+    pub fn rerank_head(&self, vocab: &[String]) -> Result<RerankHead> {
+        if self.store.index.contains_key("cls.output.weight") {
+            let weight = self.store.dequant("cls.output.weight")?;
+            ensure!(weight.len() % self.arch.hidden == 0, "cls.output.weight does not match hidden size");
+            let bias = match self.store.index.contains_key("cls.output.bias") {
+                true => Some(self.store.dequant("cls.output.bias")?),
+                false => None,
+            };
+            return Ok(RerankHead::Classifier { weight, bias });
+        }
+        let yes = crate::rerank::find_token(vocab, &["yes", "Ġyes", "▁yes"]);
+        let no = crate::rerank::find_token(vocab, &["no", "Ġno", "▁no"]);
+        match (yes, no) {
+            (Some(yes), Some(no)) => Ok(RerankHead::YesNo { yes, no }),
+            _ => anyhow::bail!("not a reranker: no cls.output.weight and no yes/no tokens in the vocab"),
+        }
+    }
+
+    /// Relevance logit of an already-formatted query+document sequence.
+    pub fn rerank_logit(&mut self, tokens: &[u32], head: &RerankHead) -> Result<f32> {
+        ensure!(!tokens.is_empty(), "cannot score an empty sequence");
+        let mut kv = KvCache::new(self.arch.n_layers);
+        let hidden = self.forward_hidden(tokens, 0, &mut kv, self.arch.n_layers)?;
+        let last = &hidden[hidden.len() - self.arch.hidden..];
+        Ok(match head {
+            RerankHead::Classifier { weight, bias } => {
+                dot(&weight[..self.arch.hidden], last) + bias.as_ref().map_or(0.0, |b| b[0])
+            }
+            RerankHead::YesNo { yes, no } => {
+                let logits = self.lm_head(last, 1)?.pop().unwrap();
+                logits[*yes as usize] - logits[*no as usize]
+            }
+        })
+    }
+
+    /// The sequence-classification head: `cls.output` with its labels.
+    pub fn classifier_head(&self) -> Result<ClassifierHead> {
+        let meta = self.store.index.get("cls.output.weight").context("not a classifier: no cls.output.weight")?;
+        ensure!(meta.cols() == self.arch.hidden, "cls.output.weight does not match hidden size");
+        let bias = match self.store.index.contains_key("cls.output.bias") {
+            true => Some(self.store.dequant("cls.output.bias")?),
+            false => None,
+        };
+        let labels = crate::classify::labels(&self.store.metadata, meta.rows());
+        Ok(ClassifierHead { weight: self.store.dequant("cls.output.weight")?, bias, labels })
+    }
+
+    /// One logit per class of `tokens`, from the last token's hidden state.
+    pub fn class_logits(&mut self, tokens: &[u32], head: &ClassifierHead) -> Result<Vec<f32>> {
+        ensure!(!tokens.is_empty(), "cannot classify an empty sequence");
+        let mut kv = KvCache::new(self.arch.n_layers);
+        let hidden = self.forward_hidden(tokens, 0, &mut kv, self.arch.n_layers)?;
+        Ok(head.logits(&hidden[hidden.len() - self.arch.hidden..]))
+    }
+
+    /// Greedy decoding after `tokens` that can only produce one of
+    /// `choices`; see `choice`. `stop_tokens` may end a candidate that is a
+    /// prefix of another.
+    pub fn choose(&mut self, tokens: &[u32], choices: &Choices, stop_tokens: &[u32]) -> Result<Choice> {
+        ensure!(!tokens.is_empty(), "cannot choose without a prompt");
+        let mut kv = KvCache::new(self.arch.n_layers);
+        let mut logits = self.prefill(tokens, 0, &mut kv, &mut |_| {})?;
+        let (mut picked, mut total) = (Vec::new(), 0.0);
+        loop {
+            log_softmax(&mut logits);
+            match choices.step(&picked, &logits, stop_tokens) {
+                Step::Done(index) => return Ok(Choice { index, logprob: total }),
+                Step::Token { id, logprob } => {
+                    picked.push(id);
+                    total += logprob;
+                    logits = self.forward(id, tokens.len() + picked.len() - 1, &mut kv)?;
+                }
+            }
+        }
+    }
+
+    /// Run `tokens` from position 0 in batched passes (`set_prefill_chunk`
+    /// tokens each) on a fresh cache and return the logits after every position.
+    pub fn evaluate(&mut self, tokens: &[u32]) -> Result<Vec<Vec<f32>>> {
+        let mut kv = KvCache::new(self.arch.n_layers);
+        let mut logits = Vec::with_capacity(tokens.len());
+        for (i, part) in tokens.chunks(self.prefill_chunk).enumerate() {
+            logits.extend(self.forward_batch(part, i * self.prefill_chunk, &mut kv)?);
+        }
+        Ok(logits)
+    }
+
+    /// Identifies the GGUF this model came from (`TensorStore::model_key`),
+    /// as the repack and section caches key on.
+    pub fn model_key(&self) -> u64 {
+        self.store.model_key()
+    }
+
+    /// The context the model was pretrained on before any RoPE extension:
+    /// `{arch}.rope.scaling.original_context_length`, else `ctx_train`.
+    pub fn rope_original_ctx(&self) -> usize {
+        let meta = &self.store.metadata;
+        let prefix = meta.get("general.architecture").and_then(|v| v.as_str()).unwrap_or("llama");
+        meta.get(&format!("{prefix}.rope.scaling.original_context_length"))
+            .and_then(|v| v.as_u64())
+            .map_or(self.arch.ctx_train, |v| v as usize)
+    }
+
+    /// Lay `sections` back to back at the front of `session`, each from
+    /// `cache` or prefilled on its own (and cached) on a miss. Returns the
+    /// positions they cover; a prompt that starts with their tokens then
+    /// prefills only the rest. A session that already starts with exactly
+    /// these tokens is left alone, since its rows saw the whole context.
+    pub fn stitch_sections(
+        &mut self,
+        cache: &mut SectionCache,
+        session: &mut KvSession,
+        sections: &[Vec<u32>],
+    ) -> Result<usize> {
+        let all = sections.concat();
+        if session.kv.k.len() == self.arch.n_layers && session.common_prefix(&all) == all.len() {
+            return Ok(all.len());
+        }
+        let model = self.model_key();
+        let mut stitched = KvSession::new(self);
+        for tokens in sections.iter().filter(|t| !t.is_empty()) {
+            let section = match cache.get(model, tokens) {
+                Some(section) => section,
+                None => {
+                    let mut section = KvSession::new(self);
+                    self.prefill(tokens, 0, &mut section.kv, &mut |_| {})?;
+                    self.last_hidden.clear();
+                    section.tokens = tokens.clone();
+                    cache.insert(model, section)
+                }
+            };
+            stitched.append(&section, &self.arch);
+        }
+        *session = stitched;
+        Ok(all.len())
+    }
+
+    /// Serve Q8_0 matmul weights from the on-disk repack cache, building it
+    /// first if this model has none yet (or an unreadable one). Returns the
+    /// cache path and whether it was built on this call.
+    pub fn use_repack_cache(&mut self) -> Result<(std::path::PathBuf, bool)> {
+        let key = self.model_key();
+        let path = repack::cache_path(key).context("no cache directory: set LLMETAL_CACHE_DIR or HOME")?;
+        if path.exists() {
+            match RepackCache::open(&path, key, &self.gpu.device) {
+                Ok(cache) => {
+                    self.repacked = Some(cache);
+                    return Ok((path, false));
+                }
+                Err(e) => eprintln!("repack cache unusable, rebuilding: {e:#}"),
+            }
+        }
+
+        let weights = self.weights.clone();
+        let mut names: Vec<&str> = weights
+            .matmul_weights()
+            .into_iter()
+            .filter(|w| w.kind == GGML_Q8_0)
+            .map(|w| w.name.as_str())
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        let tensors = names
+            .iter()
+            .map(|&n| Ok((n, self.store.get(n)?)))
+            .collect::<Result<Vec<_>>>()?;
+        repack::write_cache(&path, key, &tensors)?;
+        self.repacked = Some(RepackCache::open(&path, key, &self.gpu.device)?);
+        Ok((path, true))
+    }
+
+    /// Stack each block's `attn_q`/`attn_k`/`attn_v` and `ffn_gate`/`ffn_up`
+    /// into one matmul each; see `fusion`. Blocks on the GPU have the stack
+    /// uploaded now and their separate buffers freed; a stack that does not
+    /// fit the weight budget stays split. Matrices of mixed dtypes, those the
+    /// repack cache serves and F32/F16 ones under `use_accelerate` are left
+    /// alone. Returns how many stacks there are.
+    pub fn fuse_weights(&mut self) -> Result<usize> {
+        ensure!(
+            self.shards.is_none() && self.pipeline.is_none(),
+            "weights split across devices or pipeline stages cannot be fused"
+        );
+        let weights = self.weights.clone();
+        for (layer, w) in weights.layers.iter().enumerate() {
+            for f in fusion::layer_fusions(layer, w) {
+                let repacked = self.repacked.as_ref().is_some_and(|r| f.parts.iter().any(|p| r.index.contains_key(&p.name)));
+                let accelerated = self.cpu_gemm.is_some() && matches!(f.kind, GGML_F32 | GGML_F16);
+                if repacked || accelerated || self.fused.contains_key(&f.name) {
+                    continue;
+                }
+                if self.on_gpu(&f.name) {
+                    let bytes = self.fused_bytes(&f)?;
+                    let freed: u64 = f.parts.iter().filter_map(|p| self.weight_cache.get(&p.name)).map(|w| w.buf.length()).sum();
+                    let after = self.cached_weight_bytes() - freed + bytes.len() as u64;
+                    if self.weight_budget.is_some_and(|budget| after > budget) {
+                        continue;
+                    }
+                    let Ok(buf) = self.gpu.upload_weight(&bytes) else { continue };
+                    for p in &f.parts {
+                        self.weight_cache.remove(&p.name);
+                    }
+                    self.weight_cache.insert(f.name.clone(), WeightBuf::copied(buf));
+                }
+                self.fused.insert(f.name.clone(), f);
+            }
+        }
+        Ok(self.fused.len())
+    }
+
+    /// Upload every matmul weight to its Metal buffer now instead of on first use.
+    ///
+    /// Two thread pools form a pipeline: readers fault each tensor's mmap pages
+    /// in (the disk IO), uploaders wrap finished tensors in Metal buffers.
+    /// A bounded channel between them keeps reads of the next tensors running
+    /// while earlier ones are being wrapped. Q8_0 stays quantized on the GPU,
+    /// so on Apple Silicon's unified memory the buffer is a zero-copy view of
+    /// the mmap pages (see `TensorStore::weight_buffer`) and the page faults
+    /// are the whole upload; only what Metal will not wrap is copied.
+    ///
+    /// Blocks go up lowest first. If Metal runs out of memory, the block that
+    /// failed and everything above it stay on the CPU (see `set_gpu_layers`)
+    /// with a warning, rather than failing the load.
+    pub fn load_all_tensors(&mut self, threads: usize) -> Result<LoadStats> {
         let t0 = std::time::Instant::now();
-        for (pos, &tok) in tokens.iter().enumerate() {
-            let logits = self.forward(tok, pos, &mut kv)?;
-            last = argmax(&logits);
-            if pos + 1 == tokens.len() {
-                print_token(last, vocab);
-                generated.push(last);
+        let threads = threads.max(1);
+        let weights = self.weights.clone();
+        let mut names: Vec<&str> = weights.matmul_weights().iter().map(|w| w.name.as_str()).collect();
+        names.sort_unstable();
+        names.dedup();
+        let repacked = self.repacked.as_ref().map(|r| &r.index);
+        let sharded = self.shards.as_ref();
+        let stacked: Vec<&str> = self.fused.values().flat_map(|f| f.parts.iter().map(|p| p.name.as_str())).collect();
+        names.retain(|n| {
+            !self.weight_cache.contains_key(*n)
+                && !stacked.contains(n)
+                && !repacked.is_some_and(|r| r.contains_key(*n))
+                && !sharded.is_some_and(|tp| tp.contains(n))
+                && self.pipeline.as_ref().is_none_or(|p| p.stage_of(n).is_none())
+                && self.on_gpu(n)
+        });
+        // Lowest blocks first, so running out of memory leaves a prefix.
+        let n_layers = self.arch.n_layers;
+        names.sort_by_key(|n| weight_scope(n).0.unwrap_or(n_layers));
+
+        let (store, gpu) = (&self.store, &self.gpu);
+        let budget = self.weight_budget;
+        let used = AtomicU64::new(self.cached_weight_bytes());
+        let next = AtomicUsize::new(0);
+        let (tx, rx) = mpsc::sync_channel::<(&str, &[u8])>(threads * 2);
+        let rx = Mutex::new(rx);
+
+        let uploaded = std::thread::scope(|s| -> Result<Vec<(String, Result<WeightBuf, OutOfMemory>)>> {
+            let readers: Vec<_> = (0..threads)
+                .map(|_| {
+                    let tx = tx.clone();
+                    let (next, names) = (&next, &names);
+                    s.spawn(move || -> Result<()> {
+                        while let Some(&name) = names.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            let bytes = store.get(name)?;
+                            // One read per page is enough to fault it in.
+                            let touched = bytes.iter().step_by(4096).fold(0u8, |a, &b| a ^ b);
+                            std::hint::black_box(touched);
+                            if tx.send((name, bytes)).is_err() {
+                                break;
+                            }
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            drop(tx);
+            let uploaders: Vec<_> = (0..threads)
+                .map(|_| {
+                    s.spawn(|| {
+                        let mut out = Vec::new();
+                        loop {
+                            let msg = rx.lock().unwrap().recv();
+                            let Ok((name, bytes)) = msg else { break };
+                            let len = bytes.len() as u64;
+                            let buf = match budget {
+                                Some(budget) if used.fetch_add(len, Ordering::Relaxed) + len > budget => {
+                                    let allocated = used.fetch_sub(len, Ordering::Relaxed) - len;
+                                    Err(OutOfMemory { requested: len, allocated, budget })
+                                }
+                                _ => store.weight_buffer(gpu, bytes),
+                            };
+                            out.push((name.to_string(), buf));
+                        }
+                        out
+                    })
+                })
+                .collect();
+            for r in readers {
+                r.join().map_err(|_| anyhow::anyhow!("tensor reader thread panicked"))??;
+            }
+            let mut all = Vec::new();
+            for u in uploaders {
+                all.extend(u.join().map_err(|_| anyhow::anyhow!("tensor upload thread panicked"))?);
+            }
+            Ok(all)
+        })?;
+
+        drop(rx);
+        let first_failure = uploaded
+            .iter()
+            .filter_map(|(name, buf)| buf.as_ref().err().map(|oom| (weight_scope(name).0.unwrap_or(n_layers), *oom)))
+            .min_by_key(|&(layer, _)| layer);
+        let mut bytes = 0;
+        let mut tensors = 0;
+        for (name, buf) in uploaded {
+            if let Ok(buf) = buf {
+                bytes += self.store.meta(&name)?.byte_size;
+                tensors += 1;
+                self.weight_cache.insert(name, buf);
             }
         }
-        let prefill_ms = t0.elapsed().as_millis();
-        let n_prompt = tokens.len();
-        eprintln!(
-            "\nprefill: {n_prompt} tokens in {prefill_ms}ms  ({:.1} t/s)",
-            n_prompt as f64 / (prefill_ms as f64 / 1000.0)
+        if let Some((layer, oom)) = first_failure {
+            self.offload_from(layer, &oom);
+            bytes = self.cached_weight_bytes();
+            tensors = self.weight_cache.len();
+        }
+        let mapped = self.weight_cache.values().filter(|w| w.is_mapped()).count();
+        let ms = t0.elapsed().as_millis();
+        self.load_ms += ms;
+        Ok(LoadStats { tensors, mapped, bytes, ms, threads })
+    }
+
+    /// Get the first real token off to full speed: touch every tensor's mmap
+    /// pages (norms and embeddings included, which `load_all_tensors` does
+    /// not upload) and run a throwaway two-token prefill and one decode step,
+    /// so each kernel's first dispatch and the GPU's first touch of every
+    /// weight happen now. The importance matrix and activation dump, when
+    /// enabled, do not see the throwaway pass.
+    pub fn warmup(&mut self) -> Result<WarmupStats> {
+        let t0 = std::time::Instant::now();
+        let index = &self.store.index;
+        let mut names: Vec<&String> = index.keys().collect();
+        // File order, so the kernel's readahead helps.
+        names.sort_unstable_by_key(|n| index[*n].file_offset);
+        let mut bytes = 0;
+        let mut touched = 0u8;
+        for name in names {
+            let data = self.store.get(name)?;
+            touched ^= data.iter().step_by(crate::gpu::PAGE).fold(0u8, |a, &b| a ^ b);
+            bytes += data.len() as u64;
+        }
+        std::hint::black_box(touched);
+
+        let (imatrix, dump) = (self.imatrix.take(), self.dump.take());
+        let mut kv = KvCache::new(self.arch.n_layers);
+        // Token 1 is BOS in llama vocabularies; any id would do.
+        let passed = self.forward_batch(&[1, 1], 0, &mut kv).and_then(|_| self.forward(1, 2, &mut kv));
+        (self.imatrix, self.dump) = (imatrix, dump);
+        passed.context("warm-up pass")?;
+        let ms = t0.elapsed().as_millis();
+        self.load_ms += ms;
+        Ok(WarmupStats { bytes, ms })
+    }
+
+    /// Greedy generation. Every prefill summary, sampled token, and the final
+    /// timings are reported through `on_event`; nothing is printed here.
+    ///
+    /// With `opts.draft`, each step drafts tokens (from the context, an
+    /// early exit of this model or its Medusa heads) and checks them in one
+    /// batched pass. The sampler still picks every token from the target
+    /// logits, so the output matches plain decoding exactly.
+    pub fn generate(
+        &mut self,
+        tokens: &[u32],
+        opts: &GenerateOptions,
+        vocab: &[String],
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<()> {
+        let mut session = KvSession::new(self);
+        self.generate_cached(&mut session, tokens, opts, vocab, on_event)?;
+        Ok(())
+    }
+
+    /// `generate`, keeping K/V rows in `session` between calls. Only the part
+    /// of `tokens` after its longest common prefix with the session's tokens
+    /// is prefilled, so a multi-turn chat pays for each new turn, not the
+    /// whole history. Returns how many prompt tokens were reused.
+    pub fn generate_cached(
+        &mut self,
+        session: &mut KvSession,
+        tokens: &[u32],
+        opts: &GenerateOptions,
+        vocab: &[String],
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<usize> {
+        ensure!(!tokens.is_empty(), "cannot generate from an empty prompt");
+        ensure!(
+            opts.cfg.is_none() || opts.draft.is_none(),
+            "speculative decoding cannot be combined with classifier-free guidance"
         );
+        if let Some(DraftSource::EarlyExit(e)) = opts.draft {
+            ensure!(
+                (1..self.arch.n_layers).contains(&e.layers),
+                "early-exit draft needs 1..{} layers, got {}",
+                self.arch.n_layers,
+                e.layers
+            );
+        }
+        if let Some(DraftSource::Medusa(_)) = opts.draft {
+            ensure!(self.medusa.is_some(), "Medusa drafting needs heads: call load_medusa first");
+        }
+        if session.kv.k.len() != self.arch.n_layers {
+            *session = KvSession::new(self);
+        }
+        // At least the last prompt token is always run: its logits start decoding.
+        let reused = session.common_prefix(tokens).min(tokens.len() - 1);
+        let kv = &mut session.kv;
+        kv.truncate(reused);
+        let mut sampler = Sampler::new(opts.sampling.clone(), vocab).with_stop_tokens(&opts.stop_tokens);
+        let mut context: Vec<u32> = tokens.to_vec();
+        let mut timings = Timings { load_ms: self.load_ms, ..Timings::default() };
+
+        // Prefill
+        let t0 = std::time::Instant::now();
+        let mut logits = self.prefill(&tokens[reused..], reused, kv, on_event)?;
+        // The hidden state behind `logits`, for the Medusa heads.
+        let mut hidden = std::mem::take(&mut self.last_hidden);
+        let mut negative = match &opts.cfg {
+            Some(g) => Some(self.prefill_negative(g)?),
+            None => None,
+        };
+        if let Some(neg) = &negative {
+            apply_cfg(&mut logits, &neg.logits, neg.scale);
+        }
+        timings.prefill_ms = t0.elapsed().as_millis();
+        timings.prefill_tokens = tokens.len() - reused + opts.cfg.as_ref().map_or(0, |g| g.negative.len());
+        on_event(GenerationEvent::PromptProcessed {
+            n_tokens: timings.prefill_tokens,
+            ms: timings.prefill_ms,
+        });
 
-        // Decode
+        // Decode: the first token comes from the prefill logits.
         let t1 = std::time::Instant::now();
-        let mut n_decoded = 0usize;
         let mut pos = tokens.len();
-        for _ in 0..max_new {
-            let mut logits = self.forward(last, pos, &mut kv)?;
-            apply_repetition_penalty(&mut logits, &generated, 1.3);
-            last = argmax(&logits);
-            if last == 2 { break; }   // </s> EOS
-            print_token(last, vocab);
-            generated.push(last);
-            pos += 1;
-            n_decoded += 1;
-        }
-        let decode_ms = t1.elapsed().as_millis();
-        println!();
-        if n_decoded > 0 {
-            eprintln!(
-                "decode:  {n_decoded} tokens in {decode_ms}ms  ({:.1} t/s)",
-                n_decoded as f64 / (decode_ms as f64 / 1000.0)
-            );
+        let mut reason = FinishReason::MaxTokens;
+        // Draft tokens already in the KV cache, each with the logits that
+        // follow it and the hidden state behind them.
+        let mut verified: VecDeque<(u32, Vec<f32>, Vec<f32>)> = VecDeque::new();
+        let n_draft = match opts.draft {
+            Some(DraftSource::Lookup(l)) => l.n_draft,
+            Some(DraftSource::EarlyExit(e)) => e.n_draft,
+            Some(DraftSource::Medusa(m)) => m.n_draft,
+            None => 0,
+        };
+        let mut draft_len = DraftLength::new(n_draft, opts.adaptive_draft);
+        // The size of the draft being verified, and how much of it was kept.
+        let (mut in_flight, mut kept) = (0usize, 0usize);
+        // Summed per token in microseconds: one sample is usually well under 1ms.
+        let (mut sample_us, mut detokenize_us) = (0u128, 0u128);
+        for step in 0..=opts.max_new {
+            if opts.cancel.as_ref().is_some_and(|c| c.load(Ordering::Relaxed)) {
+                reason = FinishReason::Cancelled;
+                break;
+            }
+            // The first step that runs the model is the one captured.
+            let capture = match &opts.metal_capture {
+                Some(path) if step == 1 => Some(self.gpu.begin_capture(path)?),
+                _ => None,
+            };
+            if step > 0 {
+                let last = *context.last().unwrap();
+                match verified.pop_front() {
+                    Some((draft, next, state)) if draft == last => {
+                        logits = next;
+                        hidden = state;
+                        timings.accepted_tokens += 1;
+                        kept += 1;
+                    }
+                    _ => {
+                        // The last draft is settled: fully kept, or cut at a mismatch.
+                        draft_len.observe(in_flight, kept);
+                        (in_flight, kept) = (0, 0);
+                        verified.clear();
+                        kv.truncate(pos);
+                        let n = draft_len.get().min(opts.max_new - step);
+                        let draft = match opts.draft {
+                            Some(DraftSource::Lookup(l)) => ngram_draft(&context, l.ngram_max, n),
+                            Some(DraftSource::EarlyExit(e)) => self.early_exit_draft(last, pos, kv, e.layers, n)?,
+                            Some(DraftSource::Medusa(_)) => self.medusa_draft(&hidden, n)?,
+                            None => Vec::new(),
+                        };
+                        if draft.is_empty() {
+                            logits = self.forward(last, pos, kv)?;
+                            hidden = std::mem::take(&mut self.last_hidden);
+                        } else {
+                            timings.drafted_tokens += draft.len();
+                            in_flight = draft.len();
+                            let batch: Vec<u32> = std::iter::once(last).chain(draft.iter().copied()).collect();
+                            let mut all = self.forward_batch(&batch, pos, kv)?.into_iter();
+                            logits = all.next().context("empty verification batch")?;
+                            // No rows at all unless Medusa heads are loaded.
+                            let states = std::mem::take(&mut self.last_hidden);
+                            let mut states = states
+                                .chunks_exact(self.arch.hidden)
+                                .map(<[f32]>::to_vec)
+                                .chain(std::iter::repeat_with(Vec::new));
+                            hidden = states.next().unwrap_or_default();
+                            verified.extend(draft.into_iter().zip(all).zip(states).map(|((d, l), h)| (d, l, h)));
+                        }
+                    }
+                }
+                if let Some(neg) = &mut negative {
+                    neg.logits = self.forward(last, neg.pos, &mut neg.kv)?;
+                    neg.pos += 1;
+                    apply_cfg(&mut logits, &neg.logits, neg.scale);
+                }
+                pos += 1;
+            }
+            if let Some(capture) = capture {
+                capture.end();
+            }
+            let t = std::time::Instant::now();
+            let id = sampler.sample(&mut logits, &context, context.len() - tokens.len());
+            sample_us += t.elapsed().as_micros();
+            if sampler.looping() {
+                reason = FinishReason::Loop;
+                break;
+            }
+            if id == 2 {   // </s> EOS
+                reason = FinishReason::Eos;
+                break;
+            }
+            if opts.stop_tokens.contains(&id) {
+                reason = FinishReason::Stop;
+                break;
+            }
+            let t = std::time::Instant::now();
+            let text = detokenize(id, vocab);
+            detokenize_us += t.elapsed().as_micros();
+            on_event(GenerationEvent::Token { id, text, logprob: logprob(&logits, id) });
+            context.push(id);
+            if step > 0 {
+                timings.decode_tokens += 1;
+            }
+        }
+        timings.decode_ms = t1.elapsed().as_millis();
+        timings.sample_ms = sample_us / 1000;
+        timings.detokenize_ms = detokenize_us / 1000;
+        if timings.drafted_tokens > 0 {
+            timings.draft_len = draft_len.get();
+        }
+        // Keep exactly the rows for tokens that went through the model; the
+        // final sampled token and any unverified draft rows are not among them.
+        let usage = Usage { prompt_tokens: tokens.len(), completion_tokens: context.len() - tokens.len(), cached_tokens: reused };
+        kv.truncate(pos);
+        context.truncate(pos);
+        session.tokens = context;
+        on_event(GenerationEvent::Done { reason, timings, usage });
+        Ok(reused)
+    }
+
+    /// Continuous batching over independent prompts: up to `max_batch`
+    /// sequences are in flight, and each step is one batched pass over all of
+    /// them (the whole prompt of a sequence just admitted, the last sampled
+    /// token of the rest). A finished sequence frees its slot for the next
+    /// prompt at once. Each sequence samples exactly as `generate` would, and
+    /// its events arrive tagged with its index in `prompts`.
+    pub fn generate_batch(
+        &mut self,
+        prompts: &[Vec<u32>],
+        opts: &GenerateOptions,
+        max_batch: usize,
+        vocab: &[String],
+        on_event: &mut dyn FnMut(usize, GenerationEvent),
+    ) -> Result<()> {
+        ensure!(prompts.iter().all(|p| !p.is_empty()), "cannot generate from an empty prompt");
+        ensure!(
+            opts.cfg.is_none() && opts.draft.is_none(),
+            "batched generation supports neither classifier-free guidance nor speculative decoding"
+        );
+        struct Sequence {
+            index: usize,
+            kv: KvCache,
+            context: Vec<u32>,
+            /// Tokens for the next pass: the prompt, then each sampled token.
+            pending: Vec<u32>,
+            sampler: Sampler,
+            timings: Timings,
+            decode_start: std::time::Instant,
+            sample_us: u128,
+        }
+        impl Sequence {
+            fn usage(&self, prompts: &[Vec<u32>]) -> Usage {
+                let prompt_tokens = prompts[self.index].len();
+                Usage { prompt_tokens, completion_tokens: self.context.len() - prompt_tokens, cached_tokens: 0 }
+            }
+        }
+        let mut queue = prompts.iter().enumerate();
+        let mut active: Vec<Sequence> = Vec::new();
+        loop {
+            while active.len() < max_batch.max(1)
+                && let Some((index, prompt)) = queue.next()
+            {
+                active.push(Sequence {
+                    index,
+                    kv: KvCache::new(self.arch.n_layers),
+                    context: prompt.clone(),
+                    pending: prompt.clone(),
+                    sampler: Sampler::new(opts.sampling.clone(), vocab).with_stop_tokens(&opts.stop_tokens),
+                    timings: Timings { load_ms: self.load_ms, ..Timings::default() },
+                    decode_start: std::time::Instant::now(),
+                    sample_us: 0,
+                });
+            }
+            if active.is_empty() {
+                return Ok(());
+            }
+            if opts.cancel.as_ref().is_some_and(|c| c.load(Ordering::Relaxed)) {
+                for seq in active {
+                    let usage = seq.usage(prompts);
+                    on_event(seq.index, GenerationEvent::Done { reason: FinishReason::Cancelled, timings: seq.timings, usage });
+                }
+                return Ok(());
+            }
+
+            let t = std::time::Instant::now();
+            let tokens: Vec<u32> = active.iter().flat_map(|s| s.pending.iter().copied()).collect();
+            let hidden = {
+                let mut spans: Vec<Span> = active
+                    .iter_mut()
+                    .map(|s| Span { pos: s.context.len() - s.pending.len(), len: s.pending.len(), kv: &mut s.kv })
+                    .collect();
+                self.forward_spans(&tokens, &mut spans, self.arch.n_layers)?
+            };
+            // Only each sequence's last row is sampled from.
+            let h = self.arch.hidden;
+            let mut last = Vec::with_capacity(active.len() * h);
+            let mut end = 0;
+            for seq in &active {
+                end += seq.pending.len();
+                last.extend_from_slice(&hidden[(end - 1) * h..][..h]);
+            }
+            let logits = self.lm_head(&last, active.len())?;
+            let step_ms = t.elapsed().as_millis();
+
+            let mut still_active = Vec::with_capacity(active.len());
+            for (mut seq, mut logits) in active.into_iter().zip(logits) {
+                let prompt_len = prompts[seq.index].len();
+                let prefill = seq.timings.prefill_tokens == 0;
+                if prefill {
+                    seq.timings.prefill_tokens = prompt_len;
+                    seq.timings.prefill_ms = step_ms;
+                    on_event(seq.index, GenerationEvent::PromptProcessed { n_tokens: prompt_len, ms: step_ms });
+                    seq.decode_start = std::time::Instant::now();
+                }
+                let generated = seq.context.len() - prompt_len;
+                let t = std::time::Instant::now();
+                let id = seq.sampler.sample(&mut logits, &seq.context, generated);
+                seq.sample_us += t.elapsed().as_micros();
+                // The same budget as `generate`: the prefill's token plus `max_new`.
+                let reason = if seq.sampler.looping() {
+                    Some(FinishReason::Loop)
+                } else if id == 2 {
+                    Some(FinishReason::Eos)
+                } else if opts.stop_tokens.contains(&id) {
+                    Some(FinishReason::Stop)
+                } else {
+                    on_event(seq.index, GenerationEvent::Token {
+                        id,
+                        text: detokenize(id, vocab),
+                        logprob: logprob(&logits, id),
+                    });
+                    seq.context.push(id);
+                    seq.pending = vec![id];
+                    if !prefill {
+                        seq.timings.decode_tokens += 1;
+                    }
+                    (generated + 1 > opts.max_new).then_some(FinishReason::MaxTokens)
+                };
+                match reason {
+                    Some(reason) => {
+                        seq.timings.decode_ms = seq.decode_start.elapsed().as_millis();
+                        seq.timings.sample_ms = seq.sample_us / 1000;
+                        let usage = seq.usage(prompts);
+                        on_event(seq.index, GenerationEvent::Done { reason, timings: seq.timings, usage });
+                    }
+                    None => still_active.push(seq),
+                }
+            }
+            active = still_active;
+        }
+    }
+
+    /// Beam search: keep the `width` best partial sequences by summed log-prob,
+    /// each with its own (prefix-shared) KV cache. The best sequence is only
+    /// known once the search ends, so its tokens are emitted together at the end.
+    pub fn beam_search(
+        &mut self,
+        tokens: &[u32],
+        max_new: usize,
+        beams: &BeamConfig,
+        vocab: &[String],
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<()> {
+        let width = beams.width.max(1);
+        let score = |b: &Beam| b.logprob / (b.tokens.len().max(1) as f32).powf(beams.length_penalty);
+
+        let t0 = std::time::Instant::now();
+        let mut kv = KvCache::new(self.arch.n_layers);
+        let logits = self.prefill(tokens, 0, &mut kv, on_event)?;
+        let prefill_ms = t0.elapsed().as_millis();
+        on_event(GenerationEvent::PromptProcessed { n_tokens: tokens.len(), ms: prefill_ms });
+
+        let t1 = std::time::Instant::now();
+        let mut alive = vec![Beam { tokens: Vec::new(), logprobs: Vec::new(), logprob: 0.0, kv, logits }];
+        let mut finished: Vec<Beam> = Vec::new();
+
+        for step in 0..max_new {
+            // Expand every beam by its top `width` tokens, then keep the global best.
+            let mut candidates: Vec<(usize, u32, f32)> = Vec::new();
+            for (b, beam) in alive.iter().enumerate() {
+                let mut lp = beam.logits.clone();
+                log_softmax(&mut lp);
+                for id in top_k(&lp, width) {
+                    candidates.push((b, id, lp[id as usize]));
+                }
+            }
+            candidates.sort_by(|a, b| (alive[b.0].logprob + b.2).total_cmp(&(alive[a.0].logprob + a.2)));
+
+            let mut next = Vec::with_capacity(width);
+            for (b, id, token_lp) in candidates {
+                if next.len() == width {
+                    break;
+                }
+                let parent = &alive[b];
+                let mut seq = parent.tokens.clone();
+                seq.push(id);
+                let mut logprobs = parent.logprobs.clone();
+                logprobs.push(token_lp);
+                let logprob = parent.logprob + token_lp;
+                if id == 2 {   // </s> EOS
+                    finished.push(Beam { tokens: seq, logprobs, logprob, kv: KvCache::new(0), logits: Vec::new() });
+                    continue;
+                }
+                let mut kv = parent.kv.clone();
+                let logits = self.forward(id, tokens.len() + step, &mut kv)?;
+                next.push(Beam { tokens: seq, logprobs, logprob, kv, logits });
+            }
+            alive = next;
+            if alive.is_empty() || finished.len() >= width {
+                break;
+            }
+        }
+
+        finished.extend(alive);
+        let best = finished
+            .iter()
+            .max_by(|a, b| score(a).total_cmp(&score(b)))
+            .context("beam search produced no sequence")?;
+        eprintln!(
+            "beam search: width {width}, {} tokens, logprob {:.3}, score {:.3}",
+            best.tokens.len(),
+            best.logprob,
+            score(best),
+        );
+        let mut reason = FinishReason::MaxTokens;
+        let mut completion_tokens = 0;
+        for (&id, &logprob) in best.tokens.iter().zip(&best.logprobs) {
+            if id == 2 {
+                reason = FinishReason::Eos;
+                break;
+            }
+            on_event(GenerationEvent::Token { id, text: detokenize(id, vocab), logprob });
+            completion_tokens += 1;
         }
+        let timings = Timings {
+            load_ms: self.load_ms,
+            prefill_tokens: tokens.len(),
+            prefill_ms,
+            decode_tokens: completion_tokens,
+            decode_ms: t1.elapsed().as_millis(),
+            ..Timings::default()
+        };
+        let usage = Usage { prompt_tokens: tokens.len(), completion_tokens, cached_tokens: 0 };
+        on_event(GenerationEvent::Done { reason, timings, usage });
         Ok(())
     }
 
+    /// Run the negative prompt through its own KV cache.
+    fn prefill_negative(&mut self, g: &Guidance) -> Result<NegativeContext> {
+        let mut kv = KvCache::new(self.arch.n_layers);
+        let logits = self.prefill(&g.negative, 0, &mut kv, &mut |_| {})?;
+        Ok(NegativeContext { kv, pos: g.negative.len(), logits, scale: g.scale })
+    }
+
+    /// Greedy draft from the first `layers` blocks: feed `last` at `pos`, then
+    /// each drafted token. The target's KV rows for those layers are valid
+    /// for the draft as-is; sharing them is a pointer copy, and the draft's own
+    /// rows are dropped with the clone.
+    fn early_exit_draft(&mut self, last: u32, pos: usize, kv: &KvCache, layers: usize, n: usize) -> Result<Vec<u32>> {
+        let mut draft_kv = KvCache { k: kv.k[..layers].to_vec(), v: kv.v[..layers].to_vec() };
+        let mut tok = last;
+        let mut out = Vec::with_capacity(n);
+        for i in 0..n {
+            let logits = self.forward_layers(&[tok], pos + i, &mut draft_kv, layers)?;
+            tok = argmax(&logits[0]);
+            if tok == 2 {   // </s> EOS
+                break;
+            }
+            out.push(tok);
+        }
+        Ok(out)
+    }
+
+    /// Greedy draft from the Medusa heads, all reading `hidden`: the state
+    /// that produced the logits `last` was sampled from. Head `k` guesses the
+    /// token `k + 1` places past `last`.
+    fn medusa_draft(&mut self, hidden: &[f32], n: usize) -> Result<Vec<u32>> {
+        let heads = self.medusa.take().context("no Medusa heads loaded")?;
+        let draft = self.run_medusa_heads(&heads[..n.min(heads.len())], hidden);
+        self.medusa = Some(heads);
+        draft
+    }
+
+    fn run_medusa_heads(&mut self, heads: &[MedusaHead], hidden: &[f32]) -> Result<Vec<u32>> {
+        let (h, vocab) = (self.arch.hidden, self.arch.vocab_size);
+        let x = self.gpu.buf_from_f32(hidden);
+        let mut out = Vec::with_capacity(heads.len());
+        for head in heads {
+            let y = self.dispatch_matmul(head.fc_kind, &head.fc, &x, h, h, 1)?;
+            let state = medusa_residual(hidden, self.gpu.read_f32(&y, h), &head.bias);
+            let logits = match &head.output {
+                Some((name, kind)) => {
+                    let s = self.gpu.buf_from_f32(&state);
+                    let y = self.dispatch_matmul(*kind, name, &s, vocab, h, 1)?;
+                    self.gpu.read_f32(&y, vocab).to_vec()
+                }
+                None => self.lm_head(&state, 1)?.pop().context("empty lm_head output")?,
+            };
+            let tok = argmax(&logits);
+            if tok == 2 {   // </s> EOS
+                break;
+            }
+            out.push(tok);
+        }
+        Ok(out)
+    }
+
+    /// Run `tokens` at positions `pos..`, `prefill_chunk` at a time, and
+    /// return the logits after the last one. `last_hidden` ends up holding
+    /// only the last token's state, as after `forward`.
+    fn prefill(
+        &mut self,
+        tokens: &[u32],
+        pos: usize,
+        kv: &mut KvCache,
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<Vec<f32>> {
+        ensure!(!tokens.is_empty(), "nothing to prefill");
+        let t0 = std::time::Instant::now();
+        let mut logits = Vec::new();
+        for (i, part) in tokens.chunks(self.prefill_chunk).enumerate() {
+            logits = self.forward_batch(part, pos + i * self.prefill_chunk, kv)?.pop().context("empty prefill pass")?;
+            if tokens.len() > self.prefill_chunk {
+                let (done, total, ms) = (i * self.prefill_chunk + part.len(), tokens.len(), t0.elapsed().as_millis());
+                let eta_ms = ms * (total - done) as u128 / done as u128;
+                on_event(GenerationEvent::PrefillProgress { done, total, ms, eta_ms });
+            }
+        }
+        let keep = self.last_hidden.len().saturating_sub(self.arch.hidden);
+        self.last_hidden.drain(..keep);
+        Ok(logits)
+    }
+
     fn forward(&mut self, token: u32, pos: usize, kv: &mut KvCache) -> Result<Vec<f32>> {
-        let arch = self.arch.clone();
-        let mut x = self.embed(token)?;
+        let mut logits = self.forward_batch(&[token], pos, kv)?;
+        Ok(logits.pop().unwrap())
+    }
+
+    /// Run `tokens` at positions `pos..` in one pass and return the logits after
+    /// each of them. Every matmul is a single dispatch for the whole batch;
+    /// attention stays causal because token `i` only sees cache rows `..=pos + i`.
+    fn forward_batch(&mut self, tokens: &[u32], pos: usize, kv: &mut KvCache) -> Result<Vec<Vec<f32>>> {
+        self.forward_layers(tokens, pos, kv, self.arch.n_layers)
+    }
+
+    /// `forward_batch` through only the first `n_layers` blocks (early exit).
+    fn forward_layers(
+        &mut self,
+        tokens: &[u32],
+        pos: usize,
+        kv: &mut KvCache,
+        n_layers: usize,
+    ) -> Result<Vec<Vec<f32>>> {
+        let xn = self.forward_hidden(tokens, pos, kv, n_layers)?;
+        let logits = self.lm_head(&xn, tokens.len())?;
+        if self.dump.is_some() {
+            self.record("result_output", &logits.concat(), self.arch.vocab_size);
+        }
+        if self.medusa.is_some() && n_layers == self.arch.n_layers {
+            self.last_hidden = xn;
+        }
+        Ok(logits)
+    }
+
+    /// Final normed hidden states for `tokens`, packed `[tokens, hidden]`.
+    fn forward_hidden(&mut self, tokens: &[u32], pos: usize, kv: &mut KvCache, n_layers: usize) -> Result<Vec<f32>> {
+        self.forward_spans(tokens, &mut [Span { pos, len: tokens.len(), kv }], n_layers)
+    }
+
+    /// `forward_hidden` for several sequences at once: `spans` split `tokens`
+    /// into consecutive runs, each with its own position and cache. The
+    /// matmuls see one batch; attention stays within each span's sequence.
+    fn forward_spans(&mut self, tokens: &[u32], spans: &mut [Span], n_layers: usize) -> Result<Vec<f32>> {
+        debug_assert_eq!(spans.iter().map(|s| s.len).sum::<usize>(), tokens.len());
+        let mut xs = self.embed_all(tokens)?;
         let t_fwd = std::time::Instant::now();
-        for layer in 0..arch.n_layers {
-            let t_layer = std::time::Instant::now();
-            x = self.block(x, layer, pos, kv)?;
-            if layer < 3 || layer == arch.n_layers - 1 {
-                eprintln!("  layer {layer:2}: {}ms", t_layer.elapsed().as_millis());
+        // Dumps and imatrices record block by block, so they take the
+        // one-layer-at-a-time path even on a pipeline.
+        let pipelined = self.pipeline.is_some()
+            && spans.len() == 1
+            && n_layers == self.arch.n_layers
+            && self.dump.is_none()
+            && self.imatrix.is_none();
+        if pipelined {
+            xs = self.forward_pipelined(xs, &mut spans[0])?;
+        } else if let Some(first) = self.pipeline.as_ref().and_then(Pipeline::first_worker_layer)
+            && n_layers > first
+        {
+            anyhow::bail!("blocks on an RPC worker only run in a plain forward pass of one sequence, without dumps or imatrices");
+        } else {
+            for layer in 0..n_layers {
+                let t_layer = std::time::Instant::now();
+                xs = self.block(xs, layer, spans)?;
+                if layer < 3 || layer == n_layers - 1 {
+                    eprintln!("  layer {layer:2}: {}ms", t_layer.elapsed().as_millis());
+                }
             }
         }
         eprintln!("  all layers: {}ms", t_fwd.elapsed().as_millis());
-        let norm_w = self.f32_weights("output_norm.weight")?;
-        x = rms_norm(&x, &norm_w, 1e-5);
-        self.lm_head(&x)
+        let norm_w = self.f32_weights(&self.weights.output_norm.name)?;
+        let xn: Vec<f32> = xs.iter().flat_map(|x| rms_norm(x, &norm_w, 1e-5)).collect();
+        self.record("result_norm", &xn, self.arch.hidden);
+        Ok(xn)
     }
 
-    fn embed(&self, token: u32) -> Result<Vec<f32>> {
-        let bytes = self.store.get("token_embd.weight")?;
-        let meta  = self.store.meta("token_embd.weight")?;
-        let vocab_rows = meta.shape.get(1).copied().unwrap_or(meta.shape[0]) as usize;
-        let row = token as usize;
-        anyhow::ensure!(row < vocab_rows, "token {token} >= vocab {vocab_rows}");
-        match meta.kind {
-            GGML_Q8_0 => {
-                let rb = meta.cols() / 32 * Q8_0_BLOCK;
-                Ok(TensorStore::dequant_q8_0_row(&bytes[row * rb..][..rb]))
-            }
-            GGML_F16 => {
-                let rb = meta.cols() * 2;
-                Ok(TensorStore::dequant_f16_row(&bytes[row * rb..][..rb]))
+    fn record(&mut self, name: &str, data: &[f32], cols: usize) {
+        if let Some(dump) = &mut self.dump {
+            dump.record(name, data, cols);
+        }
+    }
+
+    /// The embedding of each of `tokens`. The table stays as stored and
+    /// only the rows looked up are dequantized: by the `get_rows` kernel for
+    /// a batch of `GPU_EMBED_MIN` or more from an F32, F16 or Q8_0 table the
+    /// GPU holds or can map without a copy, on the CPU otherwise.
+    fn embed_all(&mut self, tokens: &[u32]) -> Result<Vec<Vec<f32>>> {
+        let weights = self.weights.clone();
+        let table = &weights.token_embd;
+        if let Some(&bad) = tokens.iter().find(|&&t| t as usize >= table.rows) {
+            anyhow::bail!("token {bad} >= vocab {}", table.rows);
+        }
+        if tokens.len() >= GPU_EMBED_MIN
+            && matches!(table.kind, GGML_F32 | GGML_F16 | GGML_Q8_0)
+            && self.on_gpu(&table.name)
+            && self.map_embeddings(&table.name)
+        {
+            let w = &self.weight_cache[&table.name];
+            let (layer, op) = weight_scope(&table.name);
+            self.gpu.charge(layer, op);
+            let out = self.gpu.get_rows(table.kind, &w.buf, w.offset, tokens, table.cols)?;
+            let rows = self.gpu.read_f32(&out, tokens.len() * table.cols);
+            return Ok(rows.chunks_exact(table.cols).map(<[f32]>::to_vec).collect());
+        }
+        tokens.iter().map(|&t| self.embed(t)).collect()
+    }
+
+    /// Whether the embedding table `name` is in `weight_cache`, wrapping
+    /// its mmap pages there if not. A table that would have to be copied
+    /// (not page-aligned, or over the weight budget) stays on the CPU: its
+    /// rows are cheap to look up there, and a copy would cost the whole table.
+    fn map_embeddings(&mut self, name: &str) -> bool {
+        if self.weight_cache.contains_key(name) {
+            return true;
+        }
+        let Ok(bytes) = self.store.get(name) else { return false };
+        if self.weight_budget.is_some_and(|budget| self.cached_weight_bytes() + bytes.len() as u64 > budget) {
+            return false;
+        }
+        match self.store.weight_buffer(&self.gpu, bytes) {
+            Ok(buf) if buf.is_mapped() => {
+                self.weight_cache.insert(name.to_string(), buf);
+                true
             }
-            k => anyhow::bail!("unsupported embedding dtype {k}"),
+            _ => false,
         }
     }
 
-    fn block(&mut self, x: Vec<f32>, layer: usize, pos: usize, kv: &mut KvCache) -> Result<Vec<f32>> {
+    /// One embedding row, dequantized on the CPU.
+    fn embed(&self, token: u32) -> Result<Vec<f32>> {
+        let table = &self.weights.token_embd;
+        anyhow::ensure!((token as usize) < table.rows, "token {token} >= vocab {}", table.rows);
+        self.store.dequant_row(&table.name, token as usize)
+    }
+
+    /// One transformer block over a batch of rows, each span of them
+    /// consecutive positions of one sequence.
+    fn block(&mut self, xs: Vec<Vec<f32>>, layer: usize, spans: &mut [Span]) -> Result<Vec<Vec<f32>>> {
         let arch = self.arch.clone();
+        let weights = self.weights.clone();
+        let w = &weights.layers[layer];
+        let n = xs.len();
 
         // --- attention ---
-        let attn_norm_w = self.f32_weights(&format!("blk.{layer}.attn_norm.weight"))?;
-        let xn = rms_norm(&x, &attn_norm_w, 1e-5);
+        let attn_norm_w = self.f32_weights(&w.attn_norm.name)?;
+        let xn: Vec<f32> = xs.iter().flat_map(|x| rms_norm(x, &attn_norm_w, 1e-5)).collect();
+        self.record(&format!("attn_norm-{layer}"), &xn, arch.hidden);
         let xn_buf = self.gpu.buf_from_f32(&xn);
 
-        let q_dim  = self.tensor_rows(&format!("blk.{layer}.attn_q.weight"))?;
-        let kv_dim = self.tensor_rows(&format!("blk.{layer}.attn_k.weight"))?;
-        let head_dim = q_dim / arch.n_heads;
-
-        let q_buf  = self.matvec(&format!("blk.{layer}.attn_q.weight"),      &xn_buf, q_dim,  arch.hidden)?;
-        let k_buf  = self.matvec(&format!("blk.{layer}.attn_k.weight"),      &xn_buf, kv_dim, arch.hidden)?;
-        let v_buf  = self.matvec(&format!("blk.{layer}.attn_v.weight"),      &xn_buf, kv_dim, arch.hidden)?;
+        let q_dim  = w.attn_q.rows;
+        let kv_dim = w.attn_k.rows;
 
-        let mut q = self.gpu.read_f32(&q_buf, q_dim).to_vec();
-        let mut k = self.gpu.read_f32(&k_buf, kv_dim).to_vec();
-        let     v = self.gpu.read_f32(&v_buf, kv_dim).to_vec();
-
-        rope(&mut q, arch.n_heads,    head_dim, pos, arch.rope_base);
-        rope(&mut k, arch.n_kv_heads, head_dim, pos, arch.rope_base);
-        kv.push(layer, k, v);
+        let qkv = fusion::qkv_name(layer);
+        let (q_all, k_all, v_all) = if self.fused.contains_key(&qkv) {
+            let qkv_dim = q_dim + 2 * kv_dim;
+            let qkv_buf = self.matmul(&qkv, &xn_buf, qkv_dim, arch.hidden, n)?;
+            let rows = self.gpu.read_f32(&qkv_buf, qkv_dim * n);
+            let [q, k, v] = <[_; 3]>::try_from(fusion::split_rows(rows, &[q_dim, kv_dim, kv_dim])).expect("three parts");
+            (q, k, v)
+        } else {
+            let q_buf  = self.matmul(&w.attn_q.name, &xn_buf, q_dim,  arch.hidden, n)?;
+            let k_buf  = self.matmul(&w.attn_k.name, &xn_buf, kv_dim, arch.hidden, n)?;
+            let v_buf  = self.matmul(&w.attn_v.name, &xn_buf, kv_dim, arch.hidden, n)?;
+            (
+                self.gpu.read_f32(&q_buf, q_dim * n).to_vec(),
+                self.gpu.read_f32(&k_buf, kv_dim * n).to_vec(),
+                self.gpu.read_f32(&v_buf, kv_dim * n).to_vec(),
+            )
+        };
 
-        let attn_out = attention(&q, &kv.k[layer], &kv.v[layer], arch.n_heads, arch.n_kv_heads, head_dim);
+        let mut attn_out = Vec::with_capacity(q_dim * n);
+        let mut row = 0;
+        for span in spans.iter_mut() {
+            let kv = &mut *span.kv;
+            attn_out.extend(attend(
+                &arch,
+                [&q_all[row * q_dim..][..span.len * q_dim], &k_all[row * kv_dim..][..span.len * kv_dim], &v_all[row * kv_dim..][..span.len * kv_dim]],
+                span.pos,
+                &mut kv.k[layer],
+                &mut kv.v[layer],
+            ));
+            row += span.len;
+        }
+        self.record(&format!("kqv_out-{layer}"), &attn_out, q_dim);
         let attn_buf = self.gpu.buf_from_f32(&attn_out);
-        let x_buf    = self.gpu.buf_from_f32(&x);
-        let o_proj   = self.matvec(&format!("blk.{layer}.attn_output.weight"), &attn_buf, arch.hidden, q_dim)?;
-        let res1     = self.gpu.add(&x_buf, &o_proj, arch.hidden);
+        let x_buf    = self.gpu.buf_from_f32(&xs.concat());
+        let o_proj   = self.matmul(&w.attn_output.name, &attn_buf, arch.hidden, q_dim, n)?;
+        self.gpu.charge(Some(layer), "attn_residual");
+        let res1     = self.gpu.add(&x_buf, &o_proj, arch.hidden * n);
 
         // --- ffn ---
-        let res1_vec    = self.gpu.read_f32(&res1, arch.hidden).to_vec();
-        let ffn_norm_w  = self.f32_weights(&format!("blk.{layer}.ffn_norm.weight"))?;
-        let xn2         = rms_norm(&res1_vec, &ffn_norm_w, 1e-5);
+        let res1_vec    = self.gpu.read_f32(&res1, arch.hidden * n).to_vec();
+        self.record(&format!("ffn_inp-{layer}"), &res1_vec, arch.hidden);
+        let ffn_norm_w  = self.f32_weights(&w.ffn_norm.name)?;
+        let xn2: Vec<f32> = res1_vec.chunks_exact(arch.hidden).flat_map(|r| rms_norm(r, &ffn_norm_w, 1e-5)).collect();
+        self.record(&format!("ffn_norm-{layer}"), &xn2, arch.hidden);
         let xn2_buf     = self.gpu.buf_from_f32(&xn2);
 
-        let gate = self.matvec(&format!("blk.{layer}.ffn_gate.weight"), &xn2_buf, arch.ffn_hidden, arch.hidden)?;
-        let up   = self.matvec(&format!("blk.{layer}.ffn_up.weight"),   &xn2_buf, arch.ffn_hidden, arch.hidden)?;
-        let mid  = self.gpu.silu_hadamard(&gate, &up, arch.ffn_hidden);
-        let down = self.matvec(&format!("blk.{layer}.ffn_down.weight"), &mid, arch.hidden, arch.ffn_hidden)?;
+        let gate_up = fusion::gate_up_name(layer);
+        let mid = if self.fused.contains_key(&gate_up) {
+            let gu = self.matmul(&gate_up, &xn2_buf, 2 * arch.ffn_hidden, arch.hidden, n)?;
+            self.gpu.charge(Some(layer), "ffn_act");
+            self.gpu.silu_hadamard_fused(&gu, arch.ffn_hidden, n)
+        } else {
+            let gate = self.matmul(&w.ffn_gate.name, &xn2_buf, arch.ffn_hidden, arch.hidden, n)?;
+            let up   = self.matmul(&w.ffn_up.name,   &xn2_buf, arch.ffn_hidden, arch.hidden, n)?;
+            self.gpu.charge(Some(layer), "ffn_act");
+            self.gpu.silu_hadamard(&gate, &up, arch.ffn_hidden * n)
+        };
+        let down = self.matmul(&w.ffn_down.name, &mid, arch.hidden, arch.ffn_hidden, n)?;
+
+        if self.dump.is_some() {
+            let ffn_out = self.gpu.read_f32(&down, arch.hidden * n).to_vec();
+            self.record(&format!("ffn_out-{layer}"), &ffn_out, arch.hidden);
+        }
+
+        self.gpu.charge(Some(layer), "ffn_residual");
+        let out = self.gpu.add(&res1, &down, arch.hidden * n);
+        let out = self.gpu.read_f32(&out, arch.hidden * n).to_vec();
+        self.record(&format!("l_out-{layer}"), &out, arch.hidden);
+        Ok(out.chunks_exact(arch.hidden).map(<[f32]>::to_vec).collect())
+    }
+
+    /// Every block over one sequence's rows on the `use_pipeline` stages.
+    /// One thread per stage; micro-batches go down the stages through
+    /// channels, each stage owning its blocks' K/V rows.
+    fn forward_pipelined(&self, xs: Vec<Vec<f32>>, span: &mut Span) -> Result<Vec<Vec<f32>>> {
+        let pipeline = self.pipeline.as_ref().context("no pipeline")?;
+        let hidden = self.arch.hidden;
+        let pos = span.pos;
+        let x = xs.concat();
+        let (mut k_rest, mut v_rest) = (&mut span.kv.k[..], &mut span.kv.v[..]);
+        let mut caches = Vec::new();
+        for stage in pipeline.stages() {
+            let (k, kr) = std::mem::take(&mut k_rest).split_at_mut(stage.layers.len());
+            let (v, vr) = std::mem::take(&mut v_rest).split_at_mut(stage.layers.len());
+            (k_rest, v_rest) = (kr, vr);
+            caches.push(k.iter_mut().zip(v.iter_mut()).collect::<Vec<_>>());
+        }
 
-        let out = self.gpu.add(&res1, &down, arch.hidden);
-        Ok(self.gpu.read_f32(&out, arch.hidden).to_vec())
+        let out = std::thread::scope(|s| -> Result<Vec<f32>> {
+            let (first_tx, mut rx) = mpsc::channel();
+            let mut workers = Vec::new();
+            for (stage, kv) in pipeline.stages().iter().zip(caches) {
+                let (tx, next) = mpsc::channel();
+                let input = std::mem::replace(&mut rx, next);
+                workers.push(s.spawn(move || stage.serve(&self.arch, &self.weights, pos, kv, input, tx)));
+            }
+            for r in crate::pipeline::micro_batches(xs.len(), pipeline.micro_batch) {
+                if first_tx.send((r.start, x[r.start * hidden..r.end * hidden].to_vec())).is_err() {
+                    break;
+                }
+            }
+            drop(first_tx);
+            let mut out = vec![0.0f32; x.len()];
+            for (start, rows) in rx {
+                out[start * hidden..][..rows.len()].copy_from_slice(&rows);
+            }
+            for w in workers {
+                w.join().map_err(|_| anyhow::anyhow!("pipeline stage thread panicked"))??;
+            }
+            Ok(out)
+        })?;
+        Ok(out.chunks_exact(hidden).map(<[f32]>::to_vec).collect())
     }
 
-    fn lm_head(&mut self, x: &[f32]) -> Result<Vec<f32>> {
+    /// Logits for each of the `n` normed hidden states packed in `x`.
+    fn lm_head(&mut self, x: &[f32], n: usize) -> Result<Vec<Vec<f32>>> {
         let x_buf = self.gpu.buf_from_f32(x);
-        let name = if self.store.index.contains_key("output.weight") {
-            "output.weight"
-        } else {
-            "token_embd.weight"
-        };
+        let weights = self.weights.clone();
+        let name = &weights.output.name;
         let vocab = self.arch.vocab_size;
         let hidden = self.arch.hidden;
-        let out = self.matvec(name, &x_buf, vocab, hidden)?;
-        Ok(self.gpu.read_f32(&out, vocab).to_vec())
+        let out = self.matmul(name, &x_buf, vocab, hidden, n)?;
+        let mut logits: Vec<Vec<f32>> = self.gpu.read_f32(&out, vocab * n).chunks_exact(vocab).map(<[f32]>::to_vec).collect();
+        if let Some(cap) = self.arch.scaling.final_softcap {
+            logits.iter_mut().for_each(|row| softcap(row, cap));
+        }
+        Ok(logits)
     }
 
     // -- helpers --
 
-    fn tensor_rows(&self, name: &str) -> Result<usize> {
-        let m = self.store.meta(name)?;
-        Ok(m.shape.get(1).copied().unwrap_or(m.shape[0]) as usize)
-    }
-
-    /// Q8_0 matvec with lazy weight caching.
+    /// Quantized matmul over `batch` input rows with lazy weight caching.
     /// The first call for each tensor copies the mmap slice into a Metal buffer;
     /// every subsequent call reuses that buffer — zero copies at steady state.
-    fn matvec(&mut self, name: &str, x: &Buffer, n: usize, k: usize) -> Result<Buffer> {
+    /// A Q8_0 batch of one goes through the tuned matvec kernel; F16, Q2_K,
+    /// Q3_K and IQ4_NL have one kernel for every batch size.
+    /// Under `use_accelerate`, big F32/F16 batches go to the CPU instead;
+    /// under `use_devices`, every weight is split across the devices, and
+    /// under `use_pipeline` a block's weights live on its stage's device.
+    /// `name` may be a `fuse_weights` stack.
+    fn matmul(&mut self, name: &str, x: &Buffer, n: usize, k: usize, batch: usize) -> Result<Buffer> {
+        // llama.cpp leaves the output projection out of imatrices by default.
+        if let Some(m) = &mut self.imatrix
+            && name != self.weights.output.name
+        {
+            let x = self.gpu.read_f32(x, k * batch);
+            match self.fused.get(name) {
+                Some(f) => f.parts.iter().for_each(|p| m.record(&p.name, x, k)),
+                None => m.record(name, x, k),
+            }
+        }
+        if let Some(tp) = &self.shards
+            && tp.contains(name)
+        {
+            let out = tp.matmul(name, self.gpu.read_f32(x, k * batch), n, k, batch)?;
+            return Ok(self.gpu.buf_from_f32(&out));
+        }
+        if let Some(stage) = self.pipeline.as_ref().and_then(|p| p.stage_of(name)) {
+            let out = stage.matmul(name, self.gpu.read_f32(x, k * batch), n, k, batch)?;
+            return Ok(self.gpu.buf_from_f32(&out));
+        }
+        if let Some(cache) = &self.repacked
+            && let Some(t) = cache.index.get(name)
+        {
+            let (layer, op) = weight_scope(name);
+            self.gpu.charge(layer, op);
+            return Ok(self.gpu.q8_0r_matmul(&cache.buf, t.scales_offset(), t.quants_offset(), x, n, k, batch));
+        }
+        if let Some(gemm) = &mut self.cpu_gemm
+            && batch >= gemm.min_batch
+            && !self.fused.contains_key(name)
+            && let Some(w) = gemm.weights(&self.store, name)?
+        {
+            let out = blas::sgemm(self.gpu.read_f32(x, k * batch), w, batch, n, k);
+            return Ok(self.gpu.buf_from_f32(&out));
+        }
+        if !self.on_gpu(name) {
+            return self.cpu_matmul(name, x, n, k, batch);
+        }
         let upload_ms = if !self.weight_cache.contains_key(name) {
             let t = std::time::Instant::now();
-            let bytes = self.store.get(name)?;
-            let buf = self.gpu.buf_from_bytes(bytes);
-            self.weight_cache.insert(name.to_string(), buf);
+            let stacked = self.fused.get(name).map(|f| self.fused_bytes(f)).transpose()?;
+            let bytes = match &stacked {
+                Some(bytes) => bytes.as_slice(),
+                None => self.store.get(name)?,
+            };
+            let fits = match self.weight_budget {
+                Some(budget) if self.cached_weight_bytes() + bytes.len() as u64 > budget => Err(OutOfMemory {
+                    requested: bytes.len() as u64,
+                    allocated: self.cached_weight_bytes(),
+                    budget,
+                }),
+                _ => self.store.weight_buffer(&self.gpu, bytes),
+            };
+            match fits {
+                Ok(buf) => self.weight_cache.insert(name.to_string(), buf),
+                Err(oom) => {
+                    self.offload_from(weight_scope(name).0.unwrap_or(self.arch.n_layers), &oom);
+                    return self.cpu_matmul(name, x, n, k, batch);
+                }
+            };
             Some(t.elapsed().as_millis())
         } else { None };
 
         let t = std::time::Instant::now();
-        let w = &self.weight_cache[name];
-        let out = self.gpu.q8_0_matvec(w, 0, x, n, k);
+        let kind = match self.fused.get(name) {
+            Some(f) => f.kind,
+            None => self.store.meta(name)?.kind,
+        };
+        let out = self.dispatch_matmul(kind, name, x, n, k, batch)?;
         let dispatch_ms = t.elapsed().as_millis();
 
         if self.weight_cache.len() <= 10 || upload_ms.is_some() {
             let upload_str = upload_ms.map(|ms| format!(", upload={ms}ms")).unwrap_or_default();
-            eprintln!("    matvec {name}: dispatch={dispatch_ms}ms{upload_str}");
+            eprintln!("    matmul {name} x{batch}: dispatch={dispatch_ms}ms{upload_str}");
         }
         Ok(out)
     }
 
+    /// The kernel for a `kind` weight already in `weight_cache`.
+    fn dispatch_matmul(&self, kind: u32, name: &str, x: &Buffer, n: usize, k: usize, batch: usize) -> Result<Buffer> {
+        let w = &self.weight_cache[name];
+        let (layer, op) = weight_scope(name);
+        self.gpu.charge(layer, op);
+        match kind {
+            GGML_Q8_0 | GGML_F16 | GGML_Q2_K | GGML_Q3_K | GGML_IQ4_NL => self.gpu.matmul(kind, &w.buf, w.offset, x, n, k, batch),
+            kind => anyhow::bail!("unsupported matmul dtype {} for '{name}'", ggml_type_name(kind)),
+        }
+    }
+
+    fn on_gpu(&self, name: &str) -> bool {
+        weight_scope(name).0.unwrap_or(self.arch.n_layers) < self.gpu_layers
+    }
+
+    fn cached_weight_bytes(&self) -> u64 {
+        self.weight_cache.values().map(|w| w.buf.length()).sum()
+    }
+
+    /// Metal refused a weight of block `layer`: move it and every block
+    /// above to the CPU, freeing what they had uploaded.
+    fn offload_from(&mut self, layer: usize, oom: &OutOfMemory) {
+        let n_layers = self.arch.n_layers;
+        self.set_gpu_layers(layer.min(self.gpu_layers));
+        let on_cpu = match layer {
+            l if l >= n_layers => "the output head".to_string(),
+            l => format!("layers {l}..{n_layers} and the output head"),
+        };
+        eprintln!("warning: {oom}; {on_cpu} now run on the CPU, which is much slower");
+    }
+
+    /// `matmul` for a weight kept off the GPU: each thread dequantizes its
+    /// share of rows straight from the mmap, so nothing extra stays in memory.
+    /// Under `set_int8_activations` Q8_0 rows are not dequantized at all.
+    fn cpu_matmul(&self, name: &str, x: &Buffer, n: usize, k: usize, batch: usize) -> Result<Buffer> {
+        let x = self.gpu.read_f32(x, k * batch);
+        let threads = std::thread::available_parallelism().map_or(4, |t| t.get()).clamp(1, n.max(1));
+        let chunk = n.div_ceil(threads).max(1);
+        let (store, fused) = (&self.store, self.fused.get(name));
+        let kind = match fused {
+            Some(f) => f.kind,
+            None => store.meta(name)?.kind,
+        };
+        // Quantized once here, shared by every thread.
+        let qx: Vec<Q8Activations> = if self.int8_activations && kind == GGML_Q8_0 {
+            x.chunks_exact(k).map(quantize_q8).collect()
+        } else {
+            Vec::new()
+        };
+        let qx = &qx;
+        // Each part is `[rows, batch]` for rows `i * chunk..`.
+        let parts = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..n)
+                .step_by(chunk)
+                .map(|r0| {
+                    s.spawn(move || -> Result<Vec<f32>> {
+                        let mut part = Vec::with_capacity(chunk * batch);
+                        for r in r0..(r0 + chunk).min(n) {
+                            let (name, row) = match fused {
+                                Some(f) => {
+                                    let (part, row) = f.locate(r);
+                                    (part.name.as_str(), row)
+                                }
+                                None => (name, r),
+                            };
+                            if qx.is_empty() {
+                                let w = store.dequant_row(name, row)?;
+                                part.extend(x.chunks_exact(k).map(|xt| dot(xt, &w)));
+                            } else {
+                                let w = store.row_bytes(name, row)?;
+                                part.extend(qx.iter().map(|xt| dot_q8_0(w, xt)));
+                            }
+                        }
+                        Ok(part)
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().map_err(|_| anyhow::anyhow!("CPU matmul thread panicked"))?).collect::<Result<Vec<_>>>()
+        })?;
+        let mut out = vec![0.0f32; batch * n];
+        for (i, part) in parts.iter().enumerate() {
+            for (j, row) in part.chunks_exact(batch).enumerate() {
+                let r = i * chunk + j;
+                row.iter().enumerate().for_each(|(t, &v)| out[t * n + r] = v);
+            }
+        }
+        Ok(self.gpu.buf_from_f32(&out))
+    }
+
+    /// A stack's parts back to back, the bytes of one tensor of its shape.
+    fn fused_bytes(&self, f: &FusedWeight) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for p in &f.parts {
+            bytes.extend_from_slice(self.store.get(&p.name)?);
+        }
+        Ok(bytes)
+    }
+
     fn f32_weights(&self, name: &str) -> Result<Vec<f32>> {
         let b = self.store.get(name)?;
         Ok(b.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect())
@@ -262,18 +2055,19 @@ impl LlamaModel {
 // CPU math
 // ---------------------------------------------------------------------------
 
-fn rms_norm(x: &[f32], w: &[f32], eps: f32) -> Vec<f32> {
+pub(crate) fn rms_norm(x: &[f32], w: &[f32], eps: f32) -> Vec<f32> {
     let ss = x.iter().map(|v| v * v).sum::<f32>() / x.len() as f32;
     let inv = 1.0 / (ss + eps).sqrt();
     x.iter().zip(w.iter()).map(|(xi, wi)| xi * inv * wi).collect()
 }
 
-fn rope(x: &mut [f32], n_heads: usize, head_dim: usize, pos: usize, base: f32) {
+pub(crate) fn rope(x: &mut [f32], n_heads: usize, head_dim: usize, pos: usize, base: f32, scaling: RopeScaling) {
     for h in 0..n_heads {
         let off = h * head_dim;
         for i in 0..head_dim / 2 {
-            let theta = pos as f32 / base.powf(2.0 * i as f32 / head_dim as f32);
+            let (theta, mscale) = rope_angle(pos, i, head_dim, base, scaling);
             let (s, c) = theta.sin_cos();
+            let (s, c) = (s * mscale, c * mscale);
             let (x0, x1) = (x[off + 2*i], x[off + 2*i + 1]);
             x[off + 2*i]     = x0 * c - x1 * s;
             x[off + 2*i + 1] = x0 * s + x1 * c;
@@ -281,13 +2075,81 @@ fn rope(x: &mut [f32], n_heads: usize, head_dim: usize, pos: usize, base: f32) {
     }
 }
 
-fn attention(
-    q: &[f32], k_cache: &[Vec<f32>], v_cache: &[Vec<f32>],
+/// Move K rows that `rope` placed at some position `shift` positions
+/// later. RoPE angles grow linearly with position under every scaling, so
+/// this is the rotation by `shift` alone, without `rope`'s magnitude scale.
+pub(crate) fn shift_rope(x: &mut [f32], n_heads: usize, head_dim: usize, shift: usize, base: f32, scaling: RopeScaling) {
+    for h in 0..n_heads {
+        let off = h * head_dim;
+        for i in 0..head_dim / 2 {
+            let (s, c) = rope_angle(shift, i, head_dim, base, scaling).0.sin_cos();
+            let (x0, x1) = (x[off + 2*i], x[off + 2*i + 1]);
+            x[off + 2*i]     = x0 * c - x1 * s;
+            x[off + 2*i + 1] = x0 * s + x1 * c;
+        }
+    }
+}
+
+/// Rotation angle and magnitude scale for pair `i` at `pos`.
+fn rope_angle(pos: usize, i: usize, head_dim: usize, base: f32, scaling: RopeScaling) -> (f32, f32) {
+    let theta = pos as f32 / base.powf(2.0 * i as f32 / head_dim as f32);
+    match scaling {
+        RopeScaling::None => (theta, 1.0),
+        RopeScaling::Linear { factor } => (theta / factor, 1.0),
+        RopeScaling::Yarn(y) => {
+            let (low, high) = y.corr_dims(head_dim, base);
+            // 1 = keep the trained rotation (extrapolate), 0 = interpolate.
+            let ramp = 1.0 - ((i as f32 - low) / (high - low).max(0.001)).clamp(0.0, 1.0);
+            let theta = theta / y.factor * (1.0 - ramp) + theta * ramp;
+            (theta, 1.0 + 0.1 * y.factor.ln())
+        }
+    }
+}
+
+/// RoPE (unless ALiBi) and causal attention for `qkv`, the packed projections of
+/// consecutive rows of one sequence at positions `pos..`. Each row's K/V
+/// goes onto `k_rows`/`v_rows` (one layer's cache) before it attends.
+pub(crate) fn attend(
+    arch: &Arch, [q_all, k_all, v_all]: [&[f32]; 3], pos: usize,
+    k_rows: &mut Vec<Arc<[f32]>>, v_rows: &mut Vec<Arc<[f32]>>,
+) -> Vec<f32> {
+    let head_dim = arch.head_dim;
+    let (q_dim, kv_dim) = (arch.n_heads * head_dim, arch.n_kv_heads * head_dim);
+    let mut out = Vec::with_capacity(q_all.len());
+    for (i, q) in q_all.chunks_exact(q_dim).enumerate() {
+        let mut q = q.to_vec();
+        let mut k = k_all[i * kv_dim..][..kv_dim].to_vec();
+        let     v = v_all[i * kv_dim..][..kv_dim].to_vec();
+        if arch.scaling.alibi_max_bias.is_none() {
+            rope(&mut q, arch.n_heads,    head_dim, pos + i, arch.rope_base, arch.rope_scaling);
+            rope(&mut k, arch.n_kv_heads, head_dim, pos + i, arch.rope_base, arch.rope_scaling);
+        }
+        k_rows.push(k.into());
+        v_rows.push(v.into());
+        let seen = pos + i + 1;
+        out.extend(attention_scaled(
+            &q, &k_rows[..seen], &v_rows[..seen], arch.n_heads, arch.n_kv_heads, head_dim, &arch.scaling,
+        ));
+    }
+    out
+}
+
+pub(crate) fn attention(
+    q: &[f32], k_cache: &[Arc<[f32]>], v_cache: &[Arc<[f32]>],
     n_heads: usize, n_kv_heads: usize, head_dim: usize,
+) -> Vec<f32> {
+    attention_scaled(q, k_cache, v_cache, n_heads, n_kv_heads, head_dim, &ScoreScaling::default())
+}
+
+/// `attention` with the scores scaled, capped and biased as `scaling` says.
+/// The query is the last of the `k_cache.len()` positions.
+pub(crate) fn attention_scaled(
+    q: &[f32], k_cache: &[Arc<[f32]>], v_cache: &[Arc<[f32]>],
+    n_heads: usize, n_kv_heads: usize, head_dim: usize, scaling: &ScoreScaling,
 ) -> Vec<f32> {
     let seq  = k_cache.len();
     let gqa  = n_heads / n_kv_heads;
-    let scale = 1.0 / (head_dim as f32).sqrt();
+    let scale = scaling.query_scale(head_dim);
     let mut out = vec![0.0f32; n_heads * head_dim];
 
     for h in 0..n_heads {
@@ -298,45 +2160,59 @@ fn attention(
             let k_head = &k_cache[t][kv_h * head_dim..(kv_h + 1) * head_dim];
             scale * dot(q_head, k_head)
         }).collect();
+        if let Some(cap) = scaling.attn_softcap {
+            softcap(&mut scores, cap);
+        }
+        if let Some(slope) = scaling.alibi_slope(h, n_heads) {
+            scores.iter_mut().enumerate().for_each(|(t, s)| *s -= slope * (seq - 1 - t) as f32);
+        }
 
         let max = scores.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
         let sum: f32 = scores.iter_mut().map(|s| { *s = (*s - max).exp(); *s }).sum();
         scores.iter_mut().for_each(|s| *s /= sum);
 
+        let out_head = &mut out[h * head_dim..(h + 1) * head_dim];
         for t in 0..seq {
-            let v_head = &v_cache[t][kv_h * head_dim..(kv_h + 1) * head_dim];
-            for i in 0..head_dim {
-                out[h * head_dim + i] += scores[t] * v_head[i];
-            }
+            axpy(out_head, scores[t], &v_cache[t][kv_h * head_dim..(kv_h + 1) * head_dim]);
         }
     }
     out
 }
 
-fn dot(a: &[f32], b: &[f32]) -> f32 {
-    a.iter().zip(b.iter()).map(|(x, y)| x * y).sum()
-}
-
-/// Divide logits of recently-generated tokens by `penalty` (or multiply if logit < 0).
-/// Suppresses repetition without temperature sampling.
-fn apply_repetition_penalty(logits: &mut [f32], seen: &[u32], penalty: f32) {
-    for &id in seen {
-        if let Some(l) = logits.get_mut(id as usize) {
-            if *l > 0.0 { *l /= penalty; } else { *l *= penalty; }
-        }
+/// Classifier-free guidance in log-prob space: `neg + scale * (pos - neg)`.
+/// `scale = 1.0` leaves the positive distribution unchanged; larger values
+/// push away from whatever the negative prompt makes likely.
+pub(crate) fn apply_cfg(logits: &mut [f32], negative: &[f32], scale: f32) {
+    log_softmax(logits);
+    let mut neg = negative.to_vec();
+    log_softmax(&mut neg);
+    for (l, n) in logits.iter_mut().zip(neg) {
+        *l = n + scale * (*l - n);
     }
 }
 
-fn argmax(v: &[f32]) -> u32 {
-    v.iter().enumerate()
-        .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
-        .map(|(i, _)| i as u32)
-        .unwrap_or(0)
+fn log_softmax(x: &mut [f32]) {
+    let max = x.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let lse = x.iter().map(|v| (v - max).exp()).sum::<f32>().ln() + max;
+    x.iter_mut().for_each(|v| *v -= lse);
 }
 
-fn print_token(id: u32, vocab: &[String]) {
-    if let Some(tok) = vocab.get(id as usize) {
-        print!("{}", tok.replace('\u{0120}', " ").replace("<0x0A>", "\n"));
-        let _ = std::io::Write::flush(&mut std::io::stdout());
+/// Indices of the `k` largest values, best first.
+pub(crate) fn top_k(v: &[f32], k: usize) -> Vec<u32> {
+    let mut idx: Vec<u32> = (0..v.len() as u32).collect();
+    let k = k.min(idx.len());
+    if k == 0 {
+        return Vec::new();
     }
+    idx.select_nth_unstable_by(k - 1, |&a, &b| v[b as usize].total_cmp(&v[a as usize]));
+    idx.truncate(k);
+    idx.sort_by(|&a, &b| v[b as usize].total_cmp(&v[a as usize]));
+    idx
+}
+
+/// Log-probability of `id` under `logits`.
+pub(crate) fn logprob(logits: &[f32], id: u32) -> f32 {
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let lse = logits.iter().map(|v| (v - max).exp()).sum::<f32>().ln() + max;
+    logits[id as usize] - lse
 }
@@ -0,0 +1,281 @@
+//! Pipeline parallelism: contiguous runs of blocks on different Metal
+//! devices (`run --pipeline 0,1`), or on other Macs running `llmetal
+//! worker` (`run --rpc HOST:PORT`), for models too big for one GPU.
+//!
+//! Each stage holds its blocks' weights and does all of those blocks'
+//! work. A prompt is cut into micro-batches that move down the stages in
+//! order, so while stage 1 runs micro-batch 0, stage 0 is already on
+//! micro-batch 1. Each stage keeps its own blocks' K/V rows, and
+//! micro-batches reach it in position order, so attention stays causal.
+//!
+//! A decode step is a single micro-batch and gets no overlap; pipelining
+//! buys room for a bigger model, and speed only on prefill.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender};
+
+use anyhow::{Context, Result, bail, ensure};
+use metal::Buffer;
+
+use crate::gpu::{self, Gpu};
+use crate::model::{Arch, attend, rms_norm};
+use crate::profile::weight_scope;
+use crate::rpc::RpcStage;
+use crate::shard::split_ranges;
+use crate::tensor::{GGML_F32, TensorStore, ggml_type_name};
+use crate::weights::{LayerWeights, ModelWeights, WeightRef};
+
+/// Rows per micro-batch when `--micro-batch` is not given.
+pub const DEFAULT_MICRO_BATCH: usize = 16;
+
+/// One micro-batch between stages: its first row's offset into the prompt,
+/// and its rows packed `[rows, hidden]`.
+pub type MicroBatch = (usize, Vec<f32>);
+
+/// One layer's K/V rows.
+pub type KvRows<'a> = (&'a mut Vec<Arc<[f32]>>, &'a mut Vec<Arc<[f32]>>);
+
+/// A device and the tensors of the blocks it runs: matmul weights in its
+/// buffers, norms on the host.
+pub struct LocalStage {
+    gpu: Gpu,
+    matmul: HashMap<String, (u32, Buffer)>,
+    norms: HashMap<String, Vec<f32>>,
+}
+
+impl LocalStage {
+    pub fn new(gpu: Gpu) -> Self {
+        Self { gpu, matmul: HashMap::new(), norms: HashMap::new() }
+    }
+
+    pub fn gpu(&self) -> &Gpu {
+        &self.gpu
+    }
+
+    /// Take tensor `name`: norms (F32) stay on the host, the rest go to
+    /// the device.
+    pub fn insert(&mut self, name: &str, kind: u32, bytes: &[u8]) -> Result<()> {
+        if weight_scope(name).1.ends_with("_norm") {
+            ensure!(kind == GGML_F32, "norm '{name}' is {}, not F32", ggml_type_name(kind));
+            let w = bytes.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect();
+            self.norms.insert(name.to_string(), w);
+        } else {
+            let buf = self.gpu.upload_weight(bytes).with_context(|| format!("upload '{name}' to {}", self.gpu.device_name()))?;
+            self.matmul.insert(name.to_string(), (kind, buf));
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.matmul.contains_key(name) || self.norms.contains_key(name)
+    }
+
+    /// Bytes of weights on the device.
+    pub fn bytes(&self) -> u64 {
+        self.matmul.values().map(|(_, b)| b.length()).sum()
+    }
+
+    /// `x` (`[batch, k]`, a buffer on this device) times `name`.
+    pub fn matmul(&self, name: &str, x: &Buffer, n: usize, k: usize, batch: usize) -> Result<Buffer> {
+        let (kind, w) = self.matmul.get(name).with_context(|| format!("'{name}' is not on this stage"))?;
+        self.gpu
+            .matmul(*kind, w, 0, x, n, k, batch)
+            .with_context(|| format!("stage matmul '{name}' ({})", ggml_type_name(*kind)))
+    }
+
+    fn norm(&self, name: &str) -> Result<&[f32]> {
+        self.norms.get(name).map(Vec::as_slice).with_context(|| format!("norm '{name}' is not on this stage"))
+    }
+
+    /// One transformer block over one sequence's rows (packed `[rows,
+    /// hidden]`, from position `pos`), everything on this device: the same
+    /// arithmetic as the model's own block.
+    pub fn block(&self, arch: &Arch, w: &LayerWeights, x: Vec<f32>, pos: usize, (k_rows, v_rows): KvRows) -> Result<Vec<f32>> {
+        let gpu = &self.gpu;
+        let (hidden, n) = (arch.hidden, x.len() / arch.hidden);
+        let (q_dim, kv_dim) = (w.attn_q.rows, w.attn_k.rows);
+
+        let attn_norm_w = self.norm(&w.attn_norm.name)?;
+        let xn: Vec<f32> = x.chunks_exact(hidden).flat_map(|r| rms_norm(r, attn_norm_w, 1e-5)).collect();
+        let xn_buf = gpu.buf_from_f32(&xn);
+        let q = self.matmul(&w.attn_q.name, &xn_buf, q_dim, hidden, n)?;
+        let k = self.matmul(&w.attn_k.name, &xn_buf, kv_dim, hidden, n)?;
+        let v = self.matmul(&w.attn_v.name, &xn_buf, kv_dim, hidden, n)?;
+        let qkv = [gpu.read_f32(&q, q_dim * n), gpu.read_f32(&k, kv_dim * n), gpu.read_f32(&v, kv_dim * n)];
+        let attn_out = attend(arch, qkv, pos, k_rows, v_rows);
+        let o_proj = self.matmul(&w.attn_output.name, &gpu.buf_from_f32(&attn_out), hidden, q_dim, n)?;
+        let res1 = gpu.add(&gpu.buf_from_f32(&x), &o_proj, hidden * n);
+
+        let ffn_norm_w = self.norm(&w.ffn_norm.name)?;
+        let xn2: Vec<f32> =
+            gpu.read_f32(&res1, hidden * n).chunks_exact(hidden).flat_map(|r| rms_norm(r, ffn_norm_w, 1e-5)).collect();
+        let xn2_buf = gpu.buf_from_f32(&xn2);
+        let gate = self.matmul(&w.ffn_gate.name, &xn2_buf, arch.ffn_hidden, hidden, n)?;
+        let up = self.matmul(&w.ffn_up.name, &xn2_buf, arch.ffn_hidden, hidden, n)?;
+        let mid = gpu.silu_hadamard(&gate, &up, arch.ffn_hidden * n);
+        let down = self.matmul(&w.ffn_down.name, &mid, hidden, arch.ffn_hidden, n)?;
+        let out = gpu.add(&res1, &down, hidden * n);
+        Ok(gpu.read_f32(&out, hidden * n).to_vec())
+    }
+}
+
+/// Every tensor block `w` reads, norms included.
+pub fn block_tensors(w: &LayerWeights) -> [&str; 9] {
+    block_weights(w).map(|t| t.name.as_str())
+}
+
+/// The same tensors, in the same order.
+pub fn block_weights(w: &LayerWeights) -> [&WeightRef; 9] {
+    [
+        &w.attn_norm, &w.attn_q, &w.attn_k, &w.attn_v, &w.attn_output,
+        &w.ffn_norm, &w.ffn_gate, &w.ffn_up, &w.ffn_down,
+    ]
+}
+
+enum Backend {
+    /// A device in this process, by index into `sysinfo`'s list.
+    Device(usize, Box<LocalStage>),
+    Worker(RpcStage),
+}
+
+/// One device or worker and the blocks it runs.
+pub struct Stage {
+    pub layers: Range<usize>,
+    backend: Backend,
+}
+
+impl Stage {
+    /// Where the stage runs: `device 0` or `worker HOST:PORT`.
+    pub fn place(&self) -> String {
+        match &self.backend {
+            Backend::Device(i, _) => format!("device {i}"),
+            Backend::Worker(w) => format!("worker {}", w.addr()),
+        }
+    }
+
+    /// Weight bytes the stage holds.
+    pub fn bytes(&self) -> u64 {
+        match &self.backend {
+            Backend::Device(_, local) => local.bytes(),
+            Backend::Worker(w) => w.bytes_sent(),
+        }
+    }
+
+    /// `x` (`[batch, k]`) times `name` on this stage's device, outside a
+    /// pipelined pass. A worker only runs whole micro-batches.
+    pub fn matmul(&self, name: &str, x: &[f32], n: usize, k: usize, batch: usize) -> Result<Vec<f32>> {
+        match &self.backend {
+            Backend::Device(_, local) => {
+                let gpu = local.gpu();
+                let out = local.matmul(name, &gpu.buf_from_f32(x), n, k, batch)?;
+                Ok(gpu.read_f32(&out, n * batch).to_vec())
+            }
+            Backend::Worker(w) => {
+                bail!("'{name}' is on worker {}, which only runs whole blocks in a pipelined pass", w.addr())
+            }
+        }
+    }
+
+    fn holds(&self, name: &str) -> bool {
+        match &self.backend {
+            Backend::Device(_, local) => local.contains(name),
+            Backend::Worker(_) => true,
+        }
+    }
+
+    /// Run each micro-batch from `input`, at positions from `pos`, through
+    /// this stage's blocks and pass it to `output`. `kv` are a local
+    /// stage's blocks' K/V rows; a worker keeps its own.
+    pub(crate) fn serve(
+        &self, arch: &Arch, weights: &ModelWeights, pos: usize, kv: Vec<KvRows>,
+        input: Receiver<MicroBatch>, output: Sender<MicroBatch>,
+    ) -> Result<()> {
+        match &self.backend {
+            Backend::Device(_, local) => {
+                let mut kv = kv;
+                for (start, mut rows) in input {
+                    for (layer, (k, v)) in self.layers.clone().zip(&mut kv) {
+                        rows = local.block(arch, &weights.layers[layer], rows, pos + start, (&mut **k, &mut **v))?;
+                    }
+                    if output.send((start, rows)).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            }
+            Backend::Worker(w) => w.forward(pos, input, output),
+        }
+    }
+}
+
+/// The stages of `--pipeline` and `--rpc`, covering blocks `0..n_layers`
+/// in order: local devices first, then workers.
+pub struct Pipeline {
+    stages: Vec<Stage>,
+    pub micro_batch: usize,
+}
+
+impl Pipeline {
+    /// `n_layers` blocks split as evenly as possible across `devices` and
+    /// then `workers`, in the order given; opens the devices and connects
+    /// to the workers but uploads nothing.
+    pub fn new(devices: &[usize], workers: &[String], n_layers: usize, micro_batch: usize) -> Result<Self> {
+        ensure!(!devices.is_empty(), "a pipeline needs a local device for its first blocks");
+        let n_stages = devices.len() + workers.len();
+        ensure!(n_stages <= n_layers, "{n_stages} pipeline stages for {n_layers} layers");
+        ensure!(
+            workers.is_empty() || micro_batch <= crate::rpc::MAX_FORWARD_ROWS,
+            "--micro-batch {micro_batch} is more than a worker takes ({})",
+            crate::rpc::MAX_FORWARD_ROWS
+        );
+        let mut layers = split_ranges(n_layers, n_stages, 1).into_iter();
+        let mut stages = Vec::new();
+        for (gpu, &i) in gpu::open_devices(devices)?.into_iter().zip(devices) {
+            stages.push(Stage { layers: layers.next().unwrap_or_default(), backend: Backend::Device(i, Box::new(LocalStage::new(gpu))) });
+        }
+        for addr in workers {
+            stages.push(Stage { layers: layers.next().unwrap_or_default(), backend: Backend::Worker(RpcStage::connect(addr)?) });
+        }
+        Ok(Self { stages, micro_batch: micro_batch.max(1) })
+    }
+
+    /// Give each stage its blocks' tensors: uploaded to a device, or sent
+    /// to a worker.
+    pub fn load(&mut self, store: &TensorStore, arch: &Arch, weights: &ModelWeights) -> Result<()> {
+        for stage in &mut self.stages {
+            let blocks = &weights.layers[stage.layers.clone()];
+            match &mut stage.backend {
+                Backend::Device(_, local) => {
+                    for name in blocks.iter().flat_map(block_tensors) {
+                        local.insert(name, store.meta(name)?.kind, store.get(name)?)?;
+                    }
+                }
+                Backend::Worker(w) => w.load(store, arch, stage.layers.start, blocks)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    /// The first block on a worker, which only runs pipelined passes.
+    pub fn first_worker_layer(&self) -> Option<usize> {
+        self.stages.iter().find(|s| matches!(s.backend, Backend::Worker(_))).map(|s| s.layers.start)
+    }
+
+    /// The stage holding weight `name`, if it is a block's.
+    pub fn stage_of(&self, name: &str) -> Option<&Stage> {
+        let layer = weight_scope(name).0?;
+        self.stages.iter().find(|s| s.layers.contains(&layer) && s.holds(name))
+    }
+}
+
+/// `0..len` in runs of `size` rows, the last one shorter.
+pub fn micro_batches(len: usize, size: usize) -> Vec<Range<usize>> {
+    let size = size.max(1);
+    (0..len).step_by(size).map(|start| start..(start + size).min(len)).collect()
+}
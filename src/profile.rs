@@ -0,0 +1,229 @@
+//! os_signpost intervals, so Instruments' Points of Interest and Metal System
+//! Trace tracks show each kernel dispatch by name.
+//!
+//! The `os_signpost_interval_begin/end` macros are C-only; this calls the
+//! function they expand to, with an empty format string. Names must be
+//! `'static` because the OS records them as offsets into this binary's image.
+//! Off macOS every interval is a no-op.
+//!
+//! `GpuProfile` is the other half: the GPU time `run --profile` measured per
+//! dispatch (see `Gpu::enable_timestamps`), summed by layer and op.
+
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// A signpost channel (an `os_log` handle), subsystem `llmetal`.
+pub struct Signposts {
+    #[cfg(target_os = "macos")]
+    log: *mut std::ffi::c_void,
+}
+
+// An os_log handle is an immutable, thread-safe object that lives for the
+// whole process.
+unsafe impl Send for Signposts {}
+unsafe impl Sync for Signposts {}
+
+impl Signposts {
+    pub fn new(category: &CStr) -> Self {
+        #[cfg(target_os = "macos")]
+        {
+            let log = unsafe { ffi::os_log_create(c"llmetal".as_ptr(), category.as_ptr()) };
+            Self { log }
+        }
+        #[cfg(not(target_os = "macos"))]
+        {
+            let _ = category;
+            Self {}
+        }
+    }
+
+    /// Begin the interval `name`; it ends when the guard drops.
+    pub fn interval(&self, name: &'static CStr) -> Interval<'_> {
+        #[cfg(target_os = "macos")]
+        let id = unsafe { ffi::os_signpost_id_generate(self.log) };
+        #[cfg(not(target_os = "macos"))]
+        let id = 0;
+        self.emit(ffi::BEGIN, id, name);
+        Interval { signposts: self, id, name }
+    }
+
+    #[cfg(target_os = "macos")]
+    fn emit(&self, kind: u8, id: u64, name: &'static CStr) {
+        // Encoded arguments for an empty format: summary byte, argument count.
+        let mut buf = [0u8; 2];
+        unsafe {
+            if ffi::os_signpost_enabled(self.log) {
+                ffi::_os_signpost_emit_with_name_impl(
+                    &raw const ffi::__dso_handle,
+                    self.log,
+                    kind,
+                    id,
+                    name.as_ptr(),
+                    c"".as_ptr(),
+                    buf.as_mut_ptr(),
+                    buf.len() as u32,
+                );
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn emit(&self, _kind: u8, _id: u64, _name: &'static CStr) {}
+}
+
+pub struct Interval<'a> {
+    signposts: &'a Signposts,
+    id: u64,
+    name: &'static CStr,
+}
+
+impl Drop for Interval<'_> {
+    fn drop(&mut self) {
+        self.signposts.emit(ffi::END, self.id, self.name);
+    }
+}
+
+/// GPU time per `(layer, op)`. `op` is the weight a matmul multiplied by
+/// (`attn_q`, `ffn_down`, `output`, ...) or an elementwise step
+/// (`attn_residual`, `ffn_act`, `ffn_residual`); `layer` is `None` outside
+/// the blocks. Ops named `attn_*` and `ffn_*` make up a block's two halves.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GpuProfile {
+    entries: BTreeMap<(Option<usize>, String), OpTime>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpTime {
+    pub dispatches: usize,
+    pub time: Duration,
+}
+
+impl OpTime {
+    fn add(&mut self, other: OpTime) {
+        self.dispatches += other.dispatches;
+        self.time += other.time;
+    }
+}
+
+impl GpuProfile {
+    pub fn record(&mut self, layer: Option<usize>, op: &str, time: Duration) {
+        self.entries.entry((layer, op.to_string())).or_default().add(OpTime { dispatches: 1, time });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn total(&self) -> OpTime {
+        let mut total = OpTime::default();
+        self.entries.values().for_each(|&t| total.add(t));
+        total
+    }
+
+    /// Each op summed over the layers, most expensive first.
+    pub fn by_op(&self) -> Vec<(String, OpTime)> {
+        let mut ops: BTreeMap<&str, OpTime> = BTreeMap::new();
+        for ((_, op), &t) in &self.entries {
+            ops.entry(op).or_default().add(t);
+        }
+        let mut ops: Vec<_> = ops.into_iter().map(|(op, t)| (op.to_string(), t)).collect();
+        ops.sort_by_key(|(_, t)| std::cmp::Reverse(t.time));
+        ops
+    }
+
+    /// Each layer in order, then `None` (the output head and the rest).
+    pub fn by_layer(&self) -> Vec<(Option<usize>, OpTime)> {
+        let mut layers: BTreeMap<Option<usize>, OpTime> = BTreeMap::new();
+        for (&(layer, _), &t) in &self.entries {
+            layers.entry(layer).or_default().add(t);
+        }
+        let mut layers: Vec<_> = layers.into_iter().collect();
+        let outside = layers.iter().take_while(|(l, _)| l.is_none()).count();
+        layers.rotate_left(outside);
+        layers
+    }
+
+    /// Attention (`attn_*`), feed-forward (`ffn_*`) and everything else.
+    pub fn by_half(&self) -> [(&'static str, OpTime); 3] {
+        let mut halves = [("attention", OpTime::default()), ("ffn", OpTime::default()), ("other", OpTime::default())];
+        for ((_, op), &t) in &self.entries {
+            let i = if op.starts_with("attn_") { 0 } else if op.starts_with("ffn_") { 1 } else { 2 };
+            halves[i].1.add(t);
+        }
+        halves
+    }
+
+    /// Every time multiplied by `factor`, e.g. GPU ticks to nanoseconds.
+    pub fn scaled(&self, factor: f64) -> Self {
+        let entries = self
+            .entries
+            .iter()
+            .map(|(k, t)| (k.clone(), OpTime { dispatches: t.dispatches, time: t.time.mul_f64(factor) }))
+            .collect();
+        Self { entries }
+    }
+
+    /// The table `run --profile` prints: the attention/FFN split, each op,
+    /// then each layer, with its share of the total.
+    pub fn report(&self) -> String {
+        let total = self.total();
+        let row = |out: &mut String, name: &str, t: OpTime| {
+            let share = if total.time.is_zero() { 0.0 } else { 100.0 * t.time.as_secs_f64() / total.time.as_secs_f64() };
+            let ms = t.time.as_secs_f64() * 1e3;
+            let _ = writeln!(out, "  {name:<16} {ms:>9.2}ms {share:>5.1}%  {:>6} dispatches", t.dispatches);
+        };
+        let mut out = format!("GPU time: {:.2}ms in {} dispatches\n", total.time.as_secs_f64() * 1e3, total.dispatches);
+        for (half, t) in self.by_half() {
+            row(&mut out, half, t);
+        }
+        out.push_str("by op:\n");
+        for (op, t) in self.by_op() {
+            row(&mut out, &op, t);
+        }
+        out.push_str("by layer:\n");
+        for (layer, t) in self.by_layer() {
+            row(&mut out, &layer.map_or_else(|| "outside layers".to_string(), |l| format!("layer {l}")), t);
+        }
+        out.trim_end().to_string()
+    }
+}
+
+/// Where a matmul against tensor `name` is charged: `blk.3.attn_q.weight`
+/// is op `attn_q` of layer 3; anything else (`output.weight`,
+/// `medusa.0.fc.weight`) is its first name segment, outside the layers.
+pub fn weight_scope(name: &str) -> (Option<usize>, &str) {
+    let mut parts = name.split('.');
+    let first = parts.next().unwrap_or_default();
+    if first == "blk"
+        && let (Some(layer), Some(op)) = (parts.next().and_then(|l| l.parse().ok()), parts.next())
+    {
+        return (Some(layer), op);
+    }
+    (None, first)
+}
+
+mod ffi {
+    /// `os_signpost_type_t`
+    pub const BEGIN: u8 = 1;
+    pub const END: u8 = 2;
+
+    #[cfg(target_os = "macos")]
+    unsafe extern "C" {
+        pub static __dso_handle: u8;
+        pub fn os_log_create(subsystem: *const std::ffi::c_char, category: *const std::ffi::c_char) -> *mut std::ffi::c_void;
+        pub fn os_signpost_id_generate(log: *mut std::ffi::c_void) -> u64;
+        pub fn os_signpost_enabled(log: *mut std::ffi::c_void) -> bool;
+        pub fn _os_signpost_emit_with_name_impl(
+            dso: *const u8,
+            log: *mut std::ffi::c_void,
+            kind: u8,
+            id: u64,
+            name: *const std::ffi::c_char,
+            format: *const std::ffi::c_char,
+            buf: *mut u8,
+            size: u32,
+        );
+    }
+}
@@ -0,0 +1,59 @@
+//! How closely a candidate model's next-token distributions follow a
+//! reference's over the same tokens, for choosing a quant level: the KL
+//! divergence KL(reference ‖ candidate) at every position, and how often both
+//! put the same token on top. These are the numbers llama.cpp's
+//! `perplexity --kl-divergence` reports.
+
+/// KL(P ‖ Q) in nats for the softmax distributions of two logit rows.
+pub fn kl_divergence(reference: &[f32], candidate: &[f32]) -> f64 {
+    let (p, q) = (log_probs(reference), log_probs(candidate));
+    p.iter().zip(&q).map(|(lp, lq)| lp.exp() * (lp - lq)).sum::<f64>().max(0.0)
+}
+
+/// Log-softmax in f64, so near-zero probabilities do not underflow the sum.
+fn log_probs(logits: &[f32]) -> Vec<f64> {
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max) as f64;
+    let lse = logits.iter().map(|&v| (v as f64 - max).exp()).sum::<f64>().ln() + max;
+    logits.iter().map(|&v| v as f64 - lse).collect()
+}
+
+fn argmax(v: &[f32]) -> usize {
+    (0..v.len()).max_by(|&a, &b| v[a].total_cmp(&v[b])).unwrap_or(0)
+}
+
+/// Running totals over positions; `merge` adds one prompt's totals to another.
+#[derive(Clone, Debug, Default)]
+pub struct LogitAgreement {
+    pub positions: usize,
+    pub top1_matches: usize,
+    pub kl_sum: f64,
+    pub kl_max: f64,
+}
+
+impl LogitAgreement {
+    /// One position: the reference's and the candidate's logits for the same
+    /// next token.
+    pub fn add(&mut self, reference: &[f32], candidate: &[f32]) {
+        let kl = kl_divergence(reference, candidate);
+        self.positions += 1;
+        self.top1_matches += usize::from(argmax(reference) == argmax(candidate));
+        self.kl_sum += kl;
+        self.kl_max = self.kl_max.max(kl);
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.positions += other.positions;
+        self.top1_matches += other.top1_matches;
+        self.kl_sum += other.kl_sum;
+        self.kl_max = self.kl_max.max(other.kl_max);
+    }
+
+    pub fn mean_kl(&self) -> f64 {
+        self.kl_sum / self.positions.max(1) as f64
+    }
+
+    /// Fraction of positions where both models pick the same top token.
+    pub fn top1_agreement(&self) -> f64 {
+        self.top1_matches as f64 / self.positions.max(1) as f64
+    }
+}
@@ -0,0 +1,70 @@
+//! Retrieval-augmented generation (`llmetal rag`): documents cut into
+//! overlapping chunks, the chunks embedded into a `search::VectorIndex`,
+//! the ones nearest the question rendered into a prompt template, and the
+//! answer generated from that prompt.
+//!
+//! Chunks are runs of whole words, so a passage never splits one. Each
+//! keeps its document's id with `#n` appended, which is what the prompt
+//! cites and what `rag` reports as retrieved.
+
+use crate::embed::Document;
+use crate::search::Hit;
+
+/// The prompt `rag` fills when no `--template` is given: `{context}`
+/// becomes the numbered passages and `{query}` the question.
+pub const DEFAULT_TEMPLATE: &str = "Answer the question using only the passages below. \
+Cite passages by their number, and say so if they do not contain the answer.\n\n\
+{context}\n\nQuestion: {query}";
+
+/// Runs of `size` words starting every `size - overlap` words; the last
+/// may be shorter. Empty for text without words.
+pub fn chunk_text(text: &str, size: usize, overlap: usize) -> Vec<String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let size = size.max(1);
+    let step = size.saturating_sub(overlap).max(1);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < words.len() {
+        let end = (start + size).min(words.len());
+        chunks.push(words[start..end].join(" "));
+        if end == words.len() {
+            break;
+        }
+        start += step;
+    }
+    chunks
+}
+
+/// Every document's chunks, in order, with ids `<id>#<n>` (the document's
+/// line number when it has no id).
+pub fn chunk_documents(docs: &[Document], size: usize, overlap: usize) -> Vec<Document> {
+    docs.iter()
+        .enumerate()
+        .flat_map(|(i, doc)| {
+            let id = doc.id.clone().unwrap_or_else(|| i.to_string());
+            chunk_text(&doc.text, size, overlap)
+                .into_iter()
+                .enumerate()
+                .map(move |(n, text)| Document { id: Some(format!("{id}#{n}")), text })
+        })
+        .collect()
+}
+
+/// `template` with `{context}` replaced by the hits' passages, numbered
+/// from 1 in rank order, and `{query}` by the question.
+pub fn render_prompt(template: &str, query: &str, chunks: &[Document], hits: &[Hit]) -> String {
+    let context = hits
+        .iter()
+        .enumerate()
+        .map(|(rank, hit)| {
+            let chunk = &chunks[hit.index];
+            match &chunk.id {
+                Some(id) => format!("[{}] ({id}) {}", rank + 1, chunk.text),
+                None => format!("[{}] {}", rank + 1, chunk.text),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    // One pass, so braces inside passages or the question are left alone.
+    template.split("{context}").map(|part| part.replace("{query}", query)).collect::<Vec<_>>().join(&context)
+}
@@ -0,0 +1,110 @@
+//! Reasoning models: replies that think inside `<think> ... </think>`
+//! before they answer (Qwen3, DeepSeek-R1 and their distills).
+//!
+//! Both tags are single tokens in those vocabularies, so everything here
+//! works on ids. `ThinkStream` sorts the tokens of a streamed reply into
+//! reasoning and answer, for `--reasoning hide` / `separate`; the sampler
+//! caps the thinking with `SamplerConfig::max_thinking`, forcing `</think>`
+//! once a block has run that many tokens, so the answer still comes within
+//! `max_new`. `reply::parse` splits a finished reply the same way by text.
+
+use anyhow::{Result, bail};
+
+pub const OPEN: &str = "<think>";
+pub const CLOSE: &str = "</think>";
+
+/// Thinking budgets for `--reasoning-effort` (or `reasoning_effort`).
+pub const EFFORTS: [(&str, usize); 3] = [("low", 512), ("medium", 2048), ("high", 8192)];
+
+/// The ids of `<think>` and `</think>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThinkTokens {
+    pub open: u32,
+    pub close: u32,
+}
+
+impl ThinkTokens {
+    /// `None` unless `vocab` has both tags as single tokens.
+    pub fn find(vocab: &[String]) -> Option<Self> {
+        let id = |tag: &str| vocab.iter().position(|t| t == tag).map(|i| i as u32);
+        Some(Self { open: id(OPEN)?, close: id(CLOSE)? })
+    }
+
+    /// How many tokens the thinking block still open at the end of
+    /// `context` holds, or `None` when the last tag was a `</think>` (or
+    /// there is none). A template that opens the block in the prompt
+    /// counts the same as a reply that opens it.
+    pub fn thinking_len(&self, context: &[u32]) -> Option<usize> {
+        let last = context.iter().rposition(|&id| id == self.open || id == self.close)?;
+        (context[last] == self.open).then(|| context.len() - last - 1)
+    }
+}
+
+/// `low`, `medium` or `high` as a thinking budget in tokens.
+pub fn effort_budget(effort: &str) -> Result<usize> {
+    match EFFORTS.iter().find(|(name, _)| *name == effort) {
+        Some(&(_, budget)) => Ok(budget),
+        None => bail!("unknown reasoning effort '{effort}' (expected low, medium, high)"),
+    }
+}
+
+/// What `run` and `chat` do with the thinking of a streamed reply.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReasoningMode {
+    /// Print it inline, tags included, like any other text.
+    #[default]
+    Show,
+    /// Leave it out; only the answer is printed.
+    Hide,
+    /// The answer on stdout, the thinking on stderr (and under its own key
+    /// in `--json-output`).
+    Separate,
+}
+
+impl std::str::FromStr for ReasoningMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "show" => Ok(Self::Show),
+            "hide" => Ok(Self::Hide),
+            "separate" => Ok(Self::Separate),
+            _ => bail!("unknown reasoning mode '{s}' (expected show, hide, separate)"),
+        }
+    }
+}
+
+/// Which part of a reply a token belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Segment {
+    /// `<think>` or `</think>` itself.
+    Tag,
+    Reasoning,
+    Content,
+}
+
+/// Sorts a reply's tokens as they stream.
+#[derive(Clone, Debug)]
+pub struct ThinkStream {
+    tags: ThinkTokens,
+    thinking: bool,
+}
+
+impl ThinkStream {
+    /// For the reply to `prompt`: already thinking when the prompt ends
+    /// inside an open block.
+    pub fn new(tags: ThinkTokens, prompt: &[u32]) -> Self {
+        Self { tags, thinking: tags.thinking_len(prompt).is_some() }
+    }
+
+    pub fn route(&mut self, id: u32) -> Segment {
+        if id == self.tags.open || id == self.tags.close {
+            self.thinking = id == self.tags.open;
+            Segment::Tag
+        } else if self.thinking {
+            Segment::Reasoning
+        } else {
+            Segment::Content
+        }
+    }
+}
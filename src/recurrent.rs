@@ -0,0 +1,178 @@
+//! What the models without a KV cache (`ssm`, `rwkv`) share: matmuls routed
+//! the way the encoder (`bert`) routes them, and the decoding loop over a
+//! state that only moves forward. `t5`'s decoder runs the same loop.
+//!
+//! Such a state can't be cut back to a shorter prefix the way K/V rows can,
+//! so these models sample exactly as `LlamaModel::generate_cached` does
+//! but without drafts, guidance or beams, and reuse a state only when the
+//! new prompt extends the tokens it has seen.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{Result, ensure};
+use metal::Buffer;
+
+use crate::cpu::dot;
+use crate::events::{FinishReason, GenerationEvent, Timings, Usage};
+use crate::gpu::Gpu;
+use crate::model::{GenerateOptions, logprob};
+use crate::sampler::Sampler;
+use crate::tensor::{GGML_Q8_0, TensorStore};
+use crate::tokenizer::detokenize;
+use crate::weights::WeightRef;
+
+/// A tensor store and the weights taken out of it so far.
+pub struct Matmuls {
+    pub store: TensorStore,
+    gpu: Gpu,
+    /// Q8_0 weights uploaded for the matmul kernels.
+    weight_cache: HashMap<String, Buffer>,
+    /// Everything else (and every norm, bias and vector), dequantized once.
+    cpu_cache: HashMap<String, Arc<[f32]>>,
+}
+
+impl Matmuls {
+    pub fn open(path: &str) -> Result<Self> {
+        let gpu = Gpu::new()?;
+        let store = TensorStore::open(path, &gpu.device)?;
+        Ok(Self { store, gpu, weight_cache: HashMap::new(), cpu_cache: HashMap::new() })
+    }
+
+    /// `x @ W^T + b` over `n` packed rows: Q8_0 weights through the GPU
+    /// kernels, anything else on the CPU.
+    pub fn linear(&mut self, w: &WeightRef, bias: Option<&WeightRef>, x: &[f32], n: usize) -> Result<Vec<f32>> {
+        let (rows, cols) = (w.rows, w.cols);
+        let mut out = if w.kind == GGML_Q8_0 {
+            if !self.weight_cache.contains_key(&w.name) {
+                let buf = self.gpu.buf_from_bytes(self.store.get(&w.name)?);
+                self.weight_cache.insert(w.name.clone(), buf);
+            }
+            let buf = &self.weight_cache[&w.name];
+            let x_buf = self.gpu.buf_from_f32(x);
+            let out = match n {
+                1 => self.gpu.q8_0_matvec(buf, 0, &x_buf, rows, cols),
+                _ => self.gpu.q8_0_matmul(buf, 0, &x_buf, rows, cols, n),
+            };
+            self.gpu.read_f32(&out, rows * n).to_vec()
+        } else {
+            let m = self.cpu_weights(&w.name)?;
+            x.chunks_exact(cols).flat_map(|xi| m.chunks_exact(cols).map(|r| dot(r, xi)).collect::<Vec<_>>()).collect()
+        };
+        if let Some(b) = bias {
+            let b = self.cpu_weights(&b.name)?;
+            out.chunks_exact_mut(rows).for_each(|r| r.iter_mut().zip(b.iter()).for_each(|(a, b)| *a += b));
+        }
+        Ok(out)
+    }
+
+    pub fn cpu_weights(&mut self, name: &str) -> Result<Arc<[f32]>> {
+        if let Some(w) = self.cpu_cache.get(name) {
+            return Ok(w.clone());
+        }
+        let w: Arc<[f32]> = self.store.dequant(name)?.into();
+        self.cpu_cache.insert(name.to_string(), w.clone());
+        Ok(w)
+    }
+
+    /// Embedding rows of `tokens`, packed.
+    pub fn embed(&self, table: &WeightRef, tokens: &[u32]) -> Result<Vec<f32>> {
+        let mut x = Vec::with_capacity(tokens.len() * table.cols);
+        for &t in tokens {
+            ensure!((t as usize) < table.rows, "token {t} >= vocab {}", table.rows);
+            x.extend(self.store.dequant_row(&table.name, t as usize)?);
+        }
+        Ok(x)
+    }
+
+    /// `LlamaModel::declared_stop_tokens`.
+    pub fn declared_stop_tokens(&self) -> Vec<u32> {
+        ["tokenizer.ggml.eos_token_id", "tokenizer.ggml.eot_token_id"]
+            .iter()
+            .filter_map(|k| self.store.metadata.get(*k).and_then(|v| v.as_u64()))
+            .map(|id| id as u32)
+            .collect()
+    }
+}
+
+/// `opts` minus what needs a cache that can be cut back.
+pub fn check_options(opts: &GenerateOptions, family: &str) -> Result<()> {
+    ensure!(
+        opts.cfg.is_none() && opts.draft.is_none(),
+        "{family} models cannot run classifier-free guidance or speculative decoding"
+    );
+    Ok(())
+}
+
+/// Sample a reply to `tokens`, of which the first `reused` are already in
+/// the state `forward` advances. `forward` runs tokens after everything
+/// before them and returns the logits that follow the last.
+pub fn decode(
+    tokens: &[u32],
+    reused: usize,
+    opts: &GenerateOptions,
+    vocab: &[String],
+    load_ms: u128,
+    on_event: &mut dyn FnMut(GenerationEvent),
+    forward: &mut dyn FnMut(&[u32]) -> Result<Vec<f32>>,
+) -> Result<()> {
+    let mut sampler = Sampler::new(opts.sampling.clone(), vocab).with_stop_tokens(&opts.stop_tokens);
+    let mut context: Vec<u32> = tokens.to_vec();
+    let mut timings = Timings { load_ms, ..Timings::default() };
+
+    let t0 = std::time::Instant::now();
+    let mut logits = forward(&tokens[reused..])?;
+    timings.prefill_ms = t0.elapsed().as_millis();
+    timings.prefill_tokens = tokens.len() - reused;
+    on_event(GenerationEvent::PromptProcessed { n_tokens: timings.prefill_tokens, ms: timings.prefill_ms });
+
+    let t1 = std::time::Instant::now();
+    let mut reason = FinishReason::MaxTokens;
+    let (mut sample_us, mut detokenize_us) = (0u128, 0u128);
+    for step in 0..=opts.max_new {
+        if opts.cancel.as_ref().is_some_and(|c| c.load(std::sync::atomic::Ordering::Relaxed)) {
+            reason = FinishReason::Cancelled;
+            break;
+        }
+        if step > 0 {
+            logits = forward(&context[context.len() - 1..])?;
+        }
+        let t = std::time::Instant::now();
+        let id = sampler.sample(&mut logits, &context, context.len() - tokens.len());
+        sample_us += t.elapsed().as_micros();
+        if sampler.looping() {
+            reason = FinishReason::Loop;
+            break;
+        }
+        if id == 2 {   // </s> EOS
+            reason = FinishReason::Eos;
+            break;
+        }
+        if opts.stop_tokens.contains(&id) {
+            reason = FinishReason::Stop;
+            break;
+        }
+        let t = std::time::Instant::now();
+        let text = detokenize(id, vocab);
+        detokenize_us += t.elapsed().as_micros();
+        on_event(GenerationEvent::Token { id, text, logprob: logprob(&logits, id) });
+        context.push(id);
+        if step > 0 {
+            timings.decode_tokens += 1;
+        }
+    }
+    timings.decode_ms = t1.elapsed().as_millis();
+    timings.sample_ms = sample_us / 1000;
+    timings.detokenize_ms = detokenize_us / 1000;
+    let usage = Usage { prompt_tokens: tokens.len(), completion_tokens: context.len() - tokens.len(), cached_tokens: reused };
+    on_event(GenerationEvent::Done { reason, timings, usage });
+    Ok(())
+}
+
+/// How many of `tokens` a state that has seen `seen` can skip: all of
+/// `seen` when `tokens` extends it, nothing otherwise (the state must then
+/// start over). At least the last prompt token is always run, since its
+/// logits start decoding.
+pub fn reusable(seen: &[u32], tokens: &[u32]) -> usize {
+    if tokens.starts_with(seen) && seen.len() < tokens.len() { seen.len() } else { 0 }
+}
@@ -0,0 +1,506 @@
+//! Regex-guided decoding: `run --regex PATTERN` and `response_format:
+//! {"type": "regex", "pattern": ...}` keep the reply a prefix of a match,
+//! so dates, UUIDs or version strings come out well-formed.
+//!
+//! The pattern must match the whole reply (`^` and `$` are implied) and is
+//! compiled once to a DFA over bytes: a Thompson NFA, then subset
+//! construction over the byte classes the pattern tells apart.
+//! `RegexConstraint` walks each vocab token's bytes from the current DFA
+//! state and masks the tokens that fall off it, caching the walk per state;
+//! end-of-sequence is only allowed in an accepting state.
+//!
+//! Syntax: literals, `.`, `[...]` and `[^...]` classes of ASCII characters
+//! and ranges, `\d \w \s` and their negations, groups `(...)` and
+//! `(?:...)`, `|`, and the quantifiers `* + ? {n} {n,} {n,m}` (a trailing
+//! `?` for laziness is accepted and means nothing here). No backreferences
+//! or lookaround, which a DFA cannot express. `.` and negated classes match
+//! any non-ASCII byte, so they pass UTF-8 text through.
+
+use std::collections::HashMap;
+
+use anyhow::{Result, bail, ensure};
+
+use crate::json_grammar::token_bytes;
+use crate::tokenizer::detokenize;
+
+/// A state no match continues from.
+pub const DEAD: u32 = u32::MAX;
+/// Largest DFA `Regex::new` builds before refusing the pattern.
+pub const MAX_STATES: usize = 10_000;
+/// Largest `{n,m}` bound; each repeat copies the group.
+const MAX_REPEAT: u32 = 1_000;
+const MAX_NFA_STATES: usize = 200_000;
+
+/// Bytes as a 256-bit set.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    fn byte(b: u8) -> Self {
+        let mut s = Self::default();
+        s.insert(b);
+        s
+    }
+
+    fn range(lo: u8, hi: u8) -> Self {
+        let mut s = Self::default();
+        (lo..=hi).for_each(|b| s.insert(b));
+        s
+    }
+
+    fn insert(&mut self, b: u8) {
+        self.0[b as usize / 64] |= 1 << (b % 64);
+    }
+
+    fn contains(&self, b: u8) -> bool {
+        self.0[b as usize / 64] & (1 << (b % 64)) != 0
+    }
+
+    fn union(mut self, other: Self) -> Self {
+        self.0.iter_mut().zip(other.0).for_each(|(a, b)| *a |= b);
+        self
+    }
+
+    /// Every ASCII byte not in `self`, plus every byte above ASCII.
+    fn negate_ascii(self) -> Self {
+        let mut out = Self::range(0x80, 0xFF);
+        (0..0x80).filter(|&b| !self.contains(b)).for_each(|b| out.insert(b));
+        out
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Empty,
+    Set(ByteSet),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat(Box<Node>, u32, Option<u32>),
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let hit = self.peek() == Some(c);
+        self.pos += hit as usize;
+        hit
+    }
+
+    fn next(&mut self) -> Result<char> {
+        let c = self.peek().ok_or_else(|| anyhow::anyhow!("pattern ends early"))?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn alt(&mut self) -> Result<Node> {
+        let mut arms = vec![self.concat()?];
+        while self.eat('|') {
+            arms.push(self.concat()?);
+        }
+        Ok(if arms.len() == 1 { arms.pop().unwrap() } else { Node::Alt(arms) })
+    }
+
+    fn concat(&mut self) -> Result<Node> {
+        let mut items = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            items.push(self.repeat()?);
+        }
+        Ok(if items.len() == 1 { items.pop().unwrap() } else { Node::Concat(items) })
+    }
+
+    fn repeat(&mut self) -> Result<Node> {
+        let mut node = self.atom()?;
+        loop {
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') if self.chars.get(self.pos + 1).is_some_and(char::is_ascii_digit) => {
+                    self.pos += 1;
+                    let min = self.number()?;
+                    let max = match self.eat(',') {
+                        true if self.peek() == Some('}') => None,
+                        true => Some(self.number()?),
+                        false => Some(min),
+                    };
+                    ensure!(self.peek() == Some('}'), "expected '}}' at {}", self.pos);
+                    ensure!(max.is_none_or(|m| m >= min), "repeat {{{min},{}}} has max below min", max.unwrap_or(0));
+                    ensure!(min.max(max.unwrap_or(0)) <= MAX_REPEAT, "repeat counts above {MAX_REPEAT} are not supported");
+                    (min, max)
+                }
+                _ => return Ok(node),
+            };
+            // Past the quantifier's last character, and a lazy `?`.
+            self.pos += 1;
+            self.eat('?');
+            node = Node::Repeat(Box::new(node), min, max);
+        }
+    }
+
+    fn number(&mut self) -> Result<u32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        digits.parse().map_err(|_| anyhow::anyhow!("expected a number at {start}"))
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        let at = self.pos;
+        Ok(match self.next()? {
+            '(' => {
+                if self.eat('?') {
+                    ensure!(self.eat(':'), "only (?:...) groups are supported, at {at}");
+                }
+                let inner = self.alt()?;
+                ensure!(self.eat(')'), "unclosed group at {at}");
+                inner
+            }
+            '[' => Node::Set(self.class()?),
+            '.' => Node::Set(ByteSet::byte(b'\n').negate_ascii()),
+            '^' if at == 0 => Node::Empty,
+            '$' if self.peek().is_none() => Node::Empty,
+            '\\' => self.escape()?,
+            c @ ('*' | '+' | '?' | ')' | '^' | '$') => bail!("unexpected '{c}' at {at}"),
+            c => literal(c),
+        })
+    }
+
+    fn escape(&mut self) -> Result<Node> {
+        let c = self.next()?;
+        Ok(match shorthand(c) {
+            Some(set) => Node::Set(set),
+            None => literal(control(c)?),
+        })
+    }
+
+    /// After `[`: the class up to its `]`.
+    fn class(&mut self) -> Result<ByteSet> {
+        let at = self.pos - 1;
+        let negated = self.eat('^');
+        let mut set = ByteSet::default();
+        let mut first = true;
+        loop {
+            let c = self.next().map_err(|_| anyhow::anyhow!("unclosed class at {at}"))?;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let lo = match c {
+                '\\' => {
+                    let e = self.next()?;
+                    if let Some(s) = shorthand(e) {
+                        set = set.union(s);
+                        continue;
+                    }
+                    control(e)?
+                }
+                c => c,
+            };
+            let hi = match (self.peek(), self.chars.get(self.pos + 1)) {
+                (Some('-'), Some(&h)) if h != ']' => {
+                    self.pos += 2;
+                    if h == '\\' { control(self.next()?)? } else { h }
+                }
+                _ => lo,
+            };
+            ensure!(lo.is_ascii() && hi.is_ascii(), "non-ASCII characters in [...] are not supported (at {at})");
+            ensure!(lo <= hi, "class range {lo}-{hi} is backwards");
+            set = set.union(ByteSet::range(lo as u8, hi as u8));
+        }
+        Ok(if negated { set.negate_ascii() } else { set })
+    }
+}
+
+/// `\d`, `\w`, `\s` and their negations.
+fn shorthand(c: char) -> Option<ByteSet> {
+    let digit = ByteSet::range(b'0', b'9');
+    let word = digit.union(ByteSet::range(b'a', b'z')).union(ByteSet::range(b'A', b'Z')).union(ByteSet::byte(b'_'));
+    let space = b" \t\n\r\x0B\x0C".iter().fold(ByteSet::default(), |s, &b| s.union(ByteSet::byte(b)));
+    Some(match c {
+        'd' => digit,
+        'D' => digit.negate_ascii(),
+        'w' => word,
+        'W' => word.negate_ascii(),
+        's' => space,
+        'S' => space.negate_ascii(),
+        _ => return None,
+    })
+}
+
+/// The character an escape stands for: `\n`, `\t`, `\r`, or any
+/// punctuation as itself.
+fn control(c: char) -> Result<char> {
+    Ok(match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        c if c.is_ascii_alphanumeric() => bail!("unsupported escape \\{c}"),
+        c => c,
+    })
+}
+
+/// `c`'s UTF-8 bytes in sequence.
+fn literal(c: char) -> Node {
+    let mut buf = [0; 4];
+    let bytes: Vec<Node> = c.encode_utf8(&mut buf).bytes().map(|b| Node::Set(ByteSet::byte(b))).collect();
+    if bytes.len() == 1 { bytes.into_iter().next().unwrap() } else { Node::Concat(bytes) }
+}
+
+enum NState {
+    Eps(Vec<usize>),
+    Byte(ByteSet, usize),
+    Match,
+}
+
+/// Thompson construction, built back to front: `compile` returns the state
+/// that matches `node` and then continues at `next`.
+struct Nfa {
+    states: Vec<NState>,
+}
+
+impl Nfa {
+    fn push(&mut self, s: NState) -> Result<usize> {
+        ensure!(self.states.len() < MAX_NFA_STATES, "pattern is too large");
+        self.states.push(s);
+        Ok(self.states.len() - 1)
+    }
+
+    fn compile(&mut self, node: &Node, next: usize) -> Result<usize> {
+        Ok(match node {
+            Node::Empty => next,
+            Node::Set(set) => self.push(NState::Byte(*set, next))?,
+            Node::Concat(items) => {
+                let mut at = next;
+                for item in items.iter().rev() {
+                    at = self.compile(item, at)?;
+                }
+                at
+            }
+            Node::Alt(arms) => {
+                let starts = arms.iter().map(|a| self.compile(a, next)).collect::<Result<_>>()?;
+                self.push(NState::Eps(starts))?
+            }
+            Node::Repeat(inner, min, max) => {
+                let mut at = match max {
+                    None => {
+                        let root = self.push(NState::Eps(Vec::new()))?;
+                        let body = self.compile(inner, root)?;
+                        self.states[root] = NState::Eps(vec![body, next]);
+                        root
+                    }
+                    Some(max) => {
+                        let mut at = next;
+                        for _ in *min..*max {
+                            let body = self.compile(inner, at)?;
+                            at = self.push(NState::Eps(vec![body, next]))?;
+                        }
+                        at
+                    }
+                };
+                for _ in 0..*min {
+                    at = self.compile(inner, at)?;
+                }
+                at
+            }
+        })
+    }
+
+    /// `set` and every state reachable from it without consuming a byte,
+    /// sorted.
+    fn closure(&self, set: &mut Vec<usize>) {
+        let mut stack = set.clone();
+        while let Some(s) = stack.pop() {
+            if let NState::Eps(next) = &self.states[s] {
+                for &n in next {
+                    if !set.contains(&n) {
+                        set.push(n);
+                        stack.push(n);
+                    }
+                }
+            }
+        }
+        set.sort_unstable();
+    }
+}
+
+/// A compiled pattern.
+#[derive(Clone, Debug)]
+pub struct Regex {
+    pattern: String,
+    /// Byte → class; bytes in one class move every state the same way.
+    classes: [u8; 256],
+    n_classes: usize,
+    /// `next[state * n_classes + class]`, `DEAD` when no match continues.
+    next: Vec<u32>,
+    accepting: Vec<bool>,
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let mut parser = Parser { chars: pattern.chars().collect(), pos: 0 };
+        let ast = parser.alt()?;
+        ensure!(parser.peek().is_none(), "unmatched ')' at {}", parser.pos);
+        let mut nfa = Nfa { states: vec![NState::Match] };
+        let start = nfa.compile(&ast, 0)?;
+
+        // Bytes that every set in the pattern treats alike share a class.
+        let sets: Vec<ByteSet> = nfa.states.iter().filter_map(|s| if let NState::Byte(b, _) = s { Some(*b) } else { None }).collect();
+        let mut signatures: HashMap<Vec<bool>, u8> = HashMap::new();
+        let mut classes = [0u8; 256];
+        let mut reps = Vec::new();
+        for b in 0..=255u8 {
+            let sig: Vec<bool> = sets.iter().map(|s| s.contains(b)).collect();
+            let n = signatures.len() as u8;
+            classes[b as usize] = *signatures.entry(sig).or_insert_with(|| {
+                reps.push(b);
+                n
+            });
+        }
+        let n_classes = reps.len();
+
+        let mut first = vec![start];
+        nfa.closure(&mut first);
+        let mut ids: HashMap<Vec<usize>, u32> = HashMap::from([(first.clone(), 0)]);
+        let mut queue = vec![first];
+        let (mut next, mut accepting) = (Vec::new(), Vec::new());
+        let mut done = 0;
+        while done < queue.len() {
+            let set = queue[done].clone();
+            done += 1;
+            accepting.push(set.contains(&0));
+            for &rep in &reps {
+                let mut moved: Vec<usize> = set
+                    .iter()
+                    .filter_map(|&s| match &nfa.states[s] {
+                        NState::Byte(b, to) if b.contains(rep) => Some(*to),
+                        _ => None,
+                    })
+                    .collect();
+                if moved.is_empty() {
+                    next.push(DEAD);
+                    continue;
+                }
+                moved.sort_unstable();
+                moved.dedup();
+                nfa.closure(&mut moved);
+                let id = match ids.get(&moved) {
+                    Some(&id) => id,
+                    None => {
+                        ensure!(ids.len() < MAX_STATES, "pattern needs more than {MAX_STATES} DFA states");
+                        let id = ids.len() as u32;
+                        ids.insert(moved.clone(), id);
+                        queue.push(moved);
+                        id
+                    }
+                };
+                next.push(id);
+            }
+        }
+        Ok(Self { pattern: pattern.to_string(), classes, n_classes, next, accepting })
+    }
+
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn states(&self) -> usize {
+        self.accepting.len()
+    }
+
+    pub fn start(&self) -> u32 {
+        0
+    }
+
+    pub fn step(&self, state: u32, b: u8) -> u32 {
+        if state == DEAD {
+            return DEAD;
+        }
+        self.next[state as usize * self.n_classes + self.classes[b as usize] as usize]
+    }
+
+    /// The state after `bytes`, `DEAD` once they leave every match.
+    pub fn feed(&self, state: u32, bytes: &[u8]) -> u32 {
+        bytes.iter().try_fold(state, |s, &b| Some(self.step(s, b)).filter(|&n| n != DEAD)).unwrap_or(DEAD)
+    }
+
+    pub fn is_accepting(&self, state: u32) -> bool {
+        state != DEAD && self.accepting[state as usize]
+    }
+
+    /// Whether all of `text` matches.
+    pub fn is_match(&self, text: &str) -> bool {
+        self.is_accepting(self.feed(self.start(), text.as_bytes()))
+    }
+}
+
+/// `Regex` over a vocabulary: masks logits so sampling stays on a match.
+pub struct RegexConstraint {
+    regex: std::sync::Arc<Regex>,
+    state: u32,
+    tokens: Vec<Vec<u8>>,
+    /// Tokens that end generation: allowed only in an accepting state.
+    end: Vec<u32>,
+    /// Every token's next state, per state already visited.
+    walks: HashMap<u32, Vec<u32>>,
+}
+
+impl RegexConstraint {
+    pub fn new(regex: std::sync::Arc<Regex>, vocab: &[String]) -> Self {
+        let tokens = (0..vocab.len() as u32).map(|id| token_bytes(&detokenize(id, vocab))).collect();
+        Self { state: regex.start(), regex, tokens, end: vec![2], walks: HashMap::new() }
+    }
+
+    /// Also treat `ids` (the request's stop tokens) as ending generation.
+    pub fn with_end_tokens(mut self, ids: &[u32]) -> Self {
+        self.end.extend_from_slice(ids);
+        self
+    }
+
+    /// Whether the text so far is a whole match.
+    pub fn is_complete(&self) -> bool {
+        self.regex.is_accepting(self.state)
+    }
+
+    fn walk(&mut self) -> &[u32] {
+        let (regex, tokens, state) = (&self.regex, &self.tokens, self.state);
+        self.walks.entry(state).or_insert_with(|| {
+            tokens.iter().map(|t| if t.is_empty() { DEAD } else { regex.feed(state, t) }).collect()
+        })
+    }
+
+    /// Set the logit of every token that cannot come next to -inf.
+    pub fn mask(&mut self, logits: &mut [f32]) {
+        let complete = self.is_complete();
+        let end = self.end.clone();
+        let walk = self.walk();
+        for (id, l) in logits.iter_mut().enumerate() {
+            let allowed = match end.contains(&(id as u32)) {
+                true => complete,
+                false => walk.get(id).is_some_and(|&s| s != DEAD),
+            };
+            if !allowed {
+                *l = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    /// Advance past the chosen token.
+    pub fn accept(&mut self, id: u32) {
+        if !self.end.contains(&id) {
+            self.state = self.walk().get(id as usize).copied().unwrap_or(DEAD);
+        }
+    }
+}
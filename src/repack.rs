@@ -0,0 +1,219 @@
+//! On-disk cache of weights repacked for the Metal kernels.
+//!
+//! GGUF Q8_0 interleaves a 2-byte scale with 32 quants, so blocks sit on a
+//! 34-byte stride and the kernel has to read quants one byte at a time. The
+//! repacked layout splits every tensor into two planes, all scales then all
+//! quants, so each row of quants is contiguous and 4-byte aligned and the
+//! kernel can load `char4`s against `float4`s of the input.
+//!
+//! Repacking a large model takes a while, so it happens once: the result is
+//! written under `$LLMETAL_CACHE_DIR` (default `~/.cache/llmetal`), named by
+//! `model_key` plus `LAYOUT_VERSION`, and mmapped zero-copy on every later
+//! launch. Changing the kernel's layout means bumping the version.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail, ensure};
+use memmap2::Mmap;
+use metal::{Buffer, Device, MTLResourceOptions};
+
+use crate::tensor::Q8_0_BLOCK;
+
+/// Layout the `q8_0r_matmul` kernel expects. Bump on any change to it.
+pub const LAYOUT_VERSION: u32 = 1;
+const MAGIC: &[u8; 4] = b"LMRP";
+/// The no-copy buffer's length is rounded to this (see `gpu::PAGE`).
+const PAGE: u64 = crate::gpu::PAGE as u64;
+/// Every tensor starts on this boundary inside the cache file.
+const TENSOR_ALIGN: u64 = 256;
+/// The quant plane starts on this boundary after the scales.
+const PLANE_ALIGN: u64 = 16;
+
+/// Where one repacked tensor lives inside the cache file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RepackedTensor {
+    pub offset: u64,
+    pub n_blocks: u64,
+}
+
+impl RepackedTensor {
+    /// Byte offset of the f16 scale plane, one scale per block.
+    pub fn scales_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Byte offset of the int8 quant plane, 32 quants per block.
+    pub fn quants_offset(&self) -> u64 {
+        self.offset + (self.n_blocks * 2).next_multiple_of(PLANE_ALIGN)
+    }
+
+    fn byte_len(&self) -> u64 {
+        self.quants_offset() - self.offset + self.n_blocks * 32
+    }
+
+    /// Where the quant plane ends; `None` when that overflows, which only a
+    /// corrupt index can make it do.
+    fn checked_end(&self) -> Option<u64> {
+        let scales = self.n_blocks.checked_mul(2)?.checked_next_multiple_of(PLANE_ALIGN)?;
+        self.offset.checked_add(scales)?.checked_add(self.n_blocks.checked_mul(32)?)
+    }
+}
+
+/// Bytes hashed from each end and the middle of every tensor.
+const SAMPLE: usize = 4096;
+
+/// Cache key for a GGUF: FNV-1a over the header (metadata + tensor table),
+/// the file length, and a sample of every tensor's data (`tensors`, in file
+/// order): its first, middle and last `SAMPLE` bytes. Two fine-tunes of one
+/// base share the header and the length, but not their weights, and a
+/// sample costs three pages per tensor where the whole model would take
+/// seconds to read.
+pub fn model_key(header: &[u8], file_len: u64, tensors: &[&[u8]]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut add = |bytes: &[u8]| {
+        for &b in bytes {
+            h ^= b as u64;
+            h = h.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    };
+    add(header);
+    add(&file_len.to_le_bytes());
+    for data in tensors {
+        if data.len() <= 3 * SAMPLE {
+            add(data);
+        } else {
+            let mid = data.len() / 2 - SAMPLE / 2;
+            add(&data[..SAMPLE]);
+            add(&data[mid..mid + SAMPLE]);
+            add(&data[data.len() - SAMPLE..]);
+        }
+    }
+    h
+}
+
+/// `$LLMETAL_CACHE_DIR`, falling back to `~/.cache/llmetal`.
+pub fn cache_dir() -> Option<PathBuf> {
+    std::env::var_os("LLMETAL_CACHE_DIR")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache/llmetal")))
+}
+
+/// `<cache_dir>/<key>-v<LAYOUT_VERSION>.lmrp`.
+pub fn cache_path(key: u64) -> Option<PathBuf> {
+    Some(cache_dir()?.join(format!("{key:016x}-v{LAYOUT_VERSION}.lmrp")))
+}
+
+/// Repack Q8_0 tensors (`(name, raw GGUF bytes)`) into a cache file at `path`.
+/// Writes to a temporary file first so an interrupted run never leaves a
+/// half-written cache behind.
+pub fn write_cache(path: &Path, key: u64, tensors: &[(&str, &[u8])]) -> Result<()> {
+    let mut header = Vec::new();
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&LAYOUT_VERSION.to_le_bytes());
+    header.extend_from_slice(&key.to_le_bytes());
+    header.extend_from_slice(&(tensors.len() as u32).to_le_bytes());
+    let index_len: u64 = tensors.iter().map(|(name, _)| 4 + name.len() as u64 + 16).sum();
+
+    let mut offset = (header.len() as u64 + index_len).next_multiple_of(PAGE);
+    let mut placed = Vec::with_capacity(tensors.len());
+    for (name, bytes) in tensors {
+        ensure!(bytes.len() % Q8_0_BLOCK == 0, "tensor '{name}' is not whole Q8_0 blocks");
+        let t = RepackedTensor { offset, n_blocks: (bytes.len() / Q8_0_BLOCK) as u64 };
+        header.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        header.extend_from_slice(&t.offset.to_le_bytes());
+        header.extend_from_slice(&t.n_blocks.to_le_bytes());
+        offset = (t.offset + t.byte_len()).next_multiple_of(TENSOR_ALIGN);
+        placed.push(t);
+    }
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut w = BufWriter::new(File::create(&tmp).with_context(|| format!("create {}", tmp.display()))?);
+    w.write_all(&header)?;
+    let mut written = header.len() as u64;
+    let pad = |w: &mut BufWriter<File>, written: &mut u64, to: u64| -> Result<()> {
+        w.write_all(&vec![0u8; (to - *written) as usize])?;
+        *written = to;
+        Ok(())
+    };
+    for (t, (_, bytes)) in placed.iter().zip(tensors) {
+        pad(&mut w, &mut written, t.scales_offset())?;
+        for block in bytes.chunks_exact(Q8_0_BLOCK) {
+            w.write_all(&block[..2])?;
+        }
+        written += t.n_blocks * 2;
+        pad(&mut w, &mut written, t.quants_offset())?;
+        for block in bytes.chunks_exact(Q8_0_BLOCK) {
+            w.write_all(&block[2..])?;
+        }
+        written += t.n_blocks * 32;
+    }
+    w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    std::fs::rename(&tmp, path).with_context(|| format!("rename {} -> {}", tmp.display(), path.display()))?;
+    Ok(())
+}
+
+/// Parse and check a cache file's index. A file written for another model
+/// or layout version is an error, not a silent mismatch, and so is a
+/// corrupt one, which the caller then rebuilds.
+pub fn read_index(bytes: &[u8], key: u64) -> Result<HashMap<String, RepackedTensor>> {
+    let mut pos = 0usize;
+    let mut take = |n: usize| -> Result<&[u8]> {
+        let out = pos.checked_add(n).and_then(|end| bytes.get(pos..end)).context("repack cache truncated")?;
+        pos += n;
+        Ok(out)
+    };
+    if take(4)? != MAGIC {
+        bail!("not a repack cache file");
+    }
+    let version = u32::from_le_bytes(take(4)?.try_into().unwrap());
+    ensure!(version == LAYOUT_VERSION, "repack cache layout v{version}, kernels expect v{LAYOUT_VERSION}");
+    let file_key = u64::from_le_bytes(take(8)?.try_into().unwrap());
+    ensure!(file_key == key, "repack cache belongs to another model ({file_key:016x} != {key:016x})");
+    let n = u32::from_le_bytes(take(4)?.try_into().unwrap());
+
+    let mut index = HashMap::new();
+    for _ in 0..n {
+        let len = u32::from_le_bytes(take(4)?.try_into().unwrap()) as usize;
+        let name = String::from_utf8_lossy(take(len)?).into_owned();
+        let offset = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let n_blocks = u64::from_le_bytes(take(8)?.try_into().unwrap());
+        let t = RepackedTensor { offset, n_blocks };
+        ensure!(
+            t.offset.is_multiple_of(TENSOR_ALIGN) && t.checked_end().is_some_and(|end| end <= bytes.len() as u64),
+            "repack cache entry '{name}' out of bounds"
+        );
+        index.insert(name, t);
+    }
+    Ok(index)
+}
+
+/// A mapped cache file, exposed to the GPU as one zero-copy buffer.
+pub struct RepackCache {
+    _mmap: Mmap,
+    pub buf: Buffer,
+    pub index: HashMap<String, RepackedTensor>,
+}
+
+impl RepackCache {
+    pub fn open(path: &Path, key: u64, device: &Device) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+        let mmap = unsafe { Mmap::map(&file) }.context("mmap repack cache")?;
+        let index = read_index(&mmap, key).with_context(|| format!("read {}", path.display()))?;
+        // Same page-rounding rule as TensorStore's mmap buffer.
+        let rounded_len = (mmap.len() as u64).next_multiple_of(PAGE);
+        let buf = device.new_buffer_with_bytes_no_copy(
+            mmap.as_ptr() as *mut _,
+            rounded_len,
+            MTLResourceOptions::StorageModeShared,
+            None,
+        );
+        Ok(Self { _mmap: mmap, buf, index })
+    }
+}
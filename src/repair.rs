@@ -0,0 +1,169 @@
+//! Truncated model files. An interrupted download leaves a GGUF whose
+//! tensor table points past its end; loading one reports exactly which
+//! byte range is missing (`Truncation`) instead of failing on whichever
+//! tensor happens to be touched first, and `llmetal repair` resumes the
+//! download when the model cache manifest knows where the file came from.
+//!
+//! The manifest is `models.json` in the cache directory (see
+//! `repack::cache_dir`):
+//!
+//! ```text
+//! {"models": {FILE_NAME: {"url": URL, "size": BYTES}}}
+//! ```
+//!
+//! keyed by file name, so a model moved to another folder still finds its
+//! source; `size` is optional. `repair --url URL` records an entry. The
+//! download resumes with `curl --continue-at -`, which ships with macOS, so
+//! only the missing tail crosses the network.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, ensure};
+use serde_json::{Value, json};
+
+use crate::gguf::GgufFile;
+use crate::tensor::{TensorMeta, index_tensors};
+
+/// A file shorter than its tensor table implies.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Truncation {
+    pub path: String,
+    pub file_len: u64,
+    /// Where the last tensor ends: the size the file should have.
+    pub expected_len: u64,
+    /// Tensors with bytes past the end of the file.
+    pub damaged: usize,
+    /// The damaged tensor that starts first.
+    pub first_damaged: String,
+    /// Where the file came from, when the model cache manifest says.
+    pub source: Option<String>,
+}
+
+impl Truncation {
+    /// Fill in `source` from the model cache manifest.
+    pub fn with_source(mut self) -> Self {
+        self.source = lookup(&self.path).map(|s| s.url);
+        self
+    }
+}
+
+impl std::fmt::Display for Truncation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let missing = self.expected_len - self.file_len;
+        write!(
+            f,
+            "{} is truncated: bytes {}..{} ({:.1} MB) are missing, {} tensors from '{}' on; the download was likely interrupted. ",
+            self.path,
+            self.file_len,
+            self.expected_len,
+            missing as f64 / (1 << 20) as f64,
+            self.damaged,
+            self.first_damaged
+        )?;
+        match &self.source {
+            Some(url) => write!(f, "Resume it from {url} with `llmetal repair {}`", self.path),
+            None => write!(f, "Download it again, or resume it with `llmetal repair {} --url URL`", self.path),
+        }
+    }
+}
+
+impl std::error::Error for Truncation {}
+
+/// The byte range `index` needs beyond `file_len`, if any.
+pub fn find_truncation(path: &str, index: &HashMap<String, TensorMeta>, file_len: u64) -> Option<Truncation> {
+    let end = |m: &TensorMeta| m.file_offset + m.byte_size;
+    let expected_len = index.values().map(end).max()?;
+    if expected_len <= file_len {
+        return None;
+    }
+    let damaged: Vec<(&String, &TensorMeta)> = index.iter().filter(|(_, m)| end(m) > file_len).collect();
+    let first = damaged.iter().min_by_key(|(name, m)| (m.file_offset, *name)).map(|(name, _)| name.to_string());
+    Some(Truncation {
+        path: path.to_string(),
+        file_len,
+        expected_len,
+        damaged: damaged.len(),
+        first_damaged: first.unwrap_or_default(),
+        source: None,
+    })
+}
+
+/// Check the file at `path` against its own tensor table. Errors when not
+/// even the header parses.
+pub fn check(path: &str) -> Result<Option<Truncation>> {
+    let mmap = crate::envelope::map(path)?;
+    let gguf = GgufFile::parse(&mmap).with_context(|| format!("parse {path}"))?;
+    Ok(find_truncation(path, &index_tensors(&gguf)?, mmap.len() as u64))
+}
+
+/// One model cache manifest entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Source {
+    pub url: String,
+    /// The complete file's size, when known.
+    pub size: Option<u64>,
+}
+
+/// `<cache dir>/models.json`.
+pub fn manifest_path() -> Option<PathBuf> {
+    Some(crate::repack::cache_dir()?.join("models.json"))
+}
+
+/// Every entry of the manifest at `path`; none when it does not exist.
+pub fn read_manifest(path: &Path) -> Result<BTreeMap<String, Source>> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).with_context(|| format!("read {}", path.display())),
+    };
+    let manifest: Value = serde_json::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+    let Some(models) = manifest["models"].as_object() else { return Ok(BTreeMap::new()) };
+    Ok(models
+        .iter()
+        .filter_map(|(name, m)| {
+            let url = m["url"].as_str()?.to_string();
+            Some((name.clone(), Source { url, size: m["size"].as_u64() }))
+        })
+        .collect())
+}
+
+pub fn write_manifest(path: &Path, models: &BTreeMap<String, Source>) -> Result<()> {
+    let models: serde_json::Map<String, Value> =
+        models.iter().map(|(name, s)| (name.clone(), json!({ "url": s.url, "size": s.size }))).collect();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+    }
+    let text = serde_json::to_string_pretty(&json!({ "models": models }))?;
+    std::fs::write(path, text).with_context(|| format!("write {}", path.display()))
+}
+
+/// The manifest's entry for `model_path`.
+pub fn lookup(model_path: &str) -> Option<Source> {
+    read_manifest(&manifest_path()?).ok()?.remove(&file_name(model_path))
+}
+
+/// Add or replace the manifest's entry for `model_path`.
+pub fn record(model_path: &str, source: Source) -> Result<()> {
+    let path = manifest_path().context("no cache directory: set LLMETAL_CACHE_DIR or HOME")?;
+    let mut models = read_manifest(&path)?;
+    models.insert(file_name(model_path), source);
+    write_manifest(&path, &models)
+}
+
+/// Append what is missing of `model_path` from `url`, which must be
+/// `http://` or `https://`; anything else (`file://`, or a `-`
+/// option) is refused before curl sees it.
+pub fn resume(model_path: &str, url: &str) -> Result<()> {
+    ensure!(url.starts_with("http://") || url.starts_with("https://"), "{url:?} is not an http:// or https:// URL");
+    let status = std::process::Command::new("curl")
+        .args(["--fail", "--location", "--continue-at", "-", "--output", model_path, "--", url])
+        .status()
+        .context("run curl")?;
+    ensure!(status.success(), "curl {url} failed ({status})");
+    Ok(())
+}
+
+fn file_name(model_path: &str) -> String {
+    Path::new(model_path).file_name().map_or_else(|| model_path.to_string(), |n| n.to_string_lossy().into_owned())
+}
@@ -0,0 +1,211 @@
+//! Generated text back into a structured assistant message: the visible
+//! content, a reasoning segment, and tool calls, found by the markers the
+//! chat format (or the fine-tune behind it) writes them with.
+//!
+//! - Reasoning is a `<think>...</think>` (or `<thinking>`) block at the
+//!   start of the reply. A reply holding only the closing tag had the
+//!   opening one in its prompt, as templates for reasoning models add it;
+//!   one with no closing tag was cut off while still thinking.
+//! - Tool calls are Hermes-style `<tool_call>{"name", "arguments"}</tool_call>`
+//!   blocks in every format, plus each family's own: Llama 3's
+//!   `<|python_tag|>` followed by `;`-separated calls (or a reply that is
+//!   nothing but a `{"name", "parameters"}` object), Mistral's
+//!   `[TOOL_CALLS]` followed by an array of calls (or `name[ARGS]{...}`),
+//!   and Phi's `<|tool_call|>[...]<|/tool_call|>`.
+//!
+//! A block whose JSON does not parse as calls stays in the content
+//! verbatim, so nothing the model wrote is lost.
+
+use serde_json::{Value, json};
+
+use crate::chat::ChatFormat;
+
+/// An assistant turn as parsed from its text.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AssistantReply {
+    /// What the reply says to the user, with reasoning and calls taken out.
+    pub content: String,
+    pub reasoning: Option<String>,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ToolCall {
+    /// The call's own id, when the model wrote one.
+    pub id: Option<String>,
+    pub name: String,
+    /// A JSON object; arguments written as a JSON string are parsed.
+    pub arguments: Value,
+}
+
+const THINK_TAGS: [(&str, &str); 2] = [("<think>", "</think>"), ("<thinking>", "</thinking>")];
+
+impl AssistantReply {
+    /// The OpenAI message shape: `{"role", "content", "reasoning_content",
+    /// "tool_calls": [{"id", "type", "function": {"name", "arguments"}}]}`,
+    /// `arguments` as a JSON string. `content` is null for a reply that is
+    /// only calls; the other two are left out when empty.
+    pub fn to_json(&self) -> Value {
+        let content =
+            if self.content.is_empty() && !self.tool_calls.is_empty() { Value::Null } else { self.content.as_str().into() };
+        let mut msg = json!({ "role": "assistant", "content": content });
+        if let Some(r) = &self.reasoning {
+            msg["reasoning_content"] = r.as_str().into();
+        }
+        if !self.tool_calls.is_empty() {
+            let calls: Vec<Value> = self
+                .tool_calls
+                .iter()
+                .enumerate()
+                .map(|(i, c)| {
+                    json!({
+                        "id": c.id.clone().unwrap_or_else(|| format!("call_{i}")),
+                        "type": "function",
+                        "function": { "name": c.name, "arguments": c.arguments.to_string() },
+                    })
+                })
+                .collect();
+            msg["tool_calls"] = calls.into();
+        }
+        msg
+    }
+}
+
+/// `text`, a generated assistant turn in `format`, taken apart.
+pub fn parse(format: ChatFormat, text: &str) -> AssistantReply {
+    let mut text = text.trim();
+    // A reply decoded with special tokens may still end its turn.
+    while let Some(rest) = format.end_of_turn().iter().find_map(|m| text.strip_suffix(m)) {
+        text = rest.trim_end();
+    }
+    let (reasoning, text) = split_reasoning(text);
+    let (mut content, mut tool_calls) = extract_blocks(text, "<tool_call>", "</tool_call>");
+    let (rest, calls) = match format {
+        ChatFormat::Llama3 => split_llama3(&content),
+        ChatFormat::Mistral => split_mistral(&content),
+        ChatFormat::Phi => extract_blocks(&content, "<|tool_call|>", "<|/tool_call|>"),
+        ChatFormat::ChatMl | ChatFormat::Gemma => (content.clone(), Vec::new()),
+    };
+    content = rest;
+    tool_calls.extend(calls);
+    AssistantReply { content: content.trim().to_string(), reasoning, tool_calls }
+}
+
+fn split_reasoning(text: &str) -> (Option<String>, &str) {
+    for (open, close) in THINK_TAGS {
+        let (body, opened) = match text.strip_prefix(open) {
+            Some(body) => (body, true),
+            None => (text, false),
+        };
+        match body.split_once(close) {
+            Some((thought, rest)) => return (Some(thought.trim().to_string()), rest),
+            None if opened => return (Some(body.trim().to_string()), ""),
+            None => {}
+        }
+    }
+    (None, text)
+}
+
+/// Take every `open ... close` block that holds calls out of `text`; an
+/// unclosed last block runs to the end.
+fn extract_blocks(text: &str, open: &str, close: &str) -> (String, Vec<ToolCall>) {
+    let (mut content, mut calls, mut rest) = (String::new(), Vec::new(), text);
+    while let Some(start) = rest.find(open) {
+        content.push_str(&rest[..start]);
+        let after = &rest[start + open.len()..];
+        let (body, next) = after.split_once(close).unwrap_or((after, ""));
+        match parse_calls(body) {
+            Some(found) => calls.extend(found),
+            None => content.push_str(&rest[start..rest.len() - next.len()]),
+        }
+        rest = next;
+    }
+    content.push_str(rest);
+    (content, calls)
+}
+
+/// `<|python_tag|>` and the calls after it, or a reply that is one call.
+fn split_llama3(text: &str) -> (String, Vec<ToolCall>) {
+    if let Some((before, after)) = text.split_once("<|python_tag|>")
+        && let Some(calls) = parse_calls(after)
+    {
+        return (before.to_string(), calls);
+    }
+    let whole = text.trim();
+    match whole.starts_with('{').then(|| parse_calls(whole)).flatten() {
+        Some(calls) => (String::new(), calls),
+        None => (text.to_string(), Vec::new()),
+    }
+}
+
+/// `[TOOL_CALLS]` and a JSON array of calls, or (newer templates) one
+/// `[TOOL_CALLS]name[ARGS]{...}` per call.
+fn split_mistral(text: &str) -> (String, Vec<ToolCall>) {
+    let Some((before, after)) = text.split_once("[TOOL_CALLS]") else {
+        return (text.to_string(), Vec::new());
+    };
+    let mut calls = Vec::new();
+    for part in after.split("[TOOL_CALLS]") {
+        let found = match part.split_once("[ARGS]") {
+            Some((name, args)) if !name.trim_start().starts_with(['[', '{']) => {
+                serde_json::from_str(args.trim()).ok().map(|arguments| {
+                    vec![ToolCall { id: None, name: name.trim().to_string(), arguments: decode_arguments(arguments) }]
+                })
+            }
+            _ => parse_calls(part),
+        };
+        match found {
+            Some(found) => calls.extend(found),
+            None => return (text.to_string(), Vec::new()),
+        }
+    }
+    (before.to_string(), calls)
+}
+
+/// Calls written as JSON objects, arrays of them, or several of either
+/// separated by `;` or `,`. `None` unless all of `body` is calls.
+fn parse_calls(body: &str) -> Option<Vec<ToolCall>> {
+    let mut calls = Vec::new();
+    let mut rest = body.trim();
+    while !rest.is_empty() {
+        let mut values = serde_json::Deserializer::from_str(rest).into_iter::<Value>();
+        let value = values.next()?.ok()?;
+        rest = rest[values.byte_offset()..].trim_start_matches([';', ',', ' ', '\n', '\t', '\r']);
+        match value {
+            Value::Array(items) => {
+                for item in items {
+                    calls.push(tool_call(item)?);
+                }
+            }
+            item => calls.push(tool_call(item)?),
+        }
+    }
+    (!calls.is_empty()).then_some(calls)
+}
+
+/// `{"name", "arguments" | "parameters", "id"?}`, or the same inside
+/// `{"function": ...}`.
+fn tool_call(mut v: Value) -> Option<ToolCall> {
+    let id = v["id"].as_str().map(String::from);
+    if v["function"].is_object() {
+        v = v["function"].take();
+    }
+    let name = v["name"].as_str()?.to_string();
+    let arguments = match v["arguments"].take() {
+        Value::Null => v["parameters"].take(),
+        args => args,
+    };
+    let arguments = match arguments {
+        Value::Null => json!({}),
+        args => decode_arguments(args),
+    };
+    Some(ToolCall { id, name, arguments })
+}
+
+/// Arguments some models write as a JSON string rather than an object.
+fn decode_arguments(args: Value) -> Value {
+    match &args {
+        Value::String(s) => serde_json::from_str(s).unwrap_or(args),
+        _ => args,
+    }
+}
@@ -0,0 +1,70 @@
+//! Cross-encoder reranking: score how relevant a document is to a query.
+//!
+//! Two kinds of reranker GGUF are handled:
+//!   - a classification head (`cls.output.weight`, optional `cls.output.bias`)
+//!     applied to the last token's hidden state;
+//!   - a causal LM fine-tuned to answer "yes"/"no" (Qwen3-Reranker style),
+//!     scored as `logit(yes) - logit(no)` after a fixed judging prompt.
+//!
+//! Either way the raw score goes through a sigmoid, so relevance is in 0..1.
+
+use crate::tokenizer::PromptTokenizer;
+
+const EOS: u32 = 2;
+
+/// How a model turns its last position into a relevance logit.
+#[derive(Clone, Debug)]
+pub enum RerankHead {
+    /// `weight` is `[n_cls, hidden]` row-major; class 0 is the relevance logit.
+    Classifier { weight: Vec<f32>, bias: Option<Vec<f32>> },
+    YesNo { yes: u32, no: u32 },
+}
+
+/// One scored document, in input order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RerankScore {
+    pub index: usize,
+    pub logit: f32,
+    pub relevance: f32,
+}
+
+pub const DEFAULT_INSTRUCTION: &str = "Given a web search query, retrieve relevant passages that answer the query";
+
+/// The judging prompt Qwen3-Reranker was trained on.
+pub fn yes_no_prompt(instruction: &str, query: &str, document: &str) -> String {
+    format!(
+        "<|im_start|>system\nJudge whether the Document meets the requirements based on the Query and the Instruct provided. \
+         Note that the answer can only be \"yes\" or \"no\".<|im_end|>\n<|im_start|>user\n\
+         <Instruct>: {instruction}\n<Query>: {query}\n<Document>: {document}<|im_end|>\n\
+         <|im_start|>assistant\n<think>\n\n</think>\n\n"
+    )
+}
+
+/// Id of the first of `candidates` present in `vocab`.
+pub fn find_token(vocab: &[String], candidates: &[&str]) -> Option<u32> {
+    candidates.iter().find_map(|c| vocab.iter().position(|t| t == c).map(|i| i as u32))
+}
+
+pub fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+/// Sort best first; ties keep input order.
+pub fn rank(mut scores: Vec<RerankScore>) -> Vec<RerankScore> {
+    scores.sort_by(|a, b| b.logit.total_cmp(&a.logit));
+    scores
+}
+
+/// Token sequence the head expects for one query+document pair: the judging
+/// prompt for yes/no models, `BOS query EOS document` for classifier heads.
+pub fn pair_tokens(tokenizer: &PromptTokenizer, head: &RerankHead, instruction: &str, query: &str, document: &str) -> Vec<u32> {
+    match head {
+        RerankHead::YesNo { .. } => tokenizer.tokenize_bos(&yes_no_prompt(instruction, query, document)),
+        RerankHead::Classifier { .. } => {
+            let mut ids = tokenizer.tokenize_bos(query);
+            ids.push(EOS);
+            ids.extend(tokenizer.tokenize(document));
+            ids
+        }
+    }
+}
@@ -0,0 +1,442 @@
+//! `llmetal worker`: a pipeline stage on another Mac, reached over TCP
+//! (`run --rpc HOST:PORT`), in the spirit of llama.cpp's RPC backend. The
+//! worker needs no model file; the coordinator sends it its blocks.
+//!
+//! Frames are the daemon's (a little-endian u32 length, then JSON). A frame
+//! with `"bytes": N` is followed by N raw bytes: a tensor's data as stored
+//! in the GGUF, or rows as little-endian f32.
+//!
+//! - `{"op": "load", "arch", "first_layer", "layers"}` → `{"ok": true,
+//!   "device"}`: drop whatever the connection held and expect `layers` (one
+//!   object of tensor names, rows and columns per block, see `layer_to_json`)
+//!   starting at block `first_layer`
+//! - `{"op": "tensor", "name", "kind", "bytes"}` and the data → `{"ok":
+//!   true}`, once per tensor of those blocks, norms included
+//! - `{"op": "forward", "pos", "bytes"}` and rows `pos..` of the sequence →
+//!   `{"ok": true, "bytes"}` and the rows after the worker's blocks. The
+//!   worker keeps one sequence's K/V rows and first drops any from `pos` on,
+//!   which covers both a new prompt (`pos` 0) and rejected draft tokens.
+//!
+//! A failed request is answered with `{"error": MESSAGE}`. Weights and K/V
+//! rows live as long as the connection; connections are served one at a
+//! time.
+//!
+//! There is no authentication, so the worker listens on loopback unless
+//! `--listen` says otherwise. What a peer can make it allocate is bounded
+//! by what it has loaded: a `tensor` payload must be exactly the size its
+//! block's shape and type give, checked against `arch` at `load`, and a
+//! `forward` carries at most `MAX_FORWARD_ROWS` rows. Payloads are read as
+//! they arrive rather than allocated up front from the header. A payload
+//! that is refused unread ends the connection.
+//!
+//! Backpressure: the coordinator writes forwards from one thread and reads
+//! replies on another, with at most `WINDOW` micro-batches unanswered. The
+//! worker answers in order, so it never buffers more than one.
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result, bail, ensure};
+use serde_json::{Value, json};
+
+use crate::daemon::{read_frame, write_frame};
+use crate::gpu::Gpu;
+use crate::model::{Arch, RopeScaling, ScoreScaling, YarnParams};
+use crate::pipeline::{LocalStage, MicroBatch, block_tensors, block_weights};
+use crate::tensor::{TensorStore, ggml_block_layout};
+use crate::weights::{LayerWeights, WeightRef};
+
+/// Forwards a coordinator sends before waiting for the oldest reply.
+pub const WINDOW: usize = 2;
+
+/// Larger payloads are refused rather than allocated.
+pub const MAX_PAYLOAD: u64 = 16 << 30;
+
+/// Most rows one `forward` may carry; `--micro-batch` is capped to it for
+/// workers.
+pub const MAX_FORWARD_ROWS: usize = 4096;
+
+/// Where `llmetal worker` listens without `--listen`.
+pub const DEFAULT_LISTEN: &str = "127.0.0.1:50052";
+
+/// Write `header` (which must say `"bytes": payload.len()`) and `payload`.
+pub fn write_payload(w: &mut dyn Write, header: &Value, payload: &[u8]) -> Result<()> {
+    debug_assert_eq!(header["bytes"].as_u64(), Some(payload.len() as u64));
+    write_frame(w, header)?;
+    w.write_all(payload)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// The `"bytes"` that follow `header`, none if it names no count, and at
+/// most `max`. The buffer grows as bytes arrive, so a header that claims
+/// more than is sent costs nothing.
+pub fn read_payload(r: &mut dyn Read, header: &Value, max: u64) -> Result<Vec<u8>> {
+    let len = header["bytes"].as_u64().unwrap_or(0);
+    ensure!(len <= max, "payload of {len} bytes exceeds {max}");
+    let mut buf = Vec::with_capacity(len.min(1 << 20) as usize);
+    r.take(len).read_to_end(&mut buf)?;
+    ensure!(buf.len() as u64 == len, "truncated payload: {} of {len} bytes", buf.len());
+    Ok(buf)
+}
+
+pub fn f32_bytes(rows: &[f32]) -> Vec<u8> {
+    rows.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+pub fn f32_from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect()
+}
+
+/// The reply frame, or the worker's error.
+fn check(frame: Option<Value>) -> Result<Value> {
+    let frame = frame.context("worker closed the connection")?;
+    if let Some(e) = frame["error"].as_str() {
+        bail!("worker: {e}");
+    }
+    Ok(frame)
+}
+
+pub fn arch_to_json(arch: &Arch) -> Value {
+    let rope_scaling = match arch.rope_scaling {
+        RopeScaling::None => json!({ "type": "none" }),
+        RopeScaling::Linear { factor } => json!({ "type": "linear", "factor": factor }),
+        RopeScaling::Yarn(y) => json!({
+            "type": "yarn", "factor": y.factor, "original_ctx": y.original_ctx,
+            "beta_fast": y.beta_fast, "beta_slow": y.beta_slow,
+        }),
+    };
+    json!({
+        "hidden": arch.hidden, "n_layers": arch.n_layers, "n_heads": arch.n_heads,
+        "n_kv_heads": arch.n_kv_heads, "head_dim": arch.head_dim, "ffn_hidden": arch.ffn_hidden,
+        "vocab_size": arch.vocab_size, "ctx_train": arch.ctx_train, "rope_base": arch.rope_base,
+        "rope_scaling": rope_scaling,
+        "scaling": {
+            "query_scale": arch.scaling.query_scale, "attn_softcap": arch.scaling.attn_softcap,
+            "final_softcap": arch.scaling.final_softcap, "alibi_max_bias": arch.scaling.alibi_max_bias,
+        },
+    })
+}
+
+pub fn arch_from_json(v: &Value) -> Result<Arch> {
+    let n = |k: &str| v[k].as_u64().map(|n| n as usize).with_context(|| format!("arch.{k} missing"));
+    let f = |v: &Value, k: &str| v[k].as_f64().map(|f| f as f32).with_context(|| format!("arch value {k} missing"));
+    let s = &v["rope_scaling"];
+    let rope_scaling = match s["type"].as_str() {
+        Some("none") => RopeScaling::None,
+        Some("linear") => RopeScaling::Linear { factor: f(s, "factor")? },
+        Some("yarn") => RopeScaling::Yarn(YarnParams {
+            factor: f(s, "factor")?,
+            original_ctx: s["original_ctx"].as_u64().context("yarn original_ctx missing")? as usize,
+            beta_fast: f(s, "beta_fast")?,
+            beta_slow: f(s, "beta_slow")?,
+        }),
+        other => bail!("unknown rope scaling {other:?}"),
+    };
+    // Null (or absent, from an older coordinator) is off.
+    let opt = |k: &str| v["scaling"][k].as_f64().map(|f| f as f32);
+    let scaling = ScoreScaling {
+        query_scale: opt("query_scale"),
+        attn_softcap: opt("attn_softcap"),
+        final_softcap: opt("final_softcap"),
+        alibi_max_bias: opt("alibi_max_bias"),
+    };
+    Ok(Arch {
+        hidden: n("hidden")?,
+        n_layers: n("n_layers")?,
+        n_heads: n("n_heads")?,
+        n_kv_heads: n("n_kv_heads")?,
+        head_dim: n("head_dim")?,
+        ffn_hidden: n("ffn_hidden")?,
+        vocab_size: n("vocab_size")?,
+        ctx_train: n("ctx_train")?,
+        rope_base: f(v, "rope_base")?,
+        rope_scaling,
+        scaling,
+    })
+}
+
+/// A block's tensors as `{"attn_q": {"name", "kind", "rows", "cols"}, ...}`.
+pub fn layer_to_json(w: &LayerWeights) -> Value {
+    let t = |w: &WeightRef| json!({ "name": w.name, "kind": w.kind, "rows": w.rows, "cols": w.cols });
+    json!({
+        "attn_norm": t(&w.attn_norm), "attn_q": t(&w.attn_q), "attn_k": t(&w.attn_k),
+        "attn_v": t(&w.attn_v), "attn_output": t(&w.attn_output), "ffn_norm": t(&w.ffn_norm),
+        "ffn_gate": t(&w.ffn_gate), "ffn_up": t(&w.ffn_up), "ffn_down": t(&w.ffn_down),
+    })
+}
+
+pub fn layer_from_json(v: &Value) -> Result<LayerWeights> {
+    let t = |k: &str| -> Result<WeightRef> {
+        let w = &v[k];
+        let n = |f: &str| w[f].as_u64().with_context(|| format!("{k}.{f} missing"));
+        Ok(WeightRef {
+            name: w["name"].as_str().with_context(|| format!("{k}.name missing"))?.to_string(),
+            kind: n("kind")? as u32,
+            rows: n("rows")? as usize,
+            cols: n("cols")? as usize,
+        })
+    };
+    Ok(LayerWeights {
+        attn_norm: t("attn_norm")?,
+        attn_q: t("attn_q")?,
+        attn_k: t("attn_k")?,
+        attn_v: t("attn_v")?,
+        attn_output: t("attn_output")?,
+        ffn_norm: t("ffn_norm")?,
+        ffn_gate: t("ffn_gate")?,
+        ffn_up: t("ffn_up")?,
+        ffn_down: t("ffn_down")?,
+    })
+}
+
+/// The coordinator's end of one worker.
+pub struct RpcStage {
+    addr: String,
+    /// Locked for a whole load or pipelined pass.
+    stream: Mutex<TcpStream>,
+    bytes_sent: u64,
+}
+
+impl RpcStage {
+    pub fn connect(addr: &str) -> Result<Self> {
+        let stream = TcpStream::connect(addr).with_context(|| format!("connect to worker {addr}"))?;
+        stream.set_nodelay(true)?;
+        Ok(Self { addr: addr.to_string(), stream: Mutex::new(stream), bytes_sent: 0 })
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Weight bytes sent by `load`.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Send blocks `first_layer..` (`blocks`) and every tensor they read.
+    pub fn load(&mut self, store: &TensorStore, arch: &Arch, first_layer: usize, blocks: &[LayerWeights]) -> Result<()> {
+        let stream = self.stream.get_mut().unwrap();
+        let layers: Vec<Value> = blocks.iter().map(layer_to_json).collect();
+        write_frame(stream, &json!({ "op": "load", "arch": arch_to_json(arch), "first_layer": first_layer, "layers": layers }))?;
+        let device = check(read_frame(stream)?)?["device"].as_str().unwrap_or("?").to_string();
+        eprintln!("  worker {} ({device}): layers {first_layer}..{}", self.addr, first_layer + blocks.len());
+        for name in blocks.iter().flat_map(block_tensors) {
+            let data = store.get(name)?;
+            let header = json!({ "op": "tensor", "name": name, "kind": store.meta(name)?.kind, "bytes": data.len() });
+            write_payload(stream, &header, data)?;
+            check(read_frame(stream)?).with_context(|| format!("send '{name}' to {}", self.addr))?;
+            self.bytes_sent += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// `Stage::serve` for a worker: forward each micro-batch at `pos + start`
+    /// and pass the reply on, at most `WINDOW` of them unanswered.
+    pub fn forward(&self, pos: usize, input: Receiver<MicroBatch>, output: Sender<MicroBatch>) -> Result<()> {
+        let mut stream = self.stream.lock().unwrap();
+        let mut reader = stream.try_clone()?;
+        // One credit per forward on the wire; the reader returns them.
+        let (credit, in_flight) = mpsc::sync_channel::<usize>(WINDOW - 1);
+        std::thread::scope(|s| {
+            let replies = s.spawn(move || -> Result<()> {
+                for start in in_flight {
+                    let header = check(read_frame(&mut reader)?)?;
+                    let rows = f32_from_bytes(&read_payload(&mut reader, &header, MAX_PAYLOAD)?);
+                    if output.send((start, rows)).is_err() {
+                        break;
+                    }
+                }
+                Ok(())
+            });
+            let sent = (|| -> Result<()> {
+                for (start, rows) in input {
+                    if credit.send(start).is_err() {
+                        break; // the reader failed; its error says why
+                    }
+                    let bytes = f32_bytes(&rows);
+                    write_payload(&mut *stream, &json!({ "op": "forward", "pos": pos + start, "bytes": bytes.len() }), &bytes)?;
+                }
+                Ok(())
+            })();
+            drop(credit);
+            if sent.is_err() {
+                // Unblock the reader; the connection is unusable now.
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            let received = replies.join().map_err(|_| anyhow::anyhow!("worker reply thread panicked"))?;
+            if received.is_err() {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            sent.and(received).with_context(|| format!("worker {}", self.addr))
+        })
+    }
+}
+
+/// What a connection has loaded.
+struct Session {
+    arch: Arch,
+    first_layer: usize,
+    layers: Vec<LayerWeights>,
+    stage: LocalStage,
+    k: Vec<Vec<Arc<[f32]>>>,
+    v: Vec<Vec<Arc<[f32]>>>,
+}
+
+impl Session {
+    fn weight(&self, name: &str) -> Result<&WeightRef> {
+        self.layers
+            .iter()
+            .flat_map(block_weights)
+            .find(|w| w.name == name)
+            .with_context(|| format!("'{name}' is not in the loaded blocks"))
+    }
+}
+
+/// Bytes of `w` as stored, from its shape and its type's block layout.
+fn weight_bytes(w: &WeightRef) -> Result<u64> {
+    let (block_elems, block_bytes) =
+        ggml_block_layout(w.kind).with_context(|| format!("'{}' has unknown ggml type {}", w.name, w.kind))?;
+    ensure!((w.cols as u64).is_multiple_of(block_elems), "'{}' rows are not whole {block_elems}-value blocks", w.name);
+    (w.rows as u64)
+        .checked_mul(w.cols as u64 / block_elems)
+        .and_then(|blocks| blocks.checked_mul(block_bytes))
+        .filter(|&n| n <= MAX_PAYLOAD)
+        .with_context(|| format!("'{}' is too large", w.name))
+}
+
+/// Every tensor of a block has the shape `arch` gives it, as
+/// `ModelWeights::from_index` checks on the coordinator; the payload sizes
+/// and the kernels both go by these shapes.
+fn check_block(arch: &Arch, w: &LayerWeights) -> Result<()> {
+    let width = |heads: usize| heads.checked_mul(arch.head_dim).context("arch heads * head_dim overflows");
+    let (h, q, kv, f) = (arch.hidden, width(arch.n_heads)?, width(arch.n_kv_heads)?, arch.ffn_hidden);
+    let shapes = [(1, h), (q, h), (kv, h), (kv, h), (h, q), (1, h), (f, h), (f, h), (h, f)];
+    for (t, (rows, cols)) in block_weights(w).into_iter().zip(shapes) {
+        ensure!((t.rows, t.cols) == (rows, cols), "{} is {}x{}, expected {rows}x{cols}", t.name, t.rows, t.cols);
+    }
+    Ok(())
+}
+
+/// The most payload `req` may carry, given what the connection holds.
+fn payload_limit(req: &Value, session: Option<&Session>) -> Result<u64> {
+    Ok(match req["op"].as_str() {
+        Some("tensor") => {
+            let s = session.context("tensor before load")?;
+            weight_bytes(s.weight(req["name"].as_str().context("tensor needs a name")?)?)?
+        }
+        Some("forward") => forward_limit(&session.context("forward before load")?.arch)?,
+        _ => 0,
+    })
+}
+
+/// Bytes of a `forward` of `MAX_FORWARD_ROWS` rows of `arch`. `hidden`
+/// comes from the peer's `load`, so the product is checked.
+fn forward_limit(arch: &Arch) -> Result<u64> {
+    arch.hidden
+        .checked_mul(4 * MAX_FORWARD_ROWS)
+        .map(|n| n as u64)
+        .filter(|&n| n <= MAX_PAYLOAD)
+        .with_context(|| format!("arch.hidden {} is too large", arch.hidden))
+}
+
+/// Accept coordinators on `listener`, one at a time, until it fails. Each
+/// `load` gets a fresh `Gpu` on the default device.
+pub fn serve(listener: TcpListener) -> Result<()> {
+    eprintln!("llmetal worker: listening on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        stream.set_nodelay(true)?;
+        let peer = stream.peer_addr().map_or_else(|_| "?".to_string(), |a| a.to_string());
+        eprintln!("coordinator {peer} connected");
+        // One broken coordinator must not take the worker down.
+        match serve_connection(&mut stream) {
+            Ok(()) => eprintln!("coordinator {peer} hung up"),
+            Err(e) => eprintln!("connection error: {e:#}"),
+        }
+    }
+    Ok(())
+}
+
+fn serve_connection(stream: &mut TcpStream) -> Result<()> {
+    let mut session: Option<Session> = None;
+    while let Some(req) = read_frame(stream)? {
+        // Read any payload first, so a refused request leaves the stream at a frame.
+        let payload = match payload_limit(&req, session.as_ref()).and_then(|max| read_payload(stream, &req, max)) {
+            Ok(payload) => payload,
+            Err(e) => {
+                write_frame(stream, &json!({ "error": format!("{e:#}") }))?;
+                // Bytes left unread would be taken for the next frame.
+                if req["bytes"].as_u64().unwrap_or(0) > 0 {
+                    return Err(e);
+                }
+                continue;
+            }
+        };
+        match handle(&req, payload, &mut session) {
+            Ok((reply, rows)) => write_payload(stream, &reply, &rows)?,
+            Err(e) => write_frame(stream, &json!({ "error": format!("{e:#}") }))?,
+        }
+    }
+    Ok(())
+}
+
+/// Answer one request: the reply frame and the payload after it.
+fn handle(req: &Value, payload: Vec<u8>, session: &mut Option<Session>) -> Result<(Value, Vec<u8>)> {
+    match req["op"].as_str() {
+        Some("load") => {
+            *session = None;
+            let arch = arch_from_json(&req["arch"])?;
+            ensure!(arch.hidden > 0 && arch.head_dim > 0, "load needs a non-empty arch");
+            forward_limit(&arch)?;
+            let layers = req["layers"].as_array().context("load needs layers")?;
+            let layers: Vec<LayerWeights> = layers.iter().map(layer_from_json).collect::<Result<_>>()?;
+            for w in &layers {
+                check_block(&arch, w)?;
+            }
+            let stage = LocalStage::new(Gpu::new()?);
+            let device = stage.gpu().device_name();
+            *session = Some(Session {
+                arch,
+                first_layer: req["first_layer"].as_u64().context("load needs first_layer")? as usize,
+                k: vec![Vec::new(); layers.len()],
+                v: vec![Vec::new(); layers.len()],
+                layers,
+                stage,
+            });
+            Ok((json!({ "ok": true, "device": device, "bytes": 0 }), Vec::new()))
+        }
+        Some("tensor") => {
+            let s = session.as_mut().context("tensor before load")?;
+            let name = req["name"].as_str().context("tensor needs a name")?;
+            let w = s.weight(name)?;
+            let kind = req["kind"].as_u64().context("tensor needs a kind")? as u32;
+            ensure!(kind == w.kind, "'{name}' is type {kind}, but load said {}", w.kind);
+            let expected = weight_bytes(w)?;
+            ensure!(payload.len() as u64 == expected, "'{name}' is {} bytes, expected {expected}", payload.len());
+            s.stage.insert(name, kind, &payload)?;
+            Ok((json!({ "ok": true, "bytes": 0 }), Vec::new()))
+        }
+        Some("forward") => {
+            let s = session.as_mut().context("forward before load")?;
+            ensure!(
+                s.layers.iter().flat_map(block_tensors).all(|name| s.stage.contains(name)),
+                "forward before every tensor arrived"
+            );
+            let pos = req["pos"].as_u64().context("forward needs pos")? as usize;
+            let held = s.k.first().map_or(0, Vec::len);
+            ensure!(pos <= held, "forward at position {pos}, but only {held} are cached");
+            s.k.iter_mut().chain(&mut s.v).for_each(|rows| rows.truncate(pos));
+            let mut rows = f32_from_bytes(&payload);
+            ensure!(!rows.is_empty() && rows.len().is_multiple_of(s.arch.hidden), "forward rows are not whole [.., {}]", s.arch.hidden);
+            for (i, w) in s.layers.iter().enumerate() {
+                rows = s.stage.block(&s.arch, w, rows, pos, (&mut s.k[i], &mut s.v[i])).with_context(|| format!("block {}", s.first_layer + i))?;
+            }
+            let bytes = f32_bytes(&rows);
+            Ok((json!({ "ok": true, "bytes": bytes.len() }), bytes))
+        }
+        other => bail!("unknown op {other:?}"),
+    }
+}
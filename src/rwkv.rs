@@ -0,0 +1,332 @@
+//! RWKV-6 (`general.architecture` `rwkv6`): a recurrent network that trains
+//! like a transformer and runs like an RNN.
+//!
+//! Each block is time mixing then channel mixing, both on LayerNormed input
+//! and both fed the previous token's input too ("token shift"). Time mixing
+//! lerps between the two by amounts a small LoRA computes from the token,
+//! projects the results to receptance `r`, key `k`, value `v`, gate `g` and
+//! a per-channel decay `w`, and runs the WKV recurrence: every head keeps a
+//! `head_size x head_size` state `S` that each token decays row by row by
+//! `w` and adds `k vᵀ` to, read out as `rᵀ (S + diag(u) k vᵀ)`. Channel
+//! mixing is a squared-ReLU FFN behind a sigmoid of `r`.
+//!
+//! What a sequence has seen is therefore the two shifted inputs and `S`
+//! per layer, fixed in size however long it gets. Decoding, reuse of a
+//! state and the matmul route are `recurrent`'s.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result, ensure};
+
+use crate::bert::layer_norm;
+use crate::events::GenerationEvent;
+use crate::model::{GenerateOptions, Generator, KvSession};
+use crate::recurrent::{self, Matmuls};
+use crate::weights::{ChannelMix, LayerNorm, RwkvWeights, TimeMix};
+
+/// `general.architecture` values this module runs.
+pub const RWKV_ARCHS: [&str; 1] = ["rwkv6"];
+
+/// Epsilon of the per-head group norm after the WKV readout, as in the
+/// reference implementation (not the metadata's LayerNorm epsilon).
+pub const GROUP_NORM_EPS: f32 = 64e-5;
+
+pub fn is_rwkv(architecture: &str) -> bool {
+    RWKV_ARCHS.contains(&architecture)
+}
+
+#[derive(Clone, Debug)]
+pub struct RwkvArch {
+    pub hidden: usize,
+    pub n_layers: usize,
+    pub vocab_size: usize,
+    pub ffn_hidden: usize,
+    pub ctx_train: usize,
+    /// `{arch}.wkv.head_size`.
+    pub head_size: usize,
+    /// `{arch}.time_mix_extra_dim`: rank of the token-shift LoRA.
+    pub time_mix_extra_dim: usize,
+    /// `{arch}.time_decay_extra_dim`: rank of the decay LoRA.
+    pub time_decay_extra_dim: usize,
+    pub layer_norm_eps: f32,
+    /// `{arch}.rescale_every_n_layers`: halve the residual after every n
+    /// blocks (zero: never), which models exported for f16 expect.
+    pub rescale_every: usize,
+}
+
+impl RwkvArch {
+    pub fn n_heads(&self) -> usize {
+        self.hidden / self.head_size.max(1)
+    }
+}
+
+/// One layer's share of a sequence state.
+#[derive(Clone, Debug, PartialEq)]
+struct LayerState {
+    /// The previous token's time-mix input.
+    att_shift: Vec<f32>,
+    /// `S` of every head, `[n_heads, head_size, head_size]`, key-major.
+    wkv: Vec<f32>,
+    /// The previous token's channel-mix input.
+    ffn_shift: Vec<f32>,
+}
+
+/// The recurrent state of one sequence, with the tokens it was computed for.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RwkvState {
+    tokens: Vec<u32>,
+    layers: Vec<LayerState>,
+}
+
+impl RwkvState {
+    pub fn new(arch: &RwkvArch) -> Self {
+        let layer = LayerState {
+            att_shift: vec![0.0; arch.hidden],
+            wkv: vec![0.0; arch.n_heads() * arch.head_size * arch.head_size],
+            ffn_shift: vec![0.0; arch.hidden],
+        };
+        Self { tokens: Vec::new(), layers: vec![layer; arch.n_layers] }
+    }
+
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+}
+
+pub struct RwkvModel {
+    pub arch: RwkvArch,
+    pub weights: Arc<RwkvWeights>,
+    /// What `Generator::generate_cached` continues from, as for `SsmModel`.
+    pub session: RwkvState,
+    mm: Matmuls,
+    load_ms: u128,
+}
+
+impl RwkvModel {
+    pub fn load(path: &str) -> Result<Self> {
+        let t0 = std::time::Instant::now();
+        let mm = Matmuls::open(path)?;
+        let (meta, index) = (&mm.store.metadata, &mm.store.index);
+        let prefix = meta.get("general.architecture").and_then(|v| v.as_str()).unwrap_or("rwkv6").to_string();
+        ensure!(is_rwkv(&prefix), "'{prefix}' is not an RWKV architecture");
+        let get_u = |key: &str| meta.get(&format!("{prefix}.{key}")).and_then(|v| v.as_u64()).map(|v| v as usize);
+        let get_f = |key: &str| meta.get(&format!("{prefix}.{key}")).and_then(|v| v.as_f64()).map(|v| v as f32);
+
+        let hidden = get_u("embedding_length").context("missing embedding_length")?;
+        let arch = RwkvArch {
+            hidden,
+            n_layers: get_u("block_count").context("missing block_count")?,
+            vocab_size: index.get("token_embd.weight").map(|m| m.rows()).context("missing token_embd.weight")?,
+            ffn_hidden: get_u("feed_forward_length").context("missing feed_forward_length")?,
+            ctx_train: get_u("context_length").unwrap_or(1 << 20),
+            head_size: get_u("wkv.head_size").context("missing wkv.head_size")?,
+            time_mix_extra_dim: get_u("time_mix_extra_dim").context("missing time_mix_extra_dim")?,
+            time_decay_extra_dim: get_u("time_decay_extra_dim").context("missing time_decay_extra_dim")?,
+            layer_norm_eps: get_f("attention.layer_norm_epsilon").unwrap_or(1e-5),
+            rescale_every: get_u("rescale_every_n_layers").unwrap_or(0),
+        };
+        ensure!(arch.head_size > 0 && hidden.is_multiple_of(arch.head_size), "wkv.head_size must divide the hidden size");
+        let weights = Arc::new(RwkvWeights::from_index(index, &arch)?);
+        Ok(Self { arch, weights, session: RwkvState::default(), mm, load_ms: t0.elapsed().as_millis() })
+    }
+
+    pub fn declared_stop_tokens(&self) -> Vec<u32> {
+        self.mm.declared_stop_tokens()
+    }
+
+    /// `SsmModel::generate` for RWKV: continue `state` when `tokens` extends
+    /// what it has seen, start over otherwise.
+    pub fn generate(
+        &mut self,
+        state: &mut RwkvState,
+        tokens: &[u32],
+        opts: &GenerateOptions,
+        vocab: &[String],
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<usize> {
+        ensure!(!tokens.is_empty(), "cannot generate from an empty prompt");
+        recurrent::check_options(opts, "RWKV")?;
+        let fresh = RwkvState::new(&self.arch);
+        if state.layers.is_empty() {
+            *state = fresh.clone();
+        }
+        ensure!(
+            state.layers.len() == fresh.layers.len() && state.layers.first().map(|l| l.wkv.len()) == fresh.layers.first().map(|l| l.wkv.len()),
+            "the RWKV state was computed by a model of another shape"
+        );
+        let reused = recurrent::reusable(&state.tokens, tokens);
+        if reused == 0 {
+            *state = fresh;
+        }
+        let load_ms = self.load_ms;
+        recurrent::decode(tokens, reused, opts, vocab, load_ms, on_event, &mut |t| self.forward(t, state))?;
+        Ok(reused)
+    }
+
+    /// Run `tokens` through every block after what `state` has seen, and
+    /// return the logits following the last of them.
+    fn forward(&mut self, tokens: &[u32], state: &mut RwkvState) -> Result<Vec<f32>> {
+        let (h, n) = (self.arch.hidden, tokens.len());
+        let weights = self.weights.clone();
+        let mut x = self.mm.embed(&weights.token_embd, tokens)?;
+        x = self.layer_norm_rows(&weights.token_embd_norm, &x)?;
+
+        for (l, (w, layer)) in weights.layers.iter().zip(&mut state.layers).enumerate() {
+            let xa = self.layer_norm_rows(&w.attn_norm, &x)?;
+            let out = self.time_mix(&w.time_mix, &xa, n, layer)?;
+            x.iter_mut().zip(&out).for_each(|(a, b)| *a += b);
+
+            let xc = self.layer_norm_rows(&w.attn_norm_2, &x)?;
+            let out = self.channel_mix(&w.channel_mix, &xc, n, &mut layer.ffn_shift)?;
+            x.iter_mut().zip(&out).for_each(|(a, b)| *a += b);
+
+            if self.arch.rescale_every > 0 && (l + 1).is_multiple_of(self.arch.rescale_every) {
+                x.iter_mut().for_each(|v| *v *= 0.5);
+            }
+        }
+        state.tokens.extend_from_slice(tokens);
+
+        let last = self.layer_norm_rows(&weights.output_norm, &x[(n - 1) * h..])?;
+        self.mm.linear(&weights.output, None, &last, 1)
+    }
+
+    fn time_mix(&mut self, tm: &TimeMix, xa: &[f32], n: usize, layer: &mut LayerState) -> Result<Vec<f32>> {
+        let (h, lora, head_size) = (self.arch.hidden, self.arch.time_mix_extra_dim, self.arch.head_size);
+        let sx = shift(xa, &mut layer.att_shift);
+        let lerp_x = self.mm.cpu_weights(&tm.lerp_x.name)?;
+        let xxx = mix(xa, &sx, &lerp_x, None);
+        let m: Vec<f32> = self.mm.linear(&tm.w1, None, &xxx, n)?.into_iter().map(f32::tanh).collect();
+
+        // The five data-dependent lerps, in w, k, v, r, g order.
+        let w2 = self.mm.cpu_weights(&tm.w2.name)?;
+        let lerps: Vec<Arc<[f32]>> = tm.lerp.iter().map(|l| self.mm.cpu_weights(&l.name)).collect::<Result<_>>()?;
+        let lerps: Vec<&[f32]> = lerps.iter().flat_map(|l| l.chunks_exact(h)).collect();
+        ensure!(lerps.len() == 5 && w2.len() == 5 * h * lora, "time_mix_lerp or time_mix_w2 is not five [{h}] lerps");
+        let [xw, xk, xv, xr, xg] = std::array::from_fn(|i| {
+            let up = &w2[i * h * lora..][..h * lora];
+            let mi: Vec<f32> = m
+                .chunks_exact(5 * lora)
+                .flat_map(|row| up.chunks_exact(lora).map(|u| crate::cpu::dot(u, &row[i * lora..][..lora])))
+                .collect();
+            mix(xa, &sx, lerps[i], Some(&mi))
+        });
+
+        let r = self.mm.linear(&tm.receptance, None, &xr, n)?;
+        let k = self.mm.linear(&tm.key, None, &xk, n)?;
+        let v = self.mm.linear(&tm.value, None, &xv, n)?;
+        let g: Vec<f32> = self.mm.linear(&tm.gate, None, &xg, n)?.into_iter().map(silu).collect();
+        let dw: Vec<f32> = self.mm.linear(&tm.decay_w1, None, &xw, n)?.into_iter().map(f32::tanh).collect();
+        let decay = self.mm.cpu_weights(&tm.decay.name)?;
+        let mut w = self.mm.linear(&tm.decay_w2, None, &dw, n)?;
+        for row in w.chunks_exact_mut(h) {
+            row.iter_mut().zip(decay.iter()).for_each(|(w, d)| *w = (-(*w + d).exp()).exp());
+        }
+
+        let u = self.mm.cpu_weights(&tm.first.name)?;
+        let (ln_w, ln_b) = (self.mm.cpu_weights(&tm.ln.weight.name)?, self.mm.cpu_weights(&tm.ln.bias.name)?);
+        let mut y = Vec::with_capacity(n * h);
+        for t in 0..n {
+            let row = |m: &[f32]| m[t * h..][..h].to_vec();
+            let yt = wkv6_step(&mut layer.wkv, &row(&r), &row(&k), &row(&v), &row(&w), &u, head_size);
+            y.extend(group_norm(&yt, &ln_w, &ln_b, head_size, GROUP_NORM_EPS));
+        }
+        y.iter_mut().zip(&g).for_each(|(y, g)| *y *= g);
+        self.mm.linear(&tm.output, None, &y, n)
+    }
+
+    fn channel_mix(&mut self, cm: &ChannelMix, xc: &[f32], n: usize, ffn_shift: &mut Vec<f32>) -> Result<Vec<f32>> {
+        let sx = shift(xc, ffn_shift);
+        let xk = mix(xc, &sx, &self.mm.cpu_weights(&cm.lerp_k.name)?, None);
+        let xr = mix(xc, &sx, &self.mm.cpu_weights(&cm.lerp_r.name)?, None);
+        let k: Vec<f32> = self.mm.linear(&cm.key, None, &xk, n)?.into_iter().map(|k| k.max(0.0).powi(2)).collect();
+        let v = self.mm.linear(&cm.value, None, &k, n)?;
+        let r = self.mm.linear(&cm.receptance, None, &xr, n)?;
+        Ok(r.iter().zip(&v).map(|(r, v)| sigmoid(*r) * v).collect())
+    }
+
+    fn layer_norm_rows(&mut self, norm: &LayerNorm, x: &[f32]) -> Result<Vec<f32>> {
+        let (w, b) = (self.mm.cpu_weights(&norm.weight.name)?, self.mm.cpu_weights(&norm.bias.name)?);
+        Ok(x.chunks_exact(w.len()).flat_map(|r| layer_norm(r, &w, &b, self.arch.layer_norm_eps)).collect())
+    }
+}
+
+impl Generator for RwkvModel {
+    /// As for `SsmModel`: the model continues its own `session`.
+    fn generate_cached(
+        &mut self,
+        _session: &mut KvSession,
+        tokens: &[u32],
+        opts: &GenerateOptions,
+        vocab: &[String],
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<usize> {
+        let mut state = std::mem::take(&mut self.session);
+        let reused = self.generate(&mut state, tokens, opts, vocab, on_event);
+        self.session = state;
+        reused
+    }
+}
+
+// ---------------------------------------------------------------------------
+// CPU math
+// ---------------------------------------------------------------------------
+
+/// `prev - x` for each of the packed rows of `x`, `prev` being the row
+/// before (`last` for the first row); `last` becomes the final row.
+pub(crate) fn shift(x: &[f32], last: &mut Vec<f32>) -> Vec<f32> {
+    let h = last.len();
+    let mut sx = Vec::with_capacity(x.len());
+    let mut prev: &[f32] = last;
+    for row in x.chunks_exact(h) {
+        sx.extend(prev.iter().zip(row).map(|(p, x)| p - x));
+        prev = row;
+    }
+    *last = prev.to_vec();
+    sx
+}
+
+/// `x + sx * (lerp + m)` row by row; `m` is per row, `lerp` shared.
+fn mix(x: &[f32], sx: &[f32], lerp: &[f32], m: Option<&[f32]>) -> Vec<f32> {
+    let h = lerp.len();
+    (0..x.len()).map(|i| x[i] + sx[i] * (lerp[i % h] + m.map_or(0.0, |m| m[i]))).collect()
+}
+
+/// One token of the WKV-6 recurrence over every head: `y = rᵀ (S + diag(u)
+/// k vᵀ)`, then `S = diag(w) S + k vᵀ`. `state` is `[heads, key, value]`,
+/// `u` is `[heads, head_size]`.
+pub(crate) fn wkv6_step(
+    state: &mut [f32], r: &[f32], k: &[f32], v: &[f32], w: &[f32], u: &[f32], head_size: usize,
+) -> Vec<f32> {
+    let s = head_size;
+    let mut y = vec![0.0f32; r.len()];
+    for head in 0..r.len() / s {
+        let o = head * s;
+        let st = &mut state[head * s * s..][..s * s];
+        for i in 0..s {
+            let (ri, ki, ui, wi) = (r[o + i], k[o + i], u[o + i], w[o + i]);
+            let row = &mut st[i * s..][..s];
+            for j in 0..s {
+                let kv = ki * v[o + j];
+                y[o + j] += ri * (ui * kv + row[j]);
+                row[j] = row[j] * wi + kv;
+            }
+        }
+    }
+    y
+}
+
+/// LayerNorm over each `group`-wide slice of `x` on its own, with the
+/// per-channel scale and shift of the whole row.
+pub(crate) fn group_norm(x: &[f32], w: &[f32], b: &[f32], group: usize, eps: f32) -> Vec<f32> {
+    let (ones, zeros) = (vec![1.0; group], vec![0.0; group]);
+    let normed = x.chunks_exact(group).flat_map(|g| layer_norm(g, &ones, &zeros, eps));
+    normed.zip(w.iter().zip(b)).map(|(v, (w, b))| v * w + b).collect()
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+fn silu(x: f32) -> f32 {
+    x * sigmoid(x)
+}
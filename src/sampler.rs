@@ -0,0 +1,435 @@
+//! Logit post-processing and token choice.
+//!
+//! Everything that bends the distribution before a token is chosen lives here,
+//! in the order it is applied: classic repetition penalty, then DRY, then the
+//! loop watchdog, then the JSON grammar and regex masks, then any custom
+//! `LogitsProcessor`s, then the thinking budget, then XTC, then the final
+//! pick: greedy, or with a temperature a draw from the top-k / top-p
+//! survivors. The forward pass never touches sampling state.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+
+use crate::json_grammar::JsonConstraint;
+use crate::reasoning::ThinkTokens;
+use crate::regex_grammar::{Regex, RegexConstraint};
+use crate::tokenizer::detokenize;
+
+#[derive(Clone, Debug)]
+pub struct SamplerConfig {
+    /// Divide positive / multiply negative logits of already-generated tokens.
+    /// 1.0 disables it.
+    pub repetition_penalty: f32,
+    pub dry: Option<DryConfig>,
+    pub xtc: Option<XtcConfig>,
+    /// RNG seed for the probabilistic samplers; `None` seeds from the clock.
+    pub seed: Option<u64>,
+    /// Softmax temperature of the final pick; 0 is greedy (argmax).
+    pub temperature: f32,
+    /// Draw only among the `top_k` most likely tokens; 0 keeps all.
+    pub top_k: usize,
+    /// Draw only among the most likely tokens whose probabilities sum to
+    /// `top_p`; 1.0 keeps all.
+    pub top_p: f32,
+    /// Constrain the output to one JSON object (`response_format: json_object`).
+    pub json_object: bool,
+    /// Constrain the output to one match of this pattern (`--regex`).
+    pub regex: Option<Arc<Regex>>,
+    pub loop_guard: Option<LoopGuard>,
+    /// Force `</think>` once a thinking block has run this many tokens
+    /// (see `reasoning`). Ignored when the vocab has no thinking tags.
+    pub max_thinking: Option<usize>,
+    /// Custom steps, run in order after the built-in masks.
+    pub processors: Vec<Arc<dyn LogitsProcessor>>,
+}
+
+impl Default for SamplerConfig {
+    fn default() -> Self {
+        Self { repetition_penalty: 1.3, dry: None, xtc: None, seed: None, temperature: 0.0, top_k: 0, top_p: 1.0, json_object: false, regex: None, loop_guard: None, max_thinking: None, processors: Vec::new() }
+    }
+}
+
+/// A sampling step from outside the crate: custom constraints, watermarks,
+/// safety filters. `process` runs once per sampled token with the context
+/// so far (prompt, then `n_generated` tokens of output) and may change any
+/// logit; `-inf` bans a token. One processor can serve several sequences
+/// at once (`generate_batch`), so any state it keeps must be its own to
+/// lock, keyed by the context it is given.
+///
+/// Beam search and `choose` do not sample, and the daemon protocol cannot
+/// carry a processor, so those paths never call one.
+pub trait LogitsProcessor: Send + Sync {
+    fn process(&self, context: &[u32], n_generated: usize, logits: &mut [f32]);
+
+    /// Shown by `SamplerConfig`'s `Debug`.
+    fn name(&self) -> &str {
+        "logits processor"
+    }
+}
+
+impl std::fmt::Debug for dyn LogitsProcessor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// XTC ("exclude top choices"): with `probability`, drop every token whose
+/// probability is at least `threshold` except the least likely of them. The
+/// obvious continuation is removed while a still-plausible one survives.
+#[derive(Clone, Copy, Debug)]
+pub struct XtcConfig {
+    pub probability: f32,
+    pub threshold: f32,
+}
+
+/// DRY ("don't repeat yourself"): penalise the token that would extend a
+/// sequence already seen in the context, growing exponentially with the
+/// length of the repeat. Unlike the classic penalty it leaves single reused
+/// tokens alone, which is what code (`{`, `;`, identifiers) needs.
+#[derive(Clone, Debug)]
+pub struct DryConfig {
+    pub multiplier: f32,
+    pub base: f32,
+    /// Repeats shorter than this are free.
+    pub allowed_length: usize,
+    /// Strings that end a repeat: a match never extends across them.
+    pub sequence_breakers: Vec<String>,
+}
+
+impl Default for DryConfig {
+    fn default() -> Self {
+        Self {
+            multiplier: 0.8,
+            base: 1.75,
+            allowed_length: 2,
+            sequence_breakers: ["\n", ":", "\"", "*"].map(String::from).to_vec(),
+        }
+    }
+}
+
+/// Loop watchdog: fires once the generated tail is one block of at most
+/// `max_period` tokens repeated back to back, at least `min_repeats` times
+/// and over at least `min_tokens` tokens, so a short run like `----` is
+/// not a loop but the same sentence three times is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoopGuard {
+    pub max_period: usize,
+    pub min_repeats: usize,
+    pub min_tokens: usize,
+    pub action: LoopAction,
+}
+
+impl Default for LoopGuard {
+    fn default() -> Self {
+        Self { max_period: 32, min_repeats: 3, min_tokens: 32, action: LoopAction::Stop }
+    }
+}
+
+/// What the loop watchdog does once it fires.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoopAction {
+    /// End generation with `FinishReason::Loop`.
+    Stop,
+    /// Subtract this from the logit of the token that would continue the
+    /// loop, once more for every repeat past `min_repeats`.
+    Penalize(f32),
+    /// Forbid the token that would continue the loop and draw the next one
+    /// at this temperature, whatever the configured one.
+    Diversify(f32),
+}
+
+impl LoopAction {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Stop => "stop",
+            Self::Penalize(_) => "penalize",
+            Self::Diversify(_) => "diversify",
+        }
+    }
+}
+
+/// `stop`, `penalize[=STRENGTH]` (5 by default) or `diversify[=TEMPERATURE]`
+/// (1 by default).
+impl std::str::FromStr for LoopAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, arg) = s.split_once('=').map_or((s, None), |(n, v)| (n, Some(v)));
+        let value = |default: f32| arg.map_or(Ok(default), |v| v.parse().with_context(|| format!("loop action value {v:?}")));
+        match name {
+            "stop" if arg.is_none() => Ok(Self::Stop),
+            "penalize" => Ok(Self::Penalize(value(5.0)?)),
+            "diversify" => Ok(Self::Diversify(value(1.0)?)),
+            _ => bail!("unknown loop action '{s}' (expected stop, penalize[=F] or diversify[=F])"),
+        }
+    }
+}
+
+/// A repeating tail found by `find_loop`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LoopMatch {
+    /// Length of the repeated block.
+    pub period: usize,
+    /// How many trailing tokens repeat it.
+    pub len: usize,
+}
+
+/// The shortest-period loop `guard` would fire on at the end of `generated`.
+pub fn find_loop(generated: &[u32], guard: &LoopGuard) -> Option<LoopMatch> {
+    let n = generated.len();
+    (1..=guard.max_period).find_map(|period| {
+        let need = (period * guard.min_repeats).max(guard.min_tokens).max(2 * period);
+        if n < need || (n - need..n - period).any(|i| generated[i] != generated[i + period]) {
+            return None;
+        }
+        let mut len = need;
+        while len < n && generated[n - len - 1] == generated[n - len - 1 + period] {
+            len += 1;
+        }
+        Some(LoopMatch { period, len })
+    })
+}
+
+/// Longest repeat DRY will look for; bounds the per-step cost.
+const DRY_MAX_MATCH: usize = 64;
+
+pub struct Sampler {
+    config: SamplerConfig,
+    /// Token ids whose text contains a DRY sequence breaker.
+    breakers: HashSet<u32>,
+    json: Option<JsonConstraint>,
+    regex: Option<RegexConstraint>,
+    /// The last `sample` found a loop and the watchdog's action is `Stop`.
+    looping: bool,
+    /// The thinking tags and `max_thinking`, when both are there.
+    think: Option<(ThinkTokens, usize)>,
+    rng: Rng,
+}
+
+impl Sampler {
+    pub fn new(config: SamplerConfig, vocab: &[String]) -> Self {
+        let breakers = match &config.dry {
+            Some(dry) => (0..vocab.len() as u32)
+                .filter(|&id| {
+                    let text = detokenize(id, vocab);
+                    dry.sequence_breakers.iter().any(|b| text.contains(b.as_str()))
+                })
+                .collect(),
+            None => HashSet::new(),
+        };
+        let seed = config.seed.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64)
+        });
+        let json = config.json_object.then(|| JsonConstraint::new(vocab));
+        let regex = config.regex.clone().map(|r| RegexConstraint::new(r, vocab));
+        let think = config.max_thinking.and_then(|max| Some((ThinkTokens::find(vocab)?, max)));
+        Self { config, breakers, json, regex, looping: false, think, rng: Rng::new(seed) }
+    }
+
+    /// Tokens besides `</s>` that end generation; the JSON grammar and the
+    /// regex allow them only once the output is complete.
+    pub fn with_stop_tokens(mut self, ids: &[u32]) -> Self {
+        self.json = self.json.map(|j| j.with_end_tokens(ids));
+        self.regex = self.regex.map(|r| r.with_end_tokens(ids));
+        self
+    }
+
+    /// The loop watchdog wants generation to stop: the output before the
+    /// token `sample` just returned already loops.
+    pub fn looping(&self) -> bool {
+        self.looping
+    }
+
+    /// Apply every penalty to `logits` and pick the next token.
+    /// `context` is prompt + generated so far; its last `n_generated` ids are model output.
+    pub fn sample(&mut self, logits: &mut [f32], context: &[u32], n_generated: usize) -> u32 {
+        let generated = &context[context.len() - n_generated..];
+        apply_repetition_penalty(logits, generated, self.config.repetition_penalty);
+        if let Some(dry) = &self.config.dry {
+            apply_dry(logits, context, dry, &self.breakers);
+        }
+        let mut temperature = self.config.temperature;
+        self.looping = false;
+        if let Some((guard, m)) = self.config.loop_guard.and_then(|g| Some((g, find_loop(generated, &g)?))) {
+            let next = generated[generated.len() - m.period] as usize;
+            match guard.action {
+                LoopAction::Stop => self.looping = true,
+                LoopAction::Penalize(strength) => {
+                    if let Some(l) = logits.get_mut(next) {
+                        *l -= strength * (m.len / m.period + 1 - guard.min_repeats) as f32;
+                    }
+                }
+                LoopAction::Diversify(t) => {
+                    if let Some(l) = logits.get_mut(next) {
+                        *l = f32::NEG_INFINITY;
+                    }
+                    temperature = t;
+                }
+            }
+        }
+        if let Some(json) = &self.json {
+            json.mask(logits);
+        }
+        if let Some(regex) = &mut self.regex {
+            regex.mask(logits);
+        }
+        for processor in &self.config.processors {
+            processor.process(context, n_generated, logits);
+        }
+        if let Some((tags, max)) = self.think
+            && tags.thinking_len(context).is_some_and(|n| n >= max)
+        {
+            for (id, l) in logits.iter_mut().enumerate() {
+                if id != tags.close as usize {
+                    *l = f32::NEG_INFINITY;
+                }
+            }
+        }
+        if let Some(xtc) = self.config.xtc {
+            apply_xtc(logits, xtc, &mut self.rng);
+        }
+        let id = if temperature > 0.0 {
+            let c = &self.config;
+            sample_top_p(logits, temperature, c.top_k, c.top_p, &mut self.rng)
+        } else {
+            argmax(logits)
+        };
+        if let Some(json) = &mut self.json {
+            json.accept(id);
+        }
+        if let Some(regex) = &mut self.regex {
+            regex.accept(id);
+        }
+        id
+    }
+}
+
+/// Divide logits of recently-generated tokens by `penalty` (or multiply if logit < 0).
+/// Suppresses repetition without temperature sampling.
+pub fn apply_repetition_penalty(logits: &mut [f32], seen: &[u32], penalty: f32) {
+    for &id in seen {
+        if let Some(l) = logits.get_mut(id as usize) {
+            if *l > 0.0 { *l /= penalty; } else { *l *= penalty; }
+        }
+    }
+}
+
+/// For every earlier position `i`, measure how long the context suffix ending
+/// just before `i` matches the suffix ending now. If that match is at least
+/// `allowed_length`, then `context[i]` would extend the repeat, and its logit
+/// loses `multiplier * base^(len - allowed_length)` (longest match per token wins).
+pub fn apply_dry(logits: &mut [f32], context: &[u32], dry: &DryConfig, breakers: &HashSet<u32>) {
+    let n = context.len();
+    if n < 2 || dry.multiplier == 0.0 {
+        return;
+    }
+    let mut longest: HashMap<u32, usize> = HashMap::new();
+    for i in 1..n {
+        let next = context[i];
+        if breakers.contains(&next) {
+            continue;
+        }
+        let mut len = 0;
+        while len < i && len < DRY_MAX_MATCH {
+            let (a, b) = (context[i - 1 - len], context[n - 1 - len]);
+            if a != b || breakers.contains(&a) {
+                break;
+            }
+            len += 1;
+        }
+        if len >= dry.allowed_length {
+            let e = longest.entry(next).or_default();
+            *e = (*e).max(len);
+        }
+    }
+    for (id, len) in longest {
+        if let Some(l) = logits.get_mut(id as usize) {
+            *l -= dry.multiplier * dry.base.powi((len - dry.allowed_length) as i32);
+        }
+    }
+}
+
+pub fn apply_xtc(logits: &mut [f32], xtc: XtcConfig, rng: &mut Rng) {
+    if rng.next_f32() >= xtc.probability {
+        return;
+    }
+    let probs = softmax(logits);
+    let mut above: Vec<usize> = (0..probs.len()).filter(|&i| probs[i] >= xtc.threshold).collect();
+    if above.len() < 2 {
+        return;
+    }
+    above.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+    for &i in &above[..above.len() - 1] {
+        logits[i] = f32::NEG_INFINITY;
+    }
+}
+
+/// Draw a token from `softmax(logits / temperature)`, restricted to the
+/// `top_k` most likely (0 keeps all) and then to the shortest prefix of
+/// those whose probability reaches `top_p`. The most likely token always
+/// survives.
+pub fn sample_top_p(logits: &[f32], temperature: f32, top_k: usize, top_p: f32, rng: &mut Rng) -> u32 {
+    let scaled: Vec<f32> = logits.iter().map(|l| l / temperature).collect();
+    let probs = softmax(&scaled);
+    let mut order: Vec<usize> = (0..probs.len()).filter(|&i| probs[i] > 0.0).collect();
+    order.sort_by(|&a, &b| probs[b].total_cmp(&probs[a]));
+    if top_k > 0 {
+        order.truncate(top_k);
+    }
+    let mut mass = 0.0;
+    let keep = order.iter().position(|&i| {
+        mass += probs[i];
+        mass >= top_p
+    });
+    order.truncate(keep.map_or(order.len(), |k| k + 1));
+    let total: f32 = order.iter().map(|&i| probs[i]).sum();
+    let mut r = rng.next_f32() * total;
+    for &i in &order {
+        r -= probs[i];
+        if r < 0.0 {
+            return i as u32;
+        }
+    }
+    order.last().map_or_else(|| argmax(logits), |&i| i as u32)
+}
+
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let exp: Vec<f32> = logits.iter().map(|l| (l - max).exp()).collect();
+    let sum: f32 = exp.iter().sum();
+    exp.into_iter().map(|e| e / sum).collect()
+}
+
+/// SplitMix64: a tiny, seedable PRNG. Sampling needs reproducibility, not
+/// cryptographic quality.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in [0, 1).
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+pub fn argmax(v: &[f32]) -> u32 {
+    v.iter().enumerate()
+        .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+        .map(|(i, _)| i as u32)
+        .unwrap_or(0)
+}
@@ -0,0 +1,324 @@
+//! Nearest-neighbour search over embeddings, for local retrieval without a
+//! vector database (`embed --output docs.idx`, then `search`).
+//!
+//! An index is every document's id, text and L2-normalised vector, so cosine
+//! similarity is a dot product. `search` scores the query against every
+//! vector with the SIMD `cpu::dot` across threads, which is exact and fast
+//! enough for a few hundred thousand documents. Past that, `--hnsw` builds a
+//! Hierarchical Navigable Small World graph when the index loads and walks
+//! it instead: approximate, but it touches a few thousand vectors per query.
+//!
+//! On disk: `LLMVIDX1`, a little-endian u32 header length, a JSON header
+//! (`dim`, `pooling`, `max_tokens` and `docs` as `{"id", "text"}`), then
+//! `docs.len() * dim` little-endian f32s. `search` also reads the
+//! `{"id", "embedding"}` lines `embed --output docs.jsonl` writes, without
+//! texts to print.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result, bail, ensure};
+
+use crate::embed::{Document, Pooling, l2_normalize};
+use crate::sampler::Rng;
+
+const MAGIC: &[u8; 8] = b"LLMVIDX1";
+
+/// One result: the document's position in the index and its cosine
+/// similarity to the query.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hit {
+    pub index: usize,
+    pub score: f32,
+}
+
+pub struct VectorIndex {
+    pub dim: usize,
+    /// How the vectors were pooled, so queries are embedded the same way;
+    /// `None` for the model's default.
+    pub pooling: Option<Pooling>,
+    pub max_tokens: Option<usize>,
+    pub docs: Vec<Document>,
+    vectors: Vec<f32>,
+    hnsw: Option<Hnsw>,
+}
+
+impl VectorIndex {
+    /// An index of `docs` and their embeddings, normalised again in case
+    /// they came from elsewhere.
+    pub fn new(docs: Vec<Document>, rows: &[Vec<f32>]) -> Result<Self> {
+        ensure!(docs.len() == rows.len(), "{} documents but {} embeddings", docs.len(), rows.len());
+        let dim = rows.first().map_or(0, Vec::len);
+        ensure!(rows.iter().all(|r| r.len() == dim), "embeddings have mixed dimensions");
+        let mut vectors = Vec::with_capacity(rows.len() * dim);
+        for row in rows {
+            let start = vectors.len();
+            vectors.extend_from_slice(row);
+            l2_normalize(&mut vectors[start..]);
+        }
+        Ok(Self { dim, pooling: None, max_tokens: None, docs, vectors, hnsw: None })
+    }
+
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
+    }
+
+    pub fn vector(&self, i: usize) -> &[f32] {
+        &self.vectors[i * self.dim..(i + 1) * self.dim]
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let docs: Vec<_> = self.docs.iter().map(|d| serde_json::json!({ "id": d.id, "text": d.text })).collect();
+        let header = serde_json::json!({
+            "dim": self.dim,
+            "pooling": self.pooling.map(Pooling::name),
+            "max_tokens": self.max_tokens,
+            "docs": docs,
+        })
+        .to_string();
+        let mut w = BufWriter::new(File::create(path).with_context(|| format!("create {}", path.display()))?);
+        w.write_all(MAGIC)?;
+        w.write_all(&(header.len() as u32).to_le_bytes())?;
+        w.write_all(header.as_bytes())?;
+        for v in &self.vectors {
+            w.write_all(&v.to_le_bytes())?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// An index written by `write`, or `embed`'s JSONL output.
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+        let file_len = file.metadata()?.len();
+        let mut r = BufReader::new(file);
+        if r.fill_buf()?.starts_with(MAGIC) {
+            Self::read(&mut r, file_len).with_context(|| format!("read index {}", path.display()))
+        } else {
+            Self::read_jsonl(r).with_context(|| format!("read embeddings {}", path.display()))
+        }
+    }
+
+    /// Sizes from the header are checked against `file_len` before
+    /// anything is allocated for them.
+    fn read(r: &mut impl Read, file_len: u64) -> Result<Self> {
+        let mut head = [0u8; 12];
+        r.read_exact(&mut head)?;
+        let len = u32::from_le_bytes(head[8..].try_into().unwrap()) as u64;
+        let rest = file_len.saturating_sub(head.len() as u64);
+        ensure!(len <= rest, "index header of {len} bytes is longer than the file");
+        let mut header = vec![0u8; len as usize];
+        r.read_exact(&mut header)?;
+        let header: serde_json::Value = serde_json::from_slice(&header).context("invalid index header")?;
+        let dim = header["dim"].as_u64().context("index header has no dim")? as usize;
+        let docs = header["docs"]
+            .as_array()
+            .context("index header has no docs")?
+            .iter()
+            .map(|d| Document { id: d["id"].as_str().map(str::to_string), text: d["text"].as_str().unwrap_or("").to_string() })
+            .collect::<Vec<_>>();
+        let pooling = header["pooling"].as_str().map(str::parse).transpose()?;
+        let max_tokens = header["max_tokens"].as_u64().map(|n| n as usize);
+        let size = docs
+            .len()
+            .checked_mul(dim)
+            .and_then(|n| n.checked_mul(4))
+            .with_context(|| format!("{} vectors of dim {dim} overflow", docs.len()))?;
+        ensure!(size as u64 <= rest - len, "index is shorter than its header says");
+        let mut bytes = vec![0u8; size];
+        r.read_exact(&mut bytes).context("index is shorter than its header says")?;
+        let vectors = bytes.chunks_exact(4).map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect();
+        Ok(Self { dim, pooling, max_tokens, docs, vectors, hnsw: None })
+    }
+
+    fn read_jsonl(r: impl BufRead) -> Result<Self> {
+        let (mut docs, mut rows) = (Vec::new(), Vec::new());
+        for (i, line) in r.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let value: serde_json::Value = serde_json::from_str(&line).with_context(|| format!("line {}: invalid JSON", i + 1))?;
+            let Some(embedding) = value["embedding"].as_array() else {
+                bail!("line {}: missing array field \"embedding\"", i + 1);
+            };
+            let id = match &value["id"] {
+                serde_json::Value::String(s) => Some(s.clone()),
+                serde_json::Value::Null => None,
+                other => Some(other.to_string()),
+            };
+            docs.push(Document { id, text: String::new() });
+            rows.push(embedding.iter().map(|v| v.as_f64().unwrap_or(0.0) as f32).collect());
+        }
+        Self::new(docs, &rows)
+    }
+
+    /// The `k` documents most similar to `query`, best first: an exact scan,
+    /// or a walk of the HNSW graph once `build_hnsw` has run.
+    pub fn search(&self, query: &[f32], k: usize) -> Result<Vec<Hit>> {
+        ensure!(query.len() == self.dim, "query has {} dimensions but the index has {}", query.len(), self.dim);
+        let mut q = query.to_vec();
+        l2_normalize(&mut q);
+        Ok(match &self.hnsw {
+            Some(graph) => graph.search(self, &q, k),
+            None => self.scan(&q, k),
+        })
+    }
+
+    /// Every vector scored against `q` on all cores, the top `k` kept.
+    pub fn scan(&self, q: &[f32], k: usize) -> Vec<Hit> {
+        let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+        let per_thread = self.len().div_ceil(threads).max(1024);
+        let scores: Vec<f32> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..self.len())
+                .step_by(per_thread)
+                .map(|start| {
+                    let end = (start + per_thread).min(self.len());
+                    s.spawn(move || (start..end).map(|i| crate::cpu::dot(q, self.vector(i))).collect::<Vec<_>>())
+                })
+                .collect();
+            handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+        });
+        let mut hits: Vec<Hit> = scores.into_iter().enumerate().map(|(index, score)| Hit { index, score }).collect();
+        let by_score = |a: &Hit, b: &Hit| b.score.total_cmp(&a.score).then(a.index.cmp(&b.index));
+        if k < hits.len() {
+            hits.select_nth_unstable_by(k, by_score);
+            hits.truncate(k);
+        }
+        hits.sort_by(by_score);
+        hits
+    }
+
+    /// Build the HNSW graph `search` walks from now on. `m` links per node
+    /// (twice that on the bottom layer), `ef` candidates while linking and
+    /// searching; the level draws are seeded, so a given index always
+    /// builds the same graph.
+    pub fn build_hnsw(&mut self, m: usize, ef: usize) {
+        let mut graph = Hnsw::new(m.max(2), ef.max(1));
+        for i in 0..self.len() {
+            graph.insert(self, i);
+        }
+        self.hnsw = Some(graph);
+    }
+}
+
+/// A candidate in a graph search, ordered by similarity.
+#[derive(Clone, Copy, PartialEq)]
+struct Near(f32, u32);
+
+impl Eq for Near {}
+
+impl Ord for Near {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(other.1.cmp(&self.1))
+    }
+}
+
+impl PartialOrd for Near {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+struct Hnsw {
+    m: usize,
+    ef: usize,
+    /// `links[node][level]`: a node's neighbours on each layer it is on.
+    links: Vec<Vec<Vec<u32>>>,
+    entry: Option<u32>,
+    rng: Rng,
+}
+
+impl Hnsw {
+    fn new(m: usize, ef: usize) -> Self {
+        Self { m, ef, links: Vec::new(), entry: None, rng: Rng::new(0x5EED) }
+    }
+
+    fn cap(&self, level: usize) -> usize {
+        if level == 0 { 2 * self.m } else { self.m }
+    }
+
+    fn insert(&mut self, index: &VectorIndex, node: usize) {
+        // Levels are geometric with ratio 1/m, as in the paper.
+        let u = self.rng.next_f32().max(f32::MIN_POSITIVE);
+        let level = (-u.ln() / (self.m as f32).ln()) as usize;
+        self.links.push(vec![Vec::new(); level + 1]);
+        let Some(entry) = self.entry else {
+            self.entry = Some(node as u32);
+            return;
+        };
+        let q = index.vector(node);
+        let top = self.links[entry as usize].len() - 1;
+        let mut nearest = vec![Near(crate::cpu::dot(q, index.vector(entry as usize)), entry)];
+        for l in (level + 1..=top).rev() {
+            nearest = self.search_layer(index, q, &nearest, 1, l);
+        }
+        for l in (0..=level.min(top)).rev() {
+            nearest = self.search_layer(index, q, &nearest, self.ef, l);
+            let neighbours: Vec<u32> = nearest.iter().take(self.m).map(|n| n.1).collect();
+            for &n in &neighbours {
+                let cap = self.cap(l);
+                let list = &mut self.links[n as usize][l];
+                list.push(node as u32);
+                if list.len() > cap {
+                    let base = index.vector(n as usize);
+                    list.sort_by(|a, b| {
+                        let sim = |x: &u32| crate::cpu::dot(base, index.vector(*x as usize));
+                        sim(b).total_cmp(&sim(a))
+                    });
+                    list.truncate(cap);
+                }
+            }
+            self.links[node][l] = neighbours;
+        }
+        if level > top {
+            self.entry = Some(node as u32);
+        }
+    }
+
+    /// The `ef` nodes on layer `level` nearest `q` found greedily from
+    /// `entries`, best first.
+    fn search_layer(&self, index: &VectorIndex, q: &[f32], entries: &[Near], ef: usize, level: usize) -> Vec<Near> {
+        let mut seen: HashSet<u32> = entries.iter().map(|n| n.1).collect();
+        let mut frontier: BinaryHeap<Near> = entries.iter().copied().collect();
+        let mut found: BinaryHeap<Reverse<Near>> = entries.iter().copied().map(Reverse).collect();
+        while let Some(best) = frontier.pop() {
+            if found.len() >= ef && found.peek().is_some_and(|w| best < w.0) {
+                break;
+            }
+            for &n in self.links[best.1 as usize].get(level).into_iter().flatten() {
+                if !seen.insert(n) {
+                    continue;
+                }
+                let near = Near(crate::cpu::dot(q, index.vector(n as usize)), n);
+                if found.len() < ef || found.peek().is_some_and(|w| near > w.0) {
+                    frontier.push(near);
+                    found.push(Reverse(near));
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+        let mut out: Vec<Near> = found.into_iter().map(|r| r.0).collect();
+        out.sort_by(|a, b| b.cmp(a));
+        out
+    }
+
+    fn search(&self, index: &VectorIndex, q: &[f32], k: usize) -> Vec<Hit> {
+        let Some(entry) = self.entry else { return Vec::new() };
+        let mut nearest = vec![Near(crate::cpu::dot(q, index.vector(entry as usize)), entry)];
+        for l in (1..self.links[entry as usize].len()).rev() {
+            nearest = self.search_layer(index, q, &nearest, 1, l);
+        }
+        nearest = self.search_layer(index, q, &nearest, self.ef.max(k), 0);
+        nearest.into_iter().take(k).map(|n| Hit { index: n.1 as usize, score: n.0 }).collect()
+    }
+}
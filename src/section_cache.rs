@@ -0,0 +1,118 @@
+//! Precomputed K/V rows for prompt sections that many contexts share: a
+//! system prompt, tool definitions, RAG boilerplate. Each section is
+//! prefilled once, on its own from position 0, and kept under a hash of
+//! the model and its tokens; `LlamaModel::stitch_sections` then lays
+//! cached sections back to back at the front of a new context, so only
+//! what follows them is prefilled.
+//!
+//! K rows carry their position through RoPE, so a section stitched after
+//! another has its keys rotated forward to where it now sits, which is
+//! exact. What stitching cannot recover is attention across sections: each
+//! one's rows were computed seeing only itself. The first section of a
+//! context is therefore exactly what a full prefill would produce, and
+//! every later one an approximation that holds up for self-contained
+//! blocks (a tool schema does not change meaning after a system prompt).
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::model::KvSession;
+
+/// K/V bytes a cache holds by default before it drops sections.
+pub const DEFAULT_MAX_BYTES: usize = 1 << 30;
+
+/// Sections seen so far, the least recently used dropped past `max_bytes`.
+pub struct SectionCache {
+    sections: HashMap<u64, Entry>,
+    max_bytes: usize,
+    bytes: usize,
+    /// Bumped on every lookup, to order entries by last use.
+    clock: u64,
+    hits: usize,
+    misses: usize,
+}
+
+struct Entry {
+    section: Arc<KvSession>,
+    bytes: usize,
+    last_used: u64,
+}
+
+/// What a `SectionCache` holds and how often it was hit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SectionStats {
+    pub sections: usize,
+    pub bytes: usize,
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl Default for SectionCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_BYTES)
+    }
+}
+
+impl SectionCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self { sections: HashMap::new(), max_bytes, bytes: 0, clock: 0, hits: 0, misses: 0 }
+    }
+
+    /// The section prefilled from `tokens` on the model `model`
+    /// (`LlamaModel::model_key`), if it is cached.
+    pub fn get(&mut self, model: u64, tokens: &[u32]) -> Option<Arc<KvSession>> {
+        self.clock += 1;
+        // The key is a hash; the tokens settle a collision.
+        match self.sections.get_mut(&key(model, tokens)) {
+            Some(e) if e.section.tokens() == tokens => {
+                e.last_used = self.clock;
+                self.hits += 1;
+                Some(e.section.clone())
+            }
+            _ => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Keep `section`, dropping the least recently used until it fits. A
+    /// section larger than the whole cache is handed back but not kept.
+    pub fn insert(&mut self, model: u64, section: KvSession) -> Arc<KvSession> {
+        let section = Arc::new(section);
+        let bytes = section.stats().bytes;
+        if bytes > self.max_bytes {
+            return section;
+        }
+        let key = key(model, section.tokens());
+        if let Some(old) = self.sections.remove(&key) {
+            self.bytes -= old.bytes;
+        }
+        while self.bytes + bytes > self.max_bytes {
+            let Some(oldest) = self.sections.iter().min_by_key(|(_, e)| e.last_used).map(|(&k, _)| k) else { break };
+            self.bytes -= self.sections.remove(&oldest).map_or(0, |e| e.bytes);
+        }
+        self.bytes += bytes;
+        self.sections.insert(key, Entry { section: section.clone(), bytes, last_used: self.clock });
+        section
+    }
+
+    pub fn clear(&mut self) {
+        self.sections.clear();
+        self.bytes = 0;
+    }
+
+    pub fn stats(&self) -> SectionStats {
+        SectionStats { sections: self.sections.len(), bytes: self.bytes, hits: self.hits, misses: self.misses }
+    }
+}
+
+/// FNV-1a over the model key and the section's token ids.
+pub fn key(model: u64, tokens: &[u32]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in model.to_le_bytes().into_iter().chain(tokens.iter().flat_map(|t| t.to_le_bytes())) {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h
+}
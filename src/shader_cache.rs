@@ -0,0 +1,154 @@
+//! On-disk cache of the compiled kernels. Building a compute pipeline
+//! compiles its function for the GPU, which is most of the startup cost
+//! before the first token; a Metal binary archive keeps those GPU binaries
+//! between launches, so every launch after the first takes them from disk.
+//!
+//! There is one archive per GPU and kernel source, at
+//! `<cache dir>/shaders/<family>-<gpu>-<hash>.metallib` (see
+//! `repack::cache_dir`). The hash covers `kernels.metal` and the macOS
+//! build, so an edited kernel or a system update (which may change the
+//! compiler) starts a fresh archive rather than reusing one Metal would
+//! ignore. The cache is best effort: without a cache directory, or when an
+//! archive does not open, pipelines compile as they always did.
+
+use std::mem::ManuallyDrop;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use metal::{
+    BinaryArchive, BinaryArchiveDescriptor, ComputePipelineDescriptor, ComputePipelineState, Device,
+    FunctionRef, MTLGPUFamily, URL,
+};
+
+/// The archive for the kernels in `source` on `device`, opened from disk
+/// when an earlier launch saved one.
+pub struct ShaderCache {
+    archive: BinaryArchive,
+    path: PathBuf,
+    /// Opened from disk, so it already holds every pipeline.
+    loaded: bool,
+}
+
+impl ShaderCache {
+    /// `None` without a cache directory, or when Metal cannot create an
+    /// archive at all.
+    pub fn open(device: &Device, source: &str) -> Option<Self> {
+        let path = archive_path(device, source)?;
+        let desc = BinaryArchiveDescriptor::new();
+        if path.exists() {
+            desc.set_url(&file_url(&path));
+            // A truncated or foreign file does not open; start over.
+            if let Ok(archive) = device.new_binary_archive_with_descriptor(&desc) {
+                return Some(Self { archive, path, loaded: true });
+            }
+        }
+        let archive = device.new_binary_archive_with_descriptor(&BinaryArchiveDescriptor::new()).ok()?;
+        Some(Self { archive, path, loaded: false })
+    }
+
+    /// Whether the archive came from disk.
+    pub fn loaded(&self) -> bool {
+        self.loaded
+    }
+
+    /// The pipeline for `func`, from the archive when it has it. A fresh
+    /// archive records the compiled binary for `save`.
+    pub fn pipeline(&self, device: &Device, func: &FunctionRef) -> Result<ComputePipelineState, String> {
+        let desc = ComputePipelineDescriptor::new();
+        desc.set_compute_function(Some(func));
+        desc.set_binary_archives(&[&self.archive]);
+        if !self.loaded {
+            // A function the archive refuses still compiles below, just
+            // not from disk next time.
+            let _ = self.archive.add_compute_pipeline_functions_with_descriptor(&desc);
+        }
+        device.new_compute_pipeline_state(&desc)
+    }
+
+    /// Write a fresh archive to disk; an archive that came from disk is
+    /// left alone. Goes through a temporary file so a crash never leaves a
+    /// half-written archive for the next launch.
+    pub fn save(&self) -> Result<()> {
+        if self.loaded {
+            return Ok(());
+        }
+        let dir = self.path.parent().context("shader cache path has no directory")?;
+        std::fs::create_dir_all(dir).with_context(|| format!("create {}", dir.display()))?;
+        let tmp = self.path.with_extension(format!("tmp{}", std::process::id()));
+        self.archive
+            .serialize_to_url(&file_url(&tmp))
+            .map_err(|e| anyhow::anyhow!("serialize shader archive: {e}"))?;
+        std::fs::rename(&tmp, &self.path).with_context(|| format!("write {}", self.path.display()))
+    }
+}
+
+/// `<cache dir>/shaders/<family>-<gpu>-<hash>.metallib`.
+pub fn archive_path(device: &Device, source: &str) -> Option<PathBuf> {
+    let name = format!("{}-{:016x}.metallib", device_key(device), source_key(source, &os_build()));
+    Some(crate::repack::cache_dir()?.join("shaders").join(name))
+}
+
+/// `<family>-<gpu>`, e.g. `apple8-apple-m2-pro`: what per-GPU caches are
+/// named after.
+pub fn device_key(device: &Device) -> String {
+    format!("{}-{}", gpu_family(device), slug(device.name()))
+}
+
+/// FNV-1a over the kernel source and the macOS build it compiles on.
+pub fn source_key(source: &str, os_build: &str) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for &b in source.as_bytes().iter().chain(&[0]).chain(os_build.as_bytes()) {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h
+}
+
+/// `Apple M2 Pro` as `apple-m2-pro`.
+pub fn slug(name: &str) -> String {
+    let words: Vec<String> = name
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_ascii_lowercase())
+        .collect();
+    words.join("-")
+}
+
+/// The newest Apple GPU family `device` belongs to (`apple9`), or
+/// `mac2` for the GPUs of Intel Macs.
+fn gpu_family(device: &Device) -> &'static str {
+    use MTLGPUFamily::*;
+    let families = [
+        (Apple9, "apple9"),
+        (Apple8, "apple8"),
+        (Apple7, "apple7"),
+        (Apple6, "apple6"),
+        (Mac2, "mac2"),
+    ];
+    families.iter().find(|(f, _)| device.supports_family(*f)).map_or("gpu", |(_, name)| name)
+}
+
+/// The macOS build (`23F79`), or empty when it cannot be read.
+fn os_build() -> String {
+    let plist = std::fs::read_to_string("/System/Library/CoreServices/SystemVersion.plist").unwrap_or_default();
+    plist
+        .split("<key>ProductBuildVersion</key>")
+        .nth(1)
+        .and_then(|rest| rest.split("<string>").nth(1))
+        .and_then(|rest| rest.split("</string>").next())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// `URLWithString` hands back an autoreleased object, which `URL` would
+/// release a second time on drop; `ManuallyDrop` leaves it to the pool.
+fn file_url(path: &Path) -> ManuallyDrop<URL> {
+    let mut url = String::from("file://");
+    for &b in path.to_string_lossy().as_bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'_' | b'.' | b'~' => url.push(b as char),
+            b => url.push_str(&format!("%{b:02X}")),
+        }
+    }
+    ManuallyDrop::new(URL::new_with_string(&url))
+}
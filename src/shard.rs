@@ -0,0 +1,203 @@
+//! Tensor parallelism: matmul weights split across several Metal devices
+//! (`run --devices 0,1`), for models too big for one GPU's memory.
+//!
+//! The split follows Megatron-LM. Q/K/V, gate/up and the output head are
+//! column-parallel: each device holds a run of output rows, and the slices
+//! of the result are concatenated. `attn_output` and `ffn_down` are
+//! row-parallel: each device holds a run of input columns (whole quant
+//! blocks) of every row, multiplies its slice of the input, and the partial
+//! results are summed. Every device works at once, one thread each.
+//!
+//! Only the matmuls are split. Norms, attention and activations stay on the
+//! model's own GPU, and activations cross to the others through host memory
+//! around every matmul — cheap next to the weights on a unified-memory Mac,
+//! a PCIe round trip on a Mac Pro's discrete GPUs.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use anyhow::{Context, Result, ensure};
+use metal::Buffer;
+
+use crate::gpu::{self, Gpu};
+use crate::profile::weight_scope;
+use crate::tensor::{TensorStore, ggml_block_layout, ggml_type_name};
+
+/// Which dimension of a weight is divided between devices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Split {
+    /// Output rows (column-parallel); results are concatenated.
+    Rows,
+    /// Input columns (row-parallel); partial results are summed.
+    Cols,
+}
+
+impl Split {
+    /// How the weight called `name` is divided.
+    pub fn of(name: &str) -> Self {
+        match weight_scope(name).1 {
+            "attn_output" | "ffn_down" => Self::Cols,
+            _ => Self::Rows,
+        }
+    }
+}
+
+/// One device's part of a weight.
+struct Shard {
+    device: usize,
+    /// Rows or columns of the full weight, depending on the split.
+    range: Range<usize>,
+    buf: Buffer,
+}
+
+struct ShardedWeight {
+    kind: u32,
+    split: Split,
+    shards: Vec<Shard>,
+}
+
+/// The devices of `--devices` and the weights split across them.
+pub struct TensorParallel {
+    gpus: Vec<Gpu>,
+    weights: HashMap<String, ShardedWeight>,
+}
+
+impl TensorParallel {
+    /// The devices at `devices`, indices into `sysinfo`'s list.
+    pub fn new(devices: &[usize]) -> Result<Self> {
+        ensure!(!devices.is_empty(), "no devices to split the model across");
+        Ok(Self { gpus: gpu::open_devices(devices)?, weights: HashMap::new() })
+    }
+
+    pub fn devices(&self) -> usize {
+        self.gpus.len()
+    }
+
+    /// Split `name` across every device and upload the parts.
+    pub fn shard(&mut self, store: &TensorStore, name: &str) -> Result<()> {
+        let meta = store.meta(name)?;
+        let (rows, cols, kind) = (meta.rows(), meta.cols(), meta.kind);
+        let (block, _) = ggml_block_layout(kind).with_context(|| format!("unknown ggml type {kind} for '{name}'"))?;
+        let bytes = store.get(name)?;
+        let split = Split::of(name);
+        let ranges = match split {
+            Split::Rows => split_ranges(rows, self.gpus.len(), 1),
+            Split::Cols => split_ranges(cols, self.gpus.len(), block as usize),
+        };
+        let mut shards = Vec::new();
+        for (device, range) in ranges.into_iter().enumerate().filter(|(_, r)| !r.is_empty()) {
+            let part = match split {
+                Split::Rows => slice_rows(bytes, kind, cols, range.clone()),
+                Split::Cols => slice_cols(bytes, kind, rows, cols, range.clone()),
+            };
+            let buf = self.gpus[device]
+                .upload_weight(&part)
+                .with_context(|| format!("shard {range:?} of '{name}' on device {device}"))?;
+            shards.push(Shard { device, range, buf });
+        }
+        self.weights.insert(name.to_string(), ShardedWeight { kind, split, shards });
+        Ok(())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.weights.contains_key(name)
+    }
+
+    /// Bytes of weights held on `device`.
+    pub fn bytes_on(&self, device: usize) -> u64 {
+        self.weights.values().flat_map(|w| &w.shards).filter(|s| s.device == device).map(|s| s.buf.length()).sum()
+    }
+
+    /// `x` (`[batch, k]`) times the sharded weight `name` (`[n, k]`): the
+    /// `[batch, n]` result a single-device matmul would give.
+    pub fn matmul(&self, name: &str, x: &[f32], n: usize, k: usize, batch: usize) -> Result<Vec<f32>> {
+        let w = self.weights.get(name).with_context(|| format!("'{name}' is not sharded"))?;
+        let parts = std::thread::scope(|s| {
+            let handles: Vec<_> = w
+                .shards
+                .iter()
+                .map(|shard| {
+                    let gpu = &self.gpus[shard.device];
+                    s.spawn(move || -> Result<Vec<f32>> {
+                        let r = shard.range.clone();
+                        let (x, n, k) = match w.split {
+                            Split::Rows => (gpu.buf_from_f32(x), r.len(), k),
+                            Split::Cols => (gpu.buf_from_f32(&slice_inputs(x, k, r.clone())), n, r.len()),
+                        };
+                        let out = gpu.matmul(w.kind, &shard.buf, 0, &x, n, k, batch)?;
+                        Ok(gpu.read_f32(&out, n * batch).to_vec())
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().map_err(|_| anyhow::anyhow!("shard matmul thread panicked"))?).collect::<Result<Vec<_>>>()
+        })
+        .with_context(|| format!("sharded matmul '{name}' ({})", ggml_type_name(w.kind)))?;
+        let ranges: Vec<_> = w.shards.iter().map(|s| s.range.clone()).collect();
+        Ok(match w.split {
+            Split::Rows => concat_outputs(&parts, &ranges, n, batch),
+            Split::Cols => sum_partials(&parts, n * batch),
+        })
+    }
+}
+
+/// `0..len` cut into `parts` runs as even as whole multiples of `align`
+/// allow; trailing runs are empty when there are fewer units than parts.
+pub fn split_ranges(len: usize, parts: usize, align: usize) -> Vec<Range<usize>> {
+    let units = len.div_ceil(align);
+    let parts = parts.max(1);
+    let mut start = 0;
+    (0..parts)
+        .map(|i| {
+            let take = units / parts + usize::from(i < units % parts);
+            let end = (start + take * align).min(len);
+            let r = start..end;
+            start = end;
+            r
+        })
+        .collect()
+}
+
+/// Rows `rows` of a `kind` weight with `cols` columns, as uploaded bytes.
+pub fn slice_rows(bytes: &[u8], kind: u32, cols: usize, rows: Range<usize>) -> Vec<u8> {
+    let row_bytes = row_bytes(kind, cols);
+    bytes[rows.start * row_bytes..rows.end * row_bytes].to_vec()
+}
+
+/// Columns `cols` (whole blocks) of every row of a `kind` weight, packed
+/// into a weight of `cols.len()` columns.
+pub fn slice_cols(bytes: &[u8], kind: u32, rows: usize, n_cols: usize, cols: Range<usize>) -> Vec<u8> {
+    let (block, block_bytes) = ggml_block_layout(kind).map_or((1, 1), |(e, b)| (e as usize, b as usize));
+    let row_bytes = row_bytes(kind, n_cols);
+    let (b0, b1) = (cols.start / block * block_bytes, cols.end.div_ceil(block) * block_bytes);
+    bytes.chunks_exact(row_bytes).take(rows).flat_map(|row| &row[b0..b1]).copied().collect()
+}
+
+fn row_bytes(kind: u32, cols: usize) -> usize {
+    let (block, block_bytes) = ggml_block_layout(kind).map_or((1, 1), |(e, b)| (e as usize, b as usize));
+    cols.div_ceil(block) * block_bytes
+}
+
+/// Columns `cols` of each `k`-wide row of `x`.
+fn slice_inputs(x: &[f32], k: usize, cols: Range<usize>) -> Vec<f32> {
+    x.chunks_exact(k).flat_map(|row| &row[cols.clone()]).copied().collect()
+}
+
+/// Per-device `[batch, rows]` results placed side by side into `[batch, n]`.
+pub fn concat_outputs(parts: &[Vec<f32>], ranges: &[Range<usize>], n: usize, batch: usize) -> Vec<f32> {
+    let mut out = vec![0.0f32; batch * n];
+    for (part, r) in parts.iter().zip(ranges) {
+        for (t, row) in part.chunks_exact(r.len()).enumerate().take(batch) {
+            out[t * n + r.start..t * n + r.end].copy_from_slice(row);
+        }
+    }
+    out
+}
+
+/// The elementwise sum of per-device partial results.
+pub fn sum_partials(parts: &[Vec<f32>], len: usize) -> Vec<f32> {
+    let mut out = vec![0.0f32; len];
+    for part in parts {
+        out.iter_mut().zip(part).for_each(|(o, p)| *o += p);
+    }
+    out
+}
@@ -0,0 +1,128 @@
+//! Where generated text goes besides the terminal: `OutputSink`, and
+//! `CommandSink`, which speaks through a TTS command (`run --tts-command`).
+//!
+//! A speech engine wants whole sentences, not tokens: a word split across
+//! two tokens, or a sentence cut mid-way, comes out wrong. `Sentences`
+//! collects the streamed pieces and hands on each sentence once it ends
+//! (at `.`, `!`, `?` or `…` before whitespace, or at a newline), plus
+//! whatever is left when generation stops. A sink for a TTS model in GGUF
+//! form would implement the same trait.
+
+use std::io::Write;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use anyhow::{Context, Result, bail};
+
+/// Receives the reply as it streams. Only the answer reaches a sink, not
+/// hidden reasoning or special tokens.
+pub trait OutputSink {
+    fn text(&mut self, piece: &str) -> Result<()>;
+    /// Generation is over: flush anything held back and wait if needed.
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// Streamed text cut into sentences.
+#[derive(Clone, Debug, Default)]
+pub struct Sentences {
+    pending: String,
+}
+
+impl Sentences {
+    /// Add `piece`; returns every sentence it completed, trimmed.
+    pub fn push(&mut self, piece: &str) -> Vec<String> {
+        self.pending.push_str(piece);
+        let mut done = Vec::new();
+        while let Some(end) = sentence_end(&self.pending) {
+            let rest = self.pending.split_off(end);
+            let sentence = std::mem::replace(&mut self.pending, rest);
+            let sentence = sentence.trim();
+            if !sentence.is_empty() {
+                done.push(sentence.to_string());
+            }
+        }
+        done
+    }
+
+    /// Whatever has not ended yet, if there is any.
+    pub fn flush(&mut self) -> Option<String> {
+        let rest = std::mem::take(&mut self.pending);
+        let rest = rest.trim();
+        (!rest.is_empty()).then(|| rest.to_string())
+    }
+}
+
+/// Byte offset just past the first complete sentence of `text`. The
+/// terminator only counts once the whitespace after it has arrived, so
+/// `3.` followed by `14` stays one number.
+fn sentence_end(text: &str) -> Option<usize> {
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '\n' {
+            return Some(i + 1);
+        }
+        if matches!(c, '.' | '!' | '?' | '…') {
+            // `?!`, `...` and closing quotes or brackets stay with the sentence.
+            while let Some(&(_, next)) = chars.peek() {
+                if !matches!(next, '.' | '!' | '?' | '…' | '"' | '\'' | ')' | '”' | '’') {
+                    break;
+                }
+                chars.next();
+            }
+            if let Some(&(j, next)) = chars.peek()
+                && next.is_whitespace()
+            {
+                return Some(j);
+            }
+        }
+    }
+    None
+}
+
+/// Streams sentences to a long-running command, one per line on its
+/// stdin, e.g. `piper --model voice.onnx --output-raw | aplay -r 22050 -f S16_LE`.
+/// The command runs under `sh -c`, so pipes and quoting work.
+pub struct CommandSink {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    sentences: Sentences,
+}
+
+impl CommandSink {
+    pub fn spawn(command: &str) -> Result<Self> {
+        let mut child = Command::new("sh")
+            .args(["-c", command])
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("starting '{command}'"))?;
+        let stdin = child.stdin.take();
+        Ok(Self { child, stdin, sentences: Sentences::default() })
+    }
+
+    fn send(&mut self, sentence: &str) -> Result<()> {
+        let stdin = self.stdin.as_mut().context("the speech command's input is closed")?;
+        writeln!(stdin, "{sentence}").and_then(|_| stdin.flush()).context("the speech command stopped reading")
+    }
+}
+
+impl OutputSink for CommandSink {
+    fn text(&mut self, piece: &str) -> Result<()> {
+        for sentence in self.sentences.push(piece) {
+            self.send(&sentence)?;
+        }
+        Ok(())
+    }
+
+    /// Sends the last sentence, closes the command's stdin and waits for it
+    /// to finish speaking.
+    fn finish(&mut self) -> Result<()> {
+        if let Some(rest) = self.sentences.flush() {
+            self.send(&rest)?;
+        }
+        drop(self.stdin.take());
+        let status = self.child.wait().context("waiting for the speech command")?;
+        if !status.success() {
+            bail!("the speech command exited with {status}");
+        }
+        Ok(())
+    }
+}
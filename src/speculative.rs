@@ -0,0 +1,175 @@
+//! Draft sources for speculative decoding.
+//!
+//! A draft is a guess at the next few tokens. The target model checks the
+//! whole guess in one batched forward pass and keeps the longest prefix it
+//! agrees with, so a good guess yields several tokens per pass and a bad
+//! one costs little more than a normal step. The output is identical to
+//! plain decoding either way; only the number of passes changes.
+
+use anyhow::{Result, ensure};
+
+use crate::tensor::{ggml_type_name, TensorStore, GGML_F16, GGML_F32, GGML_IQ4_NL, GGML_Q2_K, GGML_Q3_K, GGML_Q8_0};
+
+/// Where draft tokens come from.
+#[derive(Clone, Copy, Debug)]
+pub enum DraftSource {
+    Lookup(LookupConfig),
+    EarlyExit(EarlyExitConfig),
+    Medusa(MedusaConfig),
+}
+
+/// Prompt lookup: find the most recent earlier occurrence of the context's
+/// trailing n-gram and propose whatever followed it. Costs nothing to run and
+/// pays off whenever the output copies from the input (code edits, summaries,
+/// quoting).
+#[derive(Clone, Copy, Debug)]
+pub struct LookupConfig {
+    /// Longest n-gram to match; shorter ones are tried when it finds nothing.
+    pub ngram_max: usize,
+    /// Most tokens to propose per step.
+    pub n_draft: usize,
+}
+
+impl Default for LookupConfig {
+    fn default() -> Self {
+        Self { ngram_max: 3, n_draft: 8 }
+    }
+}
+
+/// Self-speculation: the first `layers` blocks of the target model, followed
+/// by the usual output norm and head, act as a cheap draft model. No second
+/// GGUF is needed, and the draft reuses the target's KV rows for those layers.
+#[derive(Clone, Copy, Debug)]
+pub struct EarlyExitConfig {
+    pub layers: usize,
+    pub n_draft: usize,
+}
+
+/// Medusa heads: small extra heads, trained on the frozen target, that read
+/// the hidden state behind the current logits and each guess one token
+/// further ahead. One step drafts from every head at once; there is no draft
+/// loop and no draft KV cache. The heads come from a companion GGUF loaded
+/// with `LlamaModel::load_medusa`.
+#[derive(Clone, Copy, Debug)]
+pub struct MedusaConfig {
+    /// Most tokens to propose per step; capped by the number of heads.
+    pub n_draft: usize,
+}
+
+/// How many tokens to draft next, learned from how many of the last draft
+/// the target kept: all of them raises the length by two, anything less
+/// sets it to one fewer than was drafted (never below one). The configured
+/// `n_draft` stays the ceiling. Prose that stops matching the prompt, or a
+/// draft model that drifts, soon drafts short, cheap guesses, and a run of
+/// full acceptances grows them back.
+#[derive(Clone, Copy, Debug)]
+pub struct DraftLength {
+    current: usize,
+    max: usize,
+    adaptive: bool,
+}
+
+impl DraftLength {
+    /// Starts at `max`; a fixed length stays there.
+    pub fn new(max: usize, adaptive: bool) -> Self {
+        Self { current: max, max, adaptive }
+    }
+
+    pub fn get(&self) -> usize {
+        self.current
+    }
+
+    /// The target kept `accepted` of `drafted` tokens.
+    pub fn observe(&mut self, drafted: usize, accepted: usize) {
+        if !self.adaptive || drafted == 0 {
+            return;
+        }
+        // From what was actually drafted, which the budget or the number of
+        // Medusa heads may have cut below `current`.
+        self.current = if accepted >= drafted { drafted.saturating_add(2) } else { drafted - 1 };
+        self.current = self.current.clamp(1, self.max.max(1));
+    }
+}
+
+/// One Medusa head: a residual block `h + SiLU(fc · h + bias)` and a
+/// projection to the vocabulary, its own or the target's output head.
+#[derive(Clone, Debug)]
+pub struct MedusaHead {
+    pub fc: String,
+    pub fc_kind: u32,
+    pub bias: Vec<f32>,
+    /// `medusa.{k}.output.weight` and its dtype; `None` shares the target's head.
+    pub output: Option<(String, u32)>,
+}
+
+/// The heads of a companion GGUF, in draft order. Head `k` is the tensors
+///
+/// - `medusa.{k}.fc.weight`     `[hidden, hidden]`
+/// - `medusa.{k}.fc.bias`       `[hidden]`, F32
+/// - `medusa.{k}.output.weight` `[hidden, vocab]`, optional
+///
+/// for `k = 0, 1, ...` up to the first missing `fc.weight`.
+pub fn medusa_heads(store: &TensorStore, hidden: usize, vocab: usize) -> Result<Vec<MedusaHead>> {
+    let matmul_kind = |name: &str, rows: usize| -> Result<u32> {
+        let m = store.meta(name)?;
+        ensure!(
+            m.cols() == hidden && m.rows() == rows,
+            "{name} is {}x{}, the model needs {rows}x{hidden}",
+            m.rows(),
+            m.cols()
+        );
+        ensure!(
+            matches!(m.kind, GGML_Q8_0 | GGML_F16 | GGML_Q2_K | GGML_Q3_K | GGML_IQ4_NL),
+            "{name} is {}; Medusa weights must be F16, Q8_0, Q2_K, Q3_K or IQ4_NL",
+            ggml_type_name(m.kind)
+        );
+        Ok(m.kind)
+    };
+    let mut heads = Vec::new();
+    for k in 0.. {
+        let fc = format!("medusa.{k}.fc.weight");
+        if !store.index.contains_key(&fc) {
+            break;
+        }
+        let bias_name = format!("medusa.{k}.fc.bias");
+        let b = store.meta(&bias_name)?;
+        ensure!(b.kind == GGML_F32 && b.cols() == hidden, "{bias_name} must be {hidden} F32 values");
+        let bias = store.get(&bias_name)?.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect();
+        let output = format!("medusa.{k}.output.weight");
+        let output = if store.index.contains_key(&output) {
+            Some((output.clone(), matmul_kind(&output, vocab)?))
+        } else {
+            None
+        };
+        heads.push(MedusaHead { fc_kind: matmul_kind(&fc, hidden)?, fc, bias, output });
+    }
+    ensure!(!heads.is_empty(), "no Medusa heads (medusa.0.fc.weight is missing)");
+    Ok(heads)
+}
+
+/// One head's residual block, given `y = fc · h` without the bias.
+pub fn medusa_residual(h: &[f32], y: &[f32], bias: &[f32]) -> Vec<f32> {
+    h.iter().zip(y).zip(bias).map(|((h, y), b)| {
+        let z = y + b;
+        h + z / (1.0 + (-z).exp())
+    }).collect()
+}
+
+/// Up to `n_draft` tokens that followed the latest earlier match of the
+/// context's last `n` tokens, trying `n = ngram_max` down to 1.
+/// Empty when nothing matches.
+pub fn ngram_draft(context: &[u32], ngram_max: usize, n_draft: usize) -> Vec<u32> {
+    let len = context.len();
+    for n in (1..=ngram_max.min(len.saturating_sub(1))).rev() {
+        let tail = &context[len - n..];
+        // Latest match first: recent text is the better predictor.
+        for start in (0..len - n).rev() {
+            if &context[start..start + n] == tail {
+                let from = start + n;
+                let to = (from + n_draft).min(len);
+                return context[from..to].to_vec();
+            }
+        }
+    }
+    Vec::new()
+}
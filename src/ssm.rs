@@ -0,0 +1,462 @@
+//! State-space models: Mamba and hybrid (Jamba-style) GGUFs.
+//!
+//! A Mamba block has no attention and so no KV cache. Its mixer projects
+//! the normed input to a branch `x` and a gate `z`, runs `x` through a
+//! short causal convolution (`d_conv` taps per channel) and SiLU, and then
+//! through the selective scan: every channel keeps a `d_state` vector `h`
+//! that each token decays by `exp(dt * A)` and adds `dt * B * x` to, and
+//! reads out as `C · h + D * x`. `dt`, `B` and `C` are projections of the
+//! token itself, which is what makes the scan selective. What a sequence
+//! has seen is therefore a fixed-size state per layer, the last `d_conv - 1`
+//! conv inputs and the scan's `h`, however long it gets.
+//!
+//! Hybrid models interleave such blocks with attention layers; here any
+//! layer without `ssm_in` is one, with its own K/V rows in the state, and a
+//! layer may add a SwiGLU FFN. Mixture-of-experts FFNs are refused at load.
+//!
+//! A prompt reuses a state only when it extends the state's tokens (see
+//! `recurrent`). `SsmState::save` and `load` keep one across processes
+//! (`run --ssm-state`).
+//!
+//! Matmuls go through `recurrent::Matmuls`; the conv and the scan run on
+//! the CPU, token by token.
+
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail, ensure};
+use serde_json::{Value, json};
+
+use crate::events::GenerationEvent;
+use crate::model::{GenerateOptions, Generator, KvSession, attention, rms_norm};
+use crate::recurrent::{self, Matmuls};
+use crate::rpc::{f32_bytes, f32_from_bytes, read_payload, write_payload};
+use crate::weights::{Mixer, SsmMixer, SsmWeights, WeightRef};
+
+/// `general.architecture` values this module runs.
+pub const SSM_ARCHS: [&str; 2] = ["mamba", "jamba"];
+
+/// `format` of a saved state's header.
+pub const STATE_FORMAT: &str = "llmetal-ssm-state";
+
+pub fn is_ssm(architecture: &str) -> bool {
+    SSM_ARCHS.contains(&architecture)
+}
+
+#[derive(Clone, Debug)]
+pub struct SsmArch {
+    pub hidden: usize,
+    pub n_layers: usize,
+    pub vocab_size: usize,
+    pub ctx_train: usize,
+    /// `{arch}.ssm.inner_size`: channels of the scanned branch.
+    pub d_inner: usize,
+    /// `{arch}.ssm.state_size`: the length of each channel's `h`.
+    pub d_state: usize,
+    /// `{arch}.ssm.conv_kernel`: taps of the causal convolution.
+    pub d_conv: usize,
+    /// `{arch}.ssm.time_step_rank`: width of the `dt` bottleneck.
+    pub dt_rank: usize,
+    pub rms_eps: f32,
+    /// Attention layers only; zero in a pure Mamba model.
+    pub n_heads: usize,
+    pub n_kv_heads: usize,
+    pub ffn_hidden: usize,
+}
+
+impl SsmArch {
+    pub fn head_dim(&self) -> usize {
+        self.hidden / self.n_heads.max(1)
+    }
+
+    pub fn kv_dim(&self) -> usize {
+        self.n_kv_heads * self.head_dim()
+    }
+}
+
+/// What a sequence has seen, per layer.
+#[derive(Clone, Debug, PartialEq)]
+enum LayerState {
+    Ssm {
+        /// The last `d_conv - 1` conv inputs, `[d_conv - 1, d_inner]`, oldest first.
+        conv: Vec<f32>,
+        /// `h`, `[d_inner, d_state]`.
+        scan: Vec<f32>,
+    },
+    Attention {
+        k: Vec<Arc<[f32]>>,
+        v: Vec<Arc<[f32]>>,
+    },
+}
+
+/// The recurrent state of one sequence, with the tokens it was computed
+/// for. `Default` is empty and takes its shape from the first model it is
+/// used with.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SsmState {
+    tokens: Vec<u32>,
+    layers: Vec<LayerState>,
+}
+
+impl SsmState {
+    /// The state before any token: zero convolution window, zero `h`.
+    pub fn new(arch: &SsmArch, weights: &SsmWeights) -> Self {
+        let layers = weights
+            .layers
+            .iter()
+            .map(|l| match l.mixer {
+                Mixer::Ssm(_) => LayerState::Ssm {
+                    conv: vec![0.0; (arch.d_conv - 1) * arch.d_inner],
+                    scan: vec![0.0; arch.d_inner * arch.d_state],
+                },
+                Mixer::Attention(_) => LayerState::Attention { k: Vec::new(), v: Vec::new() },
+            })
+            .collect();
+        Self { tokens: Vec::new(), layers }
+    }
+
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /// Floats held: fixed for Mamba layers, growing with the sequence for
+    /// attention layers.
+    pub fn len_f32(&self) -> usize {
+        self.layers.iter().map(|l| match l {
+            LayerState::Ssm { conv, scan } => conv.len() + scan.len(),
+            LayerState::Attention { k, v } => k.iter().chain(v).map(|r| r.len()).sum(),
+        }).sum()
+    }
+
+    /// Whether `self` has the layers and sizes of `fresh`, a `new` state
+    /// of the model it is about to be used with.
+    fn fits(&self, fresh: &SsmState) -> bool {
+        self.layers.len() == fresh.layers.len()
+            && self.layers.iter().zip(&fresh.layers).all(|(a, b)| match (a, b) {
+                (LayerState::Ssm { conv, scan }, LayerState::Ssm { conv: c, scan: s }) => {
+                    conv.len() == c.len() && scan.len() == s.len()
+                }
+                (LayerState::Attention { .. }, LayerState::Attention { .. }) => true,
+                _ => false,
+            })
+    }
+
+    /// One frame: a JSON header, `{"format", "version", "tokens", "layers":
+    /// [{"ssm": [conv, scan]} | {"attention": [positions, kv_dim]}],
+    /// "bytes"}`, then every float little-endian in layer order.
+    pub fn write(&self, w: &mut dyn Write) -> Result<()> {
+        let mut floats = Vec::with_capacity(self.len_f32());
+        let layers: Vec<Value> = self
+            .layers
+            .iter()
+            .map(|l| match l {
+                LayerState::Ssm { conv, scan } => {
+                    floats.extend_from_slice(conv);
+                    floats.extend_from_slice(scan);
+                    json!({ "ssm": [conv.len(), scan.len()] })
+                }
+                LayerState::Attention { k, v } => {
+                    k.iter().chain(v).for_each(|r| floats.extend_from_slice(r));
+                    json!({ "attention": [k.len(), k.first().map_or(0, |r| r.len())] })
+                }
+            })
+            .collect();
+        let payload = f32_bytes(&floats);
+        let header = json!({
+            "format": STATE_FORMAT, "version": 1, "tokens": self.tokens,
+            "layers": layers, "bytes": payload.len(),
+        });
+        write_payload(w, &header, &payload)
+    }
+
+    pub fn read(r: &mut dyn Read) -> Result<Self> {
+        let header = crate::daemon::read_frame(r)?.context("empty state file")?;
+        ensure!(header["format"] == STATE_FORMAT, "not an SSM state (format {})", header["format"]);
+        ensure!(header["version"] == 1, "unsupported SSM state version {}", header["version"]);
+        let floats = f32_from_bytes(&read_payload(r, &header, crate::rpc::MAX_PAYLOAD)?);
+        let tokens = header["tokens"].as_array().context("state has no tokens")?;
+        let tokens = tokens.iter().map(|t| t.as_u64().map(|t| t as u32).context("bad token id")).collect::<Result<_>>()?;
+        let mut rest = floats.as_slice();
+        let mut take = |n: usize| -> Result<Vec<f32>> {
+            ensure!(n <= rest.len(), "state payload is shorter than its header says");
+            let (head, tail) = rest.split_at(n);
+            rest = tail;
+            Ok(head.to_vec())
+        };
+        let mut layers = Vec::new();
+        for l in header["layers"].as_array().context("state has no layers")? {
+            let pair = |key: &str| -> Option<(usize, usize)> {
+                let v = l[key].as_array()?;
+                Some((v.first()?.as_u64()? as usize, v.get(1)?.as_u64()? as usize))
+            };
+            layers.push(match (pair("ssm"), pair("attention")) {
+                (Some((conv, scan)), _) => LayerState::Ssm { conv: take(conv)?, scan: take(scan)? },
+                (_, Some((positions, dim))) => {
+                    let mut rows = || -> Result<Vec<Arc<[f32]>>> { (0..positions).map(|_| take(dim).map(Into::into)).collect() };
+                    let k = rows()?;
+                    LayerState::Attention { k, v: rows()? }
+                }
+                _ => bail!("bad state layer {l}"),
+            });
+        }
+        ensure!(rest.is_empty(), "state payload is longer than its header says");
+        Ok(Self { tokens, layers })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path).with_context(|| format!("create {}", path.display()))?);
+        self.write(&mut file).with_context(|| format!("write {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut file = std::io::BufReader::new(std::fs::File::open(path).with_context(|| format!("open {}", path.display()))?);
+        Self::read(&mut file).with_context(|| format!("read {}", path.display()))
+    }
+}
+
+pub struct SsmModel {
+    pub arch: SsmArch,
+    pub weights: Arc<SsmWeights>,
+    /// What `Generator::generate_cached` continues from; see there.
+    pub session: SsmState,
+    mm: Matmuls,
+    load_ms: u128,
+}
+
+impl SsmModel {
+    pub fn load(path: &str) -> Result<Self> {
+        let t0 = std::time::Instant::now();
+        let mm = Matmuls::open(path)?;
+        let (meta, index) = (&mm.store.metadata, &mm.store.index);
+        let prefix = meta.get("general.architecture").and_then(|v| v.as_str()).unwrap_or("mamba").to_string();
+        ensure!(is_ssm(&prefix), "'{prefix}' is not a state-space architecture");
+        let get_u = |key: &str| meta.get(&format!("{prefix}.{key}")).and_then(|v| v.as_u64()).map(|v| v as usize);
+        let get_f = |key: &str| meta.get(&format!("{prefix}.{key}")).and_then(|v| v.as_f64()).map(|v| v as f32);
+
+        let hidden = get_u("embedding_length").context("missing embedding_length")?;
+        let n_heads = get_u("attention.head_count").unwrap_or(0);
+        let mut arch = SsmArch {
+            hidden,
+            n_layers: get_u("block_count").context("missing block_count")?,
+            vocab_size: index.get("token_embd.weight").map(|m| m.rows()).context("missing token_embd.weight")?,
+            ctx_train: get_u("context_length").unwrap_or(1 << 20),
+            d_inner: get_u("ssm.inner_size").unwrap_or(2 * hidden),
+            d_state: get_u("ssm.state_size").context("missing ssm.state_size")?,
+            d_conv: get_u("ssm.conv_kernel").context("missing ssm.conv_kernel")?,
+            dt_rank: get_u("ssm.time_step_rank").unwrap_or(hidden.div_ceil(16)),
+            rms_eps: get_f("attention.layer_norm_rms_epsilon").unwrap_or(1e-5),
+            n_heads,
+            n_kv_heads: 0,
+            ffn_hidden: get_u("feed_forward_length").unwrap_or(0),
+        };
+        ensure!(arch.d_conv >= 1, "ssm.conv_kernel must be at least 1");
+        // Hybrids write head_count_kv per layer (zero for Mamba layers); the
+        // first attention layer's K projection says it once.
+        arch.n_kv_heads = (0..arch.n_layers)
+            .find_map(|l| index.get(&format!("blk.{l}.attn_k.weight")))
+            .map_or(0, |m| m.rows() / arch.head_dim());
+        let weights = Arc::new(SsmWeights::from_index(index, &arch)?);
+        Ok(Self {
+            arch,
+            weights,
+            session: SsmState::default(),
+            mm,
+            load_ms: t0.elapsed().as_millis(),
+        })
+    }
+
+    pub fn declared_stop_tokens(&self) -> Vec<u32> {
+        self.mm.declared_stop_tokens()
+    }
+
+    /// Generate from `tokens`, continuing `state` when `tokens` extends what
+    /// it has seen and starting over otherwise; `state` ends holding every
+    /// token that went through the model. Guidance and drafts need a cache
+    /// that can be cut back, so they are refused. Returns the prompt tokens
+    /// reused.
+    pub fn generate(
+        &mut self,
+        state: &mut SsmState,
+        tokens: &[u32],
+        opts: &GenerateOptions,
+        vocab: &[String],
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<usize> {
+        ensure!(!tokens.is_empty(), "cannot generate from an empty prompt");
+        recurrent::check_options(opts, "state-space")?;
+        let fresh = SsmState::new(&self.arch, &self.weights);
+        if state.layers.is_empty() {
+            *state = fresh.clone();
+        }
+        ensure!(state.fits(&fresh), "the SSM state was computed by a model of another shape");
+        let reused = recurrent::reusable(&state.tokens, tokens);
+        if reused == 0 {
+            *state = fresh;
+        }
+        let load_ms = self.load_ms;
+        recurrent::decode(tokens, reused, opts, vocab, load_ms, on_event, &mut |t| self.forward(t, state))?;
+        Ok(reused)
+    }
+
+    /// Run `tokens` through every layer after what `state` has seen, and
+    /// return the logits following the last of them.
+    fn forward(&mut self, tokens: &[u32], state: &mut SsmState) -> Result<Vec<f32>> {
+        let (h, n) = (self.arch.hidden, tokens.len());
+        let weights = self.weights.clone();
+        let arch = self.arch.clone();
+        let mut x = self.mm.embed(&weights.token_embd, tokens)?;
+
+        for (w, layer) in weights.layers.iter().zip(&mut state.layers) {
+            let xn = self.rms_norm_rows(&w.norm, &x)?;
+            let out = match (&w.mixer, layer) {
+                (Mixer::Ssm(m), LayerState::Ssm { conv, scan }) => self.ssm_mixer(m, &xn, n, conv, scan)?,
+                (Mixer::Attention(a), LayerState::Attention { k, v }) => {
+                    let q_all = self.mm.linear(&a.q, None, &xn, n)?;
+                    let k_all = self.mm.linear(&a.k, None, &xn, n)?;
+                    let v_all = self.mm.linear(&a.v, None, &xn, n)?;
+                    let (q_dim, kv_dim) = (arch.n_heads * arch.head_dim(), arch.kv_dim());
+                    let mut attn = Vec::with_capacity(n * q_dim);
+                    for i in 0..n {
+                        k.push(k_all[i * kv_dim..][..kv_dim].into());
+                        v.push(v_all[i * kv_dim..][..kv_dim].into());
+                        let q = &q_all[i * q_dim..][..q_dim];
+                        attn.extend(attention(q, k, v, arch.n_heads, arch.n_kv_heads, arch.head_dim()));
+                    }
+                    self.mm.linear(&a.output, None, &attn, n)?
+                }
+                _ => bail!("the SSM state does not match the model's layers"),
+            };
+            x.iter_mut().zip(&out).for_each(|(a, b)| *a += b);
+
+            if let Some(ffn) = &w.ffn {
+                let xn = self.rms_norm_rows(&ffn.norm, &x)?;
+                let gate = self.mm.linear(&ffn.gate, None, &xn, n)?;
+                let up = self.mm.linear(&ffn.up, None, &xn, n)?;
+                let mid: Vec<f32> = gate.iter().zip(&up).map(|(g, u)| silu(*g) * u).collect();
+                let down = self.mm.linear(&ffn.down, None, &mid, n)?;
+                x.iter_mut().zip(&down).for_each(|(a, b)| *a += b);
+            }
+        }
+        state.tokens.extend_from_slice(tokens);
+
+        let last = self.rms_norm_rows(&weights.output_norm, &x[(n - 1) * h..])?;
+        self.mm.linear(&weights.output, None, &last, 1)
+    }
+
+    /// One Mamba mixer over `n` packed normed rows, advancing `conv` and `scan`.
+    fn ssm_mixer(&mut self, m: &SsmMixer, xn: &[f32], n: usize, conv: &mut [f32], scan: &mut [f32]) -> Result<Vec<f32>> {
+        let (inner, n_state, dt_rank) = (self.arch.d_inner, self.arch.d_state, self.arch.dt_rank);
+        let xz = self.mm.linear(&m.in_proj, None, xn, n)?;
+        let conv_w = self.mm.cpu_weights(&m.conv1d.weight.name)?;
+        let conv_b = m.conv1d.bias.as_ref().map(|b| self.mm.cpu_weights(&b.name)).transpose()?;
+        let mut xc = Vec::with_capacity(n * inner);
+        for row in xz.chunks_exact(2 * inner) {
+            xc.extend(conv_step(conv, &row[..inner], &conv_w, conv_b.as_deref()).into_iter().map(silu));
+        }
+
+        let dbc = self.mm.linear(&m.x_proj, None, &xc, n)?;
+        let width = dt_rank + 2 * n_state;
+        let norm = |model: &mut Self, w: &Option<WeightRef>, v: &[f32]| -> Result<Vec<f32>> {
+            match w {
+                Some(w) => Ok(rms_norm(v, &model.mm.cpu_weights(&w.name)?, model.arch.rms_eps)),
+                None => Ok(v.to_vec()),
+            }
+        };
+        let mut dt_in = Vec::with_capacity(n * dt_rank);
+        let (mut bs, mut cs) = (Vec::with_capacity(n * n_state), Vec::with_capacity(n * n_state));
+        for row in dbc.chunks_exact(width) {
+            dt_in.extend(norm(self, &m.dt_norm, &row[..dt_rank])?);
+            bs.extend(norm(self, &m.b_norm, &row[dt_rank..][..n_state])?);
+            cs.extend(norm(self, &m.c_norm, &row[dt_rank + n_state..])?);
+        }
+        let dt: Vec<f32> = self.mm.linear(&m.dt_proj.weight, m.dt_proj.bias.as_ref(), &dt_in, n)?.into_iter().map(softplus).collect();
+
+        let (a, d) = (self.mm.cpu_weights(&m.a.name)?, self.mm.cpu_weights(&m.d.name)?);
+        let mut y = Vec::with_capacity(n * inner);
+        for t in 0..n {
+            let z = &xz[t * 2 * inner + inner..][..inner];
+            let b = &bs[t * n_state..][..n_state];
+            let c = &cs[t * n_state..][..n_state];
+            let yt = scan_step(scan, &xc[t * inner..][..inner], &dt[t * inner..][..inner], &a, b, c, &d);
+            y.extend(yt.iter().zip(z).map(|(y, z)| y * silu(*z)));
+        }
+        self.mm.linear(&m.out_proj, None, &y, n)
+    }
+
+    fn rms_norm_rows(&mut self, w: &WeightRef, x: &[f32]) -> Result<Vec<f32>> {
+        let w = self.mm.cpu_weights(&w.name)?;
+        Ok(x.chunks_exact(w.len()).flat_map(|r| rms_norm(r, &w, self.arch.rms_eps)).collect())
+    }
+}
+
+impl Generator for SsmModel {
+    /// A recurrent state is not K/V rows: the model continues its own
+    /// `session` and leaves `_session` empty.
+    fn generate_cached(
+        &mut self,
+        _session: &mut KvSession,
+        tokens: &[u32],
+        opts: &GenerateOptions,
+        vocab: &[String],
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<usize> {
+        let mut state = std::mem::take(&mut self.session);
+        let reused = self.generate(&mut state, tokens, opts, vocab, on_event);
+        self.session = state;
+        reused
+    }
+}
+
+// ---------------------------------------------------------------------------
+// CPU math
+// ---------------------------------------------------------------------------
+
+/// One token of the causal depthwise convolution. `window` holds the last
+/// `d_conv - 1` inputs, `[d_conv - 1, channels]` oldest first, and moves on
+/// by `x`; `w` is `[channels, d_conv]`, oldest tap first.
+pub(crate) fn conv_step(window: &mut [f32], x: &[f32], w: &[f32], bias: Option<&[f32]>) -> Vec<f32> {
+    let channels = x.len();
+    let d_conv = w.len() / channels.max(1);
+    let mut out = bias.map_or_else(|| vec![0.0; channels], <[f32]>::to_vec);
+    for (c, o) in out.iter_mut().enumerate() {
+        let taps = &w[c * d_conv..][..d_conv];
+        for (j, tap) in taps[..d_conv - 1].iter().enumerate() {
+            *o += tap * window[j * channels + c];
+        }
+        *o += taps[d_conv - 1] * x[c];
+    }
+    if d_conv > 1 {
+        window.copy_within(channels.., 0);
+        let last = window.len() - channels;
+        window[last..].copy_from_slice(x);
+    }
+    out
+}
+
+/// One token of the selective scan for every channel `c`:
+/// `h[c] = exp(dt[c] * a[c]) * h[c] + dt[c] * b * x[c]`, read out as
+/// `c · h[c] + d[c] * x[c]`. `h` and `a` are `[channels, d_state]`.
+pub(crate) fn scan_step(h: &mut [f32], x: &[f32], dt: &[f32], a: &[f32], b: &[f32], c: &[f32], d: &[f32]) -> Vec<f32> {
+    let n_state = b.len();
+    let mut y = Vec::with_capacity(x.len());
+    for ch in 0..x.len() {
+        let (h, a) = (&mut h[ch * n_state..][..n_state], &a[ch * n_state..][..n_state]);
+        let mut acc = 0.0;
+        for s in 0..n_state {
+            h[s] = (dt[ch] * a[s]).exp() * h[s] + dt[ch] * b[s] * x[ch];
+            acc += h[s] * c[s];
+        }
+        y.push(acc + d[ch] * x[ch]);
+    }
+    y
+}
+
+/// `ln(1 + e^x)`, exact past where `e^x` would overflow.
+pub(crate) fn softplus(x: f32) -> f32 {
+    if x > 20.0 { x } else { x.exp().ln_1p() }
+}
+
+fn silu(x: f32) -> f32 {
+    x / (1.0 + (-x).exp())
+}
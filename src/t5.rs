@@ -0,0 +1,331 @@
+//! Encoder-decoder transformers: T5, T5 v1.1 and Flan-T5 GGUFs
+//! (`general.architecture` `t5`), for translation and summarization.
+//!
+//! The prompt goes through the encoder once, bidirectionally. The decoder
+//! then starts from `{arch}.decoder_start_token_id` and generates with
+//! causal self-attention over its own K/V rows plus cross attention over
+//! the encoder output, whose K/V each decoder layer projects once.
+//!
+//! T5 has no position embeddings and no RoPE: the scores of each head get
+//! a learned bias looked up by the bucketed distance from query to key
+//! (`attn_rel_b`, shared by every layer of a stack). Scores are not scaled
+//! by `1/sqrt(head_dim)`, norms are RMSNorm without a mean, and the FFN is
+//! ReLU or, with `ffn_gate`, gated GELU. A head tied to the embeddings sees
+//! the decoder state scaled by `hidden^-0.5`, as in the reference model.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result, ensure};
+
+use crate::bert::gelu;
+use crate::cpu::dot;
+use crate::events::GenerationEvent;
+use crate::model::{GenerateOptions, Generator, KvSession, rms_norm};
+use crate::recurrent::{self, Matmuls};
+use crate::weights::{T5Attention, T5Ffn, T5Weights, WeightRef};
+
+/// `general.architecture` values this module runs.
+pub const T5_ARCHS: [&str; 1] = ["t5"];
+
+/// Distances from this far on share the last bucket.
+pub const MAX_DISTANCE: usize = 128;
+
+pub fn is_t5(architecture: &str) -> bool {
+    T5_ARCHS.contains(&architecture)
+}
+
+#[derive(Clone, Debug)]
+pub struct T5Arch {
+    pub hidden: usize,
+    pub n_enc_layers: usize,
+    pub n_dec_layers: usize,
+    pub n_heads: usize,
+    /// `{arch}.attention.key_length`: T5's `d_kv`, not `hidden / n_heads`.
+    pub head_dim: usize,
+    pub ffn_hidden: usize,
+    /// `{arch}.attention.relative_buckets_count`.
+    pub n_buckets: usize,
+    pub rms_eps: f32,
+    pub vocab_size: usize,
+    pub ctx_train: usize,
+    pub decoder_start: u32,
+    pub eos: u32,
+}
+
+/// The decoder's side of one generation: its own K/V rows per layer and the
+/// encoder output projected for each layer's cross attention.
+#[derive(Default)]
+struct Decoding {
+    self_k: Vec<Vec<Arc<[f32]>>>,
+    self_v: Vec<Vec<Arc<[f32]>>>,
+    cross_k: Vec<Vec<Arc<[f32]>>>,
+    cross_v: Vec<Vec<Arc<[f32]>>>,
+}
+
+pub struct T5Model {
+    pub arch: T5Arch,
+    pub weights: Arc<T5Weights>,
+    mm: Matmuls,
+    load_ms: u128,
+}
+
+impl T5Model {
+    pub fn load(path: &str) -> Result<Self> {
+        let t0 = std::time::Instant::now();
+        let mm = Matmuls::open(path)?;
+        let (meta, index) = (&mm.store.metadata, &mm.store.index);
+        let prefix = meta.get("general.architecture").and_then(|v| v.as_str()).unwrap_or("t5").to_string();
+        ensure!(is_t5(&prefix), "'{prefix}' is not an encoder-decoder architecture");
+        let get_u = |key: &str| meta.get(&format!("{prefix}.{key}")).and_then(|v| v.as_u64()).map(|v| v as usize);
+        let get_f = |key: &str| meta.get(&format!("{prefix}.{key}")).and_then(|v| v.as_f64()).map(|v| v as f32);
+
+        let hidden = get_u("embedding_length").context("missing embedding_length")?;
+        let n_heads = get_u("attention.head_count").context("missing attention.head_count")?;
+        let n_enc_layers = get_u("block_count").context("missing block_count")?;
+        let arch = T5Arch {
+            hidden,
+            n_enc_layers,
+            n_dec_layers: get_u("decoder_block_count").unwrap_or(n_enc_layers),
+            n_heads,
+            head_dim: get_u("attention.key_length").unwrap_or(hidden / n_heads.max(1)),
+            ffn_hidden: get_u("feed_forward_length").context("missing feed_forward_length")?,
+            n_buckets: get_u("attention.relative_buckets_count").unwrap_or(32),
+            rms_eps: get_f("attention.layer_norm_rms_epsilon").unwrap_or(1e-6),
+            vocab_size: index.get("token_embd.weight").map(|m| m.rows()).context("missing token_embd.weight")?,
+            ctx_train: get_u("context_length").unwrap_or(512),
+            decoder_start: get_u("decoder_start_token_id").unwrap_or(0) as u32,
+            eos: meta.get("tokenizer.ggml.eos_token_id").and_then(|v| v.as_u64()).unwrap_or(1) as u32,
+        };
+        let weights = Arc::new(T5Weights::from_index(index, &arch)?);
+        Ok(Self { arch, weights, mm, load_ms: t0.elapsed().as_millis() })
+    }
+
+    pub fn declared_stop_tokens(&self) -> Vec<u32> {
+        self.mm.declared_stop_tokens()
+    }
+
+    /// Encode `tokens` (with `</s>` added if they lack it) and sample the
+    /// decoder's reply. Usage counts the source tokens as the prompt.
+    pub fn generate(
+        &mut self,
+        tokens: &[u32],
+        opts: &GenerateOptions,
+        vocab: &[String],
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<()> {
+        ensure!(!tokens.is_empty(), "cannot generate from an empty prompt");
+        recurrent::check_options(opts, "Encoder-decoder")?;
+        let mut source = tokens.to_vec();
+        if source.last() != Some(&self.arch.eos) {
+            source.push(self.arch.eos);
+        }
+        let t0 = std::time::Instant::now();
+        let encoded = self.encode(&source)?;
+        let mut dec = self.cross_rows(&encoded, source.len())?;
+        let encode_ms = t0.elapsed().as_millis();
+
+        let n_source = source.len();
+        let mut report = |event: GenerationEvent| match event {
+            GenerationEvent::PromptProcessed { ms, .. } => {
+                on_event(GenerationEvent::PromptProcessed { n_tokens: n_source, ms: ms + encode_ms })
+            }
+            GenerationEvent::Done { reason, mut timings, mut usage } => {
+                (timings.prefill_tokens, timings.prefill_ms) = (n_source, timings.prefill_ms + encode_ms);
+                usage.prompt_tokens = n_source;
+                on_event(GenerationEvent::Done { reason, timings, usage })
+            }
+            event => on_event(event),
+        };
+        let start = [self.arch.decoder_start];
+        let load_ms = self.load_ms;
+        recurrent::decode(&start, 0, opts, vocab, load_ms, &mut report, &mut |t| self.decode_step(t, &mut dec))
+    }
+
+    /// The encoder's final hidden states, packed `[tokens, hidden]`.
+    pub fn encode(&mut self, tokens: &[u32]) -> Result<Vec<f32>> {
+        let n = tokens.len();
+        let weights = self.weights.clone();
+        let table = first_bias(weights.encoder.iter().map(|l| &l.attn))?;
+        let mut x = self.mm.embed(&weights.token_embd, tokens)?;
+        for layer in &weights.encoder {
+            let xn = self.rms_norm_rows(&layer.attn.norm, &x)?;
+            let (q, k, v) = self.qkv(&layer.attn, &xn, n)?;
+            let bias = self.mm.cpu_weights(&layer.attn.rel_bias.as_ref().unwrap_or(table).name)?;
+            let (k, v) = (self.rows(&k), self.rows(&v));
+            let inner = self.arch.n_heads * self.arch.head_dim;
+            let mut attn = Vec::with_capacity(n * inner);
+            for (i, qi) in q.chunks_exact(inner).enumerate() {
+                let b = position_bias(&bias, self.arch.n_heads, self.arch.n_buckets, i, n, true);
+                attn.extend(biased_attention(qi, &k, &v, self.arch.n_heads, self.arch.head_dim, &b));
+            }
+            add(&mut x, &self.mm.linear(&layer.attn.output, None, &attn, n)?);
+            let out = self.ffn(&layer.ffn, &x, n)?;
+            add(&mut x, &out);
+        }
+        self.rms_norm_rows(&weights.encoder_norm, &x)
+    }
+
+    /// Every decoder layer's cross-attention K/V of the encoder output.
+    fn cross_rows(&mut self, encoded: &[f32], n: usize) -> Result<Decoding> {
+        let weights = self.weights.clone();
+        let mut dec = Decoding::default();
+        for layer in &weights.decoder {
+            let cross = layer.cross.as_ref().context("decoder layer without cross attention")?;
+            let k = self.mm.linear(&cross.k, None, encoded, n)?;
+            let v = self.mm.linear(&cross.v, None, encoded, n)?;
+            dec.cross_k.push(self.rows(&k));
+            dec.cross_v.push(self.rows(&v));
+            dec.self_k.push(Vec::new());
+            dec.self_v.push(Vec::new());
+        }
+        Ok(dec)
+    }
+
+    /// Run decoder `tokens` after the rows already in `dec`, and return the
+    /// logits following the last of them.
+    fn decode_step(&mut self, tokens: &[u32], dec: &mut Decoding) -> Result<Vec<f32>> {
+        let (h, n) = (self.arch.hidden, tokens.len());
+        let (n_heads, head_dim) = (self.arch.n_heads, self.arch.head_dim);
+        let inner = n_heads * head_dim;
+        let weights = self.weights.clone();
+        let table = first_bias(weights.decoder.iter().map(|l| &l.attn))?;
+        let mut x = self.mm.embed(&weights.token_embd, tokens)?;
+        for (l, layer) in weights.decoder.iter().enumerate() {
+            let xn = self.rms_norm_rows(&layer.attn.norm, &x)?;
+            let (q, k, v) = self.qkv(&layer.attn, &xn, n)?;
+            let bias = self.mm.cpu_weights(&layer.attn.rel_bias.as_ref().unwrap_or(table).name)?;
+            let mut attn = Vec::with_capacity(n * inner);
+            for (i, qi) in q.chunks_exact(inner).enumerate() {
+                dec.self_k[l].push(k[i * inner..][..inner].into());
+                dec.self_v[l].push(v[i * inner..][..inner].into());
+                let seen = dec.self_k[l].len();
+                let b = position_bias(&bias, n_heads, self.arch.n_buckets, seen - 1, seen, false);
+                attn.extend(biased_attention(qi, &dec.self_k[l], &dec.self_v[l], n_heads, head_dim, &b));
+            }
+            add(&mut x, &self.mm.linear(&layer.attn.output, None, &attn, n)?);
+
+            let cross = layer.cross.as_ref().context("decoder layer without cross attention")?;
+            let xn = self.rms_norm_rows(&cross.norm, &x)?;
+            let q = self.mm.linear(&cross.q, None, &xn, n)?;
+            let no_bias = vec![0.0; n_heads * dec.cross_k[l].len()];
+            let attn: Vec<f32> = q
+                .chunks_exact(inner)
+                .flat_map(|qi| biased_attention(qi, &dec.cross_k[l], &dec.cross_v[l], n_heads, head_dim, &no_bias))
+                .collect();
+            add(&mut x, &self.mm.linear(&cross.output, None, &attn, n)?);
+            let out = self.ffn(&layer.ffn, &x, n)?;
+            add(&mut x, &out);
+        }
+        let mut last = self.rms_norm_rows(&weights.decoder_norm, &x[(n - 1) * h..])?;
+        if weights.tied_head() {
+            let scale = (h as f32).powf(-0.5);
+            last.iter_mut().for_each(|v| *v *= scale);
+        }
+        self.mm.linear(&weights.output, None, &last, 1)
+    }
+
+    fn qkv(&mut self, a: &T5Attention, xn: &[f32], n: usize) -> Result<(Vec<f32>, Vec<f32>, Vec<f32>)> {
+        Ok((self.mm.linear(&a.q, None, xn, n)?, self.mm.linear(&a.k, None, xn, n)?, self.mm.linear(&a.v, None, xn, n)?))
+    }
+
+    fn ffn(&mut self, f: &T5Ffn, x: &[f32], n: usize) -> Result<Vec<f32>> {
+        let xn = self.rms_norm_rows(&f.norm, x)?;
+        let up = self.mm.linear(&f.up, None, &xn, n)?;
+        let mid: Vec<f32> = match &f.gate {
+            Some(gate) => self.mm.linear(gate, None, &xn, n)?.iter().zip(&up).map(|(g, u)| gelu(*g) * u).collect(),
+            None => up.iter().map(|u| u.max(0.0)).collect(),
+        };
+        self.mm.linear(&f.down, None, &mid, n)
+    }
+
+    fn rows(&self, x: &[f32]) -> Vec<Arc<[f32]>> {
+        x.chunks_exact(self.arch.n_heads * self.arch.head_dim).map(Arc::from).collect()
+    }
+
+    fn rms_norm_rows(&mut self, w: &WeightRef, x: &[f32]) -> Result<Vec<f32>> {
+        let w = self.mm.cpu_weights(&w.name)?;
+        Ok(x.chunks_exact(w.len()).flat_map(|r| rms_norm(r, &w, self.arch.rms_eps)).collect())
+    }
+}
+
+impl Generator for T5Model {
+    /// Every prompt is encoded afresh, so nothing is cached between calls.
+    fn generate_cached(
+        &mut self,
+        _session: &mut KvSession,
+        tokens: &[u32],
+        opts: &GenerateOptions,
+        vocab: &[String],
+        on_event: &mut dyn FnMut(GenerationEvent),
+    ) -> Result<usize> {
+        self.generate(tokens, opts, vocab, on_event)?;
+        Ok(0)
+    }
+}
+
+/// Layer 0's position bias table, which the rest of the stack shares.
+fn first_bias<'a>(mut layers: impl Iterator<Item = &'a T5Attention>) -> Result<&'a WeightRef> {
+    layers.next().and_then(|a| a.rel_bias.as_ref()).context("the first T5 layer has no attn_rel_b")
+}
+
+// ---------------------------------------------------------------------------
+// CPU math
+// ---------------------------------------------------------------------------
+
+fn add(x: &mut [f32], y: &[f32]) {
+    x.iter_mut().zip(y).for_each(|(a, b)| *a += b);
+}
+
+/// T5's bucket for the distance `rel = key - query`. Half the buckets are
+/// exact distances, the rest grow logarithmically up to `MAX_DISTANCE`;
+/// bidirectional attention splits them between keys before and after the
+/// query, causal attention only has keys before.
+pub(crate) fn relative_bucket(rel: i64, n_buckets: usize, bidirectional: bool) -> usize {
+    let (mut n_buckets, mut bucket) = (n_buckets, 0);
+    let distance = if bidirectional {
+        n_buckets /= 2;
+        if rel > 0 {
+            bucket = n_buckets;
+        }
+        rel.unsigned_abs() as usize
+    } else {
+        (-rel.min(0)) as usize
+    };
+    let max_exact = n_buckets / 2;
+    if distance < max_exact {
+        return bucket + distance;
+    }
+    let log = (distance as f32 / max_exact as f32).ln() / (MAX_DISTANCE as f32 / max_exact as f32).ln();
+    bucket + (max_exact + (log * (n_buckets - max_exact) as f32) as usize).min(n_buckets - 1)
+}
+
+/// The bias of each head for the query at `query` over keys `0..seq`,
+/// `[n_heads, seq]`, from a `[buckets, n_heads]` table.
+pub(crate) fn position_bias(
+    table: &[f32], n_heads: usize, n_buckets: usize, query: usize, seq: usize, bidirectional: bool,
+) -> Vec<f32> {
+    let buckets: Vec<usize> =
+        (0..seq).map(|t| relative_bucket(t as i64 - query as i64, n_buckets, bidirectional)).collect();
+    (0..n_heads).flat_map(|h| buckets.iter().map(move |&b| table[b * n_heads + h])).collect()
+}
+
+/// Unscaled attention of one query over every row of `k`/`v`, each head's
+/// scores offset by its row of `bias` (`[n_heads, k.len()]`).
+pub(crate) fn biased_attention(
+    q: &[f32], k: &[Arc<[f32]>], v: &[Arc<[f32]>], n_heads: usize, head_dim: usize, bias: &[f32],
+) -> Vec<f32> {
+    let seq = k.len();
+    let mut out = vec![0.0f32; n_heads * head_dim];
+    for h in 0..n_heads {
+        let range = h * head_dim..(h + 1) * head_dim;
+        let mut scores: Vec<f32> =
+            (0..seq).map(|t| dot(&q[range.clone()], &k[t][range.clone()]) + bias[h * seq + t]).collect();
+        let max = scores.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let sum: f32 = scores.iter_mut().map(|s| { *s = (*s - max).exp(); *s }).sum();
+        let out_head = &mut out[range.clone()];
+        for (t, s) in scores.iter().enumerate() {
+            out_head.iter_mut().zip(&v[t][range.clone()]).for_each(|(o, v)| *o += s / sum * v);
+        }
+    }
+    out
+}
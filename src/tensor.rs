@@ -1,17 +1,78 @@
 use anyhow::{Context, Result, bail};
-use byteorder::{LittleEndian, ReadBytesExt};
 use memmap2::Mmap;
 use metal::{Buffer, Device, MTLResourceOptions};
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Read;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 
+use crate::gguf::{GgufFile, GgufVersion, MetaValue};
+use crate::gpu::{Gpu, OutOfMemory};
+
 pub const GGML_F32: u32 = 0;
 pub const GGML_F16: u32 = 1;
 pub const GGML_Q8_0: u32 = 8;
+pub const GGML_Q2_K: u32 = 10;
+pub const GGML_Q3_K: u32 = 11;
+pub const GGML_IQ2_XXS: u32 = 16;
+pub const GGML_IQ3_XXS: u32 = 18;
+pub const GGML_IQ4_NL: u32 = 20;
 pub const Q8_0_BLOCK: usize = 34; // 2-byte f16 scale + 32 × i8
+/// Elements per K-quant super-block.
+pub const QK_K: usize = 256;
+pub const Q2_K_BLOCK: usize = 84;  // 16 scale/min nibble pairs + 64 B of 2-bit quants + f16 d + f16 dmin
+pub const Q3_K_BLOCK: usize = 110; // 32 B high-bit mask + 64 B of 2-bit quants + 12 B of 6-bit scales + f16 d
+pub const IQ4_NL_BLOCK: usize = 18; // f16 d + 32 × 4-bit codebook indices
+
+/// IQ4_NL's non-linear codebook: 4-bit index → int8 level, denser near zero.
+pub const IQ4_NL_VALUES: [i8; 16] = [-127, -104, -83, -65, -49, -35, -22, -10, 1, 13, 25, 38, 53, 69, 89, 113];
+
+/// (elements per block, bytes per block) for a ggml tensor type.
+pub fn ggml_block_layout(kind: u32) -> Option<(u64, u64)> {
+    Some(match kind {
+        0 => (1, 4),        // F32
+        1 => (1, 2),        // F16
+        2 => (32, 18),      // Q4_0
+        3 => (32, 20),      // Q4_1
+        6 => (32, 22),      // Q5_0
+        7 => (32, 24),      // Q5_1
+        8 => (32, 34),      // Q8_0
+        9 => (32, 36),      // Q8_1
+        10 => (256, 84),    // Q2_K
+        11 => (256, 110),   // Q3_K
+        12 => (256, 144),   // Q4_K
+        13 => (256, 176),   // Q5_K
+        14 => (256, 210),   // Q6_K
+        15 => (256, 292),   // Q8_K
+        16 => (256, 66),    // IQ2_XXS
+        17 => (256, 74),    // IQ2_XS
+        18 => (256, 98),    // IQ3_XXS
+        19 => (256, 50),    // IQ1_S
+        20 => (32, 18),     // IQ4_NL
+        21 => (256, 110),   // IQ3_S
+        22 => (256, 82),    // IQ2_S
+        23 => (256, 136),   // IQ4_XS
+        24 => (1, 1),       // I8
+        25 => (1, 2),       // I16
+        26 => (1, 4),       // I32
+        27 => (1, 8),       // I64
+        28 => (1, 8),       // F64
+        29 => (256, 56),    // IQ1_M
+        30 => (1, 2),       // BF16
+        _ => return None,
+    })
+}
+
+/// Short ggml name for a tensor type, as llama.cpp prints it.
+pub fn ggml_type_name(kind: u32) -> &'static str {
+    match kind {
+        0 => "F32", 1 => "F16", 2 => "Q4_0", 3 => "Q4_1", 6 => "Q5_0", 7 => "Q5_1",
+        8 => "Q8_0", 9 => "Q8_1", 10 => "Q2_K", 11 => "Q3_K", 12 => "Q4_K", 13 => "Q5_K",
+        14 => "Q6_K", 15 => "Q8_K", 16 => "IQ2_XXS", 17 => "IQ2_XS", 18 => "IQ3_XXS",
+        19 => "IQ1_S", 20 => "IQ4_NL", 21 => "IQ3_S", 22 => "IQ2_S", 23 => "IQ4_XS",
+        24 => "I8", 25 => "I16", 26 => "I32", 27 => "I64", 28 => "F64", 29 => "IQ1_M",
+        30 => "BF16",
+        _ => "?",
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct TensorMeta {
@@ -25,8 +86,29 @@ impl TensorMeta {
     pub fn rows(&self) -> usize {
         if self.shape.len() >= 2 { self.shape[1] as usize } else { 1 }
     }
+    /// 1 for a 0-dim (scalar) tensor, like `rows`.
     pub fn cols(&self) -> usize {
-        self.shape[0] as usize
+        self.shape.first().map_or(1, |&c| c as usize)
+    }
+}
+
+/// A weight on the GPU: its bytes start `offset` into `buf`, which is either
+/// a copy or a zero-copy view of the model's mmap pages.
+pub struct WeightBuf {
+    pub buf: Buffer,
+    pub offset: u64,
+    /// The mapping a zero-copy `buf` points into.
+    mapping: Option<Arc<Mmap>>,
+}
+
+impl WeightBuf {
+    pub fn copied(buf: Buffer) -> Self {
+        Self { buf, offset: 0, mapping: None }
+    }
+
+    /// Whether `buf` views the mmap instead of holding a copy.
+    pub fn is_mapped(&self) -> bool {
+        self.mapping.is_some()
     }
 }
 
@@ -35,18 +117,21 @@ pub struct TensorStore {
     /// Zero-copy Metal buffer wrapping the entire mmap.
     pub mmap_buf: Buffer,
     pub index: HashMap<String, TensorMeta>,
+    pub version: GgufVersion,
+    pub metadata: BTreeMap<String, MetaValue>,
+    data_start: u64,
+    key: u64,
 }
 
 impl TensorStore {
     pub fn open(path: &str, device: &Device) -> Result<Self> {
-        let file = File::open(path).with_context(|| format!("open {path}"))?;
-        let mmap = Arc::new(unsafe { Mmap::map(&file) }.context("mmap")?);
+        let mmap = Arc::new(crate::envelope::map(path)?);
 
         // Zero-copy Metal buffer wrapping the entire mmap.
-        // Metal requires both the pointer and length to be page-aligned (4096 bytes on macOS).
+        // Metal requires both the pointer and length to be page-aligned (see `gpu::PAGE`).
         // The mmap base pointer is always page-aligned; round the length up to the page boundary.
         // The extra bytes at the end of the last page are OS-zero-filled and never accessed by kernels.
-        const PAGE: usize = 4096;
+        use crate::gpu::PAGE;
         let rounded_len = mmap.len().div_ceil(PAGE) * PAGE;
         let mmap_buf = unsafe {
             device.new_buffer_with_bytes_no_copy(
@@ -57,25 +142,28 @@ impl TensorStore {
             )
         };
 
-        let data_start = find_data_start(path)?;
-
-        let mut container = gguf_rs::get_gguf_container_array_size(path, 0)?;
-        let model = container.decode()?;
-
-        let mut index = HashMap::new();
-        for t in model.tensors() {
-            index.insert(
-                t.name.clone(),
-                TensorMeta {
-                    file_offset: data_start + t.offset,
-                    byte_size: t.size,
-                    kind: t.kind,
-                    shape: t.shape.clone(),
-                },
-            );
+        let gguf = GgufFile::parse(&mmap).with_context(|| format!("parse {path}"))?;
+        gguf.ensure_little_endian_data().with_context(|| format!("load {path}"))?;
+        let index = index_tensors(&gguf)?;
+        if let Some(truncation) = crate::repair::find_truncation(path, &index, mmap.len() as u64) {
+            return Err(truncation.with_source().into());
         }
 
-        Ok(Self { mmap, mmap_buf, index })
+        let mut tensors: Vec<&TensorMeta> = index.values().collect();
+        tensors.sort_unstable_by_key(|m| m.file_offset);
+        let data: Vec<&[u8]> = tensors.iter().map(|m| &mmap[m.file_offset as usize..][..m.byte_size as usize]).collect();
+        let header = &mmap[..(gguf.data_start as usize).min(mmap.len())];
+        let key = crate::repack::model_key(header, mmap.len() as u64, &data);
+
+        Ok(Self {
+            mmap,
+            mmap_buf,
+            index,
+            version: gguf.version,
+            metadata: gguf.metadata,
+            data_start: gguf.data_start,
+            key,
+        })
     }
 
     /// Raw bytes for a tensor (CPU-side, from mmap).
@@ -90,12 +178,78 @@ impl TensorStore {
         Ok(&self.mmap[start..end])
     }
 
+    /// `bytes` (from `get`) as a GPU weight. Bytes inside the mmap are
+    /// wrapped in place, so the weights are not held twice in unified
+    /// memory; anything Metal will not wrap is copied with `upload_weight`.
+    pub fn weight_buffer(&self, gpu: &Gpu, bytes: &[u8]) -> Result<WeightBuf, OutOfMemory> {
+        let mapped = self.mmap.as_ptr_range();
+        let inside = mapped.start <= bytes.as_ptr() && bytes.as_ptr_range().end <= mapped.end;
+        // SAFETY: the bytes lie in `self.mmap`, which the WeightBuf keeps alive.
+        let wrapped = if inside { unsafe { gpu.map_weight(bytes)? } } else { None };
+        Ok(match wrapped {
+            Some((buf, offset)) => WeightBuf { buf, offset, mapping: Some(self.mmap.clone()) },
+            None => WeightBuf::copied(gpu.upload_weight(bytes)?),
+        })
+    }
+
+    /// Everything before the tensor data: magic, metadata, tensor table.
+    pub fn header(&self) -> &[u8] {
+        &self.mmap[..(self.data_start as usize).min(self.mmap.len())]
+    }
+
+    pub fn file_len(&self) -> u64 {
+        self.mmap.len() as u64
+    }
+
+    /// `repack::model_key` of this file, computed once at open.
+    pub fn model_key(&self) -> u64 {
+        self.key
+    }
+
     pub fn meta(&self, name: &str) -> Result<&TensorMeta> {
         self.index.get(name).with_context(|| format!("tensor '{name}' not in GGUF"))
     }
 
     // -- dequantization helpers (CPU) -----------------------------------------
 
+    /// A whole F32/F16/Q8_0/Q2_K/Q3_K/IQ4_NL tensor as f32, for small weights used on the CPU.
+    pub fn dequant(&self, name: &str) -> Result<Vec<f32>> {
+        Self::dequant_bytes(self.meta(name)?.kind, self.get(name)?)
+            .with_context(|| format!("dequantize '{name}'"))
+    }
+
+    /// Row `row` of a 2-D tensor `dequant` can read (an embedding lookup).
+    pub fn dequant_row(&self, name: &str, row: usize) -> Result<Vec<f32>> {
+        Self::dequant_bytes(self.meta(name)?.kind, self.row_bytes(name, row)?)
+            .with_context(|| format!("dequantize '{name}'"))
+    }
+
+    /// Row `row` of a 2-D tensor as stored, still quantized.
+    pub fn row_bytes(&self, name: &str, row: usize) -> Result<&[u8]> {
+        let meta = self.meta(name)?;
+        let rows = meta.rows();
+        if row >= rows {
+            bail!("row {row} >= {rows} in '{name}'");
+        }
+        let row_bytes = meta.byte_size as usize / rows;
+        Ok(&self.get(name)?[row * row_bytes..][..row_bytes])
+    }
+
+    fn dequant_bytes(kind: u32, bytes: &[u8]) -> Result<Vec<f32>> {
+        match kind {
+            GGML_F32 => Ok(bytes.chunks_exact(4).map(|c| f32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect()),
+            GGML_F16 => Ok(Self::dequant_f16_row(bytes)),
+            GGML_Q8_0 => Ok(Self::dequant_q8_0_row(bytes)),
+            GGML_Q2_K => Ok(Self::dequant_q2_k_row(bytes)),
+            GGML_Q3_K => Ok(Self::dequant_q3_k_row(bytes)),
+            GGML_IQ4_NL => Ok(Self::dequant_iq4_nl_row(bytes)),
+            k @ (GGML_IQ2_XXS | GGML_IQ3_XXS) => {
+                bail!("{} needs ggml's i-quant grid codebooks, which are not bundled yet", ggml_type_name(k))
+            }
+            k => bail!("unsupported dtype {}", ggml_type_name(k)),
+        }
+    }
+
     pub fn dequant_q8_0_row(row_bytes: &[u8]) -> Vec<f32> {
         let blocks = row_bytes.len() / Q8_0_BLOCK;
         let mut out = Vec::with_capacity(blocks * 32);
@@ -110,6 +264,69 @@ impl TensorStore {
         out
     }
 
+    /// Q2_K: each 16-element sub-block has a 4-bit scale and 4-bit min,
+    /// scaled by the super-block's `d` and `dmin`; x = d·sc·q − dmin·m.
+    /// Quant byte `l` of each 32-byte group holds elements l, l+32, l+64,
+    /// l+96 of its 128-element half in bit pairs, as llama.cpp packs them.
+    pub fn dequant_q2_k_row(row_bytes: &[u8]) -> Vec<f32> {
+        let mut out = Vec::with_capacity(row_bytes.len() / Q2_K_BLOCK * QK_K);
+        for block in row_bytes.chunks_exact(Q2_K_BLOCK) {
+            let (scales, qs) = (&block[..16], &block[16..80]);
+            let d = half::f16::from_le_bytes([block[80], block[81]]).to_f32();
+            let dmin = half::f16::from_le_bytes([block[82], block[83]]).to_f32();
+            for half_qs in qs.chunks_exact(32) {
+                let sub = out.len() % QK_K / 16;
+                for shift in (0..8).step_by(2) {
+                    for (i, group) in half_qs.chunks_exact(16).enumerate() {
+                        let sc = scales[sub + shift + i];
+                        let (dl, ml) = (d * (sc & 0xF) as f32, dmin * (sc >> 4) as f32);
+                        out.extend(group.iter().map(|q| dl * ((q >> shift) & 3) as f32 - ml));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Q3_K: 2 low bits in `qs` (packed like Q2_K) plus a high bit in `hmask`
+    /// give q in -4..=3; each 16-element sub-block has a signed 6-bit scale.
+    pub fn dequant_q3_k_row(row_bytes: &[u8]) -> Vec<f32> {
+        let mut out = Vec::with_capacity(row_bytes.len() / Q3_K_BLOCK * QK_K);
+        for block in row_bytes.chunks_exact(Q3_K_BLOCK) {
+            let (hmask, qs) = (&block[..32], &block[32..96]);
+            let scales = q3_k_scales(&block[96..108]);
+            let d = half::f16::from_le_bytes([block[108], block[109]]).to_f32();
+            let mut bit = 0;
+            for (h, half_qs) in qs.chunks_exact(32).enumerate() {
+                for shift in (0..8).step_by(2) {
+                    for (i, group) in half_qs.chunks_exact(16).enumerate() {
+                        let dl = d * scales[h * 8 + shift + i] as f32;
+                        let high = &hmask[i * 16..][..16];
+                        out.extend(group.iter().zip(high).map(|(q, m)| {
+                            let q = ((q >> shift) & 3) as i32 - if m & (1 << bit) != 0 { 0 } else { 4 };
+                            dl * q as f32
+                        }));
+                    }
+                    bit += 1;
+                }
+            }
+        }
+        out
+    }
+
+    /// IQ4_NL: blocks of 32 as Q4_0, but each nibble indexes `IQ4_NL_VALUES`
+    /// instead of standing for itself. Byte `j` holds elements j and j+16.
+    pub fn dequant_iq4_nl_row(row_bytes: &[u8]) -> Vec<f32> {
+        let mut out = Vec::with_capacity(row_bytes.len() / IQ4_NL_BLOCK * 32);
+        for block in row_bytes.chunks_exact(IQ4_NL_BLOCK) {
+            let d = half::f16::from_le_bytes([block[0], block[1]]).to_f32();
+            let qs = &block[2..];
+            out.extend(qs.iter().map(|q| d * IQ4_NL_VALUES[(q & 0xF) as usize] as f32));
+            out.extend(qs.iter().map(|q| d * IQ4_NL_VALUES[(q >> 4) as usize] as f32));
+        }
+        out
+    }
+
     pub fn dequant_f16_row(row_bytes: &[u8]) -> Vec<f32> {
         row_bytes
             .chunks_exact(2)
@@ -118,37 +335,43 @@ impl TensorStore {
     }
 }
 
-fn find_data_start(path: &str) -> Result<u64> {
-    let mut file = File::open(path)?;
-    let magic = file.read_i32::<LittleEndian>()?;
-    let bo = match magic {
-        0x46554747 => gguf_rs::ByteOrder::LE,
-        0x47475546 => gguf_rs::ByteOrder::BE,
-        _ => bail!("not a GGUF file"),
-    };
-
-    let pos = Arc::new(AtomicU64::new(4));
-    let reader = CountingReader { inner: file, pos: pos.clone() };
-    let mut container = gguf_rs::GGUFContainer::new(bo, Box::new(reader), 0);
-    let model = container.decode()?;
-
-    let after_header = pos.load(Ordering::SeqCst);
-    let align = model.metadata().get("general.alignment")
-        .and_then(|v| v.as_u64())
-        .unwrap_or(32);
-
-    Ok(after_header.div_ceil(align) * align)
-}
-
-struct CountingReader {
-    inner: File,
-    pos: Arc<AtomicU64>,
+/// Q3_K's sixteen 6-bit scales, bias removed: low nibbles in bytes 0..8
+/// (sub-blocks 8..16 in the high halves), the top two bits packed four to a
+/// byte in bytes 8..12.
+fn q3_k_scales(packed: &[u8]) -> [i32; 16] {
+    std::array::from_fn(|s| {
+        let low = (packed[s % 8] >> (4 * (s / 8))) & 0xF;
+        let high = (packed[8 + s % 4] >> (2 * (s / 4))) & 3;
+        (low | high << 4) as i32 - 32
+    })
 }
 
-impl Read for CountingReader {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let n = self.inner.read(buf)?;
-        self.pos.fetch_add(n as u64, Ordering::SeqCst);
-        Ok(n)
+/// Absolute offsets and byte sizes for every tensor in the table.
+/// Offsets are relative to the aligned data section (`GgufFile::data_start`),
+/// which the parser already placed on the file's `general.alignment` boundary.
+pub fn index_tensors(gguf: &GgufFile) -> Result<HashMap<String, TensorMeta>> {
+    let mut index = HashMap::new();
+    for t in &gguf.tensors {
+        let (block_elems, block_bytes) = ggml_block_layout(t.kind)
+            .with_context(|| format!("tensor '{}' has unknown ggml type {}", t.name, t.kind))?;
+        // Checked: these come straight from the file.
+        let byte_size = (t.elements() / block_elems)
+            .checked_mul(block_bytes)
+            .with_context(|| format!("tensor '{}' of shape {:?} is too large", t.name, t.shape))?;
+        let file_offset = gguf
+            .data_start
+            .checked_add(t.offset)
+            .filter(|start| start.checked_add(byte_size).is_some())
+            .with_context(|| format!("tensor '{}' data offset {} is out of range", t.name, t.offset))?;
+        index.insert(
+            t.name.clone(),
+            TensorMeta {
+                file_offset,
+                byte_size,
+                kind: t.kind,
+                shape: t.shape.clone(),
+            },
+        );
     }
+    Ok(index)
 }
@@ -0,0 +1,293 @@
+//! Tiny GGUF files built in memory, so the parser, the loaders and the
+//! dequantizers can be tested without fixture files.
+//!
+//! `GgufBuilder` writes any version in either byte order: the metadata in
+//! the order it was added, the tensor table, then each tensor's data at the
+//! next `general.alignment` boundary (32 unless set). A table entry can also
+//! be given a fixed offset and no data, for tests of bad layouts. `q8_0` and
+//! `f16` encode f32 values the way llama.cpp's quantizer does and return
+//! what the blocks dequantize to, so a test knows the exact weights.
+//! `TempGguf` writes a buffer to a temporary file for the loaders that want
+//! a path, and removes it again on drop. `meta_index` builds a tensor table
+//! of bare names and shapes, all the `*Weights::from_index` tests need.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::gguf::MetaValue;
+use crate::tensor::{GGML_F16, GGML_F32, GGML_Q8_0, TensorMeta};
+
+struct Tensor {
+    name: String,
+    /// GGUF order, innermost first: `[cols, rows]`.
+    dims: Vec<u64>,
+    kind: u32,
+    data: Vec<u8>,
+    /// Fixed offset into the data section; the next aligned one when unset.
+    offset: Option<u64>,
+}
+
+pub struct GgufBuilder {
+    version: u32,
+    big_endian: bool,
+    alignment: u64,
+    metadata: Vec<(String, MetaValue)>,
+    tensors: Vec<Tensor>,
+}
+
+impl Default for GgufBuilder {
+    fn default() -> Self {
+        Self { version: 3, big_endian: false, alignment: 32, metadata: Vec::new(), tensors: Vec::new() }
+    }
+}
+
+impl GgufBuilder {
+    /// A little-endian v3 file.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The version word; v1 writes counts and lengths as u32.
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    pub fn big_endian(mut self) -> Self {
+        self.big_endian = true;
+        self
+    }
+
+    /// Add `general.alignment` and lay the tensors out on it.
+    pub fn alignment(mut self, alignment: u32) -> Self {
+        self.alignment = alignment as u64;
+        self.meta("general.alignment", MetaValue::U32(alignment))
+    }
+
+    pub fn meta(mut self, key: &str, value: MetaValue) -> Self {
+        self.metadata.push((key.to_string(), value));
+        self
+    }
+
+    pub fn meta_str(self, key: &str, value: &str) -> Self {
+        self.meta(key, MetaValue::Str(value.to_string()))
+    }
+
+    pub fn meta_u32(self, key: &str, value: u32) -> Self {
+        self.meta(key, MetaValue::U32(value))
+    }
+
+    pub fn meta_strings<S: AsRef<str>>(self, key: &str, values: &[S]) -> Self {
+        self.meta(key, MetaValue::Array(values.iter().map(|s| MetaValue::Str(s.as_ref().to_string())).collect()))
+    }
+
+    /// `general.architecture`.
+    pub fn architecture(self, arch: &str) -> Self {
+        self.meta_str("general.architecture", arch)
+    }
+
+    /// A tensor of ggml type `kind` holding `data` as stored. `dims` are in
+    /// GGUF order, innermost first.
+    pub fn tensor(mut self, name: &str, dims: &[u64], kind: u32, data: Vec<u8>) -> Self {
+        self.tensors.push(Tensor { name: name.to_string(), dims: dims.to_vec(), kind, data, offset: None });
+        self
+    }
+
+    /// A table entry at `offset` with no data behind it.
+    pub fn tensor_info(mut self, name: &str, dims: &[u64], kind: u32, offset: u64) -> Self {
+        self.tensors.push(Tensor { name: name.to_string(), dims: dims.to_vec(), kind, data: Vec::new(), offset: Some(offset) });
+        self
+    }
+
+    pub fn tensor_f32(self, name: &str, dims: &[u64], values: &[f32]) -> Self {
+        self.tensor(name, dims, GGML_F32, values.iter().flat_map(|v| v.to_le_bytes()).collect())
+    }
+
+    pub fn tensor_q8_0(self, name: &str, dims: &[u64], values: &[f32]) -> Self {
+        self.tensor(name, dims, GGML_Q8_0, q8_0(values).0)
+    }
+
+    pub fn tensor_f16(self, name: &str, dims: &[u64], values: &[f32]) -> Self {
+        self.tensor(name, dims, GGML_F16, f16(values).0)
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let mut w = Writer { out: b"GGUF".to_vec(), big_endian: self.big_endian, wide: self.version >= 2 };
+        w.u32(self.version);
+        w.len(self.tensors.len());
+        w.len(self.metadata.len());
+        for (key, value) in &self.metadata {
+            w.string(key);
+            w.u32(value_type(value));
+            w.value(value);
+        }
+        let mut next = 0u64;
+        let mut offsets = Vec::with_capacity(self.tensors.len());
+        for t in &self.tensors {
+            // A fixed offset may be anything, so it leaves the layout alone.
+            let offset = t.offset.unwrap_or(next);
+            w.string(&t.name);
+            w.u32(t.dims.len() as u32);
+            for &d in &t.dims {
+                w.len(d as usize);
+            }
+            w.u32(t.kind);
+            w.u64(offset);
+            if t.offset.is_none() {
+                next = (offset + t.data.len() as u64).next_multiple_of(self.alignment);
+            }
+            offsets.push(offset);
+        }
+        let mut out = w.out;
+        // A file of table entries only ends right after the table.
+        if self.tensors.iter().any(|t| !t.data.is_empty()) {
+            let data_start = out.len().next_multiple_of(self.alignment as usize);
+            for (t, offset) in self.tensors.iter().zip(offsets) {
+                out.resize(data_start + offset as usize, 0);
+                out.extend(&t.data);
+            }
+        }
+        out
+    }
+}
+
+/// GGUF's id for a metadata value's type.
+fn value_type(value: &MetaValue) -> u32 {
+    match value {
+        MetaValue::U8(_) => 0,
+        MetaValue::I8(_) => 1,
+        MetaValue::U16(_) => 2,
+        MetaValue::I16(_) => 3,
+        MetaValue::U32(_) => 4,
+        MetaValue::I32(_) => 5,
+        MetaValue::F32(_) => 6,
+        MetaValue::Bool(_) => 7,
+        MetaValue::Str(_) => 8,
+        MetaValue::Array(_) => 9,
+        MetaValue::U64(_) => 10,
+        MetaValue::I64(_) => 11,
+        MetaValue::F64(_) => 12,
+    }
+}
+
+struct Writer {
+    out: Vec<u8>,
+    big_endian: bool,
+    /// Lengths and counts are u64 (v2+) rather than u32.
+    wide: bool,
+}
+
+impl Writer {
+    fn bytes<const N: usize>(&mut self, le: [u8; N]) {
+        let mut b = le;
+        if self.big_endian {
+            b.reverse();
+        }
+        self.out.extend(b);
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.bytes(v.to_le_bytes());
+    }
+
+    fn u64(&mut self, v: u64) {
+        self.bytes(v.to_le_bytes());
+    }
+
+    fn len(&mut self, n: usize) {
+        if self.wide { self.u64(n as u64) } else { self.u32(n as u32) }
+    }
+
+    fn string(&mut self, s: &str) {
+        self.len(s.len());
+        self.out.extend(s.as_bytes());
+    }
+
+    fn value(&mut self, value: &MetaValue) {
+        match value {
+            MetaValue::U8(v) => self.out.push(*v),
+            MetaValue::I8(v) => self.out.push(*v as u8),
+            MetaValue::U16(v) => self.bytes(v.to_le_bytes()),
+            MetaValue::I16(v) => self.bytes(v.to_le_bytes()),
+            MetaValue::U32(v) => self.u32(*v),
+            MetaValue::I32(v) => self.bytes(v.to_le_bytes()),
+            MetaValue::F32(v) => self.bytes(v.to_le_bytes()),
+            MetaValue::Bool(v) => self.out.push(u8::from(*v)),
+            MetaValue::Str(s) => self.string(s),
+            MetaValue::Array(items) => {
+                // An empty array still needs an item type; u8 is as good as any.
+                self.u32(items.first().map_or(0, value_type));
+                self.len(items.len());
+                for item in items {
+                    self.value(item);
+                }
+            }
+            MetaValue::U64(v) => self.u64(*v),
+            MetaValue::I64(v) => self.bytes(v.to_le_bytes()),
+            MetaValue::F64(v) => self.bytes(v.to_le_bytes()),
+        }
+    }
+}
+
+/// Q8_0 blocks for `values` (a multiple of 32 long) and the values they
+/// decode to: `d = amax / 127` as f16, then each value rounded to `d`.
+pub fn q8_0(values: &[f32]) -> (Vec<u8>, Vec<f32>) {
+    assert!(values.len().is_multiple_of(32), "Q8_0 needs whole blocks of 32 values");
+    let (mut bytes, mut decoded) = (Vec::with_capacity(values.len() / 32 * 34), Vec::with_capacity(values.len()));
+    for block in values.chunks_exact(32) {
+        let amax = block.iter().fold(0.0f32, |m, v| m.max(v.abs()));
+        let d = half::f16::from_f32(amax / 127.0);
+        bytes.extend(d.to_le_bytes());
+        for v in block {
+            let q = if d.to_f32() == 0.0 { 0 } else { (v / d.to_f32()).round().clamp(-127.0, 127.0) as i8 };
+            bytes.push(q as u8);
+            decoded.push(d.to_f32() * q as f32);
+        }
+    }
+    (bytes, decoded)
+}
+
+/// F16 bytes for `values` and the values they decode to.
+pub fn f16(values: &[f32]) -> (Vec<u8>, Vec<f32>) {
+    let halves: Vec<half::f16> = values.iter().map(|&v| half::f16::from_f32(v)).collect();
+    (halves.iter().flat_map(|h| h.to_le_bytes()).collect(), halves.iter().map(|h| h.to_f32()).collect())
+}
+
+/// A table entry of `shape` with no data behind it.
+pub fn meta(shape: &[u64]) -> TensorMeta {
+    TensorMeta { file_offset: 0, byte_size: 0, kind: 0, shape: shape.to_vec() }
+}
+
+/// `meta` for each name and shape.
+pub fn meta_index(tensors: &[(&str, &[u64])]) -> HashMap<String, TensorMeta> {
+    tensors.iter().map(|&(name, shape)| (name.to_string(), meta(shape))).collect()
+}
+
+/// A GGUF written to the temp directory, removed when dropped.
+pub struct TempGguf {
+    pub path: PathBuf,
+}
+
+impl TempGguf {
+    /// `bytes` as `llmetal-<tag>-<pid>.gguf`; the tag keeps tests running
+    /// in parallel apart.
+    pub fn new(tag: &str, bytes: &[u8]) -> Self {
+        let path = std::env::temp_dir().join(format!("llmetal-{tag}-{}.gguf", std::process::id()));
+        std::fs::write(&path, bytes).expect("write temporary GGUF");
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn path_str(&self) -> &str {
+        self.path.to_str().expect("temp path is UTF-8")
+    }
+}
+
+impl Drop for TempGguf {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
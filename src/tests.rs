@@ -4,6 +4,7 @@
 #[cfg(test)]
 mod tests {
     use crate::tensor::TensorStore;
+    use crate::test_support::GgufBuilder;
     use crate::tokenizer::PromptTokenizer;
 
     // -------------------------------------------------------------------------
@@ -59,81 +60,4403 @@ mod tests {
         }
     }
 
+    /// Q2_K block from per-element quants (0..=3), laid out element by element
+    /// rather than in the decoder's loop order.
+    fn make_q2_k_block(d: f32, dmin: f32, scales: [u8; 16], q: &[u8; 256]) -> Vec<u8> {
+        let mut block = scales.to_vec();
+        let mut qs = [0u8; 64];
+        for (e, &v) in q.iter().enumerate() {
+            qs[32 * (e / 128) + e % 32] |= v << (2 * (e % 128 / 32));
+        }
+        block.extend(qs);
+        block.extend(half::f16::from_f32(d).to_le_bytes());
+        block.extend(half::f16::from_f32(dmin).to_le_bytes());
+        block
+    }
+
+    /// Q3_K block from per-element quants (-4..=3) and sub-block scales (-32..=31).
+    fn make_q3_k_block(d: f32, scales: [i8; 16], q: &[i8; 256]) -> Vec<u8> {
+        let (mut hmask, mut qs, mut packed) = ([0u8; 32], [0u8; 64], [0u8; 12]);
+        for (e, &v) in q.iter().enumerate() {
+            let u = (v + 4) as u8;
+            qs[32 * (e / 128) + e % 32] |= (u & 3) << (2 * (e % 128 / 32));
+            hmask[e % 32] |= (u >> 2) << (e / 32);
+        }
+        for (s, &sc) in scales.iter().enumerate() {
+            let u = (sc + 32) as u8;
+            packed[s % 8] |= (u & 0xF) << (4 * (s / 8));
+            packed[8 + s % 4] |= (u >> 4) << (2 * (s / 4));
+        }
+        [&hmask[..], &qs, &packed, &half::f16::from_f32(d).to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn dequant_q2_k_applies_sub_block_scale_and_min() {
+        let scales: [u8; 16] = std::array::from_fn(|s| (s as u8 % 16) | ((15 - s as u8) << 4));
+        let q: [u8; 256] = std::array::from_fn(|e| (e * 7 % 4) as u8);
+        let block = make_q2_k_block(0.5, 0.25, scales, &q);
+        assert_eq!(block.len(), crate::tensor::Q2_K_BLOCK);
+        let out = TensorStore::dequant_q2_k_row(&[block.clone(), block].concat());
+        assert_eq!(out.len(), 512);
+        for (e, v) in out.iter().enumerate() {
+            let sc = scales[e % 256 / 16];
+            let expected = 0.5 * (sc & 0xF) as f32 * q[e % 256] as f32 - 0.25 * (sc >> 4) as f32;
+            assert_eq!(*v, expected, "element {e}");
+        }
+    }
+
+    #[test]
+    fn dequant_q3_k_signs_quants_and_scales() {
+        let scales: [i8; 16] = std::array::from_fn(|s| s as i8 * 4 - 32);
+        let q: [i8; 256] = std::array::from_fn(|e| (e * 5 % 8) as i8 - 4);
+        let block = make_q3_k_block(0.125, scales, &q);
+        assert_eq!(block.len(), crate::tensor::Q3_K_BLOCK);
+        let out = TensorStore::dequant_q3_k_row(&block);
+        for (e, v) in out.iter().enumerate() {
+            assert_eq!(*v, 0.125 * scales[e / 16] as f32 * q[e] as f32, "element {e}");
+        }
+    }
+
+    #[test]
+    fn dequant_iq4_nl_indexes_the_codebook() {
+        let mut block = half::f16::from_f32(0.5).to_le_bytes().to_vec();
+        block.extend((0..16u8).map(|j| j | (15 - j) << 4));
+        let out = TensorStore::dequant_iq4_nl_row(&block);
+        assert_eq!(out[0], -63.5);
+        assert_eq!(out[15], 56.5);
+        assert_eq!(out[16], 56.5, "high nibbles are elements 16..32");
+        assert_eq!(out[31], -63.5);
+        assert!(crate::audit::tensor_stats(crate::tensor::GGML_IQ4_NL, &block).is_some());
+        assert!(crate::audit::tensor_stats(crate::tensor::GGML_IQ2_XXS, &[0; 66]).is_none());
+    }
+
+    #[test]
+    fn quant_kernels_match_cpu_dequant() {
+        use crate::gpu::Gpu;
+        use crate::tensor::{GGML_IQ4_NL, GGML_Q2_K, GGML_Q3_K};
+        let Ok(gpu) = Gpu::new() else {
+            eprintln!("skipping quant kernel test: no Metal device");
+            return;
+        };
+        let (rows, cols, batch) = (3, 512, 2);
+        let mut seed = 7u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            seed >> 8
+        };
+        let x: Vec<f32> = (0..batch * cols).map(|_| (next() % 200) as f32 / 100.0 - 1.0).collect();
+        let mut q2 = Vec::new();
+        let mut q3 = Vec::new();
+        let mut iq4 = Vec::new();
+        for _ in 0..rows * cols / 32 {
+            iq4.extend(half::f16::from_f32(0.01).to_le_bytes());
+            iq4.extend((0..16).map(|_| next() as u8));
+        }
+        for _ in 0..rows * cols / 256 {
+            let scales = std::array::from_fn(|_| next() as u8);
+            q2.extend(make_q2_k_block(0.01, 0.02, scales, &std::array::from_fn(|_| (next() % 4) as u8)));
+            let scales = std::array::from_fn(|_| (next() % 64) as i8 - 32);
+            q3.extend(make_q3_k_block(0.01, scales, &std::array::from_fn(|_| (next() % 8) as i8 - 4)));
+        }
+        for (kind, bytes, dequant) in [
+            (GGML_Q2_K, q2, TensorStore::dequant_q2_k_row as fn(&[u8]) -> Vec<f32>),
+            (GGML_Q3_K, q3, TensorStore::dequant_q3_k_row),
+            (GGML_IQ4_NL, iq4, TensorStore::dequant_iq4_nl_row),
+        ] {
+            let w = dequant(&bytes);
+            let out = gpu.quant_matmul(kind, &gpu.buf_from_bytes(&bytes), 0, &gpu.buf_from_f32(&x), rows, cols, batch).unwrap();
+            let got = gpu.read_f32(&out, rows * batch);
+            for t in 0..batch {
+                for r in 0..rows {
+                    let expected = crate::cpu::dot(&w[r * cols..][..cols], &x[t * cols..][..cols]);
+                    let g = got[t * rows + r];
+                    assert!((g - expected).abs() < 1e-3 * expected.abs().max(1.0), "kind {kind} t{t} r{r}: {g} vs {expected}");
+                }
+            }
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    // GGUF parser: version/endianness layouts
+    // -------------------------------------------------------------------------
+
+    fn tiny_gguf(version: u32, big_endian: bool) -> Vec<u8> {
+        tiny_gguf_with(version, big_endian, None, 0)
+    }
+
+    /// One arch key (plus an optional `general.alignment`), one 32×2 Q8_0
+    /// tensor at `tensor_offset` and no tensor data.
+    fn tiny_gguf_with(version: u32, big_endian: bool, alignment: Option<u32>, tensor_offset: u64) -> Vec<u8> {
+        let mut b = GgufBuilder::new().version(version).architecture("llama");
+        if big_endian {
+            b = b.big_endian();
+        }
+        if let Some(a) = alignment {
+            b = b.alignment(a);
+        }
+        b.tensor_info("token_embd.weight", &[32, 2], 8, tensor_offset).build()
+    }
+
+    #[test]
+    fn gguf_parses_v1_narrow_fields() {
+        let bytes = tiny_gguf(1, false);
+        let f = crate::gguf::GgufFile::parse(&bytes).unwrap();
+        assert_eq!(f.version, crate::gguf::GgufVersion::V1);
+        assert_eq!(f.architecture(), "llama");
+        assert_eq!(f.tensors[0].shape, vec![32, 2]);
+        assert_eq!(f.data_start % 32, 0);
+        assert!(f.data_start >= bytes.len() as u64);
+    }
+
+    #[test]
+    fn gguf_parses_v2_and_big_endian_v3() {
+        let v2 = crate::gguf::GgufFile::parse(&tiny_gguf(2, false)).unwrap();
+        assert_eq!(v2.version, crate::gguf::GgufVersion::V2);
+        let v3 = crate::gguf::GgufFile::parse(&tiny_gguf(3, true)).unwrap();
+        assert_eq!(v3.endian, crate::gguf::Endian::Big);
+        assert_eq!(v3.tensors[0].name, "token_embd.weight");
+        assert_eq!(v3.tensors[0].kind, 8);
+        assert!(v2.ensure_little_endian_data().is_ok());
+
+        // The header parses, but the weights would be read byte-swapped.
+        let file = crate::test_support::TempGguf::new("big-endian", &GgufBuilder::new().big_endian().tensor_f32("w", &[2], &[1.0, 2.0]).build());
+        let err = format!("{:#}", crate::audit::audit(file.path_str()).err().unwrap());
+        assert!(err.contains("big-endian tensor data is not supported"), "{err}");
+    }
+
+    #[test]
+    fn gguf_rejects_unknown_version_and_truncation() {
+        let mut bytes = tiny_gguf(3, false);
+        let err = crate::gguf::GgufFile::parse(&bytes[..bytes.len() - 4]).unwrap_err();
+        assert!(format!("{err:#}").contains("unexpected end"), "{err:#}");
+        bytes[4] = 9;
+        assert!(crate::gguf::GgufFile::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn gguf_data_start_follows_unusual_alignments() {
+        for align in [1u32, 8, 64, 4096] {
+            let bytes = tiny_gguf_with(3, false, Some(align), align as u64);
+            let f = crate::gguf::GgufFile::parse(&bytes).unwrap();
+            assert_eq!(f.alignment, align as u64);
+            assert_eq!(f.data_start % align as u64, 0, "align {align}");
+            assert!(f.data_start >= bytes.len() as u64);
+            assert!(f.data_start < bytes.len() as u64 + align as u64);
+
+            let index = crate::tensor::index_tensors(&f).unwrap();
+            let meta = &index["token_embd.weight"];
+            assert_eq!(meta.file_offset, f.data_start + align as u64);
+            assert_eq!(meta.byte_size, 2 * 34);
+        }
+    }
+
+    #[test]
+    fn gguf_rejects_bad_alignment_and_misaligned_offsets() {
+        let err = crate::gguf::GgufFile::parse(&tiny_gguf_with(3, false, Some(48), 0)).unwrap_err();
+        assert!(err.to_string().contains("power of two"), "{err}");
+        let err = crate::gguf::GgufFile::parse(&tiny_gguf_with(3, false, Some(64), 32)).unwrap_err();
+        assert!(err.to_string().contains("not a multiple"), "{err}");
+    }
+
+    #[test]
+    fn truncated_gguf_reports_the_missing_byte_range() {
+        use crate::repair::{Source, check, read_manifest, write_manifest};
+        let header = tiny_gguf(3, false);
+        let data_start = crate::gguf::GgufFile::parse(&header).unwrap().data_start;
+        let path = std::env::temp_dir().join(format!("llmetal-truncated-{}.gguf", std::process::id()));
+        let mut bytes = header.clone();
+        bytes.resize(data_start as usize + 30, 0);
+        std::fs::write(&path, &bytes).unwrap();
+        let t = check(path.to_str().unwrap()).unwrap().expect("the tensor needs 68 bytes, 30 are there");
+        assert_eq!((t.file_len, t.expected_len), (data_start + 30, data_start + 68));
+        assert_eq!((t.damaged, t.first_damaged.as_str(), t.source.as_deref()), (1, "token_embd.weight", None));
+        let msg = t.to_string();
+        assert!(msg.contains(&format!("bytes {}..{}", data_start + 30, data_start + 68)) && msg.contains("--url"), "{msg}");
+
+        bytes.resize(data_start as usize + 68, 0);
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(check(path.to_str().unwrap()).unwrap(), None);
+        std::fs::remove_file(&path).unwrap();
+
+        let manifest = std::env::temp_dir().join(format!("llmetal-models-{}/models.json", std::process::id()));
+        assert!(read_manifest(&manifest).unwrap().is_empty(), "a missing manifest has no entries");
+        let models = [("m.gguf".to_string(), Source { url: "https://example.com/m.gguf".into(), size: Some(68) })].into();
+        write_manifest(&manifest, &models).unwrap();
+        assert_eq!(read_manifest(&manifest).unwrap(), models);
+        std::fs::remove_dir_all(manifest.parent().unwrap()).unwrap();
+
+        // Refused before curl runs: neither touches the output path.
+        for url in ["file:///etc/passwd", "-o/tmp/x", "ftp://example.com/m.gguf"] {
+            let err = crate::repair::resume(path.to_str().unwrap(), url).unwrap_err().to_string();
+            assert!(err.contains("not an http:// or https:// URL"), "{url}: {err}");
+        }
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn gguf_builder_round_trips_every_metadata_type() {
+        use crate::gguf::{Endian, GgufFile, MetaValue::*};
+        let values = [
+            U8(200), I8(-3), U16(60_000), I16(-300), U32(4_000_000_000), I32(-70_000), F32(1.5), Bool(true),
+            Str("h\u{e9}".into()), U64(1 << 40), I64(-(1 << 40)), F64(0.125),
+            Array(vec![U32(1), U32(2)]), Array(vec![]),
+        ];
+        for (version, big_endian) in [(1, false), (2, false), (3, false), (3, true)] {
+            let mut b = GgufBuilder::new().version(version).architecture("llama");
+            if big_endian {
+                b = b.big_endian();
+            }
+            for (i, v) in values.iter().enumerate() {
+                b = b.meta(&format!("test.k{i}"), v.clone());
+            }
+            let f = GgufFile::parse(&b.build()).unwrap();
+            assert_eq!(f.endian, if big_endian { Endian::Big } else { Endian::Little });
+            assert_eq!(f.architecture(), "llama");
+            for (i, v) in values.iter().enumerate() {
+                let got = &f.metadata[&format!("test.k{i}")];
+                match v {
+                    Array(a) if a.is_empty() => assert_eq!(got.as_array().map(<[_]>::len), Some(0)),
+                    v => assert_eq!(got, v, "v{version} key {i}"),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gguf_builder_lays_out_tensors_the_loader_and_dequantizers_read() {
+        use crate::gguf_loader::GgufModelInfo;
+        use crate::test_support::{TempGguf, f16, q8_0};
+        let values: Vec<f32> = (0..64).map(|i| (i as f32 - 32.0) / 8.0).collect();
+        let (_, q8_decoded) = q8_0(&values);
+        let (_, f16_decoded) = f16(&values[..5]);
+        let bytes = GgufBuilder::new()
+            .architecture("llama")
+            .alignment(64)
+            .meta_u32("llama.embedding_length", 32)
+            .meta_strings("tokenizer.ggml.tokens", &["<unk>", "<s>", "</s>"])
+            .tensor_q8_0("token_embd.weight", &[32, 2], &values)
+            .tensor_f16("odd.weight", &[5], &values[..5])
+            .tensor_f32("output_norm.weight", &[3], &[1.0, 2.0, 3.0])
+            .build();
+
+        let f = crate::gguf::GgufFile::parse(&bytes).unwrap();
+        assert_eq!(f.alignment, 64);
+        assert_eq!(f.data_start % 64, 0);
+        let offsets: Vec<u64> = f.tensors.iter().map(|t| t.offset).collect();
+        assert_eq!(offsets, [0, 128, 192], "68 bytes of Q8_0, then 10 of F16, each on a 64-byte boundary");
+        let index = crate::tensor::index_tensors(&f).unwrap();
+        let slice = |name: &str| {
+            let m = &index[name];
+            &bytes[m.file_offset as usize..][..m.byte_size as usize]
+        };
+        assert_eq!(TensorStore::dequant_q8_0_row(slice("token_embd.weight")), q8_decoded);
+        assert!(q8_decoded.iter().zip(&values).all(|(d, v)| (d - v).abs() < 0.02));
+        assert_eq!(TensorStore::dequant_f16_row(slice("odd.weight")), f16_decoded);
+        assert_eq!(slice("output_norm.weight"), [1.0f32, 2.0, 3.0].map(f32::to_le_bytes).concat());
+        assert_eq!(bytes.len() as u64, f.data_start + 192 + 12, "no padding after the last tensor");
+
+        let file = TempGguf::new("builder", &bytes);
+        let info = GgufModelInfo::load(file.path_str()).unwrap();
+        assert_eq!(info.vocab, ["<unk>", "<s>", "</s>"]);
+        assert_eq!((info.tensor_count, info.architecture.hidden_size), (3, Some(32)));
+        let path = file.path().to_path_buf();
+        drop(file);
+        assert!(!path.exists(), "the temp file goes with the guard");
+    }
+
+    #[test]
+    fn gguf_reader_rejects_sizes_and_offsets_that_overflow() {
+        use crate::gguf::GgufFile;
+        let huge = GgufBuilder::new().tensor_info("w", &[u64::MAX / 2, 4], crate::tensor::GGML_F32, 0).build();
+        let err = GgufFile::parse(&huge).unwrap_err().to_string();
+        assert!(err.contains("'w'"), "{err}");
+
+        let wide = GgufBuilder::new().tensor_info("w", &[1 << 62], crate::tensor::GGML_F32, 0).build();
+        let err = crate::tensor::index_tensors(&GgufFile::parse(&wide).unwrap()).unwrap_err().to_string();
+        assert!(err.contains("too large"), "{err}");
+        let far = GgufBuilder::new().tensor_info("w", &[4], crate::tensor::GGML_F32, u64::MAX - 31).build();
+        let err = crate::tensor::index_tensors(&GgufFile::parse(&far).unwrap()).unwrap_err().to_string();
+        assert!(err.contains("out of range"), "{err}");
+
+        let zero_heads = GgufBuilder::new()
+            .architecture("llama")
+            .meta_u32("llama.embedding_length", 64)
+            .meta_u32("llama.attention.head_count", 0)
+            .build();
+        let info = crate::gguf_loader::GgufModelInfo::from_gguf("x", &GgufFile::parse(&zero_heads).unwrap());
+        assert_eq!(info.architecture.head_dim, None);
+    }
+
+    #[test]
+    fn gguf_limits_reject_oversized_claims_before_allocating() {
+        use crate::gguf::{GgufFile, GgufLimits};
+        let mut claim = b"GGUF".to_vec();
+        claim.extend(3u32.to_le_bytes());
+        claim.extend((1u64 << 60).to_le_bytes());
+        claim.extend(0u64.to_le_bytes());
+        let err = GgufFile::parse(&claim).unwrap_err().to_string();
+        assert!(err.contains("1152921504606846976 tensors") && err.contains("max_tensor_count"), "{err}");
+
+        let bytes = GgufBuilder::new()
+            .architecture("llama")
+            .meta_strings("tokenizer.ggml.tokens", &["a", "b", "c"])
+            .tensor_f32("w", &[2, 2], &[1.0; 4])
+            .build();
+        assert!(GgufFile::parse(&bytes).is_ok());
+        let limits = GgufLimits::default();
+        for (tight, field) in [
+            (GgufLimits { max_string_len: 4, ..limits }, "max_string_len"),
+            (GgufLimits { max_array_len: 2, ..limits }, "max_array_len"),
+            (GgufLimits { max_kv_count: 1, ..limits }, "max_kv_count"),
+            (GgufLimits { max_tensor_count: 0, ..limits }, "max_tensor_count"),
+            (GgufLimits { max_dims: 1, ..limits }, "max_dims"),
+        ] {
+            let err = format!("{:#}", GgufFile::parse_with_limits(&bytes, &tight).unwrap_err());
+            assert!(err.contains(field), "{field}: {err}");
+        }
+    }
+
+    #[test]
+    fn fuzz_entry_points_survive_truncated_and_corrupted_files() {
+        use crate::fuzz::{metadata_from_bytes, parse_from_bytes, tensor_info_from_bytes};
+        let bytes = GgufBuilder::new()
+            .architecture("llama")
+            .meta_u32("llama.embedding_length", 4)
+            .meta_u32("llama.attention.head_count", 2)
+            .meta_strings("tokenizer.ggml.tokens", &["a", "b"])
+            .tensor_f32("w", &[4], &[1.0, 2.0, 3.0, 4.0])
+            .build();
+        assert_eq!(parse_from_bytes(&bytes).unwrap().tensors.len(), 1);
+        for end in 0..bytes.len() {
+            let _ = parse_from_bytes(&bytes[..end]);
+        }
+        // Every byte of the header and tables, set to values that make
+        // lengths, counts and types absurd.
+        let tables = parse_from_bytes(&bytes).unwrap().data_start as usize;
+        for i in 0..tables {
+            for v in [0x00, 0x01, 0x7f, 0x80, 0xff] {
+                let mut b = bytes.clone();
+                b[i] = v;
+                let _ = parse_from_bytes(&b);
+            }
+        }
+
+        assert!(metadata_from_bytes(&[0]).is_err());
+        // One little-endian v3 u32 entry: key "k", type 4, value 7.
+        let mut entry = vec![0, 1];
+        entry.extend(1u64.to_le_bytes());
+        entry.push(b'k');
+        entry.extend(4u32.to_le_bytes());
+        entry.extend(7u32.to_le_bytes());
+        assert_eq!(metadata_from_bytes(&entry).unwrap(), 1);
+        assert!(metadata_from_bytes(&entry[..entry.len() - 1]).is_err());
+        assert!(tensor_info_from_bytes(&[0, 255, 1, 2, 3]).is_err());
+        assert_eq!(tensor_info_from_bytes(&[3, 0]).unwrap(), 0);
+    }
+
+    // -------------------------------------------------------------------------
+    // Weight table → typed layers
+    // -------------------------------------------------------------------------
+
+    fn tiny_arch() -> crate::model::Arch {
+        crate::model::Arch {
+            hidden: 64,
+            n_layers: 1,
+            n_heads: 4,
+            n_kv_heads: 2,
+            head_dim: 16,
+            ffn_hidden: 128,
+            vocab_size: 10,
+            ctx_train: 4096,
+            rope_base: 10000.0,
+            rope_scaling: crate::model::RopeScaling::None,
+            scaling: crate::model::ScoreScaling::default(),
+        }
+    }
+
+    /// Tensor index for `tiny_arch()`, shapes in GGUF order ([cols, rows]).
+    fn tiny_index() -> std::collections::HashMap<String, crate::tensor::TensorMeta> {
+        // Typed Q8_0, like the weights of a quantized model.
+        let mut index = crate::test_support::meta_index(&[
+            ("token_embd.weight", &[64, 10]),
+            ("output_norm.weight", &[64]),
+            ("blk.0.attn_norm.weight", &[64]),
+            ("blk.0.attn_q.weight", &[64, 64]),
+            ("blk.0.attn_k.weight", &[64, 32]),
+            ("blk.0.attn_v.weight", &[64, 32]),
+            ("blk.0.attn_output.weight", &[64, 64]),
+            ("blk.0.ffn_norm.weight", &[64]),
+            ("blk.0.ffn_gate.weight", &[64, 128]),
+            ("blk.0.ffn_up.weight", &[64, 128]),
+            ("blk.0.ffn_down.weight", &[128, 64]),
+        ]);
+        index.values_mut().for_each(|m| m.kind = crate::tensor::GGML_Q8_0);
+        index
+    }
+
+    #[test]
+    fn weights_resolve_layers_and_tie_output_head() {
+        let w = crate::weights::ModelWeights::from_index(&tiny_index(), &tiny_arch()).unwrap();
+        assert_eq!(w.layers.len(), 1);
+        assert_eq!(w.layers[0].attn_k.rows, 32);
+        assert_eq!(w.layers[0].ffn_down.cols, 128);
+        assert_eq!(w.output.name, "token_embd.weight", "no output.weight → tied head");
+        assert!(w.tied_head());
+
+        let mut index = tiny_index();
+        index.insert("output.weight".into(), index["token_embd.weight"].clone());
+        let w = crate::weights::ModelWeights::from_index(&index, &tiny_arch()).unwrap();
+        assert!(!w.tied_head());
+        assert_eq!(w.output.name, "output.weight");
+    }
+
+    #[test]
+    fn matmul_weights_cover_head_and_every_projection() {
+        let w = crate::weights::ModelWeights::from_index(&tiny_index(), &tiny_arch()).unwrap();
+        let names: Vec<&str> = w.matmul_weights().iter().map(|w| w.name.as_str()).collect();
+        assert_eq!(names.len(), 1 + 7);
+        assert_eq!(names[0], "token_embd.weight");
+        assert!(names.contains(&"blk.0.ffn_down.weight"));
+        assert!(!names.iter().any(|n| n.contains("norm")));
+    }
+
+    #[test]
+    fn weights_report_every_missing_or_misshaped_tensor() {
+        let mut index = tiny_index();
+        index.remove("blk.0.ffn_up.weight");
+        index.get_mut("blk.0.attn_v.weight").unwrap().shape = vec![64, 48];
+        let err = crate::weights::ModelWeights::from_index(&index, &tiny_arch()).unwrap_err().to_string();
+        assert!(err.contains("missing blk.0.ffn_up.weight"), "{err}");
+        assert!(err.contains("blk.0.attn_v.weight is 48x64, expected 32x64"), "{err}");
+
+        let mut index = tiny_index();
+        index.get_mut("output_norm.weight").unwrap().shape.clear();
+        let err = crate::weights::ModelWeights::from_index(&index, &tiny_arch()).unwrap_err().to_string();
+        assert!(err.contains("tensor 'output_norm.weight' has no dimensions"), "{err}");
+        assert_eq!(index["output_norm.weight"].cols(), 1);
+    }
+
+    #[test]
+    fn fusion_stacks_qkv_and_gate_up_and_splits_the_output_back() {
+        use crate::fusion::{layer_fusions, split_rows};
+        let w = crate::weights::ModelWeights::from_index(&tiny_index(), &tiny_arch()).unwrap();
+        let stacks = layer_fusions(0, &w.layers[0]);
+        let shapes: Vec<_> = stacks.iter().map(|f| (f.name.as_str(), f.rows, f.cols)).collect();
+        assert_eq!(shapes, [("blk.0.attn_qkv", 128, 64), ("blk.0.ffn_gate_up", 256, 64)]);
+        assert_eq!(crate::profile::weight_scope(&stacks[0].name), (Some(0), "attn_qkv"));
+        let (part, row) = stacks[0].locate(100);
+        assert_eq!((part.name.as_str(), row), ("blk.0.attn_v.weight", 4));
+
+        // A v projection of another dtype keeps Q/K/V apart.
+        let mut index = tiny_index();
+        index.get_mut("blk.0.attn_v.weight").unwrap().kind = 1;
+        let w = crate::weights::ModelWeights::from_index(&index, &tiny_arch()).unwrap();
+        let names: Vec<_> = layer_fusions(0, &w.layers[0]).into_iter().map(|f| f.name).collect();
+        assert_eq!(names, ["blk.0.ffn_gate_up"]);
+
+        // Two tokens of a [2 | 1] stack.
+        let parts = split_rows(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 1]);
+        assert_eq!(parts, [vec![1.0, 2.0, 4.0, 5.0], vec![3.0, 6.0]]);
+    }
+
+    fn tiny_encoder_arch() -> crate::bert::EncoderArch {
+        crate::bert::EncoderArch {
+            hidden: 64,
+            n_layers: 1,
+            n_heads: 4,
+            ffn_hidden: 128,
+            vocab_size: 10,
+            layer_norm_eps: 1e-12,
+            rope_base: None,
+            pooling: crate::embed::Pooling::Cls,
+        }
+    }
+
+    /// BERT tensor table for `tiny_encoder_arch()`, with a reranker head.
+    fn tiny_encoder_index() -> std::collections::HashMap<String, crate::tensor::TensorMeta> {
+        let t = crate::test_support::meta;
+        let mut index = crate::test_support::meta_index(&[
+            ("token_embd.weight", &[64, 10]),
+            ("token_types.weight", &[64, 2]),
+            ("position_embd.weight", &[64, 512]),
+            ("token_embd_norm.weight", &[64]),
+            ("token_embd_norm.bias", &[64]),
+            ("cls.weight", &[64, 64]),
+            ("cls.bias", &[64]),
+            ("cls.output.weight", &[64, 1]),
+        ]);
+        for (name, shape) in [
+            ("attn_q", vec![64, 64]), ("attn_k", vec![64, 64]), ("attn_v", vec![64, 64]),
+            ("attn_output", vec![64, 64]), ("ffn_up", vec![64, 128]), ("ffn_down", vec![128, 64]),
+        ] {
+            index.insert(format!("blk.0.{name}.bias"), t(&shape[1..]));
+            index.insert(format!("blk.0.{name}.weight"), t(&shape));
+        }
+        for norm in ["attn_output_norm", "layer_output_norm"] {
+            index.insert(format!("blk.0.{norm}.weight"), t(&[64]));
+            index.insert(format!("blk.0.{norm}.bias"), t(&[64]));
+        }
+        index
+    }
+
+    #[test]
+    fn encoder_weights_resolve_bert_and_fused_qkv() {
+        use crate::weights::{EncoderWeights, Qkv};
+        let w = EncoderWeights::from_index(&tiny_encoder_index(), &tiny_encoder_arch()).unwrap();
+        assert!(matches!(w.layers[0].qkv, Qkv::Split { .. }));
+        assert!(w.layers[0].ffn_gate.is_none() && w.layers[0].ffn_up.bias.is_some());
+        assert_eq!(w.position_embd.as_ref().unwrap().rows, 512);
+        assert_eq!(w.cls_output.as_ref().unwrap().weight.rows, 1);
+
+        // nomic-bert: fused QKV, gated FFN, no positions or biases.
+        let mut index = tiny_encoder_index();
+        index.retain(|n, _| !n.starts_with("blk.0.attn_q") && !n.starts_with("blk.0.attn_k") && !n.starts_with("blk.0.attn_v"));
+        index.remove("position_embd.weight");
+        let t = crate::test_support::meta;
+        index.insert("blk.0.attn_qkv.weight".into(), t(&[64, 192]));
+        index.insert("blk.0.ffn_gate.weight".into(), t(&[64, 128]));
+        let w = EncoderWeights::from_index(&index, &tiny_encoder_arch()).unwrap();
+        assert!(matches!(&w.layers[0].qkv, Qkv::Fused(l) if l.weight.rows == 192 && l.bias.is_none()));
+        assert!(w.layers[0].ffn_gate.is_some() && w.position_embd.is_none());
+
+        index.remove("blk.0.layer_output_norm.bias");
+        let err = EncoderWeights::from_index(&index, &tiny_encoder_arch()).unwrap_err().to_string();
+        assert!(err.contains("missing blk.0.layer_output_norm.bias"), "{err}");
+    }
+
+    // -------------------------------------------------------------------------
+    // Repack, shader and autotune caches
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn repack_cache_splits_scales_from_quants_and_round_trips_its_index() {
+        use crate::repack::{read_index, write_cache};
+        // Two Q8_0 blocks: scale bytes [s, s+1], quants all = block index + 10.
+        let mut tensor = Vec::new();
+        for b in 0..2u8 {
+            tensor.extend_from_slice(&[0xA0 + b, 0xB0 + b]);
+            tensor.extend_from_slice(&[10 + b; 32]);
+        }
+        let path = std::env::temp_dir().join(format!("llmetal-repack-{}.lmrp", std::process::id()));
+        write_cache(&path, 42, &[("w", &tensor)]).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let index = read_index(&bytes, 42).unwrap();
+        let t = index["w"];
+        assert_eq!(t.n_blocks, 2);
+        assert_eq!(t.quants_offset() % 16, 0);
+        let s = t.scales_offset() as usize;
+        assert_eq!(&bytes[s..s + 4], &[0xA0, 0xB0, 0xA1, 0xB1]);
+        let q = t.quants_offset() as usize;
+        assert!(bytes[q..q + 32].iter().all(|&v| v == 10));
+        assert!(bytes[q + 32..q + 64].iter().all(|&v| v == 11));
+
+        let err = read_index(&bytes, 7).unwrap_err().to_string();
+        assert!(err.contains("another model"), "{err}");
+
+        // The entry's offset and block count sit right after its 1-byte name.
+        let entry = 4 + 4 + 8 + 4 + 4 + 1;
+        for (field, value) in [(entry, u64::MAX - 255), (entry + 8, u64::MAX / 2), (entry, 3)] {
+            let mut corrupt = bytes.clone();
+            corrupt[field..field + 8].copy_from_slice(&value.to_le_bytes());
+            let err = read_index(&corrupt, 42).unwrap_err().to_string();
+            assert!(err.contains("out of bounds"), "{err}");
+        }
+        let mut long_name = bytes.clone();
+        long_name[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_index(&long_name, 42).unwrap_err().to_string().contains("truncated"));
+    }
+
+    #[test]
+    fn repack_model_key_tells_fine_tunes_with_one_header_apart() {
+        use crate::repack::model_key;
+        let header = b"GGUF same table";
+        let (small, big) = (vec![1u8; 100], vec![2u8; 64 << 10]);
+        let key = model_key(header, 1 << 20, &[&small, &big]);
+        assert_eq!(key, model_key(header, 1 << 20, &[&small, &big]));
+        assert_ne!(key, model_key(header, 1 << 21, &[&small, &big]));
+        let mut tuned = small.clone();
+        tuned[50] ^= 1;
+        assert_ne!(key, model_key(header, 1 << 20, &[&tuned, &big]));
+        // A large tensor is sampled at both ends and the middle.
+        for at in [0, 32 << 10, (64 << 10) - 1] {
+            let mut tuned = big.clone();
+            tuned[at] ^= 1;
+            assert_ne!(key, model_key(header, 1 << 20, &[&small, &tuned]), "byte {at}");
+        }
+    }
+
+    #[test]
+    fn shader_cache_keys_change_with_the_kernels_and_the_os() {
+        use crate::shader_cache::{slug, source_key};
+        assert_eq!(slug("Apple M2 Pro"), "apple-m2-pro");
+        assert_eq!(slug("AMD Radeon Pro 5500M (x2)"), "amd-radeon-pro-5500m-x2");
+        let key = source_key("kernel void f() {}", "23F79");
+        assert_eq!(key, source_key("kernel void f() {}", "23F79"));
+        assert_ne!(key, source_key("kernel void g() {}", "23F79"));
+        assert_ne!(key, source_key("kernel void f() {}", "24A335"));
+    }
+
+    #[test]
+    fn section_cache_keys_on_the_model_and_the_tokens() {
+        use crate::section_cache::{SectionCache, SectionStats, key};
+        assert_eq!(key(1, &[5, 6, 7]), key(1, &[5, 6, 7]));
+        assert_ne!(key(1, &[5, 6, 7]), key(2, &[5, 6, 7]));
+        assert_ne!(key(1, &[5, 6, 7]), key(1, &[5, 6]));
+        assert_ne!(key(1, &[1, 0]), key(1, &[256]), "ids hash as whole u32s");
+
+        let mut cache = SectionCache::new(1 << 20);
+        assert!(cache.get(1, &[5, 6, 7]).is_none());
+        cache.insert(1, crate::model::KvSession::default());
+        assert!(cache.get(1, &[]).is_some());
+        assert!(cache.get(2, &[]).is_none(), "another model's section");
+        assert_eq!(cache.stats(), SectionStats { sections: 1, bytes: 0, hits: 1, misses: 2 });
+    }
+
+    #[test]
+    fn autotune_tries_every_width_and_keeps_the_fastest() {
+        use crate::autotune::{DEFAULT_THREADGROUP, REPS, Tuner, candidates, key};
+        use std::time::Duration;
+        let path = std::env::temp_dir().join(format!("llmetal-autotune-{}.json", std::process::id()));
+        let shape = key("q8_0_matvec", 4096, 4096, 1);
+        assert_eq!(shape, "q8_0_matvec n4096 k4096 b1");
+        assert_eq!(key("q8_0_matmul", 8, 8, 5), "q8_0_matmul n8 k8 b8");
+
+        let mut tuner = Tuner::load(Some(path.clone()));
+        assert_eq!(tuner.choose(&shape, 1024), (DEFAULT_THREADGROUP, false), "off until enabled");
+        tuner.enabled = true;
+        let mut tried = Vec::new();
+        for _ in 0..candidates(1024).len() * REPS {
+            let (width, trial) = tuner.choose(&shape, 1024);
+            assert!(trial);
+            tried.push(width);
+            // 128 is fastest; its timings also vary run to run.
+            let micros = if width == 128 { 50 + tried.len() as u64 } else { 100 + width as u64 };
+            tuner.observe(&shape, width, Duration::from_micros(micros), 1024).unwrap();
+        }
+        tried.dedup();
+        assert_eq!(tried, candidates(1024));
+        assert_eq!(tuner.choose(&shape, 1024), (128, false));
+        assert_eq!(candidates(256), vec![64, 128, 256]);
+
+        let reloaded = Tuner::load(Some(path.clone()));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.choose(&shape, 1024), (128, false), "picks persist without tuning");
+    }
+
+    // -------------------------------------------------------------------------
+    // Tensor audit
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn audit_stats_count_nan_inf_and_skip_them_in_moments() {
+        let vals = [1.0f32, 3.0, f32::NAN, f32::INFINITY, -2.0];
+        let bytes: Vec<u8> = vals.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let s = crate::audit::tensor_stats(0, &bytes).unwrap();
+        assert_eq!((s.count, s.nan, s.inf), (5, 1, 1));
+        assert_eq!((s.min, s.max), (-2.0, 3.0));
+        assert!((s.mean - 2.0 / 3.0).abs() < 1e-9);
+        assert!((s.std - (38.0f64 / 9.0).sqrt()).abs() < 1e-9);
+        assert!(s.is_broken());
+    }
+
+    #[test]
+    fn audit_stats_dequantize_q8_0_and_reject_unknown_types() {
+        // scale 0.5 (f16 0x3800), quants 0..32 → values 0, 0.5, ..., 15.5
+        let mut block = vec![0x00, 0x38];
+        block.extend((0..32).map(|q| q as u8));
+        let s = crate::audit::tensor_stats(8, &block).unwrap();
+        assert_eq!((s.count, s.min, s.max), (32, 0.0, 15.5));
+        assert!((s.mean - 7.75).abs() < 1e-9);
+        assert!(!s.is_broken());
+        assert!(crate::audit::tensor_stats(12, &[0; 144]).is_none(), "Q4_K has no CPU dequantizer yet");
+    }
+
+    // -------------------------------------------------------------------------
+    // Activation dumps
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn activation_dump_round_trips_and_finds_first_divergence() {
+        use crate::dump::{ActivationDump, compare};
+        let mut a = ActivationDump::default();
+        a.record("attn_norm-0", &[1.0, 2.0], 2);
+        a.record("attn_norm-0", &[3.0, 4.0], 2);   // second token appends a row
+        a.record("l_out-0", &[10.0, f32::NAN], 2);
+
+        let dir = std::env::temp_dir().join(format!("llmetal-dump-{}", std::process::id()));
+        a.write(&dir).unwrap();
+        let read = ActivationDump::read(&dir).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read.tensors().len(), 2);
+        assert_eq!(read.tensors()[0].rows(), 2);
+        assert!(compare(&a, &read).iter().all(|d| d.max_abs == 0.0));
+
+        let mut b = ActivationDump::default();
+        b.record("attn_norm-0", &[1.0, 2.0, 3.0, 4.0], 2);
+        b.record("l_out-0", &[10.5, f32::NAN], 2);
+        let diffs = compare(&a, &b);
+        assert_eq!(diffs[0].max_abs, 0.0);
+        assert_eq!(diffs[1].name, "l_out-0");
+        assert!((diffs[1].rel - 0.05).abs() < 1e-6, "NaN on both sides counts as agreement");
+    }
+
+    #[test]
+    fn reference_logits_are_read_in_each_format() {
+        use crate::dump::{ActivationDump, read_logits};
+        let base = std::env::temp_dir().join(format!("llmetal-logits-{}", std::process::id()));
+        std::fs::create_dir_all(&base).unwrap();
+
+        let raw = base.join("logits.bin");
+        std::fs::write(&raw, [1.0f32, 2.0, 3.0, 4.0, 5.0, 6.0].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>()).unwrap();
+        assert_eq!(read_logits(&raw, 3).unwrap(), vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]]);
+        assert!(read_logits(&raw, 4).is_err(), "six values are not whole rows of four");
+
+        let indexed = base.join("logits.txt");
+        std::fs::write(&indexed, "0: 0.5\n1: -1.25\n2: 3\n").unwrap();
+        assert_eq!(read_logits(&indexed, 3).unwrap(), vec![vec![0.5, -1.25, 3.0]]);
+        std::fs::write(&indexed, "1 2\n3 4\n").unwrap();
+        assert_eq!(read_logits(&indexed, 2).unwrap().len(), 2);
+
+        let mut d = ActivationDump::default();
+        d.record("result_output", &[7.0, 8.0], 2);
+        d.write(&base.join("dump")).unwrap();
+        assert_eq!(read_logits(&base.join("dump"), 2).unwrap(), vec![vec![7.0, 8.0]]);
+        assert!(read_logits(&base.join("dump"), 3).is_err());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn logit_diff_reports_positions_and_the_first_divergent_tensor() {
+        use crate::dump::{ActivationDump, compare_logits, first_divergent};
+        let ours = vec![vec![0.0, 1.0, 0.0], vec![2.0, 0.0, 0.0], vec![0.0, 0.0, 1.0]];
+        let reference = vec![vec![2.0, 0.1, 0.0], vec![0.0, 0.0, 1.0]];
+        let diffs = compare_logits(&ours, &reference).unwrap();
+        assert_eq!(diffs.iter().map(|d| d.pos).collect::<Vec<_>>(), [1, 2], "a shorter reference covers the last positions");
+        assert!((diffs[0].max_abs - 0.1).abs() < 1e-6 && (diffs[0].rel - 0.05).abs() < 1e-6);
+        assert_eq!((diffs[0].top_ours, diffs[0].top_reference), (0, 0));
+        assert_eq!(diffs[1].max_abs, 0.0);
+        assert!(diffs[1].kl.abs() < 1e-9);
+        assert!(compare_logits(&ours[..1], &reference).is_err());
+        assert!(compare_logits(&ours, &[vec![0.0; 4]]).is_err(), "row widths differ");
+
+        let (mut r, mut o) = (ActivationDump::default(), ActivationDump::default());
+        r.record("attn_norm-0", &[1.0, 1.0, 1.0, 1.0], 2);
+        o.record("attn_norm-0", &[1.0, 1.0, 1.0, 1.0], 2);
+        r.record("l_out-0", &[1.0, 1.0, 2.0, 2.0], 2);
+        o.record("l_out-0", &[1.0, 1.0, 2.0, 2.5], 2);
+        r.record("only_in_reference", &[0.0, 0.0, 0.0, 0.0], 2);
+        assert!(first_divergent(&r, &o, 0, 1e-3).is_none(), "token 0 agrees everywhere");
+        let t = first_divergent(&r, &o, 1, 1e-3).unwrap();
+        assert_eq!(t.name, "l_out-0");
+        assert_eq!((t.max_abs, t.rel), (0.5, 0.25));
+    }
+
+    // -------------------------------------------------------------------------
+    // Embeddings
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn embed_pools_and_normalises() {
+        use crate::embed::{Pooling, l2_normalize, pool};
+        let hidden = [1.0, 2.0, 3.0, 6.0];   // two tokens, dim 2
+        assert_eq!(pool(&hidden, 2, Pooling::Mean), vec![2.0, 4.0]);
+        assert_eq!(pool(&hidden, 2, Pooling::Last), vec![3.0, 6.0]);
+        assert_eq!(pool(&hidden, 2, Pooling::Cls), vec![1.0, 2.0]);
+        let mut v = vec![3.0, 4.0];
+        l2_normalize(&mut v);
+        assert_eq!(v, vec![0.6, 0.8]);
+        assert!("max".parse::<Pooling>().is_err());
+    }
+
+    #[test]
+    fn embed_parses_documents_and_writes_npy() {
+        use crate::embed::{Document, parse_document, write_npy};
+        assert_eq!(
+            parse_document(r#"{"id": 7, "text": "hi"}"#).unwrap(),
+            Document { id: Some("7".into()), text: "hi".into() }
+        );
+        assert_eq!(parse_document(r#""plain""#).unwrap().text, "plain");
+        assert!(parse_document(r#"{"body": "x"}"#).is_err());
+
+        let path = std::env::temp_dir().join(format!("llmetal-embed-{}.npy", std::process::id()));
+        write_npy(&path, &[vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]]).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!((10 + header_len) % 64, 0);
+        let header = std::str::from_utf8(&bytes[10..10 + header_len]).unwrap();
+        assert!(header.contains("'shape': (2, 3)") && header.ends_with('\n'), "{header}");
+        assert_eq!(bytes.len(), 10 + header_len + 6 * 4);
+        assert_eq!(&bytes[10 + header_len..][..4], &1.0f32.to_le_bytes());
+    }
+
+    #[test]
+    fn vector_index_round_trips_and_hnsw_finds_the_exact_neighbours() {
+        use crate::embed::{Document, Pooling};
+        use crate::sampler::Rng;
+        use crate::search::VectorIndex;
+        let mut rng = Rng::new(7);
+        let rows: Vec<Vec<f32>> = (0..400).map(|_| (0..16).map(|_| rng.next_f32() - 0.5).collect()).collect();
+        let docs = (0..rows.len()).map(|i| Document { id: Some(format!("d{i}")), text: format!("doc {i}") }).collect();
+        let mut index = VectorIndex::new(docs, &rows).unwrap();
+        index.pooling = Some(Pooling::Cls);
+
+        let hits = index.search(&rows[42], 3).unwrap();
+        assert_eq!(hits[0].index, 42);
+        assert!((hits[0].score - 1.0).abs() < 1e-5 && hits[0].score >= hits[1].score, "{hits:?}");
+        assert!(index.search(&[1.0], 3).is_err());
+
+        let path = std::env::temp_dir().join(format!("llmetal-index-{}.idx", std::process::id()));
+        index.write(&path).unwrap();
+        let mut loaded = VectorIndex::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((loaded.len(), loaded.dim, loaded.pooling), (400, 16, Some(Pooling::Cls)));
+        assert_eq!(loaded.docs[5], Document { id: Some("d5".into()), text: "doc 5".into() });
+        assert_eq!(loaded.vector(9), index.vector(9));
+
+        // Sizes from a corrupt header are checked before anything is allocated.
+        let magic = b"LLMVIDX1";
+        for (header, want) in [
+            (r#"{"dim":4611686018427387904,"docs":[{},{},{},{}]}"#.to_string(), "overflow"),
+            (r#"{"dim":1000000000,"docs":[{}]}"#.to_string(), "shorter than its header"),
+        ] {
+            let mut bytes = magic.to_vec();
+            bytes.extend_from_slice(&(header.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(header.as_bytes());
+            std::fs::write(&path, &bytes).unwrap();
+            let err = format!("{:#}", VectorIndex::load(&path).err().unwrap());
+            assert!(err.contains(want), "{err}");
+        }
+        let mut bytes = magic.to_vec();
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(format!("{:#}", VectorIndex::load(&path).err().unwrap()).contains("longer than the file"));
+        std::fs::remove_file(&path).unwrap();
+
+        // Recall@10 of the graph against the exact scan.
+        loaded.build_hnsw(8, 64);
+        let mut found = 0;
+        for q in rows.iter().step_by(20) {
+            let exact: Vec<_> = index.search(q, 10).unwrap().into_iter().map(|h| h.index).collect();
+            found += loaded.search(q, 10).unwrap().iter().filter(|h| exact.contains(&h.index)).count();
+        }
+        assert!(found >= 180, "HNSW found {found} of 200 exact neighbours");
+    }
+
+    #[test]
+    fn rag_chunks_overlap_and_fill_the_template() {
+        use crate::embed::Document;
+        use crate::rag::{DEFAULT_TEMPLATE, chunk_documents, chunk_text, render_prompt};
+        use crate::search::Hit;
+        assert_eq!(chunk_text("a b c d e f g", 3, 1), vec!["a b c", "c d e", "e f g"]);
+        assert_eq!(chunk_text("a b c d", 3, 5), vec!["a b c", "b c d"]);
+        assert!(chunk_text("  \n ", 3, 1).is_empty());
+
+        let docs = [Document { id: Some("faq".into()), text: "one two three".into() }, Document { id: None, text: "four".into() }];
+        let chunks = chunk_documents(&docs, 2, 0);
+        let ids: Vec<_> = chunks.iter().map(|c| c.id.as_deref().unwrap()).collect();
+        assert_eq!(ids, vec!["faq#0", "faq#1", "1#0"]);
+
+        let hits = [Hit { index: 2, score: 0.9 }, Hit { index: 0, score: 0.5 }];
+        let prompt = render_prompt(DEFAULT_TEMPLATE, "which {context}?", &chunks, &hits);
+        assert!(prompt.contains("[1] (1#0) four\n\n[2] (faq#0) one two\n\nQuestion: which {context}?"), "{prompt}");
+    }
+
+    #[test]
+    fn encoder_math_layer_norm_gelu_and_neox_rope() {
+        use crate::bert::{gelu, layer_norm, rope_neox};
+        let out = layer_norm(&[1.0, 2.0, 3.0, 4.0], &[1.0; 4], &[0.5; 4], 1e-12);
+        let mean = out.iter().sum::<f32>() / 4.0;
+        let var = out.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / 4.0;
+        assert!((mean - 0.5).abs() < 1e-6 && (var - 1.0).abs() < 1e-5, "{out:?}");
+
+        assert_eq!(gelu(0.0), 0.0);
+        assert!((gelu(1.0) - 0.8412).abs() < 1e-3);
+        assert!(gelu(-6.0).abs() < 1e-6);
+
+        // NeoX pairs dim i with i + head_dim/2: rotating [1, 0, 0, 0] by pos 1
+        // moves mass into dim 2, not dim 1.
+        let mut x = vec![1.0, 0.0, 0.0, 0.0];
+        rope_neox(&mut x, 1, 4, 1, 10000.0);
+        assert!((x[0] - 1f32.cos()).abs() < 1e-6 && (x[2] - 1f32.sin()).abs() < 1e-6 && x[1] == 0.0, "{x:?}");
+    }
+
+    // -------------------------------------------------------------------------
+    // Reranking
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn rerank_finds_yes_no_and_ranks_best_first() {
+        use crate::rerank::{RerankScore, find_token, rank, sigmoid};
+        let vocab: Vec<String> = ["<unk>", "<s>", "</s>", "Ġno", "yes"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(find_token(&vocab, &["yes", "Ġyes"]), Some(4));
+        assert_eq!(find_token(&vocab, &["no", "Ġno"]), Some(3));
+        assert_eq!(find_token(&vocab, &["maybe"]), None);
+        assert_eq!(sigmoid(0.0), 0.5);
+
+        let score = |index, logit| RerankScore { index, logit, relevance: sigmoid(logit) };
+        let ranked = rank(vec![score(0, -1.0), score(1, 2.0), score(2, 0.5), score(3, 2.0)]);
+        assert_eq!(ranked.iter().map(|s| s.index).collect::<Vec<_>>(), vec![1, 3, 2, 0]);
+    }
+
+    #[test]
+    fn rerank_pair_tokens_follow_the_head() {
+        use crate::rerank::{RerankHead, pair_tokens, yes_no_prompt};
+        use crate::tokenizer::PromptTokenizer;
+        let vocab: Vec<String> = ["<unk>", "<s>", "</s>", "Ġcat", "Ġdog"].iter().map(|s| s.to_string()).collect();
+        let tokenizer = PromptTokenizer::new(vocab);
+        let head = RerankHead::Classifier { weight: vec![0.0; 4], bias: None };
+        assert_eq!(pair_tokens(&tokenizer, &head, "", "cat", "dog"), vec![1, 3, 2, 4]);
+
+        let prompt = yes_no_prompt("Find pets", "cat", "dog");
+        assert!(prompt.contains("<Instruct>: Find pets\n<Query>: cat\n<Document>: dog<|im_end|>"), "{prompt}");
+        assert!(prompt.ends_with("<|im_start|>assistant\n<think>\n\n</think>\n\n"));
+    }
+
+    #[test]
+    fn classify_names_labels_and_normalises_scores() {
+        use crate::classify::{ClassifierHead, labels, predictions};
+        use crate::gguf::MetaValue;
+        use std::collections::BTreeMap;
+        let mut meta = BTreeMap::new();
+        meta.insert("general.architecture".to_string(), MetaValue::Str("qwen2".into()));
+        assert_eq!(labels(&meta, 2), vec!["LABEL_0", "LABEL_1"]);
+        let names = vec![MetaValue::Str("safe".into()), MetaValue::Str("unsafe".into())];
+        meta.insert("qwen2.classifier.output_labels".to_string(), MetaValue::Array(names));
+        assert_eq!(labels(&meta, 2), vec!["safe", "unsafe"]);
+        assert_eq!(labels(&meta, 3).len(), 3, "a label list of the wrong length is ignored");
+
+        let head = ClassifierHead { weight: vec![1.0, 0.0, 0.0, 1.0], bias: Some(vec![0.0, 1.0]), labels: labels(&meta, 2) };
+        assert_eq!(head.logits(&[2.0, 0.5]), vec![2.0, 1.5]);
+
+        let p = predictions(&[0.0, 2.0, 1.0], &["a".into(), "b".into(), "c".into()], false);
+        assert_eq!(p.iter().map(|p| p.label.as_str()).collect::<Vec<_>>(), vec!["b", "c", "a"]);
+        assert!((p.iter().map(|p| p.probability).sum::<f32>() - 1.0).abs() < 1e-6);
+        let multi = predictions(&[0.0, 0.0], &head.labels, true);
+        assert!(multi.iter().all(|p| p.probability == 0.5));
+        assert_eq!(predictions(&[0.0], &[], false)[0].label, "LABEL_0");
+    }
+
+    // -------------------------------------------------------------------------
+    // Quant quality comparison
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn kl_divergence_is_zero_for_shifted_logits_and_matches_closed_form() {
+        use crate::quality::kl_divergence;
+        assert!(kl_divergence(&[1.0, 2.0, 3.0], &[11.0, 12.0, 13.0]).abs() < 1e-9);
+        // P = (0.5, 0.5), Q = softmax(0, ln 3) = (0.25, 0.75).
+        let expected = 0.5 * (0.5f64 / 0.25).ln() + 0.5 * (0.5f64 / 0.75).ln();
+        assert!((kl_divergence(&[0.0, 0.0], &[0.0, 3f32.ln()]) - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn logit_agreement_counts_top1_matches_and_merges() {
+        use crate::quality::LogitAgreement;
+        let mut a = LogitAgreement::default();
+        a.add(&[0.0, 5.0], &[0.0, 4.0]);
+        a.add(&[5.0, 0.0], &[0.0, 5.0]);
+        assert_eq!((a.positions, a.top1_matches), (2, 1));
+        let mut b = LogitAgreement::default();
+        b.add(&[1.0, 0.0], &[1.0, 0.0]);
+        b.merge(&a);
+        assert_eq!(b.positions, 3);
+        assert!((b.top1_agreement() - 2.0 / 3.0).abs() < 1e-12);
+        assert!(b.kl_max > 1.0 && b.mean_kl() < b.kl_max);
+    }
+
+    // -------------------------------------------------------------------------
+    // Importance matrix
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn imatrix_accumulates_mean_squares_and_round_trips() {
+        use crate::imatrix::Imatrix;
+        let mut m = Imatrix::default();
+        m.record("blk.0.attn_q.weight", &[1.0, 2.0, 3.0, 4.0], 2);
+        m.end_chunk();
+        m.record("blk.0.attn_q.weight", &[0.0, 2.0], 2);
+        m.record("blk.0.ffn_down.weight", &[3.0], 1);
+        m.end_chunk();
+        let q = &m.entries["blk.0.attn_q.weight"];
+        assert_eq!((q.count, q.ncall), (3, 2));
+        assert_eq!(q.importance(), vec![10.0 / 3.0, 8.0]);
+        assert_eq!(m.entries["blk.0.ffn_down.weight"].ncall, 1);
+        m.dataset = "calibration.txt".into();
+
+        let path = std::env::temp_dir().join(format!("llmetal-imatrix-{}.dat", std::process::id()));
+        m.write(&path).unwrap();
+        let back = Imatrix::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!((back.chunks, back.dataset.as_str()), (2, "calibration.txt"));
+        for (name, e) in &m.entries {
+            let got = back.entries[name].importance();
+            assert!(got.iter().zip(e.importance()).all(|(a, b)| (a - b).abs() < 1e-5), "{name}: {got:?}");
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    // Sealed model files
+    // -------------------------------------------------------------------------
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn envelope_matches_the_gcm_spec_vectors() {
+        use crate::envelope::encrypt_in_place;
+        // Test cases 13 and 14: zero key and nonce.
+        let mut empty = [];
+        assert_eq!(encrypt_in_place(&[0; 32], &[0; 12], &[], &mut empty).unwrap().to_vec(), unhex("530f8afbc74536b9a963b4f1c4cb738b"));
+        let mut block = [0u8; 16];
+        let tag = encrypt_in_place(&[0; 32], &[0; 12], &[], &mut block).unwrap();
+        assert_eq!(block.to_vec(), unhex("cea7403d4d606b6e074ec5d3baf39d18"));
+        assert_eq!(tag.to_vec(), unhex("d0d1c8a799996bf0265b98b5d48ab919"));
+
+        // Test case 16: associated data and a partial last block.
+        let key: [u8; 32] = unhex("feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308").try_into().unwrap();
+        let nonce: [u8; 12] = unhex("cafebabefacedbaddecaf888").try_into().unwrap();
+        let mut data = unhex(
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+             1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+        );
+        let tag = encrypt_in_place(&key, &nonce, &unhex("feedfacedeadbeeffeedfacedeadbeefabaddad2"), &mut data).unwrap();
+        assert_eq!(
+            data,
+            unhex(
+                "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa\
+                 8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662"
+            )
+        );
+        assert_eq!(tag.to_vec(), unhex("76fc6ece0f4e1768cddf8853bb2d551b"));
+    }
+
+    #[test]
+    fn envelope_round_trips_and_rejects_tampering() {
+        use crate::envelope::{is_sealed, open, parse_key, seal};
+        let key = parse_key(&"0f".repeat(32)).unwrap();
+        let (plain, _) = golden_gguf();
+        let sealed = seal(&plain, &key, &[7; 12]).unwrap();
+        assert!(is_sealed(&sealed) && !is_sealed(&plain));
+        assert_eq!(open(&sealed, &key).unwrap(), plain);
+
+        let mut flipped = sealed.clone();
+        flipped[40] ^= 1;
+        assert!(open(&flipped, &key).is_err());
+        let mut header = sealed.clone();
+        header[9] ^= 1; // the nonce
+        assert!(open(&header, &key).is_err());
+        assert!(open(&sealed, &parse_key(&"0e".repeat(32)).unwrap()).is_err());
+        assert!(parse_key("abc").is_err());
+        // 64 bytes, but not 64 digits: must not slice inside the "é".
+        assert!(parse_key(&format!("a\u{e9}{}", "0".repeat(61))).is_err());
+        assert!(parse_key(&format!("+f{}", "0".repeat(62))).is_err());
+    }
+
+    // -------------------------------------------------------------------------
+    // Signed manifests
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn sha512_matches_known_digests() {
+        use crate::ed25519::{Sha512, sha512};
+        assert_eq!(
+            sha512(b"abc").to_vec(),
+            unhex(
+                "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                 2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+            )
+        );
+        // Streaming across block boundaries agrees with one shot.
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7) as u8).collect();
+        let mut h = Sha512::default();
+        for chunk in data.chunks(111) {
+            h.update(chunk);
+        }
+        assert_eq!(h.finish(), sha512(&data));
+    }
+
+    #[test]
+    fn ed25519_matches_rfc8032_and_rejects_forgeries() {
+        use crate::ed25519::{public_key, sign, verify};
+        let cases = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+        ];
+        for (seed, pk, msg, sig) in cases {
+            let seed: [u8; 32] = unhex(seed).try_into().unwrap();
+            let pk: [u8; 32] = unhex(pk).try_into().unwrap();
+            let (msg, sig) = (unhex(msg), unhex(sig));
+            assert_eq!(public_key(&seed), pk);
+            assert_eq!(sign(&seed, &msg).to_vec(), sig);
+            let sig: [u8; 64] = sig.try_into().unwrap();
+            assert!(verify(&pk, &msg, &sig));
+            assert!(!verify(&pk, b"other", &sig));
+            let mut bad = sig;
+            bad[5] ^= 1;
+            assert!(!verify(&pk, &msg, &bad));
+        }
+    }
+
+    #[test]
+    fn manifest_verifies_and_names_the_tampered_tensor() {
+        use crate::manifest::{sign, verify};
+        let (bytes, _) = golden_gguf();
+        let dir = std::env::temp_dir().join(format!("llmetal-manifest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (model, manifest) = (dir.join("m.gguf"), dir.join("m.gguf.manifest.json"));
+        let model_str = model.to_str().unwrap();
+        std::fs::write(&model, &bytes).unwrap();
+        let seed = [3u8; 32];
+        std::fs::write(&manifest, sign(model_str, &seed).unwrap().to_string()).unwrap();
+        let pk = crate::ed25519::public_key(&seed);
+        verify(model_str, &manifest, &pk).unwrap();
+        assert!(verify(model_str, &manifest, &crate::ed25519::public_key(&[4; 32])).is_err());
+
+        // The last byte belongs to the last tensor's data.
+        let mut tampered = bytes.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        std::fs::write(&model, &tampered).unwrap();
+        let err = verify(model_str, &manifest, &pk).unwrap_err().to_string();
+        assert!(err.contains("differs from the manifest"), "{err}");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_hashes_a_file_that_ends_before_its_data_start() {
+        let bytes = GgufBuilder::new().meta_str("general.name", "x").build();
+        // No tensors, so no padding is written up to the aligned data start.
+        assert!(crate::gguf::GgufFile::parse(&bytes).unwrap().data_start > bytes.len() as u64);
+        let file = crate::test_support::TempGguf::new("manifest-short", &bytes);
+        let (_, tensors) = crate::manifest::hash_model(file.path_str()).unwrap();
+        assert!(tensors.is_empty());
+    }
+
+    #[test]
+    fn required_manifest_checks_the_mapping_loaders_read() {
+        use crate::manifest::{require, sign};
+        let (bytes, _) = golden_gguf();
+        let dir = std::env::temp_dir().join(format!("llmetal-require-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (model, manifest) = (dir.join("m.gguf"), dir.join("m.gguf.manifest.json"));
+        let model_str = model.to_str().unwrap();
+        std::fs::write(&model, &bytes).unwrap();
+        let seed = [5u8; 32];
+        std::fs::write(&manifest, sign(model_str, &seed).unwrap().to_string()).unwrap();
+        assert!(require(model_str, &manifest, &crate::ed25519::public_key(&[6; 32])).is_err());
+        require(model_str, &manifest, &crate::ed25519::public_key(&seed)).unwrap();
+        assert_eq!(&crate::envelope::map(model_str).unwrap()[..], &bytes[..]);
+
+        // Swapped after the signature check: the load itself sees it.
+        let mut tampered = bytes.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let swap = dir.join("swap.gguf");
+        std::fs::write(&swap, &tampered).unwrap();
+        std::fs::rename(&swap, &model).unwrap();
+        let err = crate::envelope::map(model_str).unwrap_err().to_string();
+        assert!(err.contains("differs from the manifest"), "{err}");
+        assert!(crate::gguf_loader::GgufModelInfo::load(model_str).is_err());
+        assert!(crate::envelope::map_unchecked(model_str).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // -------------------------------------------------------------------------
+    // Daemon protocol
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn daemon_frames_round_trip_and_reject_truncation() {
+        use crate::daemon::{read_frame, write_frame};
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &serde_json::json!({ "op": "info" })).unwrap();
+        write_frame(&mut buf, &serde_json::json!({ "op": "generate", "prompt": "hi" })).unwrap();
+        assert_eq!(u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize, r#"{"op":"info"}"#.len());
+
+        let mut r = &buf[..];
+        assert_eq!(read_frame(&mut r).unwrap().unwrap()["op"], "info");
+        assert_eq!(read_frame(&mut r).unwrap().unwrap()["prompt"], "hi");
+        assert!(read_frame(&mut r).unwrap().is_none());
+
+        // Cut mid-body: an error, not a clean end of stream.
+        let mut cut = &buf[..buf.len() - 3];
+        read_frame(&mut cut).unwrap();
+        assert!(read_frame(&mut cut).is_err());
+    }
+
+    #[test]
+    fn usage_reports_totals_in_the_openai_shape() {
+        let usage = crate::events::Usage { prompt_tokens: 12, completion_tokens: 5, cached_tokens: 8 };
+        assert_eq!(usage.total_tokens(), 17);
+        assert_eq!(
+            usage.to_json(),
+            serde_json::json!({
+                "prompt_tokens": 12,
+                "completion_tokens": 5,
+                "total_tokens": 17,
+                "prompt_tokens_details": { "cached_tokens": 8 },
+            })
+        );
+    }
+
+    #[test]
+    fn daemon_request_options_round_trip() {
+        use crate::daemon::{options_from_json, options_to_json};
+        use crate::sampler::{DryConfig, LoopAction, LoopGuard, SamplerConfig, XtcConfig};
+        let opts = crate::model::GenerateOptions {
+            max_new: 9,
+            stop_tokens: vec![2, 7],
+            sampling: SamplerConfig {
+                seed: Some(42),
+                repetition_penalty: 1.1,
+                dry: Some(DryConfig { multiplier: 0.8, sequence_breakers: vec!["\n".into()], ..DryConfig::default() }),
+                xtc: Some(XtcConfig { probability: 0.5, threshold: 0.2 }),
+                temperature: 0.8,
+                top_k: 40,
+                top_p: 0.95,
+                json_object: true,
+                regex: None,
+                loop_guard: Some(LoopGuard { max_period: 8, action: LoopAction::Penalize(2.5), ..LoopGuard::default() }),
+                max_thinking: Some(300),
+                processors: Vec::new(),
+            },
+            ..crate::model::GenerateOptions::default()
+        };
+        let back = options_from_json(&options_to_json(&opts)).unwrap();
+        assert_eq!((back.max_new, back.stop_tokens, back.sampling.seed), (9, vec![2, 7], Some(42)));
+        assert_eq!(back.sampling.repetition_penalty, 1.1);
+        assert_eq!((back.sampling.temperature, back.sampling.top_k, back.sampling.top_p), (0.8, 40, 0.95));
+        assert!(back.sampling.json_object);
+        let dry = back.sampling.dry.unwrap();
+        assert_eq!((dry.multiplier, dry.sequence_breakers), (0.8, vec!["\n".to_string()]));
+        assert_eq!(back.sampling.xtc.map(|x| (x.probability, x.threshold)), Some((0.5, 0.2)));
+        assert_eq!(back.sampling.loop_guard, opts.sampling.loop_guard);
+        assert_eq!(back.sampling.max_thinking, Some(300));
+        let effort = options_from_json(&serde_json::json!({ "reasoning_effort": "low" })).unwrap();
+        assert_eq!(effort.sampling.max_thinking, Some(crate::reasoning::EFFORTS[0].1));
+
+        // Absent fields are the defaults.
+        let bare = options_from_json(&serde_json::json!({ "op": "generate" })).unwrap();
+        assert_eq!((bare.max_new, bare.sampling.dry.is_none(), bare.sampling.xtc.is_none()), (64, true, true));
+        assert_eq!((bare.sampling.temperature, bare.sampling.top_k, bare.sampling.top_p), (0.0, 0, 1.0));
+        assert!(!bare.sampling.json_object && bare.sampling.loop_guard.is_none());
+        assert!(options_from_json(&serde_json::json!({ "response_format": { "type": "json_schema" } })).is_err());
+        let stop = options_from_json(&serde_json::json!({ "loop_guard": {} })).unwrap();
+        assert_eq!(stop.sampling.loop_guard, Some(LoopGuard::default()));
+
+        let mut opts = opts;
+        opts.sampling.regex = Some(std::sync::Arc::new(crate::regex_grammar::Regex::new(r"\d{4}").unwrap()));
+        let back = options_from_json(&options_to_json(&opts)).unwrap();
+        assert_eq!(back.sampling.regex.map(|r| r.pattern().to_string()).as_deref(), Some(r"\d{4}"));
+        assert!(options_from_json(&serde_json::json!({ "response_format": { "type": "regex" } })).is_err());
+        assert!(options_from_json(&serde_json::json!({ "response_format": { "type": "regex", "pattern": "(" } })).is_err());
+    }
+
+    #[test]
+    fn daemon_limits_clamp_each_request() {
+        use crate::daemon::{Limits, options_from_json};
+        let mut opts = options_from_json(&serde_json::json!({
+            "max_tokens": 100_000, "temperature": 9.0, "top_k": 40, "top_p": 1.5, "repeat_penalty": 0.0,
+            "dry": { "multiplier": 50.0 }, "xtc": { "probability": 3.0 },
+        }))
+        .unwrap();
+        Limits { max_tokens: 256, max_temperature: 1.5, max_penalty: 2.0, ..Limits::default() }.clamp(&mut opts);
+        let s = &opts.sampling;
+        assert_eq!((opts.max_new, s.temperature, s.top_k, s.top_p), (256, 1.5, 40, 1.0));
+        assert_eq!((s.repetition_penalty, s.dry.as_ref().unwrap().multiplier, s.xtc.unwrap().probability), (1.0, 2.0, 1.0));
+
+        // Values inside the limits pass untouched.
+        let mut opts = options_from_json(&serde_json::json!({ "max_tokens": 8, "temperature": 0.7, "top_p": 0.9 })).unwrap();
+        Limits::default().clamp(&mut opts);
+        assert_eq!((opts.max_new, opts.sampling.temperature, opts.sampling.top_p), (8, 0.7, 0.9));
+    }
+
+    // -------------------------------------------------------------------------
+    // Chat templates
+    // -------------------------------------------------------------------------
+
+    fn chat_messages() -> Vec<crate::chat::Message> {
+        use crate::chat::{Message, Role};
+        vec![
+            Message::new(Role::System, "Be brief."),
+            Message::new(Role::User, "Hi"),
+            Message::new(Role::Assistant, "Hello!"),
+            Message::new(Role::User, "Bye"),
+        ]
+    }
+
+    #[test]
+    fn chat_formats_render_turns_and_generation_prompt() {
+        use crate::chat::ChatFormat;
+        let m = chat_messages();
+        assert_eq!(
+            ChatFormat::ChatMl.render(&m[..2], true),
+            "<|im_start|>system\nBe brief.<|im_end|>\n<|im_start|>user\nHi<|im_end|>\n<|im_start|>assistant\n"
+        );
+        assert_eq!(
+            ChatFormat::Llama3.render(&m[1..2], true),
+            "<|start_header_id|>user<|end_header_id|>\n\nHi<|eot_id|><|start_header_id|>assistant<|end_header_id|>\n\n"
+        );
+        assert_eq!(ChatFormat::Phi.render(&m[1..3], false), "<|user|>\nHi<|end|>\n<|assistant|>\nHello!<|end|>\n");
+        // No system role: it is folded into the first user turn only.
+        assert_eq!(ChatFormat::Mistral.render(&m, true), "[INST] Be brief.\n\nHi [/INST]Hello!</s>[INST] Bye [/INST]");
+        assert_eq!(
+            ChatFormat::Gemma.render(&m[..3], true),
+            "<start_of_turn>user\nBe brief.\n\nHi<end_of_turn>\n<start_of_turn>model\nHello!<end_of_turn>\n<start_of_turn>model\n"
+        );
+    }
+
+    #[test]
+    fn chat_render_parts_tag_message_content_by_role() {
+        use crate::chat::{ChatFormat, Role};
+        let parts = ChatFormat::Mistral.render_parts(&chat_messages()[..2], false);
+        let tagged: Vec<(&str, Option<Role>)> = parts.iter().map(|(t, r)| (t.as_str(), *r)).collect();
+        assert_eq!(
+            tagged,
+            [
+                ("[INST] ", None),
+                ("Be brief.", Some(Role::System)),
+                ("\n\n", None),
+                ("Hi", Some(Role::User)),
+                (" [/INST]", None)
+            ]
+        );
+    }
+
+    #[test]
+    fn chat_format_flag_overrides_or_detects_embedded_template() {
+        use crate::chat::{ChatFormat, resolve};
+        let qwen = "{% for message in messages %}{{'<|im_start|>' + message['role'] + '\n'}}{% endfor %}";
+        assert_eq!(resolve("auto", Some(qwen)).unwrap(), ChatFormat::ChatMl);
+        assert_eq!(resolve("gemma", Some(qwen)).unwrap(), ChatFormat::Gemma);
+        assert_eq!(ChatFormat::detect("{{ '[INST] ' + content + ' [/INST]' }}"), Some(ChatFormat::Mistral));
+        assert!(resolve("auto", None).is_err());
+        assert!(resolve("auto", Some("{{ messages }}")).is_err());
+        assert!(resolve("vicuna", None).is_err());
+        for f in ChatFormat::ALL {
+            assert_eq!(f.name().parse::<ChatFormat>().unwrap(), f);
+        }
+    }
+
+    #[test]
+    fn chat_stop_tokens_come_from_format_and_metadata() {
+        use crate::chat::{ChatFormat, stop_tokens};
+        let vocab: Vec<String> = ["<unk>", "<s>", "</s>", "<|im_end|>", "<|eot_id|>", "<|end_of_text|>"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(stop_tokens(ChatFormat::ChatMl, &vocab, &[2]), vec![3, 2]);
+        assert_eq!(stop_tokens(ChatFormat::Llama3, &vocab, &[5, 4]), vec![4, 5]);
+        assert_eq!(stop_tokens(ChatFormat::Gemma, &vocab, &[]), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn chat_history_keeps_system_prompt_through_context_shift() {
+        use crate::chat::{ChatFormat, ChatHistory, Role};
+        let turns = chat_messages()[1..].to_vec();
+        let mut h = ChatHistory { system: Some("Be brief.".into()), summary: None, turns };
+        assert_eq!(h.messages()[0].role, Role::System);
+        assert_eq!(h.messages().len(), 4);
+
+        // Budget fits the system prompt plus one turn: the oldest exchange goes.
+        let budget = ChatFormat::ChatMl.render(&h.messages()[..1], false).len() + 60;
+        let dropped = h.shift_to_fit(ChatFormat::ChatMl, |p| p.len() <= budget);
+        assert_eq!(dropped, 2);
+        assert_eq!(h.turns.len(), 1);
+        assert_eq!(h.turns[0].content, "Bye");
+        assert!(ChatFormat::ChatMl.render(&h.messages(), true).starts_with("<|im_start|>system\nBe brief."));
+
+        // Nothing fits: the latest turn still stays.
+        assert_eq!(h.shift_to_fit(ChatFormat::ChatMl, |_| false), 0);
+        assert_eq!(h.turns.len(), 1);
+    }
+
+    #[test]
+    fn chat_session_round_trips_and_undo_drops_one_exchange() {
+        use crate::chat::{ChatHistory, Role};
+        let mut h = ChatHistory { system: Some("Be brief.".into()), summary: None, turns: chat_messages()[1..].to_vec() };
+        let path = std::env::temp_dir().join(format!("llmetal-chat-{}.json", std::process::id()));
+        h.save(&path).unwrap();
+        let loaded = ChatHistory::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, h);
+
+        // Trailing user turn with no reply yet: undo removes just that turn.
+        assert!(h.undo());
+        assert_eq!(h.turns.last().unwrap().role, Role::Assistant);
+        assert!(h.undo());
+        assert!(h.turns.is_empty() && !h.undo());
+        assert_eq!(h.system.as_deref(), Some("Be brief."));
+    }
+
+    #[test]
+    fn context_budget_reserves_the_reply() {
+        use crate::budget::ContextBudget;
+        let budget = ContextBudget::new(10, 4);
+        assert_eq!(budget.prompt_limit(), 6);
+        assert!(budget.fits(6) && !budget.fits(7));
+        let mut tokens: Vec<u32> = (1..=9).collect();
+        assert_eq!(budget.truncate(&mut tokens, 1).unwrap(), 3);
+        assert_eq!(tokens, vec![1, 5, 6, 7, 8, 9], "BOS stays, the oldest text goes");
+        assert_eq!(budget.truncate(&mut tokens, 1).unwrap(), 0);
+        assert!(budget.truncate(&mut (0..9).collect(), 6).is_err());
+        assert!(budget.check(6).is_ok());
+        let err = budget.check(7).unwrap_err().to_string();
+        assert!(err.contains("7 tokens") && err.contains("leaves 6"), "{err}");
+        assert_eq!(ContextBudget::new(4, 8).prompt_limit(), 0);
+    }
+
+    #[test]
+    fn chat_summarizes_turns_that_no_longer_fit() {
+        use crate::chat::{ChatFormat, Conversation, Summarizer};
+        use crate::events::GenerationEvent;
+        use crate::model::{GenerateOptions, Generator, KvSession};
+        use crate::tokenizer::PromptTokenizer;
+
+        /// Answers every prompt with the same tokens and records prompt lengths.
+        struct Canned {
+            reply: Vec<u32>,
+            prompts: Vec<(usize, usize)>,
+        }
+        impl Generator for Canned {
+            fn generate_cached(
+                &mut self,
+                _session: &mut KvSession,
+                tokens: &[u32],
+                opts: &GenerateOptions,
+                _vocab: &[String],
+                on_event: &mut dyn FnMut(GenerationEvent),
+            ) -> anyhow::Result<usize> {
+                self.prompts.push((tokens.len(), opts.max_new));
+                for &id in &self.reply {
+                    on_event(GenerationEvent::Token { id, text: String::new(), logprob: 0.0 });
+                }
+                Ok(0)
+            }
+        }
+
+        let mut vocab: Vec<String> = ["<unk>", "<s>", "</s>", "\u{2581}"].map(String::from).to_vec();
+        vocab.extend((b'!'..=b'~').map(|c| (c as char).to_string()));
+        let tokenizer = PromptTokenizer::new(vocab.clone());
+        let reply: Vec<u32> = "ok".chars().map(|c| vocab.iter().position(|v| *v == c.to_string()).unwrap() as u32).collect();
+        let (ctx, max_new) = (800, 20);
+        let mut conv = Conversation::with_limits(ChatFormat::ChatMl, tokenizer, ctx, &[]);
+        conv.summarizer = Some(Summarizer::Model);
+        let mut model = Canned { reply, prompts: Vec::new() };
+        let opts = GenerateOptions { max_new, ..GenerateOptions::default() };
+        let mut summarized = 0;
+        for i in 0..10 {
+            conv.send(&mut model, &format!("message {i}: {}", "words ".repeat(10)), &opts, &mut |_| {}).unwrap();
+            summarized += conv.last_turn().summarized_turns;
+        }
+        assert!(summarized > 0, "ten long turns outgrow an 800-token context");
+        assert_eq!(conv.history.summary.as_deref(), Some("ok"));
+        assert!(conv.history.messages()[0].content.contains("ok"));
+        for &(prompt, reserve) in &model.prompts {
+            assert!(prompt + reserve <= ctx, "{prompt} prompt tokens + {reserve} reserved exceed {ctx}");
+        }
+
+        // A message that cannot fit even alone fails before any prefill.
+        let before = model.prompts.len();
+        assert!(conv.send(&mut model, &"x".repeat(ctx), &opts, &mut |_| {}).is_err());
+        assert_eq!(model.prompts.len(), before);
+    }
+
+    #[test]
+    fn chat_summarizes_past_a_threshold_with_a_callback() {
+        use std::sync::{Arc, Mutex};
+        use crate::chat::{ChatFormat, Conversation, Message, Summarizer};
+        use crate::model::{GenerateOptions, Generator, KvSession};
+        use crate::tokenizer::PromptTokenizer;
+
+        struct Silent;
+        impl Generator for Silent {
+            fn generate_cached(
+                &mut self,
+                _session: &mut KvSession,
+                _tokens: &[u32],
+                _opts: &GenerateOptions,
+                _vocab: &[String],
+                _on_event: &mut dyn FnMut(crate::events::GenerationEvent),
+            ) -> anyhow::Result<usize> {
+                Ok(0)
+            }
+        }
+
+        let mut vocab: Vec<String> = ["<unk>", "<s>", "</s>", "\u{2581}"].map(String::from).to_vec();
+        vocab.extend((b'!'..=b'~').map(|c| (c as char).to_string()));
+        let mut conv = Conversation::with_limits(ChatFormat::ChatMl, PromptTokenizer::new(vocab), 100_000, &[]);
+        let calls = Arc::new(Mutex::new(Vec::<(Option<String>, usize)>::new()));
+        let seen = calls.clone();
+        conv.summarizer = Some(Summarizer::Callback(Box::new(move |earlier: Option<&str>, turns: &[Message]| {
+            seen.lock().unwrap().push((earlier.map(str::to_string), turns.len()));
+            Ok(format!("note {}", seen.lock().unwrap().len()))
+        })));
+        conv.summarize_after = Some(300);
+        let opts = GenerateOptions { max_new: 16, ..GenerateOptions::default() };
+        for i in 0..12 {
+            conv.send(&mut Silent, &format!("turn {i} {}", "abc ".repeat(10)), &opts, &mut |_| {}).unwrap();
+        }
+        let calls = calls.lock().unwrap();
+        assert!(calls.len() >= 2, "a 300-token threshold is crossed more than once in 12 turns");
+        assert_eq!(calls[0].0, None);
+        assert_eq!(calls[1].0.as_deref(), Some("note 1"), "each summary folds in the one before");
+        assert!(calls.iter().all(|&(_, n)| n % 2 == 0), "whole exchanges are folded");
+        assert_eq!(conv.history.summary, Some(format!("note {}", calls.len())));
+        assert_eq!(conv.history.turns.last().unwrap().role, crate::chat::Role::Assistant);
+    }
+
+    #[test]
+    fn reply_parse_splits_reasoning_content_and_hermes_tool_calls() {
+        use crate::chat::ChatFormat;
+        use crate::reply::parse;
+        let r = parse(
+            ChatFormat::ChatMl,
+            "<think>\nThe user wants weather.\n</think>\n\nChecking.\n<tool_call>\n{\"name\": \"get_weather\", \"arguments\": {\"city\": \"Paris\"}}\n</tool_call><|im_end|>",
+        );
+        assert_eq!(r.reasoning.as_deref(), Some("The user wants weather."));
+        assert_eq!(r.content, "Checking.");
+        assert_eq!(r.tool_calls.len(), 1);
+        assert_eq!(r.tool_calls[0].name, "get_weather");
+        assert_eq!(r.tool_calls[0].arguments, serde_json::json!({ "city": "Paris" }));
+        let msg = r.to_json();
+        assert_eq!(msg["tool_calls"][0]["function"]["arguments"], "{\"city\":\"Paris\"}");
+        assert_eq!(msg["tool_calls"][0]["id"], "call_0");
+
+        // The opening tag was in the prompt; a reply cut off mid-thought.
+        assert_eq!(parse(ChatFormat::ChatMl, "plan</think>Done.").reasoning.as_deref(), Some("plan"));
+        let cut = parse(ChatFormat::ChatMl, "<think>still going");
+        assert_eq!((cut.reasoning.as_deref(), cut.content.as_str()), (Some("still going"), ""));
+
+        // Broken JSON is kept as written; plain replies pass through.
+        let broken = parse(ChatFormat::ChatMl, "a <tool_call>{oops</tool_call> b");
+        assert!(broken.tool_calls.is_empty());
+        assert_eq!(broken.content, "a <tool_call>{oops</tool_call> b");
+        let plain = parse(ChatFormat::Gemma, "Hello!");
+        assert_eq!(plain, crate::reply::AssistantReply { content: "Hello!".into(), ..Default::default() });
+        assert_eq!(plain.to_json(), serde_json::json!({ "role": "assistant", "content": "Hello!" }));
+    }
+
+    #[test]
+    fn reply_parse_reads_each_familys_tool_call_markers() {
+        use crate::chat::ChatFormat;
+        use crate::reply::parse;
+        let names = |f, text| parse(f, text).tool_calls.into_iter().map(|c| c.name).collect::<Vec<_>>();
+
+        let llama = parse(ChatFormat::Llama3, "<|python_tag|>{\"name\": \"a\", \"parameters\": {\"x\": 1}}; {\"name\": \"b\", \"parameters\": {}}");
+        assert_eq!(llama.tool_calls.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), ["a", "b"]);
+        assert_eq!(llama.tool_calls[0].arguments, serde_json::json!({ "x": 1 }));
+        assert_eq!(names(ChatFormat::Llama3, "{\"name\": \"lookup\", \"parameters\": {\"q\": \"rust\"}}"), ["lookup"]);
+        assert!(names(ChatFormat::Llama3, "{\"not\": \"a call\"}").is_empty());
+
+        let mistral = parse(ChatFormat::Mistral, "[TOOL_CALLS] [{\"name\": \"a\", \"arguments\": \"{\\\"x\\\": 2}\"}]</s>");
+        assert_eq!((mistral.content.as_str(), mistral.tool_calls[0].arguments.clone()), ("", serde_json::json!({ "x": 2 })));
+        assert_eq!(names(ChatFormat::Mistral, "[TOOL_CALLS]a[ARGS]{}[TOOL_CALLS]b[ARGS]{\"y\": 1}"), ["a", "b"]);
+
+        let phi = parse(ChatFormat::Phi, "Sure.<|tool_call|>[{\"name\": \"c\", \"arguments\": {}, \"id\": \"t1\"}]<|/tool_call|>");
+        assert_eq!((phi.content.as_str(), phi.tool_calls[0].id.as_deref()), ("Sure.", Some("t1")));
+        assert_eq!(phi.to_json()["content"], "Sure.");
+        assert!(parse(ChatFormat::Phi, "<|tool_call|>[{\"name\": \"c\"}]<|/tool_call|>").to_json()["content"].is_null());
+    }
+
+    // -------------------------------------------------------------------------
+    // Tokenizer
+    // -------------------------------------------------------------------------
+
+    fn tiny_vocab() -> Vec<String> {
+        // Simulate a tiny GPT-2/Tekken vocab.
+        // Ġ = U+0120 (space prefix).
+        vec![
+            "<unk>".to_string(),   // 0
+            "<s>".to_string(),     // 1 BOS
+            "</s>".to_string(),    // 2 EOS
+            "h".to_string(),       // 3
+            "e".to_string(),       // 4
+            "l".to_string(),       // 5
+            "o".to_string(),       // 6
+            "\u{0120}".to_string(),// 7 — lone space token
+            "\u{0120}h".to_string(),        // 8
+            "\u{0120}he".to_string(),       // 9
+            "\u{0120}hel".to_string(),      // 10
+            "\u{0120}hell".to_string(),     // 11
+            "\u{0120}hello".to_string(),    // 12
+            "hello".to_string(),   // 13
+        ]
+    }
+
+    #[test]
+    fn tokenizer_bos_prepended() {
+        let tok = PromptTokenizer::new(tiny_vocab());
+        let ids = tok.tokenize_bos("hello");
+        assert_eq!(ids[0], 1, "BOS must be first token");
+    }
+
+    #[test]
+    fn tokenizer_greedy_longest_match() {
+        let tok = PromptTokenizer::new(tiny_vocab());
+        // " hello" normalises to "Ġhello"; longest match from pos=0 should be id=12 "Ġhello"
+        let ids = tok.tokenize("hello");
+        assert_eq!(ids, vec![12], "expected Ġhello (id=12)");
+    }
+
+    #[test]
+    fn tokenizer_no_unknown_for_known_chars() {
+        let tok = PromptTokenizer::new(tiny_vocab());
+        let ids = tok.tokenize("hello");
+        assert!(!ids.contains(&u32::MAX), "no unknowns expected for vocab-covered input");
+    }
+
+    #[test]
+    fn wordpiece_splits_punctuation_and_marks_continuations() {
+        let vocab: Vec<String> = ["[PAD]", "[UNK]", "[CLS]", "[SEP]", "hello", ",", "play", "##ing", "world", "!"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let tok = PromptTokenizer::wordpiece(vocab).unwrap();
+        assert_eq!(tok.tokenize("Hello, playing"), vec![4, 5, 6, 7]);
+        assert_eq!(tok.tokenize_bos("world!"), vec![2, 8, 9, 3]);
+        assert_eq!(tok.tokenize("plays world"), vec![1, 8], "unmatched tail makes the whole word [UNK]");
+        assert_eq!(tok.sep(), Some(3));
+        assert!(PromptTokenizer::wordpiece(vec!["a".into()]).is_err());
+    }
+
+    #[test]
+    fn detokenize_maps_space_and_newline_markers() {
+        let vocab = vec!["\u{0120}hello".to_string(), "<0x0A>".to_string()];
+        assert_eq!(crate::tokenizer::detokenize(0, &vocab), " hello");
+        assert_eq!(crate::tokenizer::detokenize(1, &vocab), "\n");
+        assert_eq!(crate::tokenizer::detokenize(9, &vocab), "");
+    }
+
+    #[test]
+    fn pretokenizers_split_like_their_regexes() {
+        use crate::unicode::PreTokenizer;
+        assert_eq!(
+            PreTokenizer::Gpt2.split("Hello world's  42!?"),
+            ["Hello", " world", "'s", " ", " 42", "!?"]
+        );
+        assert_eq!(
+            PreTokenizer::Llama3.split("I'M 12345\n\nok a...\nb"),
+            ["I", "'M", " ", "123", "45", "\n\n", "ok", " a", "...\n", "b"]
+        );
+        assert_eq!(PreTokenizer::Qwen2.split("x 123"), ["x", " ", "1", "2", "3"]);
+        assert_eq!(PreTokenizer::from_name("llama-bpe"), Some(PreTokenizer::Llama3));
+        assert_eq!(PreTokenizer::from_name("tekken"), None);
+    }
+
+    #[test]
+    fn nfc_composes_decomposed_latin_and_hangul() {
+        use crate::unicode::nfc;
+        assert_eq!(nfc("cafe\u{301}"), "caf\u{e9}");
+        assert_eq!(nfc("A\u{302}\u{301}"), "\u{1EA4}", "Vietnamese marks stack");
+        assert_eq!(nfc("\u{1100}\u{1161}\u{11A8}"), "\u{AC01}");
+        assert_eq!(nfc("caf\u{e9} \u{5d0}\u{5b7}"), "caf\u{e9} \u{5d0}\u{5b7}");
+    }
+
+    #[test]
+    fn byte_level_tokenizer_matches_within_pretokens() {
+        use crate::unicode::PreTokenizer;
+        let vocab: Vec<String> = ["Hello", "\u{120}world", "\u{10A}", "\u{120}", "\u{120}1", "12", "\u{C3}\u{A9}"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let tok = PromptTokenizer::byte_level(vocab, PreTokenizer::Llama3);
+        assert_eq!(tok.tokenize("Hello world\n"), vec![0, 1, 2], "no prefix space, newline is Ċ");
+        assert_eq!(tok.tokenize(" 12"), vec![3, 5], "Ġ1 would cross the space/digit split");
+        assert_eq!(tok.tokenize("e\u{301}"), vec![6], "NFC first, then UTF-8 bytes");
+    }
+
+    #[test]
+    fn special_tokens_split_the_input_unless_literal() {
+        use crate::tokenizer::SpecialTokens::{Literal, Parse};
+        use crate::unicode::PreTokenizer;
+        let vocab: Vec<String> = ["<|im_start|>", "user", "\u{10A}", "hi", "<|", "im", "_start", "|>"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let tok = PromptTokenizer::byte_level(vocab, PreTokenizer::Llama3).with_special_tokens([0, 99]);
+        assert_eq!(tok.tokenize("<|im_start|>user\nhi"), vec![0, 1, 2, 3]);
+        assert_eq!(tok.encode(&[("<|im_start|>", Literal)]), vec![4, 5, 6, 7]);
+        assert_eq!(tok.encode_bos(&[("<|im_start|>", Parse), ("<|im_start|>", Literal)]), vec![1, 0, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn decode_renders_or_skips_special_tokens() {
+        use crate::unicode::PreTokenizer;
+        let vocab: Vec<String> = ["<|im_end|>", "Hi", "\u{120}th\u{C3}\u{A9}", "\u{10A}"].iter().map(|s| s.to_string()).collect();
+        let tok = PromptTokenizer::byte_level(vocab.clone(), PreTokenizer::Gpt2).with_special_tokens([0]);
+        assert!(tok.is_special(0) && !tok.is_special(1));
+        assert_eq!(tok.decode(&[1, 2, 3, 0], true), "Hi th\u{e9}\n");
+        assert_eq!(tok.decode(&[1, 2, 3, 0], false), "Hi th\u{e9}\n<|im_end|>");
+        let legacy = PromptTokenizer::new(vocab).with_special_tokens([0]);
+        assert_eq!(legacy.decode(&[0, 1], true), "Hi");
+        assert_eq!(legacy.decode(&[0, 1], false), "<|im_end|>Hi");
+    }
+
+    #[test]
+    fn spans_locate_each_token_in_the_decoded_text() {
+        use crate::tokenizer::TokenSpan;
+        use crate::unicode::PreTokenizer;
+        let vocab: Vec<String> = ["<|im_end|>", "Hi", "\u{120}th\u{C3}", "\u{A9}", "\u{10A}"].iter().map(|s| s.to_string()).collect();
+        let tok = PromptTokenizer::byte_level(vocab, PreTokenizer::Gpt2).with_special_tokens([0]);
+        let ids = [1, 2, 3, 4, 0];
+        let span = |id, start, end, special| TokenSpan { id, start, end, special };
+        // "é" is split over two tokens: the spans are bytes, not characters.
+        let spans = tok.spans(&ids, false);
+        assert_eq!(
+            spans,
+            vec![span(1, 0, 2, false), span(2, 2, 6, false), span(3, 6, 7, false), span(4, 7, 8, false), span(0, 8, 18, true)]
+        );
+        let text = tok.decode(&ids, false);
+        assert_eq!((&text[..2], &text[8..]), ("Hi", "<|im_end|>"));
+        assert_eq!(tok.spans(&ids, true)[4], span(0, 8, 8, true));
+        assert_eq!(tok.spans(&ids, true).last().unwrap().end, tok.decode(&ids, true).len());
+    }
+
+    // -------------------------------------------------------------------------
+    // CPU math (rms_norm, RoPE correctness smoke test)
+    // -------------------------------------------------------------------------
+
+    fn rms_norm(x: &[f32], w: &[f32], eps: f32) -> Vec<f32> {
+        let ss = x.iter().map(|v| v * v).sum::<f32>() / x.len() as f32;
+        let inv = 1.0 / (ss + eps).sqrt();
+        x.iter().zip(w.iter()).map(|(xi, wi)| xi * inv * wi).collect()
+    }
+
+    #[test]
+    fn rms_norm_unit_weights_normalises() {
+        let x = vec![3.0f32, 4.0];  // RMS = sqrt((9+16)/2) = sqrt(12.5)
+        let w = vec![1.0f32, 1.0];
+        let out = rms_norm(&x, &w, 1e-8);
+        // After norm, RMS of out should be ~1.0
+        let rms = (out.iter().map(|v| v * v).sum::<f32>() / out.len() as f32).sqrt();
+        assert!((rms - 1.0).abs() < 1e-5, "RMS should be ~1.0 after normalisation, got {rms}");
+    }
+
+    #[test]
+    fn rms_norm_scales_with_weight() {
+        let x = vec![1.0f32, 1.0, 1.0, 1.0];
+        let w = vec![2.0f32, 2.0, 2.0, 2.0];
+        let out = rms_norm(&x, &w, 1e-8);
+        // x is constant so norm(x) = x/1.0 = [1,1,1,1]; multiplied by w=2 → [2,2,2,2]
+        for v in &out {
+            assert!((v - 2.0).abs() < 1e-5, "expected 2.0, got {v}");
+        }
+    }
+
+    #[test]
+    fn rope_linear_scaling_interpolates_positions() {
+        use crate::model::{RopeScaling, rope};
+        let x: Vec<f32> = (0..8).map(|i| i as f32 * 0.25 - 1.0).collect();
+        let (mut scaled, mut plain) = (x.clone(), x.clone());
+        rope(&mut scaled, 1, 8, 6, 10000.0, RopeScaling::Linear { factor: 2.0 });
+        rope(&mut plain, 1, 8, 3, 10000.0, RopeScaling::None);
+        for (a, b) in scaled.iter().zip(&plain) {
+            assert!((a - b).abs() < 1e-5, "pos 6 at factor 2 should equal pos 3: {a} vs {b}");
+        }
+    }
+
+    #[test]
+    fn shift_rope_moves_rotated_keys_to_a_later_position() {
+        use crate::model::{RopeScaling, YarnParams, rope, shift_rope};
+        let yarn = RopeScaling::Yarn(YarnParams { factor: 4.0, original_ctx: 4096, beta_fast: 32.0, beta_slow: 1.0 });
+        let x: Vec<f32> = (0..16).map(|i| (i as f32 * 0.37).sin()).collect();
+        for scaling in [RopeScaling::None, RopeScaling::Linear { factor: 2.0 }, yarn] {
+            let (mut shifted, mut direct) = (x.clone(), x.clone());
+            rope(&mut shifted, 2, 8, 5, 10000.0, scaling);
+            shift_rope(&mut shifted, 2, 8, 7, 10000.0, scaling);
+            rope(&mut direct, 2, 8, 12, 10000.0, scaling);
+            for (a, b) in shifted.iter().zip(&direct) {
+                assert!((a - b).abs() < 1e-4, "{scaling:?}: {a} vs {b}");
+            }
+        }
+    }
+
+    /// Angle and magnitude of RoPE pair `i` applied to the unit vector (1, 0).
+    fn rope_pair(i: usize, head_dim: usize, pos: usize, scaling: crate::model::RopeScaling) -> (f32, f32) {
+        let mut x = vec![0.0f32; head_dim];
+        x[2 * i] = 1.0;
+        crate::model::rope(&mut x, 1, head_dim, pos, 10000.0, scaling);
+        (x[2 * i + 1].atan2(x[2 * i]), x[2 * i].hypot(x[2 * i + 1]))
+    }
+
+    #[test]
+    fn yarn_extrapolates_fast_dims_and_interpolates_slow_dims() {
+        use crate::model::{RopeScaling, YarnParams};
+        let yarn = |factor| RopeScaling::Yarn(YarnParams { factor, original_ctx: 4096, beta_fast: 32.0, beta_slow: 1.0 });
+
+        // factor 1 is exactly plain RoPE.
+        let (a, m) = rope_pair(10, 128, 3, yarn(1.0));
+        let (b, _) = rope_pair(10, 128, 3, RopeScaling::None);
+        assert!((a - b).abs() < 1e-6 && (m - 1.0).abs() < 1e-6);
+
+        // Fastest pair keeps its angle; slowest pair matches linear interpolation.
+        let (fast, mscale) = rope_pair(0, 128, 3, yarn(4.0));
+        assert!((fast - 3.0).abs() < 1e-5, "fast dim angle {fast}");
+        assert!(mscale > 1.0, "YaRN sharpens attention, mscale {mscale}");
+        let (slow, _) = rope_pair(63, 128, 3, yarn(4.0));
+        let (lin, _) = rope_pair(63, 128, 3, RopeScaling::Linear { factor: 4.0 });
+        assert!((slow - lin).abs() < 1e-7, "slow dim {slow} vs linear {lin}");
+    }
+
+    #[test]
+    fn score_scaling_reads_metadata_and_caps_scores() {
+        use crate::gguf::MetaValue;
+        use crate::model::{ScoreScaling, attention, attention_scaled, softcap};
+        let mut meta = std::collections::BTreeMap::new();
+        meta.insert("gemma2.attn_logit_softcapping".to_string(), MetaValue::F32(50.0));
+        meta.insert("gemma2.final_logit_softcapping".to_string(), MetaValue::F32(30.0));
+        meta.insert("gemma2.attention.scale".to_string(), MetaValue::F32(0.0));
+        let s = ScoreScaling::from_metadata(&meta, "gemma2");
+        assert_eq!(s, ScoreScaling { attn_softcap: Some(50.0), final_softcap: Some(30.0), ..ScoreScaling::default() });
+        assert_eq!(s.active(), ["attn softcap 50", "final softcap 30"]);
+        assert!(ScoreScaling::from_metadata(&meta, "llama").active().is_empty());
+
+        let mut xs = [-1e4f32, -1.0, 0.0, 2.5, 1e4];
+        softcap(&mut xs, 30.0);
+        assert!(xs.iter().all(|x| x.abs() <= 30.0) && xs[2] == 0.0);
+        assert!((xs[3] - 2.5).abs() < 0.01, "small scores pass nearly unchanged: {}", xs[3]);
+
+        // A query scale of s is the default scale with q multiplied by s * sqrt(head_dim).
+        let k: Vec<std::sync::Arc<[f32]>> = vec![[1.0f32, 0.5, -1.0, 2.0].into(), [0.0f32, 1.0, 1.0, -0.5].into()];
+        let v: Vec<std::sync::Arc<[f32]>> = vec![[1.0f32, 0.0, 0.0, 1.0].into(), [0.0f32, 2.0, 1.0, 0.0].into()];
+        let q = [0.5f32, -1.0, 2.0, 1.0];
+        let scaled = ScoreScaling { query_scale: Some(0.25), ..ScoreScaling::default() };
+        let a = attention_scaled(&q, &k, &v, 1, 1, 4, &scaled);
+        let b = attention(&q.map(|x| x * 0.25 * 2.0), &k, &v, 1, 1, 4);
+        for (a, b) in a.iter().zip(&b) {
+            assert!((a - b).abs() < 1e-6, "{a} vs {b}");
+        }
+        // A loose cap flattens nothing; a tight one pulls the mix toward the mean.
+        let loose = ScoreScaling { attn_softcap: Some(1e6), ..ScoreScaling::default() };
+        assert!(attention_scaled(&q, &k, &v, 1, 1, 4, &loose).iter().zip(attention(&q, &k, &v, 1, 1, 4)).all(|(a, b)| (a - b).abs() < 1e-5));
+        let tight = ScoreScaling { attn_softcap: Some(0.01), ..ScoreScaling::default() };
+        let flat = attention_scaled(&q, &k, &v, 1, 1, 4, &tight);
+        assert!((flat[0] - 0.5).abs() < 0.01 && (flat[1] - 1.0).abs() < 0.01, "{flat:?}");
+    }
+
+    #[test]
+    fn alibi_slopes_follow_ggml_and_replace_rope() {
+        use crate::model::{ScoreScaling, attend, attention_scaled};
+        let alibi = ScoreScaling { alibi_max_bias: Some(8.0), ..ScoreScaling::default() };
+        assert_eq!(ScoreScaling::default().alibi_slope(0, 8), None);
+        let slopes: Vec<f32> = (0..8).map(|h| alibi.alibi_slope(h, 8).unwrap()).collect();
+        assert_eq!(slopes, [0.5, 0.25, 0.125, 0.0625, 0.03125, 0.015625, 0.0078125, 0.00390625]);
+        // Past the largest power of two, the odd half-steps in between.
+        let past = alibi.alibi_slope(8, 12).unwrap();
+        assert!((past - 0.5f32.sqrt()).abs() < 1e-6 && (alibi.alibi_slope(9, 12).unwrap() - 0.125f32.sqrt()).abs() < 1e-6);
+
+        // Two equal keys: the bias favours the nearer one.
+        let k: Vec<std::sync::Arc<[f32]>> = vec![[1.0f32, 0.0].into(), [1.0f32, 0.0].into()];
+        let v: Vec<std::sync::Arc<[f32]>> = vec![[1.0f32, 0.0].into(), [0.0f32, 1.0].into()];
+        let out = attention_scaled(&[1.0, 0.0], &k, &v, 1, 1, 2, &alibi);
+        let slope = alibi.alibi_slope(0, 1).unwrap();
+        assert_eq!(slope, 1.0 / 256.0);
+        let near = 1.0 / (1.0 + (-slope).exp());
+        assert!((out[1] - near).abs() < 1e-6 && (out[0] - (1.0 - near)).abs() < 1e-6, "{out:?}");
+
+        // ALiBi keys are cached as projected, at any position.
+        let arch = crate::model::Arch { scaling: alibi, ..tiny_arch() };
+        let q = vec![0.5f32; 64];
+        let kv: Vec<f32> = (0..32).map(|i| i as f32 / 32.0).collect();
+        let (mut k_rows, mut v_rows) = (vec![[0.0f32; 32].into(); 5], vec![[0.0f32; 32].into(); 5]);
+        attend(&arch, [&q, &kv, &kv], 5, &mut k_rows, &mut v_rows);
+        assert_eq!(&*k_rows[5], kv.as_slice());
+
+        // The blocks ALiBi models pair it with are not there: refused at load.
+        for arch in ["mpt", "bloom"] {
+            let err = crate::model::ensure_supported(arch).unwrap_err().to_string();
+            assert!(err.contains("not supported"), "{err}");
+        }
+        crate::model::ensure_supported("llama").unwrap();
+    }
+
+    // -------------------------------------------------------------------------
+    // State-space models (Mamba, Jamba)
+    // -------------------------------------------------------------------------
+
+    fn tiny_ssm_arch() -> crate::ssm::SsmArch {
+        crate::ssm::SsmArch {
+            hidden: 8,
+            n_layers: 2,
+            vocab_size: 10,
+            ctx_train: 1024,
+            d_inner: 16,
+            d_state: 4,
+            d_conv: 4,
+            dt_rank: 2,
+            rms_eps: 1e-5,
+            n_heads: 2,
+            n_kv_heads: 1,
+            ffn_hidden: 32,
+        }
+    }
+
+    /// Block 0 a Mamba mixer, block 1 attention with an FFN: a Jamba in miniature.
+    fn tiny_ssm_index() -> std::collections::HashMap<String, crate::tensor::TensorMeta> {
+        crate::test_support::meta_index(&[
+            ("token_embd.weight", &[8, 10]),
+            ("output_norm.weight", &[8]),
+            ("blk.0.attn_norm.weight", &[8]),
+            ("blk.0.ssm_in.weight", &[8, 32]),
+            ("blk.0.ssm_conv1d.weight", &[4, 16]),
+            ("blk.0.ssm_conv1d.bias", &[16]),
+            ("blk.0.ssm_x.weight", &[16, 10]),
+            ("blk.0.ssm_dt.weight", &[2, 16]),
+            ("blk.0.ssm_dt.bias", &[16]),
+            ("blk.0.ssm_a", &[4, 16]),
+            ("blk.0.ssm_d", &[16]),
+            ("blk.0.ssm_out.weight", &[16, 8]),
+            ("blk.1.attn_norm.weight", &[8]),
+            ("blk.1.attn_q.weight", &[8, 8]),
+            ("blk.1.attn_k.weight", &[8, 4]),
+            ("blk.1.attn_v.weight", &[8, 4]),
+            ("blk.1.attn_output.weight", &[8, 8]),
+            ("blk.1.ffn_norm.weight", &[8]),
+            ("blk.1.ffn_gate.weight", &[8, 32]),
+            ("blk.1.ffn_up.weight", &[8, 32]),
+            ("blk.1.ffn_down.weight", &[32, 8]),
+        ])
+    }
+
+    #[test]
+    fn ssm_weights_pick_a_mixer_per_layer_and_refuse_moe() {
+        use crate::weights::{Mixer, SsmWeights};
+        let w = SsmWeights::from_index(&tiny_ssm_index(), &tiny_ssm_arch()).unwrap();
+        assert!(matches!(&w.layers[0].mixer, Mixer::Ssm(m) if m.conv1d.bias.is_some() && m.dt_norm.is_none()));
+        assert!(matches!(w.layers[1].mixer, Mixer::Attention(_)));
+        assert!(w.layers[0].ffn.is_none() && w.layers[1].ffn.is_some());
+        assert_eq!(w.output.name, "token_embd.weight");
+
+        let mut index = tiny_ssm_index();
+        index.insert("blk.1.ffn_gate_inp.weight".into(), index["blk.1.ffn_norm.weight"].clone());
+        index.get_mut("blk.0.ssm_a").unwrap().shape = vec![8, 16];
+        let err = SsmWeights::from_index(&index, &tiny_ssm_arch()).unwrap_err().to_string();
+        assert!(err.contains("blk.1 has a mixture-of-experts FFN"), "{err}");
+        assert!(err.contains("blk.0.ssm_a is 16x8, expected 16x4"), "{err}");
+    }
+
+    #[test]
+    fn ssm_conv_and_scan_steps_match_their_closed_forms() {
+        use crate::ssm::{conv_step, scan_step, softplus};
+        // Two channels, three taps: each output is the last three inputs
+        // through that channel's filter, zeros before the first.
+        let w = [1.0f32, 2.0, 3.0, -1.0, 0.0, 1.0];
+        let mut window = vec![0.0f32; 2 * 2];
+        let xs = [[1.0f32, 10.0], [2.0, 20.0], [3.0, 30.0], [4.0, 40.0]];
+        let outs: Vec<Vec<f32>> = xs.iter().map(|x| conv_step(&mut window, x, &w, Some(&[0.5, 0.0]))).collect();
+        assert_eq!(outs[0], [3.5, 10.0]);
+        assert_eq!(outs[1], [2.0 + 6.0 + 0.5, 20.0]);
+        assert_eq!(outs[3], [2.0 + 6.0 + 12.0 + 0.5, 40.0 - 20.0]);
+        assert_eq!(window, [3.0, 30.0, 4.0, 40.0]);
+
+        // No decay (a = 0): h sums dt * b * x; strong decay forgets the past.
+        let (b, c, d) = ([1.0f32, 2.0], [1.0f32, 1.0], [0.5f32]);
+        let mut h = vec![0.0f32; 2];
+        scan_step(&mut h, &[1.0], &[0.5], &[0.0, 0.0], &b, &c, &d);
+        let y = scan_step(&mut h, &[2.0], &[0.5], &[0.0, 0.0], &b, &c, &d);
+        assert_eq!(h, [1.5, 3.0]);
+        assert_eq!(y, [4.5 + 1.0]);
+        let y = scan_step(&mut h, &[0.0], &[10.0], &[-10.0, -10.0], &b, &c, &d);
+        assert!(y[0].abs() < 1e-30, "{y:?}");
+
+        assert_eq!(softplus(30.0), 30.0);
+        assert!((softplus(0.0) - 2f32.ln()).abs() < 1e-7);
+    }
+
+    #[test]
+    fn ssm_state_round_trips_through_its_file_format() {
+        use crate::ssm::SsmState;
+        let arch = tiny_ssm_arch();
+        let w = crate::weights::SsmWeights::from_index(&tiny_ssm_index(), &arch).unwrap();
+        let state = SsmState::new(&arch, &w);
+        assert_eq!(state.len_f32(), 3 * 16 + 16 * 4, "conv window and h of the one Mamba layer");
+        let mut bytes = Vec::new();
+        state.write(&mut bytes).unwrap();
+        assert_eq!(SsmState::read(&mut bytes.as_slice()).unwrap(), state);
+
+        // A shorter payload than the header declares is refused.
+        let mut short = Vec::new();
+        state.write(&mut short).unwrap();
+        short.truncate(short.len() - 4);
+        assert!(SsmState::read(&mut short.as_slice()).is_err());
+        let path = std::env::temp_dir().join(format!("llmetal-ssm-{}.state", std::process::id()));
+        state.save(&path).unwrap();
+        assert_eq!(SsmState::load(&path).unwrap(), state);
+        std::fs::remove_file(&path).unwrap();
+        assert!(SsmState::read(&mut &br#"{"format":"other"}"#[..]).is_err());
+    }
+
+    // -------------------------------------------------------------------------
+    // RWKV
+    // -------------------------------------------------------------------------
+
+    fn tiny_rwkv_arch() -> crate::rwkv::RwkvArch {
+        crate::rwkv::RwkvArch {
+            hidden: 8,
+            n_layers: 1,
+            vocab_size: 10,
+            ffn_hidden: 16,
+            ctx_train: 64,
+            head_size: 4,
+            time_mix_extra_dim: 2,
+            time_decay_extra_dim: 3,
+            layer_norm_eps: 1e-5,
+            rescale_every: 0,
+        }
+    }
+
+    fn tiny_rwkv_index() -> std::collections::HashMap<String, crate::tensor::TensorMeta> {
+        let t = crate::test_support::meta;
+        let mut index = crate::test_support::meta_index(&[
+            ("token_embd.weight", &[8, 10]),
+            ("blk.0.time_mix_lerp_x.weight", &[8, 1, 1]),
+            ("blk.0.time_mix_lerp_fused.weight", &[8, 1, 1, 5]),
+            ("blk.0.time_mix_w1.weight", &[8, 10]),
+            ("blk.0.time_mix_w2.weight", &[2, 8, 5]),
+            ("blk.0.time_mix_first.weight", &[4, 2]),
+            ("blk.0.time_mix_decay.weight", &[8, 1, 1]),
+            ("blk.0.time_mix_decay_w1.weight", &[8, 3]),
+            ("blk.0.time_mix_decay_w2.weight", &[3, 8]),
+            ("blk.0.channel_mix_lerp_k.weight", &[8, 1, 1]),
+            ("blk.0.channel_mix_lerp_r.weight", &[8, 1, 1]),
+            ("blk.0.channel_mix_key.weight", &[8, 16]),
+            ("blk.0.channel_mix_value.weight", &[16, 8]),
+        ]);
+        for name in ["key", "value", "receptance", "gate", "output"] {
+            index.insert(format!("blk.0.time_mix_{name}.weight"), t(&[8, 8]));
+        }
+        index.insert("blk.0.channel_mix_receptance.weight".into(), t(&[8, 8]));
+        for norm in ["token_embd_norm", "output_norm", "blk.0.attn_norm", "blk.0.attn_norm_2", "blk.0.time_mix_ln"] {
+            index.insert(format!("{norm}.weight"), t(&[8]));
+            index.insert(format!("{norm}.bias"), t(&[8]));
+        }
+        index
+    }
+
+    #[test]
+    fn rwkv_weights_take_fused_or_split_lerps() {
+        use crate::weights::RwkvWeights;
+        let w = RwkvWeights::from_index(&tiny_rwkv_index(), &tiny_rwkv_arch()).unwrap();
+        assert_eq!(w.layers[0].time_mix.lerp.len(), 1);
+        assert_eq!(w.output.name, "token_embd.weight");
+
+        let mut index = tiny_rwkv_index();
+        let fused = index.remove("blk.0.time_mix_lerp_fused.weight").unwrap();
+        for x in ["w", "k", "v", "r", "g"] {
+            index.insert(format!("blk.0.time_mix_lerp_{x}.weight"), crate::tensor::TensorMeta { shape: vec![8, 1, 1], ..fused.clone() });
+        }
+        let w = RwkvWeights::from_index(&index, &tiny_rwkv_arch()).unwrap();
+        assert_eq!(w.layers[0].time_mix.lerp.iter().map(|l| &l.name[..]).collect::<Vec<_>>()[1], "blk.0.time_mix_lerp_k.weight");
+
+        index.get_mut("blk.0.time_mix_first.weight").unwrap().shape = vec![8];
+        index.remove("blk.0.time_mix_ln.bias");
+        let err = RwkvWeights::from_index(&index, &tiny_rwkv_arch()).unwrap_err().to_string();
+        assert!(err.contains("blk.0.time_mix_first.weight is 1x8, expected 2x4"), "{err}");
+        assert!(err.contains("missing blk.0.time_mix_ln.bias"), "{err}");
+    }
+
+    #[test]
+    fn rwkv_wkv_step_and_token_shift_follow_the_recurrence() {
+        use crate::rwkv::{group_norm, shift, wkv6_step};
+        // One head of size 2: y = r^T (S + diag(u) k v^T), then S = diag(w) S + k v^T.
+        let (r, k, v, w, u) = ([1.0, 2.0], [0.5, 1.0], [2.0, -1.0], [0.5, 0.25], [1.0, 0.0]);
+        let mut state = vec![0.0; 4];
+        let y = wkv6_step(&mut state, &r, &k, &v, &w, &u, 2);
+        assert_eq!(y, vec![1.0, -0.5]);
+        assert_eq!(state, vec![1.0, -0.5, 2.0, -1.0]);
+        let y = wkv6_step(&mut state, &r, &k, &v, &w, &u, 2);
+        assert_eq!(y, vec![1.0 + 1.0 + 4.0, -0.5 - 0.5 - 2.0]);
+        assert_eq!(state, vec![1.5, -0.75, 2.5, -1.25]);
+
+        // Each row is shifted against the one before it, the first against
+        // the last row of the previous call.
+        let mut last = vec![1.0, 1.0];
+        assert_eq!(shift(&[2.0, 3.0, 5.0, 7.0], &mut last), vec![-1.0, -2.0, -3.0, -4.0]);
+        assert_eq!(last, vec![5.0, 7.0]);
+
+        let normed = group_norm(&[1.0, 3.0, 10.0, 30.0], &[1.0, 1.0, 2.0, 2.0], &[0.0, 0.0, 1.0, 1.0], 2, 0.0);
+        assert_eq!(normed, vec![-1.0, 1.0, -1.0, 3.0]);
+    }
+
+    // -------------------------------------------------------------------------
+    // Encoder-decoder (T5)
+    // -------------------------------------------------------------------------
+
+    fn tiny_t5_arch() -> crate::t5::T5Arch {
+        crate::t5::T5Arch {
+            hidden: 8,
+            n_enc_layers: 2,
+            n_dec_layers: 1,
+            n_heads: 2,
+            head_dim: 3,
+            ffn_hidden: 16,
+            n_buckets: 32,
+            rms_eps: 1e-6,
+            vocab_size: 10,
+            ctx_train: 512,
+            decoder_start: 0,
+            eos: 1,
+        }
+    }
+
+    fn tiny_t5_index() -> std::collections::HashMap<String, crate::tensor::TensorMeta> {
+        let t = crate::test_support::meta;
+        let mut index = crate::test_support::meta_index(&[("token_embd.weight", &[8, 10])]);
+        let mut attention = |p: &str| {
+            index.insert(format!("{p}_norm.weight"), t(&[8]));
+            for x in ["q", "k", "v"] {
+                index.insert(format!("{p}_{x}.weight"), t(&[8, 6]));
+            }
+            index.insert(format!("{p}_o.weight"), t(&[6, 8]));
+        };
+        for p in ["enc.blk.0.attn", "enc.blk.1.attn", "dec.blk.0.attn", "dec.blk.0.cross_attn"] {
+            attention(p);
+        }
+        for p in ["enc.blk.0", "enc.blk.1", "dec.blk.0"] {
+            index.insert(format!("{p}.ffn_norm.weight"), t(&[8]));
+            index.insert(format!("{p}.ffn_up.weight"), t(&[8, 16]));
+            index.insert(format!("{p}.ffn_down.weight"), t(&[16, 8]));
+        }
+        index.insert("enc.blk.0.attn_rel_b.weight".into(), t(&[2, 32]));
+        index.insert("dec.blk.0.attn_rel_b.weight".into(), t(&[2, 32]));
+        index.insert("dec.blk.0.ffn_gate.weight".into(), t(&[8, 16]));
+        index.insert("enc.output_norm.weight".into(), t(&[8]));
+        index.insert("dec.output_norm.weight".into(), t(&[8]));
+        index
+    }
+
+    #[test]
+    fn t5_weights_share_the_first_position_bias_and_pick_an_ffn() {
+        use crate::weights::T5Weights;
+        let w = T5Weights::from_index(&tiny_t5_index(), &tiny_t5_arch()).unwrap();
+        assert!(w.encoder[0].attn.rel_bias.is_some() && w.encoder[1].attn.rel_bias.is_none());
+        assert!(w.encoder.iter().all(|l| l.cross.is_none() && l.ffn.gate.is_none()));
+        assert!(matches!(&w.decoder[0].cross, Some(c) if c.rel_bias.is_none() && c.q.name == "dec.blk.0.cross_attn_q.weight"));
+        assert!(w.decoder[0].ffn.gate.is_some());
+        assert!(w.tied_head());
+
+        let mut index = tiny_t5_index();
+        index.remove("enc.blk.0.attn_rel_b.weight");
+        index.get_mut("dec.blk.0.cross_attn_o.weight").unwrap().shape = vec![8, 8];
+        let err = T5Weights::from_index(&index, &tiny_t5_arch()).unwrap_err().to_string();
+        assert!(err.contains("missing enc.blk.0.attn_rel_b.weight"), "{err}");
+        assert!(err.contains("dec.blk.0.cross_attn_o.weight is 8x8, expected 8x6"), "{err}");
+    }
+
+    #[test]
+    fn t5_relative_buckets_match_the_reference_and_bias_attention() {
+        use crate::t5::{biased_attention, position_bias, relative_bucket};
+        // Bidirectional: 16 buckets each side, 8 of them exact distances.
+        let bi: Vec<usize> = [0, -1, 1, -7, -8, -20, 20, -200].iter().map(|&r| relative_bucket(r, 32, true)).collect();
+        assert_eq!(bi, vec![0, 1, 17, 7, 8, 10, 26, 15]);
+        // Causal: keys after the query all land in bucket 0.
+        let causal: Vec<usize> = [3, 0, -5, -15, -16, -20, -1000].iter().map(|&r| relative_bucket(r, 32, false)).collect();
+        assert_eq!(causal, vec![0, 0, 5, 15, 16, 17, 31]);
+
+        // A [buckets, heads] table: bucket b of head h holds 10 * b + h.
+        let table: Vec<f32> = (0..32).flat_map(|b| [10.0 * b as f32, 10.0 * b as f32 + 1.0]).collect();
+        assert_eq!(position_bias(&table, 2, 32, 1, 3, true), vec![10.0, 0.0, 170.0, 11.0, 1.0, 171.0]);
+
+        let rows = |xs: [f32; 2]| xs.map(|x| std::sync::Arc::from(&[x][..])).to_vec();
+        let (k, v) = (rows([1.0, 1.0]), rows([2.0, 6.0]));
+        // Unscaled equal scores average the values; a bias moves the weight.
+        assert_eq!(biased_attention(&[3.0], &k, &v, 1, 1, &[0.0, 0.0]), vec![4.0]);
+        let tilted = biased_attention(&[3.0], &k, &v, 1, 1, &[0.0, 2.0_f32.ln()]);
+        assert!((tilted[0] - (2.0 + 2.0 * 6.0) / 3.0).abs() < 1e-5, "{tilted:?}");
+    }
+
+    // -------------------------------------------------------------------------
+    // Vision input (Qwen2-VL patching, M-RoPE)
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn vision_resizes_within_budget_and_patches_by_merge_group() {
+        use crate::vision::{GridThw, Image, PatchConfig, patchify, smart_resize};
+        let cfg = PatchConfig::default();
+        assert_eq!(smart_resize(1080, 1920, 28, cfg.min_pixels, cfg.max_pixels).unwrap(), (728, 1316));
+        assert_eq!(smart_resize(10, 10, 28, cfg.min_pixels, cfg.max_pixels).unwrap(), (56, 56));
+        assert_eq!(smart_resize(280, 420, 28, cfg.min_pixels, cfg.max_pixels).unwrap(), (280, 420));
+        assert!(smart_resize(3, 900, 28, cfg.min_pixels, cfg.max_pixels).is_err());
+
+        let mut ppm = b"P6\n# tiny\n4 4\n255\n".to_vec();
+        ppm.extend((0..48).map(|i| (i * 5) as u8));
+        let image = Image::parse_ppm(&ppm).unwrap();
+        assert_eq!((image.width, image.height, image.rgb[3]), (4, 4, 15));
+        assert!(Image::parse_ppm(&ppm[..ppm.len() - 1]).is_err());
+
+        let cfg = PatchConfig { patch_size: 2, merge_size: 2, temporal_patch_size: 2, min_pixels: 0, max_pixels: 1 << 20 };
+        let (patches, grid) = patchify(&image, &cfg).unwrap();
+        assert_eq!(grid, GridThw { t: 1, h: 2, w: 2 });
+        assert_eq!((grid.n_tokens(2), patches.len()), (1, 4 * 3 * 2 * 2 * 2));
+        let pixels = image.normalized(4, 4);
+        // Patch 1 is the top right square: channel 0, frame 0, row 0 first,
+        // and frame 1 repeats frame 0.
+        assert_eq!(&patches[24..26], &pixels[2..4]);
+        assert_eq!(&patches[24..28], &patches[28..32]);
+    }
+
+    #[test]
+    fn mrope_positions_place_image_tokens_on_their_grid() {
+        use crate::vision::{GridThw, mrope, mrope_positions};
+        let grid = GridThw { t: 1, h: 4, w: 4 };
+        let positions = mrope_positions(&[5, 9, 9, 9, 9, 6, 7], 9, &[grid], 2).unwrap();
+        assert_eq!(positions, vec![[0; 3], [1, 1, 1], [1, 1, 2], [1, 2, 1], [1, 2, 2], [3; 3], [4; 3]]);
+        assert!(mrope_positions(&[5, 9, 9, 6], 9, &[grid], 2).is_err());
+        assert!(mrope_positions(&[5, 6], 9, &[grid], 2).is_err());
+
+        // Text alone is NeoX RoPE.
+        let x: Vec<f32> = (0..16).map(|i| i as f32 * 0.1 - 0.7).collect();
+        let (mut a, mut b) = (x.clone(), x.clone());
+        mrope(&mut a, 2, 8, [5; 3], [1, 2, 1], 10000.0);
+        crate::bert::rope_neox(&mut b, 2, 8, 5, 10000.0);
+        assert!(a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 1e-6));
+        // Only the pairs of the row section move with the row position.
+        let mut c = x;
+        mrope(&mut c, 2, 8, [5, 6, 5], [1, 2, 1], 10000.0);
+        let moved: Vec<usize> = (0..4).filter(|&i| (c[i] - a[i]).abs() > 1e-6).collect();
+        assert_eq!(moved, vec![1, 2]);
+    }
+
+    // -------------------------------------------------------------------------
+    // Audio input (Whisper)
+    // -------------------------------------------------------------------------
+
+    fn wav(channels: u16, rate: u32, format: u16, bits: u16, data: &[u8]) -> Vec<u8> {
+        let mut b = b"RIFF\0\0\0\0WAVE".to_vec();
+        b.extend(b"LIST\x03\0\0\0abc\0");
+        b.extend(b"fmt \x10\0\0\0");
+        b.extend(format.to_le_bytes());
+        b.extend(channels.to_le_bytes());
+        b.extend(rate.to_le_bytes());
+        b.extend((rate * channels as u32 * bits as u32 / 8).to_le_bytes());
+        b.extend((channels * bits / 8).to_le_bytes());
+        b.extend(bits.to_le_bytes());
+        b.extend(b"data");
+        b.extend((data.len() as u32).to_le_bytes());
+        b.extend(data);
+        b
+    }
+
+    #[test]
+    fn whisper_reads_wav_and_builds_its_log_mel_input() {
+        use crate::whisper::{HOP, N_FFT, SAMPLE_RATE, log_mel, mel_filters, parse_wav, resample, unfold};
+        // Stereo 16-bit PCM mixes down to the mean of each frame; odd-sized
+        // chunks before it are skipped with their pad byte.
+        let pcm: Vec<u8> = [16384i16, 0, -32768, -32768].iter().flat_map(|s| s.to_le_bytes()).collect();
+        let (mono, rate) = parse_wav(&wav(2, 8000, 1, 16, &pcm)).unwrap();
+        assert_eq!((mono, rate), (vec![0.25, -1.0], 8000));
+        let float: Vec<u8> = [0.5f32, -0.5].iter().flat_map(|s| s.to_le_bytes()).collect();
+        assert_eq!(parse_wav(&wav(1, 16000, 3, 32, &float)).unwrap().0, vec![0.5, -0.5]);
+        assert!(parse_wav(&wav(1, 16000, 1, 8, &[0, 0])).is_err());
+        for rate in [0, 1, 999, 384_001] {
+            let err = parse_wav(&wav(1, rate, 1, 16, &pcm)).unwrap_err().to_string();
+            assert!(err.contains("sample rate"), "{rate}: {err}");
+        }
+        assert_eq!(parse_wav(&wav(1, 384_000, 1, 16, &pcm)).unwrap().1, 384_000);
+        assert_eq!(resample(&[0.0, 1.0, 0.0, -1.0], 8000, 16000), vec![0.0, 0.5, 1.0, 0.5, 0.0, -0.5, -1.0, -1.0]);
+
+        let filters = mel_filters(80, N_FFT, SAMPLE_RATE);
+        let bins = N_FFT / 2 + 1;
+        assert!(filters.iter().all(|w| *w >= 0.0));
+        assert!(filters.chunks_exact(bins).all(|f| f.iter().any(|w| *w > 0.0)));
+
+        // A 1 kHz tone (bin 25) is loudest in the mel band weighting bin 25 most.
+        let tone: Vec<f32> = (0..1600).map(|i| (2.0 * std::f32::consts::PI * 1000.0 * i as f32 / SAMPLE_RATE as f32).sin()).collect();
+        let mel = log_mel(&tone, &filters, 80);
+        let frames = 1600 / HOP;
+        assert_eq!(mel.len(), 80 * frames);
+        let loudest = (0..80).max_by(|&a, &b| mel[a * frames + 5].total_cmp(&mel[b * frames + 5])).unwrap();
+        let band = (0..80).max_by(|&a, &b| filters[a * bins + 25].total_cmp(&filters[b * bins + 25])).unwrap();
+        assert_eq!(loudest, band);
+        // Clamped to 8 decades below the peak, then divided by 4.
+        let (lo, hi) = mel.iter().fold((f32::MAX, f32::MIN), |(lo, hi), v| (lo.min(*v), hi.max(*v)));
+        assert!((hi - lo - 2.0).abs() < 1e-5, "{lo}..{hi}");
+
+        // Kernel-3 windows at stride 2, zero past the ends, channel by channel.
+        let x = [[1.0, 2.0, 3.0, 4.0], [10.0, 20.0, 30.0, 40.0]];
+        let cols = unfold(|c, t| x[c][t], 2, 4, 3, 2);
+        assert_eq!(cols, vec![0.0, 1.0, 2.0, 0.0, 10.0, 20.0, 2.0, 3.0, 4.0, 20.0, 30.0, 40.0]);
+    }
+
+    #[test]
+    fn whisper_weights_resolve_the_conv_front_and_both_stacks() {
+        use crate::weights::WhisperWeights;
+        let arch = crate::whisper::WhisperArch {
+            hidden: 4,
+            n_heads: 2,
+            n_enc_layers: 1,
+            n_dec_layers: 1,
+            n_mels: 3,
+            n_audio_ctx: 5,
+            n_text_ctx: 6,
+            vocab_size: 7,
+            layer_norm_eps: 1e-5,
+        };
+        let t = crate::test_support::meta;
+        let mut index = crate::test_support::meta_index(&[
+            ("enc.conv1.weight", &[3, 3, 4]),
+            ("enc.conv2.weight", &[3, 4, 4]),
+            ("enc.position_embd.weight", &[4, 5]),
+            ("dec.position_embd.weight", &[4, 6]),
+            ("token_embd.weight", &[4, 7]),
+        ]);
+        let bias = ["enc.conv1", "enc.conv2", "enc.output_norm", "dec.output_norm"];
+        let mut blocks = vec![];
+        for stack in ["enc.blk.0", "dec.blk.0"] {
+            for a in ["attn", "cross_attn"].iter().take(if stack == "dec.blk.0" { 2 } else { 1 }) {
+                for x in ["q", "k", "v", "o"] {
+                    index.insert(format!("{stack}.{a}_{x}.weight"), t(&[4, 4]));
+                }
+                blocks.extend([format!("{stack}.{a}_norm"), format!("{stack}.{a}_q"), format!("{stack}.{a}_v")]);
+            }
+            index.insert(format!("{stack}.ffn_up.weight"), t(&[4, 16]));
+            index.insert(format!("{stack}.ffn_down.weight"), t(&[16, 4]));
+            blocks.push(format!("{stack}.ffn_norm"));
+        }
+        for p in bias.iter().map(|p| p.to_string()).chain(blocks) {
+            if p.ends_with("norm") {
+                index.insert(format!("{p}.weight"), t(&[4]));
+            }
+            index.insert(format!("{p}.bias"), t(&[4]));
+        }
+        let w = WhisperWeights::from_index(&index, &arch).unwrap();
+        assert_eq!((w.conv1.weight.rows, w.conv1.weight.cols, w.conv2.weight.cols), (4, 9, 12));
+        assert!(w.encoder[0].cross.is_none() && w.decoder[0].cross.is_some());
+        assert!(w.decoder[0].attn.k.bias.is_none() && w.decoder[0].attn.q.bias.is_some());
+
+        index.get_mut("enc.conv1.weight").unwrap().shape = vec![3, 3, 8];
+        let err = WhisperWeights::from_index(&index, &arch).unwrap_err().to_string();
+        assert!(err.contains("enc.conv1.weight has 8 output channels, expected 4"), "{err}");
+    }
+
+    // -------------------------------------------------------------------------
+    // Output sinks (text to speech)
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn sentences_end_at_terminators_followed_by_whitespace() {
+        use crate::sink::Sentences;
+        let mut s = Sentences::default();
+        let mut spoken = Vec::new();
+        for piece in ["Pi is 3", ".", "14 or", " so. Really", "?! \"Yes", ".\" Done", "\nNext", " one"] {
+            spoken.extend(s.push(piece));
+        }
+        assert_eq!(spoken, vec!["Pi is 3.14 or so.", "Really?!", "\"Yes.\"", "Done"]);
+        assert_eq!(s.flush().as_deref(), Some("Next one"));
+        assert_eq!(s.flush(), None);
+        assert!(s.push("  \n").is_empty());
+    }
+
+    #[test]
+    fn command_sink_writes_a_sentence_per_line() {
+        use crate::sink::{CommandSink, OutputSink};
+        let path = std::env::temp_dir().join(format!("llmetal-tts-{}.txt", std::process::id()));
+        let mut sink = CommandSink::spawn(&format!("cat > '{}'", path.display())).unwrap();
+        for piece in ["Hello", " there. How", " are you", "?"] {
+            sink.text(piece).unwrap();
+        }
+        sink.finish().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "Hello there.\nHow are you?\n");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(CommandSink::spawn("exit 3").unwrap().finish().is_err());
+    }
+
+    // -------------------------------------------------------------------------
+    // Agent mode (tool execution loop)
+    // -------------------------------------------------------------------------
+
+    /// A model that replies with `replies` in turn, one character per token,
+    /// over the printable-ASCII vocab `chat_vocab` builds, and notes how long
+    /// each prompt was.
+    struct Scripted {
+        replies: Vec<&'static str>,
+        prompts: Vec<usize>,
+    }
+
+    fn chat_vocab() -> Vec<String> {
+        let mut vocab: Vec<String> = ["<unk>", "<s>", "</s>", "\u{2581}"].map(String::from).to_vec();
+        vocab.extend((b'!'..=b'~').map(|c| (c as char).to_string()));
+        vocab
+    }
+
+    impl crate::model::Generator for Scripted {
+        fn generate_cached(
+            &mut self,
+            _session: &mut crate::model::KvSession,
+            tokens: &[u32],
+            _opts: &crate::model::GenerateOptions,
+            vocab: &[String],
+            on_event: &mut dyn FnMut(crate::events::GenerationEvent),
+        ) -> anyhow::Result<usize> {
+            self.prompts.push(tokens.len());
+            let reply = self.replies.remove(0);
+            for c in reply.chars() {
+                let id = vocab.iter().position(|v| *v == c.to_string()).unwrap() as u32;
+                on_event(crate::events::GenerationEvent::Token { id, text: String::new(), logprob: 0.0 });
+            }
+            Ok(0)
+        }
+    }
+
+    #[test]
+    fn agent_runs_tool_calls_until_the_model_answers() {
+        use crate::agent::Agent;
+        use crate::chat::{ChatFormat, Conversation, Role};
+        use crate::model::GenerateOptions;
+        use crate::tokenizer::PromptTokenizer;
+
+        let mut conv = Conversation::with_limits(ChatFormat::ChatMl, PromptTokenizer::new(chat_vocab()), 100_000, &[]);
+        let mut agent = Agent::default();
+        agent.register("add", "Add two numbers.", serde_json::json!({"type": "object"}), |args| {
+            Ok((args["a"].as_i64().unwrap() + args["b"].as_i64().unwrap()).to_string())
+        });
+        conv.history.system = Some(agent.system_prompt(Some("Be brief.")));
+        assert!(conv.history.system.as_deref().unwrap().contains("\"name\":\"add\""));
+        let mut model = Scripted {
+            replies: vec![
+                r#"<tool_call>{"name":"add","arguments":{"a":2,"b":3}}</tool_call><tool_call>{"name":"mul","arguments":{}}</tool_call>"#,
+                "Five.",
+            ],
+            prompts: Vec::new(),
+        };
+        let mut seen = Vec::new();
+        let answer = agent
+            .run(&mut conv, &mut model, "2+3?", &GenerateOptions::default(), &mut |_| {}, &mut |run| seen.push(run.ok))
+            .unwrap();
+        assert_eq!(answer.reply.content, "Five.");
+        assert_eq!(answer.iterations, 2);
+        assert_eq!(seen, vec![true, false]);
+        assert_eq!(answer.runs[0].output, "5");
+        assert!(answer.runs[1].output.starts_with("error: no tool named 'mul'"));
+        let roles: Vec<Role> = conv.history.turns.iter().map(|m| m.role).collect();
+        assert_eq!(roles, [Role::User, Role::Assistant, Role::Tool, Role::Tool, Role::Assistant]);
+        assert!(model.prompts[1] > model.prompts[0], "the second turn sees the call and both results");
+
+        // A model that never stops calling tools runs out of turns.
+        agent.max_iterations = 2;
+        let call = r#"<tool_call>{"name":"add","arguments":{"a":1,"b":1}}</tool_call>"#;
+        let mut model = Scripted { replies: vec![call, call], prompts: Vec::new() };
+        assert!(agent.run(&mut conv, &mut model, "again", &GenerateOptions::default(), &mut |_| {}, &mut |_| {}).is_err());
+    }
+
+    #[test]
+    fn tool_turns_render_in_each_family_s_tool_role() {
+        use crate::chat::{ChatFormat, Message, Role};
+        let msgs = [Message::new(Role::User, "Hi"), Message::new(Role::Assistant, "call"), Message::new(Role::Tool, "42")];
+        assert!(ChatFormat::ChatMl.render(&msgs, false).ends_with("<|im_start|>user\n<tool_response>\n42\n</tool_response><|im_end|>\n"));
+        assert!(ChatFormat::Llama3.render(&msgs, false).ends_with("<|start_header_id|>ipython<|end_header_id|>\n\n42<|eot_id|>"));
+        assert!(ChatFormat::Mistral.render(&msgs, false).ends_with("[TOOL_RESULTS] 42 [/TOOL_RESULTS]"));
+        assert!(ChatFormat::Gemma.render(&msgs, false).ends_with("<start_of_turn>user\n42<end_of_turn>\n"));
+        assert_eq!("tool".parse::<Role>().unwrap(), Role::Tool);
+    }
+
+    #[test]
+    fn command_tools_get_json_on_stdin_a_clean_env_and_a_timeout() {
+        use crate::agent::Agent;
+        use crate::reply::ToolCall;
+        use std::time::Duration;
+
+        // SAFETY: no other test reads or writes this variable.
+        unsafe { std::env::set_var("LLMETAL_AGENT_SECRET", "hunter2") };
+        let mut agent = Agent::default();
+        let sh = |script: &str| vec!["sh".to_string(), "-c".to_string(), script.to_string()];
+        let schema = serde_json::json!({"type": "object"});
+        agent.register_command("echo", "", schema.clone(), sh("cat; echo \"[$LLMETAL_AGENT_SECRET]\""));
+        agent.register_command("fail", "", schema.clone(), sh("echo bad input >&2; exit 2"));
+        agent.register_command("slow", "", schema.clone(), sh("sleep 5"));
+        agent.register_command("spawns", "", schema.clone(), sh("sleep 5 & wait"));
+        agent.register_command("detaches", "", schema, sh("sleep 5 & echo started"));
+        agent.sandbox.timeout = Duration::from_millis(200);
+        let call = |name: &str| ToolCall { id: None, name: name.to_string(), arguments: serde_json::json!({"q": 1}) };
+
+        assert_eq!(agent.call(&call("echo")).unwrap(), "{\"q\":1}[]", "the arguments on stdin, the secret cleared");
+        let err = format!("{:#}", agent.call(&call("fail")).unwrap_err());
+        assert!(err.contains("bad input"), "{err}");
+        let start = std::time::Instant::now();
+        assert!(format!("{:#}", agent.call(&call("slow")).unwrap_err()).contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(3));
+        // A grandchild is killed with the group; one that outlives the
+        // command but keeps its stdout open is bounded by the same deadline.
+        for name in ["spawns", "detaches"] {
+            let start = std::time::Instant::now();
+            assert!(format!("{:#}", agent.call(&call(name)).unwrap_err()).contains("timed out"), "{name}");
+            assert!(start.elapsed() < Duration::from_secs(3), "{name}");
+        }
+
+        agent.sandbox.max_output = 4;
+        assert_eq!(agent.call(&call("echo")).unwrap(), "{\"q\"");
+    }
+
+    // -------------------------------------------------------------------------
+    // MCP client
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn mcp_config_files_list_stdio_servers() {
+        use crate::mcp::ServerConfig;
+        let path = std::env::temp_dir().join(format!("llmetal-mcp-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"mcpServers": {"files": {"command": "npx", "args": ["-y", "server-filesystem", "/tmp"], "env": {"DEBUG": "1"}}}}"#,
+        )
+        .unwrap();
+        let servers = ServerConfig::load(&path).unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!((servers[0].name.as_str(), servers[0].command.as_str()), ("files", "npx"));
+        assert_eq!(servers[0].args, ["-y", "server-filesystem", "/tmp"]);
+        assert_eq!(servers[0].env["DEBUG"], "1");
+
+        std::fs::write(&path, r#"{"mcpServers": {"remote": {"url": "https://example.com/mcp"}}}"#).unwrap();
+        assert!(ServerConfig::load(&path).is_err(), "HTTP servers are not supported");
+        std::fs::remove_file(&path).unwrap();
+
+        let cli = ServerConfig::from_command_line("/usr/bin/mcp-time --tz UTC").unwrap();
+        assert_eq!((cli.name.as_str(), cli.args.len()), ("mcp-time", 2));
+    }
+
+    #[test]
+    fn mcp_client_discovers_tools_across_pages_and_forwards_calls() {
+        use crate::agent::Agent;
+        use crate::mcp::{McpClient, ServerConfig};
+        use crate::reply::ToolCall;
+
+        // A server with canned responses: requests arrive with ids 1, 2, ...
+        let script = r#"
+read l; echo '{"jsonrpc":"2.0","id":1,"result":{"protocolVersion":"2024-11-05","capabilities":{"tools":{}},"serverInfo":{"name":"fake"}}}'
+read l
+read l; echo '{"jsonrpc":"2.0","method":"notifications/message","params":{"level":"info"}}'
+echo '{"jsonrpc":"2.0","id":"s1","method":"ping"}'
+read pong; case "$pong" in *'"id":"s1"'*'"result"'*) ;; *) exit 1;; esac
+echo '{"jsonrpc":"2.0","id":2,"result":{"tools":[{"name":"echo","description":"Echo text","inputSchema":{"type":"object"}}],"nextCursor":"p2"}}'
+read l; case "$l" in *'"cursor":"p2"'*) ;; *) exit 1;; esac
+echo '{"jsonrpc":"2.0","id":3,"result":{"tools":[{"name":"fail"}]}}'
+read l; case "$l" in *'"name":"echo"'*) ;; *) exit 1;; esac; case "$l" in *'"text":"hi"'*) ;; *) exit 1;; esac
+echo '{"jsonrpc":"2.0","id":4,"result":{"content":[{"type":"text","text":"hi"},{"type":"image","data":""}]}}'
+read l; echo '{"jsonrpc":"2.0","id":5,"result":{"content":[{"type":"text","text":"disk full"}],"isError":true}}'
+read l; echo '{"jsonrpc":"2.0","id":6,"error":{"code":-32602,"message":"unknown tool"}}'
+read l
+"#;
+        let config = ServerConfig { name: "test".into(), command: "sh".into(), args: vec!["-c".into(), script.into()], ..Default::default() };
+        let client = McpClient::spawn(&config).unwrap();
+        assert_eq!(client.name, "test (fake)");
+        let mut agent = Agent::default();
+        assert_eq!(client.register_tools(&mut agent).unwrap(), 2);
+        assert_eq!(agent.tools[1].parameters, serde_json::json!({"type": "object", "properties": {}}));
+        assert!(agent.system_prompt(None).contains("Echo text"));
+
+        let call = |name: &str, arguments| ToolCall { id: None, name: name.to_string(), arguments };
+        assert_eq!(agent.call(&call("echo", serde_json::json!({"text": "hi"}))).unwrap(), "hi\n[image]");
+        assert_eq!(format!("{:#}", agent.call(&call("fail", serde_json::json!({}))).unwrap_err()), "disk full");
+        let err = format!("{:#}", agent.call(&call("echo", serde_json::json!({}))).unwrap_err());
+        assert!(err.contains("unknown tool") && err.contains("-32602"), "{err}");
+    }
+
+    // -------------------------------------------------------------------------
+    // Code completion (fill in the middle)
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn completion_cursor_maps_line_and_column_to_a_byte_offset() {
+        use crate::complete::Cursor;
+        let text = "fn main() {\n    let é = 1;\n}\n";
+        let at = |s: &str| s.parse::<Cursor>().unwrap().offset(text);
+        assert_eq!(at("1:1").unwrap(), 0);
+        assert_eq!(at("2:11").unwrap(), text.find('=').unwrap());
+        assert_eq!(at("2:15").unwrap(), text.find(";\n}").unwrap() + 1, "one past the last character");
+        assert_eq!(at("4:1").unwrap(), text.len());
+        assert!(at("2:16").is_err());
+        assert!(at("5:1").is_err());
+        assert!("0:3".parse::<Cursor>().is_err());
+    }
+
+    #[test]
+    fn fim_prompt_orders_prefix_suffix_middle_and_keeps_the_cursor_line() {
+        use crate::complete::{FimTokens, fim_prompt};
+        use crate::tokenizer::PromptTokenizer;
+        let mut vocab = chat_vocab();
+        vocab.extend(["\n", "\u{120}", "<fim_prefix>", "<fim_suffix>", "<fim_middle>", "<file_sep>"].map(String::from));
+        let fim = FimTokens::find(&vocab).expect("StarCoder-style markers");
+        assert_eq!(fim.stops, vec![vocab.len() as u32 - 1]);
+        let tok = PromptTokenizer::new(vocab.clone());
+        let text = "aaaa\nbbbb\ncc|dd\neeee\nffff\n";
+        let offset = text.find('|').unwrap();
+        // The legacy scan starts each side with a space.
+        let render = |ids: &[u32]| ids.iter().map(|&i| vocab[i as usize].replace('\u{120}', " ")).collect::<String>();
+
+        let whole = fim_prompt(&tok, &fim, text, offset, "#p\n", 100, false);
+        assert_eq!(render(&whole), "<fim_prefix> #p\naaaa\nbbbb\ncc<fim_suffix> |dd\neeee\nffff\n<fim_middle>");
+        // A tight budget drops the farthest lines, but never the cursor's.
+        let tight = fim_prompt(&tok, &fim, text, offset, "", 17, true);
+        assert_eq!(tight[0], 1);
+        assert_eq!(render(&tight[1..]), "<fim_prefix> bbbb\ncc<fim_suffix> |dd\n<fim_middle>");
+        assert!(tight.len() <= 17);
+
+        assert!(FimTokens::find(&chat_vocab()).is_none());
+    }
+
+    #[test]
+    fn completions_are_trimmed_deduplicated_and_ranked() {
+        use crate::complete::{CompleteOptions, candidates, trim_completion};
+        use crate::tokenizer::PromptTokenizer;
+
+        assert_eq!(trim_completion("x + 1;\n    y\n}\nfn next() {}", "\n}\n", false), "x + 1;\n    y");
+        assert_eq!(trim_completion("x + 1;\nmore", "", true), "x + 1;");
+        assert_eq!(trim_completion("}\n", "}\n", false), "}", "the first line is the cursor's own");
+
+        let mut vocab = chat_vocab();
+        vocab.push("\n".into());
+        let tok = PromptTokenizer::new(vocab);
+        let mut model = Scripted { replies: vec!["foo();\nbar", "foo();", "\n", "baz();"], prompts: Vec::new() };
+        let opts = CompleteOptions { n: 4, single_line: true, ..CompleteOptions::default() };
+        let found = candidates(&mut model, &tok, &[5, 6, 7], &[], "", &opts).unwrap();
+        let texts: Vec<&str> = found.iter().map(|c| c.text.as_str()).collect();
+        assert_eq!(texts, ["foo();", "baz();"]);
+        assert_eq!(model.prompts, [3; 4]);
+    }
+
+    // -------------------------------------------------------------------------
+    // LSP server
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn lsp_positions_count_utf16_units() {
+        use crate::lsp::{offset_at, position_at};
+        let text = "a😀b\nxyz";
+        assert_eq!(offset_at(text, 0, 3), "a😀".len(), "the emoji is two UTF-16 units");
+        assert_eq!(offset_at(text, 0, 99), "a😀b".len());
+        assert_eq!(offset_at(text, 1, 1), text.find('y').unwrap());
+        assert_eq!(offset_at(text, 7, 0), text.len());
+        assert_eq!(position_at(text, "a😀b".len()), serde_json::json!({"line": 0, "character": 4}));
+        assert_eq!(position_at(text, text.len()), serde_json::json!({"line": 1, "character": 3}));
+    }
+
+    #[test]
+    fn lsp_session_answers_completions_and_resolves_rewrites() {
+        use crate::complete::Completion;
+        use crate::lsp::{REWRITE_TITLE, read_message, serve, write_message};
+        use serde_json::json;
+
+        let uri = "file:///src/lib.rs";
+        let msgs = [
+            json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}),
+            json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+            json!({"jsonrpc": "2.0", "method": "textDocument/didOpen", "params": {"textDocument": {"uri": uri, "languageId": "rust", "version": 1, "text": "old"}}}),
+            json!({"jsonrpc": "2.0", "method": "textDocument/didChange", "params": {"textDocument": {"uri": uri}, "contentChanges": [{"text": "fn f() {\n    \n}\n"}]}}),
+            json!({"jsonrpc": "2.0", "id": 2, "method": "textDocument/inlineCompletion", "params": {"textDocument": {"uri": uri}, "position": {"line": 1, "character": 4}}}),
+            json!({"jsonrpc": "2.0", "id": 3, "method": "textDocument/completion", "params": {"textDocument": {"uri": uri}, "position": {"line": 1, "character": 4}}}),
+            json!({"jsonrpc": "2.0", "id": 4, "method": "textDocument/codeAction", "params": {"textDocument": {"uri": uri}, "range": {"start": {"line": 0, "character": 0}, "end": {"line": 0, "character": 2}}}}),
+            json!({"jsonrpc": "2.0", "id": 5, "method": "textDocument/hover", "params": {}}),
+            json!({"jsonrpc": "2.0", "id": 6, "method": "shutdown"}),
+            json!({"jsonrpc": "2.0", "method": "exit"}),
+        ];
+        let mut input = Vec::new();
+        for m in &msgs {
+            write_message(&mut input, m).unwrap();
+        }
+        let mut output = Vec::new();
+        let mut fills = Vec::new();
+        serve(&mut std::io::Cursor::new(input), &mut output, &mut |fill| {
+            fills.push((fill.path.to_string(), fill.language.map(str::to_string), fill.start, fill.end, fill.single_line));
+            Ok(vec![Completion { text: "todo!()".into(), score: -0.1, tokens: 3 }])
+        })
+        .unwrap();
+        let mut out = std::io::Cursor::new(output);
+        let mut replies = Vec::new();
+        while let Some(m) = read_message(&mut out).unwrap() {
+            replies.push(m);
+        }
+        assert_eq!(replies.len(), 6, "notifications get no answer");
+        assert_eq!(replies[0]["result"]["capabilities"]["codeActionProvider"]["resolveProvider"], true);
+        let at = json!({"line": 1, "character": 4});
+        assert_eq!(replies[1]["result"]["items"], json!([{"insertText": "todo!()", "range": {"start": at, "end": at}}]));
+        assert_eq!(replies[2]["result"]["items"][0]["label"], "todo!()");
+        let action = &replies[3]["result"][0];
+        assert_eq!(action["title"], REWRITE_TITLE);
+        assert!(action.get("edit").is_none(), "the edit waits for resolve");
+        assert_eq!(replies[4]["error"]["code"], -32601);
+        assert_eq!(replies[5]["result"], serde_json::Value::Null);
+        assert_eq!(fills[0], ("/src/lib.rs".into(), Some("rust".into()), 13, 13, false));
+        assert!(fills[1].4, "completion items are single-line");
+
+        // Resolving the action fills the selection and returns the edit.
+        let mut input = Vec::new();
+        write_message(&mut input, &msgs[2]).unwrap();
+        write_message(&mut input, &json!({"jsonrpc": "2.0", "id": 7, "method": "codeAction/resolve", "params": action})).unwrap();
+        let mut output = Vec::new();
+        let mut span = None;
+        serve(&mut std::io::Cursor::new(input), &mut output, &mut |fill| {
+            span = Some((fill.start, fill.end));
+            Ok(vec![Completion { text: "new".into(), score: 0.0, tokens: 1 }])
+        })
+        .unwrap();
+        assert_eq!(span, Some((0, 2)));
+        let resolved = read_message(&mut std::io::Cursor::new(output)).unwrap().unwrap();
+        assert_eq!(resolved["result"]["edit"]["changes"][uri][0]["newText"], "new");
+        assert_eq!(resolved["result"]["edit"]["changes"][uri][0]["range"]["end"], json!({"line": 0, "character": 2}));
+    }
+
+    // -------------------------------------------------------------------------
+    // Commit messages
+    // -------------------------------------------------------------------------
+
+    const STAGED_DIFF: &str = "diff --git a/src/a.rs b/src/a.rs
+index 1..2 100644
+--- a/src/a.rs
++++ b/src/a.rs
+@@ -1,3 +1,3 @@
+ fn a() {
+-    old();
++    new();
++    more();
+ }
+diff --git a/README.md b/README.md
+--- a/README.md
++++ b/README.md
+@@ -1 +1 @@
+-Old line
++New line
+";
+
+    #[test]
+    fn commit_diff_summary_counts_lines_per_file() {
+        use crate::commit_msg::{FileChange, summarize};
+
+        let summary = summarize(STAGED_DIFF, 10_000);
+        assert_eq!(
+            summary.files,
+            vec![
+                FileChange { path: "src/a.rs".into(), added: 2, removed: 1 },
+                FileChange { path: "README.md".into(), added: 1, removed: 1 },
+            ]
+        );
+        assert_eq!(summary.excerpt, STAGED_DIFF, "a diff under budget is kept whole");
+    }
+
+    #[test]
+    fn commit_diff_summary_keeps_every_header_under_a_tight_budget() {
+        use crate::commit_msg::{prompt, summarize};
+
+        let summary = summarize(STAGED_DIFF, 0);
+        assert!(summary.excerpt.contains("+++ b/src/a.rs\n[... 6 more lines]\n"), "{}", summary.excerpt);
+        assert!(summary.excerpt.contains("+++ b/README.md\n[... 3 more lines]\n"), "{}", summary.excerpt);
+        assert!(!summary.excerpt.contains("new();"));
+
+        let text = prompt(&summary, Some("rename the helper"));
+        assert!(text.contains("The author says: rename the helper"));
+        assert!(text.contains("  src/a.rs (+2 -1)\n  README.md (+1 -1)\n"));
+        assert!(text.ends_with("[... 3 more lines]\n```"));
+    }
+
+    #[test]
+    fn commit_headers_need_a_known_type_and_subject() {
+        use crate::commit_msg::is_header;
+
+        assert!(is_header("feat: add a flag"));
+        assert!(is_header("fix(parser): handle empty input"));
+        assert!(is_header("refactor(core)!: drop the old API"));
+        assert!(!is_header("feature: add a flag"), "unknown type");
+        assert!(!is_header("fix(): no scope"));
+        assert!(!is_header("fix(two words): bad scope"));
+        assert!(!is_header("fix: "), "empty subject");
+        assert!(!is_header("Add a flag"));
+    }
+
+    #[test]
+    fn commit_replies_are_cleaned_to_the_message() {
+        use crate::commit_msg::clean;
+
+        let reply = "Here is the commit message:\n```\nFeat(cli): add a commit-msg command.\n\nReads the staged diff  \nand asks the model.\n```\n";
+        assert_eq!(
+            clean(reply).as_deref(),
+            Some("feat(cli): add a commit-msg command\n\nReads the staged diff\nand asks the model.")
+        );
+        assert_eq!(clean("  fix: typo\n\n").as_deref(), Some("fix: typo"));
+        assert_eq!(clean("I changed some files."), None);
+    }
+
+    // -------------------------------------------------------------------------
+    // Multiple-choice evals
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn eval_reads_hellaswag_and_mmlu_records() {
+        use crate::eval::Task;
+        use serde_json::json;
+
+        let hs = json!({
+            "ind": 24, "activity_label": "Roof shingle removal",
+            "ctx_a": "A man is sitting on a roof.", "ctx_b": "he",
+            "endings": ["is using wrap to wrap a pair of skis.", "starts pulling up roofing [title] on a roof."],
+            "label": "1",
+        });
+        let item = Task::HellaSwag.parse(&hs, 0).unwrap();
+        assert_eq!(item.id, "24");
+        assert_eq!(item.context, "Roof shingle removal: A man is sitting on a roof. He");
+        assert_eq!(item.choices[1], " starts pulling up roofing. on a roof.");
+        assert_eq!(item.answer, 1);
+
+        let mmlu = json!({"question": "2 + 2 = ?", "subject": "elementary_mathematics", "choices": ["3", "4"], "answer": "B"});
+        let item = Task::Mmlu.parse(&mmlu, 7).unwrap();
+        assert_eq!(item.id, "7");
+        assert_eq!(
+            item.context,
+            "The following are multiple choice questions (with answers) about elementary mathematics.\n\n2 + 2 = ?\nA. 3\nB. 4\nAnswer:"
+        );
+        assert_eq!(item.choices, [" A", " B"]);
+        assert_eq!(item.answer, 1);
+        assert_eq!(Task::Mmlu.parse(&json!({"question": "q", "choices": ["a", "b"], "answer": 1}), 0).unwrap().answer, 1);
+        assert!(Task::Mmlu.parse(&json!({"question": "q", "choices": ["a", "b"], "answer": "C"}), 0).is_err());
+        assert!("arc".parse::<Task>().is_err());
+    }
+
+    #[test]
+    fn eval_parses_quoted_csv_fields() {
+        use crate::eval::{hellaswag_clean, parse_csv};
+
+        let rows = parse_csv("\"Which, of these?\",a,\"say \"\"b\"\"\",c,\"d\nline\",A\r\nq2,1,2,3,4,D");
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0], ["Which, of these?", "a", "say \"b\"", "c", "d\nline", "A"]);
+        assert_eq!(rows[1][5], "D");
+        assert_eq!(hellaswag_clean(" Put it [header] back  in. "), "Put it back in.");
+    }
+
+    #[test]
+    fn eval_scores_choices_by_total_and_per_byte_logprob() {
+        use crate::eval::{Item, Report, score_item};
+        use crate::unicode::PreTokenizer;
+
+        let mut vocab: Vec<String> = ["<unk>", "<s>", "\u{120}"].map(String::from).to_vec();
+        vocab.extend((b'!'..=b'~').map(|c| (c as char).to_string()));
+        let x = vocab.iter().position(|v| v == "x").unwrap();
+        let n = vocab.len();
+        let tokenizer = PromptTokenizer::byte_level(vocab, PreTokenizer::Gpt2);
+        // Every position strongly predicts "x".
+        let mut calls = Vec::new();
+        let mut evaluate = |tokens: &[u32]| {
+            calls.push(tokens.to_vec());
+            let mut row = vec![0.0f32; n];
+            row[x] = 6.0;
+            Ok(vec![row; tokens.len()])
+        };
+        let item = Item {
+            id: "0".into(),
+            group: "g".into(),
+            context: "Q:".into(),
+            choices: vec![" x".into(), " y".into(), " xxxxxxxx".into()],
+            answer: 2,
+        };
+        let scores = score_item(&mut evaluate, &tokenizer, &item).unwrap();
+        assert_eq!(calls.len(), 3, "one pass per choice");
+        assert_eq!(calls[0], tokenizer.tokenize_bos("Q: x"));
+        assert_eq!(calls[0].len(), 5, "BOS, Q, :, \u{120}, x");
+        assert_eq!((scores[0].tokens, scores[0].bytes), (2, 2));
+        assert_eq!((scores[2].tokens, scores[2].bytes), (9, 9));
+        assert!(scores[0].logprob > scores[1].logprob && scores[0].logprob > scores[2].logprob);
+        assert!(scores[2].normalized() > scores[0].normalized());
+
+        let mut report = Report::default();
+        assert_eq!(report.add(&item, &scores), (0, 2));
+        assert_eq!((report.total.correct, report.total.correct_norm), (0, 1));
+        assert_eq!(report.groups["g"].items, 1);
+        report.add(&Item { answer: 0, ..item.clone() }, &scores);
+        assert_eq!(report.total.acc(), 0.5);
+        assert!((report.total.stderr(0.5) - 0.5).abs() < 1e-12);
+    }
+
+    // -------------------------------------------------------------------------
+    // Classifier-free guidance
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn cfg_scale_one_keeps_positive_ranking() {
+        let mut logits = vec![1.0f32, 3.0, 2.0];
+        crate::model::apply_cfg(&mut logits, &[5.0, 0.0, 0.0], 1.0);
+        let total: f32 = logits.iter().map(|l| l.exp()).sum();
+        assert!((total - 1.0).abs() < 1e-5, "scale 1.0 should yield log-probs");
+        assert!(logits[1] > logits[2] && logits[2] > logits[0]);
+    }
+
+    #[test]
+    fn cfg_pushes_away_from_negative() {
+        // Token 0 and 1 tie under the positive prompt; the negative prompt likes token 0.
+        let mut logits = vec![2.0f32, 2.0, 0.0];
+        crate::model::apply_cfg(&mut logits, &[4.0, 0.0, 0.0], 2.0);
+        assert!(logits[1] > logits[0], "guided logits: {logits:?}");
+    }
+
+    #[test]
+    fn top_k_returns_best_first() {
+        let v = [0.1f32, 0.9, -1.0, 0.5, 0.7];
+        assert_eq!(crate::model::top_k(&v, 3), vec![1, 4, 3]);
+        assert_eq!(crate::model::top_k(&v, 10).len(), 5);
+        assert!(crate::model::top_k(&v, 0).is_empty());
+    }
+
+    // -------------------------------------------------------------------------
+    // Sampler: DRY
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn dry_penalises_token_that_extends_a_repeat() {
+        use crate::sampler::{DryConfig, apply_dry};
+        let dry = DryConfig { multiplier: 1.0, base: 2.0, allowed_length: 2, sequence_breakers: Vec::new() };
+        // "5 6 7 8 ... 5 6 7" — 8 would extend a length-3 repeat.
+        let context = [5u32, 6, 7, 8, 1, 2, 5, 6, 7];
+        let mut logits = vec![0.0f32; 10];
+        apply_dry(&mut logits, &context, &dry, &Default::default());
+        assert!((logits[8] + 2.0).abs() < 1e-6, "len 3, allowed 2 → 1·2^1, got {}", logits[8]);
+        assert_eq!(logits[1], 0.0, "token 1 only follows the single token 8");
+    }
+
+    #[test]
+    fn dry_stops_at_sequence_breakers() {
+        use crate::sampler::{DryConfig, apply_dry};
+        let dry = DryConfig { multiplier: 1.0, base: 2.0, allowed_length: 2, sequence_breakers: Vec::new() };
+        let context = [5u32, 6, 7, 8, 1, 2, 5, 6, 7];
+        let breakers = [6u32].into_iter().collect();
+        let mut logits = vec![0.0f32; 10];
+        apply_dry(&mut logits, &context, &dry, &breakers);
+        assert_eq!(logits[8], 0.0, "the match is cut to length 1 at the breaker");
+    }
+
+    #[test]
+    fn xtc_keeps_only_the_weakest_top_choice() {
+        use crate::sampler::{Rng, XtcConfig, apply_xtc, argmax};
+        let mut logits = vec![3.0f32, 2.5, 2.0, -5.0];
+        let xtc = XtcConfig { probability: 1.0, threshold: 0.1 };
+        apply_xtc(&mut logits, xtc, &mut Rng::new(7));
+        assert_eq!(logits[0], f32::NEG_INFINITY);
+        assert_eq!(logits[1], f32::NEG_INFINITY);
+        assert_eq!(argmax(&logits), 2, "lowest token above threshold survives");
+        assert_eq!(logits[3], -5.0);
+    }
+
+    #[test]
+    fn xtc_is_a_no_op_with_a_single_top_choice_or_zero_probability() {
+        use crate::sampler::{Rng, XtcConfig, apply_xtc};
+        let mut peaked = vec![10.0f32, 0.0, 0.0];
+        apply_xtc(&mut peaked, XtcConfig { probability: 1.0, threshold: 0.1 }, &mut Rng::new(1));
+        assert_eq!(peaked, vec![10.0, 0.0, 0.0]);
+        let mut flat = vec![1.0f32, 1.0];
+        apply_xtc(&mut flat, XtcConfig { probability: 0.0, threshold: 0.1 }, &mut Rng::new(1));
+        assert_eq!(flat, vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn loop_guard_finds_blocks_repeated_past_the_threshold() {
+        use crate::sampler::{LoopAction, LoopGuard, LoopMatch, find_loop};
+        let guard = LoopGuard { max_period: 4, min_repeats: 3, min_tokens: 6, action: LoopAction::Stop };
+        assert_eq!(find_loop(&[9, 1, 2, 3, 1, 2, 3, 1, 2, 3], &guard), Some(LoopMatch { period: 3, len: 9 }));
+        assert_eq!(find_loop(&[3, 1, 2, 3, 1, 2, 3], &guard), None, "two repeats are not enough");
+        assert_eq!(find_loop(&[1, 2, 1, 2], &guard), None, "shorter than min_tokens");
+        assert_eq!(find_loop(&[7; 5], &guard), None);
+        assert_eq!(find_loop(&[4, 7, 7, 7, 7, 7, 7], &guard), Some(LoopMatch { period: 1, len: 6 }));
+        assert_eq!(find_loop(&[1, 2, 3, 4, 5, 1, 2, 3, 4, 5, 1, 2, 3, 4, 5], &guard), None, "period above max_period");
+
+        assert_eq!("penalize=2.5".parse::<LoopAction>().unwrap(), LoopAction::Penalize(2.5));
+        assert_eq!("diversify".parse::<LoopAction>().unwrap(), LoopAction::Diversify(1.0));
+        assert!("stop=1".parse::<LoopAction>().is_err());
+        assert!("rewind".parse::<LoopAction>().is_err());
+    }
+
+    #[test]
+    fn loop_guard_stops_penalizes_or_diversifies() {
+        use crate::sampler::{LoopAction, LoopGuard, Sampler, SamplerConfig};
+        let vocab: Vec<String> = (0..8).map(|i| format!("t{i}")).collect();
+        let context = [1u32, 2, 3, 1, 2, 3, 1, 2, 3];
+        let logits = [0.0f32, 2.0, 0.0, 0.0, 0.0, 1.5, 0.0, 0.0];
+        let sample = |action: LoopAction| {
+            let guard = LoopGuard { max_period: 4, min_repeats: 3, min_tokens: 6, action };
+            let config = SamplerConfig { repetition_penalty: 1.0, loop_guard: Some(guard), seed: Some(1), ..SamplerConfig::default() };
+            let mut sampler = Sampler::new(config, &vocab);
+            let id = sampler.sample(&mut logits.clone(), &context, context.len());
+            let before = sampler.looping();
+            // Without the loop in the output the watchdog stays quiet.
+            sampler.sample(&mut logits.clone(), &context, 2);
+            (id, before, sampler.looping())
+        };
+        assert_eq!(sample(LoopAction::Stop), (1, true, false));
+        assert_eq!(sample(LoopAction::Penalize(1.0)), (5, false, false), "token 1 drops from 2.0 to 1.0");
+        let (id, looping, _) = sample(LoopAction::Diversify(0.5));
+        assert!(id != 1 && !looping, "the loop's next token is forbidden, got {id}");
+    }
+
+    #[test]
+    fn logits_processors_see_the_context_and_edit_logits_in_order() {
+        use std::sync::{Arc, Mutex};
+        use crate::sampler::{LogitsProcessor, Sampler, SamplerConfig};
+
+        /// Bans whatever token the context ended with.
+        struct NoEcho(Mutex<Vec<(usize, usize)>>);
+        impl LogitsProcessor for NoEcho {
+            fn process(&self, context: &[u32], n_generated: usize, logits: &mut [f32]) {
+                self.0.lock().unwrap().push((context.len(), n_generated));
+                logits[*context.last().unwrap() as usize] = f32::NEG_INFINITY;
+            }
+        }
+        /// Adds a fixed bias, like a watermark's green list.
+        struct Boost(usize, f32);
+        impl LogitsProcessor for Boost {
+            fn process(&self, _: &[u32], _: usize, logits: &mut [f32]) {
+                logits[self.0] += self.1;
+            }
+            fn name(&self) -> &str {
+                "boost"
+            }
+        }
+
+        let vocab: Vec<String> = (0..4).map(|i| format!("t{i}")).collect();
+        let no_echo = Arc::new(NoEcho(Mutex::default()));
+        let config = SamplerConfig {
+            repetition_penalty: 1.0,
+            processors: vec![no_echo.clone(), Arc::new(Boost(3, 0.5))],
+            ..SamplerConfig::default()
+        };
+        assert!(format!("{config:?}").contains("boost"));
+        let mut sampler = Sampler::new(config, &vocab);
+        // Token 1 is best but was just emitted; 3 beats 2 only with the boost.
+        assert_eq!(sampler.sample(&mut [0.0, 2.0, 1.2, 1.0], &[0, 1], 1), 3);
+        // The ban runs before the boost, so a boosted echo stays banned.
+        assert_eq!(sampler.sample(&mut [0.0, 0.0, 1.2, 2.0], &[0, 1, 3], 2), 2);
+        assert_eq!(*no_echo.0.lock().unwrap(), vec![(2, 1), (3, 2)]);
+    }
+
+    #[test]
+    fn watermark_shows_in_sampled_tokens_and_only_under_its_key() {
+        use std::sync::Arc;
+        use crate::sampler::{Rng, Sampler, SamplerConfig};
+        use crate::watermark::Watermark;
+        let vocab: Vec<String> = (0..500).map(|i| format!("t{i}")).collect();
+        let mark = Watermark::new(0xC0FFEE);
+        let green = (0..20_000u32).filter(|&t| mark.is_green(t % 97, t)).count();
+        assert!((4_500..5_500).contains(&green), "about gamma = 25% of tokens are green, got {green}");
+
+        // Sample 200 tokens from noisy logits, with and without the watermark.
+        let generate = |watermarked: bool| {
+            let processors: Vec<Arc<dyn crate::sampler::LogitsProcessor>> =
+                if watermarked { vec![Arc::new(mark)] } else { Vec::new() };
+            let config = SamplerConfig { repetition_penalty: 1.0, temperature: 1.0, seed: Some(9), processors, ..SamplerConfig::default() };
+            let mut sampler = Sampler::new(config, &vocab);
+            let mut rng = Rng::new(4);
+            let mut context = vec![1u32];
+            for n in 0..200 {
+                let mut logits: Vec<f32> = (0..vocab.len()).map(|_| rng.next_f32() * 3.0).collect();
+                let id = sampler.sample(&mut logits, &context, n);
+                context.push(id);
+            }
+            context
+        };
+        let marked = generate(true);
+        let found = mark.detect(&marked);
+        assert!(found.z > 4.0 && found.scored > 150, "{found:?}");
+        assert!(Watermark::new(7).detect(&marked).z < 4.0, "another key sees no watermark");
+        assert!(mark.detect(&generate(false)).z < 4.0);
+
+        // A repeated pair only counts once.
+        assert_eq!(mark.detect(&[5, 6, 5, 6, 5, 6]).scored, 2);
+        assert_eq!(mark.detect(&[5]).z, 0.0);
+
+        // 0 and 1 would make the z-score's denominator 0.
+        assert_eq!(Watermark::parse_gamma("0.5").unwrap(), 0.5);
+        for bad in ["0", "1", "-0.1", "1.5", "NaN", "quarter"] {
+            assert!(Watermark::parse_gamma(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn thinking_budget_forces_the_closing_tag_and_streams_sort_tokens() {
+        use crate::reasoning::{Segment, ThinkStream, ThinkTokens};
+        use crate::sampler::{Sampler, SamplerConfig};
+        let vocab: Vec<String> = ["a", "b", "<think>", "</think>", "c"].map(String::from).to_vec();
+        let tags = ThinkTokens::find(&vocab).unwrap();
+        assert_eq!(tags, ThinkTokens { open: 2, close: 3 });
+        assert!(ThinkTokens::find(&vocab[..3]).is_none());
+        assert_eq!(tags.thinking_len(&[0, 2, 1, 1]), Some(2));
+        assert_eq!(tags.thinking_len(&[2, 1, 3, 0]), None);
+        assert_eq!(tags.thinking_len(&[0, 1]), None);
+
+        let config = SamplerConfig { repetition_penalty: 1.0, max_thinking: Some(2), ..SamplerConfig::default() };
+        let mut sampler = Sampler::new(config, &vocab);
+        let logits = [5.0f32, 1.0, 0.0, -3.0, 0.0];
+        assert_eq!(sampler.sample(&mut logits.clone(), &[4, 2, 0], 2), 0, "one thinking token is under the budget");
+        assert_eq!(sampler.sample(&mut logits.clone(), &[4, 2, 0, 0], 3), 3, "two reach it");
+        assert_eq!(sampler.sample(&mut logits.clone(), &[2, 0, 0, 3, 0, 0], 5), 0, "closed blocks do not count");
+
+        // A prompt left inside `<think>` starts the reply thinking.
+        let mut stream = ThinkStream::new(tags, &[4, 2]);
+        let routed: Vec<Segment> = [0, 3, 1, 2, 4].into_iter().map(|id| stream.route(id)).collect();
+        assert_eq!(routed, [Segment::Reasoning, Segment::Tag, Segment::Content, Segment::Tag, Segment::Reasoning]);
+        assert_eq!(crate::reasoning::effort_budget("medium").unwrap(), 2048);
+        assert!(crate::reasoning::effort_budget("max").is_err());
+        assert!("separate".parse::<crate::reasoning::ReasoningMode>().is_ok());
+    }
+
+    #[test]
+    fn top_k_and_top_p_restrict_the_draw() {
+        use crate::sampler::{Rng, sample_top_p};
+        let logits = [2.0f32, 1.9, 1.8, -1.0];
+        let mut rng = Rng::new(3);
+        let draws = |top_k, top_p, rng: &mut Rng| -> std::collections::HashSet<u32> {
+            (0..200).map(|_| sample_top_p(&logits, 1.0, top_k, top_p, rng)).collect()
+        };
+        assert_eq!(draws(1, 1.0, &mut rng), [0].into());
+        assert_eq!(draws(2, 1.0, &mut rng), [0, 1].into());
+        // Token 0 alone is ~0.35 of the mass; 0.5 needs the first two.
+        assert_eq!(draws(0, 0.01, &mut rng), [0].into());
+        assert_eq!(draws(0, 0.5, &mut rng), [0, 1].into());
+        assert_eq!(draws(0, 1.0, &mut rng).len(), 4);
+        // A cold temperature is nearly greedy; the same seed repeats the draw.
+        assert!((0..50).all(|_| sample_top_p(&logits, 0.01, 0, 1.0, &mut rng) == 0));
+        let seq = |seed| { let mut r = Rng::new(seed); (0..20).map(|_| sample_top_p(&logits, 1.0, 0, 1.0, &mut r)).collect::<Vec<_>>() };
+        assert_eq!(seq(9), seq(9));
+    }
+
+    // -------------------------------------------------------------------------
+    // JSON grammar
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn json_state_accepts_objects_and_rejects_everything_else() {
+        use crate::json_grammar::JsonState;
+        let run = |doc: &str| {
+            let mut s = JsonState::default();
+            s.feed_all(doc.as_bytes()).then_some(s.is_complete())
+        };
+        for doc in [
+            r#"{}"#,
+            r#" { "a" : [1, -2.5e+3, 0, true, false, null, "x\n\u00e9"], "b": {"c": []} } "#,
+            "{\"k\": \"caf\u{e9}\"}\n",
+            r#"{"n": 10}"#,
+        ] {
+            assert_eq!(run(doc), Some(true), "{doc}");
+        }
+        // Prefixes are fine, just not complete.
+        assert_eq!(run(r#"{"a": [1, 2"#), Some(false));
+        assert_eq!(run(r#"{"a": 1"#), Some(false));
+        for doc in [
+            r#"[]"#, r#"{"a" 1}"#, r#"{"a": 01}"#, r#"{"a": tru}"#, r#"{"a": [1,]}"#, r#"{,}"#, r#"{} {}"#,
+            "{\"a\": \"x\ny\"}", r#"{"a": "\q"}"#, r#"{"a": 1.}"#, r#"{'a': 1}"#, r#"{"a": [}"#,
+        ] {
+            assert_eq!(run(doc), None, "{doc}");
+        }
+        // Nesting past MAX_DEPTH is refused.
+        let deep = format!(r#"{{"a": {}"#, "[".repeat(crate::json_grammar::MAX_DEPTH as usize));
+        assert_eq!(run(&deep), None);
+    }
+
+    #[test]
+    fn json_constraint_masks_tokens_that_break_the_document() {
+        use crate::json_grammar::JsonConstraint;
+        let vocab: Vec<String> = ["<unk>", "<s>", "</s>", "{", "}", "\"", "a", ":", "Ġ1", "Ċ", "x", "\"}"]
+            .map(String::from)
+            .to_vec();
+        let allowed = |c: &JsonConstraint| {
+            let mut logits = vec![0.0f32; vocab.len()];
+            c.mask(&mut logits);
+            (0..vocab.len()).filter(|&i| logits[i] == 0.0).collect::<Vec<_>>()
+        };
+        let mut c = JsonConstraint::new(&vocab).with_end_tokens(&[10]);
+        // Only the opening brace or whitespace (`Ċ` decodes to a newline);
+        // neither `</s>` nor the stop token 10 before the object closes.
+        assert_eq!(allowed(&c), vec![3, 9]);
+        for id in [3, 5, 6, 5, 7, 8] {
+            assert!(allowed(&c).contains(&(id as usize)), "token {id} after {:?}", c.state());
+            c.accept(id);
+        }
+        assert_eq!(allowed(&c), vec![4, 9]);
+        c.accept(4);
+        assert_eq!(allowed(&c), vec![2, 9, 10]);
+    }
+
+    #[test]
+    fn regex_matches_whole_strings_and_refuses_unsupported_syntax() {
+        use crate::regex_grammar::Regex;
+        let cases: [(&str, &[&str], &[&str]); 6] = [
+            (r"\d{4}-\d{2}-\d{2}", &["2024-01-31"], &["2024-1-31", "2024-01-311", ""]),
+            (
+                r"[0-9a-f]{8}-(?:[0-9a-f]{4}-){3}[0-9a-f]{12}",
+                &["123e4567-e89b-12d3-a456-426614174000"],
+                &["123e4567-e89b-12d3-a456-42661417400", "123E4567-e89b-12d3-a456-426614174000"],
+            ),
+            (r"^(0|[1-9]\d*)\.(0|[1-9]\d*)\.(0|[1-9]\d*)(-[\w.]+)?$", &["1.0.0", "10.2.33-rc.1"], &["01.0.0", "1.0", "1.0.0-"]),
+            ("yes|no|maybe", &["yes", "maybe"], &["ye", "yesno"]),
+            (r"[^,\s]+(, [^,\s]+)*", &["a, b, café"], &["a,b", "a, "]),
+            (r"x{2,}y?\{.", &["xx{!", "xxxxy{z"], &["x{!", "xx{\n"]),
+        ];
+        for (pattern, good, bad) in cases {
+            let re = Regex::new(pattern).unwrap();
+            good.iter().for_each(|t| assert!(re.is_match(t), "{pattern} should match {t:?}"));
+            bad.iter().for_each(|t| assert!(!re.is_match(t), "{pattern} should not match {t:?}"));
+        }
+        for pattern in ["(a", "a)", "[a-", "*a", r"(a)\1", "(?=a)", "a{3,2}", "[é]", "a{5000}"] {
+            assert!(Regex::new(pattern).is_err(), "{pattern} should be refused");
+        }
+    }
+
+    #[test]
+    fn regex_constraint_masks_tokens_off_the_pattern() {
+        use crate::regex_grammar::{Regex, RegexConstraint};
+        let vocab: Vec<String> = ["<unk>", "<s>", "</s>", "1", "12", "-", "a", "3-", "Ġ4"].map(String::from).to_vec();
+        let mut c = RegexConstraint::new(std::sync::Arc::new(Regex::new(r"\d+-\d").unwrap()), &vocab).with_end_tokens(&[6]);
+        let allowed = |c: &mut RegexConstraint| {
+            let mut logits = vec![0.0f32; vocab.len()];
+            c.mask(&mut logits);
+            (0..vocab.len()).filter(|&i| logits[i] == 0.0).collect::<Vec<_>>()
+        };
+        // Digits first; no special (empty) tokens, no end before a match.
+        assert_eq!(allowed(&mut c), vec![3, 4, 7]);
+        c.accept(7);
+        assert_eq!(allowed(&mut c), vec![3]);
+        c.accept(3);
+        assert!(c.is_complete());
+        assert_eq!(allowed(&mut c), vec![2, 6]);
+    }
+
     // -------------------------------------------------------------------------
-    // Tokenizer
+    // Constrained choice
     // -------------------------------------------------------------------------
 
-    fn tiny_vocab() -> Vec<String> {
-        // Simulate a tiny GPT-2/Tekken vocab.
-        // Ġ = U+0120 (space prefix).
-        vec![
-            "<unk>".to_string(),   // 0
-            "<s>".to_string(),     // 1 BOS
-            "</s>".to_string(),    // 2 EOS
-            "h".to_string(),       // 3
-            "e".to_string(),       // 4
-            "l".to_string(),       // 5
-            "o".to_string(),       // 6
-            "\u{0120}".to_string(),// 7 — lone space token
-            "\u{0120}h".to_string(),        // 8
-            "\u{0120}he".to_string(),       // 9
-            "\u{0120}hel".to_string(),      // 10
-            "\u{0120}hell".to_string(),     // 11
-            "\u{0120}hello".to_string(),    // 12
-            "hello".to_string(),   // 13
-        ]
+    #[test]
+    fn choice_steps_follow_candidates_and_end_on_prefixes() {
+        use crate::choice::{Choices, Step};
+        assert!(Choices::new(vec![]).is_err());
+        assert!(Choices::new(vec![vec![5], vec![]]).is_err());
+        assert!(Choices::new(vec![vec![5, 6], vec![5, 6]]).is_err());
+
+        let choices = Choices::new(vec![vec![5, 6], vec![5], vec![7, 8, 9]]).unwrap();
+        assert_eq!(choices.next_tokens(&[]), vec![5, 7]);
+        assert_eq!(choices.next_tokens(&[5]), vec![6]);
+        assert_eq!((choices.complete(&[5]), choices.complete(&[7, 8])), (Some(1), None));
+
+        // The best allowed token wins even when a masked one is likelier.
+        let mut lp = vec![-9.0f32; 10];
+        lp[3] = -0.1;
+        lp[7] = -2.0;
+        lp[5] = -3.0;
+        assert_eq!(choices.step(&[], &lp, &[]), Step::Token { id: 7, logprob: -2.0 });
+        // After `5`, "5" ends if an end token beats `6`, else "5 6" continues.
+        lp[6] = -1.0;
+        assert_eq!(choices.step(&[5], &lp, &[]), Step::Token { id: 6, logprob: -1.0 });
+        lp[4] = -0.5;
+        assert_eq!(choices.step(&[5], &lp, &[4]), Step::Done(1));
+        assert_eq!(choices.step(&[5, 6], &lp, &[]), Step::Done(0));
     }
 
+    // -------------------------------------------------------------------------
+    // Speculative decoding: drafts
+    // -------------------------------------------------------------------------
+
     #[test]
-    fn tokenizer_bos_prepended() {
-        let tok = PromptTokenizer::new(tiny_vocab());
-        let ids = tok.tokenize_bos("hello");
-        assert_eq!(ids[0], 1, "BOS must be first token");
+    fn ngram_draft_copies_what_followed_the_latest_match() {
+        use crate::speculative::ngram_draft;
+        // "1 2 3 4 5 ... 1 2" -> the trailing bigram last appeared before "3 4 5".
+        let ctx = [1, 2, 3, 4, 5, 9, 1, 2];
+        assert_eq!(ngram_draft(&ctx, 3, 2), vec![3, 4]);
+        assert_eq!(ngram_draft(&ctx, 3, 10), vec![3, 4, 5, 9, 1, 2]);
+        // Two earlier matches: the more recent one wins.
+        assert_eq!(ngram_draft(&[7, 1, 7, 2, 7], 1, 1), vec![2]);
     }
 
     #[test]
-    fn tokenizer_greedy_longest_match() {
-        let tok = PromptTokenizer::new(tiny_vocab());
-        // " hello" normalises to "Ġhello"; longest match from pos=0 should be id=12 "Ġhello"
-        let ids = tok.tokenize("hello");
-        assert_eq!(ids, vec![12], "expected Ġhello (id=12)");
+    fn ngram_draft_falls_back_to_shorter_ngrams_and_may_find_nothing() {
+        use crate::speculative::ngram_draft;
+        // No earlier "5 6", but an earlier "6".
+        assert_eq!(ngram_draft(&[6, 8, 5, 6], 2, 4), vec![8, 5, 6]);
+        assert!(ngram_draft(&[1, 2, 3], 3, 4).is_empty());
+        assert!(ngram_draft(&[], 3, 4).is_empty());
     }
 
     #[test]
-    fn tokenizer_no_unknown_for_known_chars() {
-        let tok = PromptTokenizer::new(tiny_vocab());
-        let ids = tok.tokenize("hello");
-        assert!(!ids.contains(&u32::MAX), "no unknowns expected for vocab-covered input");
+    fn medusa_residual_adds_silu_of_the_biased_projection() {
+        use crate::speculative::medusa_residual;
+        // SiLU(0) = 0: a zero block leaves the hidden state as it was.
+        assert_eq!(medusa_residual(&[1.0, -2.0], &[0.0, 0.0], &[0.0, 0.0]), vec![1.0, -2.0]);
+        let out = medusa_residual(&[1.0, 1.0], &[1.0, -3.0], &[1.0, 0.0]);
+        let silu = |z: f32| z / (1.0 + (-z).exp());
+        assert!((out[0] - (1.0 + silu(2.0))).abs() < 1e-6 && (out[1] - (1.0 + silu(-3.0))).abs() < 1e-6, "{out:?}");
+    }
+
+    #[test]
+    fn draft_length_follows_acceptance_within_one_and_n_draft() {
+        use crate::speculative::DraftLength;
+        let mut len = DraftLength::new(8, true);
+        assert_eq!(len.get(), 8);
+        len.observe(8, 2);
+        assert_eq!(len.get(), 7);
+        len.observe(3, 3); // a draft cut short by the budget, fully kept
+        assert_eq!(len.get(), 5);
+        len.observe(5, 5);
+        len.observe(7, 7);
+        assert_eq!(len.get(), 8);
+        len.observe(1, 0);
+        assert_eq!(len.get(), 1);
+        len.observe(0, 0); // nothing drafted says nothing
+        assert_eq!(len.get(), 1);
+        // Medusa's unbounded default does not overflow.
+        let mut len = DraftLength::new(usize::MAX, true);
+        len.observe(usize::MAX, usize::MAX);
+        assert_eq!(len.get(), usize::MAX);
+        let mut fixed = DraftLength::new(4, false);
+        fixed.observe(4, 0);
+        assert_eq!(fixed.get(), 4);
+    }
+
+    #[test]
+    fn timings_report_speculation_only_when_something_was_drafted() {
+        let mut t = crate::events::Timings { decode_tokens: 10, decode_ms: 100, ..Default::default() };
+        assert!(!t.report().contains("speculative"));
+        (t.drafted_tokens, t.accepted_tokens, t.draft_len) = (40, 30, 6);
+        assert_eq!(t.acceptance_rate(), 0.75);
+        assert!(t.report().ends_with("speculative: drafted 40, accepted 30 (75%), draft length 6"), "{}", t.report());
     }
 
     // -------------------------------------------------------------------------
-    // CPU math (rms_norm, RoPE correctness smoke test)
+    // Async API (feature = "tokio")
     // -------------------------------------------------------------------------
 
-    fn rms_norm(x: &[f32], w: &[f32], eps: f32) -> Vec<f32> {
-        let ss = x.iter().map(|v| v * v).sum::<f32>() / x.len() as f32;
-        let inv = 1.0 / (ss + eps).sqrt();
-        x.iter().zip(w.iter()).map(|(xi, wi)| xi * inv * wi).collect()
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_load_rejects_non_gguf_before_blocking_load() {
+        let path = std::env::temp_dir().join("llmetal-not-a-model.bin");
+        std::fs::write(&path, b"nope, not gguf").unwrap();
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let err = rt
+            .block_on(crate::async_api::AsyncModel::load(path.to_string_lossy()))
+            .err()
+            .expect("load must fail");
+        assert!(err.to_string().contains("not a GGUF file"), "{err}");
     }
 
+    // -------------------------------------------------------------------------
+    // CPU kernels
+    // -------------------------------------------------------------------------
+
     #[test]
-    fn rms_norm_unit_weights_normalises() {
-        let x = vec![3.0f32, 4.0];  // RMS = sqrt((9+16)/2) = sqrt(12.5)
-        let w = vec![1.0f32, 1.0];
-        let out = rms_norm(&x, &w, 1e-8);
-        // After norm, RMS of out should be ~1.0
-        let rms = (out.iter().map(|v| v * v).sum::<f32>() / out.len() as f32).sqrt();
-        assert!((rms - 1.0).abs() < 1e-5, "RMS should be ~1.0 after normalisation, got {rms}");
+    fn cpu_kernels_agree_with_the_scalar_path() {
+        use crate::cpu::{CpuFeatures, CpuKernel, axpy_with, dot_with};
+        let features = CpuFeatures::detect();
+        // 67 covers whole vectors and a ragged tail for both lane widths.
+        let a: Vec<f32> = (0..67).map(|i| (i as f32 * 0.37).sin()).collect();
+        let b: Vec<f32> = (0..67).map(|i| (i as f32 * 0.11).cos()).collect();
+        let want = dot_with(CpuKernel::Scalar, &a, &b);
+        let mut want_y = b.clone();
+        axpy_with(CpuKernel::Scalar, &mut want_y, 0.5, &a);
+        for kernel in [CpuKernel::Neon, CpuKernel::Avx2].into_iter().filter(|k| k.is_supported(&features)) {
+            assert!((dot_with(kernel, &a, &b) - want).abs() < 1e-4, "{kernel:?}");
+            let mut y = b.clone();
+            axpy_with(kernel, &mut y, 0.5, &a);
+            assert!(y.iter().zip(&want_y).all(|(x, w)| (x - w).abs() < 1e-6), "{kernel:?}");
+        }
+        assert!(features.best_kernel().is_supported(&features));
+        assert_eq!("avx2".parse::<CpuKernel>().unwrap(), CpuKernel::Avx2);
+        assert!("sse".parse::<CpuKernel>().is_err());
+        let foreign = if cfg!(target_arch = "aarch64") { CpuKernel::Avx2 } else { CpuKernel::Neon };
+        assert!(crate::cpu::set_kernel(foreign).unwrap_err().to_string().contains("cannot run"));
     }
 
     #[test]
-    fn rms_norm_scales_with_weight() {
-        let x = vec![1.0f32, 1.0, 1.0, 1.0];
-        let w = vec![2.0f32, 2.0, 2.0, 2.0];
-        let out = rms_norm(&x, &w, 1e-8);
-        // x is constant so norm(x) = x/1.0 = [1,1,1,1]; multiplied by w=2 → [2,2,2,2]
-        for v in &out {
-            assert!((v - 2.0).abs() < 1e-5, "expected 2.0, got {v}");
+    fn int8_dots_agree_across_kernels_and_track_the_f32_dot() {
+        use crate::cpu::{CpuFeatures, CpuKernel, dot_i8_with, dot_q8_0, quantize_q8};
+        let features = CpuFeatures::detect();
+        // 71 leaves a tail after whole 16- and 32-lane vectors; ±127 are the extremes.
+        let a: Vec<i8> = (0..71).map(|i| if i == 0 { 127 } else { ((i * 37) % 255 - 127) as i8 }).collect();
+        let b: Vec<i8> = (0..71).map(|i| if i == 0 { -127 } else { ((i * 91) % 255 - 127) as i8 }).collect();
+        let want = dot_i8_with(CpuKernel::Scalar, &a, &b);
+        assert_eq!(want, a.iter().zip(&b).map(|(&x, &y)| x as i32 * y as i32).sum::<i32>());
+        for kernel in [CpuKernel::Neon, CpuKernel::Avx2].into_iter().filter(|k| k.is_supported(&features)) {
+            assert_eq!(dot_i8_with(kernel, &a, &b), want, "{kernel:?}");
+        }
+
+        let x: Vec<f32> = (0..64).map(|i| (i as f32 * 0.29).sin() * 3.0).collect();
+        let q = quantize_q8(&x);
+        assert_eq!((q.scales.len(), q.quants.len()), (2, 64));
+        assert!(q.quants.iter().all(|&v| v >= -127) && q.quants.iter().any(|&v| v.abs() == 127));
+        // A Q8_0 row of two blocks with weights w[i] = (i % 7 - 3) * 0.5.
+        let mut row = Vec::new();
+        for _ in 0..2 {
+            row.extend(half::f16::from_f32(0.5).to_bits().to_le_bytes());
+            row.extend((0..32).map(|i| (i % 7 - 3) as i8 as u8));
+        }
+        let w = crate::tensor::TensorStore::dequant_q8_0_row(&row);
+        let exact: f32 = w.iter().zip(&x).map(|(w, x)| w * x).sum();
+        let got = dot_q8_0(&row, &q);
+        assert!((got - exact).abs() < 0.02 * (1.0 + exact.abs()), "{got} vs {exact}");
+        assert_eq!(dot_q8_0(&row, &quantize_q8(&[0.0; 64])), 0.0);
+    }
+
+    #[test]
+    fn sgemm_matches_one_dot_per_output() {
+        let (batch, n, k) = (3, 5, 37);
+        let x: Vec<f32> = (0..batch * k).map(|i| (i as f32 * 0.13).sin()).collect();
+        let w: Vec<f32> = (0..n * k).map(|i| (i as f32 * 0.07).cos()).collect();
+        let out = crate::blas::sgemm(&x, &w, batch, n, k);
+        assert_eq!(out.len(), batch * n);
+        for t in 0..batch {
+            for r in 0..n {
+                let expected = crate::cpu::dot_with(crate::cpu::CpuKernel::Scalar, &x[t * k..][..k], &w[r * k..][..k]);
+                assert!((out[t * n + r] - expected).abs() < 1e-4, "t{t} r{r}");
+            }
+        }
+    }
+
+    // -------------------------------------------------------------------------
+    // Multiple devices: tensor and pipeline parallelism
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn shard_splits_follow_megatron_and_whole_blocks() {
+        use crate::shard::{Split, split_ranges};
+        assert_eq!(Split::of("blk.3.attn_q.weight"), Split::Rows);
+        assert_eq!(Split::of("blk.3.attn_output.weight"), Split::Cols);
+        assert_eq!(Split::of("blk.0.ffn_down.weight"), Split::Cols);
+        assert_eq!(Split::of("output.weight"), Split::Rows);
+        assert_eq!(split_ranges(10, 3, 1), [0..4, 4..7, 7..10]);
+        assert_eq!(split_ranges(96, 2, 32), [0..64, 64..96]);
+        assert_eq!(split_ranges(32, 2, 32), [0..32, 32..32]);
+    }
+
+    #[test]
+    fn sharded_q8_0_slices_recombine_to_the_full_matmul() {
+        use crate::shard::{concat_outputs, slice_cols, slice_rows, split_ranges, sum_partials};
+        use crate::tensor::GGML_Q8_0;
+        let (n, k, batch) = (3, 96, 2);
+        let mut w = Vec::new();
+        for b in 0..n * k / 32 {
+            w.extend(make_q8_0_block(0x3C00, std::array::from_fn(|i| ((b * 7 + i * 3) % 19) as i8 - 9)));
+        }
+        let x: Vec<f32> = (0..batch * k).map(|i| (i as f32 * 0.21).sin()).collect();
+        let matmul = |w: &[u8], x: &[f32], n: usize, k: usize| -> Vec<f32> {
+            let rows: Vec<_> = w.chunks_exact(w.len() / n).map(TensorStore::dequant_q8_0_row).collect();
+            x.chunks_exact(k).flat_map(|xt| rows.iter().map(|r| crate::cpu::dot(xt, r)).collect::<Vec<_>>()).collect()
+        };
+        let full = matmul(&w, &x, n, k);
+        let close = |got: &[f32]| got.len() == full.len() && got.iter().zip(&full).all(|(a, b)| (a - b).abs() < 1e-3);
+
+        let rows = split_ranges(n, 2, 1);
+        let parts: Vec<_> =
+            rows.iter().map(|r| matmul(&slice_rows(&w, GGML_Q8_0, k, r.clone()), &x, r.len(), k)).collect();
+        assert!(close(&concat_outputs(&parts, &rows, n, batch)));
+
+        let partials: Vec<_> = split_ranges(k, 2, 32)
+            .into_iter()
+            .map(|c| {
+                let xs: Vec<f32> = x.chunks_exact(k).flat_map(|xt| &xt[c.clone()]).copied().collect();
+                matmul(&slice_cols(&w, GGML_Q8_0, n, k, c.clone()), &xs, n, c.len())
+            })
+            .collect();
+        assert!(close(&sum_partials(&partials, n * batch)));
+    }
+
+    #[test]
+    fn pipeline_cuts_prompts_into_micro_batches() {
+        use crate::pipeline::{Pipeline, micro_batches};
+        assert_eq!(micro_batches(10, 4), [0..4, 4..8, 8..10]);
+        let short = micro_batches(3, 16);
+        assert_eq!((short.len(), short[0].clone()), (1, 0..3));
+        assert!(micro_batches(0, 4).is_empty());
+        let err = Pipeline::new(&[0, 0, 0], &[], 2, 4).err().unwrap();
+        assert!(err.to_string().contains("3 pipeline stages for 2 layers"), "{err}");
+        assert!(Pipeline::new(&[], &["localhost:1".into()], 2, 4).is_err());
+        let err = Pipeline::new(&[0], &["localhost:1".into()], 2, 5000).err().unwrap();
+        assert!(err.to_string().contains("more than a worker takes"), "{err}");
+    }
+
+    #[test]
+    fn rpc_frames_carry_tensors_and_the_model_shape() {
+        use crate::rpc::{arch_from_json, arch_to_json, f32_bytes, f32_from_bytes, layer_from_json, layer_to_json, read_payload, write_payload};
+        let rows = [1.5f32, -2.0, 0.25];
+        let mut wire = Vec::new();
+        write_payload(&mut wire, &serde_json::json!({ "op": "forward", "pos": 3, "bytes": 12 }), &f32_bytes(&rows)).unwrap();
+        let mut r = wire.as_slice();
+        let header = crate::daemon::read_frame(&mut r).unwrap().unwrap();
+        assert_eq!(header["pos"], 3);
+        assert_eq!(f32_from_bytes(&read_payload(&mut r, &header, 12).unwrap()), rows);
+        assert!(r.is_empty());
+        assert!(read_payload(&mut r, &serde_json::json!({ "bytes": 13 }), 12).is_err());
+        // A header claiming a gigabyte over an empty stream allocates nothing of the sort.
+        let err = read_payload(&mut r, &serde_json::json!({ "bytes": 1u64 << 30 }), 1 << 31).unwrap_err();
+        assert!(err.to_string().contains("truncated payload: 0 of"), "{err}");
+
+        let mut arch = tiny_arch();
+        arch.rope_scaling = crate::model::RopeScaling::Yarn(crate::model::YarnParams {
+            factor: 4.0, original_ctx: 4096, beta_fast: 32.0, beta_slow: 1.0,
+        });
+        arch.scaling.attn_softcap = Some(50.0);
+        arch.scaling.alibi_max_bias = Some(8.0);
+        let back = arch_from_json(&arch_to_json(&arch)).unwrap();
+        assert_eq!(format!("{back:?}"), format!("{arch:?}"));
+        let w = crate::weights::ModelWeights::from_index(&tiny_index(), &tiny_arch()).unwrap();
+        let layer = layer_from_json(&layer_to_json(&w.layers[0])).unwrap();
+        assert_eq!(format!("{layer:?}"), format!("{:?}", w.layers[0]));
+    }
+
+    #[test]
+    fn rpc_worker_refuses_requests_out_of_order() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || crate::rpc::serve(listener));
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        let mut ask = |req: serde_json::Value| {
+            crate::daemon::write_frame(&mut stream, &req).unwrap();
+            crate::daemon::read_frame(&mut stream).unwrap().unwrap()["error"].as_str().unwrap_or_default().to_string()
+        };
+        assert!(ask(serde_json::json!({ "op": "forward", "pos": 0, "bytes": 0 })).contains("forward before load"));
+        assert!(ask(serde_json::json!({ "op": "tensor", "name": "blk.0.attn_q.weight", "kind": 8, "bytes": 0 })).contains("tensor before load"));
+        assert!(ask(serde_json::json!({ "op": "reboot" })).contains("unknown op"));
+        // Shapes are checked against the arch before any device is opened.
+        let w = crate::weights::ModelWeights::from_index(&tiny_index(), &tiny_arch()).unwrap();
+        let mut layer = crate::rpc::layer_to_json(&w.layers[0]);
+        layer["attn_norm"]["cols"] = 48.into();
+        let load = serde_json::json!({ "op": "load", "arch": crate::rpc::arch_to_json(&tiny_arch()), "first_layer": 0, "layers": [layer] });
+        assert!(ask(load).contains("blk.0.attn_norm.weight is 1x48, expected 1x64"));
+        // Sizes derived from a hostile arch are checked, not wrapped.
+        let layer = crate::rpc::layer_to_json(&w.layers[0]);
+        for (field, value, want) in [("hidden", usize::MAX / 2, "arch.hidden"), ("n_heads", usize::MAX / 2, "overflows")] {
+            let mut arch = crate::rpc::arch_to_json(&tiny_arch());
+            arch[field] = value.into();
+            let load = serde_json::json!({ "op": "load", "arch": arch, "first_layer": 0, "layers": [layer] });
+            let err = ask(load);
+            assert!(err.contains(want), "{field}: {err}");
+        }
+
+        // A payload refused unread ends the connection instead of being allocated.
+        crate::daemon::write_frame(&mut stream, &serde_json::json!({ "op": "forward", "pos": 0, "bytes": 1u64 << 34 })).unwrap();
+        let reply = crate::daemon::read_frame(&mut stream).unwrap().unwrap();
+        assert!(reply["error"].as_str().unwrap().contains("forward before load"), "{reply}");
+        assert!(crate::daemon::read_frame(&mut stream).unwrap().is_none());
+    }
+
+    // -------------------------------------------------------------------------
+    // GPU profile
+    // -------------------------------------------------------------------------
+
+    #[test]
+    fn weight_scope_splits_block_tensors_into_layer_and_op() {
+        use crate::profile::weight_scope;
+        assert_eq!(weight_scope("blk.12.attn_q.weight"), (Some(12), "attn_q"));
+        assert_eq!(weight_scope("blk.0.ffn_down.weight"), (Some(0), "ffn_down"));
+        assert_eq!(weight_scope("output.weight"), (None, "output"));
+        assert_eq!(weight_scope("medusa.1.fc.weight"), (None, "medusa"));
+    }
+
+    #[test]
+    fn gpu_profile_sums_by_half_op_and_layer() {
+        use std::time::Duration;
+        let ms = Duration::from_millis;
+        let mut p = crate::profile::GpuProfile::default();
+        for layer in 0..2 {
+            p.record(Some(layer), "attn_q", ms(1));
+            p.record(Some(layer), "ffn_down", ms(3));
+            p.record(Some(layer), "ffn_act", ms(1));
+        }
+        p.record(None, "output", ms(5));
+
+        let total = p.total();
+        assert_eq!((total.dispatches, total.time), (7, ms(15)));
+        let halves: Vec<_> = p.by_half().iter().map(|(name, t)| (*name, t.time)).collect();
+        assert_eq!(halves, [("attention", ms(2)), ("ffn", ms(8)), ("other", ms(5))]);
+        let ops: Vec<_> = p.by_op().into_iter().map(|(op, t)| (op, t.dispatches)).collect();
+        assert_eq!(ops[0], ("ffn_down".to_string(), 2));
+        let layers: Vec<_> = p.by_layer().into_iter().map(|(l, t)| (l, t.time)).collect();
+        assert_eq!(layers, [(Some(0), ms(5)), (Some(1), ms(5)), (None, ms(5))]);
+        assert_eq!(p.scaled(2.0).total().time, ms(30));
+
+        let report = p.report();
+        assert!(report.starts_with("GPU time: 15.00ms in 7 dispatches"), "{report}");
+        assert!(report.contains("ffn                   8.00ms  53.3%"), "{report}");
+    }
+
+    // -------------------------------------------------------------------------
+    // Golden model: a tiny synthetic llama GGUF, end to end
+    //
+    // The file is generated, not shipped: deterministic Q8_0 weights, a
+    // 16-token vocab, two layers. A plain CPU forward over the same
+    // dequantized weights is the reference. The CPU test pins its greedy
+    // output so generator or reference drift shows up; the GPU test (skipped
+    // without a Metal device) checks tokenizer → forward → sampling against it.
+    // -------------------------------------------------------------------------
+
+    const GOLDEN_VOCAB: [&str; 16] = [
+        "<unk>", "<s>", "</s>", "Ġthe", "Ġcat", "Ġsat", "Ġon", "Ġmat",
+        "Ġa", "Ġdog", "Ġran", "Ġto", "Ġit", "Ġand", "Ġred", ".",
+    ];
+    const GOLDEN_PROMPT: &str = "the cat sat";
+    const GOLDEN_MAX_NEW: usize = 6;
+    /// Greedy continuation of `GOLDEN_PROMPT`, pinned from the CPU reference.
+    const GOLDEN_TOKENS: [u32; 7] = [3, 8, 7, 4, 15, 14, 5];
+
+    /// Dequantized weights by name: (rows, cols, row-major values).
+    type GoldenWeights = std::collections::HashMap<String, (usize, usize, Vec<f32>)>;
+
+    /// GGUF v3 bytes for `tiny_arch()` with two layers and a tied head, plus
+    /// the exact f32 values the Q8_0 blocks decode to.
+    fn golden_gguf() -> (Vec<u8>, GoldenWeights) {
+        let arch = crate::model::Arch { n_layers: 2, vocab_size: GOLDEN_VOCAB.len(), ..tiny_arch() };
+        let mut seed = 0x2545_F491_4F6C_DD1Du64;
+        let mut rand = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+        };
+
+        // (name, rows, cols, quantized?)
+        let mut specs = vec![("token_embd.weight".to_string(), arch.vocab_size, arch.hidden, true)];
+        for l in 0..arch.n_layers {
+            let (h, q, kv, f) = (arch.hidden, arch.n_heads * arch.head_dim, arch.n_kv_heads * arch.head_dim, arch.ffn_hidden);
+            specs.extend([
+                (format!("blk.{l}.attn_norm.weight"), 1, h, false),
+                (format!("blk.{l}.attn_q.weight"), q, h, true),
+                (format!("blk.{l}.attn_k.weight"), kv, h, true),
+                (format!("blk.{l}.attn_v.weight"), kv, h, true),
+                (format!("blk.{l}.attn_output.weight"), h, q, true),
+                (format!("blk.{l}.ffn_norm.weight"), 1, h, false),
+                (format!("blk.{l}.ffn_gate.weight"), f, h, true),
+                (format!("blk.{l}.ffn_up.weight"), f, h, true),
+                (format!("blk.{l}.ffn_down.weight"), h, f, true),
+            ]);
+        }
+        specs.push(("output_norm.weight".to_string(), 1, arch.hidden, false));
+
+        let mut weights = GoldenWeights::new();
+        let mut gguf = GgufBuilder::new()
+            .architecture("llama")
+            .meta_u32("llama.embedding_length", arch.hidden as u32)
+            .meta_u32("llama.block_count", arch.n_layers as u32)
+            .meta_u32("llama.attention.head_count", arch.n_heads as u32)
+            .meta_u32("llama.attention.head_count_kv", arch.n_kv_heads as u32)
+            .meta_u32("llama.feed_forward_length", arch.ffn_hidden as u32)
+            .meta_u32("llama.context_length", arch.ctx_train as u32)
+            .meta_strings("tokenizer.ggml.tokens", &GOLDEN_VOCAB);
+        for (name, rows, cols, quantized) in &specs {
+            let dims = if *rows == 1 { vec![*cols as u64] } else { vec![*cols as u64, *rows as u64] };
+            if *quantized {
+                let values: Vec<f32> = (0..rows * cols)
+                    .map(|i| {
+                        // Shrink the </s> embedding so the tied head rarely picks EOS
+                        // and the pinned continuation has some length.
+                        let eos_row = name == "token_embd.weight" && i / cols == 2;
+                        rand() * if eos_row { 0.02 } else { 0.5 }
+                    })
+                    .collect();
+                let (bytes, decoded) = crate::test_support::q8_0(&values);
+                gguf = gguf.tensor(name, &dims, crate::tensor::GGML_Q8_0, bytes);
+                weights.insert(name.clone(), (*rows, *cols, decoded));
+            } else {
+                let values: Vec<f32> = (0..*cols).map(|_| 1.0 + 0.1 * rand()).collect();
+                gguf = gguf.tensor_f32(name, &dims, &values);
+                weights.insert(name.clone(), (*rows, *cols, values));
+            }
+        }
+        (gguf.build(), weights)
+    }
+
+    /// Straightforward CPU llama forward over every position of `tokens`.
+    fn golden_reference_logits(w: &GoldenWeights, tokens: &[u32]) -> Vec<Vec<f32>> {
+        let arch = crate::model::Arch { n_layers: 2, vocab_size: GOLDEN_VOCAB.len(), ..tiny_arch() };
+        let (hd, nh, nkv) = (arch.head_dim, arch.n_heads, arch.n_kv_heads);
+        let get = |n: &str| &w[n];
+        let matvec = |n: &str, x: &[f32]| -> Vec<f32> {
+            let (rows, cols, v) = get(n);
+            (0..*rows).map(|r| (0..*cols).map(|c| v[r * cols + c] * x[c]).sum()).collect()
+        };
+        let norm = |n: &str, x: &[f32]| -> Vec<f32> {
+            let inv = 1.0 / (x.iter().map(|v| v * v).sum::<f32>() / x.len() as f32 + 1e-5).sqrt();
+            x.iter().zip(&get(n).2).map(|(a, b)| a * inv * b).collect()
+        };
+        let mut ks = vec![Vec::<Vec<f32>>::new(); arch.n_layers];
+        let mut vs = vec![Vec::<Vec<f32>>::new(); arch.n_layers];
+        let mut out = Vec::new();
+        for (pos, &tok) in tokens.iter().enumerate() {
+            let embd = &get("token_embd.weight").2;
+            let mut x = embd[tok as usize * arch.hidden..][..arch.hidden].to_vec();
+            for l in 0..arch.n_layers {
+                let xn = norm(&format!("blk.{l}.attn_norm.weight"), &x);
+                let mut q = matvec(&format!("blk.{l}.attn_q.weight"), &xn);
+                let mut k = matvec(&format!("blk.{l}.attn_k.weight"), &xn);
+                let v = matvec(&format!("blk.{l}.attn_v.weight"), &xn);
+                crate::model::rope(&mut q, nh, hd, pos, arch.rope_base, arch.rope_scaling);
+                crate::model::rope(&mut k, nkv, hd, pos, arch.rope_base, arch.rope_scaling);
+                ks[l].push(k);
+                vs[l].push(v);
+                let mut attn = vec![0.0f32; nh * hd];
+                for h in 0..nh {
+                    let kvh = h / (nh / nkv);
+                    let qh = &q[h * hd..][..hd];
+                    let scores: Vec<f32> = ks[l]
+                        .iter()
+                        .map(|k| qh.iter().zip(&k[kvh * hd..][..hd]).map(|(a, b)| a * b).sum::<f32>() / (hd as f32).sqrt())
+                        .collect();
+                    let max = scores.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                    let e: Vec<f32> = scores.iter().map(|s| (s - max).exp()).collect();
+                    let sum: f32 = e.iter().sum();
+                    for (t, p) in e.iter().enumerate() {
+                        for i in 0..hd {
+                            attn[h * hd + i] += p / sum * vs[l][t][kvh * hd + i];
+                        }
+                    }
+                }
+                let o = matvec(&format!("blk.{l}.attn_output.weight"), &attn);
+                x.iter_mut().zip(&o).for_each(|(a, b)| *a += b);
+                let xn2 = norm(&format!("blk.{l}.ffn_norm.weight"), &x);
+                let g = matvec(&format!("blk.{l}.ffn_gate.weight"), &xn2);
+                let u = matvec(&format!("blk.{l}.ffn_up.weight"), &xn2);
+                let mid: Vec<f32> = g.iter().zip(&u).map(|(g, u)| g / (1.0 + (-g).exp()) * u).collect();
+                let d = matvec(&format!("blk.{l}.ffn_down.weight"), &mid);
+                x.iter_mut().zip(&d).for_each(|(a, b)| *a += b);
+            }
+            out.push(matvec("token_embd.weight", &norm("output_norm.weight", &x)));
+        }
+        out
+    }
+
+    /// Greedy decode with the reference, stopping like `generate` does.
+    fn golden_reference_greedy(w: &GoldenWeights, prompt: &[u32]) -> Vec<u32> {
+        let mut ctx = prompt.to_vec();
+        let mut out = Vec::new();
+        for _ in 0..=GOLDEN_MAX_NEW {
+            let logits = golden_reference_logits(w, &ctx);
+            let id = crate::sampler::argmax(logits.last().unwrap());
+            if id == 2 {
+                break;
+            }
+            out.push(id);
+            ctx.push(id);
+        }
+        out
+    }
+
+    fn golden_prompt() -> Vec<u32> {
+        let vocab = GOLDEN_VOCAB.map(String::from).to_vec();
+        PromptTokenizer::new(vocab).tokenize_bos(GOLDEN_PROMPT)
+    }
+
+    #[test]
+    fn gguf_summary_detects_a_tied_output_head() {
+        let (bytes, _) = golden_gguf();
+        let path = std::env::temp_dir().join(format!("llmetal-tied-{}.gguf", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let info = crate::gguf_loader::GgufModelInfo::load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(info.unwrap().architecture.tied_embeddings);
+    }
+
+    #[test]
+    fn golden_model_parses_and_reference_output_is_pinned() {
+        let (bytes, w) = golden_gguf();
+        let gguf = crate::gguf::GgufFile::parse(&bytes).unwrap();
+        let index = crate::tensor::index_tensors(&gguf).unwrap();
+        let arch = crate::model::Arch { n_layers: 2, vocab_size: GOLDEN_VOCAB.len(), ..tiny_arch() };
+        crate::weights::ModelWeights::from_index(&index, &arch).unwrap();
+
+        let prompt = golden_prompt();
+        assert_eq!(prompt, vec![1, 3, 4, 5], "tokenizer: <s> the cat sat");
+        assert_eq!(golden_reference_greedy(&w, &prompt), GOLDEN_TOKENS);
+    }
+
+    /// The golden GGUF loaded on the GPU, or `None` without a Metal device.
+    fn golden_gpu_model(tag: &str) -> Option<(crate::model::LlamaModel, Vec<String>, GoldenWeights)> {
+        if metal::Device::system_default().is_none() {
+            eprintln!("skipping golden GPU test: no Metal device");
+            return None;
+        }
+        let (bytes, w) = golden_gguf();
+        let path = std::env::temp_dir().join(format!("llmetal-golden-{tag}-{}.gguf", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let model = crate::model::LlamaModel::load(path.to_str().unwrap()).unwrap();
+        let vocab = crate::gguf_loader::GgufModelInfo::load(path.to_str().unwrap()).unwrap().vocab;
+        std::fs::remove_file(&path).unwrap();
+        Some((model, vocab, w))
+    }
+
+    fn golden_greedy_opts(max_new: usize) -> crate::model::GenerateOptions {
+        crate::model::GenerateOptions {
+            max_new,
+            sampling: crate::sampler::SamplerConfig { repetition_penalty: 1.0, ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn golden_model_gpu_matches_reference() {
+        let Some((mut model, vocab, w)) = golden_gpu_model("ref") else { return };
+        let prompt = PromptTokenizer::new(vocab.clone()).tokenize_bos(GOLDEN_PROMPT);
+
+        let reference = golden_reference_logits(&w, &prompt);
+        for (pos, (got, want)) in model.evaluate(&prompt).unwrap().iter().zip(&reference).enumerate() {
+            for (g, r) in got.iter().zip(want) {
+                assert!((g - r).abs() <= 1e-3 * (1.0 + r.abs()), "pos {pos}: {g} vs {r}");
+            }
+        }
+
+        let opts = golden_greedy_opts(GOLDEN_MAX_NEW);
+        let mut tokens = Vec::new();
+        model
+            .generate(&prompt, &opts, &vocab, &mut |e| {
+                if let crate::events::GenerationEvent::Token { id, .. } = e {
+                    tokens.push(id);
+                }
+            })
+            .unwrap();
+        assert_eq!(tokens, GOLDEN_TOKENS);
+    }
+
+    #[test]
+    fn golden_model_warmup_leaves_the_output_and_imatrix_alone() {
+        let Some((mut model, vocab, _)) = golden_gpu_model("warmup") else { return };
+        model.load_all_tensors(1).unwrap();
+        model.enable_imatrix();
+        let stats = model.warmup().unwrap();
+        assert!(stats.bytes > 0);
+        assert!(model.take_imatrix().unwrap().entries.is_empty(), "the warm-up pass fed the imatrix");
+
+        let mut tokens = Vec::new();
+        model
+            .generate(&golden_prompt(), &golden_greedy_opts(GOLDEN_MAX_NEW), &vocab, &mut |e| {
+                if let crate::events::GenerationEvent::Token { id, .. } = e {
+                    tokens.push(id);
+                }
+            })
+            .unwrap();
+        assert_eq!(tokens, GOLDEN_TOKENS);
+    }
+
+    #[test]
+    fn golden_model_prefill_chunks_match_one_pass() {
+        let Some((mut model, vocab, w)) = golden_gpu_model("chunks") else { return };
+        let prompt = golden_prompt();
+        let reference = golden_reference_logits(&w, &prompt);
+        for chunk in [1, 3, crate::model::PREFILL_CHUNK] {
+            model.set_prefill_chunk(chunk);
+            for (pos, (got, want)) in model.evaluate(&prompt).unwrap().iter().zip(&reference).enumerate() {
+                for (g, r) in got.iter().zip(want) {
+                    assert!((g - r).abs() <= 1e-3 * (1.0 + r.abs()), "chunk {chunk}, pos {pos}: {g} vs {r}");
+                }
+            }
+            let mut tokens = Vec::new();
+            model
+                .generate(&prompt, &golden_greedy_opts(GOLDEN_MAX_NEW), &vocab, &mut |e| {
+                    if let crate::events::GenerationEvent::Token { id, .. } = e {
+                        tokens.push(id);
+                    }
+                })
+                .unwrap();
+            assert_eq!(tokens, GOLDEN_TOKENS, "chunk {chunk}");
+        }
+    }
+
+    #[test]
+    fn golden_model_gpu_embedding_lookup_matches_the_reference() {
+        let Some((mut model, _, w)) = golden_gpu_model("get-rows") else { return };
+        // Long enough for the `get_rows` kernel rather than the CPU rows.
+        let prompt: Vec<u32> = golden_prompt().into_iter().cycle().take(crate::model::GPU_EMBED_MIN + 3).collect();
+        let reference = golden_reference_logits(&w, &prompt);
+        for (pos, (got, want)) in model.evaluate(&prompt).unwrap().iter().zip(&reference).enumerate() {
+            for (g, r) in got.iter().zip(want) {
+                assert!((g - r).abs() <= 1e-3 * (1.0 + r.abs()), "pos {pos}: {g} vs {r}");
+            }
+        }
+    }
+
+    #[test]
+    fn golden_model_fused_weights_match_the_reference() {
+        let Some((mut model, vocab, w)) = golden_gpu_model("fused") else { return };
+        assert_eq!(model.fuse_weights().unwrap(), 2 * model.arch.n_layers);
+        let prompt = golden_prompt();
+        let reference = golden_reference_logits(&w, &prompt);
+        for (pos, (got, want)) in model.evaluate(&prompt).unwrap().iter().zip(&reference).enumerate() {
+            for (g, r) in got.iter().zip(want) {
+                assert!((g - r).abs() <= 1e-3 * (1.0 + r.abs()), "pos {pos}: {g} vs {r}");
+            }
+        }
+        // Blocks on the CPU read the stacks' rows from their parts.
+        model.set_gpu_layers(0);
+        let mut tokens = Vec::new();
+        model
+            .generate(&prompt, &golden_greedy_opts(GOLDEN_MAX_NEW), &vocab, &mut |e| {
+                if let crate::events::GenerationEvent::Token { id, .. } = e {
+                    tokens.push(id);
+                }
+            })
+            .unwrap();
+        assert_eq!(tokens, GOLDEN_TOKENS);
+    }
+
+    #[test]
+    fn golden_model_reports_prefill_progress_per_chunk() {
+        let Some((mut model, vocab, _)) = golden_gpu_model("progress") else { return };
+        let prompt = golden_prompt();
+        model.set_prefill_chunk(3);
+        let mut progress = Vec::new();
+        model
+            .generate(&prompt, &golden_greedy_opts(1), &vocab, &mut |e| {
+                if let crate::events::GenerationEvent::PrefillProgress { done, total, .. } = e {
+                    progress.push((done, total));
+                }
+            })
+            .unwrap();
+        let want: Vec<_> = (1..=prompt.len().div_ceil(3)).map(|i| ((i * 3).min(prompt.len()), prompt.len())).collect();
+        assert_eq!(progress, want);
+
+        // A prompt that fits one chunk has nothing to report.
+        model.set_prefill_chunk(crate::model::PREFILL_CHUNK);
+        let mut any = false;
+        model
+            .generate(&prompt, &golden_greedy_opts(1), &vocab, &mut |e| {
+                any |= matches!(e, crate::events::GenerationEvent::PrefillProgress { .. });
+            })
+            .unwrap();
+        assert!(!any);
+    }
+
+    #[test]
+    fn golden_model_stitched_first_section_matches_a_full_prefill() {
+        let Some((mut model, vocab, _)) = golden_gpu_model("sections") else { return };
+        let prompt = golden_prompt();
+        let mut cache = crate::section_cache::SectionCache::default();
+        let sections = vec![prompt[..2].to_vec()];
+        for round in 0..2 {
+            let mut session = crate::model::KvSession::new(&model);
+            assert_eq!(model.stitch_sections(&mut cache, &mut session, &sections).unwrap(), 2);
+            assert_eq!(session.tokens(), &prompt[..2]);
+            let mut tokens = Vec::new();
+            let reused = model
+                .generate_cached(&mut session, &prompt, &golden_greedy_opts(GOLDEN_MAX_NEW), &vocab, &mut |e| {
+                    if let crate::events::GenerationEvent::Token { id, .. } = e {
+                        tokens.push(id);
+                    }
+                })
+                .unwrap();
+            assert_eq!(reused, 2);
+            assert_eq!(tokens, GOLDEN_TOKENS, "round {round}");
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.sections, stats.hits, stats.misses), (1, 1, 1));
+
+        // A second section lands after the first, rows and tokens alike.
+        let mut session = crate::model::KvSession::new(&model);
+        let two = vec![prompt[..2].to_vec(), prompt[2..].to_vec()];
+        assert_eq!(model.stitch_sections(&mut cache, &mut session, &two).unwrap(), prompt.len());
+        assert_eq!(session.tokens(), &prompt[..]);
+        assert_eq!(session.stats().positions, prompt.len());
+    }
+
+    #[test]
+    fn golden_model_stops_on_a_stop_token() {
+        let Some((mut model, vocab, _)) = golden_gpu_model("stop") else { return };
+        let opts = crate::model::GenerateOptions { stop_tokens: vec![GOLDEN_TOKENS[2]], ..golden_greedy_opts(GOLDEN_MAX_NEW) };
+        let (mut tokens, mut reason) = (Vec::new(), None);
+        model
+            .generate(&golden_prompt(), &opts, &vocab, &mut |e| match e {
+                crate::events::GenerationEvent::Token { id, .. } => tokens.push(id),
+                crate::events::GenerationEvent::Done { reason: r, .. } => reason = Some(r),
+                _ => {}
+            })
+            .unwrap();
+        assert_eq!(tokens, GOLDEN_TOKENS[..2]);
+        assert_eq!(reason, Some(crate::events::FinishReason::Stop));
+    }
+
+    #[test]
+    fn golden_model_batched_generation_matches_one_at_a_time() {
+        let Some((mut model, vocab, _)) = golden_gpu_model("batch") else { return };
+        let full = golden_prompt();
+        let prompts = vec![full.clone(), full[..2].to_vec(), full[1..].to_vec()];
+        let opts = golden_greedy_opts(GOLDEN_MAX_NEW);
+        let expected: Vec<Vec<u32>> = prompts
+            .iter()
+            .map(|p| {
+                let mut tokens = Vec::new();
+                model
+                    .generate(p, &opts, &vocab, &mut |e| {
+                        if let crate::events::GenerationEvent::Token { id, .. } = e {
+                            tokens.push(id);
+                        }
+                    })
+                    .unwrap();
+                tokens
+            })
+            .collect();
+        // Two slots for three prompts: the third joins when one finishes.
+        let mut got = vec![Vec::new(); prompts.len()];
+        let mut done = 0;
+        model
+            .generate_batch(&prompts, &opts, 2, &vocab, &mut |i, e| match e {
+                crate::events::GenerationEvent::Token { id, .. } => got[i].push(id),
+                crate::events::GenerationEvent::Done { .. } => done += 1,
+                _ => {}
+            })
+            .unwrap();
+        assert_eq!(done, prompts.len());
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn golden_model_daemon_streams_the_greedy_continuation() {
+        if metal::Device::system_default().is_none() {
+            return;
+        }
+        let (bytes, _) = golden_gguf();
+        let path = std::env::temp_dir().join(format!("llmetal-golden-daemon-{}.gguf", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let mut daemon = crate::daemon::Daemon::load(path.to_str().unwrap(), 1).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let req = serde_json::json!({
+            "op": "generate", "prompt": GOLDEN_PROMPT, "max_tokens": GOLDEN_MAX_NEW, "repeat_penalty": 1.0,
+        });
+        let mut out = Vec::new();
+        assert!(daemon.handle(&req, &mut out).unwrap());
+        let mut frames = Vec::new();
+        let mut r = &out[..];
+        while let Some(f) = crate::daemon::read_frame(&mut r).unwrap() {
+            frames.push(f);
+        }
+        let ids: Vec<u32> = frames.iter().filter(|f| f["event"] == "token").map(|f| f["id"].as_u64().unwrap() as u32).collect();
+        assert_eq!(ids, GOLDEN_TOKENS);
+        let done = frames.last().unwrap();
+        assert_eq!((done["event"].as_str(), done["usage"]["completion_tokens"].as_u64()), (Some("done"), Some(7)));
+
+        // The same prompt again, as token ids: its K/V rows are still there.
+        let req = serde_json::json!({
+            "op": "generate", "tokens": golden_prompt(), "max_tokens": GOLDEN_MAX_NEW, "repeat_penalty": 1.0,
+        });
+        let mut out = Vec::new();
+        daemon.handle(&req, &mut out).unwrap();
+        let mut r = &out[..];
+        let (mut ids, mut done) = (Vec::new(), serde_json::Value::Null);
+        while let Some(f) = crate::daemon::read_frame(&mut r).unwrap() {
+            match f["event"].as_str() {
+                Some("token") => ids.push(f["id"].as_u64().unwrap() as u32),
+                _ => done = f,
+            }
+        }
+        assert_eq!(ids, GOLDEN_TOKENS);
+        assert!(done["timings"]["prefill_tokens"].as_u64().unwrap() < golden_prompt().len() as u64);
+        let usage = &done["usage"];
+        assert_eq!(usage["prompt_tokens_details"]["cached_tokens"], golden_prompt().len() - 1);
+        assert_eq!(usage["total_tokens"], golden_prompt().len() + GOLDEN_TOKENS.len());
+
+        // tokenize gives the ids generate prefilled; detokenize maps them
+        // back (BOS left out: the golden vocab marks nothing special).
+        let mut out = Vec::new();
+        daemon.handle(&serde_json::json!({ "op": "tokenize", "text": GOLDEN_PROMPT }), &mut out).unwrap();
+        let reply = crate::daemon::read_frame(&mut &out[..]).unwrap().unwrap();
+        let ids: Vec<u64> = reply["tokens"].as_array().unwrap().iter().map(|t| t["id"].as_u64().unwrap()).collect();
+        assert_eq!(ids, golden_prompt().iter().map(|&id| id as u64).collect::<Vec<_>>());
+        let mut out = Vec::new();
+        daemon.handle(&serde_json::json!({ "op": "detokenize", "tokens": golden_prompt()[1..], "special": false }), &mut out).unwrap();
+        let reply = crate::daemon::read_frame(&mut &out[..]).unwrap().unwrap();
+        let text = reply["text"].as_str().unwrap();
+        assert_eq!(text.trim(), GOLDEN_PROMPT);
+        assert_eq!(reply["tokens"].as_array().unwrap().last().unwrap()["end"], text.len());
+    }
+
+    #[test]
+    fn golden_model_daemon_keeps_sessions_by_id() {
+        if metal::Device::system_default().is_none() {
+            return;
+        }
+        let (bytes, _) = golden_gguf();
+        let path = std::env::temp_dir().join(format!("llmetal-golden-sessions-{}.gguf", std::process::id()));
+        std::fs::write(&path, &bytes).unwrap();
+        let mut daemon = crate::daemon::Daemon::load(path.to_str().unwrap(), 1).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let call = |daemon: &mut crate::daemon::Daemon, req: serde_json::Value| {
+            let mut out = Vec::new();
+            daemon.handle(&req, &mut out).unwrap();
+            let mut r = &out[..];
+            let mut last = serde_json::Value::Null;
+            while let Some(f) = crate::daemon::read_frame(&mut r).unwrap() {
+                last = f;
+            }
+            last
+        };
+        let generate = |id: &str, tokens: &[u32]| {
+            serde_json::json!({ "op": "generate", "session": id, "tokens": tokens, "max_tokens": 2, "repeat_penalty": 1.0 })
+        };
+
+        // "b" between the two "a" requests does not evict a's rows.
+        let prompt = golden_prompt();
+        call(&mut daemon, generate("a", &prompt));
+        call(&mut daemon, generate("b", &prompt[..2]));
+        let done = call(&mut daemon, generate("a", &prompt));
+        assert_eq!(done["usage"]["prompt_tokens_details"]["cached_tokens"], prompt.len() - 1);
+
+        let list = call(&mut daemon, serde_json::json!({ "op": "sessions" }));
+        let mut ids: Vec<&str> = list["sessions"].as_array().unwrap().iter().map(|s| s["id"].as_str().unwrap()).collect();
+        ids.sort();
+        assert_eq!(ids, ["a", "b"]);
+        assert_eq!(call(&mut daemon, serde_json::json!({ "op": "drop_session", "session": "b" }))["dropped"], true);
+        assert_eq!(call(&mut daemon, serde_json::json!({ "op": "info" }))["sessions"], 1);
+
+        // Under a one-byte cap "a" goes first, then "c" itself.
+        let limits = crate::daemon::Limits { session_bytes: 1, ..Default::default() };
+        let mut daemon = daemon.with_limits(limits);
+        call(&mut daemon, generate("c", &prompt));
+        let list = call(&mut daemon, serde_json::json!({ "op": "sessions" }));
+        assert_eq!(list["sessions"], serde_json::json!([]));
+    }
+
+    #[test]
+    fn golden_model_profile_charges_every_dispatch() {
+        let Some((mut model, vocab, _)) = golden_gpu_model("profile") else { return };
+        if model.enable_gpu_profile().is_err() {
+            return;
+        }
+        model.generate(&golden_prompt(), &golden_greedy_opts(2), &vocab, &mut |_| {}).unwrap();
+        let profile = model.gpu_profile();
+        let layers: Vec<_> = profile.by_layer().into_iter().map(|(l, _)| l).collect();
+        assert_eq!(layers, [Some(0), Some(1), None]);
+        assert!(profile.by_op().iter().all(|(op, t)| !op.ends_with("_matvec") && !op.starts_with("vec_") && t.dispatches > 0));
+        assert!(profile.total().time > std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn golden_model_moves_blocks_past_the_gpu_budget_to_the_cpu() {
+        let Some((mut model, vocab, _)) = golden_gpu_model("offload") else { return };
+        // Room for block 0's weights and nothing more.
+        let l = &model.weights.layers[0];
+        let block0: usize = [&l.attn_q, &l.attn_k, &l.attn_v, &l.attn_output, &l.ffn_gate, &l.ffn_up, &l.ffn_down]
+            .iter()
+            .map(|w| w.rows * w.cols / 32 * 34)
+            .sum();
+        model.limit_gpu_memory(block0 as u64);
+        let load = model.load_all_tensors(1).unwrap();
+        assert_eq!((model.gpu_layers(), load.tensors), (1, 7));
+
+        let mut ids = Vec::new();
+        model
+            .generate(&golden_prompt(), &golden_greedy_opts(GOLDEN_MAX_NEW), &vocab, &mut |e| {
+                if let crate::events::GenerationEvent::Token { id, .. } = e {
+                    ids.push(id);
+                }
+            })
+            .unwrap();
+        assert_eq!(ids, GOLDEN_TOKENS);
+
+        // Nothing on the GPU but the elementwise kernels.
+        model.set_gpu_layers(0);
+        let mut ids = Vec::new();
+        model
+            .generate(&golden_prompt(), &golden_greedy_opts(GOLDEN_MAX_NEW), &vocab, &mut |e| {
+                if let crate::events::GenerationEvent::Token { id, .. } = e {
+                    ids.push(id);
+                }
+            })
+            .unwrap();
+        assert_eq!(ids, GOLDEN_TOKENS);
+    }
+
+    #[test]
+    fn golden_model_split_across_two_queues_matches_reference() {
+        let Some((mut model, vocab, _)) = golden_gpu_model("shard") else { return };
+        // The same device twice: two queues, each with half of every weight.
+        let mut names: Vec<_> = model.weights.matmul_weights().iter().map(|w| w.name.clone()).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(model.use_devices(&[0, 0]).unwrap(), names.len());
+        assert!(model.device_weight_bytes().iter().all(|&b| b > 0));
+        assert_eq!(model.load_all_tensors(1).unwrap().tensors, 0);
+
+        let mut ids = Vec::new();
+        model
+            .generate(&golden_prompt(), &golden_greedy_opts(GOLDEN_MAX_NEW), &vocab, &mut |e| {
+                if let crate::events::GenerationEvent::Token { id, .. } = e {
+                    ids.push(id);
+                }
+            })
+            .unwrap();
+        assert_eq!(ids, GOLDEN_TOKENS);
+        assert!(model.use_devices(&[99]).unwrap_err().to_string().contains("no Metal device 99"));
+    }
+
+    #[test]
+    fn golden_model_pipelined_over_two_stages_matches_reference() {
+        let Some((mut model, vocab, _)) = golden_gpu_model("pipeline") else { return };
+        // One block per stage; micro-batches of two rows overlap on prefill.
+        model.use_pipeline(&[0, 0], &[], 2).unwrap();
+        let layers: Vec<_> = model.pipeline_stages().iter().map(|s| s.layers.clone()).collect();
+        assert_eq!(layers, [0..1, 1..2]);
+        assert_eq!(model.load_all_tensors(1).unwrap().tensors, 1);
+
+        let mut ids = Vec::new();
+        model
+            .generate(&golden_prompt(), &golden_greedy_opts(GOLDEN_MAX_NEW), &vocab, &mut |e| {
+                if let crate::events::GenerationEvent::Token { id, .. } = e {
+                    ids.push(id);
+                }
+            })
+            .unwrap();
+        assert_eq!(ids, GOLDEN_TOKENS);
+        assert!(model.use_devices(&[0]).is_err());
+    }
+
+    #[test]
+    fn golden_model_chooses_the_candidate_greedy_decoding_starts() {
+        let Some((mut model, _, _)) = golden_gpu_model("choice") else { return };
+        let mut logits = model.evaluate(&golden_prompt()).unwrap().pop().unwrap();
+        let candidates = vec![vec![GOLDEN_TOKENS[0] + 1], GOLDEN_TOKENS[..3].to_vec(), vec![GOLDEN_TOKENS[0], 0]];
+        let choices = crate::choice::Choices::new(candidates).unwrap();
+        let picked = model.choose(&golden_prompt(), &choices, &[]).unwrap();
+        assert_eq!(picked.index, 1);
+        let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let lse = logits.iter_mut().map(|v| (*v - max).exp()).sum::<f32>().ln() + max;
+        assert!(picked.logprob <= logits[GOLDEN_TOKENS[0] as usize] - lse + 1e-4, "{picked:?}");
+    }
+
+    #[test]
+    fn golden_model_with_its_last_block_on_an_rpc_worker_matches_reference() {
+        let Some((mut model, vocab, _)) = golden_gpu_model("rpc") else { return };
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || crate::rpc::serve(listener));
+        model.use_pipeline(&[0], std::slice::from_ref(&addr), 2).unwrap();
+        let places: Vec<_> = model.pipeline_stages().iter().map(|s| (s.place(), s.layers.clone())).collect();
+        assert_eq!(places, [("device 0".to_string(), 0..1), (format!("worker {addr}"), 1..2)]);
+
+        // Twice: the second prompt starts over at position 0 on the worker too.
+        for _ in 0..2 {
+            let mut ids = Vec::new();
+            model
+                .generate(&golden_prompt(), &golden_greedy_opts(GOLDEN_MAX_NEW), &vocab, &mut |e| {
+                    if let crate::events::GenerationEvent::Token { id, .. } = e {
+                        ids.push(id);
+                    }
+                })
+                .unwrap();
+            assert_eq!(ids, GOLDEN_TOKENS);
+        }
+    }
+
+    #[test]
+    fn golden_model_stops_when_cancelled() {
+        let Some((mut model, vocab, _)) = golden_gpu_model("cancel") else { return };
+        let cancel = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let opts = crate::model::GenerateOptions { cancel: Some(cancel), ..golden_greedy_opts(GOLDEN_MAX_NEW) };
+        let (mut tokens, mut reason) = (0, None);
+        model
+            .generate(&golden_prompt(), &opts, &vocab, &mut |e| match e {
+                crate::events::GenerationEvent::Token { .. } => tokens += 1,
+                crate::events::GenerationEvent::Done { reason: r, .. } => reason = Some(r),
+                _ => {}
+            })
+            .unwrap();
+        assert_eq!((tokens, reason), (0, Some(crate::events::FinishReason::Cancelled)));
+    }
+
+    #[test]
+    fn golden_model_reports_a_timing_breakdown() {
+        let Some((mut model, vocab, _)) = golden_gpu_model("timings") else { return };
+        let prompt = golden_prompt();
+        let mut timings = None;
+        model
+            .generate(&prompt, &golden_greedy_opts(GOLDEN_MAX_NEW), &vocab, &mut |e| {
+                if let crate::events::GenerationEvent::Done { timings: t, .. } = e {
+                    timings = Some(t);
+                }
+            })
+            .unwrap();
+        let t = timings.unwrap();
+        assert_eq!(t.prefill_tokens, prompt.len());
+        assert!(t.sample_ms + t.detokenize_ms <= t.decode_ms, "{t:?}");
+        let report = t.report();
+        assert!(report.starts_with("load:") && report.contains("ms/token"), "{report}");
+    }
+
+    #[test]
+    fn golden_model_kv_session_reuses_the_common_prefix() {
+        let Some((mut model, vocab, _)) = golden_gpu_model("kv") else { return };
+        let prompt = golden_prompt();
+        let mut session = crate::model::KvSession::new(&model);
+        let run = |model: &mut crate::model::LlamaModel, session: &mut _, tokens: &[u32], max_new| {
+            let mut out = Vec::new();
+            let reused = model
+                .generate_cached(session, tokens, &golden_greedy_opts(max_new), &vocab, &mut |e| {
+                    if let crate::events::GenerationEvent::Token { id, .. } = e {
+                        out.push(id);
+                    }
+                })
+                .unwrap();
+            (reused, out)
+        };
+        assert_eq!(run(&mut model, &mut session, &prompt, GOLDEN_MAX_NEW), (0, GOLDEN_TOKENS.to_vec()));
+        // Every token but the last sampled one has K/V rows.
+        assert_eq!(session.tokens().len(), prompt.len() + GOLDEN_TOKENS.len() - 1);
+
+        // Continue from three generated tokens: all but the last are reused,
+        // and the continuation matches the uncached one.
+        let follow_up: Vec<u32> = prompt.iter().chain(&GOLDEN_TOKENS[..3]).copied().collect();
+        let (reused, out) = run(&mut model, &mut session, &follow_up, 3);
+        assert_eq!(reused, follow_up.len() - 1);
+        assert_eq!(out, GOLDEN_TOKENS[3..]);
+        let a = &model.arch;
+        let row_bytes = 2 * a.n_layers * a.n_kv_heads * a.head_dim * 4;
+        let stats = session.stats();
+        assert_eq!((stats.positions, stats.bytes), (session.tokens().len(), session.tokens().len() * row_bytes));
+
+        // A short unrelated prompt truncates the cache; compaction hands the
+        // row tables' leftover capacity back and keeps the live rows.
+        run(&mut model, &mut session, &prompt[..1], 0);
+        let stats = session.stats();
+        assert_eq!((stats.positions, stats.bytes), (1, row_bytes));
+        assert!(stats.slack_bytes > 0);
+        assert!(session.shrink_to_fit() >= stats.slack_bytes);
+        assert_eq!(session.stats(), crate::model::KvStats { slack_bytes: 0, ..stats });
+    }
+
+    /// A companion GGUF of two Medusa heads with zero residual blocks: head 0
+    /// shares the model's output head, head 1 has an F16 copy of it.
+    fn golden_medusa_gguf(w: &GoldenWeights) -> Vec<u8> {
+        let h = tiny_arch().hidden;
+        let (vocab, _, embd) = &w["token_embd.weight"];
+        let (h64, zeros) = (h as u64, vec![0.0; h * h]);
+        GgufBuilder::new()
+            .tensor_f16("medusa.0.fc.weight", &[h64, h64], &zeros)
+            .tensor_f32("medusa.0.fc.bias", &[h64], &vec![0.0; h])
+            .tensor_f16("medusa.1.fc.weight", &[h64, h64], &zeros)
+            .tensor_f32("medusa.1.fc.bias", &[h64], &vec![0.0; h])
+            .tensor_f16("medusa.1.output.weight", &[h64, *vocab as u64], embd)
+            .build()
+    }
+
+    #[test]
+    fn golden_model_medusa_draft_keeps_the_greedy_output() {
+        let Some((mut model, vocab, w)) = golden_gpu_model("medusa") else { return };
+        let path = std::env::temp_dir().join(format!("llmetal-golden-medusa-{}.gguf", std::process::id()));
+        std::fs::write(&path, golden_medusa_gguf(&w)).unwrap();
+        assert_eq!(model.load_medusa(path.to_str().unwrap()).unwrap(), 2);
+        std::fs::remove_file(&path).unwrap();
+
+        for n_draft in [1, 2] {
+            let opts = crate::model::GenerateOptions {
+                draft: Some(crate::speculative::DraftSource::Medusa(crate::speculative::MedusaConfig { n_draft })),
+                ..golden_greedy_opts(GOLDEN_MAX_NEW)
+            };
+            let mut tokens = Vec::new();
+            model
+                .generate(&golden_prompt(), &opts, &vocab, &mut |e| {
+                    if let crate::events::GenerationEvent::Token { id, .. } = e {
+                        tokens.push(id);
+                    }
+                })
+                .unwrap();
+            assert_eq!(tokens, GOLDEN_TOKENS, "n_draft {n_draft}");
         }
     }
 
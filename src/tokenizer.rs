@@ -1,3 +1,10 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+use crate::gguf_loader::GgufModelInfo;
+use crate::unicode::{PreTokenizer, nfc};
+
 /// Greedy longest-match tokenizer over a GGUF vocab.
 ///
 /// GGUF uses SentencePiece-style tokens where spaces are represented as the
@@ -6,30 +13,252 @@
 pub struct PromptTokenizer {
     /// Sorted vocab slice, indexed by token id.
     vocab: Vec<String>,
+    /// Set for BERT-family vocabs (`tokenizer.ggml.model = bert`).
+    wordpiece: Option<WordPiece>,
+    /// Set for byte-level BPE vocabs whose `tokenizer.ggml.pre` we can split.
+    pre: Option<PreTokenizer>,
+    /// Control and user-defined tokens (`<|im_start|>`, FIM markers), longest
+    /// first, matched as whole strings before any other tokenization.
+    special: Vec<(String, u32)>,
+}
+
+/// One token of a tokenized or decoded text and its byte range in that text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenSpan {
+    pub id: u32,
+    pub start: usize,
+    pub end: usize,
+    /// A control or user-defined token (BOS, `<|im_end|>`).
+    pub special: bool,
+}
+
+/// Whether special-token text in the input encodes to the special token or
+/// as ordinary characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpecialTokens {
+    /// Trusted text (templates, the CLI prompt): `<|im_start|>` becomes its id.
+    Parse,
+    /// Untrusted text: `<|im_start|>` is tokenized like any other characters,
+    /// so it cannot close a turn or open a forged one.
+    Literal,
+}
+
+enum Fragment<'a> {
+    Text(&'a str),
+    Special(u32),
+}
+
+/// BERT WordPiece: lowercase, split on whitespace and punctuation, then match
+/// each word longest-prefix-first with `##` marking word-internal pieces.
+struct WordPiece {
+    ids: HashMap<String, u32>,
+    cls: u32,
+    sep: u32,
+    unk: u32,
 }
 
 impl PromptTokenizer {
     pub fn new(vocab: Vec<String>) -> Self {
-        Self { vocab }
+        Self { vocab, wordpiece: None, pre: None, special: Vec::new() }
+    }
+
+    /// Byte-level BPE (GPT-2, Llama 3, Qwen2): NFC-normalise, split with
+    /// `pre`, map each piece's UTF-8 bytes to GPT-2's printable alphabet, then
+    /// match within the piece. No space is prepended.
+    pub fn byte_level(vocab: Vec<String>, pre: PreTokenizer) -> Self {
+        Self { vocab, wordpiece: None, pre: Some(pre), special: Vec::new() }
+    }
+
+    /// Split input on these ids' strings and encode them directly. Ids past
+    /// the vocab and empty strings are ignored.
+    pub fn with_special_tokens(mut self, ids: impl IntoIterator<Item = u32>) -> Self {
+        self.special = ids
+            .into_iter()
+            .filter_map(|id| self.vocab.get(id as usize).filter(|t| !t.is_empty()).map(|t| (t.clone(), id)))
+            .collect();
+        self.special.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.1.cmp(&b.1)));
+        self
+    }
+
+    /// The tokenizer a GGUF's `tokenizer.ggml.model` and `tokenizer.ggml.pre`
+    /// call for, splitting on the control and user-defined tokens named by
+    /// `tokenizer.ggml.token_type`. Unknown pretokenizers fall back to the
+    /// unsplit greedy scan.
+    pub fn for_model(info: &GgufModelInfo) -> Result<Self> {
+        let vocab = info.vocab.clone();
+        let tokenizer = match info.tokenizer_model.as_deref() {
+            Some("bert") => Self::wordpiece(vocab)?,
+            Some("gpt2") => match PreTokenizer::from_name(info.tokenizer_pre.as_deref().unwrap_or("default")) {
+                Some(pre) => Self::byte_level(vocab, pre),
+                None => Self::new(vocab),
+            },
+            _ => Self::new(vocab),
+        };
+        let special = info.token_types.iter().enumerate().filter(|&(_, &t)| t == TOKEN_CONTROL || t == TOKEN_USER_DEFINED);
+        Ok(tokenizer.with_special_tokens(special.map(|(id, _)| id as u32)))
+    }
+
+    pub fn vocab(&self) -> &[String] {
+        &self.vocab
+    }
+
+    /// A control or user-defined token (see `with_special_tokens`).
+    pub fn is_special(&self, id: u32) -> bool {
+        self.special.iter().any(|&(_, s)| s == id)
+    }
+
+    /// Text for `ids`. With `skip_special_tokens` special tokens are left out,
+    /// for showing a reply to a user; without, they render as their text
+    /// (`<|im_end|>`), for debugging. Byte-level vocabs are mapped back
+    /// through GPT-2's byte alphabet; a character split across the end of
+    /// `ids` decodes to U+FFFD.
+    pub fn decode(&self, ids: &[u32], skip_special_tokens: bool) -> String {
+        let kept = ids.iter().copied().filter(|&id| !(skip_special_tokens && self.is_special(id)));
+        let bytes: Vec<u8> = kept.flat_map(|id| self.token_bytes(id)).collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// The bytes `decode` produces for one token: a byte-level token mapped
+    /// back through GPT-2's alphabet, so possibly part of a UTF-8 character.
+    pub fn token_bytes(&self, id: u32) -> Vec<u8> {
+        if self.pre.is_none() {
+            return detokenize(id, &self.vocab).into_bytes();
+        }
+        let Some(tok) = self.vocab.get(id as usize) else { return Vec::new() };
+        if self.is_special(id) {
+            return tok.as_bytes().to_vec();
+        }
+        let mut bytes = Vec::new();
+        for c in tok.chars() {
+            match char_byte(c) {
+                Some(b) => bytes.push(b),
+                None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        bytes
+    }
+
+    /// Where each of `ids` lands in `decode(ids, skip_special_tokens)`, for
+    /// clients budgeting context. For text from `tokenize` that is the input
+    /// itself, NFC-normalised for byte-level vocabs and with the legacy
+    /// scan's leading space. Skipped special tokens get an empty span.
+    pub fn spans(&self, ids: &[u32], skip_special_tokens: bool) -> Vec<TokenSpan> {
+        let mut start = 0;
+        ids.iter()
+            .map(|&id| {
+                let special = self.is_special(id);
+                let len = if skip_special_tokens && special { 0 } else { self.token_bytes(id).len() };
+                start += len;
+                TokenSpan { id, start: start - len, end: start, special }
+            })
+            .collect()
+    }
+
+    /// WordPiece tokenizer over a BERT vocab with `[CLS]`, `[SEP]` and `[UNK]`.
+    pub fn wordpiece(vocab: Vec<String>) -> Result<Self> {
+        let ids: HashMap<String, u32> = vocab.iter().enumerate().map(|(i, t)| (t.clone(), i as u32)).collect();
+        let id = |t: &str| ids.get(t).copied().with_context(|| format!("WordPiece vocab has no {t}"));
+        let wordpiece = WordPiece { cls: id("[CLS]")?, sep: id("[SEP]")?, unk: id("[UNK]")?, ids };
+        Ok(Self { vocab, wordpiece: Some(wordpiece), pre: None, special: Vec::new() })
+    }
+
+    /// `[SEP]` for WordPiece vocabs, the segment boundary in sentence pairs.
+    pub fn sep(&self) -> Option<u32> {
+        self.wordpiece.as_ref().map(|w| w.sep)
     }
 
     /// Tokenize with BOS token (id=1) prepended, matching llama.cpp conventions.
+    /// WordPiece sequences are wrapped as `[CLS] ... [SEP]` instead.
     pub fn tokenize_bos(&self, prompt: &str) -> Vec<u32> {
+        self.encode_bos(&[(prompt, SpecialTokens::Parse)])
+    }
+
+    /// `encode` with BOS (or `[CLS] ... [SEP]`) around it.
+    pub fn encode_bos(&self, parts: &[(&str, SpecialTokens)]) -> Vec<u32> {
+        if let Some(w) = &self.wordpiece {
+            let mut ids = vec![w.cls];
+            ids.extend(self.encode(parts));
+            ids.push(w.sep);
+            return ids;
+        }
         let mut ids = vec![1u32]; // BOS
-        ids.extend(self.tokenize(prompt));
+        ids.extend(self.encode(parts));
         ids
     }
 
+    /// Tokenize trusted text: special-token strings become their ids.
     pub fn tokenize(&self, prompt: &str) -> Vec<u32> {
+        self.encode(&[(prompt, SpecialTokens::Parse)])
+    }
+
+    /// Tokenize consecutive parts as one sequence, each under its own
+    /// special-token policy, e.g. a chat template's markup parsed and a user's
+    /// message literal.
+    pub fn encode(&self, parts: &[(&str, SpecialTokens)]) -> Vec<u32> {
+        let mut ids = Vec::new();
         if self.vocab.is_empty() {
-            return Vec::new();
+            return ids;
+        }
+        let mut first = true;
+        for &(text, special) in parts {
+            for fragment in self.fragments(text, special) {
+                match fragment {
+                    Fragment::Special(id) => ids.push(id),
+                    Fragment::Text(text) => self.encode_text(text, first, &mut ids),
+                }
+                first = false;
+            }
+        }
+        ids
+    }
+
+    /// `text` cut at every special-token string, longest match first.
+    fn fragments<'a>(&self, text: &'a str, special: SpecialTokens) -> Vec<Fragment<'a>> {
+        let mut out = Vec::new();
+        if special == SpecialTokens::Literal || self.special.is_empty() {
+            out.extend((!text.is_empty()).then_some(Fragment::Text(text)));
+            return out;
+        }
+        let mut start = 0;
+        let mut i = 0;
+        while i < text.len() {
+            if let Some((tok, id)) = self.special.iter().find(|(tok, _)| text[i..].starts_with(tok.as_str())) {
+                out.extend((start < i).then(|| Fragment::Text(&text[start..i])));
+                out.push(Fragment::Special(*id));
+                i += tok.len();
+                start = i;
+            } else {
+                i += text[i..].chars().next().map_or(1, char::len_utf8);
+            }
+        }
+        out.extend((start < text.len()).then(|| Fragment::Text(&text[start..])));
+        out
+    }
+
+    /// One run of text with no special tokens in it. The legacy scan's
+    /// leading space goes only on the first fragment of a sequence.
+    fn encode_text(&self, text: &str, first: bool, ids: &mut Vec<u32>) {
+        if let Some(w) = &self.wordpiece {
+            ids.extend(w.tokenize(text));
+            return;
+        }
+        if let Some(pre) = self.pre {
+            for piece in pre.split(&nfc(text)) {
+                let mapped: String = piece.bytes().map(byte_char).collect();
+                self.greedy(&mapped, ids);
+            }
+            return;
         }
 
         // GPT-2 / Tekken: prepend a space then replace all spaces with Ġ (U+0120)
         // so "hello world" → " hello world" → "Ġhello Ġworld" → "ĠhelloĠworld"
-        let normalised = format!(" {prompt}").replace(' ', "\u{0120}");
+        let normalised = if first { format!(" {text}") } else { text.to_string() }.replace(' ', "\u{0120}");
+        self.greedy(&normalised, ids);
+    }
+
+    /// Greedy longest match over `normalised`, appending to `ids`.
+    fn greedy(&self, normalised: &str, ids: &mut Vec<u32>) {
         let chars: Vec<char> = normalised.chars().collect();
-        let mut ids = Vec::new();
         let mut pos = 0;
 
         while pos < chars.len() {
@@ -57,8 +286,6 @@ impl PromptTokenizer {
                 pos += best_len;
             }
         }
-
-        ids
     }
 
     pub fn explain(&self, prompt: &str) -> String {
@@ -96,3 +323,83 @@ impl PromptTokenizer {
         }
     }
 }
+
+impl WordPiece {
+    fn tokenize(&self, text: &str) -> Vec<u32> {
+        let lower = text.to_lowercase();
+        let mut words = Vec::new();
+        for chunk in lower.split_whitespace() {
+            let mut start = 0;
+            for (i, c) in chunk.char_indices() {
+                if c.is_ascii_punctuation() || (!c.is_alphanumeric() && !c.is_ascii()) {
+                    words.extend((start < i).then(|| &chunk[start..i]));
+                    words.push(&chunk[i..i + c.len_utf8()]);
+                    start = i + c.len_utf8();
+                }
+            }
+            words.extend((start < chunk.len()).then(|| &chunk[start..]));
+        }
+        words.into_iter().flat_map(|w| self.word(w)).collect()
+    }
+
+    /// Longest-match-first pieces of one word, or `[UNK]` if any part fails.
+    fn word(&self, word: &str) -> Vec<u32> {
+        let mut pieces = Vec::new();
+        let mut start = 0;
+        while start < word.len() {
+            let piece = word[start..]
+                .char_indices()
+                .map(|(i, c)| start + i + c.len_utf8())
+                .rev()
+                .find_map(|end| {
+                    let sub = &word[start..end];
+                    let key = if start == 0 { sub.to_string() } else { format!("##{sub}") };
+                    self.ids.get(&key).map(|&id| (id, end))
+                });
+            let Some((id, end)) = piece else {
+                return vec![self.unk];
+            };
+            pieces.push(id);
+            start = end;
+        }
+        pieces
+    }
+}
+
+/// `tokenizer.ggml.token_type` values that mark special tokens.
+const TOKEN_CONTROL: u32 = 3;
+const TOKEN_USER_DEFINED: u32 = 4;
+
+/// GPT-2's byte-to-unicode table: printable Latin-1 bytes stand for
+/// themselves, the rest are shifted to U+0100 and up in byte order, so a space
+/// is `Ġ` and a newline `Ċ`.
+fn byte_char(b: u8) -> char {
+    if printable_byte(b) {
+        return b as char;
+    }
+    let shifted = (0..b).filter(|&x| !printable_byte(x)).count() as u32;
+    char::from_u32(0x100 + shifted).unwrap_or('\u{FFFD}')
+}
+
+/// Inverse of `byte_char`; `None` for characters outside the alphabet.
+fn char_byte(c: char) -> Option<u8> {
+    let c = c as u32;
+    if let Ok(b) = u8::try_from(c) {
+        return printable_byte(b).then_some(b);
+    }
+    let shifted = usize::try_from(c.checked_sub(0x100)?).ok()?;
+    (0..=255u8).filter(|&x| !printable_byte(x)).nth(shifted)
+}
+
+fn printable_byte(b: u8) -> bool {
+    matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF)
+}
+
+/// Display text for one token id: GPT-2 `Ġ` becomes a space, `<0x0A>` a newline.
+/// Unknown ids decode to an empty string.
+pub fn detokenize(id: u32, vocab: &[String]) -> String {
+    vocab
+        .get(id as usize)
+        .map(|tok| tok.replace('\u{0120}', " ").replace("<0x0A>", "\n"))
+        .unwrap_or_default()
+}
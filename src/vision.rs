@@ -0,0 +1,260 @@
+//! Qwen2-VL image input: dynamic-resolution patching and M-RoPE positions.
+//!
+//! Qwen2-VL does not squash every image to one square like LLaVA's CLIP
+//! tower. `smart_resize` keeps the aspect ratio and picks the nearest size
+//! whose sides are multiples of `patch_size * merge_size` within a pixel
+//! budget, so the number of image tokens follows the image. `patchify`
+//! cuts the normalized pixels into `patch_size` squares (the single frame
+//! repeated `temporal_patch_size` times), ordered so that every
+//! `merge_size x merge_size` group the merger folds into one token is
+//! contiguous.
+//!
+//! The language model then places tokens in three dimensions (M-RoPE):
+//! text tokens at `(p, p, p)`, the image's tokens at the start position
+//! plus their (frame, row, column) in the merged grid. `mrope` rotates a
+//! head with each rotary pair driven by one of the three, as
+//! `{arch}.rope.dimension_sections` assigns them. For text alone all three
+//! are equal and it is NeoX RoPE.
+//!
+//! This is the input side only: running the vision tower (`mmproj`) and
+//! splicing its output in place of the `<|image_pad|>` embeddings is not
+//! done here.
+
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result, bail, ensure};
+
+use crate::gguf::MetaValue;
+
+/// CLIP's per-channel normalization, which Qwen2-VL keeps.
+pub const IMAGE_MEAN: [f32; 3] = [0.481_454_66, 0.457_827_5, 0.408_210_73];
+pub const IMAGE_STD: [f32; 3] = [0.268_629_54, 0.261_302_6, 0.275_777_1];
+
+/// The Qwen2-VL image processor's settings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PatchConfig {
+    pub patch_size: usize,
+    pub merge_size: usize,
+    pub temporal_patch_size: usize,
+    pub min_pixels: usize,
+    pub max_pixels: usize,
+}
+
+impl Default for PatchConfig {
+    fn default() -> Self {
+        Self { patch_size: 14, merge_size: 2, temporal_patch_size: 2, min_pixels: 56 * 56, max_pixels: 28 * 28 * 1280 }
+    }
+}
+
+impl PatchConfig {
+    /// Side lengths are multiples of this.
+    pub fn factor(&self) -> usize {
+        self.patch_size * self.merge_size
+    }
+}
+
+/// An image's patch grid: frames, rows and columns of patches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GridThw {
+    pub t: usize,
+    pub h: usize,
+    pub w: usize,
+}
+
+impl GridThw {
+    pub fn n_patches(&self) -> usize {
+        self.t * self.h * self.w
+    }
+
+    /// Tokens the language model sees once the merger has folded each
+    /// `merge x merge` group of patches into one.
+    pub fn n_tokens(&self, merge: usize) -> usize {
+        self.n_patches() / (merge * merge)
+    }
+}
+
+/// An 8-bit RGB image, row-major.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    /// A binary PPM (`P6`, maxval 255).
+    pub fn parse_ppm(bytes: &[u8]) -> Result<Self> {
+        let mut fields = Vec::new();
+        let mut i = 0;
+        while fields.len() < 4 {
+            while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            if bytes.get(i) == Some(&b'#') {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                continue;
+            }
+            let start = i;
+            while i < bytes.len() && !bytes[i].is_ascii_whitespace() {
+                i += 1;
+            }
+            ensure!(i > start, "truncated PPM header");
+            fields.push(std::str::from_utf8(&bytes[start..i])?);
+        }
+        ensure!(fields[0] == "P6", "not a binary PPM (P6) image");
+        let num = |s: &str| s.parse::<usize>().with_context(|| format!("bad PPM header field '{s}'"));
+        let (width, height, maxval) = (num(fields[1])?, num(fields[2])?, num(fields[3])?);
+        ensure!(maxval == 255, "only 8-bit PPM images are supported (maxval {maxval})");
+        let data = bytes.get(i + 1..).unwrap_or_default();
+        let len = width * height * 3;
+        ensure!(data.len() >= len, "PPM pixel data is {} bytes, expected {len}", data.len());
+        Ok(Self { width, height, rgb: data[..len].to_vec() })
+    }
+
+    pub fn read(path: &std::path::Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        Self::parse_ppm(&bytes).with_context(|| format!("decoding {}", path.display()))
+    }
+
+    /// Bilinear resize to `width x height`, scaled to 0..1 and normalized
+    /// per channel, planar `[3, height, width]`.
+    pub fn normalized(&self, width: usize, height: usize) -> Vec<f32> {
+        let mut out = vec![0.0; 3 * width * height];
+        let sx = self.width as f32 / width as f32;
+        let sy = self.height as f32 / height as f32;
+        let px = |x: usize, y: usize, c: usize| self.rgb[(y * self.width + x) * 3 + c] as f32;
+        for y in 0..height {
+            let fy = ((y as f32 + 0.5) * sy - 0.5).clamp(0.0, (self.height - 1) as f32);
+            let (y0, dy) = (fy as usize, fy.fract());
+            let y1 = (y0 + 1).min(self.height - 1);
+            for x in 0..width {
+                let fx = ((x as f32 + 0.5) * sx - 0.5).clamp(0.0, (self.width - 1) as f32);
+                let (x0, dx) = (fx as usize, fx.fract());
+                let x1 = (x0 + 1).min(self.width - 1);
+                for c in 0..3 {
+                    let top = px(x0, y0, c) * (1.0 - dx) + px(x1, y0, c) * dx;
+                    let bottom = px(x0, y1, c) * (1.0 - dx) + px(x1, y1, c) * dx;
+                    let v = (top * (1.0 - dy) + bottom * dy) / 255.0;
+                    out[(c * height + y) * width + x] = (v - IMAGE_MEAN[c]) / IMAGE_STD[c];
+                }
+            }
+        }
+        out
+    }
+}
+
+/// `(height, width)` for an image of that size: both multiples of
+/// `factor`, the aspect ratio kept, and the area within the pixel budget.
+/// Rounds half to even, as the reference processor does.
+pub fn smart_resize(height: usize, width: usize, factor: usize, min_pixels: usize, max_pixels: usize) -> Result<(usize, usize)> {
+    ensure!(height > 0 && width > 0, "empty image");
+    let ratio = height.max(width) as f64 / height.min(width) as f64;
+    if ratio > 200.0 {
+        bail!("image aspect ratio {ratio:.0}:1 is over 200:1");
+    }
+    let (h, w, f) = (height as f64, width as f64, factor as f64);
+    let round = |x: f64| ((x / f).round_ties_even() as usize).max(1) * factor;
+    let (mut h_bar, mut w_bar) = (round(h), round(w));
+    if h_bar * w_bar > max_pixels {
+        let beta = (h * w / max_pixels as f64).sqrt();
+        h_bar = ((h / beta / f).floor() as usize).max(1) * factor;
+        w_bar = ((w / beta / f).floor() as usize).max(1) * factor;
+    } else if h_bar * w_bar < min_pixels {
+        let beta = (min_pixels as f64 / (h * w)).sqrt();
+        h_bar = (h * beta / f).ceil() as usize * factor;
+        w_bar = (w * beta / f).ceil() as usize * factor;
+    }
+    Ok((h_bar, w_bar))
+}
+
+/// Resize and normalize `image` and cut it into patches, each flattened as
+/// `[channel, frame, row, column]`: `[n_patches, 3 * T * P * P]`.
+pub fn patchify(image: &Image, cfg: &PatchConfig) -> Result<(Vec<f32>, GridThw)> {
+    let (height, width) = smart_resize(image.height, image.width, cfg.factor(), cfg.min_pixels, cfg.max_pixels)?;
+    let pixels = image.normalized(width, height);
+    let (p, m, t) = (cfg.patch_size, cfg.merge_size, cfg.temporal_patch_size);
+    let grid = GridThw { t: 1, h: height / p, w: width / p };
+    let mut out = Vec::with_capacity(grid.n_patches() * 3 * t * p * p);
+    for bh in 0..grid.h / m {
+        for bw in 0..grid.w / m {
+            for mh in 0..m {
+                for mw in 0..m {
+                    let (py, px) = ((bh * m + mh) * p, (bw * m + mw) * p);
+                    for c in 0..3 {
+                        // A still image is the same frame `t` times.
+                        for _ in 0..t {
+                            for y in 0..p {
+                                let row = (c * height + py + y) * width + px;
+                                out.extend_from_slice(&pixels[row..row + p]);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok((out, grid))
+}
+
+/// `{arch}.rope.dimension_sections`: how many rotary pairs follow the
+/// frame, row and column positions (a fourth section, zero in Qwen2-VL,
+/// is ignored).
+pub fn rope_sections(meta: &BTreeMap<String, MetaValue>, prefix: &str) -> Option<[usize; 3]> {
+    let values = meta.get(&format!("{prefix}.rope.dimension_sections"))?.as_array()?;
+    let s: Vec<usize> = values.iter().map(|v| v.as_u64().map(|v| v as usize)).collect::<Option<_>>()?;
+    (s.len() >= 3).then(|| [s[0], s[1], s[2]])
+}
+
+/// M-RoPE positions of `tokens` with one image per run of `image_pad`,
+/// `grids` in order: text at `(p, p, p)`, an image's tokens at its start
+/// position plus their place in the merged grid, and the text after it
+/// from one past the image's largest coordinate.
+pub fn mrope_positions(tokens: &[u32], image_pad: u32, grids: &[GridThw], merge: usize) -> Result<Vec<[usize; 3]>> {
+    let mut positions = Vec::with_capacity(tokens.len());
+    let (mut next, mut i, mut images) = (0, 0, grids.iter());
+    while i < tokens.len() {
+        if tokens[i] != image_pad {
+            positions.push([next; 3]);
+            next += 1;
+            i += 1;
+            continue;
+        }
+        let grid = images.next().context("more image placeholders than images")?;
+        let (gt, gh, gw) = (grid.t, grid.h / merge, grid.w / merge);
+        let run = tokens[i..].iter().take_while(|&&t| t == image_pad).count();
+        ensure!(run == gt * gh * gw, "an image of {} tokens has {run} placeholders", gt * gh * gw);
+        for t in 0..gt {
+            for h in 0..gh {
+                for w in 0..gw {
+                    positions.push([next + t, next + h, next + w]);
+                }
+            }
+        }
+        next += gt.max(gh).max(gw);
+        i += run;
+    }
+    ensure!(images.next().is_none(), "more images than image placeholders");
+    Ok(positions)
+}
+
+/// NeoX-style RoPE of every head of `x` at the three-part position `pos`:
+/// pair `i` turns by `pos[s] / base^(2i / head_dim)`, `s` being the
+/// section pair `i` falls in (cycling when the sections cover fewer pairs).
+pub fn mrope(x: &mut [f32], n_heads: usize, head_dim: usize, pos: [usize; 3], sections: [usize; 3], base: f32) {
+    let half = head_dim / 2;
+    let total = sections.iter().sum::<usize>().max(1);
+    for h in 0..n_heads {
+        let off = h * head_dim;
+        for i in 0..half {
+            let sector = i % total;
+            let axis = if sector < sections[0] { 0 } else if sector < sections[0] + sections[1] { 1 } else { 2 };
+            let theta = pos[axis] as f32 / base.powf(2.0 * i as f32 / head_dim as f32);
+            let (s, c) = theta.sin_cos();
+            let (x0, x1) = (x[off + i], x[off + i + half]);
+            x[off + i] = x0 * c - x1 * s;
+            x[off + i + half] = x0 * s + x1 * c;
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Statistical output watermark (`run --watermark KEY`) and its detector
+//! (`llmetal detect-watermark`), for provenance experiments.
+//!
+//! The scheme is the green-list watermark of Kirchenbauer et al. (2023):
+//! before each token, the previous token and a secret key pick a `gamma`
+//! share of the vocabulary as "green", and every green logit gains `delta`.
+//! Text generated that way holds far more green tokens than the `gamma`
+//! expected by chance, which a z-test over the token sequence shows without
+//! the model; only the tokenizer and the key are needed.
+//!
+//! Green membership is a keyed hash of `(previous, token)` compared to
+//! `gamma`, so no per-step permutation of the vocabulary is built. The
+//! detector counts each `(previous, token)` pair once, so a phrase repeated
+//! many times does not pass for a watermark.
+
+use std::collections::HashSet;
+
+use anyhow::{Context, Result, ensure};
+
+use crate::sampler::LogitsProcessor;
+
+/// z-score above which `detect` calls text watermarked; about one false
+/// positive in 30 000 unwatermarked texts.
+pub const DEFAULT_THRESHOLD: f32 = 4.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Watermark {
+    pub key: u64,
+    /// Share of the vocabulary that is green at each step.
+    pub gamma: f32,
+    /// Added to every green logit.
+    pub delta: f32,
+}
+
+impl Watermark {
+    pub fn new(key: u64) -> Self {
+        Self { key, gamma: 0.25, delta: 2.0 }
+    }
+
+    /// A `gamma` flag: strictly between 0 and 1, since `detect` divides by
+    /// `gamma * (1 - gamma)`.
+    pub fn parse_gamma(s: &str) -> Result<f32> {
+        let gamma: f32 = s.parse().with_context(|| format!("gamma {s:?} is not a number"))?;
+        ensure!(gamma > 0.0 && gamma < 1.0, "gamma must be between 0 and 1 (exclusive), not {gamma}");
+        Ok(gamma)
+    }
+
+    /// Whether `token` is green after `prev`.
+    pub fn is_green(&self, prev: u32, token: u32) -> bool {
+        let h = mix(self.key ^ mix(u64::from(prev) << 32 | u64::from(token)));
+        let unit = (h >> 11) as f64 / (1u64 << 53) as f64;
+        unit < f64::from(self.gamma)
+    }
+
+    /// Score `tokens`: every token after the first, against the one before.
+    pub fn detect(&self, tokens: &[u32]) -> Detection {
+        let mut seen = HashSet::new();
+        let (mut scored, mut green) = (0, 0);
+        for pair in tokens.windows(2) {
+            if seen.insert((pair[0], pair[1])) {
+                scored += 1;
+                green += usize::from(self.is_green(pair[0], pair[1]));
+            }
+        }
+        let (n, g) = (scored as f64, f64::from(self.gamma));
+        let z = if scored == 0 { 0.0 } else { (green as f64 - g * n) / (n * g * (1.0 - g)).sqrt() };
+        Detection { scored, green, z: z as f32 }
+    }
+}
+
+impl LogitsProcessor for Watermark {
+    fn process(&self, context: &[u32], _n_generated: usize, logits: &mut [f32]) {
+        let Some(&prev) = context.last() else { return };
+        for (id, l) in logits.iter_mut().enumerate() {
+            if self.is_green(prev, id as u32) {
+                *l += self.delta;
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "watermark"
+    }
+}
+
+/// What `Watermark::detect` found.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Detection {
+    /// Distinct `(previous, token)` pairs counted.
+    pub scored: usize,
+    pub green: usize,
+    /// Standard deviations above the green share chance would give.
+    pub z: f32,
+}
+
+/// splitmix64's finalizer.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
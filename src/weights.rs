@@ -0,0 +1,669 @@
+//! Typed view of the GGUF tensor table.
+//!
+//! The forward pass used to build names like `blk.{layer}.attn_q.weight` on
+//! every call and find out about a missing or mis-shaped tensor halfway
+//! through a token. This module resolves every name once at load time and
+//! checks shapes against the architecture, so a bad file fails before the
+//! first matvec with one error listing everything that is wrong.
+
+use std::collections::HashMap;
+
+use anyhow::{Result, bail};
+
+use crate::bert::EncoderArch;
+use crate::model::Arch;
+use crate::rwkv::RwkvArch;
+use crate::ssm::SsmArch;
+use crate::t5::T5Arch;
+use crate::whisper::WhisperArch;
+use crate::tensor::TensorMeta;
+
+/// One weight tensor: its GGUF name plus the matrix view the kernels use.
+/// GGUF stores shapes innermost-first, so `cols = shape[0]`, `rows = shape[1]`.
+#[derive(Clone, Debug)]
+pub struct WeightRef {
+    pub name: String,
+    pub kind: u32,
+    pub rows: usize,
+    pub cols: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct LayerWeights {
+    pub attn_norm: WeightRef,
+    pub attn_q: WeightRef,
+    pub attn_k: WeightRef,
+    pub attn_v: WeightRef,
+    pub attn_output: WeightRef,
+    pub ffn_norm: WeightRef,
+    pub ffn_gate: WeightRef,
+    pub ffn_up: WeightRef,
+    pub ffn_down: WeightRef,
+}
+
+#[derive(Clone, Debug)]
+pub struct ModelWeights {
+    pub token_embd: WeightRef,
+    pub output_norm: WeightRef,
+    /// `output.weight`, or `token_embd.weight` when the head is tied.
+    pub output: WeightRef,
+    pub layers: Vec<LayerWeights>,
+}
+
+impl ModelWeights {
+    pub fn from_index(index: &HashMap<String, TensorMeta>, arch: &Arch) -> Result<Self> {
+        let mut r = Resolver { index, errors: Vec::new() };
+        let q_dim = arch.n_heads * arch.head_dim;
+        let kv_dim = arch.n_kv_heads * arch.head_dim;
+
+        let token_embd = r.get("token_embd.weight", arch.vocab_size, arch.hidden);
+        let output_norm = r.get("output_norm.weight", 1, arch.hidden);
+        let output = r.opt("output.weight", arch.vocab_size, arch.hidden).unwrap_or_else(|| token_embd.clone());
+
+        let layers = (0..arch.n_layers)
+            .map(|l| LayerWeights {
+                attn_norm: r.get(&format!("blk.{l}.attn_norm.weight"), 1, arch.hidden),
+                attn_q: r.get(&format!("blk.{l}.attn_q.weight"), q_dim, arch.hidden),
+                attn_k: r.get(&format!("blk.{l}.attn_k.weight"), kv_dim, arch.hidden),
+                attn_v: r.get(&format!("blk.{l}.attn_v.weight"), kv_dim, arch.hidden),
+                attn_output: r.get(&format!("blk.{l}.attn_output.weight"), arch.hidden, q_dim),
+                ffn_norm: r.get(&format!("blk.{l}.ffn_norm.weight"), 1, arch.hidden),
+                ffn_gate: r.get(&format!("blk.{l}.ffn_gate.weight"), arch.ffn_hidden, arch.hidden),
+                ffn_up: r.get(&format!("blk.{l}.ffn_up.weight"), arch.ffn_hidden, arch.hidden),
+                ffn_down: r.get(&format!("blk.{l}.ffn_down.weight"), arch.hidden, arch.ffn_hidden),
+            })
+            .collect();
+
+        r.finish()?;
+        Ok(Self { token_embd, output_norm, output, layers })
+    }
+
+    /// Whether the output head is the token embedding matrix.
+    pub fn tied_head(&self) -> bool {
+        self.output.name == self.token_embd.name
+    }
+
+    /// Every tensor that goes through a matmul kernel (and so gets a Metal
+    /// buffer), output head first. Norms and the embedding are read on the CPU.
+    pub fn matmul_weights(&self) -> Vec<&WeightRef> {
+        let mut out = vec![&self.output];
+        for l in &self.layers {
+            out.extend([
+                &l.attn_q, &l.attn_k, &l.attn_v, &l.attn_output,
+                &l.ffn_gate, &l.ffn_up, &l.ffn_down,
+            ]);
+        }
+        out
+    }
+}
+
+/// A projection plus its bias, when the model has one.
+#[derive(Clone, Debug)]
+pub struct Linear {
+    pub weight: WeightRef,
+    pub bias: Option<WeightRef>,
+}
+
+/// LayerNorm: scale and shift.
+#[derive(Clone, Debug)]
+pub struct LayerNorm {
+    pub weight: WeightRef,
+    pub bias: WeightRef,
+}
+
+#[derive(Clone, Debug)]
+pub enum Qkv {
+    /// BERT: separate `attn_q`/`attn_k`/`attn_v`.
+    Split { q: Linear, k: Linear, v: Linear },
+    /// nomic-bert: one `attn_qkv` producing `[q | k | v]`.
+    Fused(Linear),
+}
+
+#[derive(Clone, Debug)]
+pub struct EncoderLayerWeights {
+    pub qkv: Qkv,
+    pub attn_output: Linear,
+    pub attn_output_norm: LayerNorm,
+    /// Present for gated (SwiGLU) FFNs; BERT's FFN is a plain GELU.
+    pub ffn_gate: Option<Linear>,
+    pub ffn_up: Linear,
+    pub ffn_down: Linear,
+    pub layer_output_norm: LayerNorm,
+}
+
+#[derive(Clone, Debug)]
+pub struct EncoderWeights {
+    pub token_embd: WeightRef,
+    pub token_types: Option<WeightRef>,
+    /// Learned absolute positions; absent when the model uses RoPE.
+    pub position_embd: Option<WeightRef>,
+    pub token_embd_norm: LayerNorm,
+    pub layers: Vec<EncoderLayerWeights>,
+    /// Sequence-classification pooler (`cls`) and head (`cls.output`), for rerankers.
+    pub cls: Option<Linear>,
+    pub cls_output: Option<Linear>,
+}
+
+impl EncoderWeights {
+    pub fn from_index(index: &HashMap<String, TensorMeta>, arch: &EncoderArch) -> Result<Self> {
+        let mut r = Resolver { index, errors: Vec::new() };
+        let (h, f) = (arch.hidden, arch.ffn_hidden);
+
+        let token_embd = r.get("token_embd.weight", arch.vocab_size, h);
+        let token_types = index.get("token_types.weight").map(|m| r.get("token_types.weight", m.rows(), h));
+        let position_embd = index.get("position_embd.weight").map(|m| r.get("position_embd.weight", m.rows(), h));
+        let token_embd_norm = r.norm("token_embd_norm", h);
+
+        let layers = (0..arch.n_layers)
+            .map(|l| {
+                let p = format!("blk.{l}");
+                let qkv = match index.contains_key(&format!("{p}.attn_qkv.weight")) {
+                    true => Qkv::Fused(r.linear(&format!("{p}.attn_qkv"), 3 * h, h)),
+                    false => Qkv::Split {
+                        q: r.linear(&format!("{p}.attn_q"), h, h),
+                        k: r.linear(&format!("{p}.attn_k"), h, h),
+                        v: r.linear(&format!("{p}.attn_v"), h, h),
+                    },
+                };
+                let ffn_gate = index
+                    .contains_key(&format!("{p}.ffn_gate.weight"))
+                    .then(|| r.linear(&format!("{p}.ffn_gate"), f, h));
+                EncoderLayerWeights {
+                    qkv,
+                    attn_output: r.linear(&format!("{p}.attn_output"), h, h),
+                    attn_output_norm: r.norm(&format!("{p}.attn_output_norm"), h),
+                    ffn_gate,
+                    ffn_up: r.linear(&format!("{p}.ffn_up"), f, h),
+                    ffn_down: r.linear(&format!("{p}.ffn_down"), h, f),
+                    layer_output_norm: r.norm(&format!("{p}.layer_output_norm"), h),
+                }
+            })
+            .collect();
+
+        let cls = index.contains_key("cls.weight").then(|| r.linear("cls", h, h));
+        let cls_output = index
+            .get("cls.output.weight")
+            .map(|m| m.rows())
+            .map(|n_cls| r.linear("cls.output", n_cls, h));
+
+        r.finish()?;
+        Ok(Self { token_embd, token_types, position_embd, token_embd_norm, layers, cls, cls_output })
+    }
+}
+
+/// Mamba's selective-scan mixer (`ssm_*`), in place of attention.
+#[derive(Clone, Debug)]
+pub struct SsmMixer {
+    /// `[x | z]`: the branch that is scanned and the gate applied after.
+    pub in_proj: WeightRef,
+    /// One `d_conv`-tap causal filter per inner channel, oldest tap first.
+    pub conv1d: Linear,
+    /// `[dt | B | C]` from the convolved branch.
+    pub x_proj: WeightRef,
+    pub dt_proj: Linear,
+    /// `[d_inner, d_state]`, already `-exp(A_log)` as converters store it.
+    pub a: WeightRef,
+    pub d: WeightRef,
+    /// Jamba's RMS norms on dt, B and C; Mamba has none.
+    pub dt_norm: Option<WeightRef>,
+    pub b_norm: Option<WeightRef>,
+    pub c_norm: Option<WeightRef>,
+    pub out_proj: WeightRef,
+}
+
+/// A hybrid model's attention layer: Llama projections, no RoPE.
+#[derive(Clone, Debug)]
+pub struct HybridAttention {
+    pub q: WeightRef,
+    pub k: WeightRef,
+    pub v: WeightRef,
+    pub output: WeightRef,
+}
+
+#[derive(Clone, Debug)]
+pub enum Mixer {
+    Ssm(Box<SsmMixer>),
+    Attention(HybridAttention),
+}
+
+/// A Llama-style gated FFN with its pre-norm.
+#[derive(Clone, Debug)]
+pub struct SwiGlu {
+    pub norm: WeightRef,
+    pub gate: WeightRef,
+    pub up: WeightRef,
+    pub down: WeightRef,
+}
+
+#[derive(Clone, Debug)]
+pub struct SsmLayerWeights {
+    /// RMS norm before the mixer (`attn_norm`, whichever the mixer is).
+    pub norm: WeightRef,
+    pub mixer: Mixer,
+    /// Jamba's FFN; a Mamba block is the mixer alone.
+    pub ffn: Option<SwiGlu>,
+}
+
+#[derive(Clone, Debug)]
+pub struct SsmWeights {
+    pub token_embd: WeightRef,
+    pub output_norm: WeightRef,
+    /// `output.weight`, or `token_embd.weight` when the head is tied.
+    pub output: WeightRef,
+    pub layers: Vec<SsmLayerWeights>,
+}
+
+impl SsmWeights {
+    /// A layer with `ssm_in` is a Mamba mixer, any other an attention layer.
+    pub fn from_index(index: &HashMap<String, TensorMeta>, arch: &SsmArch) -> Result<Self> {
+        let mut r = Resolver { index, errors: Vec::new() };
+        let (h, inner, state) = (arch.hidden, arch.d_inner, arch.d_state);
+        let q_dim = arch.n_heads * arch.head_dim();
+        let kv_dim = arch.n_kv_heads * arch.head_dim();
+
+        let token_embd = r.get("token_embd.weight", arch.vocab_size, h);
+        let output_norm = r.get("output_norm.weight", 1, h);
+        let output = r.opt("output.weight", arch.vocab_size, h).unwrap_or_else(|| token_embd.clone());
+
+        let layers = (0..arch.n_layers)
+            .map(|l| {
+                let p = format!("blk.{l}");
+                let mixer = match index.contains_key(&format!("{p}.ssm_in.weight")) {
+                    true => Mixer::Ssm(Box::new(SsmMixer {
+                        in_proj: r.get(&format!("{p}.ssm_in.weight"), 2 * inner, h),
+                        conv1d: r.linear(&format!("{p}.ssm_conv1d"), inner, arch.d_conv),
+                        x_proj: r.get(&format!("{p}.ssm_x.weight"), arch.dt_rank + 2 * state, inner),
+                        dt_proj: r.linear(&format!("{p}.ssm_dt"), inner, arch.dt_rank),
+                        a: r.get(&format!("{p}.ssm_a"), inner, state),
+                        d: r.get(&format!("{p}.ssm_d"), 1, inner),
+                        dt_norm: r.opt(&format!("{p}.ssm_dt_norm.weight"), 1, arch.dt_rank),
+                        b_norm: r.opt(&format!("{p}.ssm_b_norm.weight"), 1, state),
+                        c_norm: r.opt(&format!("{p}.ssm_c_norm.weight"), 1, state),
+                        out_proj: r.get(&format!("{p}.ssm_out.weight"), h, inner),
+                    })),
+                    false => Mixer::Attention(HybridAttention {
+                        q: r.get(&format!("{p}.attn_q.weight"), q_dim, h),
+                        k: r.get(&format!("{p}.attn_k.weight"), kv_dim, h),
+                        v: r.get(&format!("{p}.attn_v.weight"), kv_dim, h),
+                        output: r.get(&format!("{p}.attn_output.weight"), h, q_dim),
+                    }),
+                };
+                if index.contains_key(&format!("{p}.ffn_gate_inp.weight")) {
+                    r.errors.push(format!("{p} has a mixture-of-experts FFN, which is not supported"));
+                }
+                let ffn = index.contains_key(&format!("{p}.ffn_up.weight")).then(|| SwiGlu {
+                    norm: r.get(&format!("{p}.ffn_norm.weight"), 1, h),
+                    gate: r.get(&format!("{p}.ffn_gate.weight"), arch.ffn_hidden, h),
+                    up: r.get(&format!("{p}.ffn_up.weight"), arch.ffn_hidden, h),
+                    down: r.get(&format!("{p}.ffn_down.weight"), h, arch.ffn_hidden),
+                });
+                SsmLayerWeights { norm: r.get(&format!("{p}.attn_norm.weight"), 1, h), mixer, ffn }
+            })
+            .collect();
+
+        r.finish()?;
+        Ok(Self { token_embd, output_norm, output, layers })
+    }
+}
+
+/// RWKV-6 time mixing: token shift through a data-dependent lerp, the WKV
+/// recurrence, and a per-head group norm.
+#[derive(Clone, Debug)]
+pub struct TimeMix {
+    pub lerp_x: WeightRef,
+    /// `lerp_w, lerp_k, lerp_v, lerp_r, lerp_g` as one `[5, hidden]`
+    /// tensor, or as five.
+    pub lerp: Vec<WeightRef>,
+    /// The shared LoRA down (`[5 * lora, hidden]`) and its five ups
+    /// (`[5][hidden, lora]`) that make the lerps data-dependent.
+    pub w1: WeightRef,
+    pub w2: WeightRef,
+    /// `u`, the bonus of the current token, `[n_heads, head_size]`.
+    pub first: WeightRef,
+    pub decay: WeightRef,
+    pub decay_w1: WeightRef,
+    pub decay_w2: WeightRef,
+    pub key: WeightRef,
+    pub value: WeightRef,
+    pub receptance: WeightRef,
+    pub gate: WeightRef,
+    pub ln: LayerNorm,
+    pub output: WeightRef,
+}
+
+/// RWKV channel mixing: a token-shifted, squared-ReLU FFN gated by a sigmoid.
+#[derive(Clone, Debug)]
+pub struct ChannelMix {
+    pub lerp_k: WeightRef,
+    pub lerp_r: WeightRef,
+    pub key: WeightRef,
+    pub value: WeightRef,
+    pub receptance: WeightRef,
+}
+
+#[derive(Clone, Debug)]
+pub struct RwkvLayerWeights {
+    pub attn_norm: LayerNorm,
+    pub time_mix: TimeMix,
+    pub attn_norm_2: LayerNorm,
+    pub channel_mix: ChannelMix,
+}
+
+#[derive(Clone, Debug)]
+pub struct RwkvWeights {
+    pub token_embd: WeightRef,
+    /// LayerNorm straight after the embedding lookup.
+    pub token_embd_norm: LayerNorm,
+    pub output_norm: LayerNorm,
+    pub output: WeightRef,
+    pub layers: Vec<RwkvLayerWeights>,
+}
+
+impl RwkvWeights {
+    pub fn from_index(index: &HashMap<String, TensorMeta>, arch: &RwkvArch) -> Result<Self> {
+        let mut r = Resolver { index, errors: Vec::new() };
+        let (h, f, lora) = (arch.hidden, arch.ffn_hidden, arch.time_mix_extra_dim);
+
+        let token_embd = r.get("token_embd.weight", arch.vocab_size, h);
+        let token_embd_norm = r.norm("token_embd_norm", h);
+        let output_norm = r.norm("output_norm", h);
+        let output = r.opt("output.weight", arch.vocab_size, h).unwrap_or_else(|| token_embd.clone());
+
+        let layers = (0..arch.n_layers)
+            .map(|l| {
+                let p = format!("blk.{l}");
+                let t = |name: &str| format!("{p}.time_mix_{name}.weight");
+                let lerp = match index.contains_key(&t("lerp_fused")) {
+                    true => vec![r.get(&t("lerp_fused"), 1, h)],
+                    false => ["w", "k", "v", "r", "g"].iter().map(|x| r.get(&t(&format!("lerp_{x}")), 1, h)).collect(),
+                };
+                let time_mix = TimeMix {
+                    lerp_x: r.get(&t("lerp_x"), 1, h),
+                    lerp,
+                    w1: r.get(&t("w1"), 5 * lora, h),
+                    w2: r.get(&t("w2"), h, lora),
+                    first: r.get(&t("first"), arch.n_heads(), arch.head_size),
+                    decay: r.get(&t("decay"), 1, h),
+                    decay_w1: r.get(&t("decay_w1"), arch.time_decay_extra_dim, h),
+                    decay_w2: r.get(&t("decay_w2"), h, arch.time_decay_extra_dim),
+                    key: r.get(&t("key"), h, h),
+                    value: r.get(&t("value"), h, h),
+                    receptance: r.get(&t("receptance"), h, h),
+                    gate: r.get(&t("gate"), h, h),
+                    ln: r.norm(&format!("{p}.time_mix_ln"), h),
+                    output: r.get(&t("output"), h, h),
+                };
+                let c = |name: &str| format!("{p}.channel_mix_{name}.weight");
+                let channel_mix = ChannelMix {
+                    lerp_k: r.get(&c("lerp_k"), 1, h),
+                    lerp_r: r.get(&c("lerp_r"), 1, h),
+                    key: r.get(&c("key"), f, h),
+                    value: r.get(&c("value"), h, f),
+                    receptance: r.get(&c("receptance"), h, h),
+                };
+                RwkvLayerWeights {
+                    attn_norm: r.norm(&format!("{p}.attn_norm"), h),
+                    time_mix,
+                    attn_norm_2: r.norm(&format!("{p}.attn_norm_2"), h),
+                    channel_mix,
+                }
+            })
+            .collect();
+
+        r.finish()?;
+        Ok(Self { token_embd, token_embd_norm, output_norm, output, layers })
+    }
+}
+
+/// One T5 attention sublayer: RMSNorm, projections without biases, and the
+/// relative position bias table `[buckets, n_heads]` where the layer has one.
+#[derive(Clone, Debug)]
+pub struct T5Attention {
+    pub norm: WeightRef,
+    pub q: WeightRef,
+    pub k: WeightRef,
+    pub v: WeightRef,
+    pub output: WeightRef,
+    pub rel_bias: Option<WeightRef>,
+}
+
+/// ReLU FFN (T5), or gated GELU when there is a gate (T5 v1.1, Flan-T5).
+#[derive(Clone, Debug)]
+pub struct T5Ffn {
+    pub norm: WeightRef,
+    pub gate: Option<WeightRef>,
+    pub up: WeightRef,
+    pub down: WeightRef,
+}
+
+#[derive(Clone, Debug)]
+pub struct T5LayerWeights {
+    pub attn: T5Attention,
+    /// Attention over the encoder output: decoder layers only.
+    pub cross: Option<T5Attention>,
+    pub ffn: T5Ffn,
+}
+
+#[derive(Clone, Debug)]
+pub struct T5Weights {
+    pub token_embd: WeightRef,
+    pub encoder: Vec<T5LayerWeights>,
+    pub encoder_norm: WeightRef,
+    pub decoder: Vec<T5LayerWeights>,
+    pub decoder_norm: WeightRef,
+    /// `output.weight`, or `token_embd.weight` when the head is tied.
+    pub output: WeightRef,
+}
+
+impl T5Weights {
+    /// Layer 0 of each stack must carry `attn_rel_b`; the layers after it
+    /// share that table unless they have their own.
+    pub fn from_index(index: &HashMap<String, TensorMeta>, arch: &T5Arch) -> Result<Self> {
+        let mut r = Resolver { index, errors: Vec::new() };
+        let (h, f) = (arch.hidden, arch.ffn_hidden);
+        let inner = arch.n_heads * arch.head_dim;
+
+        let token_embd = r.get("token_embd.weight", arch.vocab_size, h);
+        let output = r.opt("output.weight", arch.vocab_size, h).unwrap_or_else(|| token_embd.clone());
+        let attention = |r: &mut Resolver, p: &str, layer: usize| T5Attention {
+            norm: r.get(&format!("{p}_norm.weight"), 1, h),
+            q: r.get(&format!("{p}_q.weight"), inner, h),
+            k: r.get(&format!("{p}_k.weight"), inner, h),
+            v: r.get(&format!("{p}_v.weight"), inner, h),
+            output: r.get(&format!("{p}_o.weight"), h, inner),
+            rel_bias: match layer {
+                0 => Some(r.get(&format!("{p}_rel_b.weight"), arch.n_buckets, arch.n_heads)),
+                _ => r.opt(&format!("{p}_rel_b.weight"), arch.n_buckets, arch.n_heads),
+            },
+        };
+        let stack = |r: &mut Resolver, stack: &str, n_layers: usize| -> Vec<T5LayerWeights> {
+            (0..n_layers)
+                .map(|l| {
+                    let p = format!("{stack}.blk.{l}");
+                    let attn = attention(r, &format!("{p}.attn"), l);
+                    // Cross attention has no position bias, whatever the file holds.
+                    let cross = (stack == "dec").then(|| {
+                        let mut cross = attention(r, &format!("{p}.cross_attn"), usize::MAX);
+                        cross.rel_bias = None;
+                        cross
+                    });
+                    let ffn = T5Ffn {
+                        norm: r.get(&format!("{p}.ffn_norm.weight"), 1, h),
+                        gate: r.opt(&format!("{p}.ffn_gate.weight"), f, h),
+                        up: r.get(&format!("{p}.ffn_up.weight"), f, h),
+                        down: r.get(&format!("{p}.ffn_down.weight"), h, f),
+                    };
+                    T5LayerWeights { attn, cross, ffn }
+                })
+                .collect()
+        };
+        let encoder = stack(&mut r, "enc", arch.n_enc_layers);
+        let decoder = stack(&mut r, "dec", arch.n_dec_layers);
+        let encoder_norm = r.get("enc.output_norm.weight", 1, h);
+        let decoder_norm = r.get("dec.output_norm.weight", 1, h);
+
+        r.finish()?;
+        Ok(Self { token_embd, encoder, encoder_norm, decoder, decoder_norm, output })
+    }
+
+    pub fn tied_head(&self) -> bool {
+        self.output.name == self.token_embd.name
+    }
+}
+
+/// A `[out, in, kernel]` convolution over mel frames or its own output.
+/// Its rows are contiguous `in x kernel` filters, so `weight` is the
+/// `[out, in * kernel]` matrix that multiplies unfolded windows.
+#[derive(Clone, Debug)]
+pub struct Conv1d {
+    pub weight: WeightRef,
+    pub bias: WeightRef,
+    pub kernel: usize,
+}
+
+/// Pre-LayerNorm attention of a Whisper block; `attn_k` has no bias.
+#[derive(Clone, Debug)]
+pub struct WhisperAttention {
+    pub norm: LayerNorm,
+    pub q: Linear,
+    pub k: Linear,
+    pub v: Linear,
+    pub output: Linear,
+}
+
+#[derive(Clone, Debug)]
+pub struct WhisperLayerWeights {
+    pub attn: WhisperAttention,
+    /// Attention over the encoded audio: decoder layers only.
+    pub cross: Option<WhisperAttention>,
+    pub ffn_norm: LayerNorm,
+    pub ffn_up: Linear,
+    pub ffn_down: Linear,
+}
+
+/// Whisper under the `enc.` / `dec.` names T5 GGUFs use. The text head is
+/// always `token_embd`.
+#[derive(Clone, Debug)]
+pub struct WhisperWeights {
+    pub conv1: Conv1d,
+    pub conv2: Conv1d,
+    pub encoder_position: WeightRef,
+    pub encoder: Vec<WhisperLayerWeights>,
+    pub encoder_norm: LayerNorm,
+    pub token_embd: WeightRef,
+    pub decoder_position: WeightRef,
+    pub decoder: Vec<WhisperLayerWeights>,
+    pub decoder_norm: LayerNorm,
+}
+
+impl WhisperWeights {
+    pub fn from_index(index: &HashMap<String, TensorMeta>, arch: &WhisperArch) -> Result<Self> {
+        let mut r = Resolver { index, errors: Vec::new() };
+        let h = arch.hidden;
+        let attention = |r: &mut Resolver, p: &str| WhisperAttention {
+            norm: r.norm(&format!("{p}_norm"), h),
+            q: r.linear(&format!("{p}_q"), h, h),
+            k: r.linear(&format!("{p}_k"), h, h),
+            v: r.linear(&format!("{p}_v"), h, h),
+            output: r.linear(&format!("{p}_o"), h, h),
+        };
+        let stack = |r: &mut Resolver, stack: &str, n_layers: usize| -> Vec<WhisperLayerWeights> {
+            (0..n_layers)
+                .map(|l| {
+                    let p = format!("{stack}.blk.{l}");
+                    WhisperLayerWeights {
+                        attn: attention(r, &format!("{p}.attn")),
+                        cross: (stack == "dec").then(|| attention(r, &format!("{p}.cross_attn"))),
+                        ffn_norm: r.norm(&format!("{p}.ffn_norm"), h),
+                        ffn_up: r.linear(&format!("{p}.ffn_up"), 4 * h, h),
+                        ffn_down: r.linear(&format!("{p}.ffn_down"), h, 4 * h),
+                    }
+                })
+                .collect()
+        };
+
+        let conv1 = r.conv1d("enc.conv1", h, arch.n_mels, 3);
+        let conv2 = r.conv1d("enc.conv2", h, h, 3);
+        let encoder_position = r.get("enc.position_embd.weight", arch.n_audio_ctx, h);
+        let encoder = stack(&mut r, "enc", arch.n_enc_layers);
+        let encoder_norm = r.norm("enc.output_norm", h);
+        let token_embd = r.get("token_embd.weight", arch.vocab_size, h);
+        let decoder_position = r.get("dec.position_embd.weight", arch.n_text_ctx, h);
+        let decoder = stack(&mut r, "dec", arch.n_dec_layers);
+        let decoder_norm = r.norm("dec.output_norm", h);
+
+        r.finish()?;
+        Ok(Self { conv1, conv2, encoder_position, encoder, encoder_norm, token_embd, decoder_position, decoder, decoder_norm })
+    }
+}
+
+/// Looks names up in the tensor table and collects every problem instead of
+/// stopping at the first.
+struct Resolver<'a> {
+    index: &'a HashMap<String, TensorMeta>,
+    errors: Vec<String>,
+}
+
+impl Resolver<'_> {
+    fn get(&mut self, name: &str, rows: usize, cols: usize) -> WeightRef {
+        match self.opt(name, rows, cols) {
+            Some(w) => w,
+            None => {
+                self.errors.push(format!("missing {name}"));
+                WeightRef { name: name.to_string(), kind: 0, rows, cols }
+            }
+        }
+    }
+
+    /// Like `get`, but a missing tensor is fine; a mis-shaped one is not.
+    fn opt(&mut self, name: &str, rows: usize, cols: usize) -> Option<WeightRef> {
+        let m = self.index.get(name)?;
+        let w = WeightRef {
+            name: name.to_string(),
+            kind: m.kind,
+            rows: m.shape.get(1).copied().unwrap_or(1) as usize,
+            cols: m.shape.first().copied().unwrap_or(0) as usize,
+        };
+        if m.shape.is_empty() {
+            self.errors.push(format!("tensor '{name}' has no dimensions"));
+        } else if (w.rows, w.cols) != (rows, cols) {
+            self.errors.push(format!("{name} is {}x{}, expected {rows}x{cols}", w.rows, w.cols));
+        }
+        Some(w)
+    }
+
+    fn linear(&mut self, prefix: &str, rows: usize, cols: usize) -> Linear {
+        Linear {
+            weight: self.get(&format!("{prefix}.weight"), rows, cols),
+            bias: self.opt(&format!("{prefix}.bias"), 1, rows),
+        }
+    }
+
+    /// GGUF keeps a conv weight as `[kernel, in, out]`; the matrix view
+    /// sees `in` rows of `kernel`, so `out` is checked on its own.
+    fn conv1d(&mut self, prefix: &str, out: usize, inp: usize, kernel: usize) -> Conv1d {
+        let name = format!("{prefix}.weight");
+        let mut weight = self.get(&name, inp, kernel);
+        if let Some(m) = self.index.get(&name)
+            && m.shape.get(2).copied().unwrap_or(1) as usize != out
+        {
+            self.errors.push(format!("{name} has {} output channels, expected {out}", m.shape.get(2).copied().unwrap_or(1)));
+        }
+        (weight.rows, weight.cols) = (out, inp * kernel);
+        Conv1d { weight, bias: self.get(&format!("{prefix}.bias"), 1, out), kernel }
+    }
+
+    fn norm(&mut self, prefix: &str, dim: usize) -> LayerNorm {
+        LayerNorm {
+            weight: self.get(&format!("{prefix}.weight"), 1, dim),
+            bias: self.get(&format!("{prefix}.bias"), 1, dim),
+        }
+    }
+
+    fn finish(self) -> Result<()> {
+        if !self.errors.is_empty() {
+            bail!("GGUF tensor table does not match the architecture:\n  {}", self.errors.join("\n  "));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,466 @@
+//! Speech input: a Whisper encoder-decoder GGUF (`general.architecture`
+//! `whisper`) transcribes `run --audio FILE.wav`, and the transcript joins
+//! the prompt.
+//!
+//! Audio is read as 16-bit PCM or float WAV, mixed down to mono and
+//! resampled to 16 kHz, then cut into 30 s windows (the last one padded
+//! with silence, as Whisper was trained). Each window becomes an 80- (or
+//! 128-) bin log-mel spectrogram of 10 ms frames, which two GELU
+//! convolutions (the second with stride 2) turn into 1500 positions for a
+//! pre-LayerNorm encoder with learned positions. The decoder starts from
+//! `<|startoftranscript|>`, the language, `<|transcribe|>` and
+//! `<|notimestamps|>`, and decodes greedily with timestamps and other
+//! special tokens suppressed. Without a language the first window picks
+//! the likeliest language token.
+//!
+//! Tensor names follow the `enc.` / `dec.` layout of `t5`, with
+//! `enc.conv1`, `enc.conv2` and `{enc,dec}.position_embd` besides.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail, ensure};
+
+use crate::bert::{gelu, layer_norm};
+use crate::model::attention;
+use crate::recurrent::Matmuls;
+use crate::weights::{Conv1d, LayerNorm, Linear, WhisperAttention, WhisperLayerWeights, WhisperWeights};
+
+/// `general.architecture` values this module runs.
+pub const WHISPER_ARCHS: [&str; 1] = ["whisper"];
+
+pub const SAMPLE_RATE: usize = 16_000;
+pub const N_FFT: usize = 400;
+/// Samples per mel frame: 10 ms.
+pub const HOP: usize = 160;
+/// One encoder window: 30 s.
+pub const WINDOW_SAMPLES: usize = 30 * SAMPLE_RATE;
+
+pub fn is_whisper(architecture: &str) -> bool {
+    WHISPER_ARCHS.contains(&architecture)
+}
+
+#[derive(Clone, Debug)]
+pub struct WhisperArch {
+    pub hidden: usize,
+    pub n_heads: usize,
+    pub n_enc_layers: usize,
+    pub n_dec_layers: usize,
+    pub n_mels: usize,
+    /// Encoder positions per window (1500).
+    pub n_audio_ctx: usize,
+    /// Decoder positions (448).
+    pub n_text_ctx: usize,
+    pub vocab_size: usize,
+    pub layer_norm_eps: f32,
+}
+
+/// The special tokens decoding needs, found by name in the vocabulary.
+#[derive(Clone, Debug)]
+struct Specials {
+    sot: u32,
+    eot: u32,
+    transcribe: u32,
+    no_timestamps: u32,
+    /// `(code, id)` of every language token, for models that have them.
+    languages: Vec<(String, u32)>,
+}
+
+impl Specials {
+    fn find(vocab: &[String], multilingual: bool) -> Result<Self> {
+        let id = |tag: &str| {
+            vocab.iter().position(|t| t == tag).map(|i| i as u32).with_context(|| format!("the vocabulary has no {tag}"))
+        };
+        let (sot, translate) = (id("<|startoftranscript|>")?, id("<|translate|>")?);
+        let languages = match multilingual {
+            true => (sot + 1..translate)
+                .map(|i| (vocab[i as usize].trim_start_matches("<|").trim_end_matches("|>").to_string(), i))
+                .collect(),
+            false => Vec::new(),
+        };
+        Ok(Self { sot, eot: id("<|endoftext|>")?, transcribe: id("<|transcribe|>")?, no_timestamps: id("<|notimestamps|>")?, languages })
+    }
+}
+
+/// What `WhisperModel::transcribe` heard.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transcript {
+    /// The language code it transcribed in; `None` for English-only models.
+    pub language: Option<String>,
+    /// Text tokens of every window, special tokens left out.
+    pub tokens: Vec<u32>,
+}
+
+/// The decoder's K/V rows and each layer's cross K/V of one window.
+#[derive(Default)]
+struct Decoding {
+    self_k: Vec<Vec<Arc<[f32]>>>,
+    self_v: Vec<Vec<Arc<[f32]>>>,
+    cross_k: Vec<Vec<Arc<[f32]>>>,
+    cross_v: Vec<Vec<Arc<[f32]>>>,
+}
+
+pub struct WhisperModel {
+    pub arch: WhisperArch,
+    pub weights: Arc<WhisperWeights>,
+    mm: Matmuls,
+    /// `[n_mels, N_FFT / 2 + 1]`.
+    filters: Vec<f32>,
+}
+
+impl WhisperModel {
+    pub fn load(path: &str) -> Result<Self> {
+        let mm = Matmuls::open(path)?;
+        let (meta, index) = (&mm.store.metadata, &mm.store.index);
+        let prefix = meta.get("general.architecture").and_then(|v| v.as_str()).unwrap_or("whisper").to_string();
+        ensure!(is_whisper(&prefix), "'{prefix}' is not a Whisper GGUF");
+        let get_u = |key: &str| meta.get(&format!("{prefix}.{key}")).and_then(|v| v.as_u64()).map(|v| v as usize);
+        let rows = |name: &str| index.get(name).map(|m| m.rows()).with_context(|| format!("missing {name}"));
+
+        let n_enc_layers = get_u("block_count").context("missing block_count")?;
+        let arch = WhisperArch {
+            hidden: index.get("token_embd.weight").map(|m| m.cols()).context("missing token_embd.weight")?,
+            n_heads: get_u("attention.head_count").context("missing attention.head_count")?,
+            n_enc_layers,
+            n_dec_layers: get_u("decoder_block_count").unwrap_or(n_enc_layers),
+            n_mels: rows("enc.conv1.weight")?,
+            n_audio_ctx: rows("enc.position_embd.weight")?,
+            n_text_ctx: rows("dec.position_embd.weight")?,
+            vocab_size: rows("token_embd.weight")?,
+            layer_norm_eps: meta
+                .get(&format!("{prefix}.attention.layer_norm_epsilon"))
+                .and_then(|v| v.as_f64())
+                .map_or(1e-5, |v| v as f32),
+        };
+        let weights = Arc::new(WhisperWeights::from_index(index, &arch)?);
+        let filters = mel_filters(arch.n_mels, N_FFT, SAMPLE_RATE);
+        Ok(Self { arch, weights, mm, filters })
+    }
+
+    /// Transcribe 16 kHz mono `samples` window by window. `language` is a
+    /// code such as `en` or `de`; `None` detects it from the first window.
+    pub fn transcribe(&mut self, samples: &[f32], language: Option<&str>, vocab: &[String]) -> Result<Transcript> {
+        // Multilingual vocabularies have one more token (51865 vs 51864).
+        let multilingual = vocab.len() >= 51865;
+        let sp = Specials::find(vocab, multilingual)?;
+        let mut chosen = match (language, multilingual) {
+            (Some(code), true) => {
+                Some(sp.languages.iter().find(|(c, _)| c == code).cloned().with_context(|| format!("unknown language '{code}'"))?)
+            }
+            (Some(code), false) if code != "en" => bail!("this Whisper model only transcribes English"),
+            _ => None,
+        };
+
+        let mut tokens = Vec::new();
+        for window in samples.chunks(WINDOW_SAMPLES) {
+            let mut padded = window.to_vec();
+            padded.resize(WINDOW_SAMPLES, 0.0);
+            let mel = log_mel(&padded, &self.filters, self.arch.n_mels);
+            let encoded = self.encode(&mel, WINDOW_SAMPLES / HOP)?;
+            let mut dec = self.cross_rows(&encoded)?;
+
+            let mut prompt = vec![sp.sot];
+            if multilingual {
+                let lang = match &chosen {
+                    Some(lang) => lang.clone(),
+                    None => {
+                        // `<|startoftranscript|>` is followed by the language.
+                        let logits = self.decode_step(&prompt, &mut dec)?;
+                        prompt.clear();
+                        let best = sp.languages.iter().max_by(|a, b| logits[a.1 as usize].total_cmp(&logits[b.1 as usize]));
+                        let lang = best.cloned().context("the vocabulary has no language tokens")?;
+                        chosen = Some(lang.clone());
+                        lang
+                    }
+                };
+                prompt.extend([lang.1, sp.transcribe]);
+            }
+            prompt.push(sp.no_timestamps);
+
+            let mut logits = self.decode_step(&prompt, &mut dec)?;
+            let budget = self.arch.n_text_ctx / 2;
+            for _ in 0..budget {
+                // Only text or the end: no timestamps, tasks or languages.
+                let id = (0..=sp.eot).max_by(|&a, &b| logits[a as usize].total_cmp(&logits[b as usize])).unwrap_or(sp.eot);
+                if id == sp.eot {
+                    break;
+                }
+                tokens.push(id);
+                logits = self.decode_step(&[id], &mut dec)?;
+            }
+        }
+        Ok(Transcript { language: chosen.map(|(code, _)| code), tokens })
+    }
+
+    /// Encoder states of one window's `[n_mels, frames]` spectrogram,
+    /// packed `[frames / 2, hidden]`.
+    fn encode(&mut self, mel: &[f32], frames: usize) -> Result<Vec<f32>> {
+        let (h, n_mels) = (self.arch.hidden, self.arch.n_mels);
+        let weights = self.weights.clone();
+        let x = self.conv(&weights.conv1, |c, t| mel[c * frames + t], n_mels, frames, 1)?;
+        let mut x = self.conv(&weights.conv2, |c, t| x[t * h + c], h, frames, 2)?;
+        let n = x.len() / h;
+        ensure!(n <= self.arch.n_audio_ctx, "{n} audio positions, the model has {}", self.arch.n_audio_ctx);
+        for t in 0..n {
+            let pos = self.mm.store.dequant_row(&weights.encoder_position.name, t)?;
+            x[t * h..][..h].iter_mut().zip(pos).for_each(|(a, b)| *a += b);
+        }
+        for layer in &weights.encoder {
+            let xn = self.layer_norm_rows(&layer.attn.norm, &x)?;
+            let q = self.linear(&layer.attn.q, &xn, n)?;
+            let (k, v) = (self.linear(&layer.attn.k, &xn, n)?, self.linear(&layer.attn.v, &xn, n)?);
+            let (k, v) = (rows(&k, h), rows(&v, h));
+            let heads = self.arch.n_heads;
+            let attn: Vec<f32> = q.chunks_exact(h).flat_map(|qi| attention(qi, &k, &v, heads, heads, h / heads)).collect();
+            add(&mut x, &self.linear(&layer.attn.output, &attn, n)?);
+            let out = self.ffn(layer, &x, n)?;
+            add(&mut x, &out);
+        }
+        self.layer_norm_rows(&weights.encoder_norm, &x)
+    }
+
+    /// `conv` over `len` positions of `channels` inputs read through `at`,
+    /// zero-padded by one on each side, then GELU: `[len / stride, out]`.
+    fn conv(&mut self, conv: &Conv1d, at: impl Fn(usize, usize) -> f32, channels: usize, len: usize, stride: usize) -> Result<Vec<f32>> {
+        let cols = unfold(at, channels, len, conv.kernel, stride);
+        let n = cols.len() / (channels * conv.kernel);
+        let out = self.mm.linear(&conv.weight, Some(&conv.bias), &cols, n)?;
+        Ok(out.into_iter().map(gelu).collect())
+    }
+
+    fn cross_rows(&mut self, encoded: &[f32]) -> Result<Decoding> {
+        let h = self.arch.hidden;
+        let n = encoded.len() / h;
+        let weights = self.weights.clone();
+        let mut dec = Decoding::default();
+        for layer in &weights.decoder {
+            let cross = layer.cross.as_ref().context("decoder layer without cross attention")?;
+            let (k, v) = (self.linear(&cross.k, encoded, n)?, self.linear(&cross.v, encoded, n)?);
+            dec.cross_k.push(rows(&k, h));
+            dec.cross_v.push(rows(&v, h));
+            dec.self_k.push(Vec::new());
+            dec.self_v.push(Vec::new());
+        }
+        Ok(dec)
+    }
+
+    /// Run decoder `tokens` after the rows already in `dec`, and return the
+    /// logits following the last of them.
+    fn decode_step(&mut self, tokens: &[u32], dec: &mut Decoding) -> Result<Vec<f32>> {
+        let (h, n, heads) = (self.arch.hidden, tokens.len(), self.arch.n_heads);
+        let weights = self.weights.clone();
+        let start = dec.self_k.first().map_or(0, Vec::len);
+        ensure!(start + n <= self.arch.n_text_ctx, "the transcript is longer than the decoder's {} positions", self.arch.n_text_ctx);
+        let mut x = self.mm.embed(&weights.token_embd, tokens)?;
+        for i in 0..n {
+            let pos = self.mm.store.dequant_row(&weights.decoder_position.name, start + i)?;
+            x[i * h..][..h].iter_mut().zip(pos).for_each(|(a, b)| *a += b);
+        }
+        for (l, layer) in weights.decoder.iter().enumerate() {
+            let xn = self.layer_norm_rows(&layer.attn.norm, &x)?;
+            let q = self.linear(&layer.attn.q, &xn, n)?;
+            let (k, v) = (self.linear(&layer.attn.k, &xn, n)?, self.linear(&layer.attn.v, &xn, n)?);
+            let mut attn = Vec::with_capacity(n * h);
+            for (i, qi) in q.chunks_exact(h).enumerate() {
+                dec.self_k[l].push(k[i * h..][..h].into());
+                dec.self_v[l].push(v[i * h..][..h].into());
+                attn.extend(attention(qi, &dec.self_k[l], &dec.self_v[l], heads, heads, h / heads));
+            }
+            add(&mut x, &self.linear(&layer.attn.output, &attn, n)?);
+
+            let cross = layer.cross.as_ref().context("decoder layer without cross attention")?;
+            let out = self.cross_attention(cross, &x, n, &dec.cross_k[l], &dec.cross_v[l])?;
+            add(&mut x, &out);
+            let out = self.ffn(layer, &x, n)?;
+            add(&mut x, &out);
+        }
+        let last = self.layer_norm_rows(&weights.decoder_norm, &x[(n - 1) * h..])?;
+        self.mm.linear(&weights.token_embd, None, &last, 1)
+    }
+
+    fn cross_attention(&mut self, a: &WhisperAttention, x: &[f32], n: usize, k: &[Arc<[f32]>], v: &[Arc<[f32]>]) -> Result<Vec<f32>> {
+        let (h, heads) = (self.arch.hidden, self.arch.n_heads);
+        let xn = self.layer_norm_rows(&a.norm, x)?;
+        let q = self.linear(&a.q, &xn, n)?;
+        let attn: Vec<f32> = q.chunks_exact(h).flat_map(|qi| attention(qi, k, v, heads, heads, h / heads)).collect();
+        self.linear(&a.output, &attn, n)
+    }
+
+    fn ffn(&mut self, layer: &WhisperLayerWeights, x: &[f32], n: usize) -> Result<Vec<f32>> {
+        let xn = self.layer_norm_rows(&layer.ffn_norm, x)?;
+        let up: Vec<f32> = self.linear(&layer.ffn_up, &xn, n)?.into_iter().map(gelu).collect();
+        self.linear(&layer.ffn_down, &up, n)
+    }
+
+    fn linear(&mut self, l: &Linear, x: &[f32], n: usize) -> Result<Vec<f32>> {
+        self.mm.linear(&l.weight, l.bias.as_ref(), x, n)
+    }
+
+    fn layer_norm_rows(&mut self, norm: &LayerNorm, x: &[f32]) -> Result<Vec<f32>> {
+        let (w, b) = (self.mm.cpu_weights(&norm.weight.name)?, self.mm.cpu_weights(&norm.bias.name)?);
+        Ok(x.chunks_exact(w.len()).flat_map(|r| layer_norm(r, &w, &b, self.arch.layer_norm_eps)).collect())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Audio
+// ---------------------------------------------------------------------------
+
+/// A WAV file as mono 16 kHz samples.
+pub fn read_audio(path: &std::path::Path) -> Result<Vec<f32>> {
+    let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+    let (samples, rate) = parse_wav(&bytes).with_context(|| format!("decoding {}", path.display()))?;
+    Ok(resample(&samples, rate as usize, SAMPLE_RATE))
+}
+
+/// Mono samples and the sample rate of a RIFF WAV: 16-bit PCM or 32-bit
+/// float, any number of channels (averaged).
+pub fn parse_wav(bytes: &[u8]) -> Result<(Vec<f32>, u32)> {
+    ensure!(bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WAVE", "not a RIFF WAVE file");
+    let u16_at = |b: &[u8], i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+    let u32_at = |b: &[u8], i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+    let (mut format, mut data) = (None, None);
+    let mut i = 12;
+    while i + 8 <= bytes.len() {
+        let size = u32_at(bytes, i + 4) as usize;
+        let body = bytes.get(i + 8..i + 8 + size).unwrap_or(&bytes[i + 8..]);
+        match &bytes[i..i + 4] {
+            b"fmt " => {
+                ensure!(body.len() >= 16, "short fmt chunk");
+                let mut tag = u16_at(body, 0);
+                // WAVE_FORMAT_EXTENSIBLE: the real tag opens the sub-format GUID.
+                if tag == 0xFFFE && body.len() >= 26 {
+                    tag = u16_at(body, 24);
+                }
+                format = Some((tag, u16_at(body, 2) as usize, u32_at(body, 4), u16_at(body, 14)));
+            }
+            b"data" => data = Some(body),
+            _ => {}
+        }
+        i += 8 + size + size % 2;
+    }
+    let (tag, channels, rate, bits) = format.context("no fmt chunk")?;
+    let data = data.context("no data chunk")?;
+    ensure!(channels > 0, "no channels");
+    // `resample` divides by the rate and sizes its output from it.
+    ensure!((1_000..=384_000).contains(&rate), "unsupported sample rate {rate} Hz (1000 to 384000)");
+    let frames: Vec<f32> = match (tag, bits) {
+        (1, 16) => data.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0).collect(),
+        (3, 32) => data.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect(),
+        _ => bail!("unsupported WAV encoding (format {tag}, {bits} bits); use 16-bit PCM or 32-bit float"),
+    };
+    let mono = frames.chunks_exact(channels).map(|f| f.iter().sum::<f32>() / channels as f32).collect();
+    Ok((mono, rate))
+}
+
+/// Linear-interpolation resampling from `from` Hz to `to` Hz.
+pub fn resample(samples: &[f32], from: usize, to: usize) -> Vec<f32> {
+    if from == to || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let n = samples.len() * to / from;
+    (0..n)
+        .map(|i| {
+            let x = i as f64 * from as f64 / to as f64;
+            let (i0, frac) = (x as usize, x.fract() as f32);
+            let i1 = (i0 + 1).min(samples.len() - 1);
+            samples[i0] * (1.0 - frac) + samples[i1] * frac
+        })
+        .collect()
+}
+
+/// Slaney-scale mel filterbank with Slaney area normalization, as librosa
+/// builds it for Whisper: `[n_mels, n_fft / 2 + 1]`.
+pub fn mel_filters(n_mels: usize, n_fft: usize, sample_rate: usize) -> Vec<f32> {
+    const F_SP: f64 = 200.0 / 3.0;
+    const MIN_LOG_HZ: f64 = 1000.0;
+    let min_log_mel = MIN_LOG_HZ / F_SP;
+    let logstep = 6.4f64.ln() / 27.0;
+    let to_mel = |hz: f64| if hz < MIN_LOG_HZ { hz / F_SP } else { min_log_mel + (hz / MIN_LOG_HZ).ln() / logstep };
+    let to_hz = |mel: f64| if mel < min_log_mel { mel * F_SP } else { MIN_LOG_HZ * (logstep * (mel - min_log_mel)).exp() };
+
+    let n_bins = n_fft / 2 + 1;
+    let top = to_mel(sample_rate as f64 / 2.0);
+    let edges: Vec<f64> = (0..n_mels + 2).map(|i| to_hz(top * i as f64 / (n_mels + 1) as f64)).collect();
+    let mut filters = vec![0.0f32; n_mels * n_bins];
+    for m in 0..n_mels {
+        let (lo, mid, hi) = (edges[m], edges[m + 1], edges[m + 2]);
+        let norm = 2.0 / (hi - lo);
+        for k in 0..n_bins {
+            let f = k as f64 * sample_rate as f64 / n_fft as f64;
+            let w = ((f - lo) / (mid - lo)).min((hi - f) / (hi - mid)).max(0.0);
+            filters[m * n_bins + k] = (w * norm) as f32;
+        }
+    }
+    filters
+}
+
+/// Whisper's log-mel spectrogram, `[n_mels, samples.len() / HOP]`: a
+/// centred, reflect-padded STFT with a periodic Hann window, power through
+/// `filters`, `log10` floored at 1e-10, clamped to 8 below its maximum and
+/// scaled to about -1..1.
+pub fn log_mel(samples: &[f32], filters: &[f32], n_mels: usize) -> Vec<f32> {
+    let (half, n_bins) = (N_FFT / 2, N_FFT / 2 + 1);
+    let frames = samples.len() / HOP;
+    let len = samples.len() as isize;
+    let at = |i: isize| {
+        // Reflect about both ends, `numpy.pad(mode="reflect")`.
+        let i = if i < 0 { -i } else if i >= len { 2 * (len - 1) - i } else { i };
+        samples.get(i.clamp(0, len - 1) as usize).copied().unwrap_or(0.0)
+    };
+    let window: Vec<f32> = (0..N_FFT).map(|n| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * n as f32 / N_FFT as f32).cos()).collect();
+    let (cos, sin): (Vec<f32>, Vec<f32>) = (0..n_bins * N_FFT)
+        .map(|i| {
+            let angle = 2.0 * std::f64::consts::PI * ((i / N_FFT) * (i % N_FFT) % N_FFT) as f64 / N_FFT as f64;
+            (angle.cos() as f32, angle.sin() as f32)
+        })
+        .unzip();
+
+    let mut mel = vec![0.0f32; n_mels * frames];
+    let mut frame = vec![0.0f32; N_FFT];
+    let mut power = vec![0.0f32; n_bins];
+    for f in 0..frames {
+        let start = (f * HOP) as isize - half as isize;
+        frame.iter_mut().enumerate().for_each(|(n, x)| *x = at(start + n as isize) * window[n]);
+        for (k, p) in power.iter_mut().enumerate() {
+            let (re, im) = (crate::cpu::dot(&cos[k * N_FFT..][..N_FFT], &frame), crate::cpu::dot(&sin[k * N_FFT..][..N_FFT], &frame));
+            *p = re * re + im * im;
+        }
+        for m in 0..n_mels {
+            mel[m * frames + f] = crate::cpu::dot(&filters[m * n_bins..][..n_bins], &power).max(1e-10).log10();
+        }
+    }
+    let max = mel.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    mel.iter_mut().for_each(|v| *v = ((*v).max(max - 8.0) + 4.0) / 4.0);
+    mel
+}
+
+// ---------------------------------------------------------------------------
+// CPU math
+// ---------------------------------------------------------------------------
+
+fn add(x: &mut [f32], y: &[f32]) {
+    x.iter_mut().zip(y).for_each(|(a, b)| *a += b);
+}
+
+fn rows(x: &[f32], width: usize) -> Vec<Arc<[f32]>> {
+    x.chunks_exact(width).map(Arc::from).collect()
+}
+
+/// The `kernel`-wide windows a padded convolution sees at every `stride`-th
+/// of `len` positions, zero past either end, each flattened channel by
+/// channel (`[positions, channels * kernel]`); `at(channel, position)`
+/// reads the input.
+pub(crate) fn unfold(at: impl Fn(usize, usize) -> f32, channels: usize, len: usize, kernel: usize, stride: usize) -> Vec<f32> {
+    let pad = kernel / 2;
+    let n_out = (len + 2 * pad - kernel) / stride + 1;
+    let mut cols = Vec::with_capacity(n_out * channels * kernel);
+    for t in 0..n_out {
+        for c in 0..channels {
+            for j in 0..kernel {
+                let src = (t * stride + j).checked_sub(pad).filter(|&s| s < len);
+                cols.push(src.map_or(0.0, |s| at(c, s)));
+            }
+        }
+    }
+    cols
+}